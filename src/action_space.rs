@@ -0,0 +1,302 @@
+/**
+ * Utility module for a fixed action space, where every move is mapped to an
+ * index of a network output head. Moves are indexed from the player's point
+ * of view (ranks flipped for Black, like the state vector) by their source
+ * and destination squares, which covers castling (as the king's move) and
+ * queen promotions, followed by a block of indices for underpromotions to a
+ * knight, bishop or rook. Outputs for illegal moves are masked out before
+ * picking a move.
+ */
+use crate::mdp::get_action;
+use crate::sampling::random_position;
+
+use chess::{Board, ChessMove, Color, MoveGen, Piece, ALL_SQUARES};
+use rand::Rng;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+// Number of indices for moves given by their source and destination squares
+const SQUARE_PAIRS: usize = 64 * 64;
+
+// Pieces a pawn can underpromote to, in index order
+const UNDERPROMOTIONS: [Piece; 3] = [Piece::Knight, Piece::Bishop, Piece::Rook];
+
+// Size of the action space: every square pair, then 8 source files, 3
+// directions (capture left, push, capture right) and 3 pieces for
+// underpromotions
+pub const ACTION_SPACE: usize = SQUARE_PAIRS + 8 * 3 * 3;
+
+// Positions with promotions, underpromotions and castling for both sides to
+// move
+const CHECK_FENS: [&str; 3] = [
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+    "r3k2r/1P4P1/8/8/8/8/1p4p1/R3K2R w KQkq - 0 1",
+];
+
+// Longest random game played to sample a position to check
+const MAX_CHECK_PLIES: usize = 120;
+
+/**
+ * [relative_index(index, player_white)] returns the index of the square with
+ * index [index] from the player's point of view, flipping ranks for Black.
+ */
+fn relative_index(index: usize, player_white: bool) -> usize {
+    if player_white {
+        return index;
+    }
+    return index ^ 56;
+}
+
+/**
+ * [move_to_index(m, player_white)] returns the action index of move [m]
+ * depending on whether the player is white.
+ */
+pub fn move_to_index(m: ChessMove, player_white: bool) -> usize {
+    let from = relative_index(m.get_source().to_index(), player_white);
+    let to = relative_index(m.get_dest().to_index(), player_white);
+    return relative_move_index(from, to, m.get_promotion());
+}
+
+/**
+ * [relative_move_index(from, to, promotion)] returns the action index of the
+ * move from square index [from] to [to], both from the player's point of
+ * view, promoting to [promotion] if any.
+ */
+fn relative_move_index(from: usize, to: usize, promotion: Option<Piece>) -> usize {
+    match promotion {
+        None | Some(Piece::Queen) => from * 64 + to,
+        Some(piece) => {
+            let direction = to % 8 + 1 - from % 8;
+            let piece_index = UNDERPROMOTIONS.iter().position(|p| *p == piece).unwrap();
+            SQUARE_PAIRS + ((from % 8) * 3 + direction) * 3 + piece_index
+        }
+    }
+}
+
+/**
+ * [action_index(action)] returns the action index of the move encoded by the
+ * action vector [action], as built by [get_action], which already gives its
+ * squares from the player's point of view. Returns None if [action] does not
+ * encode a move.
+ */
+pub fn action_index(action: &[f64]) -> Option<usize> {
+    let hot = |plane: &[f64]| plane.iter().position(|x| *x == 1.);
+    let from = hot(action.get(0..64)?)?;
+    let to = hot(action.get(64..128)?)?;
+    let promotion = match hot(action.get(128..132)?) {
+        None => None,
+        Some(0) => Some(Piece::Bishop),
+        Some(1) => Some(Piece::Knight),
+        Some(2) => Some(Piece::Rook),
+        Some(_) => Some(Piece::Queen),
+    };
+    let direction = (to % 8 + 1).checked_sub(from % 8);
+    if promotion.is_some() && (from / 8 != 6 || to / 8 != 7 || direction.map_or(true, |d| d > 2)) {
+        return None;
+    }
+    return Some(relative_move_index(from, to, promotion));
+}
+
+/**
+ * [index_to_move(index, b, player_white)] returns the move in board [b] with
+ * action index [index] depending on whether the player is white, or None if
+ * that move is not legal.
+ */
+pub fn index_to_move(index: usize, b: &Board, player_white: bool) -> Option<ChessMove> {
+    if index >= ACTION_SPACE {
+        return None;
+    }
+
+    let (from, to, promotion) = if index < SQUARE_PAIRS {
+        (index / 64, index % 64, None)
+    } else {
+        let i = index - SQUARE_PAIRS;
+        let from_file = i / 9;
+        let to_file = (from_file + (i / 3) % 3).checked_sub(1)?;
+        if to_file >= 8 {
+            return None;
+        }
+        (48 + from_file, 56 + to_file, Some(UNDERPROMOTIONS[i % 3]))
+    };
+
+    let source = ALL_SQUARES[relative_index(from, player_white)];
+    let dest = ALL_SQUARES[relative_index(to, player_white)];
+
+    // Pawn moves to the last rank without an underpromotion are queen
+    // promotions
+    let promotion = match promotion {
+        None if to >= 56 && b.piece_on(source) == Some(Piece::Pawn) => Some(Piece::Queen),
+        p => p,
+    };
+
+    let m = ChessMove::new(source, dest, promotion);
+    if !b.legal(m) {
+        return None;
+    }
+    return Some(m);
+}
+
+/**
+ * [legal_mask(b, player_white)] returns for every action index whether it is
+ * a legal move in board [b] depending on whether the player is white.
+ */
+pub fn legal_mask(b: &Board, player_white: bool) -> Vec<bool> {
+    let mut mask = vec![false; ACTION_SPACE];
+    for m in MoveGen::new_legal(b) {
+        mask[move_to_index(m, player_white)] = true;
+    }
+
+    return mask;
+}
+
+/**
+ * [mask_outputs(outputs, b, player_white)] sets the outputs of the action head
+ * [outputs] for illegal moves in board [b] to negative infinity, depending on
+ * whether the player is white.
+ */
+pub fn mask_outputs(outputs: &mut [f64], b: &Board, player_white: bool) {
+    let mask = legal_mask(b, player_white);
+    for (output, legal) in outputs.iter_mut().zip(mask.iter()) {
+        if !legal {
+            *output = f64::NEG_INFINITY;
+        }
+    }
+}
+
+/**
+ * [masked_argmax(outputs, b, player_white)] returns the legal move in board
+ * [b] with the highest output of the action head [outputs] depending on
+ * whether the player is white, or None if there are no legal moves.
+ */
+pub fn masked_argmax(outputs: &[f64], b: &Board, player_white: bool) -> Option<ChessMove> {
+    let mut masked = outputs.to_vec();
+    mask_outputs(&mut masked, b, player_white);
+
+    let mut best: Option<(usize, f64)> = None;
+    for (i, output) in masked.iter().enumerate() {
+        match best {
+            _ if *output == f64::NEG_INFINITY => (),
+            Some((_, high)) if high >= *output => (),
+            _ => best = Some((i, *output)),
+        };
+    }
+
+    return index_to_move(best?.0, b, player_white);
+}
+
+/**
+ * [masked_softmax(outputs, b, player_white, temperature)] returns the
+ * probability of each action index given the outputs of the action head
+ * [outputs] at [temperature], with illegal moves in board [b] given
+ * probability 0 depending on whether the player is white.
+ */
+pub fn masked_softmax(
+    outputs: &[f64],
+    b: &Board,
+    player_white: bool,
+    temperature: f64,
+) -> Vec<f64> {
+    let mut masked = outputs.to_vec();
+    mask_outputs(&mut masked, b, player_white);
+
+    let high = masked.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if high == f64::NEG_INFINITY {
+        return vec![0.; masked.len()];
+    }
+    let exps: Vec<f64> = masked
+        .iter()
+        .map(|o| ((o - high) / temperature).exp())
+        .collect();
+    let total: f64 = exps.iter().sum();
+
+    return exps.iter().map(|e| e / total).collect();
+}
+
+/**
+ * [check_round_trip(b)] checks that every legal move in board [b] has a
+ * distinct action index from the point of view of the side to move that maps
+ * back to the same move, and that masking random outputs of the action head
+ * only ever picks legal moves. Returns a description of each failure.
+ */
+pub fn check_round_trip(b: &Board) -> Vec<String> {
+    let player_white = b.side_to_move() == Color::White;
+
+    let mut failures = Vec::new();
+    let mut seen = HashSet::new();
+    for m in MoveGen::new_legal(b) {
+        let index = move_to_index(m, player_white);
+        if index >= ACTION_SPACE {
+            failures.push(format!("{} in {} has index {} out of range", m, b, index));
+            continue;
+        }
+        if !seen.insert(index) {
+            failures.push(format!("{} in {} shares index {}", m, b, index));
+        }
+        let back = index_to_move(index, b, player_white);
+        if back != Some(m) {
+            failures.push(format!("{} in {} maps back to {:?}", m, b, back));
+        }
+        let encoded = action_index(&get_action(m, player_white));
+        if encoded != Some(index) {
+            failures.push(format!("{} in {} is encoded as index {:?}", m, b, encoded));
+        }
+    }
+
+    let mut rng = rand::thread_rng();
+    let outputs: Vec<f64> = (0..ACTION_SPACE)
+        .map(|_| rng.gen_range(-1.0..1.0))
+        .collect();
+    match masked_argmax(&outputs, b, player_white) {
+        Some(m) if !b.legal(m) => failures.push(format!("Masking picked {} in {}", m, b)),
+        None if seen.len() > 0 => failures.push(format!("Masking picked no move in {}", b)),
+        _ => (),
+    };
+    let illegal_probability: f64 = masked_softmax(&outputs, b, player_white, 1.)
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !seen.contains(i))
+        .map(|(_, p)| p)
+        .sum();
+    if illegal_probability > 0. {
+        failures.push(format!("Masking left illegal moves possible in {}", b));
+    }
+
+    return failures;
+}
+
+/**
+ * [check_action_space(positions)] checks the action index round trip over
+ * fixed positions with promotions and castling and over [positions] randomly
+ * sampled positions. Returns a description of each failure.
+ */
+pub fn check_action_space(positions: usize) -> Vec<String> {
+    let mut failures = Vec::new();
+    for fen in CHECK_FENS {
+        failures.append(&mut check_round_trip(&Board::from_str(fen).unwrap()));
+    }
+    for _ in 0..positions {
+        failures.append(&mut check_round_trip(&random_position(MAX_CHECK_PLIES)));
+    }
+
+    return failures;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_positions_round_trip_through_the_action_space() {
+        for fen in CHECK_FENS {
+            let failures = check_round_trip(&Board::from_str(fen).unwrap());
+            assert!(failures.is_empty(), "{}: {:?}", fen, failures);
+        }
+    }
+
+    #[test]
+    fn random_positions_round_trip_through_the_action_space() {
+        let failures = check_action_space(100);
+        assert!(failures.is_empty(), "{:?}", failures);
+    }
+}
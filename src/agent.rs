@@ -0,0 +1,580 @@
+/**
+ * Utility module for the strategies that select moves, behind a single Agent
+ * trait so that self-play, the arena and the Lichess loop can pit any of them
+ * against each other. Agents can be built from an object in config.json, e.g.
+ * {"kind": "policy", "model": "policy.flow", "temperature": 0, "epsilon": 0.1,
+ *  "book": true}, where the kind is one of "policy" (which samples its moves
+ * from the softmax of their Q-values at a positive "temperature", for the
+ * first "temperature_plies" plies if given and at "final_temperature", by
+ * default 0, after), "mcts" (the policy
+ * network searching with the "mcts" settings, whose "simulations" the agent
+ * can override), "alphabeta" (the policy network searching with the
+ * "alphabeta" settings, whose "depth" the agent can override), "random",
+ * "search" (the
+ * handcrafted evaluation), "greedy_capture" or "mate_blocker" (scripted
+ * opponents) or "engine" (an external UCI engine configured by its "engine"
+ * settings), "epsilon" plays a random move with that probability,
+ * "underpromotion" turns queen promotions into random underpromotions with
+ * that probability and "book" follows the configured repertoire first.
+ */
+use crate::decision::{MoveDecision, MoveSource};
+use crate::eval::EvalWeights;
+use crate::game_context::GameContext;
+use crate::limits::best_move_limited;
+use crate::make_random_move_with;
+use crate::mdp::{evaluate_game_position, get_action, get_state_with_history, q_value};
+use crate::models::{read_network, DEFAULT_MODEL_PATH};
+use crate::normalization::{read_stats, FeatureStats, Normalized};
+use crate::policy_head::{is_policy_head, NetworkHead};
+use crate::q_function::QFunction;
+use crate::repertoire::Repertoire;
+use crate::scripted::scripted_agent;
+use crate::search::alphabeta::{self, AlphaBetaSettings};
+use crate::search::mcts::{search, MctsSettings};
+use crate::search::transposition::TranspositionTable;
+use crate::selfplay::{boltzmann_move, handcrafted_move};
+use crate::uci_engine::UciEngine;
+
+use chess::{ChessMove, Piece};
+use neuroflow::FeedForward;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::borrow::BorrowMut;
+use tracing::warn;
+
+// Pieces a pawn can underpromote to
+const UNDERPROMOTIONS: [Piece; 3] = [Piece::Knight, Piece::Bishop, Piece::Rook];
+
+// A strategy for selecting moves
+pub trait Agent {
+    /**
+     * [select_move(context)] returns the agent's decision of which move to
+     * play in the current position of the game given by [context], charging
+     * any nodes it evaluates to the clock of the side to move. Alternatively
+     * if there are no legal moves it returns None.
+     */
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision>;
+
+    /**
+     * [name()] describes the agent for the logs.
+     */
+    fn name(&self) -> String;
+
+    /**
+     * [evaluate(context, m)] returns the agent's Q-value of move [m] in the
+     * current position of the game given by [context] for the side to move,
+     * or None if it does not score moves.
+     */
+    fn evaluate(&mut self, _context: &GameContext, _m: ChessMove) -> Option<f64> {
+        return None;
+    }
+}
+
+// The temperature moves are sampled at from the softmax of their Q-values over
+// a game: [initial] for the first [plies] plies, or throughout if None, and
+// [final_temperature] after (0 plays the best move)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TemperatureSchedule {
+    pub initial: f64,
+    pub plies: Option<usize>,
+    pub final_temperature: f64,
+}
+
+// Plays the move its policy network scores highest, or samples moves by their
+// Q-values at a positive temperature. The network is either owned or borrowed.
+pub struct PolicyAgent<N: BorrowMut<FeedForward>> {
+    pub network: N,
+    pub label: String,
+    pub temperature: TemperatureSchedule,
+    pub policy_head: bool,
+    pub stats: FeatureStats, // feature statistics of the network
+}
+
+// Plays the move visited most by a Monte Carlo Tree Search guided by its
+// policy network. The network is either owned or borrowed.
+pub struct MctsAgent<N: BorrowMut<FeedForward>> {
+    pub network: N,
+    pub label: String,
+    pub settings: MctsSettings,
+    pub policy_head: bool,
+    pub table: TranspositionTable, // evaluations of the network
+    pub stats: FeatureStats,       // feature statistics of the network
+}
+
+// Plays the best move found by an alpha-beta search evaluating its leaves with
+// its policy network. The network is either owned or borrowed.
+pub struct AlphaBetaAgent<N: BorrowMut<FeedForward>> {
+    pub network: N,
+    pub label: String,
+    pub settings: AlphaBetaSettings,
+    pub policy_head: bool,
+    pub table: TranspositionTable, // evaluations and best moves of the network
+    pub stats: FeatureStats,       // feature statistics of the network
+}
+
+// Plays uniformly random moves
+pub struct RandomAgent;
+
+// Plays a random move with probability epsilon and otherwise defers to another
+// agent, turning its queen promotions into random underpromotions with
+// probability underpromotion
+pub struct EpsilonGreedyAgent<A: Agent> {
+    pub inner: A,
+    pub epsilon: f64,
+    pub underpromotion: f64,
+}
+
+// Plays the move leaving the best material under the handcrafted evaluation
+pub struct SearchAgent {
+    pub weights: EvalWeights,
+}
+
+// Follows the repertoire while in book and otherwise defers to another agent
+pub struct BookAgent<A: Agent> {
+    pub repertoire: Repertoire,
+    pub inner: A,
+}
+
+// Asks an external engine over UCI, playing a random move if it fails
+pub struct ExternalUciAgent {
+    pub engine: UciEngine,
+}
+
+impl TemperatureSchedule {
+    /**
+     * [constant(temperature)] returns the schedule sampling at [temperature]
+     * throughout the game.
+     */
+    pub fn constant(temperature: f64) -> TemperatureSchedule {
+        return TemperatureSchedule {
+            initial: temperature,
+            plies: None,
+            final_temperature: temperature,
+        };
+    }
+
+    /**
+     * [from_config(settings, default_temperature)] reads the schedule given by
+     * the "temperature", "temperature_plies" and "final_temperature" of
+     * [settings], which samples at [default_temperature] throughout unless
+     * configured.
+     */
+    pub fn from_config(settings: &Value, default_temperature: f64) -> TemperatureSchedule {
+        let initial = settings["temperature"]
+            .as_f64()
+            .unwrap_or(default_temperature);
+        let plies = settings["temperature_plies"].as_u64().map(|n| n as usize);
+        return TemperatureSchedule {
+            initial,
+            plies,
+            final_temperature: match plies {
+                Some(_) => settings["final_temperature"].as_f64().unwrap_or(0.),
+                None => initial,
+            },
+        };
+    }
+
+    /**
+     * [at(ply)] returns the temperature of the move at ply [ply], counting
+     * from 1.
+     */
+    pub fn at(&self, ply: usize) -> f64 {
+        return match self.plies {
+            Some(plies) if ply > plies => self.final_temperature,
+            _ => self.initial,
+        };
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> PolicyAgent<N> {
+    /**
+     * [new(network, label)] creates an agent always playing the best move of
+     * [network], described in the logs by [label].
+     */
+    pub fn new(network: N, label: &str) -> PolicyAgent<N> {
+        let policy_head = is_policy_head(network.borrow());
+        return PolicyAgent {
+            network,
+            label: label.to_string(),
+            temperature: TemperatureSchedule::default(),
+            policy_head,
+            stats: FeatureStats::new(),
+        };
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> Agent for PolicyAgent<N> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let nn = &mut Normalized::new(head, self.stats);
+        let (b, player_white) = (context.board(), context.player_white());
+        let temperature = self.temperature.at(context.ply());
+        if temperature > 0. {
+            let scores = evaluate_game_position(&context.history, nn, player_white);
+            context.clock_to_move().spend(scores.len());
+            let m = boltzmann_move(&scores, temperature, &mut context.rng)?;
+            return Some(MoveDecision::from_scores(&scores, m, MoveSource::Policy));
+        }
+
+        match context.clock_to_move().node_budget() {
+            None => {
+                let scores = evaluate_game_position(&context.history, nn, player_white);
+                context.clock_to_move().spend(scores.len());
+                MoveDecision::best_of(&scores, MoveSource::Policy)
+            }
+            Some(budget) => {
+                let state = get_state_with_history(&context.history, player_white);
+                let (m, nodes) = best_move_limited(nn, &b, &state, player_white, Some(budget))?;
+                context.clock_to_move().spend(nodes);
+                Some(MoveDecision::new(m, MoveSource::Search))
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        return self.label.clone();
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        let player_white = context.player_white();
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let mut nn = Normalized::new(head, self.stats);
+        let mut sa = get_state_with_history(&context.history, player_white);
+        sa.append(&mut get_action(m, player_white));
+        return Some(nn.predict(&sa[..]));
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> MctsAgent<N> {
+    /**
+     * [new(network, label, settings)] creates an agent searching with
+     * [network] according to [settings], described in the logs by [label].
+     */
+    pub fn new(network: N, label: &str, settings: MctsSettings) -> MctsAgent<N> {
+        let policy_head = is_policy_head(network.borrow());
+        return MctsAgent {
+            network,
+            label: label.to_string(),
+            settings,
+            policy_head,
+            table: TranspositionTable::default(),
+            stats: FeatureStats::new(),
+        };
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> Agent for MctsAgent<N> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let nn = &mut Normalized::new(head, self.stats);
+        let b = context.board();
+        let counters = context.history.counters();
+        let clock = context.clock_to_move();
+        let budget = clock.node_budget();
+        let result = search(nn, &b, counters, &self.settings, &mut self.table, budget)?;
+        clock.spend(result.nodes);
+
+        let mut decision =
+            MoveDecision::from_scores(&result.scores(), result.best, MoveSource::Search);
+        if self.settings.targets {
+            decision.target = Some(result.value);
+        }
+        return Some(decision);
+    }
+
+    fn name(&self) -> String {
+        return format!("{} with MCTS", self.label);
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        let (b, player_white) = (context.board(), context.player_white());
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let mut nn = Normalized::new(head, self.stats);
+        return Some(q_value(
+            &mut nn,
+            &b,
+            context.history.counters(),
+            player_white,
+            m,
+        ));
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> AlphaBetaAgent<N> {
+    /**
+     * [new(network, label, settings)] creates an agent searching with
+     * [network] according to [settings], described in the logs by [label].
+     */
+    pub fn new(network: N, label: &str, settings: AlphaBetaSettings) -> AlphaBetaAgent<N> {
+        let policy_head = is_policy_head(network.borrow());
+        return AlphaBetaAgent {
+            network,
+            label: label.to_string(),
+            settings,
+            policy_head,
+            table: TranspositionTable::default(),
+            stats: FeatureStats::new(),
+        };
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> Agent for AlphaBetaAgent<N> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let nn = &mut Normalized::new(head, self.stats);
+        let b = context.board();
+        let counters = context.history.counters();
+        let clock = context.clock_to_move();
+        let budget = clock.node_budget();
+        let deadline = clock.deadline;
+        let result = alphabeta::search(
+            nn,
+            &b,
+            counters,
+            &self.settings,
+            &mut self.table,
+            budget,
+            deadline,
+        )?;
+        clock.spend(result.nodes);
+
+        let mut decision = MoveDecision::new(result.best, MoveSource::Search);
+        decision.score = Some(result.score);
+        return Some(decision);
+    }
+
+    fn name(&self) -> String {
+        return format!("{} with alpha-beta", self.label);
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        let (b, player_white) = (context.board(), context.player_white());
+        let head = NetworkHead::new(self.network.borrow_mut(), self.policy_head);
+        let mut nn = Normalized::new(head, self.stats);
+        return Some(q_value(
+            &mut nn,
+            &b,
+            context.history.counters(),
+            player_white,
+            m,
+        ));
+    }
+}
+
+impl Agent for RandomAgent {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let m = make_random_move_with(context.board(), &mut context.rng)?;
+        return Some(MoveDecision::new(m, MoveSource::Exploration));
+    }
+
+    fn name(&self) -> String {
+        return "random mover".to_string();
+    }
+}
+
+impl<A: Agent> Agent for EpsilonGreedyAgent<A> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        if context.rng.gen_bool(self.epsilon.clamp(0., 1.)) {
+            let m = make_random_move_with(context.board(), &mut context.rng)?;
+            return Some(MoveDecision::new(m, MoveSource::Exploration));
+        }
+
+        let decision = self.inner.select_move(context)?;
+        let m = decision.chosen;
+        if m.get_promotion() == Some(Piece::Queen)
+            && self.underpromotion > 0.
+            && context.rng.gen_bool(self.underpromotion.min(1.))
+        {
+            let piece = UNDERPROMOTIONS[context.rng.gen_range(0..UNDERPROMOTIONS.len())];
+            let underpromotion = ChessMove::new(m.get_source(), m.get_dest(), Some(piece));
+            return Some(MoveDecision::new(underpromotion, MoveSource::Exploration));
+        }
+        return Some(decision);
+    }
+
+    fn name(&self) -> String {
+        return self.inner.name();
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        return self.inner.evaluate(context, m);
+    }
+}
+
+impl Agent for SearchAgent {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let (b, player_white) = (context.board(), context.player_white());
+        let m = handcrafted_move(&b, player_white, &self.weights, &mut context.rng)?;
+        return Some(MoveDecision::new(m, MoveSource::Search));
+    }
+
+    fn name(&self) -> String {
+        return "handcrafted evaluation".to_string();
+    }
+}
+
+impl<A: Agent> Agent for BookAgent<A> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let (b, player_white) = (context.board(), context.player_white());
+        match self.repertoire.lookup(&b, player_white, context.ply()) {
+            Some(m) => Some(MoveDecision::new(m, MoveSource::Book)),
+            None => self.inner.select_move(context),
+        }
+    }
+
+    fn name(&self) -> String {
+        return format!("{} with book", self.inner.name());
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        return self.inner.evaluate(context, m);
+    }
+}
+
+impl Agent for ExternalUciAgent {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let b = context.board();
+        match self.engine.best_move(&b) {
+            Ok(m) => m.map(|m| MoveDecision::new(m, MoveSource::Engine)),
+            Err(e) => {
+                warn!("Engine failed ({}), playing a random move.", e);
+                let m = make_random_move_with(b, &mut context.rng)?;
+                Some(MoveDecision::new(m, MoveSource::Fallback))
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        return "UCI engine".to_string();
+    }
+}
+
+impl<A: Agent + ?Sized> Agent for Box<A> {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        return (**self).select_move(context);
+    }
+
+    fn name(&self) -> String {
+        return (**self).name();
+    }
+
+    fn evaluate(&mut self, context: &GameContext, m: ChessMove) -> Option<f64> {
+        return (**self).evaluate(context, m);
+    }
+}
+
+/**
+ * [without_exploration(settings)] returns the agent [settings] with every
+ * source of randomness in its choice of moves turned off.
+ */
+pub fn without_exploration(settings: &Value) -> Value {
+    let mut settings = settings.clone();
+    if settings.is_object() {
+        for key in [
+            "epsilon",
+            "underpromotion",
+            "temperature",
+            "final_temperature",
+        ] {
+            settings[key] = json!(0);
+        }
+    }
+    return settings;
+}
+
+/**
+ * [agent_from_config(settings, config)] builds the agent described by
+ * [settings] within the parsed [config], or None if [settings] names no kind
+ * of agent, its engine fails to start or its network cannot be loaded.
+ */
+pub fn agent_from_config(settings: &Value, config: &Value) -> Option<Box<dyn Agent + Send>> {
+    let kind = match settings {
+        Value::String(s) => s.as_str(),
+        _ => settings["kind"].as_str()?,
+    };
+    let load = |path: &str| match read_network(path).and_then(|nn| Ok((nn, read_stats(path)?))) {
+        Ok(loaded) => Some(loaded),
+        Err(e) => {
+            warn!("Unable to load network ({})", e);
+            None
+        }
+    };
+
+    let agent: Box<dyn Agent + Send> = match kind {
+        "policy" => {
+            let path = settings["model"]
+                .as_str()
+                .or(config["models"]["white"].as_str())
+                .unwrap_or(DEFAULT_MODEL_PATH);
+            let (nn, stats) = load(path)?;
+            let mut agent = PolicyAgent::new(nn, path);
+            agent.stats = stats;
+            agent.temperature = TemperatureSchedule::from_config(settings, 0.);
+            Box::new(agent)
+        }
+        "mcts" => {
+            let path = settings["model"]
+                .as_str()
+                .or(config["models"]["white"].as_str())
+                .unwrap_or(DEFAULT_MODEL_PATH);
+            let mut mcts = MctsSettings::from_config(config);
+            if let Some(n) = settings["simulations"].as_u64() {
+                mcts.simulations = n as usize;
+            }
+            let (nn, stats) = load(path)?;
+            let mut agent = MctsAgent::new(nn, path, mcts);
+            agent.table = TranspositionTable::from_config(config);
+            agent.stats = stats;
+            Box::new(agent)
+        }
+        "alphabeta" => {
+            let path = settings["model"]
+                .as_str()
+                .or(config["models"]["white"].as_str())
+                .unwrap_or(DEFAULT_MODEL_PATH);
+            let mut alphabeta = AlphaBetaSettings::from_config(config);
+            if let Some(depth) = settings["depth"].as_u64() {
+                alphabeta.depth = (depth as usize).max(1);
+            }
+            let (nn, stats) = load(path)?;
+            let mut agent = AlphaBetaAgent::new(nn, path, alphabeta);
+            agent.table = TranspositionTable::from_config(config);
+            agent.stats = stats;
+            Box::new(agent)
+        }
+        "random" => Box::new(RandomAgent),
+        "greedy_capture" | "mate_blocker" => {
+            scripted_agent(kind, &EvalWeights::from_config(config))?
+        }
+        "search" => Box::new(SearchAgent {
+            weights: EvalWeights::from_config(config),
+        }),
+        "engine" => match UciEngine::from_config(&settings["engine"]) {
+            Ok(engine) => Box::new(ExternalUciAgent { engine }),
+            Err(e) => {
+                warn!("Unable to start engine ({})", e);
+                return None;
+            }
+        },
+        _ => panic!("Unknown agent kind {}", kind),
+    };
+
+    let epsilon = settings["epsilon"].as_f64().unwrap_or(0.);
+    let underpromotion = settings["underpromotion"].as_f64().unwrap_or(0.);
+    let agent: Box<dyn Agent + Send> = if epsilon > 0. || underpromotion > 0. {
+        Box::new(EpsilonGreedyAgent {
+            inner: agent,
+            epsilon,
+            underpromotion,
+        })
+    } else {
+        agent
+    };
+    if settings["book"].as_bool().unwrap_or(false) {
+        return Some(Box::new(BookAgent {
+            repertoire: Repertoire::from_config(config),
+            inner: agent,
+        }));
+    }
+    return Some(agent);
+}
@@ -0,0 +1,622 @@
+/**
+ * Utility module for measuring networks against each other offline, by
+ * playing round-robin tournaments between them. Every pairing plays each
+ * opening from a book of starting positions (FEN or EPD, one per line) once
+ * with each color, so neither network is favored by the openings it gets.
+ * Each player can be given its own search limit, for time or node odds.
+ * Results are reported as per-pairing scores, a crosstable and Elo ratings
+ * fitted to all the games. Two networks can also be compared head to head
+ * over a seeded set of openings, with results reported per pair of games.
+ * Any player can instead be one of the scripted opponents, named by
+ * "random", "greedy_capture", "mate_blocker" or "handcrafted", as a baseline,
+ * or an external UCI engine, e.g. "engine:skill=3,nodes=5000".
+ *
+ * A gauntlet compares the current network against the promoted checkpoint, or
+ * the latest one if none was promoted, and saves and promotes the current
+ * network as a new checkpoint only if it scores at least the "promote_score"
+ * of the "gauntlet" object in config.json, e.g. {"promote_score": 0.55}.
+ * Otherwise the current network is kept among the rejected checkpoints for
+ * analysis (see checkpoint). With "after_training": true, e.g.
+ * {"promote_score": 0.55, "after_training": true, "openings": 4, "plies": 6,
+ *  "seed": 0}, a short gauntlet from 4 seeded openings of 6 plies gates
+ * promotion at the end of every train command and self-play run, so that the
+ * network serving games is only ever replaced by a better one.
+ */
+use crate::agent::{Agent, ExternalUciAgent, PolicyAgent};
+use crate::checkpoint::{read_metadata, CheckpointManager};
+use crate::error::BotResult;
+use crate::eval::EvalWeights;
+use crate::game_context::GameContext;
+use crate::history::MoveCounters;
+use crate::limits::{parse_limit, SearchLimit};
+use crate::models::{read_network, white_path};
+use crate::normalization::read_stats;
+use crate::sampling::seeded_openings;
+use crate::scripted::scripted_agent;
+use crate::uci_engine::UciEngine;
+
+use chess::{Board, BoardStatus, Color};
+use serde_json::Value;
+use std::fs;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+// Number of moves by each side after which a game is adjudicated a draw
+const MAX_MOVES: usize = 150;
+
+// Iterations and step size used when fitting Elo ratings
+const ELO_ITERATIONS: usize = 1000;
+const ELO_STEP: f64 = 10.;
+
+// The results of a pairing in a tournament, from the first player's view
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PairingResult {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+// The results of a head-to-head comparison played as pairs of games, one with
+// each color from the same opening
+#[derive(Clone, Debug, Default)]
+pub struct PairedComparison {
+    pub pair_scores: Vec<f64>, // score of the first player in each pair, 0 to 2
+    pub game_scores: Vec<f64>, // score of the first player in each game
+}
+
+// The results of a round-robin tournament between named players
+#[derive(Clone, Debug)]
+pub struct Tournament {
+    pub players: Vec<String>,
+    pub results: Vec<Vec<PairingResult>>, // results[i][j] is i against j
+}
+
+/**
+ * [player_agent(player)] creates the agent for [player], which is either the
+ * name of a scripted opponent, the name of an external engine or the path of
+ * a saved network, or returns an error if the engine fails to start or the
+ * network cannot be loaded.
+ */
+pub fn player_agent(player: &str) -> BotResult<Box<dyn Agent + Send>> {
+    if let Some(settings) = UciEngine::settings_from_name(player) {
+        let engine = UciEngine::from_config(&settings)?;
+        return Ok(Box::new(ExternalUciAgent { engine }));
+    }
+    return match scripted_agent(player, &EvalWeights::default()) {
+        Some(agent) => Ok(agent),
+        None => {
+            let mut agent = PolicyAgent::new(read_network(player)?, player);
+            agent.stats = read_stats(player)?;
+            Ok(Box::new(agent))
+        }
+    };
+}
+
+/**
+ * [load_openings(path)] loads the starting positions in the book at [path],
+ * one FEN or EPD record per line. Lines starting with '#' are ignored.
+ */
+pub fn load_openings(path: &str) -> Vec<Board> {
+    return load_positions(path).into_iter().map(|(b, _)| b).collect();
+}
+
+/**
+ * [load_positions(path)] loads the positions in the book at [path] like
+ * [load_openings], along with their move counters, those of a game's first
+ * move for EPD records.
+ */
+pub fn load_positions(path: &str) -> Vec<(Board, MoveCounters)> {
+    let contents = fs::read_to_string(path).expect("Unable to read opening book");
+
+    let mut openings = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+
+        // EPD records only have the first 4 fields of a FEN, followed by
+        // operations
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let fen = if fields.len() >= 6 && fields[4].parse::<u32>().is_ok() {
+            fields[..6].join(" ")
+        } else {
+            fields[..4.min(fields.len())].join(" ") + " 0 1"
+        };
+        match Board::from_str(&fen) {
+            Ok(b) => openings.push((b, MoveCounters::from_fen(&fen))),
+            Err(_) => warn!("Skipping invalid opening {}", line),
+        };
+    }
+
+    return openings;
+}
+
+/**
+ * [parse_player(arg)] parses a player given as the path of its network,
+ * optionally followed by @ and its search limit, e.g. policy.flow@nodes=10.
+ */
+pub fn parse_player(arg: &str) -> (String, SearchLimit) {
+    match arg.split_once('@') {
+        Some((path, limit)) => (path.to_string(), parse_limit(limit)),
+        None => (arg.to_string(), SearchLimit::Unlimited),
+    }
+}
+
+/**
+ * [play_match_game(white, black, start)] plays a game from board [start] with
+ * agent [white] as White and agent [black] as Black, each given as the agent
+ * and its search limit. A side that runs out of time loses. Returns the score
+ * of White: 1 for a win, 0.5 for a draw and 0 for a loss.
+ */
+pub fn play_match_game(
+    white: (&mut dyn Agent, SearchLimit),
+    black: (&mut dyn Agent, SearchLimit),
+    start: Board,
+) -> f64 {
+    let (white_agent, white_limit) = white;
+    let (black_agent, black_limit) = black;
+    let mut context = GameContext::new(&start, (white_limit, black_limit));
+
+    for _ in 0..2 * MAX_MOVES {
+        let board = context.board();
+        if board.status() != BoardStatus::Ongoing || context.history.can_declare_draw() {
+            break;
+        }
+
+        let player_white = context.player_white();
+        let agent: &mut dyn Agent = if player_white {
+            &mut *white_agent
+        } else {
+            &mut *black_agent
+        };
+        match agent.select_move(&mut context) {
+            Some(decision) => {
+                if context.clock(player_white).flagged() {
+                    return if player_white { 0. } else { 1. };
+                }
+                context.make_move(decision.chosen);
+            }
+            None => break,
+        };
+    }
+
+    let board = context.board();
+    return match board.status() {
+        BoardStatus::Checkmate if board.side_to_move() == Color::Black => 1.,
+        BoardStatus::Checkmate => 0.,
+        _ => 0.5,
+    };
+}
+
+impl PairingResult {
+    /**
+     * [record(score)] records a game the first player scored [score] in.
+     */
+    pub fn record(&mut self, score: f64) {
+        if score > 0.5 {
+            self.wins += 1;
+        } else if score < 0.5 {
+            self.losses += 1;
+        } else {
+            self.draws += 1;
+        }
+    }
+
+    /**
+     * [games()] returns the number of games played in the pairing.
+     */
+    pub fn games(&self) -> u32 {
+        return self.wins + self.draws + self.losses;
+    }
+
+    /**
+     * [score()] returns the points scored by the first player.
+     */
+    pub fn score(&self) -> f64 {
+        return self.wins as f64 + 0.5 * self.draws as f64;
+    }
+
+    /**
+     * [reversed()] returns the results from the second player's view.
+     */
+    pub fn reversed(&self) -> PairingResult {
+        return PairingResult {
+            wins: self.losses,
+            draws: self.draws,
+            losses: self.wins,
+        };
+    }
+}
+
+/**
+ * [round_robin(players, openings)] plays a round-robin tournament between the
+ * [players], given as network paths or scripted opponents, each playing within its search
+ * limit, where every pairing plays each of [openings] once with each color.
+ * Returns an error if any player cannot be created.
+ */
+pub fn round_robin(players: &[(String, SearchLimit)], openings: &[Board]) -> BotResult<Tournament> {
+    let mut agents: Vec<Box<dyn Agent + Send>> = players
+        .iter()
+        .map(|(p, _)| player_agent(p))
+        .collect::<BotResult<_>>()?;
+    let names: Vec<String> = players
+        .iter()
+        .map(|(p, limit)| match limit {
+            SearchLimit::Unlimited => p.clone(),
+            _ => format!("{} ({:?})", p, limit),
+        })
+        .collect();
+    let n = players.len();
+    let mut results = vec![vec![PairingResult::default(); n]; n];
+
+    for i in 0..n {
+        for j in i + 1..n {
+            // Split the agents so both can be borrowed mutably at once
+            let (left, right) = agents.split_at_mut(j);
+            let (a, b) = (&mut *left[i], &mut *right[0]);
+            let (a_limit, b_limit) = (players[i].1, players[j].1);
+            for opening in openings {
+                let score = play_match_game((&mut *a, a_limit), (&mut *b, b_limit), *opening);
+                results[i][j].record(score);
+                let score = play_match_game((&mut *b, b_limit), (&mut *a, a_limit), *opening);
+                results[i][j].record(1. - score);
+            }
+            results[j][i] = results[i][j].reversed();
+
+            let r = results[i][j];
+            println!(
+                "{} vs {}: +{} ={} -{}",
+                names[i], names[j], r.wins, r.draws, r.losses
+            );
+        }
+    }
+
+    return Ok(Tournament {
+        players: names,
+        results,
+    });
+}
+
+impl Tournament {
+    /**
+     * [elo()] fits Elo ratings to the results of the tournament by gradient
+     * ascent on their likelihood, centered so the average rating is 0.
+     */
+    pub fn elo(&self) -> Vec<f64> {
+        let n = self.players.len();
+        let mut ratings = vec![0.; n];
+        for _ in 0..ELO_ITERATIONS {
+            let mut gradient = vec![0.; n];
+            for i in 0..n {
+                for j in 0..n {
+                    let r = self.results[i][j];
+                    if i == j || r.games() == 0 {
+                        continue;
+                    }
+                    let expected = 1. / (1. + 10f64.powf((ratings[j] - ratings[i]) / 400.));
+                    gradient[i] += (r.score() - expected * r.games() as f64) / r.games() as f64;
+                }
+            }
+            for i in 0..n {
+                ratings[i] += ELO_STEP * gradient[i];
+            }
+
+            let mean = ratings.iter().sum::<f64>() / n as f64;
+            ratings = ratings.iter().map(|r| r - mean).collect();
+        }
+
+        return ratings;
+    }
+
+    /**
+     * [crosstable()] formats the tournament as a crosstable, with each row
+     * giving a player's Elo, total score and score against each opponent,
+     * sorted by Elo.
+     */
+    pub fn crosstable(&self) -> String {
+        let n = self.players.len();
+        let elo = self.elo();
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by(|a, b| elo[*b].partial_cmp(&elo[*a]).unwrap());
+
+        let mut table = format!("{:>3} {:<30} {:>6} {:>7}", "#", "Player", "Elo", "Score");
+        for k in 0..n {
+            table += &format!(" {:>7}", k + 1);
+        }
+        table += "\n";
+
+        for (rank, i) in order.iter().enumerate() {
+            let total: f64 = self.results[*i].iter().map(|r| r.score()).sum();
+            let games: u32 = self.results[*i].iter().map(|r| r.games()).sum();
+            table += &format!(
+                "{:>3} {:<30} {:>6.0} {:>7}",
+                rank + 1,
+                self.players[*i],
+                elo[*i],
+                format!("{}/{}", total, games)
+            );
+            for j in &order {
+                let cell = if i == j {
+                    "-".to_string()
+                } else {
+                    format!("{}", self.results[*i][*j].score())
+                };
+                table += &format!(" {:>7}", cell);
+            }
+            table += "\n";
+        }
+
+        return table;
+    }
+}
+
+/**
+ * [compare(first, second, openings)] plays the players [first] and [second],
+ * given as network paths or scripted opponents, each within its search limit, against each other from
+ * every one of [openings] once with each color, printing the result of each
+ * pair of games. Returns an error if either player cannot be created.
+ */
+pub fn compare(
+    first: &(String, SearchLimit),
+    second: &(String, SearchLimit),
+    openings: &[Board],
+) -> BotResult<PairedComparison> {
+    let mut a = player_agent(&first.0)?;
+    let mut b = player_agent(&second.0)?;
+
+    let mut comparison = PairedComparison::default();
+    for (i, opening) in openings.iter().enumerate() {
+        let as_white = play_match_game((&mut *a, first.1), (&mut *b, second.1), *opening);
+        let as_black = 1. - play_match_game((&mut *b, second.1), (&mut *a, first.1), *opening);
+        println!(
+            "Opening {} ({}): {} as White, {} as Black",
+            i + 1,
+            opening,
+            as_white,
+            as_black
+        );
+        comparison.pair_scores.push(as_white + as_black);
+        comparison.game_scores.extend([as_white, as_black]);
+    }
+
+    return Ok(comparison);
+}
+
+impl PairedComparison {
+    /**
+     * [score()] returns the fraction of the points scored by the first
+     * player.
+     */
+    pub fn score(&self) -> f64 {
+        let total: f64 = self.pair_scores.iter().sum();
+        return total / (2 * self.pair_scores.len()).max(1) as f64;
+    }
+
+    /**
+     * [pentanomial()] returns how many pairs the first player scored 0, 0.5,
+     * 1, 1.5 and 2 points in.
+     */
+    pub fn pentanomial(&self) -> [usize; 5] {
+        let mut counts = [0; 5];
+        for s in &self.pair_scores {
+            counts[(s * 2.).round() as usize] += 1;
+        }
+        return counts;
+    }
+
+    /**
+     * [wdl()] returns how many games the first player won, drew and lost.
+     */
+    pub fn wdl(&self) -> (usize, usize, usize) {
+        let count = |score: f64| self.game_scores.iter().filter(|s| **s == score).count();
+        return (count(1.), count(0.5), count(0.));
+    }
+
+    /**
+     * [elo_difference()] returns the Elo difference between the first and
+     * second player implied by the score, or None if either player scored
+     * every point.
+     */
+    pub fn elo_difference(&self) -> Option<f64> {
+        let score = self.score();
+        if score <= 0. || score >= 1. {
+            return None;
+        }
+        return Some(-400. * (1. / score - 1.).log10());
+    }
+}
+
+// Default score the current network needs against the baseline checkpoint to
+// be promoted
+const DEFAULT_PROMOTE_SCORE: f64 = 0.55;
+
+// Default number and length of the openings of the gauntlet after training
+const DEFAULT_GATE_OPENINGS: u64 = 4;
+const DEFAULT_GATE_PLIES: u64 = 6;
+
+/**
+ * [gauntlet(config, baseline, openings)] plays the current network given by
+ * the parsed [config] against the checkpoint at [baseline], or else the
+ * promoted or latest checkpoint, from each of [openings] with both colors.
+ * If the current network scores at least the promotion score it is saved as a
+ * new checkpoint and promoted, and otherwise kept as a rejected checkpoint.
+ * Returns the comparison along with the path of the checkpoint promoted, or
+ * None if there is no checkpoint to play against, or an error if a network
+ * cannot be loaded or saved.
+ */
+pub fn gauntlet(
+    config: &Value,
+    baseline: Option<&str>,
+    openings: &[Board],
+) -> BotResult<Option<(PairedComparison, Option<String>)>> {
+    let checkpoints = CheckpointManager::from_config(config);
+    let baseline = match baseline.map(|path| path.to_string()).or_else(|| {
+        checkpoints
+            .promoted()
+            .or_else(|| checkpoints.list().pop().map(|(_, path)| path))
+    }) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let current = white_path(config);
+    info!("Gauntlet: {} against {}", current, baseline);
+
+    let comparison = compare(
+        &(current.clone(), SearchLimit::Unlimited),
+        &(baseline, SearchLimit::Unlimited),
+        openings,
+    )?;
+    let promote_score = config["gauntlet"]["promote_score"]
+        .as_f64()
+        .unwrap_or(DEFAULT_PROMOTE_SCORE);
+    let mut metadata = read_metadata(&current);
+    metadata.score = Some(comparison.score());
+    if comparison.score() < promote_score {
+        let (nn, stats) = (read_network(&current)?, read_stats(&current)?);
+        if let Some(path) = checkpoints.save_rejected(&nn, &stats, &metadata)? {
+            info!("Kept rejected network as {}", path);
+        }
+        return Ok(Some((comparison, None)));
+    }
+
+    let (nn, stats) = (read_network(&current)?, read_stats(&current)?);
+    let path = checkpoints.save(&nn, &stats, &metadata)?;
+    if let Err(e) = checkpoints.promote(&path) {
+        warn!("Unable to promote {}: {}", path, e);
+        return Ok(Some((comparison, None)));
+    }
+    return Ok(Some((comparison, Some(path))));
+}
+
+/**
+ * [promote_first_baseline(config, current)] saves the network at [current] as
+ * a checkpoint given by the parsed [config] and promotes it, as the baseline
+ * of later gauntlets when there is no checkpoint yet. Returns the path of the
+ * checkpoint promoted, or None if it could not be.
+ */
+pub fn promote_first_baseline(config: &Value, current: &str) -> Option<String> {
+    let checkpoints = CheckpointManager::from_config(config);
+    let saved = read_network(current).and_then(|nn| {
+        let stats = read_stats(current)?;
+        return checkpoints.save(&nn, &stats, &read_metadata(current));
+    });
+    let path = match saved {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("Unable to save {} as a checkpoint: {}", current, e);
+            return None;
+        }
+    };
+    return match checkpoints.promote(&path) {
+        Ok(()) => {
+            info!("Promoted {} as the first baseline", path);
+            Some(path)
+        }
+        Err(e) => {
+            warn!("Unable to promote {}: {}", path, e);
+            None
+        }
+    };
+}
+
+/**
+ * [gate_after_training(config)] plays the short gauntlet after training if
+ * the parsed [config] asks for it, promoting the current network only if it
+ * beats the promoted checkpoint, or as the first baseline if there is none.
+ */
+pub fn gate_after_training(config: &Value) {
+    let settings = &config["gauntlet"];
+    if !settings["after_training"].as_bool().unwrap_or(false) {
+        return;
+    }
+
+    let openings = seeded_openings(
+        settings["openings"]
+            .as_u64()
+            .unwrap_or(DEFAULT_GATE_OPENINGS) as usize,
+        settings["plies"].as_u64().unwrap_or(DEFAULT_GATE_PLIES) as usize,
+        settings["seed"].as_u64().unwrap_or(0),
+    );
+    match gauntlet(config, None, &openings) {
+        Ok(Some((comparison, Some(path)))) => {
+            println!("Scored {:.3}, promoted {}", comparison.score(), path)
+        }
+        Ok(Some((comparison, None))) => {
+            println!("Scored {:.3}, not promoted", comparison.score())
+        }
+        Ok(None) => {
+            promote_first_baseline(config, &white_path(config));
+        }
+        Err(e) => warn!("Unable to play the gauntlet: {}", e),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * [tournament(n, scores)] returns a tournament between [n] players named
+     * after their index, where [scores] gives the games each pair played, as
+     * (i, j, score of i).
+     */
+    fn tournament(n: usize, scores: &[(usize, usize, f64)]) -> Tournament {
+        let mut results = vec![vec![PairingResult::default(); n]; n];
+        for (i, j, score) in scores {
+            results[*i][*j].record(*score);
+            results[*j][*i].record(1. - score);
+        }
+        return Tournament {
+            players: (0..n).map(|i| i.to_string()).collect(),
+            results,
+        };
+    }
+
+    #[test]
+    fn elo_fit_matches_the_expected_score() {
+        // Scoring 75% is worth 400 * log10(3) Elo
+        let t = tournament(2, &[(0, 1, 1.), (0, 1, 1.), (0, 1, 0.5), (0, 1, 0.5)]);
+        assert_eq!(t.results[1][0].losses, 2);
+        let elo = t.elo();
+        assert!((elo[0] - elo[1] - 400. * 3f64.log10()).abs() < 0.5);
+        assert!((elo[0] + elo[1]).abs() < 1e-9);
+
+        // Players with even results are rated evenly, around 0
+        let t = tournament(3, &[(0, 1, 1.), (1, 2, 1.), (2, 0, 1.)]);
+        assert!(t.elo().iter().all(|r| r.abs() < 1e-9));
+    }
+
+    #[test]
+    fn crosstable_is_sorted_by_elo() {
+        let t = tournament(3, &[(2, 0, 1.), (2, 1, 0.5), (1, 0, 1.), (1, 0, 0.5)]);
+        let table = t.crosstable();
+        let rows: Vec<&str> = table.lines().skip(1).collect();
+        let names: Vec<&str> = rows
+            .iter()
+            .map(|row| row.split_whitespace().nth(1).unwrap())
+            .collect();
+        assert_eq!(names, vec!["2", "1", "0"]);
+        assert!(rows[0].contains("1.5/2"));
+        assert!(rows[2].contains("0.5/3"));
+    }
+
+    #[test]
+    fn paired_comparisons_count_pairs_and_games() {
+        let comparison = PairedComparison {
+            pair_scores: vec![2., 1.5, 1., 0.5],
+            game_scores: vec![1., 1., 1., 0.5, 0.5, 0.5, 0.5, 0.],
+        };
+        assert_eq!(comparison.score(), 0.625);
+        assert_eq!(comparison.pentanomial(), [0, 1, 1, 1, 1]);
+        assert_eq!(comparison.wdl(), (3, 4, 1));
+        let elo = comparison.elo_difference().unwrap();
+        assert!((elo - 400. * (5f64 / 3.).log10()).abs() < 1e-9);
+
+        let sweep = PairedComparison {
+            pair_scores: vec![2.],
+            game_scores: vec![1., 1.],
+        };
+        assert_eq!(sweep.elo_difference(), None);
+    }
+}
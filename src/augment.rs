@@ -0,0 +1,110 @@
+/**
+ * Utility module for augmenting experiences with their mirror images, with
+ * the files of the board flipped from a to h, which are worth exactly the
+ * same since chess is symmetric between the queenside and the kingside once
+ * castling is no longer possible. Experiences of positions where either side
+ * may still castle have no mirror image. Learning fits the mirror image of
+ * every experience it draws alongside it when "mirror" is set in the
+ * "replay" object of config.json, e.g. {"mirror": true}, so the same games
+ * teach the network twice as many positions.
+ */
+use crate::mdp::{
+    Experience, ExperienceMeta, BOARD_DIM, HISTORY_DIM, HISTORY_POSITIONS, PIECE_DIM,
+};
+
+use chess::{Board, CastleRights, Color};
+use std::str::FromStr;
+
+// Number of leading planes of the state vector, which hold the pieces and the
+// en passant square, of the history features that follow, which hold the
+// pieces of earlier positions, and of the action vector, which hold its
+// squares
+const STATE_PLANES: usize = (PIECE_DIM + 64) / 64;
+const HISTORY_PLANES: usize = HISTORY_POSITIONS * PIECE_DIM / 64;
+const ACTION_PLANES: usize = 2;
+
+/**
+ * [mirror_planes(v, planes)] returns [v] with the files of its first [planes]
+ * planes of 64 squares flipped, and the rest of its features kept.
+ */
+fn mirror_planes(v: &[f64], planes: usize) -> Vec<f64> {
+    let mut mirrored = v.to_vec();
+    for i in 0..(planes * 64).min(v.len()) {
+        let (plane, rank, file) = (i / 64, (i % 64) / 8, i % 8);
+        mirrored[plane * 64 + rank * 8 + (7 - file)] = v[i];
+    }
+    return mirrored;
+}
+
+/**
+ * [mirror_state(state)] returns the state vector [state] with the files of its
+ * planes flipped, including those of the positions before it.
+ */
+fn mirror_state(state: &[f64]) -> Vec<f64> {
+    let mut mirrored = mirror_planes(state, STATE_PLANES);
+    if HISTORY_DIM > 0 {
+        let history = mirror_planes(&state[BOARD_DIM..], HISTORY_PLANES);
+        mirrored[BOARD_DIM..].copy_from_slice(&history);
+    }
+    return mirrored;
+}
+
+/**
+ * [can_castle(state)] returns whether either side may still castle in the
+ * state vector [state].
+ */
+fn can_castle(state: &[f64]) -> bool {
+    return state[PIECE_DIM + 64..BOARD_DIM - 1]
+        .iter()
+        .any(|r| *r != 0.);
+}
+
+/**
+ * [mirror_board(b)] returns board [b] with its files flipped, or None if
+ * either side may still castle in it.
+ */
+pub fn mirror_board(b: &Board) -> Option<Board> {
+    for color in [Color::White, Color::Black] {
+        if b.castle_rights(color) != CastleRights::NoRights {
+            return None;
+        }
+    }
+
+    // Flip each rank of the placement and the file of the en passant square
+    let fen = b.to_string();
+    let mut fields: Vec<String> = fen.split(' ').map(|f| f.to_string()).collect();
+    fields[0] = fields[0]
+        .split('/')
+        .map(|rank| rank.chars().rev().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("/");
+    if fields.len() > 3 && fields[3] != "-" {
+        let mut square = fields[3].chars();
+        let file = square.next()?;
+        let mirrored = (b'a' + b'h' - file as u8) as char;
+        fields[3] = format!("{}{}", mirrored, square.as_str());
+    }
+    return Board::from_str(&fields.join(" ")).ok();
+}
+
+/**
+ * [mirror_experience(e)] returns experience [e] with the files of its states,
+ * action and next board flipped, or None if either side may still castle
+ * before or after it.
+ */
+pub fn mirror_experience(e: &Experience) -> Option<Experience> {
+    if can_castle(&e.state) || can_castle(&e.next_state) {
+        return None;
+    }
+    return Some(Experience {
+        state: mirror_state(&e.state),
+        action: mirror_planes(&e.action, ACTION_PLANES),
+        next_state: mirror_state(&e.next_state),
+        next_board: mirror_board(&e.next_board)?,
+        meta: ExperienceMeta {
+            position: None, // the hash is of the unmirrored board
+            ..e.meta.clone()
+        },
+        ..e.clone()
+    });
+}
@@ -0,0 +1,250 @@
+/**
+ * Utility module for backing up the bot's training state to a WebDAV server,
+ * so that a long training run survives the machine it runs on. The policy
+ * networks with their metadata, the checkpoints and the replay shards are
+ * uploaded on a schedule, skipping files unchanged since their last upload,
+ * along with a manifest listing every file backed up, from which a fresh
+ * machine can restore them. Backups are turned on by the "backup" object in
+ * config.json, e.g. {"url": "https://dav.example.com/chess-bot", "username":
+ * "bot", "password": "...", "interval_minutes": 60}.
+ */
+use crate::checkpoint::{metadata_path, CheckpointManager};
+use crate::models::DEFAULT_MODEL_PATH;
+use crate::replay_shards::DEFAULT_SHARD_DIR;
+
+use reqwest::{Method, RequestBuilder};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+const DEFAULT_INTERVAL_MINUTES: u64 = 60;
+
+// Name of the manifest listing every file backed up
+const MANIFEST_FILE: &str = "manifest.json";
+
+// A WebDAV server the training state is backed up to
+pub struct Backup {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub interval: Duration,
+    files: Vec<String>,
+    last_upload: Option<Instant>,
+    uploaded: HashMap<String, (u64, SystemTime)>, // size and modification time
+}
+
+/**
+ * [backed_up_files(config)] returns the local files and directories holding
+ * the training state given by the parsed [config].
+ */
+fn backed_up_files(config: &Value) -> Vec<String> {
+    let models = &config["models"];
+    let mut files = Vec::new();
+    for key in ["white", "black", "endgame"] {
+        let path = match models[key].as_str() {
+            Some(p) => p,
+            None if key.eq("endgame") => continue,
+            None => DEFAULT_MODEL_PATH,
+        };
+        files.push(path.to_string());
+        files.push(metadata_path(path));
+    }
+    files.push(CheckpointManager::from_config(config).dir);
+    files.push(
+        config["replay"]["shard_dir"]
+            .as_str()
+            .unwrap_or(DEFAULT_SHARD_DIR)
+            .to_string(),
+    );
+    files.sort();
+    files.dedup();
+
+    return files;
+}
+
+/**
+ * [expand(paths)] returns every file in [paths], listing the files within any
+ * directories among them.
+ */
+fn expand(paths: &[String]) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in paths {
+        match fs::read_dir(path) {
+            Ok(entries) => {
+                let mut within: Vec<String> = entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path().to_string_lossy().to_string())
+                    .collect();
+                within.sort();
+                files.append(&mut expand(&within));
+            }
+            Err(_) if Path::new(path).is_file() => files.push(path.clone()),
+            Err(_) => (),
+        };
+    }
+
+    return files;
+}
+
+impl Backup {
+    /**
+     * [from_config(config)] reads the backup settings from the parsed
+     * [config], returning None if backups are not turned on.
+     */
+    pub fn from_config(config: &Value) -> Option<Backup> {
+        let settings = &config["backup"];
+        let url = settings["url"].as_str()?;
+        let minutes = settings["interval_minutes"]
+            .as_u64()
+            .unwrap_or(DEFAULT_INTERVAL_MINUTES);
+
+        return Some(Backup {
+            url: url.trim_end_matches('/').to_string(),
+            username: settings["username"].as_str().map(|u| u.to_string()),
+            password: settings["password"].as_str().map(|p| p.to_string()),
+            interval: Duration::from_secs(60 * minutes),
+            files: backed_up_files(config),
+            last_upload: None,
+            uploaded: HashMap::new(),
+        });
+    }
+
+    /**
+     * [remote_url(path)] returns the url the local file at [path] is backed up
+     * to.
+     */
+    fn remote_url(&self, path: &str) -> String {
+        let relative = path.trim_start_matches("./").trim_start_matches('/');
+        return format!("{}/{}", self.url, relative);
+    }
+
+    /**
+     * [request(client, method, url)] builds a request to the WebDAV server
+     * with [method] for [url], authenticated if credentials are set.
+     */
+    fn request(&self, client: &reqwest::Client, method: Method, url: &str) -> RequestBuilder {
+        let request = client.request(method, url);
+        return match &self.username {
+            Some(u) => request.basic_auth(u, self.password.as_ref()),
+            None => request,
+        };
+    }
+
+    /**
+     * [due()] returns whether the next scheduled upload is due.
+     */
+    pub fn due(&self) -> bool {
+        return match self.last_upload {
+            Some(t) => t.elapsed() >= self.interval,
+            None => true,
+        };
+    }
+
+    /**
+     * [upload(client)] uploads every file of the training state changed since
+     * its last upload, followed by the manifest, returning the number of files
+     * uploaded.
+     */
+    pub async fn upload(&mut self, client: &reqwest::Client) -> Result<usize, reqwest::Error> {
+        self.last_upload = Some(Instant::now());
+        let files = expand(&self.files);
+
+        let mut uploaded = 0;
+        for path in &files {
+            let stamp = match fs::metadata(path) {
+                Ok(m) => (m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)),
+                Err(_) => continue,
+            };
+            if self.uploaded.get(path) == Some(&stamp) {
+                continue;
+            }
+            let contents = match fs::read(path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            // Create the collections the file is in, which may already exist
+            let parts: Vec<&str> = path
+                .trim_start_matches("./")
+                .trim_start_matches('/')
+                .split('/')
+                .collect();
+            for i in 1..parts.len() {
+                let url = format!("{}/{}/", self.url, parts[..i].join("/"));
+                let mkcol = Method::from_bytes(b"MKCOL").unwrap();
+                self.request(client, mkcol, &url).send().await?;
+            }
+
+            let url = self.remote_url(path);
+            let res = self
+                .request(client, Method::PUT, &url)
+                .body(contents)
+                .send()
+                .await?;
+            if !res.status().is_success() {
+                warn!("Uploading {} failed with status {}", path, res.status());
+                continue;
+            }
+            self.uploaded.insert(path.clone(), stamp);
+            uploaded += 1;
+        }
+
+        let manifest = json!({ "files": files }).to_string();
+        let url = format!("{}/{}", self.url, MANIFEST_FILE);
+        self.request(client, Method::PUT, &url)
+            .body(manifest)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        return Ok(uploaded);
+    }
+
+    /**
+     * [restore(client)] downloads every file listed in the manifest that does
+     * not exist locally, returning the number of files restored.
+     */
+    pub async fn restore(&self, client: &reqwest::Client) -> Result<usize, reqwest::Error> {
+        let url = format!("{}/{}", self.url, MANIFEST_FILE);
+        let manifest: Value = self
+            .request(client, Method::GET, &url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let files: Vec<String> = match &manifest["files"] {
+            Value::Array(a) => a
+                .iter()
+                .filter_map(|f| f.as_str())
+                .map(|f| f.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut restored = 0;
+        for path in &files {
+            if Path::new(path).exists() {
+                info!("Keeping local {}", path);
+                continue;
+            }
+            let contents = self
+                .request(client, Method::GET, &self.remote_url(path))
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            if let Some(dir) = Path::new(path).parent() {
+                fs::create_dir_all(dir).unwrap();
+            }
+            fs::write(path, &contents).unwrap();
+            restored += 1;
+        }
+
+        return Ok(restored);
+    }
+}
@@ -0,0 +1,385 @@
+/**
+ * Utility module for benchmarking the hot path of move selection: how many
+ * states get_state encodes per second, how many moves move_by_policy selects
+ * per second, and the latency of compute_q_max by the number of legal moves
+ * in the position. Encoding and move selection are timed on a suite of
+ * standard positions, and Q-max latency on those and random legal positions
+ * generated from a seed, all with the configured white network. Each
+ * benchmark is timed over several samples and printed as a table. Given a
+ * directory, e.g. target/criterion, the estimates of each benchmark are also
+ * written there in the layout criterion uses, <dir>/<group>/<function>/new,
+ * and the estimates of the previous run are kept in base and compared
+ * against, so that tools reading criterion output can track regressions.
+ */
+use crate::error::BotResult;
+use crate::history::MoveCounters;
+use crate::mdp::{compute_q_max, get_state, move_by_policy};
+use crate::models::ModelRegistry;
+use crate::search::transposition::TranspositionTable;
+use crate::testing::random_legal_position;
+
+use chess::{Board, BoardStatus, Color, MoveGen};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+// Standard positions the benchmarks are run on: the starting position and
+// the perft test positions, covering castling, promotions and en passant
+const BENCH_FENS: [&str; 6] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+];
+
+// Number of random legal positions added to the suite for Q-max latency, and
+// the longest random game played to reach one
+const RANDOM_POSITIONS: usize = 200;
+const MAX_RANDOM_PLIES: usize = 120;
+
+// Upper bounds of the legal move counts Q-max latency is grouped by, the last
+// group holding every position with more moves
+const MOVE_BUCKETS: [usize; 4] = [10, 20, 30, 40];
+
+// Criterion's estimates are given with 95% confidence intervals
+const CONFIDENCE_LEVEL: f64 = 0.95;
+const Z_95: f64 = 1.96;
+
+// A benchmark timed over several samples, each running [iters] operations in
+// [times] nanoseconds
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    pub group: String,
+    pub function: String,
+    pub iters: Vec<f64>,
+    pub times: Vec<f64>,
+}
+
+// Summary statistics of a benchmark's time per operation, in nanoseconds
+#[derive(Clone, Copy, Debug)]
+pub struct Estimates {
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    pub median_abs_dev: f64,
+}
+
+impl Benchmark {
+    /**
+     * [time(group, function, samples, run)] times [samples] runs of [run],
+     * which returns the number of operations it performed, after one run to
+     * warm up.
+     */
+    fn time(
+        group: &str,
+        function: &str,
+        samples: usize,
+        mut run: impl FnMut() -> usize,
+    ) -> Benchmark {
+        run();
+        let mut benchmark = Benchmark {
+            group: group.to_string(),
+            function: function.to_string(),
+            iters: Vec::new(),
+            times: Vec::new(),
+        };
+        for _ in 0..samples.max(1) {
+            let started = Instant::now();
+            let ops = run();
+            benchmark.times.push(started.elapsed().as_nanos() as f64);
+            benchmark.iters.push(ops.max(1) as f64);
+        }
+        return benchmark;
+    }
+
+    /**
+     * [id()] returns the id of the benchmark, its group and function.
+     */
+    pub fn id(&self) -> String {
+        return format!("{}/{}", self.group, self.function);
+    }
+
+    /**
+     * [estimates()] returns the statistics of the benchmark's time per
+     * operation over its samples.
+     */
+    pub fn estimates(&self) -> Estimates {
+        let per_op: Vec<f64> = self
+            .times
+            .iter()
+            .zip(&self.iters)
+            .map(|(t, n)| t / n)
+            .collect();
+        let n = per_op.len() as f64;
+        let mean = per_op.iter().sum::<f64>() / n;
+        let variance = per_op.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.).max(1.);
+        let middle = median(per_op.clone());
+        let deviations = per_op.iter().map(|x| (x - middle).abs()).collect();
+        return Estimates {
+            mean,
+            median: middle,
+            std_dev: variance.sqrt(),
+            median_abs_dev: median(deviations),
+        };
+    }
+}
+
+/**
+ * [median(values)] returns the median of [values], which must not be empty.
+ */
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        return (values[mid - 1] + values[mid]) / 2.;
+    }
+    return values[mid];
+}
+
+/**
+ * [bench_positions()] returns the suite of standard positions.
+ */
+fn bench_positions() -> Vec<Board> {
+    return BENCH_FENS
+        .iter()
+        .map(|fen| Board::from_str(fen).unwrap())
+        .collect();
+}
+
+/**
+ * [bucket_label(moves)] returns the label of the group of positions with
+ * [moves] legal moves.
+ */
+fn bucket_label(moves: usize) -> String {
+    let mut low = 1;
+    for high in MOVE_BUCKETS {
+        if moves <= high {
+            return format!("moves_{}-{}", low, high);
+        }
+        low = high + 1;
+    }
+    return format!("moves_{}+", low);
+}
+
+/**
+ * [run_bench(config, samples, iterations, seed)] times [samples] samples of
+ * each benchmark, each passing [iterations] times over its positions, with
+ * the white network given by the parsed [config] and random positions
+ * generated from [seed]. Returns the benchmarks in the order they ran, or an
+ * error if the network cannot be loaded.
+ */
+pub fn run_bench(
+    config: &Value,
+    samples: usize,
+    iterations: usize,
+    seed: u64,
+) -> BotResult<Vec<Benchmark>> {
+    let iterations = iterations.max(1);
+    let mut models = ModelRegistry::from_config(config)?;
+    let nn = &mut models.head(true);
+    let table = &mut TranspositionTable::default();
+    let suite = bench_positions();
+    let mut benchmarks = Vec::new();
+
+    // Positions are timed as if reached at the start of a game, since their
+    // move counters do not change how long they take to score
+    let counters = MoveCounters::default();
+
+    benchmarks.push(Benchmark::time("encoding", "get_state", samples, || {
+        for _ in 0..iterations {
+            for b in &suite {
+                get_state(b, counters, b.side_to_move() == Color::White);
+            }
+        }
+        return iterations * suite.len();
+    }));
+
+    // The table is cleared before every selection so that each one evaluates
+    // its position instead of reading the previous evaluation back
+    benchmarks.push(Benchmark::time(
+        "selection",
+        "move_by_policy",
+        samples,
+        || {
+            for _ in 0..iterations {
+                for b in &suite {
+                    table.clear();
+                    move_by_policy(nn, b, counters, b.side_to_move() == Color::White, table);
+                }
+            }
+            return iterations * suite.len();
+        },
+    ));
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut positions = suite.clone();
+    positions
+        .extend((0..RANDOM_POSITIONS).map(|_| random_legal_position(&mut rng, MAX_RANDOM_PLIES)));
+    positions.retain(|b| b.status() == BoardStatus::Ongoing);
+    positions.sort_by_key(|b| MoveGen::new_legal(b).len());
+    let mut buckets: Vec<(String, Vec<Board>)> = Vec::new();
+    for b in positions {
+        let label = bucket_label(MoveGen::new_legal(&b).len());
+        match buckets.last_mut() {
+            Some((l, boards)) if *l == label => boards.push(b),
+            _ => buckets.push((label, vec![b])),
+        };
+    }
+    for (label, boards) in &buckets {
+        benchmarks.push(Benchmark::time("q_max", label, samples, || {
+            for _ in 0..iterations {
+                for b in boards {
+                    let player_white = b.side_to_move() == Color::White;
+                    let state = get_state(b, counters, player_white);
+                    table.clear();
+                    compute_q_max(b, &state, nn, player_white, table);
+                }
+            }
+            return iterations * boards.len();
+        }));
+    }
+
+    return Ok(benchmarks);
+}
+
+/**
+ * [format_time(nanos)] formats a duration of [nanos] nanoseconds in the
+ * largest unit it is at least one of.
+ */
+fn format_time(nanos: f64) -> String {
+    if nanos >= 1e9 {
+        return format!("{:.2} s", nanos / 1e9);
+    } else if nanos >= 1e6 {
+        return format!("{:.2} ms", nanos / 1e6);
+    } else if nanos >= 1e3 {
+        return format!("{:.2} µs", nanos / 1e3);
+    }
+    return format!("{:.0} ns", nanos);
+}
+
+/**
+ * [bench_table(benchmarks, baselines)] returns a table of the time per
+ * operation and throughput of every benchmark in [benchmarks], along with the
+ * change of its mean time from its entry in [baselines], if any.
+ */
+pub fn bench_table(benchmarks: &[Benchmark], baselines: &[Option<Estimates>]) -> String {
+    let mut table = format!(
+        "{:<32} {:>11} {:>11} {:>14} {:>9}\n",
+        "Benchmark", "Mean", "Std dev", "Per second", "Change"
+    );
+    for (i, benchmark) in benchmarks.iter().enumerate() {
+        let estimates = benchmark.estimates();
+        let change = match baselines.get(i).copied().flatten() {
+            Some(base) => format!("{:+.1}%", (estimates.mean / base.mean - 1.) * 100.),
+            None => "-".to_string(),
+        };
+        table += &format!(
+            "{:<32} {:>11} {:>11} {:>14.0} {:>9}\n",
+            benchmark.id(),
+            format_time(estimates.mean),
+            format_time(estimates.std_dev),
+            1e9 / estimates.mean,
+            change
+        );
+    }
+    return table;
+}
+
+/**
+ * [estimate_json(point, standard_error)] returns a criterion estimate of
+ * [point] with [standard_error].
+ */
+fn estimate_json(point: f64, standard_error: f64) -> Value {
+    return json!({
+        "confidence_interval": {
+            "confidence_level": CONFIDENCE_LEVEL,
+            "lower_bound": point - Z_95 * standard_error,
+            "upper_bound": point + Z_95 * standard_error,
+        },
+        "point_estimate": point,
+        "standard_error": standard_error,
+    });
+}
+
+/**
+ * [estimates_json(benchmark)] returns the estimates of [benchmark] the way
+ * criterion writes them to estimates.json, with the standard errors of their
+ * normal approximations rather than bootstrapped ones.
+ */
+fn estimates_json(benchmark: &Benchmark) -> Value {
+    let estimates = benchmark.estimates();
+    let n = benchmark.times.len() as f64;
+    let mean_error = estimates.std_dev / n.sqrt();
+    let spread_error = estimates.std_dev / (2. * (n - 1.).max(1.)).sqrt();
+    return json!({
+        "mean": estimate_json(estimates.mean, mean_error),
+        "median": estimate_json(estimates.median, 1.2533 * mean_error),
+        "median_abs_dev": estimate_json(estimates.median_abs_dev, spread_error),
+        "slope": null,
+        "std_dev": estimate_json(estimates.std_dev, spread_error),
+    });
+}
+
+/**
+ * [read_estimates(path)] reads the mean and spread of the estimates.json file
+ * at [path], or None if there is none.
+ */
+fn read_estimates(path: &Path) -> Option<Estimates> {
+    let json: Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let point = |key: &str| json[key]["point_estimate"].as_f64();
+    return Some(Estimates {
+        mean: point("mean")?,
+        median: point("median")?,
+        std_dev: point("std_dev")?,
+        median_abs_dev: point("median_abs_dev")?,
+    });
+}
+
+/**
+ * [write_criterion(dir, benchmark)] writes [benchmark] to directory [dir] in
+ * the layout criterion uses, first moving the estimates of the previous run
+ * to the baseline. Returns the estimates of the previous run, if any.
+ */
+pub fn write_criterion(dir: &str, benchmark: &Benchmark) -> BotResult<Option<Estimates>> {
+    let bench_dir = Path::new(dir)
+        .join(&benchmark.group)
+        .join(&benchmark.function);
+    let new_dir = bench_dir.join("new");
+    let base_dir = bench_dir.join("base");
+    if new_dir.exists() {
+        if base_dir.exists() {
+            fs::remove_dir_all(&base_dir)?;
+        }
+        fs::rename(&new_dir, &base_dir)?;
+    }
+    fs::create_dir_all(&new_dir)?;
+
+    let id = benchmark.id();
+    let description = json!({
+        "group_id": benchmark.group,
+        "function_id": benchmark.function,
+        "value_str": null,
+        "throughput": null,
+        "full_id": id,
+        "directory_name": id,
+        "title": id,
+    });
+    let sample = json!({
+        "sampling_mode": "Flat",
+        "iters": benchmark.iters,
+        "times": benchmark.times,
+    });
+    fs::write(new_dir.join("benchmark.json"), description.to_string())?;
+    fs::write(new_dir.join("sample.json"), sample.to_string())?;
+    fs::write(
+        new_dir.join("estimates.json"),
+        estimates_json(benchmark).to_string(),
+    )?;
+    return Ok(read_estimates(&base_dir.join("estimates.json")));
+}
@@ -0,0 +1,13 @@
+/**
+ * Binary for playing: the Lichess daemon, single Lichess games, UCI mode and
+ * analysis of positions. Experiences are stored in the replay buffer for
+ * chessbot-train to learn from, and the daemon plays with the checkpoint it
+ * last promoted.
+ */
+use rust_chess_bot::cli::{run, Role};
+use rust_chess_bot::error::BotResult;
+
+#[tokio::main]
+async fn main() -> BotResult<()> {
+    return run(Role::Play).await;
+}
@@ -0,0 +1,12 @@
+/**
+ * Binary for training: self-play, learning passes over the replay buffer that
+ * chessbot-play fills, promoting checkpoints for it to play with, and
+ * managing networks and runs.
+ */
+use rust_chess_bot::cli::{run, Role};
+use rust_chess_bot::error::BotResult;
+
+#[tokio::main]
+async fn main() -> BotResult<()> {
+    return run(Role::Train).await;
+}
@@ -0,0 +1,152 @@
+/**
+ * Utility module for broadcasting the bot's evaluation and principal variation
+ * while it plays, either to the spectator chat of the game or to an annotation
+ * file with one line per bot move. Configured through the "broadcast" object
+ * in config.json, e.g. {"target": "chat", "interval_secs": 30, "pv_length": 4}.
+ */
+use crate::error::BotResult;
+use crate::history::MoveCounters;
+use crate::lichess::LichessClient;
+use crate::mdp::principal_variation;
+use crate::notation::line_to_san;
+use crate::q_function::QFunction;
+
+use chess::{Board, ChessMove};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+pub const ANNOTATION_DIR: &str = "annotations";
+
+// Lichess rejects chat lines longer than this
+const MAX_CHAT_LEN: usize = 140;
+
+// Where the evaluations get broadcast to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BroadcastTarget {
+    Off,
+    Chat,
+    File,
+}
+
+// Broadcasting state for a single game
+pub struct Broadcaster {
+    pub target: BroadcastTarget,
+    pub interval: Duration,
+    pub pv_length: usize,
+    game_id: String,
+    last_sent: Option<Instant>,
+}
+
+/**
+ * [chat_message(b, score, line)] returns the chat message with evaluation
+ * [score] of board [b] and its principal variation [line], cut to the length
+ * Lichess accepts.
+ */
+fn chat_message(b: &Board, score: f64, line: &[ChessMove]) -> String {
+    let line_san = line_to_san(b, line);
+    let mut text = format!("Eval {:.3}, PV: {}", score, line_san.join(" "));
+    text.truncate(MAX_CHAT_LEN);
+    return text;
+}
+
+impl Broadcaster {
+    /**
+     * [from_config(config, game_id)] creates the broadcaster for game
+     * [game_id] from the parsed [config]. Broadcasting is off unless
+     * configured.
+     */
+    pub fn from_config(config: &Value, game_id: &str) -> Broadcaster {
+        let settings = &config["broadcast"];
+        let target = match &settings["target"] {
+            Value::String(s) if s.eq("chat") => BroadcastTarget::Chat,
+            Value::String(s) if s.eq("file") => BroadcastTarget::File,
+            Value::String(s) if s.eq("off") => BroadcastTarget::Off,
+            Value::Null => BroadcastTarget::Off,
+            _ => panic!("Invalid broadcast target"),
+        };
+
+        return Broadcaster {
+            target,
+            interval: Duration::from_secs(settings["interval_secs"].as_u64().unwrap_or(30)),
+            pv_length: settings["pv_length"].as_u64().unwrap_or(4) as usize,
+            game_id: game_id.to_string(),
+            last_sent: None,
+        };
+    }
+
+    /**
+     * [chat_text(b, counters, nn, player_white)] returns the chat message
+     * with the evaluation by policy network [nn] of board [b], reached with
+     * move [counters], and its principal variation depending on whether the
+     * player is white, or None if there are no legal moves.
+     */
+    pub fn chat_text<Q: QFunction + ?Sized>(
+        &self,
+        b: &Board,
+        counters: MoveCounters,
+        nn: &mut Q,
+        player_white: bool,
+    ) -> Option<String> {
+        let (score, line) = principal_variation(nn, b, counters, player_white, self.pv_length)?;
+        return Some(chat_message(b, score, &line));
+    }
+
+    /**
+     * [broadcast(lichess, ply, b, counters, nn, player_white)] broadcasts the
+     * evaluation by policy network [nn] of board [b], reached with move
+     * [counters], in which the bot plays its move at [ply], depending on
+     * whether the player is white. Chat
+     * messages are skipped if one was sent within the configured interval.
+     */
+    pub async fn broadcast<Q: QFunction + ?Sized>(
+        &mut self,
+        lichess: &LichessClient,
+        ply: usize,
+        b: &Board,
+        counters: MoveCounters,
+        nn: &mut Q,
+        player_white: bool,
+    ) -> BotResult<()> {
+        if self.target == BroadcastTarget::Off {
+            return Ok(());
+        }
+        if self.target == BroadcastTarget::Chat {
+            if let Some(t) = self.last_sent {
+                if t.elapsed() < self.interval {
+                    return Ok(());
+                }
+            }
+        }
+
+        let (score, line) = match principal_variation(nn, b, counters, player_white, self.pv_length)
+        {
+            Some(pv) => pv,
+            None => return Ok(()),
+        };
+        let line_str: Vec<String> = line.iter().map(|m| m.to_string()).collect();
+
+        match self.target {
+            BroadcastTarget::Chat => {
+                let text = chat_message(b, score, &line);
+                lichess.chat(&self.game_id, "spectator", &text).await?;
+                self.last_sent = Some(Instant::now());
+            }
+            BroadcastTarget::File => {
+                // Annotations are keyed by ply so they line up with the game's moves
+                fs::create_dir_all(ANNOTATION_DIR).unwrap();
+                let path = format!("{}/{}.txt", ANNOTATION_DIR, self.game_id);
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .unwrap();
+                writeln!(file, "{} {:.3} {}", ply, score, line_str.join(" ")).unwrap();
+            }
+            BroadcastTarget::Off => (),
+        };
+
+        return Ok(());
+    }
+}
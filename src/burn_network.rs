@@ -0,0 +1,200 @@
+/**
+ * Utility module for a value function backed by burn, built only with the
+ * "burn" feature. The network is a multilayer perceptron of the architecture
+ * given by the "model" object in config.json, run on the CPU with burn's
+ * ndarray backend and trained on whole minibatches at once with Adam at the
+ * learning rate of the "q_function" object. Networks are saved in burn's
+ * binary format, with the extension of their path replaced by .bin, along
+ * with their architecture in the usual metadata sidecar file.
+ */
+use crate::checkpoint::{read_metadata, write_metadata};
+use crate::error::{BotError, BotResult};
+use crate::model::{Architecture, ModelBuilder};
+use crate::q_function::QFunction;
+
+use burn::backend::ndarray::NdArrayDevice;
+use burn::backend::{Autodiff, NdArray};
+use burn::module::Module;
+use burn::nn::loss::{MseLoss, Reduction};
+use burn::nn::{Linear, LinearConfig};
+use burn::optim::adaptor::OptimizerAdaptor;
+use burn::optim::{Adam, AdamConfig, GradientsParams, Optimizer};
+use burn::record::{BinFileRecorder, FullPrecisionSettings};
+use burn::tensor::backend::Backend;
+use burn::tensor::{activation, Tensor, TensorData};
+use serde_json::Value;
+use std::path::Path;
+
+// Default learning rate of Adam
+const DEFAULT_LEARNING_RATE: f64 = 0.001;
+
+// The backend networks are trained on
+type TrainBackend = Autodiff<NdArray<f32>>;
+
+// The layers of a multilayer perceptron with a single linear output
+#[derive(Module, Debug)]
+pub struct Mlp<B: Backend> {
+    hidden: Vec<Linear<B>>,
+    output: Linear<B>,
+}
+
+// A burn network along with its optimizer and architecture
+pub struct BurnNetwork {
+    model: Option<Mlp<TrainBackend>>, // only None while being trained
+    optimizer: OptimizerAdaptor<Adam, Mlp<TrainBackend>, TrainBackend>,
+    pub architecture: Architecture,
+    pub learning_rate: f64,
+    device: NdArrayDevice,
+}
+
+impl<B: Backend> Mlp<B> {
+    /**
+     * [new(architecture, device)] creates a freshly initialized network of
+     * [architecture] on [device].
+     */
+    fn new(architecture: &Architecture, device: &B::Device) -> Mlp<B> {
+        let mut inputs = architecture.input_dim;
+        let mut hidden = Vec::new();
+        for size in &architecture.hidden {
+            hidden.push(LinearConfig::new(inputs, *size).init(device));
+            inputs = *size;
+        }
+        return Mlp {
+            hidden,
+            output: LinearConfig::new(inputs, 1).init(device),
+        };
+    }
+
+    /**
+     * [forward(x, activation)] returns the outputs of the network for the
+     * batch of inputs [x], with hidden layers activated by [activation].
+     */
+    fn forward(&self, x: Tensor<B, 2>, activation: &str) -> Tensor<B, 2> {
+        let mut x = x;
+        for layer in &self.hidden {
+            x = layer.forward(x);
+            x = match activation {
+                "relu" => activation::relu(x),
+                "sigmoid" => activation::sigmoid(x),
+                _ => activation::tanh(x),
+            };
+        }
+        return self.output.forward(x);
+    }
+}
+
+/**
+ * [record_path(path)] returns where the network saved at [path] is recorded
+ * by burn.
+ */
+fn record_path(path: &str) -> String {
+    return Path::new(path)
+        .with_extension("bin")
+        .to_string_lossy()
+        .to_string();
+}
+
+impl BurnNetwork {
+    /**
+     * [new(architecture, learning_rate)] creates a freshly initialized
+     * network of [architecture] trained at [learning_rate].
+     */
+    pub fn new(architecture: Architecture, learning_rate: f64) -> BurnNetwork {
+        let device = NdArrayDevice::default();
+        return BurnNetwork {
+            model: Some(Mlp::new(&architecture, &device)),
+            optimizer: AdamConfig::new().init(),
+            architecture,
+            learning_rate,
+            device,
+        };
+    }
+
+    /**
+     * [load_with(config, path)] loads the network saved at [path], or
+     * creates one of the architecture given by the parsed [config] if there
+     * is none, trained at the learning rate given by [config].
+     */
+    pub fn load_with(config: &Value, path: &str) -> BotResult<BurnNetwork> {
+        let learning_rate = config["q_function"]["learning_rate"]
+            .as_f64()
+            .unwrap_or(DEFAULT_LEARNING_RATE);
+        let architecture = read_metadata(path)
+            .architecture
+            .unwrap_or_else(|| ModelBuilder::from_config(config).architecture());
+        if architecture.is_policy_head() {
+            return Err(BotError::Config(
+                "policy heads need the neuroflow backend".to_string(),
+            ));
+        }
+        let mut network = BurnNetwork::new(architecture, learning_rate);
+        if Path::new(&record_path(path)).exists() {
+            let model = network.model.take().unwrap();
+            let recorder = BinFileRecorder::<FullPrecisionSettings>::new();
+            let loaded = model
+                .load_file(path, &recorder, &network.device)
+                .map_err(|e| BotError::Config(format!("unable to load {}: {}", path, e)))?;
+            network.model = Some(loaded);
+        }
+        return Ok(network);
+    }
+
+    /**
+     * [inputs(batch)] converts the encoded state-action pairs [batch] into a
+     * tensor.
+     */
+    fn inputs(&self, batch: &[Vec<f64>]) -> Tensor<TrainBackend, 2> {
+        let flat: Vec<f32> = batch.iter().flatten().map(|x| *x as f32).collect();
+        let data = TensorData::new(flat, [batch.len(), self.architecture.input_dim]);
+        return Tensor::from_data(data, &self.device);
+    }
+}
+
+impl QFunction for BurnNetwork {
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        if inputs.len() == 0 {
+            return Vec::new();
+        }
+        let x = self.inputs(inputs);
+        let model = self.model.as_ref().unwrap();
+        let outputs = model.forward(x, &self.architecture.activation).into_data();
+        return outputs
+            .to_vec::<f32>()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|y| y as f64)
+            .collect();
+    }
+
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]) {
+        if inputs.len() == 0 {
+            return;
+        }
+        let x = self.inputs(inputs);
+        let labels: Vec<f32> = targets.iter().map(|y| *y as f32).collect();
+        let y = Tensor::from_data(TensorData::new(labels, [targets.len(), 1]), &self.device);
+
+        let model = self.model.take().unwrap();
+        let predicted = model.forward(x, &self.architecture.activation);
+        let loss = MseLoss::new().forward(predicted, y, Reduction::Mean);
+        let grads = GradientsParams::from_grads(loss.backward(), &model);
+        self.model = Some(self.optimizer.step(self.learning_rate, model, grads));
+    }
+
+    fn save(&self, path: &str) -> BotResult<()> {
+        let recorder = BinFileRecorder::<FullPrecisionSettings>::new();
+        let model = self.model.clone().unwrap();
+        model
+            .save_file(path, &recorder)
+            .map_err(|e| BotError::Config(format!("unable to save {}: {}", path, e)))?;
+
+        let mut metadata = read_metadata(path);
+        metadata.architecture = Some(self.architecture.clone());
+        write_metadata(path, &metadata);
+        return Ok(());
+    }
+
+    fn load(path: &str) -> BotResult<BurnNetwork> {
+        return BurnNetwork::load_with(&Value::Null, path);
+    }
+}
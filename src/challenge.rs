@@ -0,0 +1,338 @@
+/**
+ * Utility module for deciding on its own which challenges the daemon accepts.
+ * Challenges arriving on the event stream are checked against the filters of
+ * the "challenges" object in config.json, e.g.
+ * {"enabled": true, "variants": ["standard", "fromPosition"], "rated": true,
+ *  "casual": true, "min_base_secs": 60, "max_base_secs": 1800,
+ *  "max_increment_secs": 30, "min_rating": 1200, "max_rating": 2400},
+ * and declined with the reason Lichess shows the challenger when they fail
+ * one. Correspondence and unlimited challenges are declined unless
+ * "untimed" is true. Without the object challenges are left for the bot's
+ * owner to answer.
+ *
+ * The module also runs the challenge command, which farms training games by
+ * challenging other bots, since self-play alone only ever meets its own weak
+ * policy. Every so often it challenges a random online bot rated within the
+ * band of the "outgoing_challenges" object in config.json, e.g.
+ * {"min_rating": 1200, "max_rating": 2000, "base_secs": 180,
+ *  "increment_secs": 2, "rated": false, "interval_secs": 30,
+ *  "timeout_secs": 60}, in the perf of the time control, plays the game with
+ * the normal game loop if it is accepted, and stores the experiences in the
+ * replay buffer for training. Challenges that go unanswered for the timeout
+ * are withdrawn, and challenges from others are declined for later.
+ */
+use crate::error::BotResult;
+use crate::game_loop::{learning_disabled, play_game};
+use crate::idle_learning::TurnSignal;
+use crate::lichess::{ChallengeEvent, Event, LichessClient, NdjsonStream, User};
+use crate::models::ModelRegistry;
+use crate::replay_shards::ShardedReplay;
+use crate::shutdown;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Number of online bots listed to pick an opponent from
+const ONLINE_BOTS: usize = 100;
+
+// Default rating band, time control, and pause between challenges and before
+// an unanswered challenge is withdrawn, in seconds
+const DEFAULT_MIN_RATING: i64 = 1000;
+const DEFAULT_MAX_RATING: i64 = 2000;
+const DEFAULT_BASE_SECS: u64 = 180;
+const DEFAULT_INCREMENT_SECS: u64 = 2;
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+// Which challenges are accepted, with time controls in seconds
+#[derive(Clone, Debug)]
+pub struct ChallengeFilter {
+    pub variants: Vec<String>,
+    pub rated: bool,
+    pub casual: bool,
+    pub untimed: bool,
+    pub min_base_secs: u64,
+    pub max_base_secs: u64,
+    pub min_increment_secs: u64,
+    pub max_increment_secs: u64,
+    pub min_rating: i64,
+    pub max_rating: i64,
+}
+
+// Which bots the challenge command challenges, and to which time control
+#[derive(Clone, Debug)]
+pub struct OutgoingChallenges {
+    pub min_rating: i64,
+    pub max_rating: i64,
+    pub base_secs: u64,
+    pub increment_secs: u64,
+    pub rated: bool,
+    pub interval: Duration, // between challenges
+    pub timeout: Duration,  // before an unanswered challenge is withdrawn
+}
+
+impl ChallengeFilter {
+    /**
+     * [from_config(config)] reads the challenge filter given by the parsed
+     * [config], or returns None if challenges are not answered automatically.
+     */
+    pub fn from_config(config: &Value) -> Option<ChallengeFilter> {
+        let settings = &config["challenges"];
+        if !settings["enabled"].as_bool().unwrap_or(false) {
+            return None;
+        }
+
+        let variants = match settings["variants"].as_array() {
+            Some(a) => a
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect(),
+            None => vec!["standard".to_string()],
+        };
+        let flag = |key: &str, default: bool| settings[key].as_bool().unwrap_or(default);
+        let secs = |key: &str, default: u64| settings[key].as_u64().unwrap_or(default);
+        let rating = |key: &str, default: i64| settings[key].as_i64().unwrap_or(default);
+        return Some(ChallengeFilter {
+            variants,
+            rated: flag("rated", true),
+            casual: flag("casual", true),
+            untimed: flag("untimed", false),
+            min_base_secs: secs("min_base_secs", 0),
+            max_base_secs: secs("max_base_secs", u64::MAX),
+            min_increment_secs: secs("min_increment_secs", 0),
+            max_increment_secs: secs("max_increment_secs", u64::MAX),
+            min_rating: rating("min_rating", i64::MIN),
+            max_rating: rating("max_rating", i64::MAX),
+        });
+    }
+
+    /**
+     * [decline_reason(challenge)] returns the reason to decline [challenge]
+     * with, as one of the reasons known to Lichess, or None if it should be
+     * accepted.
+     */
+    pub fn decline_reason(&self, challenge: &ChallengeEvent) -> Option<&'static str> {
+        let variant = challenge
+            .variant
+            .as_ref()
+            .map_or("standard", |v| v.key.as_str());
+        if !self.variants.iter().any(|v| v.eq(variant)) {
+            return Some(if variant.eq("standard") {
+                "variant"
+            } else {
+                "standard"
+            });
+        }
+        if challenge.rated && !self.rated {
+            return Some("casual");
+        }
+        if !challenge.rated && !self.casual {
+            return Some("rated");
+        }
+
+        let clock = challenge
+            .time_control
+            .as_ref()
+            .filter(|t| t.kind.eq("clock"));
+        match clock {
+            Some(t) => {
+                let (base, increment) = (t.limit.unwrap_or(0), t.increment.unwrap_or(0));
+                if base < self.min_base_secs || increment < self.min_increment_secs {
+                    return Some("tooFast");
+                }
+                if base > self.max_base_secs || increment > self.max_increment_secs {
+                    return Some("tooSlow");
+                }
+            }
+            None if !self.untimed => return Some("timeControl"),
+            None => (),
+        };
+
+        let rating = challenge.challenger.as_ref().and_then(|p| p.rating);
+        if let Some(r) = rating {
+            if r < self.min_rating || r > self.max_rating {
+                return Some("generic");
+            }
+        }
+
+        return None;
+    }
+}
+
+impl OutgoingChallenges {
+    /**
+     * [from_config(config)] reads the outgoing challenge settings from the
+     * parsed [config].
+     */
+    pub fn from_config(config: &Value) -> OutgoingChallenges {
+        let settings = &config["outgoing_challenges"];
+        let secs = |key: &str, default: u64| settings[key].as_u64().unwrap_or(default);
+        let rating = |key: &str, default: i64| settings[key].as_i64().unwrap_or(default);
+        return OutgoingChallenges {
+            min_rating: rating("min_rating", DEFAULT_MIN_RATING),
+            max_rating: rating("max_rating", DEFAULT_MAX_RATING),
+            base_secs: secs("base_secs", DEFAULT_BASE_SECS),
+            increment_secs: secs("increment_secs", DEFAULT_INCREMENT_SECS),
+            rated: settings["rated"].as_bool().unwrap_or(false),
+            interval: Duration::from_secs(secs("interval_secs", DEFAULT_INTERVAL_SECS)),
+            timeout: Duration::from_secs(secs("timeout_secs", DEFAULT_TIMEOUT_SECS)),
+        };
+    }
+
+    /**
+     * [perf()] returns the Lichess perf of the time control, from its
+     * estimated duration of the base time plus 40 increments.
+     */
+    pub fn perf(&self) -> &'static str {
+        return match self.base_secs + 40 * self.increment_secs {
+            0..=29 => "ultraBullet",
+            30..=179 => "bullet",
+            180..=479 => "blitz",
+            480..=1499 => "rapid",
+            _ => "classical",
+        };
+    }
+
+    /**
+     * [candidates(bots, me)] returns the ids of the bots among [bots] rated
+     * within the band in the perf of the time control, other than [me].
+     */
+    pub fn candidates(&self, bots: &[User], me: &str) -> Vec<String> {
+        return bots
+            .iter()
+            .filter(|bot| !bot.id.eq_ignore_ascii_case(me))
+            .filter(|bot| {
+                let rating = bot.perfs.get(self.perf()).and_then(|p| p.rating);
+                rating.map_or(false, |r| r >= self.min_rating && r <= self.max_rating)
+            })
+            .map(|bot| bot.id.clone())
+            .collect();
+    }
+}
+
+/**
+ * [wait_for_answer(lichess, events, challenge_id, timeout)] reads the event
+ * stream [events] until the challenge with id [challenge_id] turns into a
+ * game or is declined, declining other challenges for later, and returns
+ * whether its game started within [timeout].
+ */
+async fn wait_for_answer(
+    lichess: &LichessClient,
+    events: &mut NdjsonStream,
+    challenge_id: &str,
+    timeout: Duration,
+) -> BotResult<bool> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let event = match tokio::time::timeout_at(deadline, events.next_line()).await {
+            Ok(event) => event?,
+            Err(_) => return Ok(false),
+        };
+        match event {
+            Some(Event::GameStart { game }) if game.id.eq(challenge_id) => return Ok(true),
+            Some(Event::ChallengeDeclined { challenge })
+            | Some(Event::ChallengeCanceled { challenge })
+                if challenge.id.eq(challenge_id) =>
+            {
+                return Ok(false)
+            }
+            Some(Event::Challenge { challenge })
+                if challenge.direction.as_deref() != Some("out") =>
+            {
+                lichess.decline_challenge(&challenge.id, "later").await?;
+            }
+            _ => (),
+        };
+    }
+}
+
+/**
+ * [run_challenges(lichess, config, games)] challenges online bots as set by
+ * the parsed [config] until [games] games have been played, or forever if
+ * None, playing each accepted challenge with the configured networks and
+ * storing its experiences in the replay buffer unless learning is disabled.
+ */
+pub async fn run_challenges(
+    lichess: &LichessClient,
+    config: &Value,
+    games: Option<usize>,
+) -> BotResult<()> {
+    let settings = OutgoingChallenges::from_config(config);
+    let me = lichess.account_id().await?.unwrap_or_default();
+    let mut replay = ShardedReplay::from_config(config)?;
+    let mut events = lichess.event_stream();
+    let mut rng = StdRng::from_entropy();
+    let mut played = 0;
+    while games.map_or(true, |n| played < n) {
+        // Pick an opponent among the online bots in the rating band
+        let bots = lichess.online_bots(ONLINE_BOTS).await?;
+        let candidates = settings.candidates(&bots, &me);
+        if candidates.len() == 0 {
+            info!(
+                "No online bots rated {}-{} in {}",
+                settings.min_rating,
+                settings.max_rating,
+                settings.perf()
+            );
+            tokio::time::sleep(settings.interval).await;
+            continue;
+        }
+        let opponent = &candidates[rng.gen_range(0..candidates.len())];
+
+        // Challenge it, withdrawing the challenge if it goes unanswered
+        let challenge_id = match lichess
+            .create_challenge(
+                opponent,
+                settings.rated,
+                settings.base_secs,
+                settings.increment_secs,
+            )
+            .await?
+        {
+            Some(id) => id,
+            None => {
+                warn!("Unable to challenge {}", opponent);
+                tokio::time::sleep(settings.interval).await;
+                continue;
+            }
+        };
+        info!("Challenged {} ({})", opponent, challenge_id);
+        if !wait_for_answer(lichess, &mut events, &challenge_id, settings.timeout).await? {
+            info!("{} did not accept the challenge", opponent);
+            lichess.cancel_challenge(&challenge_id).await?;
+            tokio::time::sleep(settings.interval).await;
+            continue;
+        }
+
+        // Play the game and keep its experiences for training
+        let mut models = ModelRegistry::from_config(config)?;
+        let (experiences, color_white) = play_game(
+            lichess,
+            config,
+            &challenge_id,
+            &mut models,
+            &TurnSignal::default(),
+        )
+        .await?;
+        if !learning_disabled(config) {
+            replay.append(&experiences, color_white)?;
+        }
+        played += 1;
+        info!(
+            "Stored {} experiences from game {} against {} ({} played)",
+            experiences.len(),
+            challenge_id,
+            opponent,
+            played
+        );
+        if shutdown::requested() {
+            break;
+        }
+        tokio::time::sleep(settings.interval).await;
+    }
+
+    return Ok(());
+}
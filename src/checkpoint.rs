@@ -0,0 +1,444 @@
+/**
+ * Utility module for the metadata saved alongside each network file, stored as
+ * json in a sidecar file next to it (e.g. policy.flow.json), and for the
+ * numbered checkpoints of a network saved over a training run. Networks
+ * without a sidecar file are treated as general purpose. The metadata also
+ * records how many self-play games a network was trained on, so that a resumed
+ * run carries on with its exploration schedule and checkpoint intervals
+ * instead of starting cold, along with the exploration rate it reached, the
+ * seed of its run, the hyperparameters it was trained with and the
+ * architecture it was created with; the learning
+ * rate and momentum terms of training are saved in the network file itself.
+ * Each self-play checkpoint is saved with its target network next to it (e.g.
+ * policy_000010.flow.target), so that selfplay --resume can restore the full
+ * training state of the latest checkpoint after a bad run.
+ *
+ * Checkpoints are configured by the "checkpoints" object in config.json, e.g.
+ * {"dir": "checkpoints", "interval": 10, "keep_last": 5, "keep_every": 100,
+ *  "keep_best": true}, which saves a checkpoint every 10 games and prunes all
+ * but the last 5, every 100th and the one with the best evaluation score.
+ * Networks that failed to be promoted are kept in the rejected directory
+ * within the checkpoint directory for analysis, unless "keep_rejected" is
+ * false.
+ * A checkpoint can be promoted to play the daemon's games by writing its path
+ * to the pointer file in the checkpoint directory, which the daemon checks
+ * before each game, so that training and serving can run as separate
+ * processes.
+ */
+use crate::error::BotResult;
+use crate::model::Architecture;
+use crate::models::{write_network, DEFAULT_ENDGAME_PIECE_THRESHOLD};
+use crate::normalization::{stats_path, write_stats, FeatureStats};
+
+use chess::Board;
+use neuroflow::FeedForward;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+const DEFAULT_CHECKPOINT_DIR: &str = "checkpoints";
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10;
+const DEFAULT_KEEP_LAST: u64 = 5;
+
+// Plies counted as the opening when classifying positions by phase
+const OPENING_PHASE_PLIES: usize = 20;
+
+// Name of the file naming the promoted checkpoint, within the checkpoint
+// directory
+const POINTER_FILE: &str = "promoted";
+
+// Directory of the networks that failed to be promoted, within the checkpoint
+// directory
+const REJECTED_DIR: &str = "rejected";
+
+// The phase of the game a network is intended to play
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Phase {
+    #[default]
+    Any,
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+// Metadata describing a saved network
+#[derive(Clone, Debug, Default)]
+pub struct CheckpointMetadata {
+    pub phase: Phase,
+    pub score: Option<f64>,   // evaluation score, if the network was evaluated
+    pub games: usize,         // self-play games trained on
+    pub epsilon: Option<f64>, // exploration rate of the last game trained on
+    pub run_seed: Option<u64>,
+    pub hyperparameters: Value,
+    pub architecture: Option<Architecture>, // None for networks of unknown shape
+}
+
+// Which checkpoints survive pruning: the [keep_last] most recent, every
+// [keep_every]th version (none if 0) and the best scoring if [keep_best]
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_every: usize,
+    pub keep_best: bool,
+}
+
+// Saves numbered checkpoints to a directory and prunes old ones
+#[derive(Clone, Debug)]
+pub struct CheckpointManager {
+    pub dir: String,
+    pub interval: usize,
+    pub retention: RetentionPolicy,
+    pub keep_rejected: bool,
+}
+
+/**
+ * [parse_phase(s)] converts the phase name [s] into a Phase.
+ */
+pub fn parse_phase(s: &str) -> Phase {
+    match s {
+        "any" => Phase::Any,
+        "opening" => Phase::Opening,
+        "middlegame" => Phase::Middlegame,
+        "endgame" => Phase::Endgame,
+        _ => panic!("Invalid phase: {}", s),
+    }
+}
+
+/**
+ * [phase_name(phase)] converts [phase] into its name.
+ */
+pub fn phase_name(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Any => "any",
+        Phase::Opening => "opening",
+        Phase::Middlegame => "middlegame",
+        Phase::Endgame => "endgame",
+    }
+}
+
+/**
+ * [board_phase(b, ply)] classifies board [b], reached at ply [ply], by the
+ * phase of the game it belongs to: the opening for the first plies, the
+ * endgame once few enough pieces remain and the middlegame otherwise.
+ */
+pub fn board_phase(b: &Board, ply: usize) -> Phase {
+    if b.combined().popcnt() <= DEFAULT_ENDGAME_PIECE_THRESHOLD {
+        return Phase::Endgame;
+    }
+    if ply <= OPENING_PHASE_PLIES {
+        return Phase::Opening;
+    }
+    return Phase::Middlegame;
+}
+
+/**
+ * [metadata_path(path)] returns the path of the sidecar metadata file for the
+ * network saved at [path].
+ */
+pub fn metadata_path(path: &str) -> String {
+    return format!("{}.json", path);
+}
+
+/**
+ * [target_path(path)] returns the path of the target network saved alongside
+ * the checkpoint saved at [path].
+ */
+pub fn target_path(path: &str) -> String {
+    return format!("{}.target", path);
+}
+
+/**
+ * [read_metadata(path)] reads the metadata of the network saved at [path].
+ */
+pub fn read_metadata(path: &str) -> CheckpointMetadata {
+    let json: Value = match fs::read_to_string(metadata_path(path)) {
+        Ok(s) => serde_json::from_str(&s).expect("Checkpoint metadata was not well-formatted"),
+        Err(_) => Value::Null,
+    };
+    let phase = match &json["phase"] {
+        Value::String(s) => parse_phase(s),
+        _ => Phase::Any,
+    };
+
+    return CheckpointMetadata {
+        phase,
+        score: json["score"].as_f64(),
+        games: json["games"].as_u64().unwrap_or(0) as usize,
+        epsilon: json["epsilon"].as_f64(),
+        run_seed: json["run_seed"].as_u64(),
+        hyperparameters: json["hyperparameters"].clone(),
+        architecture: Architecture::from_json(&json["architecture"]),
+    };
+}
+
+/**
+ * [write_metadata(path, metadata)] writes [metadata] for the network saved at
+ * [path].
+ */
+pub fn write_metadata(path: &str, metadata: &CheckpointMetadata) {
+    let json = json!({
+        "phase": phase_name(metadata.phase),
+        "score": metadata.score,
+        "games": metadata.games,
+        "epsilon": metadata.epsilon,
+        "run_seed": metadata.run_seed,
+        "hyperparameters": metadata.hyperparameters,
+        "architecture": metadata.architecture.as_ref().map(|a| a.to_json()),
+    });
+    let tmp_path = format!("{}.tmp", metadata_path(path));
+    fs::write(&tmp_path, json.to_string()).unwrap();
+    fs::rename(&tmp_path, metadata_path(path)).unwrap();
+}
+
+impl RetentionPolicy {
+    /**
+     * [keep(checkpoints)] returns for each of [checkpoints], given as
+     * (version, score) pairs sorted by version, whether it survives pruning.
+     */
+    pub fn keep(&self, checkpoints: &[(usize, Option<f64>)]) -> Vec<bool> {
+        let best = checkpoints
+            .iter()
+            .filter_map(|(v, s)| s.map(|s| (*v, s)))
+            .fold(None, |best: Option<(usize, f64)>, (v, s)| match best {
+                Some((_, high)) if high >= s => best,
+                _ => Some((v, s)),
+            });
+
+        let first_recent = checkpoints.len().saturating_sub(self.keep_last);
+        return checkpoints
+            .iter()
+            .enumerate()
+            .map(|(i, (v, _))| {
+                i >= first_recent
+                    || (self.keep_every > 0 && v % self.keep_every == 0)
+                    || (self.keep_best && best.map(|(b, _)| b) == Some(*v))
+            })
+            .collect();
+    }
+}
+
+impl CheckpointManager {
+    /**
+     * [from_config(config)] reads the checkpoint settings from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> CheckpointManager {
+        let settings = &config["checkpoints"];
+        return CheckpointManager {
+            dir: settings["dir"]
+                .as_str()
+                .unwrap_or(DEFAULT_CHECKPOINT_DIR)
+                .to_string(),
+            interval: settings["interval"]
+                .as_u64()
+                .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL) as usize,
+            retention: RetentionPolicy {
+                keep_last: settings["keep_last"].as_u64().unwrap_or(DEFAULT_KEEP_LAST) as usize,
+                keep_every: settings["keep_every"].as_u64().unwrap_or(0) as usize,
+                keep_best: settings["keep_best"].as_bool().unwrap_or(true),
+            },
+            keep_rejected: settings["keep_rejected"].as_bool().unwrap_or(true),
+        };
+    }
+
+    /**
+     * [checkpoint_path(version)] returns where checkpoint [version] is saved.
+     */
+    pub fn checkpoint_path(&self, version: usize) -> String {
+        return format!("{}/policy_{:06}.flow", self.dir, version);
+    }
+
+    /**
+     * [list()] returns the version and path of every saved checkpoint, sorted
+     * by version.
+     */
+    pub fn list(&self) -> Vec<(usize, String)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut checkpoints: Vec<(usize, String)> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                let version = name.strip_prefix("policy_")?.strip_suffix(".flow")?;
+                Some((
+                    version.parse().ok()?,
+                    e.path().to_string_lossy().to_string(),
+                ))
+            })
+            .collect();
+        checkpoints.sort();
+
+        return checkpoints;
+    }
+
+    /**
+     * [next_version()] returns the version of the next checkpoint to save.
+     */
+    pub fn next_version(&self) -> usize {
+        return match self.list().last() {
+            Some((v, _)) => v + 1,
+            None => 1,
+        };
+    }
+
+    /**
+     * [latest()] returns the path of the most recent checkpoint, or None if
+     * there are none.
+     */
+    pub fn latest(&self) -> Option<String> {
+        return self.list().pop().map(|(_, path)| path);
+    }
+
+    /**
+     * [save(nn, stats, metadata)] saves [nn] and its feature [stats] with
+     * [metadata] as the next checkpoint and prunes old checkpoints, returning
+     * the path it was saved to, or an error if it cannot be saved.
+     */
+    pub fn save(
+        &self,
+        nn: &FeedForward,
+        stats: &FeatureStats,
+        metadata: &CheckpointMetadata,
+    ) -> BotResult<String> {
+        return self.save_with_target(nn, stats, None, metadata);
+    }
+
+    /**
+     * [save_with_target(nn, stats, target, metadata)] saves [nn] and its
+     * feature [stats] with [metadata] as the next checkpoint along with its
+     * [target] network and the target's statistics, if given, and prunes old
+     * checkpoints, returning the path it was saved to, or an error if either
+     * network cannot be saved.
+     */
+    pub fn save_with_target(
+        &self,
+        nn: &FeedForward,
+        stats: &FeatureStats,
+        target: Option<(&FeedForward, &FeatureStats)>,
+        metadata: &CheckpointMetadata,
+    ) -> BotResult<String> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.checkpoint_path(self.next_version());
+        write_network(nn, &path)?;
+        write_stats(stats, &path)?;
+        if let Some((target, target_stats)) = target {
+            write_network(target, &target_path(&path))?;
+            write_stats(target_stats, &target_path(&path))?;
+        }
+        write_metadata(&path, metadata);
+
+        for removed in self.prune() {
+            info!("Pruned checkpoint {}", removed);
+        }
+
+        return Ok(path);
+    }
+
+    /**
+     * [save_rejected(nn, stats, metadata)] saves [nn], which failed to be
+     * promoted, and its feature [stats] with [metadata] to the rejected
+     * directory, stamped with the current
+     * time, returning the path it was saved to, or None if rejected networks
+     * are not kept, or an error if it cannot be saved. Rejected networks are
+     * never pruned.
+     */
+    pub fn save_rejected(
+        &self,
+        nn: &FeedForward,
+        stats: &FeatureStats,
+        metadata: &CheckpointMetadata,
+    ) -> BotResult<Option<String>> {
+        if !self.keep_rejected {
+            return Ok(None);
+        }
+        let dir = format!("{}/{}", self.dir, REJECTED_DIR);
+        fs::create_dir_all(&dir)?;
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let path = format!("{}/policy_{}.flow", dir, stamp);
+        write_network(nn, &path)?;
+        write_stats(stats, &path)?;
+        write_metadata(&path, metadata);
+        return Ok(Some(path));
+    }
+
+    /**
+     * [prune()] deletes every checkpoint that does not survive the retention
+     * policy, returning their paths.
+     */
+    pub fn prune(&self) -> Vec<String> {
+        let checkpoints = self.list();
+        let scored: Vec<(usize, Option<f64>)> = checkpoints
+            .iter()
+            .map(|(v, path)| (*v, read_metadata(path).score))
+            .collect();
+
+        let mut removed = Vec::new();
+        for ((_, path), keep) in checkpoints.iter().zip(self.retention.keep(&scored)) {
+            if keep {
+                continue;
+            }
+            fs::remove_file(path).unwrap();
+            for sidecar in [
+                metadata_path(path),
+                target_path(path),
+                stats_path(path),
+                stats_path(&target_path(path)),
+            ] {
+                if Path::new(&sidecar).exists() {
+                    fs::remove_file(sidecar).unwrap();
+                }
+            }
+            removed.push(path.clone());
+        }
+
+        return removed;
+    }
+
+    /**
+     * [pointer_path()] returns where the path of the promoted checkpoint is
+     * saved.
+     */
+    pub fn pointer_path(&self) -> String {
+        return format!("{}/{}", self.dir, POINTER_FILE);
+    }
+
+    /**
+     * [promote(path)] makes the network saved at [path] the promoted
+     * checkpoint, replacing the pointer atomically so that a reader never sees
+     * it half written.
+     */
+    pub fn promote(&self, path: &str) -> io::Result<()> {
+        if !Path::new(path).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no network at {}", path),
+            ));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let tmp_path = format!("{}.tmp", self.pointer_path());
+        fs::write(&tmp_path, path)?;
+        return fs::rename(&tmp_path, self.pointer_path());
+    }
+
+    /**
+     * [promoted()] returns the path of the promoted checkpoint, or None if no
+     * checkpoint has been promoted.
+     */
+    pub fn promoted(&self) -> Option<String> {
+        let path = fs::read_to_string(self.pointer_path()).ok()?;
+        let path = path.trim();
+        if path.len() == 0 {
+            return None;
+        }
+        return Some(path.to_string());
+    }
+}
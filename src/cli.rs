@@ -0,0 +1,1160 @@
+/**
+ * Utility module for the command line of the bot's binaries, parsed into
+ * subcommands with clap. Each binary offers the commands of its role: playing
+ * commands (the Lichess daemon and single games, UCI mode and analysis of
+ * positions) and training commands (self-play, learning from the replay
+ * buffer, evaluation, promoting checkpoints and managing networks and runs),
+ * with the combined binary offering all of them. When only playing,
+ * experiences are stored in the replay buffer for the training binary to learn
+ * from instead of being learned from directly.
+ */
+use crate::action_space::check_action_space;
+use crate::arena::{
+    compare, gate_after_training, gauntlet, load_openings, load_positions, parse_player,
+    round_robin, PairedComparison,
+};
+use crate::backup::Backup;
+use crate::bench::{bench_table, run_bench, write_criterion};
+use crate::challenge::run_challenges;
+use crate::checkpoint::{parse_phase, read_metadata, write_metadata, CheckpointManager};
+use crate::config::{read_auth_token, read_config};
+use crate::daemon::run_daemon;
+use crate::database::GameDatabase;
+use crate::discount;
+use crate::display::render_board;
+use crate::distill::distill;
+use crate::distributed::{run_learner, run_selfplay_actor};
+use crate::error::{BotError, BotResult};
+use crate::eval::EvalWeights;
+use crate::explain::explain;
+use crate::game_loop::{learning_disabled, play_game};
+use crate::history::MoveCounters;
+use crate::idle_learning::TurnSignal;
+use crate::ingest::ingest_dump;
+use crate::learning::{learn_pass, Learner};
+use crate::lichess::LichessClient;
+use crate::limits::SearchLimit;
+use crate::local_play::play_local;
+use crate::mdp::{evaluate_position, learn_from_experience, TargetNetwork};
+use crate::model::{check_network, Architecture, ModelBuilder};
+use crate::models::{read_network, white_path, write_network, ModelRegistry};
+use crate::normalization::{read_stats, write_stats, FeatureStats};
+use crate::notation::to_san;
+use crate::onnx::{export_onnx, import_onnx};
+use crate::output_scaling::OutputScaling;
+use crate::puzzles::run_puzzles;
+use crate::quantize::{verify, QuantizedNetwork};
+use crate::replay::{load_experiences, migrate_replay};
+use crate::replay_buffer::ReplayBuffer;
+use crate::replay_shards::ShardedReplay;
+use crate::returns::ReturnTarget;
+use crate::run_report::{compare_runs, parse_format, DEFAULT_WINDOW};
+use crate::runs::{config_differences, list_runs, Run};
+use crate::sampling::{rng_from_config, seeded_openings};
+use crate::selfplay::{replay_selfplay, run_selfplay};
+use crate::server::run_server;
+use crate::shutdown;
+use crate::sweep::Sweep;
+use crate::testing::{bench_encoding, run_selftest};
+use crate::tournament::run_tournament;
+use crate::training::parse_duration;
+use crate::uci::run_uci;
+use crate::warmstart::warm_start;
+use crate::weights::{export_weights, NetworkWeights};
+use crate::zoo::ModelZoo;
+
+use chess::{Board, Color};
+use clap::{Parser, Subcommand};
+use reqwest;
+use serde_json::{json, Value};
+use std::fs;
+use std::str::FromStr;
+
+// Number of experiences learned from at a time by the train command
+const DEFAULT_CHUNK_SIZE: usize = 10000;
+
+// Which commands a binary offers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Play,
+    Train,
+    All,
+}
+
+// The command line of the bot's binaries
+#[derive(Debug, Parser)]
+#[command(about = "A chess bot that learns from its own games")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /** Play with an alpha-beta search this many plies deep */
+    #[arg(long, global = true)]
+    pub search_depth: Option<usize>,
+    /** Play without exploring, and neither learn from nor store the games */
+    #[arg(long, global = true)]
+    pub no_learn: bool,
+    /** Show the board and its evaluation after every move of a game */
+    #[arg(long, short, global = true)]
+    pub verbose: bool,
+}
+
+// The commands of the bot's binaries, each offered by the roles that play,
+// train or both
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /** Play the Lichess game with the given id, then learn from it */
+    Play { game_id: String },
+    /** Play Lichess games and train on the configured schedule, forever */
+    Daemon,
+    /** Challenge online bots and store the games in the replay buffer */
+    Challenge {
+        /** Stop after this many games instead of running forever */
+        #[arg(long)]
+        games: Option<usize>,
+    },
+    /** Join a Lichess arena tournament and play its games until it finishes */
+    Tournament {
+        id: String,
+        /** Join the swiss tournament with this id instead of an arena */
+        #[arg(long)]
+        swiss: bool,
+    },
+    /** Speak the UCI protocol on standard input and output */
+    Uci,
+    /** Check the encoding invariants over random legal positions */
+    Selftest {
+        #[arg(default_value_t = 1000)]
+        positions: usize,
+        #[arg(default_value_t = 0)]
+        seed: u64,
+    },
+    /** Time state encoding against the old string-based encoding */
+    BenchEncoding {
+        #[arg(default_value_t = 1000)]
+        positions: usize,
+        #[arg(default_value_t = 0)]
+        seed: u64,
+    },
+    /** Time state encoding, move selection and Q-max by legal move count */
+    Bench {
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+        /** Passes over the positions in each sample */
+        #[arg(long, default_value_t = 20)]
+        iterations: usize,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /** Also write criterion-compatible estimates to this directory */
+        #[arg(long)]
+        criterion: Option<String>,
+    },
+    /** Upload the training state to the backup server */
+    Backup,
+    /** Download the training state from the backup server */
+    Restore,
+    /** Show the scores of every move in the position with the given FEN */
+    Analyze {
+        #[arg(required = true)]
+        fen: Vec<String>,
+    },
+    /** Show which pieces drive the network's choice of move in a position */
+    Explain {
+        #[arg(required = true)]
+        fen: Vec<String>,
+    },
+    /** Write the best moves of every position in a FEN/EPD file to a CSV */
+    Bestmove {
+        #[arg(long)]
+        input: String,
+        #[arg(long)]
+        output: String,
+        #[arg(long, default_value_t = 3)]
+        alternatives: usize,
+    },
+    /** Report how often games were lost on time per time control */
+    Timeouts,
+    /** Play against the bot in the terminal */
+    PlayLocal {
+        /** Play Black instead of White */
+        #[arg(long)]
+        black: bool,
+        /** Start from this position instead of the initial one */
+        #[arg(long)]
+        fen: Option<String>,
+    },
+    /** Serve the network's best moves and evaluations over HTTP */
+    Serve {
+        /** Address to listen on, e.g. 127.0.0.1:8080, overriding the config */
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /** Learn from a replay file, or from the whole replay buffer */
+    #[command(alias = "learn")]
+    Train {
+        #[arg(long)]
+        replay: Option<String>,
+        #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
+        chunk_size: usize,
+    },
+    /** Tag the network at the given path with its intended phase */
+    Tag { path: String, phase: String },
+    /** Promote a network to play the next games of a running daemon */
+    Promote { path: String },
+    /** Create a fresh network with the configured architecture */
+    Init {
+        path: String,
+        /** Hidden layer sizes, e.g. 128,64, overriding the config */
+        #[arg(long, value_delimiter = ',')]
+        hidden: Option<Vec<usize>>,
+        /** Activation of the hidden layers: tanh or sigmoid */
+        #[arg(long)]
+        activation: Option<String>,
+        /** Output a Q-value for every action from the state alone */
+        #[arg(long)]
+        policy_head: bool,
+        /** Split the Q-value of every action into value and advantage streams */
+        #[arg(long)]
+        dueling: bool,
+    },
+    /** Distill a network into a smaller one */
+    Distill {
+        teacher: String,
+        student: String,
+        #[arg(default_value_t = 16)]
+        hidden: usize,
+        #[arg(default_value_t = 10000)]
+        positions: usize,
+        #[arg(default_value_t = 1)]
+        epochs: usize,
+    },
+    /** Train a fresh network to approximate the handcrafted evaluation */
+    Warmstart {
+        path: String,
+        #[arg(default_value_t = 16)]
+        hidden: usize,
+        #[arg(default_value_t = 1000000)]
+        positions: usize,
+    },
+    /** Export the weights of a network as csv or npy */
+    WeightsExport {
+        path: String,
+        dir: String,
+        #[arg(default_value = "csv")]
+        format: String,
+    },
+    /** Export a network to an ONNX file */
+    ExportOnnx { path: String, onnx: String },
+    /** Import a network from an ONNX file */
+    ImportOnnx { onnx: String, path: String },
+    /** Summarize the weights of a network */
+    WeightsStats {
+        path: String,
+        #[arg(default_value_t = 200)]
+        positions: usize,
+    },
+    /** Verify the quantized copy of a network */
+    QuantizeCheck {
+        path: String,
+        #[arg(default_value_t = 200)]
+        positions: usize,
+    },
+    /** Check the action index round trip and masking */
+    ActionCheck {
+        #[arg(default_value_t = 200)]
+        positions: usize,
+    },
+    /** Upgrade a replay file to the current format */
+    ReplayMigrate { path: String },
+    /** Play a round-robin between networks (path@limit) or checkpoints */
+    Arena {
+        openings: String,
+        players: Vec<String>,
+    },
+    /** Pretrain on the games of a Lichess database dump or PGN file */
+    #[command(alias = "pretrain")]
+    Ingest {
+        path: String,
+        max_games: Option<usize>,
+    },
+    /** Score the networks on a puzzle suite, optionally training on it */
+    Puzzles {
+        path: Option<String>,
+        #[arg(long)]
+        train: bool,
+    },
+    /** Compare two networks (path@limit) from seeded random openings */
+    Compare {
+        first: String,
+        second: String,
+        #[arg(default_value_t = 50)]
+        openings: usize,
+        #[arg(default_value_t = 0)]
+        seed: u64,
+        #[arg(default_value_t = 8)]
+        plies: usize,
+    },
+    /** Play the configured network against a checkpoint, promoting it if better */
+    Gauntlet {
+        #[arg(long)]
+        checkpoint: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        openings: usize,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        #[arg(long, default_value_t = 8)]
+        plies: usize,
+    },
+    /** Evaluate the configured network against an opponent */
+    Eval {
+        #[arg(long)]
+        opponent: String,
+        #[arg(long, default_value_t = 50)]
+        openings: usize,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        #[arg(long, default_value_t = 8)]
+        plies: usize,
+    },
+    /** Train offline over self-play games, within a named run if given */
+    Selfplay {
+        #[arg(long)]
+        games: Option<usize>,
+        #[arg(long)]
+        run: Option<String>,
+        /** Start every game from a position in this FEN or EPD file */
+        #[arg(long)]
+        positions: Option<String>,
+        /** Restore the training state of the latest checkpoint first */
+        #[arg(long)]
+        resume: bool,
+        /** Stop once this much time has passed, e.g. 3600, 90m or 2h */
+        #[arg(long)]
+        time_budget: Option<String>,
+    },
+    /** Play a self-play game again from the seed recorded in the metrics */
+    ReplaySelfplay {
+        #[arg(long)]
+        seed: u64,
+        #[arg(long, default_value_t = 1)]
+        game: usize,
+        #[arg(long)]
+        checkpoint: Option<String>,
+    },
+    /** Compare the metrics of the named runs side by side */
+    CompareRuns {
+        #[arg(num_args = 2.., required = true)]
+        runs: Vec<String>,
+        #[arg(long, default_value = "markdown")]
+        format: String,
+        #[arg(long, default_value_t = DEFAULT_WINDOW)]
+        window: usize,
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /** Play games for a learner process, or learn from the games of actors */
+    Distributed {
+        /** "actor" or "learner" */
+        #[arg(long)]
+        role: String,
+        /** Play Lichess games with the daemon instead of self-play games */
+        #[arg(long)]
+        lichess: bool,
+        /** Stop a self-play actor after this many games */
+        #[arg(long)]
+        games: Option<usize>,
+    },
+    /** Train and evaluate every configuration of a sweep, ranking them */
+    Sweep { path: String },
+    /** List the training runs, or show one or compare two of them */
+    Runs {
+        #[command(subcommand)]
+        action: Option<RunsCommand>,
+    },
+    /** List the networks of the model zoo, or add one to it */
+    Zoo {
+        #[command(subcommand)]
+        action: Option<ZooCommand>,
+    },
+}
+
+// The ways of looking at training runs
+#[derive(Debug, Subcommand)]
+pub enum RunsCommand {
+    /** Show the summary and config of a run */
+    Show { name: String },
+    /** Show two runs and where their configs differ */
+    Compare { first: String, second: String },
+}
+
+// The ways of managing the model zoo
+#[derive(Debug, Subcommand)]
+pub enum ZooCommand {
+    /** Copy the network at the given path into the zoo under a name */
+    Add { name: String, path: String },
+}
+
+impl Role {
+    /**
+     * [plays()] returns whether the role offers the playing commands.
+     */
+    pub fn plays(self) -> bool {
+        return self != Role::Train;
+    }
+
+    /**
+     * [trains()] returns whether the role offers the training commands.
+     */
+    pub fn trains(self) -> bool {
+        return self != Role::Play;
+    }
+
+    /**
+     * [offers(command)] returns whether the role offers [command].
+     */
+    pub fn offers(self, command: &Command) -> bool {
+        return match command {
+            Command::Selftest { .. }
+            | Command::BenchEncoding { .. }
+            | Command::Bench { .. }
+            | Command::Backup
+            | Command::Restore => true,
+            Command::Play { .. }
+            | Command::Daemon
+            | Command::Challenge { .. }
+            | Command::Tournament { .. }
+            | Command::Uci
+            | Command::Analyze { .. }
+            | Command::Explain { .. }
+            | Command::Bestmove { .. }
+            | Command::Timeouts
+            | Command::PlayLocal { .. }
+            | Command::Serve { .. } => self.plays(),
+            Command::Distributed { role, .. } if role.eq("actor") => self.plays(),
+            _ => self.trains(),
+        };
+    }
+}
+
+/**
+ * [train(config, replay, chunk_size)] learns from the experiences in the
+ * replay file at [replay] in chunks of [chunk_size], or makes a learning pass
+ * over the whole replay buffer given by the parsed [config] if None.
+ */
+fn train(config: &Value, replay: Option<&str>, chunk_size: usize) {
+    let path = match replay {
+        Some(p) => p,
+        None => {
+            let buffer = ShardedReplay::from_config(config).expect("Unable to open replay buffer");
+            match learn_pass(config, &buffer, chunk_size, &mut rng_from_config(config)) {
+                Ok(n) => println!("Learning pass complete after {} experiences.", n),
+                Err(e) => println!("Learning pass failed: {}", e),
+            };
+            return;
+        }
+    };
+
+    let experiences = match load_experiences(path) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Unable to load {}: {}", path, e);
+            return;
+        }
+    };
+    let mut rng = rng_from_config(config);
+    let mut learner = Learner::from_config(config).expect("Unable to learn");
+    for chunk in experiences.chunks(chunk_size.max(1)) {
+        learner.learn(chunk, &mut rng);
+        if learner.save_due() {
+            learner.save().expect("Unable to save the policy networks");
+        }
+    }
+    learner.save().expect("Unable to save the policy networks");
+    println!(
+        "Learned from {} experiences in {}.",
+        experiences.len(),
+        path
+    );
+}
+
+/**
+ * [apply_search_depth(config, depth)] makes the bot play Lichess games and UCI
+ * searches with an alpha-beta search [depth] plies deep in the parsed
+ * [config], keeping the other settings of a configured alpha-beta agent.
+ */
+fn apply_search_depth(config: &mut Value, depth: usize) {
+    config["alphabeta"]["depth"] = json!(depth);
+    let agent = &config["lichess"]["agent"];
+    let alphabeta = agent.as_str() == Some("alphabeta") || agent["kind"] == "alphabeta";
+    if !alphabeta {
+        config["lichess"]["agent"] = json!("alphabeta");
+    } else if agent.is_object() {
+        config["lichess"]["agent"]["depth"] = json!(depth);
+    }
+}
+
+/**
+ * [run(role)] runs the command given on the command line if [role] offers it.
+ */
+pub async fn run(role: Role) -> BotResult<()> {
+    let cli = Cli::parse();
+    let command = cli.command;
+    if !role.offers(&command) {
+        println!("This binary does not offer {:?}", command);
+        return Ok(());
+    }
+
+    // Parse auth token from config file, which UCI mode and the selftest do
+    // not need
+    let mut config = read_config()?;
+    if let Some(depth) = cli.search_depth {
+        apply_search_depth(&mut config, depth);
+    }
+    if cli.no_learn {
+        config["lichess"]["no_learn"] = json!(true);
+    }
+    if cli.verbose {
+        config["display"]["boards"] = json!(true);
+    }
+    crate::logging::init(&config);
+    match command {
+        Command::Uci => {
+            run_uci(&config);
+            return Ok(());
+        }
+        Command::Selftest { positions, seed } => {
+            let failed = run_selftest(positions, seed);
+            println!("{} of {} positions failed", failed, positions);
+            if failed > 0 {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::BenchEncoding { positions, seed } => {
+            let mismatches = bench_encoding(positions, seed);
+            if mismatches > 0 {
+                println!("{} bitboards were encoded differently", mismatches);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::Bench {
+            samples,
+            iterations,
+            seed,
+            criterion,
+        } => {
+            // e.g. bench --samples 20 --criterion target/criterion
+            let benchmarks = run_bench(&config, samples, iterations, seed)?;
+            let mut baselines = Vec::new();
+            for benchmark in &benchmarks {
+                baselines.push(match &criterion {
+                    Some(dir) => write_criterion(dir, benchmark)?,
+                    None => None,
+                });
+            }
+            print!("{}", bench_table(&benchmarks, &baselines));
+            return Ok(());
+        }
+        _ => (),
+    };
+    let auth_token = read_auth_token(&config)?;
+    crate::lichess_log::init(&config, &auth_token);
+
+    // Create new client to interact with lichess
+    let client = reqwest::Client::new();
+
+    match command {
+        Command::Backup | Command::Restore => {
+            // Upload the training state to the backup server, or download it
+            // onto a fresh machine
+            let mut backup = Backup::from_config(&config).expect("No backup server configured");
+            if let Command::Backup = command {
+                println!("Backed up {} files.", backup.upload(&client).await?);
+            } else {
+                println!("Restored {} files.", backup.restore(&client).await?);
+            }
+        }
+        Command::Daemon => {
+            shutdown::install_handler();
+            let learn = role.trains() && !learning_disabled(&config);
+            return run_daemon(&client, &auth_token, &config, learn).await;
+        }
+        Command::Challenge { games } => {
+            // e.g. challenge --games 20
+            shutdown::install_handler();
+            let lichess = LichessClient::from_config(&client, &auth_token, &config);
+            return run_challenges(&lichess, &config, games).await;
+        }
+        Command::Tournament { id, swiss } => {
+            // e.g. tournament abcd1234, withdrawing from it on Ctrl-C
+            shutdown::install_handler();
+            let lichess = LichessClient::from_config(&client, &auth_token, &config);
+            return run_tournament(&lichess, &config, &id, swiss).await;
+        }
+        Command::Analyze { fen } => {
+            // Score every move under the network for the side to move, e.g.
+            // in a position taken from the move log
+            let fen = fen.join(" ");
+            let board = Board::from_str(&fen).expect("Invalid FEN");
+            let player_white = board.side_to_move() == Color::White;
+            let mut models = ModelRegistry::from_config(&config)?;
+            let mut scores = evaluate_position(
+                &board,
+                MoveCounters::from_fen(&fen),
+                &mut models.head_for(&board, player_white),
+                player_white,
+            );
+            scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+            println!("{}", render_board(&board, None, player_white));
+            for (m, score) in scores {
+                println!("{:>8} {:>6} {:.3}", to_san(&board, m), m, score);
+            }
+        }
+        Command::Explain { fen } => {
+            let fen = fen.join(" ");
+            let board = Board::from_str(&fen).expect("Invalid FEN");
+            let player_white = board.side_to_move() == Color::White;
+            let mut models = ModelRegistry::from_config(&config)?;
+            let nn = &mut models.head_for(&board, player_white);
+            match explain(nn, &board, MoveCounters::from_fen(&fen)) {
+                Some(e) => println!("{}", e.report()),
+                None => println!("No legal moves in {}", fen),
+            };
+        }
+        Command::Bestmove {
+            input,
+            output,
+            alternatives,
+        } => {
+            // Write the best move, its score and the top alternatives of
+            // every position, e.g.
+            // bestmove --input positions.fen --output results.csv --alternatives 3
+            let mut models = ModelRegistry::from_config(&config)?;
+            let positions = load_positions(&input);
+            let mut csv = String::from("fen,best_move,best_san,score,alternatives\n");
+            for (board, counters) in &positions {
+                let player_white = board.side_to_move() == Color::White;
+                let nn = &mut models.head_for(board, player_white);
+                let mut scores = evaluate_position(board, *counters, nn, player_white);
+                scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+                let others: Vec<String> = scores
+                    .iter()
+                    .skip(1)
+                    .take(alternatives)
+                    .map(|(m, score)| format!("{}:{:.4}", m, score))
+                    .collect();
+                csv += &match scores.first() {
+                    Some((m, score)) => format!(
+                        "{},{},{},{:.4},{}\n",
+                        board,
+                        m,
+                        to_san(board, *m),
+                        score,
+                        others.join(" ")
+                    ),
+                    None => format!("{},,,,\n", board),
+                };
+            }
+            fs::write(&output, csv).expect("Unable to write results");
+            println!(
+                "Wrote best moves for {} positions to {}.",
+                positions.len(),
+                output
+            );
+        }
+        Command::PlayLocal { black, fen } => {
+            // e.g. play-local --black --search-depth 3
+            let (start, counters) = match fen {
+                Some(fen) => (
+                    Board::from_str(&fen).map_err(|_| BotError::InvalidFen(fen.clone()))?,
+                    MoveCounters::from_fen(&fen),
+                ),
+                None => (Board::default(), MoveCounters::default()),
+            };
+            play_local(&config, !black, start, counters)?;
+        }
+        Command::Serve { address } => {
+            // e.g. serve --address 0.0.0.0:8080, then
+            // curl -d '{"fen": "..."}' localhost:8080/bestmove
+            if let Some(address) = address {
+                config["server"]["address"] = json!(address);
+            }
+            return run_server(&config).await;
+        }
+        Command::Timeouts => {
+            for t in GameDatabase::from_config(&config).time_losses() {
+                println!(
+                    "{}: {} of {} games lost on time",
+                    t.time_control, t.time_losses, t.games
+                );
+            }
+        }
+        Command::Train { replay, chunk_size } => {
+            // Learning passes over the buffer resume where an interrupted
+            // pass stopped
+            train(&config, replay.as_deref(), chunk_size);
+            gate_after_training(&config);
+        }
+        Command::Tag { path, phase } => {
+            let mut metadata = read_metadata(&path);
+            metadata.phase = parse_phase(&phase);
+            write_metadata(&path, &metadata);
+            println!("Tagged {} as a {} network.", path, phase);
+        }
+        Command::Promote { path } => {
+            // The network at the path, e.g. a checkpoint, plays the next
+            // games of a running daemon
+            match CheckpointManager::from_config(&config).promote(&path) {
+                Ok(()) => println!("Promoted {}.", path),
+                Err(e) => println!("Unable to promote {}: {}", path, e),
+            };
+        }
+        Command::Init {
+            path,
+            hidden,
+            activation,
+            policy_head,
+            dueling,
+        } => {
+            // e.g. init policy.flow --hidden 128,64 --activation sigmoid
+            let mut builder = ModelBuilder::from_config(&config);
+            if policy_head {
+                builder = builder.policy_head(true);
+            }
+            if dueling {
+                builder = builder.dueling(true);
+            }
+            if let Some(sizes) = hidden {
+                builder = builder.hidden(&sizes);
+            }
+            if let Some(name) = activation {
+                builder = builder.activation(&name);
+            }
+            builder.create(&path)?;
+            println!(
+                "Created {} network at {}.",
+                builder.architecture().summary(),
+                path
+            );
+        }
+        Command::Distill {
+            teacher,
+            student,
+            hidden,
+            positions,
+            epochs,
+        } => {
+            let mut teacher_nn = read_network(&teacher)?;
+            let stats = read_stats(&teacher)?;
+            let builder = ModelBuilder::from_config(&config)
+                .hidden(&[hidden])
+                .policy_head(false)
+                .dueling(false);
+            let mut student_nn = builder.build()?;
+            let error = distill(&mut teacher_nn, &mut student_nn, &stats, positions, epochs);
+            println!("Student mean squared error on probe positions: {}", error);
+
+            write_network(&student_nn, &student)?;
+            write_stats(&stats, &student)?;
+            let mut metadata = read_metadata(&teacher);
+            metadata.architecture = Some(builder.architecture());
+            write_metadata(&student, &metadata);
+            println!("Saved distilled network to {}.", student);
+        }
+        Command::Warmstart {
+            path,
+            hidden,
+            positions,
+        } => {
+            let builder = ModelBuilder::from_config(&config)
+                .hidden(&[hidden])
+                .policy_head(false)
+                .dueling(false);
+            let mut nn = builder.create(&path)?;
+            let weights = EvalWeights::from_config(&config);
+            let scaling = OutputScaling::from_config(&config);
+            let error = warm_start(&mut nn, &weights, &scaling, positions);
+            println!("Mean squared error on probe positions: {}", error);
+
+            write_network(&nn, &path)?;
+            println!("Saved warm-started network to {}.", path);
+        }
+        Command::WeightsExport { path, dir, format } => {
+            let weights = NetworkWeights::from_network(&read_network(&path)?);
+            match export_weights(&weights, &dir, &format) {
+                Ok(paths) => println!("Wrote {}.", paths.join(", ")),
+                Err(e) => println!("Unable to export weights: {}", e),
+            };
+        }
+        Command::ExportOnnx { path, onnx } => {
+            // e.g. export-onnx policy.flow policy.onnx
+            let weights = NetworkWeights::from_network(&read_network(&path)?);
+            export_onnx(&weights, &onnx)?;
+            println!("Exported {} to {}.", path, onnx);
+        }
+        Command::ImportOnnx { onnx, path } => {
+            // The network is checked against the encoding once saved, and
+            // its architecture recorded in its metadata
+            let nn = import_onnx(&onnx)?.to_network()?;
+            write_network(&nn, &path)?;
+            write_stats(&FeatureStats::new(), &path)?;
+            let mut metadata = read_metadata(&path);
+            metadata.architecture = Some(Architecture::of(&nn));
+            write_metadata(&path, &metadata);
+            check_network(&nn, &path)?;
+            println!(
+                "Imported {} network from {} to {}.",
+                Architecture::of(&nn).summary(),
+                onnx,
+                path
+            );
+        }
+        Command::WeightsStats { path, positions } => {
+            // Dead units are found over the given number of positions
+            let weights = NetworkWeights::from_network(&read_network(&path)?);
+            let feature_stats = read_stats(&path)?;
+            for (j, s) in weights.stats(positions, &feature_stats).iter().enumerate() {
+                println!(
+                    "Layer {}: {}x{}, weight norm {:.4}, mean |w| {:.4}, max |w| {:.4}, bias norm {:.4}, {} dead units",
+                    j, s.neurons, s.inputs, s.weight_norm, s.mean_abs_weight, s.max_abs_weight, s.bias_norm, s.dead_units
+                );
+            }
+        }
+        Command::QuantizeCheck { path, positions } => {
+            let mut nn = read_network(&path)?;
+            let q = QuantizedNetwork::from_network(&nn, read_stats(&path)?);
+            let report = verify(&mut nn, &q, positions);
+            println!("Max error: {}", report.max_error);
+            println!("Mean error: {}", report.mean_error);
+            println!("Best move agreement: {}", report.best_move_agreement);
+        }
+        Command::ActionCheck { positions } => {
+            // Fixed positions are checked along with the random ones
+            let failures = check_action_space(positions);
+            for f in &failures {
+                println!("{}", f);
+            }
+            println!("{} action space check failures", failures.len());
+        }
+        Command::ReplayMigrate { path } => {
+            match migrate_replay(&path) {
+                Ok(n) => println!("Migrated {} experiences in {}.", n, path),
+                Err(e) => println!("Unable to migrate {}: {}", path, e),
+            };
+        }
+        Command::Arena { openings, players } => {
+            // Play the given networks, or all saved checkpoints, from the
+            // openings in the given book (or "startpos"), where each network
+            // can be given a search limit as path@limit, e.g.
+            // policy.flow@nodes=10
+            let openings = if openings.eq("startpos") {
+                vec![Board::default()]
+            } else {
+                load_openings(&openings)
+            };
+            let players: Vec<(String, SearchLimit)> = if players.len() > 0 {
+                players.iter().map(|a| parse_player(a)).collect()
+            } else {
+                let checkpoints = CheckpointManager::from_config(&config);
+                checkpoints
+                    .list()
+                    .into_iter()
+                    .map(|(_, p)| (p, SearchLimit::Unlimited))
+                    .collect()
+            };
+
+            let tournament = round_robin(&players, &openings)?;
+            println!("{}", tournament.crosstable());
+        }
+        Command::Ingest { path, max_games } => {
+            // Read at most the given number of games this run
+            match ingest_dump(&config, &path, max_games) {
+                Ok(p) => println!(
+                    "Pretrained on {} of {} games read from {}.",
+                    p.games_used, p.games_read, path
+                ),
+                Err(e) => println!("Unable to read {}: {}", path, e),
+            };
+        }
+        Command::Puzzles { path, train } => {
+            // e.g. puzzles lichess_db_puzzle.csv.zst --train
+            match run_puzzles(&config, path.as_deref(), train) {
+                Ok(report) => println!("Puzzle accuracy: {:.3}", report.accuracy()),
+                Err(e) => println!("Unable to score puzzles: {}", e),
+            };
+        }
+        Command::Compare {
+            first,
+            second,
+            openings,
+            seed,
+            plies,
+        } => {
+            let openings = seeded_openings(openings, plies, seed);
+            let comparison = compare(&parse_player(&first), &parse_player(&second), &openings)?;
+            print_comparison(&first, &comparison);
+        }
+        Command::Gauntlet {
+            checkpoint,
+            openings,
+            seed,
+            plies,
+        } => {
+            // e.g. gauntlet --checkpoint checkpoints/policy_000010.flow
+            let openings = seeded_openings(openings, plies, seed);
+            match gauntlet(&config, checkpoint.as_deref(), &openings)? {
+                Some((comparison, promoted)) => {
+                    print_comparison("the current network", &comparison);
+                    match promoted {
+                        Some(path) => println!("Promoted {}.", path),
+                        None => println!("Not promoted."),
+                    };
+                }
+                None => println!("No checkpoint to play against."),
+            };
+        }
+        Command::Eval {
+            opponent,
+            openings,
+            seed,
+            plies,
+        } => {
+            // The opponent is a network (path@limit) or a scripted opponent
+            let player = (white_path(&config), SearchLimit::Unlimited);
+            let openings = seeded_openings(openings, plies, seed);
+            let comparison = compare(&player, &parse_player(&opponent), &openings)?;
+            print_comparison(&player.0, &comparison);
+        }
+        Command::Selfplay {
+            games,
+            run,
+            positions,
+            resume,
+            time_budget,
+        } => {
+            // e.g. selfplay --games 100 --positions endgames.epd --resume, or
+            // selfplay --time-budget 2h
+            shutdown::install_handler();
+            let mut config = config.clone();
+            if let Some(path) = positions {
+                config["selfplay"]["openings"]["suite"] = json!(path);
+                config["selfplay"]["openings"]["fraction"] = json!(1.);
+            }
+            if let Some(budget) = time_budget {
+                let budget = parse_duration(&budget).expect("Invalid time budget");
+                config["training"]["time_budget_secs"] = json!(budget.as_secs_f64());
+            }
+            match run {
+                Some(name) => {
+                    run_selfplay(&Run::open(&config, &name).start(&config), games, resume)
+                }
+                None => run_selfplay(&config, games, resume),
+            }?;
+        }
+        Command::ReplaySelfplay {
+            seed,
+            game,
+            checkpoint,
+        } => {
+            // e.g. replay-selfplay --seed 123 --game 42 --checkpoint checkpoints/c.flow
+            replay_selfplay(&config, game, seed, checkpoint.as_deref())?;
+        }
+        Command::Distributed {
+            role,
+            lichess,
+            games,
+        } => {
+            // e.g. distributed --role learner, then in other processes
+            // distributed --role actor, or --role actor --lichess
+            shutdown::install_handler();
+            match role.as_str() {
+                "learner" => return run_learner(&config).await,
+                "actor" if lichess => {
+                    let mut config = config.clone();
+                    config["distributed"]["actor"] = json!(true);
+                    return run_daemon(&client, &auth_token, &config, false).await;
+                }
+                "actor" => return run_selfplay_actor(&config, games),
+                _ => panic!("Invalid role: {}", role),
+            };
+        }
+        Command::Sweep { path } => {
+            // e.g. sweep sweeps/gamma.json, stopping after the current
+            // configuration on Ctrl-C
+            shutdown::install_handler();
+            let results = Sweep::load(&path)?.run(&config)?;
+            if let Some(best) = results.first() {
+                println!(
+                    "Best configuration: {} ({:.3}) {}",
+                    best.run,
+                    best.comparison.score(),
+                    Value::Object(best.overrides.clone())
+                );
+            }
+        }
+        Command::CompareRuns {
+            runs,
+            format,
+            window,
+            output,
+        } => {
+            // e.g. compare-runs baseline wide --format html --output report.html
+            let runs: Vec<Run> = runs.iter().map(|name| Run::open(&config, name)).collect();
+            let report = compare_runs(&runs, window, parse_format(&format));
+            match output {
+                Some(path) => {
+                    fs::write(&path, report).expect("Unable to write report");
+                    println!("Wrote comparison of {} runs to {}.", runs.len(), path);
+                }
+                None => println!("{}", report),
+            };
+        }
+        Command::Zoo { action } => {
+            let zoo = match ModelZoo::from_config(&config) {
+                Some(zoo) => zoo,
+                None => panic!("No model zoo is configured"),
+            };
+            match action {
+                Some(ZooCommand::Add { name, path }) => match zoo.add(&name, &path) {
+                    Ok(saved) => println!("Added {} to the zoo as {}.", path, saved),
+                    Err(e) => println!("Unable to add {} to the zoo: {}", path, e),
+                },
+                None => {
+                    for (name, path) in zoo.list() {
+                        println!("{}", zoo.describe(&name, &path));
+                    }
+                }
+            };
+        }
+        Command::Runs { action } => {
+            let print_summary = |run: &Run| {
+                let s = run.summary();
+                println!(
+                    "{}: {} games, score {:.3} ({:.3} recently), {:.1} experiences per game, {} checkpoints",
+                    run.name, s.games, s.score, s.recent_score, s.mean_experiences, s.checkpoints
+                );
+            };
+            match action {
+                Some(RunsCommand::Show { name }) => {
+                    let run = Run::open(&config, &name);
+                    print_summary(&run);
+                    println!("{}", serde_json::to_string_pretty(&run.snapshot()).unwrap());
+                }
+                Some(RunsCommand::Compare { first, second }) => {
+                    let first = Run::open(&config, &first);
+                    let second = Run::open(&config, &second);
+                    print_summary(&first);
+                    print_summary(&second);
+                    for (path, a, b) in config_differences(&first.snapshot(), &second.snapshot()) {
+                        println!("{}: {} vs {}", path, a, b);
+                    }
+                }
+                None => {
+                    for run in list_runs(&config) {
+                        print_summary(&run);
+                    }
+                }
+            };
+        }
+        Command::Play { game_id } => {
+            shutdown::install_handler();
+            return play(&client, &auth_token, &config, &game_id, role).await;
+        }
+        Command::Uci
+        | Command::Selftest { .. }
+        | Command::BenchEncoding { .. }
+        | Command::Bench { .. } => (),
+    };
+
+    return Ok(());
+}
+
+/**
+ * [print_comparison(first, comparison)] prints the results of [comparison],
+ * with the score given from the view of the player [first].
+ */
+fn print_comparison(first: &str, comparison: &PairedComparison) {
+    let counts = comparison.pentanomial();
+    let (wins, draws, losses) = comparison.wdl();
+    println!(
+        "Wins/draws/losses of {}: {}/{}/{}",
+        first, wins, draws, losses
+    );
+    println!("Pairs scoring 0/0.5/1/1.5/2: {:?}", counts);
+    println!("Score of {}: {:.3}", first, comparison.score());
+    match comparison.elo_difference() {
+        Some(elo) => println!("Elo difference: {:.0}", elo),
+        None => println!("Elo difference: unbounded"),
+    };
+}
+
+/**
+ * [play(client, auth_token, config, game_id, role)] plays the Lichess game
+ * with id [game_id] and then learns from it, or stores its experiences in the
+ * replay buffer if [role] does not train, unless learning is disabled.
+ */
+async fn play(
+    client: &reqwest::Client,
+    auth_token: &str,
+    config: &Value,
+    game_id: &str,
+    role: Role,
+) -> BotResult<()> {
+    // Initialize policy networks for each color
+    let mut models = ModelRegistry::from_config(config)?;
+
+    // Play the game
+    let lichess = LichessClient::from_config(client, auth_token, config);
+    let (experience_memory, color_white) = play_game(
+        &lichess,
+        config,
+        game_id,
+        &mut models,
+        &TurnSignal::default(),
+    )
+    .await?;
+
+    println!("Game is over!");
+    if learning_disabled(config) {
+        println!("Learning is disabled, leaving the networks untouched.");
+        return Ok(());
+    }
+    println!("Collected {} experiences", experience_memory.len());
+
+    // Leave the experiences to the training binary when only playing
+    if !role.trains() {
+        let mut replay = ShardedReplay::from_config(config)?;
+        replay.append(&experience_memory, color_white)?;
+        println!("Stored experiences in the replay buffer.");
+        return Ok(());
+    }
+
+    // Learn from experience gained in the game, with the target network
+    // starting from the network that played the game
+    let (network, stats) = models.learner(color_white);
+    let mut target = TargetNetwork::from_config(config, network, *stats);
+    let updates = experience_memory.len();
+    let mut replay = ReplayBuffer::from_config(config);
+    replay.extend(experience_memory);
+    learn_from_experience(
+        network,
+        stats,
+        &mut target,
+        &replay,
+        updates,
+        discount(config),
+        &OutputScaling::from_config(config),
+        &ReturnTarget::from_config(config),
+        &mut rng_from_config(config),
+    );
+
+    // Save neural network to file
+    models.save(color_white)?;
+    println!(
+        "Learned from game and saved policy network to {}.",
+        models.path(color_white)
+    );
+
+    return Ok(());
+}
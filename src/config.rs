@@ -0,0 +1,47 @@
+/**
+ * Utility module for reading the config.json file, which holds the Lichess
+ * auth token along with any optional settings for the bot.
+ */
+use crate::error::{BotError, BotResult};
+
+use serde_json::Value;
+use std::fs;
+
+const CONFIG_PATH: &str = "config.json";
+
+// Where the Lichess API is served from unless configured otherwise
+const LICHESS_URL: &str = "https://lichess.org";
+
+/**
+ * [read_config()] reads and parses the config.json file, which must be
+ * included for the bot to work.
+ */
+pub fn read_config() -> BotResult<Value> {
+    let config_str = fs::read_to_string(CONFIG_PATH)?;
+    let json: Value = serde_json::from_str(&config_str)?;
+
+    return Ok(json);
+}
+
+/**
+ * [read_auth_token(config)] reads the Auth Token given by Lichess from the
+ * parsed [config].
+ */
+pub fn read_auth_token(config: &Value) -> BotResult<String> {
+    let auth = match &config["auth_token"] {
+        Value::String(s) => s,
+        _ => return Err(BotError::Config("missing auth_token".to_string())),
+    };
+
+    return Ok(auth.to_string());
+}
+
+/**
+ * [read_lichess_url(config)] reads the url the Lichess API is served from
+ * from the parsed [config], which is only overridden to point the bot at a
+ * mock server.
+ */
+pub fn read_lichess_url(config: &Value) -> String {
+    let url = config["lichess"]["url"].as_str().unwrap_or(LICHESS_URL);
+    return url.trim_end_matches('/').to_string();
+}
@@ -0,0 +1,229 @@
+/**
+ * Utility module for curriculum learning, where self-play starts out from
+ * easy positions close to a checkmate, whose sparse rewards a weak network can
+ * still reach, and moves on to harder and earlier positions as the network
+ * gets better at converting them. The curriculum is a list of stages in the
+ * "curriculum" object of config.json, e.g.
+ * {"stages": [{"generate": "kq_vs_k"}, {"generate": "kr_vs_k"},
+ *             {"positions": "mate_in_2.epd", "promote_score": 0.7},
+ *             {"positions": "endgames.epd"}, {}],
+ *  "promote_score": 0.8, "window": 50, "progress": "curriculum.json"},
+ * where each stage starts every game from a random position generated with
+ * the learner (White) holding the material of "generate" ("kq_vs_k" or
+ * "kr_vs_k"), from a random position of the FEN or EPD file "positions", or
+ * from the initial position if it gives neither. Once the learner has scored
+ * at least the stage's "promote_score" on average over the last "window"
+ * games of the stage, the next stage begins. The stage reached and the
+ * results of its games are saved to the "progress" file after every game, so
+ * that a resumed run carries on with its stage.
+ */
+use crate::arena::load_openings;
+
+use chess::{Board, BoardBuilder, BoardStatus, Color, Piece, ALL_SQUARES};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::fs;
+use tracing::warn;
+
+const DEFAULT_PROMOTE_SCORE: f64 = 0.8;
+const DEFAULT_WINDOW: u64 = 50;
+const DEFAULT_PROGRESS_PATH: &str = "curriculum.json";
+
+// Number of positions generated for a stage
+const GENERATED_POSITIONS: usize = 1000;
+
+// Where the games of a stage start from
+#[derive(Clone, Debug, PartialEq)]
+pub enum StageSource {
+    Generated(String), // the learner's material, e.g. "kq_vs_k"
+    File(String),
+    Initial,
+}
+
+// A stage of the curriculum, left once the learner scores [promote_score]
+#[derive(Clone, Debug)]
+pub struct CurriculumStage {
+    pub source: StageSource,
+    pub promote_score: f64,
+}
+
+// The stages of the curriculum and how far the learner has got through them
+#[derive(Clone, Debug)]
+pub struct Curriculum {
+    pub stages: Vec<CurriculumStage>,
+    pub window: usize, // games the score of a stage is averaged over
+    pub progress_path: String,
+    pub stage: usize,
+    pub results: Vec<f64>, // of the games of the current stage
+}
+
+/**
+ * [generated_pieces(kind)] returns the pieces the learner holds besides its
+ * king in positions of kind [kind].
+ */
+fn generated_pieces(kind: &str) -> Vec<Piece> {
+    return match kind {
+        "kq_vs_k" => vec![Piece::Queen],
+        "kr_vs_k" => vec![Piece::Rook],
+        _ => panic!("Invalid curriculum position kind: {}", kind),
+    };
+}
+
+/**
+ * [generate_positions(kind, count, rng)] generates [count] random legal
+ * positions with White to move holding its king and the pieces of [kind]
+ * against the lone black king, drawn with [rng].
+ */
+pub fn generate_positions(kind: &str, count: usize, rng: &mut impl Rng) -> Vec<Board> {
+    let mut pieces = vec![(Piece::King, Color::White), (Piece::King, Color::Black)];
+    pieces.extend(
+        generated_pieces(kind)
+            .into_iter()
+            .map(|p| (p, Color::White)),
+    );
+
+    let mut positions = Vec::new();
+    while positions.len() < count {
+        let mut squares = Vec::new();
+        while squares.len() < pieces.len() {
+            let square = ALL_SQUARES[rng.gen_range(0..64)];
+            if !squares.contains(&square) {
+                squares.push(square);
+            }
+        }
+
+        let mut builder = BoardBuilder::new();
+        for ((piece, color), square) in pieces.iter().zip(squares) {
+            builder.piece(square, *piece, *color);
+        }
+        builder.side_to_move(Color::White);
+        if let Ok(board) = Board::try_from(&builder) {
+            if board.status() == BoardStatus::Ongoing {
+                positions.push(board);
+            }
+        }
+    }
+    return positions;
+}
+
+impl Curriculum {
+    /**
+     * [from_config(config)] reads the curriculum given by the parsed [config]
+     * along with the progress saved through it, or returns None if there is
+     * none.
+     */
+    pub fn from_config(config: &Value) -> Option<Curriculum> {
+        let settings = &config["curriculum"];
+        let promote_score = settings["promote_score"]
+            .as_f64()
+            .unwrap_or(DEFAULT_PROMOTE_SCORE);
+        let stages: Vec<CurriculumStage> = settings["stages"]
+            .as_array()?
+            .iter()
+            .map(|stage| CurriculumStage {
+                source: match (stage["generate"].as_str(), stage["positions"].as_str()) {
+                    (Some(kind), _) => StageSource::Generated(kind.to_string()),
+                    (None, Some(path)) => StageSource::File(path.to_string()),
+                    (None, None) => StageSource::Initial,
+                },
+                promote_score: stage["promote_score"].as_f64().unwrap_or(promote_score),
+            })
+            .collect();
+        if stages.len() == 0 {
+            return None;
+        }
+
+        let mut curriculum = Curriculum {
+            stages,
+            window: settings["window"].as_u64().unwrap_or(DEFAULT_WINDOW).max(1) as usize,
+            progress_path: settings["progress"]
+                .as_str()
+                .unwrap_or(DEFAULT_PROGRESS_PATH)
+                .to_string(),
+            stage: 0,
+            results: Vec::new(),
+        };
+        if let Ok(text) = fs::read_to_string(&curriculum.progress_path) {
+            let progress: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+            let stage = progress["stage"].as_u64().unwrap_or(0) as usize;
+            curriculum.stage = stage.min(curriculum.stages.len() - 1);
+            curriculum.results = progress["results"]
+                .as_array()
+                .map(|a| a.iter().filter_map(|r| r.as_f64()).collect())
+                .unwrap_or_default();
+        }
+        return Some(curriculum);
+    }
+
+    /**
+     * [describe()] names the current stage for the logs.
+     */
+    pub fn describe(&self) -> String {
+        let source = match &self.stages[self.stage].source {
+            StageSource::Generated(kind) => kind.clone(),
+            StageSource::File(path) => path.clone(),
+            StageSource::Initial => "the initial position".to_string(),
+        };
+        return format!(
+            "stage {}/{} ({})",
+            self.stage + 1,
+            self.stages.len(),
+            source
+        );
+    }
+
+    /**
+     * [positions(seed)] returns the positions the games of the current stage
+     * start from, generated from [seed] if the stage generates them, or none
+     * if they start from the initial position.
+     */
+    pub fn positions(&self, seed: u64) -> Vec<Board> {
+        return match &self.stages[self.stage].source {
+            StageSource::Generated(kind) => {
+                let mut rng = StdRng::seed_from_u64(seed);
+                generate_positions(kind, GENERATED_POSITIONS, &mut rng)
+            }
+            StageSource::File(path) => load_openings(path),
+            StageSource::Initial => Vec::new(),
+        };
+    }
+
+    /**
+     * [success_rate()] returns the learner's average score over the last
+     * games of the current stage, or None if fewer than a window of them
+     * were played.
+     */
+    pub fn success_rate(&self) -> Option<f64> {
+        if self.results.len() < self.window {
+            return None;
+        }
+        let recent = &self.results[self.results.len() - self.window..];
+        return Some(recent.iter().sum::<f64>() / recent.len() as f64);
+    }
+
+    /**
+     * [record(result)] records the learner's [result] in a game of the
+     * current stage, moving on to the next stage if it scores well enough,
+     * and saves the progress. Returns whether a new stage began.
+     */
+    pub fn record(&mut self, result: f64) -> bool {
+        self.results.push(result);
+        let promote_score = self.stages[self.stage].promote_score;
+        let last_stage = self.stage + 1 == self.stages.len();
+        let advanced = !last_stage && self.success_rate().map_or(false, |r| r >= promote_score);
+        if advanced {
+            self.stage += 1;
+            self.results.clear();
+        } else if self.results.len() > self.window {
+            self.results.remove(0);
+        }
+
+        let progress = json!({ "stage": self.stage, "results": self.results });
+        if let Err(e) = fs::write(&self.progress_path, progress.to_string()) {
+            warn!("Unable to save curriculum progress: {}", e);
+        }
+        return advanced;
+    }
+}
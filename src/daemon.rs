@@ -0,0 +1,395 @@
+/**
+ * Utility module for running the bot as a long-lived daemon, which plays
+ * Lichess games during the play windows of its schedule and learns from the
+ * experiences it stored to the replay buffer during the train windows. Each
+ * game runs as its own task with its own copy of the networks and sends its
+ * experiences to the shared replay buffer when it finishes, which may also
+ * trigger learning passes between games. Up to "max_games" games of the
+ * "lichess" settings in config.json are played at once (1 by default), and
+ * games still running when a play window closes are played out. While games
+ * wait on their opponents the daemon may also learn in short bursts, as set by
+ * the idle learning settings. The training state is backed up on the schedule
+ * of the backup settings, if any. Before each game the daemon checks which
+ * checkpoint is promoted, so that a network promoted by another process (e.g.
+ * with the promote command) plays the next games without a restart; until one
+ * is, the configured networks play. Challenges are answered on their own if
+ * the "challenges" settings say so, and declined for later while the bot is
+ * playing as many games as it can, though a game that starts anyway is still
+ * played. As an actor of distributed training the daemon sends the experiences
+ * of its games to the learner instead of storing them.
+ */
+use crate::backup::Backup;
+use crate::challenge::ChallengeFilter;
+use crate::checkpoint::CheckpointManager;
+use crate::distributed::LearnerLink;
+use crate::error::BotResult;
+use crate::game_loop::play_game;
+use crate::idle_learning::{IdleLearner, TurnSignal};
+use crate::learning::Learner;
+use crate::lichess::{ChallengeEvent, Event, LichessClient, NdjsonStream};
+use crate::mdp::Experience;
+use crate::models::ModelRegistry;
+use crate::replay_shards::ShardedReplay;
+use crate::sampling::rng_from_config;
+use crate::schedule::{Mode, Schedule};
+use crate::shared_replay::{Episode, EpisodeSender, SharedReplayBuffer};
+use crate::shutdown;
+
+use rand::Rng;
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+// Number of experiences learned from before the schedule is checked again
+const TRAIN_CHUNK_SIZE: usize = 1000;
+
+// How long to wait for a game to start, and between checks when there is
+// nothing to do
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often to check whether the running games have left when shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Default number of games played at once
+const DEFAULT_MAX_GAMES: u64 = 1;
+
+// A game being played by its own task, along with its id
+type RunningGame = (String, JoinHandle<BotResult<()>>);
+
+/**
+ * [answer_challenge(lichess, challenge, filter, busy)] accepts or declines
+ * [challenge] according to [filter], declining it for later if [busy],
+ * and leaves it unanswered if there is no filter or the bot sent it.
+ */
+async fn answer_challenge(
+    lichess: &LichessClient,
+    challenge: &ChallengeEvent,
+    filter: &Option<ChallengeFilter>,
+    busy: bool,
+) -> BotResult<()> {
+    let filter = match filter {
+        Some(f) if challenge.direction.as_deref() != Some("out") => f,
+        _ => return Ok(()),
+    };
+    let challenger = challenge.challenger.as_ref().and_then(|p| p.id.clone());
+    let challenger = challenger.unwrap_or("unknown".to_string());
+
+    let reason = if busy {
+        Some("later")
+    } else {
+        filter.decline_reason(challenge)
+    };
+    match reason {
+        None => {
+            info!("Accepting challenge {} from {}", challenge.id, challenger);
+            if !lichess.accept_challenge(&challenge.id).await? {
+                warn!("Unable to accept challenge {}", challenge.id);
+            }
+        }
+        Some(reason) => {
+            info!(
+                "Declining challenge {} from {} ({})",
+                challenge.id, challenger, reason
+            );
+            lichess.decline_challenge(&challenge.id, reason).await?;
+        }
+    };
+    return Ok(());
+}
+
+/**
+ * [poll_game_start(lichess, events, challenges, busy)] waits a while for the
+ * next event on the event stream [events], answering challenges according to
+ * the filter [challenges] or for later if [busy], and returns Some(id) if it
+ * reports a started game with id [id] and None otherwise.
+ */
+pub async fn poll_game_start(
+    lichess: &LichessClient,
+    events: &mut NdjsonStream,
+    challenges: &Option<ChallengeFilter>,
+    busy: bool,
+) -> BotResult<Option<String>> {
+    match tokio::time::timeout(POLL_INTERVAL, events.next_line()).await {
+        Ok(Ok(Some(Event::GameStart { game }))) => Ok(Some(game.id)),
+        Ok(Ok(Some(Event::Challenge { challenge }))) => {
+            answer_challenge(lichess, &challenge, challenges, busy).await?;
+            Ok(None)
+        }
+        Ok(Err(e)) => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/**
+ * [serving_checkpoint(checkpoints, serving)] returns the checkpoint that
+ * should play the next game, which is the one promoted in [checkpoints] if it
+ * exists, and reports when it differs from the one [serving] so far.
+ */
+fn serving_checkpoint(checkpoints: &CheckpointManager, serving: &Option<String>) -> Option<String> {
+    let promoted = match checkpoints.promoted() {
+        Some(path) if !Path::new(&path).exists() => {
+            warn!("Promoted checkpoint {} does not exist, ignoring it", path);
+            serving.clone()
+        }
+        promoted => promoted,
+    };
+
+    if promoted != *serving {
+        match &promoted {
+            Some(path) => info!("Switching to promoted checkpoint {}", path),
+            None => info!("Switching back to the configured networks"),
+        };
+    }
+    return promoted;
+}
+
+/**
+ * [play_episode(lichess, config, checkpoint, game_id, episodes, turns)] plays
+ * the Lichess game with id [game_id] with the network saved at [checkpoint],
+ * or the configured networks if None, signalling its turns through [turns],
+ * and sends the experiences collected over it to the shared replay buffer
+ * through [episodes].
+ */
+async fn play_episode(
+    lichess: LichessClient,
+    config: Value,
+    checkpoint: Option<String>,
+    game_id: String,
+    episodes: EpisodeSender,
+    turns: TurnSignal,
+) -> BotResult<()> {
+    let mut models = ModelRegistry::serving(&config, checkpoint.as_deref())?;
+    let (experiences, player_white) =
+        play_game(&lichess, &config, &game_id, &mut models, &turns).await?;
+
+    info!("Game {} is over!", game_id);
+    info!("Collected {} experiences", experiences.len());
+    episodes.send(Episode {
+        game_id,
+        player_white,
+        experiences,
+    });
+
+    return Ok(());
+}
+
+/**
+ * [reap_games(games)] removes the finished games from [games], reporting
+ * those that failed.
+ */
+async fn reap_games(games: &mut Vec<RunningGame>) {
+    let (finished, running): (Vec<RunningGame>, Vec<RunningGame>) =
+        games.drain(..).partition(|(_, game)| game.is_finished());
+    *games = running;
+    for (game_id, game) in finished {
+        match game.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Game {} failed: {}", game_id, e),
+            Err(e) => warn!("Game task {} failed: {}", game_id, e),
+        };
+    }
+}
+
+/**
+ * [learn_from_chunk(config, chunk, rng)] learns from the experiences in
+ * [chunk], each paired with whether its player was white, saving the policy
+ * networks given by the parsed [config]. Minibatches are drawn with [rng].
+ */
+pub fn learn_from_chunk(config: &Value, chunk: &[(Experience, bool)], rng: &mut impl Rng) {
+    let result = Learner::from_config(config).and_then(|mut learner| {
+        learner.learn(chunk, rng);
+        return learner.save();
+    });
+    if let Err(e) = result {
+        warn!("Unable to learn: {}", e);
+    }
+}
+
+/**
+ * [train_from_buffer(config, replay, keep_training, rng)] learns from the
+ * experiences in [replay] in chunks, oldest shard first, with [rng] and the
+ * policy networks given by the parsed [config] kept in memory. Whenever the
+ * networks are saved, as often as configured and once [keep_training]
+ * returns false, the experiences learned from are dropped from [replay], so
+ * that training can stop after any chunk without learning from an experience
+ * twice. Returns whether there was anything to learn from.
+ */
+fn train_from_buffer(
+    config: &Value,
+    replay: &mut ShardedReplay,
+    keep_training: impl Fn() -> bool,
+    rng: &mut impl Rng,
+) -> bool {
+    if replay.len() == 0 {
+        return false;
+    }
+
+    let mut learner = match Learner::from_config(config) {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("Unable to learn: {}", e);
+            return false;
+        }
+    };
+    while replay.len() > 0 && keep_training() {
+        let experiences = match replay.oldest() {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Unable to load replay buffer: {}", e);
+                return false;
+            }
+        };
+
+        let mut learned = 0;
+        let mut keep = true;
+        while keep && learned < experiences.len() {
+            let end = (learned + TRAIN_CHUNK_SIZE).min(experiences.len());
+            learner.learn(&experiences[learned..end], rng);
+            learned = end;
+            keep = keep_training();
+            if !keep || learned == experiences.len() || learner.save_due() {
+                if let Err(e) = learner.save() {
+                    warn!("Unable to save the policy networks: {}", e);
+                    return false;
+                }
+                replay.replace_oldest(&experiences[learned..]).unwrap();
+                info!(
+                    "Learned from {} experiences, {} remaining in buffer.",
+                    learned,
+                    replay.len()
+                );
+            }
+        }
+    }
+
+    return true;
+}
+
+/**
+ * [run_daemon(client, auth_token, config, learn)] runs the bot forever,
+ * switching between playing Lichess games and training from its replay buffer
+ * according to the schedule in the parsed [config]. Unless [learn], the
+ * daemon only stores experiences, leaving them to a separate training
+ * process, and idles during the train windows.
+ */
+pub async fn run_daemon(
+    client: &reqwest::Client,
+    auth_token: &str,
+    config: &Value,
+    learn: bool,
+) -> BotResult<()> {
+    let schedule = &Schedule::from_config(config)?;
+    let lichess = LichessClient::from_config(client, auth_token, config);
+    let storage = ShardedReplay::from_config(config)?;
+    let (mut buffer, episodes) = SharedReplayBuffer::from_config(config, storage);
+    buffer.learner = LearnerLink::from_config(config)?;
+    let mut backup = Backup::from_config(config);
+    let checkpoints = CheckpointManager::from_config(config);
+    let mut serving = None;
+    let mut events = None;
+    let challenges = ChallengeFilter::from_config(config);
+    let max_games = config["lichess"]["max_games"]
+        .as_u64()
+        .unwrap_or(DEFAULT_MAX_GAMES)
+        .max(1) as usize;
+    let mut games: Vec<RunningGame> = Vec::new();
+    let turns = TurnSignal::default();
+    let idle_learner = IdleLearner::from_config(config, &turns).filter(|_| learn);
+    let mut rng = rng_from_config(config);
+    loop {
+        // Once a shutdown is requested no games are started, and the daemon
+        // stops once the running games have left and their experiences are
+        // stored
+        if shutdown::requested() {
+            while games.len() > 0 {
+                tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+                reap_games(&mut games).await;
+            }
+            if let Err(e) = buffer.collect() {
+                warn!("Unable to store experiences: {}", e);
+            }
+            info!("Shut down");
+            return Ok(());
+        }
+
+        // Back up the training state when it is due, carrying on if the
+        // server can not be reached
+        if let Some(b) = backup.as_mut().filter(|b| b.due()) {
+            match b.upload(client).await {
+                Ok(n) => info!("Backed up {} files", n),
+                Err(e) => warn!("Backup failed: {}", e),
+            };
+        }
+
+        // Store the episodes of finished games, learning from a sample of the
+        // buffer if enough have been collected since the last learning pass
+        reap_games(&mut games).await;
+        if let Err(e) = buffer.collect() {
+            warn!("Unable to store experiences: {}", e);
+        }
+        if learn && buffer.learning_due() {
+            match buffer.storage.sample(buffer.sample_size, &mut rng) {
+                Ok(sample) => {
+                    learn_from_chunk(config, &sample, &mut rng);
+                    info!("Learned from {} sampled experiences.", sample.len());
+                }
+                Err(e) => warn!("Unable to sample replay buffer: {}", e),
+            };
+            buffer.mark_learned();
+        }
+
+        match schedule.current_mode() {
+            Mode::Play => {
+                // Learn for a while if every game is waiting on its opponent
+                if let Some(idle) = idle_learner.as_ref().filter(|_| games.len() > 0) {
+                    let storage = &buffer.storage;
+                    match tokio::task::block_in_place(|| idle.learn(config, storage, &mut rng)) {
+                        Ok(0) => (),
+                        Ok(n) => info!("Learned from {} experiences while idle.", n),
+                        Err(e) => warn!("Unable to learn while idle: {}", e),
+                    };
+                }
+
+                // Challenges are declined while as many games as allowed are
+                // running, but a game that starts anyway (e.g. one accepted
+                // before the others started) is played rather than forfeited
+                let stream = events.get_or_insert_with(|| lichess.event_stream());
+                let busy = games.len() >= max_games;
+                let started = poll_game_start(&lichess, stream, &challenges, busy).await?;
+
+                // Lichess repeats the start of every ongoing game when the
+                // stream is opened, so games already being played are skipped
+                let game_id = match started {
+                    Some(id) if !games.iter().any(|(g, _)| g.eq(&id)) => id,
+                    _ => continue,
+                };
+                info!("Starting game {} ({} playing)", game_id, games.len() + 1);
+                serving = serving_checkpoint(&checkpoints, &serving);
+                let game = tokio::spawn(play_episode(
+                    lichess.clone(),
+                    config.clone(),
+                    serving.clone(),
+                    game_id.clone(),
+                    episodes.clone(),
+                    turns.clone(),
+                ));
+                games.push((game_id, game));
+            }
+            Mode::Train if learn => {
+                events = None;
+                let in_train_window =
+                    || schedule.current_mode() == Mode::Train && !shutdown::requested();
+                if !train_from_buffer(config, &mut buffer.storage, in_train_window, &mut rng) {
+                    tokio::time::sleep(IDLE_INTERVAL).await;
+                }
+            }
+            Mode::Train | Mode::Idle => {
+                events = None;
+                tokio::time::sleep(IDLE_INTERVAL).await;
+            }
+        }
+    }
+}
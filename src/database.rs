@@ -0,0 +1,200 @@
+/**
+ * Utility module for the persistent SQLite database of games the bot has
+ * played, stored at the "database" path in config.json (games.db by default).
+ * Each game records its time control and how it ended, so that games lost on
+ * time can be counted per time control.
+ */
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_DATABASE_PATH: &str = "games.db";
+
+// A finished game to be stored in the database
+#[derive(Clone, Debug)]
+pub struct GameRecord {
+    pub id: String,
+    pub opponent: String,
+    pub opponent_rating: Option<i64>,
+    pub bot_white: bool,
+    pub moves: String,
+    pub result: f64, // 1 for a win, 0.5 for a draw and 0 for a loss
+    pub opening: String,
+    pub opponent_moves: u32,
+    pub opponent_blunders: u32,
+    pub time_control: String, // e.g. "180+2", in seconds
+    pub termination: String,  // the Lichess game status, e.g. "mate" or "outoftime"
+}
+
+// How often games of a time control were lost on time
+#[derive(Clone, Debug)]
+pub struct TimeLosses {
+    pub time_control: String,
+    pub games: u32,
+    pub time_losses: u32,
+}
+
+// Aggregated history of the games played against an opponent
+#[derive(Clone, Debug, Default)]
+pub struct OpponentHistory {
+    pub games: u32,
+    pub average_result: f64,
+    pub opponent_moves: u32,
+    pub opponent_blunders: u32,
+}
+
+// The database of played games
+pub struct GameDatabase {
+    conn: Connection,
+}
+
+impl GameDatabase {
+    /**
+     * [open(path)] opens the database at [path], creating its tables if this
+     * is a new database.
+     */
+    pub fn open(path: &str) -> GameDatabase {
+        let conn = Connection::open(path).expect("Unable to open game database");
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS games (
+                id TEXT PRIMARY KEY,
+                opponent TEXT NOT NULL,
+                opponent_rating INTEGER,
+                bot_white INTEGER NOT NULL,
+                moves TEXT NOT NULL,
+                result REAL NOT NULL,
+                opening TEXT NOT NULL,
+                opponent_moves INTEGER NOT NULL,
+                opponent_blunders INTEGER NOT NULL,
+                played_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+
+        // Databases from before time controls were tracked lack their columns,
+        // which fail to be added again to newer ones
+        for column in ["time_control", "termination"] {
+            let sql = format!(
+                "ALTER TABLE games ADD COLUMN {} TEXT NOT NULL DEFAULT 'unknown'",
+                column
+            );
+            let _ = conn.execute(&sql, []);
+        }
+
+        return GameDatabase { conn };
+    }
+
+    /**
+     * [from_config(config)] opens the database given by the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> GameDatabase {
+        let path = config["database"].as_str().unwrap_or(DEFAULT_DATABASE_PATH);
+        return GameDatabase::open(path);
+    }
+
+    /**
+     * [record_game(record)] stores the finished game [record], replacing any
+     * earlier record of the same game.
+     */
+    pub fn record_game(&self, record: &GameRecord) {
+        let played_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO games (id, opponent, opponent_rating, bot_white, moves,
+                    result, opening, opponent_moves, opponent_blunders, played_at,
+                    time_control, termination)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    record.id,
+                    record.opponent,
+                    record.opponent_rating,
+                    record.bot_white,
+                    record.moves,
+                    record.result,
+                    record.opening,
+                    record.opponent_moves,
+                    record.opponent_blunders,
+                    played_at,
+                    record.time_control,
+                    record.termination,
+                ],
+            )
+            .unwrap();
+    }
+
+    /**
+     * [opponent_history(opponent)] aggregates every stored game against
+     * [opponent].
+     */
+    pub fn opponent_history(&self, opponent: &str) -> OpponentHistory {
+        return self
+            .conn
+            .query_row(
+                "SELECT COUNT(*), COALESCE(AVG(result), 0), COALESCE(SUM(opponent_moves), 0),
+                    COALESCE(SUM(opponent_blunders), 0)
+                 FROM games WHERE opponent = ?1",
+                params![opponent],
+                |row| {
+                    Ok(OpponentHistory {
+                        games: row.get(0)?,
+                        average_result: row.get(1)?,
+                        opponent_moves: row.get(2)?,
+                        opponent_blunders: row.get(3)?,
+                    })
+                },
+            )
+            .unwrap();
+    }
+
+    /**
+     * [opponent_openings(opponent, limit)] returns up to [limit] of the
+     * openings [opponent] has played most against the bot, each with the
+     * number of games it was played in.
+     */
+    pub fn opponent_openings(&self, opponent: &str, limit: u32) -> Vec<(String, u32)> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT opening, COUNT(*) AS n FROM games WHERE opponent = ?1
+                 GROUP BY opening ORDER BY n DESC LIMIT ?2",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![opponent, limit], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        return rows.map(|r| r.unwrap()).collect();
+    }
+
+    /**
+     * [time_losses()] returns for each time control played how many games
+     * were played and how many of them the bot lost on time.
+     */
+    pub fn time_losses(&self) -> Vec<TimeLosses> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT time_control, COUNT(*),
+                    SUM(CASE WHEN termination = 'outoftime' AND result = 0 THEN 1 ELSE 0 END)
+                 FROM games GROUP BY time_control ORDER BY time_control",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(TimeLosses {
+                    time_control: row.get(0)?,
+                    games: row.get(1)?,
+                    time_losses: row.get(2)?,
+                })
+            })
+            .unwrap();
+
+        return rows.map(|r| r.unwrap()).collect();
+    }
+}
@@ -0,0 +1,164 @@
+/**
+ * Utility module for the decisions behind chosen moves. Move selection returns
+ * the chosen move along with where it came from (the book, a tablebase, a
+ * search, the policy network, exploration, an external engine or a fallback),
+ * its score and the best alternatives with theirs, so that the logs show why
+ * each move was played and how close the choice was.
+ */
+use crate::notation::to_san;
+
+use chess::{Board, ChessMove};
+use serde_json::{json, Value};
+
+// Number of alternatives to the chosen move kept for the logs
+pub const ALTERNATIVES: usize = 3;
+
+// Where a chosen move came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveSource {
+    Book,
+    Tablebase,
+    Search,
+    Policy,
+    Exploration,
+    Engine,
+    Fallback,
+}
+
+// A chosen move along with why it was chosen
+#[derive(Clone, Debug)]
+pub struct MoveDecision {
+    pub chosen: ChessMove,
+    pub source: MoveSource,
+    pub score: Option<f64>, // score of the chosen move, if moves were scored
+    pub alternatives: Vec<(ChessMove, f64)>, // best other moves, best first
+    pub target: Option<f64>, // searched Q-value of the chosen move to learn
+}
+
+/**
+ * [source_name(source)] converts [source] into its name.
+ */
+pub fn source_name(source: MoveSource) -> &'static str {
+    match source {
+        MoveSource::Book => "book",
+        MoveSource::Tablebase => "tablebase",
+        MoveSource::Search => "search",
+        MoveSource::Policy => "policy",
+        MoveSource::Exploration => "exploration",
+        MoveSource::Engine => "engine",
+        MoveSource::Fallback => "fallback",
+    }
+}
+
+impl MoveDecision {
+    /**
+     * [new(chosen, source)] creates the decision to play [chosen], which came
+     * from [source] without scoring any moves.
+     */
+    pub fn new(chosen: ChessMove, source: MoveSource) -> MoveDecision {
+        return MoveDecision {
+            chosen,
+            source,
+            score: None,
+            alternatives: Vec::new(),
+            target: None,
+        };
+    }
+
+    /**
+     * [from_scores(scores, chosen, source)] creates the decision to play
+     * [chosen], which came from [source] after scoring the moves in
+     * [scores], keeping the best of the other moves as alternatives.
+     */
+    pub fn from_scores(
+        scores: &[(ChessMove, f64)],
+        chosen: ChessMove,
+        source: MoveSource,
+    ) -> MoveDecision {
+        let mut alternatives: Vec<(ChessMove, f64)> = scores
+            .iter()
+            .filter(|(m, _)| *m != chosen)
+            .copied()
+            .collect();
+        alternatives.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        alternatives.truncate(ALTERNATIVES);
+
+        return MoveDecision {
+            chosen,
+            source,
+            score: scores.iter().find(|(m, _)| *m == chosen).map(|(_, s)| *s),
+            alternatives,
+            target: None,
+        };
+    }
+
+    /**
+     * [best_of(scores, source)] creates the decision to play the move scored
+     * highest in [scores], which came from [source], or None if there are no
+     * moves.
+     */
+    pub fn best_of(scores: &[(ChessMove, f64)], source: MoveSource) -> Option<MoveDecision> {
+        let (chosen, _) = scores
+            .iter()
+            .fold(None, |best: Option<(ChessMove, f64)>, (m, s)| match best {
+                Some((_, high)) if high > *s => best,
+                _ => Some((*m, *s)),
+            })?;
+        return Some(MoveDecision::from_scores(scores, chosen, source));
+    }
+
+    /**
+     * [margin()] returns how much higher the chosen move scored than the
+     * second best move, which is negative if a better move was passed over,
+     * or None if there is nothing to compare.
+     */
+    pub fn margin(&self) -> Option<f64> {
+        let (_, second) = self.alternatives.first()?;
+        return Some(self.score? - second);
+    }
+
+    /**
+     * [summary(b)] describes the decision made in board [b] for the logs.
+     */
+    pub fn summary(&self, b: &Board) -> String {
+        let mut s = format!(
+            "{} ({}) from {}",
+            to_san(b, self.chosen),
+            self.chosen,
+            source_name(self.source)
+        );
+        if let Some(score) = self.score {
+            s += &format!(", score {:.3}", score);
+        }
+        if let Some(margin) = self.margin() {
+            s += &format!(", margin {:.3}", margin);
+        }
+        if self.alternatives.len() > 0 {
+            let others: Vec<String> = self
+                .alternatives
+                .iter()
+                .map(|(m, score)| format!("{} {:.3}", to_san(b, *m), score))
+                .collect();
+            s += &format!(", alternatives {}", others.join(", "));
+        }
+        return s;
+    }
+
+    /**
+     * [to_json(b)] converts the decision made in board [b] into json for the
+     * move log.
+     */
+    pub fn to_json(&self, b: &Board) -> Value {
+        let alternatives: Vec<Value> = self
+            .alternatives
+            .iter()
+            .map(|(m, score)| json!({"move": m.to_string(), "san": to_san(b, *m), "q": score}))
+            .collect();
+        return json!({
+            "source": source_name(self.source),
+            "score": self.score,
+            "margin": self.margin(),
+            "alternatives": alternatives,
+        });
+    }
+}
@@ -0,0 +1,132 @@
+/**
+ * Utility module for rendering boards as text for logs and terminals, with
+ * Unicode pieces, rank and file labels, the squares of the last move in
+ * brackets and a king in check between exclamation marks, optionally followed
+ * by the evaluation of the position. Per-move boards in the Lichess and
+ * self-play game logs are turned on through the "display" object in
+ * config.json, e.g. {"boards": true}, or with --verbose on the command line.
+ */
+use chess::{Board, ChessMove, Color, Piece, Square, ALL_SQUARES};
+use serde_json::Value;
+
+// Whether boards are rendered in game logs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisplaySettings {
+    pub boards: bool,
+}
+
+impl DisplaySettings {
+    /**
+     * [from_config(config)] reads the display settings given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> DisplaySettings {
+        return DisplaySettings {
+            boards: config["display"]["boards"].as_bool().unwrap_or(false),
+        };
+    }
+}
+
+/**
+ * [piece_symbol(piece, color)] returns the Unicode symbol of [piece] of
+ * [color].
+ */
+pub fn piece_symbol(piece: Piece, color: Color) -> char {
+    match (color, piece) {
+        (Color::White, Piece::King) => '♔',
+        (Color::White, Piece::Queen) => '♕',
+        (Color::White, Piece::Rook) => '♖',
+        (Color::White, Piece::Bishop) => '♗',
+        (Color::White, Piece::Knight) => '♘',
+        (Color::White, Piece::Pawn) => '♙',
+        (Color::Black, Piece::King) => '♚',
+        (Color::Black, Piece::Queen) => '♛',
+        (Color::Black, Piece::Rook) => '♜',
+        (Color::Black, Piece::Bishop) => '♝',
+        (Color::Black, Piece::Knight) => '♞',
+        (Color::Black, Piece::Pawn) => '♟',
+    }
+}
+
+/**
+ * [render_square(b, square, last_move)] renders [square] of board [b] as a
+ * three character cell, highlighting it if it is part of [last_move] or holds
+ * a king in check.
+ */
+fn render_square(b: &Board, square: Square, last_move: Option<ChessMove>) -> String {
+    let symbol = match (b.piece_on(square), b.color_on(square)) {
+        (Some(piece), Some(color)) => piece_symbol(piece, color),
+        _ => '·',
+    };
+
+    let in_check = b.piece_on(square) == Some(Piece::King)
+        && b.color_on(square) == Some(b.side_to_move())
+        && b.checkers().popcnt() > 0;
+    let moved = match last_move {
+        Some(m) => m.get_source() == square || m.get_dest() == square,
+        None => false,
+    };
+
+    if in_check {
+        return format!("!{}!", symbol);
+    }
+    if moved {
+        return format!("[{}]", symbol);
+    }
+    return format!(" {} ", symbol);
+}
+
+/**
+ * [render_board(b, last_move, white_bottom)] renders board [b] with
+ * [last_move] highlighted, from White's side if [white_bottom] and otherwise
+ * from Black's side.
+ */
+pub fn render_board(b: &Board, last_move: Option<ChessMove>, white_bottom: bool) -> String {
+    let ranks: Vec<usize> = if white_bottom {
+        (0..8).rev().collect()
+    } else {
+        (0..8).collect()
+    };
+    let files: Vec<usize> = if white_bottom {
+        (0..8).collect()
+    } else {
+        (0..8).rev().collect()
+    };
+
+    let mut rendered = String::new();
+    for rank in &ranks {
+        rendered += &format!("{} ", rank + 1);
+        for file in &files {
+            rendered += &render_square(b, ALL_SQUARES[rank * 8 + file], last_move);
+        }
+        rendered += "\n";
+    }
+    rendered += "  ";
+    for file in &files {
+        rendered += &format!(" {} ", (b'a' + *file as u8) as char);
+    }
+
+    return rendered;
+}
+
+/**
+ * [render_position(b, last_move, white_bottom, eval)] renders board [b] like
+ * [render_board], followed by [eval] if given, the Q-value of [last_move] for
+ * the player who made it.
+ */
+pub fn render_position(
+    b: &Board,
+    last_move: Option<ChessMove>,
+    white_bottom: bool,
+    eval: Option<f64>,
+) -> String {
+    let mut rendered = render_board(b, last_move, white_bottom);
+    if let Some(q) = eval {
+        let mover = match b.side_to_move() {
+            Color::White => "Black",
+            Color::Black => "White",
+        };
+        rendered += &format!("\nEvaluation: {:+.3} for {}", q, mover);
+    }
+    return rendered;
+}
@@ -0,0 +1,116 @@
+/**
+ * Utility module for distilling a large trained network into a smaller one,
+ * by training the smaller network to match the outputs of the larger one over
+ * state-action pairs from randomly sampled positions. The pairs are
+ * normalized by the larger network's feature statistics, which the smaller
+ * one is served with.
+ */
+use crate::history::MoveCounters;
+use crate::mdp::{get_action, get_state};
+use crate::normalization::FeatureStats;
+use crate::sampling::random_game_position;
+
+use chess::{Board, MoveGen};
+use neuroflow::FeedForward;
+use rand::Rng;
+use tracing::info;
+
+// Longest random game played to sample a position
+const MAX_SAMPLE_PLIES: usize = 120;
+
+// Number of positions held out to measure how well the student matches
+const PROBE_POSITIONS: usize = 200;
+
+/**
+ * [state_action_pairs(b, counters, player_white, stats)] returns the
+ * state-action vector for every legal move in board [b], reached with move
+ * [counters], depending on whether the player is white, normalized by
+ * [stats].
+ */
+fn state_action_pairs(
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+    stats: &FeatureStats,
+) -> Vec<Vec<f64>> {
+    let state = stats.normalized(&get_state(b, counters, player_white));
+
+    let mut pairs = Vec::new();
+    for m in MoveGen::new_legal(b) {
+        let mut sa = state.clone();
+        sa.append(&mut get_action(m, player_white));
+        pairs.push(sa);
+    }
+
+    return pairs;
+}
+
+/**
+ * [sample_pairs(positions, stats)] samples [positions] random positions, each
+ * from the perspective of a random player, and returns the state-action
+ * vectors of all of their legal moves, normalized by [stats].
+ */
+fn sample_pairs(positions: usize, stats: &FeatureStats) -> Vec<Vec<f64>> {
+    let mut pairs = Vec::new();
+    for _ in 0..positions {
+        let (board, counters) = random_game_position(MAX_SAMPLE_PLIES);
+        let player_white = rand::thread_rng().gen_bool(0.5);
+        pairs.append(&mut state_action_pairs(
+            &board,
+            counters,
+            player_white,
+            stats,
+        ));
+    }
+
+    return pairs;
+}
+
+/**
+ * [mean_squared_error(teacher, student, pairs)] returns the mean squared
+ * difference between the outputs of [teacher] and [student] over the
+ * state-action vectors [pairs].
+ */
+pub fn mean_squared_error(
+    teacher: &mut FeedForward,
+    student: &mut FeedForward,
+    pairs: &[Vec<f64>],
+) -> f64 {
+    let mut total = 0.;
+    for sa in pairs {
+        let diff = teacher.calc(&sa[..])[0] - student.calc(&sa[..])[0];
+        total += diff * diff;
+    }
+
+    return total / pairs.len().max(1) as f64;
+}
+
+/**
+ * [distill(teacher, student, stats, positions, epochs)] trains [student] to
+ * match the outputs of [teacher], whose inputs are normalized by its feature
+ * [stats], over the legal moves of [positions] randomly sampled positions,
+ * making [epochs] passes over them. Returns the mean squared error of the
+ * student on a separate set of probe positions.
+ */
+pub fn distill(
+    teacher: &mut FeedForward,
+    student: &mut FeedForward,
+    stats: &FeatureStats,
+    positions: usize,
+    epochs: usize,
+) -> f64 {
+    // Label every sampled pair with the teacher's output once up front
+    let pairs = sample_pairs(positions, stats);
+    let labels: Vec<f64> = pairs.iter().map(|sa| teacher.calc(&sa[..])[0]).collect();
+    info!("Sampled {} state-action pairs", pairs.len());
+
+    for epoch in 0..epochs {
+        for (sa, label) in pairs.iter().zip(labels.iter()) {
+            student.fit(&sa[..], &[*label]);
+        }
+        info!("Finished distillation epoch {}", epoch + 1);
+    }
+
+    let probe_pairs = sample_pairs(PROBE_POSITIONS, stats);
+    return mean_squared_error(teacher, student, &probe_pairs);
+}
@@ -0,0 +1,369 @@
+/**
+ * Utility module for splitting training between actor processes, which play
+ * games, and a single learner process, which learns from them. Actors connect
+ * to the learner over TCP and stream the experiences of every game they
+ * finish as one json line, e.g.
+ * {"game": "actor-123-7", "player_white": true, "experiences": [...]}, with
+ * each experience encoded as in a replay file, after a first line giving the
+ * replay format they were encoded with, which the learner refuses unless it
+ * matches its own. The learner stores the experiences in the sharded replay
+ * buffer and learns from a sample of it as set by the "replay" settings (after
+ * every game by default), and every few learning passes saves the network as a
+ * checkpoint, promotes it and tells every actor its path, so that they play
+ * their next games with it. Self-play actors reload the network they are told
+ * about, and Lichess actors, which run the daemon, pick up the promoted
+ * checkpoint before each game, so actors and learner share a filesystem.
+ * Configured by the "distributed" object in config.json, e.g.
+ * {"address": "127.0.0.1:7878", "publish_every": 5}, which has the learner
+ * listen on (and the actors connect to) port 7878 and publish the network
+ * after every 5 learning passes.
+ */
+use crate::checkpoint::{read_metadata, CheckpointManager};
+use crate::daemon::learn_from_chunk;
+use crate::error::{BotError, BotResult};
+use crate::mdp::Experience;
+use crate::models::{read_network, white_path};
+use crate::normalization::read_stats;
+use crate::replay::{
+    current_format, experience_from_json, experience_to_json, format_header, parse_format,
+};
+use crate::replay_shards::ShardedReplay;
+use crate::sampling::{config_seed, rng_from_config};
+use crate::selfplay::{game_seed, SelfPlaySettings};
+use crate::shared_replay::{Episode, EpisodeSender, SharedReplayBuffer};
+use crate::shutdown;
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+// Address the learner listens on unless configured otherwise
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+// Default number of learning passes between published networks
+const DEFAULT_PUBLISH_EVERY: u64 = 5;
+
+// How often the learner stores the episodes the actors sent
+const COLLECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Where the learner listens and how often it publishes its network
+#[derive(Clone, Debug)]
+pub struct DistributedSettings {
+    pub address: String,
+    pub publish_every: usize, // learning passes
+}
+
+// An actor's connection to the learner, along with the path of the last
+// network the learner published
+pub struct LearnerLink {
+    pub address: String,
+    stream: TcpStream,
+    published: Arc<Mutex<Option<String>>>,
+}
+
+impl DistributedSettings {
+    /**
+     * [from_config(config)] reads the distributed training settings given by
+     * the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> DistributedSettings {
+        let settings = &config["distributed"];
+        return DistributedSettings {
+            address: settings["address"]
+                .as_str()
+                .unwrap_or(DEFAULT_ADDRESS)
+                .to_string(),
+            publish_every: settings["publish_every"]
+                .as_u64()
+                .unwrap_or(DEFAULT_PUBLISH_EVERY)
+                .max(1) as usize,
+        };
+    }
+}
+
+impl LearnerLink {
+    /**
+     * [connect(address)] connects to the learner listening on [address] and
+     * starts following the networks it publishes.
+     */
+    pub fn connect(address: &str) -> io::Result<LearnerLink> {
+        let mut stream = TcpStream::connect(address)?;
+        writeln!(stream, "{}", format_header(&current_format()))?;
+
+        let published = Arc::new(Mutex::new(None));
+        let latest = published.clone();
+        let reader = BufReader::new(stream.try_clone()?);
+        thread::spawn(move || {
+            for line in reader.lines().map_while(Result::ok) {
+                let message: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+                if let Some(path) = message["weights"].as_str() {
+                    *latest.lock().unwrap() = Some(path.to_string());
+                }
+            }
+        });
+
+        return Ok(LearnerLink {
+            address: address.to_string(),
+            stream,
+            published,
+        });
+    }
+
+    /**
+     * [from_config(config)] connects to the learner given by the parsed
+     * [config] if the process is an actor, as set by "actor" in its
+     * "distributed" object, and returns None otherwise.
+     */
+    pub fn from_config(config: &Value) -> io::Result<Option<LearnerLink>> {
+        if !config["distributed"]["actor"].as_bool().unwrap_or(false) {
+            return Ok(None);
+        }
+        let address = DistributedSettings::from_config(config).address;
+        let link = LearnerLink::connect(&address)?;
+        info!("Sending experiences to the learner at {}", address);
+        return Ok(Some(link));
+    }
+
+    /**
+     * [published()] returns the path of the last network the learner
+     * published, or None if it has not published one.
+     */
+    pub fn published(&self) -> Option<String> {
+        return self.published.lock().unwrap().clone();
+    }
+
+    /**
+     * [send_episode(game_id, player_white, experiences)] sends the
+     * [experiences] of game [game_id], gathered by the player whose color is
+     * given by [player_white], to the learner, connecting to it again once if
+     * the connection was lost.
+     */
+    pub fn send_episode(
+        &mut self,
+        game_id: &str,
+        player_white: bool,
+        experiences: &[Experience],
+    ) -> io::Result<()> {
+        let encoded: Vec<Value> = experiences
+            .iter()
+            .map(|e| experience_to_json(e, player_white))
+            .collect();
+        let message = json!({
+            "game": game_id,
+            "player_white": player_white,
+            "experiences": encoded,
+        });
+
+        if writeln!(self.stream, "{}", message).is_ok() {
+            return Ok(());
+        }
+        warn!("Lost the learner at {}, reconnecting", self.address);
+        *self = LearnerLink::connect(&self.address)?;
+        return writeln!(self.stream, "{}", message);
+    }
+}
+
+/**
+ * [episode_from_json(message)] converts a line sent by an actor into the
+ * episode it carries, or None if it is malformed.
+ */
+fn episode_from_json(message: &str) -> Option<Episode> {
+    let json: Value = serde_json::from_str(message).ok()?;
+    let experiences = json["experiences"]
+        .as_array()?
+        .iter()
+        .map(|e| experience_from_json(e).0)
+        .collect();
+    return Some(Episode {
+        game_id: json["game"].as_str()?.to_string(),
+        player_white: json["player_white"].as_bool()?,
+        experiences,
+    });
+}
+
+/**
+ * [serve_actor(stream, episodes, weights)] receives the episodes the actor
+ * connected on [stream] sends, passing them on to the replay buffer through
+ * [episodes], and tells it the path of every network published on
+ * [weights], starting with the current one, until it disconnects.
+ */
+async fn serve_actor(
+    stream: tokio::net::TcpStream,
+    episodes: EpisodeSender,
+    mut weights: watch::Receiver<Option<String>>,
+) -> BotResult<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+
+    let header = lines.next_line().await?.unwrap_or_default();
+    let format = parse_format(&header)?;
+    if format != current_format() {
+        return Err(BotError::Config(format!(
+            "actor sends replay format {:?}, expected {:?}",
+            format,
+            current_format()
+        )));
+    }
+
+    let mut current = weights.borrow_and_update().clone();
+    loop {
+        if let Some(path) = current.take() {
+            let message = json!({ "weights": path }).to_string() + "\n";
+            write.write_all(message.as_bytes()).await?;
+        }
+        tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => match episode_from_json(&line) {
+                    Some(episode) => episodes.send(episode),
+                    None => warn!("Ignoring malformed episode from an actor"),
+                },
+                None => return Ok(()),
+            },
+            changed = weights.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                current = weights.borrow_and_update().clone();
+            }
+        };
+    }
+}
+
+/**
+ * [publish(config, checkpoints)] saves the white policy network given by the
+ * parsed [config] as the next checkpoint in [checkpoints] and promotes it,
+ * returning its path.
+ */
+fn publish(config: &Value, checkpoints: &CheckpointManager) -> BotResult<String> {
+    let current = white_path(config);
+    let path = checkpoints.save(
+        &read_network(&current)?,
+        &read_stats(&current)?,
+        &read_metadata(&current),
+    )?;
+    checkpoints.promote(&path)?;
+    return Ok(path);
+}
+
+/**
+ * [run_learner(config)] listens for actors at the configured address, stores
+ * the experiences they send in the replay buffer given by the parsed
+ * [config], learns from samples of it and publishes the network to the
+ * actors every few learning passes, until a shutdown is requested.
+ */
+pub async fn run_learner(config: &Value) -> BotResult<()> {
+    let settings = DistributedSettings::from_config(config);
+    let storage = ShardedReplay::from_config(config)?;
+    let (mut buffer, episodes) = SharedReplayBuffer::from_config(config, storage);
+    buffer.learn_every_games = buffer.learn_every_games.max(1);
+    let checkpoints = CheckpointManager::from_config(config);
+    let (publisher, _) = watch::channel(checkpoints.promoted());
+    let listener = TcpListener::bind(&settings.address).await?;
+    info!("Learning from actors on {}", settings.address);
+
+    let mut rng = rng_from_config(config);
+    let mut passes = 0;
+    while !shutdown::requested() {
+        match tokio::time::timeout(COLLECT_INTERVAL, listener.accept()).await {
+            Ok(Ok((stream, peer))) => {
+                info!("Actor {} connected", peer);
+                let actor = serve_actor(stream, episodes.clone(), publisher.subscribe());
+                tokio::spawn(report_actor(peer, actor));
+            }
+            Ok(Err(e)) => warn!("Unable to accept an actor: {}", e),
+            Err(_) => (),
+        };
+
+        if let Err(e) = buffer.collect() {
+            warn!("Unable to store experiences: {}", e);
+        }
+        if !buffer.learning_due() {
+            continue;
+        }
+        match buffer.storage.sample(buffer.sample_size, &mut rng) {
+            Ok(sample) => {
+                tokio::task::block_in_place(|| learn_from_chunk(config, &sample, &mut rng));
+                info!("Learned from {} sampled experiences.", sample.len());
+            }
+            Err(e) => warn!("Unable to sample replay buffer: {}", e),
+        };
+        buffer.mark_learned();
+        passes += 1;
+
+        if passes % settings.publish_every == 0 {
+            let path = publish(config, &checkpoints)?;
+            info!("Published {} to the actors", path);
+            publisher.send_replace(Some(path));
+        }
+    }
+
+    if let Err(e) = buffer.collect() {
+        warn!("Unable to store experiences: {}", e);
+    }
+    info!("Shut down");
+    return Ok(());
+}
+
+/**
+ * [report_actor(peer, actor)] serves the actor at [peer] with [actor],
+ * reporting when it disconnects or fails.
+ */
+async fn report_actor(peer: SocketAddr, actor: impl std::future::Future<Output = BotResult<()>>) {
+    match actor.await {
+        Ok(()) => info!("Actor {} disconnected", peer),
+        Err(e) => warn!("Actor {} failed: {}", peer, e),
+    };
+}
+
+/**
+ * [run_selfplay_actor(config, games)] plays [games] self-play games, or plays
+ * forever if None, as set by the parsed [config] without learning from them,
+ * sending their experiences to the learner and switching to every network it
+ * publishes before the next game. Stops early when a shutdown is requested.
+ */
+pub fn run_selfplay_actor(config: &Value, games: Option<usize>) -> BotResult<()> {
+    let address = DistributedSettings::from_config(config).address;
+    let mut link = LearnerLink::connect(&address)?;
+    info!("Sending experiences to the learner at {}", address);
+    let mut settings = SelfPlaySettings::from_config(config);
+    let mut policy_path = white_path(config);
+    let mut network = read_network(&policy_path)?;
+    let mut stats = read_stats(&policy_path)?;
+    let actor_seed = match config["selfplay"]["seed"].as_u64().or(config_seed(config)) {
+        Some(seed) => seed,
+        None => rand::thread_rng().gen(),
+    };
+    info!("Actor seed: {}", actor_seed);
+
+    let mut played = 0;
+    while games.map_or(true, |n| played < n) && !shutdown::requested() {
+        if let Some(path) = link.published().filter(|p| !p.eq(&policy_path)) {
+            info!("Switching to published network {}", path);
+            network = read_network(&path)?;
+            stats = read_stats(&path)?;
+            policy_path = path;
+        }
+
+        let game_id = format!("actor-{}-{}", actor_seed, played + 1);
+        let seed = game_seed(actor_seed, played);
+        let (experiences, metrics) =
+            settings.play(&mut network, stats, &policy_path, played, seed, &game_id)?;
+        link.send_episode(&game_id, true, &experiences)?;
+        played += 1;
+        info!(
+            "Sent {} experiences from game {} (result {})",
+            experiences.len(),
+            played,
+            metrics.result
+        );
+    }
+    return Ok(());
+}
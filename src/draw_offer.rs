@@ -0,0 +1,182 @@
+/**
+ * Utility module for deciding when the bot offers its opponent a draw. The bot
+ * keeps a history of its evaluations over the game and offers a draw once
+ * they have all stayed near zero for many moves in a row, waiting a number of
+ * moves between offers so that it does not spam its opponent. Offers are
+ * turned on by the "draw_offers" object in config.json, e.g.
+ * {"enabled": true, "threshold": 0.5, "moves": 20, "cooldown": 10,
+ *  "rated_only": true}, which offers a draw in rated games once the evaluation
+ * has been within 0.5 of zero for 20 moves, at most once every 10 moves.
+ *
+ * The module also decides whether to claim a draw that is available by
+ * threefold repetition or the fifty move rule, in self-play and on Lichess
+ * (where offering a draw claims it): the draw is only claimed if the
+ * handcrafted evaluation for the side that could claim it is at most a
+ * threshold, so that a won position that merely repeated is played on. Claims
+ * are configured by the "draw_claims" object in config.json, e.g.
+ * {"enabled": true, "threshold": 1}, which claims unless more than a pawn
+ * ahead. With claims turned off, available draws are never claimed.
+ *
+ * Draws offered by the opponent on Lichess are accepted when the bot's
+ * evaluation of the position is at most a threshold and declined otherwise,
+ * as configured by the "draw_acceptance" object in config.json, e.g.
+ * {"enabled": true, "threshold": -0.5}, which accepts once the bot is behind
+ * by more than 0.5. With acceptance turned off, offers are always declined.
+ */
+use crate::eval::{evaluate, EvalWeights};
+use crate::history::PositionHistory;
+
+use serde_json::Value;
+
+const DEFAULT_THRESHOLD: f64 = 0.5;
+const DEFAULT_MOVES: usize = 20;
+const DEFAULT_COOLDOWN: usize = 10;
+const DEFAULT_CLAIM_THRESHOLD: f64 = 1.;
+const DEFAULT_ACCEPT_THRESHOLD: f64 = 0.;
+
+// When the bot offers draws
+#[derive(Clone, Copy, Debug)]
+pub struct DrawOfferStrategy {
+    pub enabled: bool,
+    pub threshold: f64,
+    pub moves: usize,
+    pub cooldown: usize, // moves between offers
+    pub rated_only: bool,
+}
+
+// When available draws are claimed: if the evaluation for the side that can
+// claim is at most the threshold
+#[derive(Clone, Copy, Debug)]
+pub struct DrawClaimStrategy {
+    pub enabled: bool,
+    pub threshold: f64,
+}
+
+// When the opponent's draw offers are accepted: if the bot's evaluation is at
+// most the threshold
+#[derive(Clone, Copy, Debug)]
+pub struct DrawAcceptStrategy {
+    pub enabled: bool,
+    pub threshold: f64,
+}
+
+// The bot's evaluations over a game, along with when it last offered a draw
+#[derive(Clone, Debug, Default)]
+pub struct EvalHistory {
+    pub evals: Vec<f64>,
+    pub last_offer: Option<usize>, // number of evaluations at the last offer
+}
+
+impl DrawOfferStrategy {
+    /**
+     * [from_config(config)] reads the draw offer strategy from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> DrawOfferStrategy {
+        let settings = &config["draw_offers"];
+        return DrawOfferStrategy {
+            enabled: settings["enabled"].as_bool().unwrap_or(false),
+            threshold: settings["threshold"].as_f64().unwrap_or(DEFAULT_THRESHOLD),
+            moves: settings["moves"]
+                .as_u64()
+                .map_or(DEFAULT_MOVES, |n| n.max(1) as usize),
+            cooldown: settings["cooldown"]
+                .as_u64()
+                .map_or(DEFAULT_COOLDOWN, |n| n as usize),
+            rated_only: settings["rated_only"].as_bool().unwrap_or(true),
+        };
+    }
+
+    /**
+     * [should_offer(history, rated)] returns whether the bot should offer a
+     * draw now given its evaluation [history] in a game that is [rated] or
+     * not.
+     */
+    pub fn should_offer(&self, history: &EvalHistory, rated: bool) -> bool {
+        if !self.enabled || (self.rated_only && !rated) || history.evals.len() < self.moves {
+            return false;
+        }
+        if let Some(last) = history.last_offer {
+            if history.evals.len() - last < self.cooldown {
+                return false;
+            }
+        }
+
+        let recent = &history.evals[history.evals.len() - self.moves..];
+        return recent.iter().all(|e| e.abs() <= self.threshold);
+    }
+}
+
+impl DrawClaimStrategy {
+    /**
+     * [from_config(config)] reads the draw claim strategy from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> DrawClaimStrategy {
+        let settings = &config["draw_claims"];
+        return DrawClaimStrategy {
+            enabled: settings["enabled"].as_bool().unwrap_or(true),
+            threshold: settings["threshold"]
+                .as_f64()
+                .unwrap_or(DEFAULT_CLAIM_THRESHOLD),
+        };
+    }
+
+    /**
+     * [should_claim(history, player_white, weights)] returns whether the
+     * player to move, who is white or not, claims a draw in the latest
+     * position of [history], evaluated under [weights].
+     */
+    pub fn should_claim(
+        &self,
+        history: &PositionHistory,
+        player_white: bool,
+        weights: &EvalWeights,
+    ) -> bool {
+        if !self.enabled || !history.can_declare_draw() {
+            return false;
+        }
+        return evaluate(&history.board(), player_white, weights) <= self.threshold;
+    }
+}
+
+impl DrawAcceptStrategy {
+    /**
+     * [from_config(config)] reads the draw acceptance strategy from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> DrawAcceptStrategy {
+        let settings = &config["draw_acceptance"];
+        return DrawAcceptStrategy {
+            enabled: settings["enabled"].as_bool().unwrap_or(false),
+            threshold: settings["threshold"]
+                .as_f64()
+                .unwrap_or(DEFAULT_ACCEPT_THRESHOLD),
+        };
+    }
+
+    /**
+     * [should_accept(eval)] returns whether the bot accepts a draw offer in a
+     * position it evaluates at [eval], declining if it has no evaluation.
+     */
+    pub fn should_accept(&self, eval: Option<f64>) -> bool {
+        return self.enabled && eval.map_or(false, |e| e <= self.threshold);
+    }
+}
+
+impl EvalHistory {
+    /**
+     * [record(eval)] adds the bot's evaluation [eval] of its latest position.
+     */
+    pub fn record(&mut self, eval: f64) {
+        self.evals.push(eval);
+    }
+
+    /**
+     * [offered()] records that the bot offered a draw after its latest
+     * evaluation.
+     */
+    pub fn offered(&mut self) {
+        self.last_offer = Some(self.evals.len());
+    }
+}
@@ -0,0 +1,52 @@
+/**
+ * Utility module for dueling networks, a kind of policy head (see
+ * policy_head) whose outputs are split into two streams over the shared
+ * hidden layers: the value of the state, V(s), as the first output, and the
+ * advantage of every action index, A(s, a), as the rest. They are combined
+ * into Q-values as Q(s, a) = V(s) + A(s, a) - mean(A(s, ·)), which separates
+ * how good a position is from how much each move matters in it and tends to
+ * stabilize Q-learning over an action space this large. A dueling network is
+ * trained by fitting its outputs to labels chosen so that the error of the
+ * combined Q-value is propagated through both streams. Dueling networks are
+ * created with "head": "dueling" in the "model" object in config.json, and
+ * recognized by their number of outputs when loaded.
+ */
+use crate::action_space::ACTION_SPACE;
+
+// Number of outputs of a dueling network: the state value, then an advantage
+// for every action index
+pub const DUELING_OUTPUTS: usize = ACTION_SPACE + 1;
+
+/**
+ * [mean_advantage(outputs)] returns the mean advantage in the [outputs] of a
+ * dueling network.
+ */
+fn mean_advantage(outputs: &[f64]) -> f64 {
+    return outputs[1..].iter().sum::<f64>() / ACTION_SPACE as f64;
+}
+
+/**
+ * [q_values(outputs)] combines the [outputs] of a dueling network into a
+ * Q-value for every action index.
+ */
+pub fn q_values(outputs: &[f64]) -> Vec<f64> {
+    let value = outputs[0];
+    let mean = mean_advantage(outputs);
+    return outputs[1..].iter().map(|a| value + a - mean).collect();
+}
+
+/**
+ * [labels(outputs, index, target)] returns the labels which, once the
+ * [outputs] of a dueling network are fit to them, bring the Q-value of action
+ * [index] towards [target]: the error is added to the state value and to the
+ * action's advantage, less its share of the mean, and its share of the mean
+ * is taken from every other advantage.
+ */
+pub fn labels(outputs: &[f64], index: usize, target: f64) -> Vec<f64> {
+    let error = target - (outputs[0] + outputs[index + 1] - mean_advantage(outputs));
+    let share = error / ACTION_SPACE as f64;
+    let mut labels: Vec<f64> = outputs.iter().map(|o| o - share).collect();
+    labels[0] = outputs[0] + error;
+    labels[index + 1] = outputs[index + 1] + error - share;
+    return labels;
+}
@@ -0,0 +1,136 @@
+/**
+ * Utility module for optionally shaping self-play rewards with the evaluation
+ * of an external UCI engine (e.g. Stockfish), so that the learner is rewarded
+ * for every move that improves its position rather than only at the end of
+ * the game. Each move's reward is blended with the change in the engine's
+ * evaluation, from the mover's perspective, between the position it was
+ * played in and the position reached after the reply. Evaluations are cached
+ * by position, so every position costs one short engine search. Configured by
+ * the "engine" settings of the "reward" object in config.json, e.g.
+ * {"command": "stockfish", "nodes": 200, "weight": 0.5,
+ *  "centipawn_scale": 0.01, "clip": 2000, "cache_size": 100000},
+ * which searches 200 nodes per position and rewards each move with half of
+ * its usual reward plus half of its evaluation change in pawns, counting
+ * evaluations (and mates) as at most 20 pawns either way. Off unless the
+ * settings are given.
+ */
+use crate::uci_engine::UciEngine;
+
+use chess::{Board, BoardStatus, Color};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use tracing::warn;
+
+const DEFAULT_ENGINE_NODES: u64 = 200;
+const DEFAULT_WEIGHT: f64 = 0.5;
+const DEFAULT_CENTIPAWN_SCALE: f64 = 0.01;
+const DEFAULT_CLIP_CP: i64 = 2000;
+const DEFAULT_CACHE_SIZE: u64 = 100000;
+
+// Rewards shaped by an engine's evaluations, with the evaluations seen so far
+pub struct EngineShaping {
+    pub weight: f64,          // of the evaluation change, against the reward
+    pub centipawn_scale: f64, // reward per centipawn
+    pub clip: i64,            // largest evaluation counted, in centipawns
+    pub cache_size: usize,
+    engine: UciEngine,
+    cache: HashMap<u64, i64>, // position hash to White's evaluation
+}
+
+impl EngineShaping {
+    /**
+     * [from_config(config)] starts the engine shaping rewards as given by the
+     * parsed [config], or returns None if there is none. Full strength is
+     * kept, so only the node limit bounds its evaluations.
+     */
+    pub fn from_config(config: &Value) -> io::Result<Option<EngineShaping>> {
+        let settings = &config["reward"]["engine"];
+        if !settings.is_object() {
+            return Ok(None);
+        }
+
+        let command = settings["command"].as_str().unwrap_or("stockfish");
+        let nodes = settings["nodes"].as_u64().unwrap_or(DEFAULT_ENGINE_NODES);
+        let engine = UciEngine::spawn(command, &[], &format!("go nodes {}", nodes))?;
+        return Ok(Some(EngineShaping {
+            weight: settings["weight"]
+                .as_f64()
+                .unwrap_or(DEFAULT_WEIGHT)
+                .clamp(0., 1.),
+            centipawn_scale: settings["centipawn_scale"]
+                .as_f64()
+                .unwrap_or(DEFAULT_CENTIPAWN_SCALE),
+            clip: settings["clip"].as_i64().unwrap_or(DEFAULT_CLIP_CP),
+            cache_size: settings["cache_size"]
+                .as_u64()
+                .unwrap_or(DEFAULT_CACHE_SIZE)
+                .max(1) as usize,
+            engine,
+            cache: HashMap::new(),
+        }));
+    }
+
+    /**
+     * [white_evaluation(b)] returns the evaluation of board [b] in centipawns
+     * from White's perspective, clipped, read from the cache or else asked of
+     * the engine. Finished games are evaluated without the engine.
+     */
+    fn white_evaluation(&mut self, b: &Board) -> io::Result<i64> {
+        let white_to_move = b.side_to_move() == Color::White;
+        match b.status() {
+            BoardStatus::Checkmate if white_to_move => return Ok(-self.clip),
+            BoardStatus::Checkmate => return Ok(self.clip),
+            BoardStatus::Stalemate => return Ok(0),
+            BoardStatus::Ongoing => (),
+        };
+        if let Some(cp) = self.cache.get(&b.get_hash()) {
+            return Ok(*cp);
+        }
+
+        let score = self.engine.evaluate(b)?.unwrap_or(0);
+        let cp = if white_to_move { score } else { -score };
+        let cp = cp.clamp(-self.clip, self.clip);
+        if self.cache.len() >= self.cache_size {
+            self.cache.clear();
+        }
+        self.cache.insert(b.get_hash(), cp);
+        return Ok(cp);
+    }
+
+    /**
+     * [evaluation_change(before, after, player_white)] returns how much the
+     * engine's evaluation changed from board [before] to board [after] in
+     * centipawns, from the perspective of the player given by
+     * [player_white].
+     */
+    pub fn evaluation_change(
+        &mut self,
+        before: &Board,
+        after: &Board,
+        player_white: bool,
+    ) -> io::Result<i64> {
+        let change = self.white_evaluation(after)? - self.white_evaluation(before)?;
+        return Ok(if player_white { change } else { -change });
+    }
+
+    /**
+     * [blend(reward, before, after, player_white)] returns [reward] for the
+     * move of the player given by [player_white] from board [before] to
+     * board [after], blended with the change in the engine's evaluation by
+     * the configured weight. The reward is left as it is if the engine
+     * fails.
+     */
+    pub fn blend(&mut self, reward: f64, before: &Board, after: &Board, player_white: bool) -> f64 {
+        return match self.evaluation_change(before, after, player_white) {
+            Ok(change) => {
+                let shaped = self.centipawn_scale * change as f64;
+                (1. - self.weight) * reward + self.weight * shaped
+            }
+            Err(e) => {
+                warn!("Unable to evaluate with the engine: {}", e);
+                reward
+            }
+        };
+    }
+}
@@ -0,0 +1,32 @@
+/**
+ * Utility module for the error type shared across the crate. Failures that
+ * used to panic, such as a request to Lichess failing, a malformed FEN or
+ * move, or an unreadable config file, are returned as a BotError instead, so
+ * that the caller can retry, log and carry on, or end a game early while
+ * still keeping what it learned from it.
+ */
+use std::io;
+
+// Everything that can go wrong while the bot is running
+#[derive(Debug, thiserror::Error)]
+pub enum BotError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("I/O failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid config: {0}")]
+    Config(String),
+    #[error("network I/O failed: {0}")]
+    Network(String),
+    #[error("invalid FEN {0}")]
+    InvalidFen(String),
+    #[error("malformed move {0}")]
+    InvalidMove(String),
+    #[error("illegal move {0}")]
+    IllegalMove(String),
+}
+
+// The result of anything that can fail with a BotError
+pub type BotResult<T> = Result<T, BotError>;
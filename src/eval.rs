@@ -0,0 +1,216 @@
+/**
+ * Utility module for handcrafted evaluation of chess positions, with piece
+ * values read from the "eval" object in config.json, e.g.
+ * {"piece_values": {"pawn": 1, "knight": 3, "bishop": 3, "rook": 5, "queen": 10}}.
+ * Positions can also be scored with piece-square tables on top of material.
+ */
+use chess::{Board, Color, Piece, ALL_SQUARES};
+use serde_json::Value;
+
+// Piece-square tables in centipawns from White's side, listed from a8 to h1
+// so they read like a board
+#[rustfmt::skip]
+const PAWN_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+#[rustfmt::skip]
+const KNIGHT_TABLE: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+#[rustfmt::skip]
+const BISHOP_TABLE: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+#[rustfmt::skip]
+const ROOK_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+#[rustfmt::skip]
+const QUEEN_TABLE: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+#[rustfmt::skip]
+const KING_TABLE: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+
+// Value of each piece in a handcrafted evaluation
+#[derive(Clone, Debug)]
+pub struct EvalWeights {
+    pub pawn: f64,
+    pub knight: f64,
+    pub bishop: f64,
+    pub rook: f64,
+    pub queen: f64,
+}
+
+impl Default for EvalWeights {
+    fn default() -> EvalWeights {
+        return EvalWeights {
+            pawn: 1.,
+            knight: 3.,
+            bishop: 3.,
+            rook: 5.,
+            queen: 10.,
+        };
+    }
+}
+
+impl EvalWeights {
+    /**
+     * [from_config(config)] reads the piece values from the parsed [config],
+     * with each missing value taking its default.
+     */
+    pub fn from_config(config: &Value) -> EvalWeights {
+        let values = &config["eval"]["piece_values"];
+        let default = EvalWeights::default();
+        return EvalWeights {
+            pawn: values["pawn"].as_f64().unwrap_or(default.pawn),
+            knight: values["knight"].as_f64().unwrap_or(default.knight),
+            bishop: values["bishop"].as_f64().unwrap_or(default.bishop),
+            rook: values["rook"].as_f64().unwrap_or(default.rook),
+            queen: values["queen"].as_f64().unwrap_or(default.queen),
+        };
+    }
+
+    /**
+     * [piece_value(piece)] returns the value of [piece], which is 0 for the
+     * king.
+     */
+    pub fn piece_value(&self, piece: Piece) -> f64 {
+        match piece {
+            Piece::Pawn => self.pawn,
+            Piece::Knight => self.knight,
+            Piece::Bishop => self.bishop,
+            Piece::Rook => self.rook,
+            Piece::Queen => self.queen,
+            Piece::King => 0.,
+        }
+    }
+}
+
+/**
+ * [material(b, color, weights)] returns the total value under [weights] of the
+ * pieces of [color] in board [b].
+ */
+pub fn material(b: &Board, color: Color, weights: &EvalWeights) -> f64 {
+    let mut total = 0.;
+    for piece in chess::ALL_PIECES {
+        let count = (b.pieces(piece) & b.color_combined(color)).popcnt();
+        total += weights.piece_value(piece) * count as f64;
+    }
+
+    return total;
+}
+
+/**
+ * [point_difference(b, player_white, weights)] returns the material of the
+ * player minus the material of the opponent in board [b] under [weights],
+ * depending on whether the player is white.
+ */
+pub fn point_difference(b: &Board, player_white: bool, weights: &EvalWeights) -> f64 {
+    let (player, opponent) = if player_white {
+        (Color::White, Color::Black)
+    } else {
+        (Color::Black, Color::White)
+    };
+
+    return material(b, player, weights) - material(b, opponent, weights);
+}
+
+/**
+ * [piece_square_table(piece)] returns the piece-square table of [piece].
+ */
+fn piece_square_table(piece: Piece) -> &'static [i32; 64] {
+    match piece {
+        Piece::Pawn => &PAWN_TABLE,
+        Piece::Knight => &KNIGHT_TABLE,
+        Piece::Bishop => &BISHOP_TABLE,
+        Piece::Rook => &ROOK_TABLE,
+        Piece::Queen => &QUEEN_TABLE,
+        Piece::King => &KING_TABLE,
+    }
+}
+
+/**
+ * [positional(b, color)] returns the piece-square table bonus of the pieces
+ * of [color] in board [b], in pawns.
+ */
+pub fn positional(b: &Board, color: Color) -> f64 {
+    let mut total = 0;
+    for square in ALL_SQUARES {
+        if b.color_on(square) != Some(color) {
+            continue;
+        }
+        let piece = b.piece_on(square).unwrap();
+
+        // Tables are listed from a8, so White's ranks are flipped
+        let rank = square.get_rank().to_index();
+        let file = square.get_file().to_index();
+        let index = match color {
+            Color::White => (7 - rank) * 8 + file,
+            Color::Black => rank * 8 + file,
+        };
+        total += piece_square_table(piece)[index];
+    }
+
+    return total as f64 / 100.;
+}
+
+/**
+ * [evaluate(b, player_white, weights)] returns the handcrafted evaluation of
+ * board [b] depending on whether the player is white: the point difference
+ * under [weights] plus the difference in piece-square table bonuses.
+ */
+pub fn evaluate(b: &Board, player_white: bool, weights: &EvalWeights) -> f64 {
+    let (player, opponent) = if player_white {
+        (Color::White, Color::Black)
+    } else {
+        (Color::Black, Color::White)
+    };
+
+    return point_difference(b, player_white, weights) + positional(b, player)
+        - positional(b, opponent);
+}
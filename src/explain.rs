@@ -0,0 +1,184 @@
+/**
+ * Utility module for explaining the network's choice of move in a position,
+ * by removing each piece (other than the kings) in turn and measuring how much
+ * the network's evaluation of its chosen move changes. Pieces whose removal
+ * changes the evaluation the most are the ones driving the decision.
+ */
+use crate::display::piece_symbol;
+use crate::history::MoveCounters;
+use crate::mdp::{best_move_with_score, q_value};
+use crate::notation::to_san;
+use crate::q_function::QFunction;
+
+use chess::{Board, ChessMove, Color, Piece, Square, ALL_SQUARES};
+use std::str::FromStr;
+
+// How much removing a piece changes the evaluation of the chosen move
+#[derive(Clone, Copy, Debug)]
+pub struct Saliency {
+    pub square: Square,
+    pub piece: Piece,
+    pub color: Color,
+    pub delta: f64, // evaluation with the piece minus evaluation without it
+}
+
+// The explanation of the network's choice of move in a position
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    pub board: Board,
+    pub best_move: ChessMove,
+    pub score: f64,
+    pub saliencies: Vec<Saliency>,
+}
+
+/**
+ * [remove_piece(b, square)] returns board [b] with the piece on [square]
+ * removed, dropping castling rights if the position is otherwise invalid, or
+ * None if the resulting position is still not valid (e.g. the side not to
+ * move is left in check).
+ */
+pub fn remove_piece(b: &Board, square: Square) -> Option<Board> {
+    let fen = b.to_string();
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+
+    // Expand the piece placement into one character per square, from a8
+    let mut squares: Vec<char> = Vec::new();
+    for c in fields[0].chars() {
+        match c.to_digit(10) {
+            Some(n) => squares.extend(std::iter::repeat('1').take(n as usize)),
+            None if c == '/' => (),
+            None => squares.push(c),
+        };
+    }
+    let rank = square.get_rank().to_index();
+    let file = square.get_file().to_index();
+    squares[(7 - rank) * 8 + file] = '1';
+
+    // Collapse runs of empty squares back into digits
+    let mut placement = String::new();
+    for (i, row) in squares.chunks(8).enumerate() {
+        if i > 0 {
+            placement.push('/');
+        }
+        let mut empty = 0;
+        for c in row {
+            if *c == '1' {
+                empty += 1;
+                continue;
+            }
+            if empty > 0 {
+                placement += &empty.to_string();
+                empty = 0;
+            }
+            placement.push(*c);
+        }
+        if empty > 0 {
+            placement += &empty.to_string();
+        }
+    }
+
+    let rest = fields[3..].join(" ");
+    let with_castling = format!("{} {} {} {}", placement, fields[1], fields[2], rest);
+    if let Ok(board) = Board::from_str(&with_castling) {
+        return Some(board);
+    }
+    return Board::from_str(&format!("{} {} - {}", placement, fields[1], rest)).ok();
+}
+
+/**
+ * [explain(nn, b, counters)] explains the move policy network [nn] chooses for
+ * the side to move in board [b], reached with move [counters], or returns None
+ * if there are no legal moves. If the chosen move is no longer legal once a
+ * piece is removed, the best move in the new position is evaluated instead.
+ */
+pub fn explain<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+) -> Option<Explanation> {
+    let player_white = b.side_to_move() == Color::White;
+    let (best_move, score) = best_move_with_score(nn, b, counters, player_white)?;
+
+    let mut saliencies = Vec::new();
+    for square in ALL_SQUARES {
+        let (piece, color) = match (b.piece_on(square), b.color_on(square)) {
+            (Some(Piece::King), _) | (None, _) | (_, None) => continue,
+            (Some(p), Some(c)) => (p, c),
+        };
+        let perturbed = match remove_piece(b, square) {
+            Some(board) => board,
+            None => continue,
+        };
+
+        let perturbed_score = if perturbed.legal(best_move) {
+            q_value(nn, &perturbed, counters, player_white, best_move)
+        } else {
+            match best_move_with_score(nn, &perturbed, counters, player_white) {
+                Some((_, s)) => s,
+                None => continue,
+            }
+        };
+        saliencies.push(Saliency {
+            square,
+            piece,
+            color,
+            delta: score - perturbed_score,
+        });
+    }
+    saliencies.sort_by(|a, b| b.delta.abs().partial_cmp(&a.delta.abs()).unwrap());
+
+    return Some(Explanation {
+        board: *b,
+        best_move,
+        score,
+        saliencies,
+    });
+}
+
+impl Explanation {
+    /**
+     * [report()] formats the explanation as the chosen move, a map of how
+     * much each square's piece contributes to its evaluation, and the pieces
+     * ranked by contribution.
+     */
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "Best move {} ({}) with evaluation {:.3}\n\n",
+            to_san(&self.board, self.best_move),
+            self.best_move,
+            self.score
+        );
+
+        for rank in (0..8).rev() {
+            report += &format!("{} ", rank + 1);
+            for file in 0..8 {
+                let square = ALL_SQUARES[rank * 8 + file];
+                let cell = match self.saliencies.iter().find(|s| s.square == square) {
+                    Some(s) => format!("{:+7.2}", s.delta),
+                    None => match (self.board.piece_on(square), self.board.color_on(square)) {
+                        (Some(p), Some(c)) => format!("{:>7}", piece_symbol(p, c)),
+                        _ => format!("{:>7}", "·"),
+                    },
+                };
+                report += &cell;
+            }
+            report += "\n";
+        }
+        report += "  ";
+        for file in 0..8 {
+            report += &format!("{:>7}", (b'a' + file as u8) as char);
+        }
+        report += "\n\n";
+
+        for s in &self.saliencies {
+            report += &format!(
+                "{} on {}: {:+.3}\n",
+                piece_symbol(s.piece, s.color),
+                s.square,
+                s.delta
+            );
+        }
+
+        return report;
+    }
+}
@@ -0,0 +1,97 @@
+/**
+ * Utility module for the state of a game that move selection can depend on
+ * beyond the current board: the positions reached so far, both sides' clocks,
+ * who the opponent is and whether they have offered a draw. Agents are given
+ * the context of the game rather than a bare board, so that strategies such as
+ * avoiding repetitions, managing time or playing for a win against weaker
+ * opponents have what they need. Agents draw their random decisions from the
+ * context's generator, so that seeding it makes a game reproducible.
+ */
+use crate::history::PositionHistory;
+use crate::limits::{SearchLimit, SideClock};
+use crate::opponent::OpponentProfile;
+
+use chess::{Board, ChessMove, Color};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+// The state of a game as seen by the side to move
+#[derive(Clone, Debug)]
+pub struct GameContext {
+    pub history: PositionHistory,
+    pub clocks: (SideClock, SideClock), // White's and Black's
+    pub opponent: Option<OpponentProfile>,
+    pub draw_offered: bool, // whether the opponent's draw offer is pending
+    pub rng: StdRng,        // source of the agents' random decisions
+}
+
+impl GameContext {
+    /**
+     * [new(start, limits)] creates the context of a game played from board
+     * [start] with White and Black searching within their [limits], whose
+     * random decisions are unseeded.
+     */
+    pub fn new(start: &Board, limits: (SearchLimit, SearchLimit)) -> GameContext {
+        return GameContext {
+            history: PositionHistory::new(start),
+            clocks: (SideClock::new(limits.0), SideClock::new(limits.1)),
+            opponent: None,
+            draw_offered: false,
+            rng: StdRng::from_entropy(),
+        };
+    }
+
+    /**
+     * [board()] returns the current position of the game.
+     */
+    pub fn board(&self) -> Board {
+        return self.history.board();
+    }
+
+    /**
+     * [player_white()] returns whether the side to move is white.
+     */
+    pub fn player_white(&self) -> bool {
+        return self.board().side_to_move() == Color::White;
+    }
+
+    /**
+     * [ply()] returns the ply of the next move, starting from 1.
+     */
+    pub fn ply(&self) -> usize {
+        return self.history.ply() + 1;
+    }
+
+    /**
+     * [clock(player_white)] returns the clock of the side that plays white or
+     * not.
+     */
+    pub fn clock(&self, player_white: bool) -> &SideClock {
+        return if player_white {
+            &self.clocks.0
+        } else {
+            &self.clocks.1
+        };
+    }
+
+    /**
+     * [clock_to_move()] returns the clock of the side to move, which is
+     * charged for the nodes it evaluates.
+     */
+    pub fn clock_to_move(&mut self) -> &mut SideClock {
+        return if self.player_white() {
+            &mut self.clocks.0
+        } else {
+            &mut self.clocks.1
+        };
+    }
+
+    /**
+     * [make_move(m)] plays move [m] in the current position, which answers any
+     * pending draw offer.
+     */
+    pub fn make_move(&mut self, m: ChessMove) {
+        self.history.make_move(m);
+        self.draw_offered = false;
+    }
+}
@@ -0,0 +1,995 @@
+/**
+ * Utility module for playing a single game on Lichess with the policy network,
+ * collecting the experiences gained along the way. Moves can instead be
+ * selected by any agent given by the "agent" settings of the "lichess" object
+ * in config.json, e.g. {"agent": {"kind": "search", "epsilon": 0.1}}, or
+ * {"agent": "mcts"} to search ahead with the policy network, in which case the
+ * searched values of the bot's moves are kept with its experiences. In timed
+ * games the time manager budgets the bot's thinking time from its clock and
+ * increment, which bounds searches by nodes and by a deadline. The opponent's
+ * draw offers are answered by the bot's evaluation, their takeback proposals
+ * are always declined, the bot resigns hopeless positions as set by the
+ * resignation settings, and games ending by resignation, on time or by an
+ * abort are rewarded by the result Lichess reports rather than the board.
+ * The bot greets its opponent in the player chat, says goodbye once the game
+ * is over, answers "!eval" with its evaluation and logs what others say.
+ * With "no_learn": true, which the --no-learn option sets, the bot plays
+ * without exploring and keeps no experiences, so that nothing is learned
+ * from its games and its networks are never written, e.g. in rated games.
+ * A game the bot was playing when its process died is resumed on restart
+ * from a fresh snapshot of it, along with the experiences saved for recovery
+ * (see recovery). While waiting on the opponent the bot ponders their likely
+ * replies as set by the pondering settings (see ponder).
+ */
+use crate::agent::{agent_from_config, without_exploration};
+use crate::broadcast::Broadcaster;
+use crate::database::{GameDatabase, GameRecord};
+use crate::decision::{MoveDecision, MoveSource};
+use crate::display::{render_position, DisplaySettings};
+use crate::draw_offer::{DrawAcceptStrategy, DrawClaimStrategy, DrawOfferStrategy, EvalHistory};
+use crate::error::{BotError, BotResult};
+use crate::eval::EvalWeights;
+use crate::game_context::GameContext;
+use crate::history::{MoveCounters, PositionHistory};
+use crate::idle_learning::TurnSignal;
+use crate::lichess::{
+    ChatSettings, Clock, Event, GameFull, GameState, GameUpdate, LichessClient, MoveResponse,
+};
+use crate::limits::{SearchLimit, SideClock};
+use crate::logging::game_span;
+use crate::mdp::{
+    best_scored_move, evaluate_game_position_until, get_action, get_reward, get_state_with_history,
+    move_by_scores_with_bonus, q_value, Experience, ExperienceMeta, ExperienceSource, LOSS_REWARD,
+    WIN_REWARD,
+};
+use crate::models::ModelRegistry;
+use crate::move_log::MoveLog;
+use crate::normalization::Normalized;
+use crate::opponent::{OpponentProfile, BLUNDER_THRESHOLD, OPENING_PLIES};
+use crate::pgn::{result_from_reward, PgnGame, PgnLog};
+use crate::policy_head::{is_policy_head, NetworkHead};
+use crate::ponder::Ponderer;
+use crate::quantize::{move_by_quantized, QuantizedInference};
+use crate::recovery::{GameRecovery, PendingMove};
+use crate::repertoire::Repertoire;
+use crate::resign::ResignStrategy;
+use crate::reward::RewardShaping;
+use crate::shutdown;
+use crate::tablebase::Tablebase;
+use crate::time_manager::TimeManager;
+use crate::watchdog::fallback_move;
+use crate::zoo::ModelZoo;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn, Instrument};
+
+// Evaluates to the value of [result], or ends the game loop labelled [label]
+// with its error, so that the game is still recorded and its experiences kept
+macro_rules! or_abort {
+    ($label:lifetime, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(e) => break $label Some(BotError::from(e)),
+        }
+    };
+}
+
+// Delay before posting a move again that failed to be posted
+const MOVE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/**
+ * [move_already_played(lichess, game_id, ply, uci_str)] returns whether move
+ * [uci_str] was played at ply [ply] of game [game_id] according to the game
+ * stream.
+ */
+async fn move_already_played(
+    lichess: &LichessClient,
+    game_id: &str,
+    ply: usize,
+    uci_str: &str,
+) -> BotResult<bool> {
+    let played = match lichess.stream_game(game_id).await? {
+        Some(game) => game.state.moves.split_whitespace().nth(ply - 1) == Some(uci_str),
+        None => false,
+    };
+
+    return Ok(played);
+}
+
+/**
+ * [post_move(lichess, game_id, ply, uci_str)] posts move [uci_str] for ply
+ * [ply] of game [game_id] once, the client retrying it only while it cannot
+ * have reached Lichess. A move that was rejected or failed after it may have
+ * reached Lichess still counts as posted if the game shows it was played at
+ * [ply], which happens when the move went through without its response
+ * arriving. Returns whether the move was posted, and otherwise the game loop
+ * posts it again once the game shows it was not played.
+ */
+async fn post_move(
+    lichess: &LichessClient,
+    game_id: &str,
+    ply: usize,
+    uci_str: &str,
+) -> BotResult<bool> {
+    match lichess.make_move(game_id, uci_str).await {
+        Ok(MoveResponse::Accepted) => return Ok(true),
+        Ok(MoveResponse::Rejected(reason)) => {
+            warn!("Move {} was rejected: {}", uci_str, reason)
+        }
+        Ok(MoveResponse::Failed(status)) => {
+            warn!("Posting move {} failed with status {}", uci_str, status)
+        }
+        Err(e) => warn!("Posting move {} failed: {}", uci_str, e),
+    };
+
+    return move_already_played(lichess, game_id, ply, uci_str).await;
+}
+
+/**
+ * [find_color(lichess, game_id)] reads the event stream until it lists game
+ * [game_id], returning whether the bot plays white in it, or None if the
+ * stream ends first.
+ */
+async fn find_color(lichess: &LichessClient, game_id: &str) -> BotResult<Option<bool>> {
+    let mut events = lichess.event_stream();
+    loop {
+        match events.next_line().await? {
+            Some(Event::GameStart { game }) if game.id.eq(game_id) => {
+                return Ok(Some(game.color.eq("white")))
+            }
+            Some(_) => continue,
+            None => return Ok(None),
+        };
+    }
+}
+
+/**
+ * [snapshot_color(lichess, game)] returns whether the bot plays white in the
+ * full game [game], by the id of its account, or None if Lichess did not
+ * send the id or the bot does not play in the game.
+ */
+async fn snapshot_color(lichess: &LichessClient, game: &GameFull) -> BotResult<Option<bool>> {
+    let me = match lichess.account_id().await? {
+        Some(id) => id.to_lowercase(),
+        None => return Ok(None),
+    };
+    let plays = |white: bool| {
+        game.player(white).id.as_deref().map(|id| id.to_lowercase()) == Some(me.clone())
+    };
+    if plays(true) {
+        return Ok(Some(true));
+    }
+    if plays(false) {
+        return Ok(Some(false));
+    }
+    return Ok(None);
+}
+
+/**
+ * [lichess_clock(game, white)] returns the clock of the given color in the
+ * full game [game], which is unlimited in games without a clock.
+ */
+fn lichess_clock(game: &GameFull, white: bool) -> SideClock {
+    let limit = match game.clock {
+        Some(Clock { initial, increment }) => SearchLimit::Clock {
+            time_ms: initial,
+            increment_ms: increment,
+        },
+        None => SearchLimit::Unlimited,
+    };
+    let mut clock = SideClock::new(limit);
+    if let Some(ms) = game.state.time_ms(white) {
+        clock.remaining_ms = ms;
+    }
+    return clock;
+}
+
+/**
+ * [final_reward(state, b, white, shaping)] returns the reward of the finished
+ * game with final state [state] and board [b] for the given color, from the
+ * status and winner Lichess reports, so that games won or lost by
+ * resignation or on time are rewarded even though the board is ongoing, with
+ * a loss on time worth the time loss reward of [shaping]. Draws are read from
+ * the board, and aborted games are worth nothing.
+ */
+fn final_reward(state: &GameState, b: &Board, white: bool, shaping: &RewardShaping) -> f64 {
+    let my_color = if white { "white" } else { "black" };
+    let status = state.status.as_deref().unwrap_or("unknown");
+    return match (status, state.winner.as_deref()) {
+        ("aborted", _) | ("noStart", _) => {
+            info!("Game was aborted");
+            0.
+        }
+        (_, None) => get_reward(b, white),
+        ("outoftime", Some(winner)) if winner != my_color => {
+            info!("Lost on time");
+            shaping.time_loss
+        }
+        (_, Some(winner)) if winner == my_color => {
+            info!("Won by {}", status);
+            WIN_REWARD
+        }
+        (_, Some(_)) => {
+            info!("Lost by {}", status);
+            LOSS_REWARD
+        }
+    };
+}
+
+/**
+ * [game_result(state, b, white, claimed_victory)] returns the score of the
+ * finished game for the given color, 1 for a win, 0.5 for a draw and 0 for a
+ * loss: a win if the bot claimed victory, and otherwise from the winner
+ * Lichess reports in the game's final [state], or from the final board [b]
+ * if there is none. Unlike the rewards, which may be shaped, it only depends
+ * on how the game ended. Aborted and unfinished games count as draws.
+ */
+fn game_result(state: Option<&GameState>, b: &Board, white: bool, claimed_victory: bool) -> f64 {
+    if claimed_victory {
+        return 1.;
+    }
+    let my_color = if white { "white" } else { "black" };
+    if let Some(winner) = state.and_then(|s| s.winner.as_deref()) {
+        return if winner == my_color { 1. } else { 0. };
+    }
+    return match b.status() {
+        BoardStatus::Checkmate if (b.side_to_move() == Color::White) == white => 0.,
+        BoardStatus::Checkmate => 1.,
+        _ => 0.5,
+    };
+}
+
+/**
+ * [opponent_opening(moves_str, player_white)] returns the first moves the
+ * opponent of the player played in the space separated uci moves [moves_str],
+ * depending on whether the player is white.
+ */
+fn opponent_opening(moves_str: &str, player_white: bool) -> String {
+    let opponent_parity = if player_white { 1 } else { 0 };
+    let moves: Vec<&str> = moves_str
+        .split_whitespace()
+        .enumerate()
+        .filter(|(i, _)| i % 2 == opponent_parity)
+        .map(|(_, m)| m)
+        .take(OPENING_PLIES)
+        .collect();
+
+    return moves.join(" ");
+}
+
+/**
+ * [learning_disabled(config)] returns whether the parsed [config] has the bot
+ * play without exploring or learning from its games.
+ */
+pub fn learning_disabled(config: &Value) -> bool {
+    return config["lichess"]["no_learn"].as_bool().unwrap_or(false);
+}
+
+/**
+ * [play_game(lichess, config, game_id, models, turns)] plays the Lichess game
+ * with id [game_id] to completion, following the repertoire in the parsed
+ * [config] in the opening and otherwise selecting moves with the policy
+ * network in [models] for the bot's color, or the one the model zoo picks
+ * for the opponent, adjusted to the opponent's profile.
+ * Whenever it is the bot's turn this is signalled through [turns]. The game
+ * is recorded in the game database once over. Returns the
+ * experiences collected over the game, none if learning is disabled, along
+ * with whether the bot played as white. A game aborted by an error once it started, e.g. when Lichess can no
+ * longer be reached, still returns the experiences collected up to then, so
+ * that they are learned from and the network is saved. A game already under
+ * way is resumed from its current ply, with the experiences saved before the
+ * bot's process died. Everything logged while playing it goes to its
+ * transcript, if transcripts are enabled.
+ */
+pub async fn play_game(
+    lichess: &LichessClient,
+    config: &Value,
+    game_id: &str,
+    models: &mut ModelRegistry,
+    turns: &TurnSignal,
+) -> BotResult<(Vec<Experience>, bool)> {
+    return play_game_logged(lichess, config, game_id, models, turns)
+        .instrument(game_span(game_id))
+        .await;
+}
+
+/**
+ * [play_game_logged(lichess, config, game_id, models, turns)] plays the game
+ * as play_game does, within the span of the game.
+ */
+async fn play_game_logged(
+    lichess: &LichessClient,
+    config: &Value,
+    game_id: &str,
+    models: &mut ModelRegistry,
+    turns: &TurnSignal,
+) -> BotResult<(Vec<Experience>, bool)> {
+    let repertoire = Repertoire::from_config(config);
+    let no_learn = learning_disabled(config);
+    let agent_settings = if no_learn {
+        without_exploration(&config["lichess"]["agent"])
+    } else {
+        config["lichess"]["agent"].clone()
+    };
+    let mut agent = agent_from_config(&agent_settings, config);
+    let mut broadcaster = Broadcaster::from_config(config, game_id);
+    let chat = ChatSettings::from_config(config);
+    let database = GameDatabase::from_config(config);
+    let mut quantized_inference = QuantizedInference::from_config(config);
+    let shaping = RewardShaping::from_config(config);
+    let display = DisplaySettings::from_config(config);
+    let draw_offers = DrawOfferStrategy::from_config(config);
+    let draw_claims = DrawClaimStrategy::from_config(config);
+    let draw_acceptance = DrawAcceptStrategy::from_config(config);
+    let eval_weights = EvalWeights::from_config(config);
+    let mut time_manager = TimeManager::from_config(config);
+    let tablebase = Tablebase::from_config(config, &lichess.client);
+    let mut tablebase_label = None; // exact value of the bot's last move, if known
+    let mut claim_ply = None;
+    let mut eval_history = EvalHistory::default();
+    let resignation = ResignStrategy::from_config(config);
+    let mut resign_evals = Vec::new(); // the bot's evaluations judging resignation
+    let move_log = MoveLog::from_config(config);
+    let game_log = move_log.game(game_id);
+    let zoo = ModelZoo::from_config(config);
+    let recovery = GameRecovery::from_config(config);
+    let mut ponderer = Ponderer::from_config(config);
+
+    // Initialize board, which may start from a custom position (e.g. a
+    // material-odds game from an accepted fromPosition challenge)
+    let mut initial_board = Board::default();
+    let mut initial_counters = MoveCounters::default();
+    let mut moves_str = String::new();
+    let mut time_control = String::from("unlimited");
+    let mut termination = String::from("unknown");
+
+    // Opponent tracking
+    let mut profile: Option<OpponentProfile> = None;
+    let mut prev_eval: Option<f64> = None;
+    let mut opponent_moves = 0;
+    let mut opponent_blunders = 0;
+    let mut expected_eval: Option<f64> = None; // Q-value of the bot's last move
+
+    // The last move posted along with its ply, which is posted again rather
+    // than selecting a different move if it did not go through
+    let mut posted_move: Option<(usize, String)> = None;
+
+    // Game state booleans
+    let mut first_move = true;
+    let mut game_over = false;
+    let mut claimed_victory = false;
+    let mut repost = false;
+    let mut greeted = false;
+
+    // Fetch a snapshot of the game, which may already be under way, and find
+    // the bot's color in it from the bot's account, or else from the event
+    // stream, which lists every ongoing game of the bot's when it is opened
+    let snapshot = match lichess.stream_game(game_id).await? {
+        Some(g) if g.state.in_progress() => g,
+        _ => {
+            info!("Game {} is not ongoing", game_id);
+            return Ok((Vec::new(), true));
+        }
+    };
+    let timeout = lichess.watchdog.stream_timeout;
+    let color_white = match snapshot_color(lichess, &snapshot).await? {
+        Some(white) => white,
+        None => match tokio::time::timeout(timeout, find_color(lichess, game_id)).await {
+            Ok(Ok(Some(white))) => white,
+            Ok(Err(e)) => return Err(e),
+            _ => {
+                info!("Game {} is not ongoing", game_id);
+                return Ok((Vec::new(), true));
+            }
+        },
+    };
+
+    // Reconstruct the board from the snapshot
+    if let Some(fen) = snapshot.start_fen() {
+        initial_board = Board::from_str(fen).map_err(|_| BotError::InvalidFen(fen.to_string()))?;
+        initial_counters = MoveCounters::from_fen(fen);
+    }
+    let mut history = PositionHistory::with_counters(&initial_board, initial_counters);
+    history.update(&initial_board, &snapshot.state.moves)?;
+    let mut board = history.board();
+
+    // Follow the game stream, which starts with the full game and then sends
+    // every change to its state
+    let mut updates = lichess.game_stream(game_id);
+    let mut current: Option<GameFull> = None;
+    let mut claim_at: Option<tokio::time::Instant> = None;
+
+    // Initialize experience replay memory logic
+    let mut curr_experience = Experience {
+        state: Vec::new(),
+        action: Vec::new(),
+        reward: 0.,
+        next_state: Vec::new(),
+        next_board: board.clone(),
+        clock: None,
+        done: false,
+        search_target: None,
+        meta: ExperienceMeta::default(),
+    };
+    let mut experience_memory: Vec<Experience> = Vec::new();
+    let mut move_board = board.clone(); // where the bot made its last move
+    let mut bot_q = HashMap::new(); // the bot's Q-value of its move at each ply
+
+    // Resume the experiences saved before the process died, along with the
+    // bot's latest move, which is posted again if it was never played. The
+    // bot's earlier moves that have no saved experience still count towards
+    // the number of moves played
+    let mut skipped_moves = 0;
+    let played = history.moves().len();
+    if !no_learn {
+        let recovered = recovery
+            .load(game_id)
+            .filter(|r| r.consistent_with(&snapshot.state.moves))
+            .unwrap_or_default();
+        experience_memory = recovered.experiences;
+        let mut pending_played = false;
+        if let Some(p) = recovered.pending {
+            pending_played = p.ply <= played;
+            first_move = false;
+            move_board = p.board;
+            curr_experience = p.experience;
+            posted_move = Some((p.ply, p.uci));
+        }
+        let bot_started = (initial_board.side_to_move() == Color::White) == color_white;
+        let bot_moves = (0..played).filter(|i| (i % 2 == 0) == bot_started).count();
+        skipped_moves = bot_moves.saturating_sub(experience_memory.len() + pending_played as usize);
+        if played > 0 {
+            info!(
+                "Resuming game {} after {} plies with {} saved experiences",
+                game_id,
+                played,
+                experience_memory.len()
+            );
+        }
+    }
+
+    // The game loop, which ends with the error that aborted it, if any
+    let aborted = 'game: loop {
+        // Wait for my turn or the end of the game, unless a move has to be
+        // posted again
+        while !repost {
+            let pondering = ponderer.as_ref().map_or(false, |p| p.pending());
+            let update = match claim_at {
+                // Ponder the opponent's replies whenever no update is waiting
+                _ if pondering => {
+                    match tokio::time::timeout(Duration::ZERO, updates.next_line()).await {
+                        Ok(update) => or_abort!('game, update),
+                        Err(_) => {
+                            ponderer.as_mut().unwrap().step(models, color_white);
+                            tokio::task::yield_now().await;
+                            continue;
+                        }
+                    }
+                }
+                Some(at) => match tokio::time::timeout_at(at, updates.next_line()).await {
+                    Ok(update) => or_abort!('game, update),
+                    Err(_) => {
+                        // Claim victory once the opponent has been gone long enough
+                        claim_at = None;
+                        if or_abort!('game, lichess.claim_victory(game_id).await) {
+                            claimed_victory = true;
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                None => or_abort!('game, updates.next_line().await),
+            };
+            match update {
+                Some(GameUpdate::GameFull(g)) => current = Some(g),
+                Some(GameUpdate::GameState(state)) => match current.as_mut() {
+                    Some(g) => g.state = state,
+                    None => continue,
+                },
+                Some(GameUpdate::OpponentGone { gone, .. }) if !gone => {
+                    claim_at = None;
+                    continue;
+                }
+                Some(GameUpdate::OpponentGone {
+                    claim_win_in_seconds,
+                    ..
+                }) => {
+                    let secs = claim_win_in_seconds.unwrap_or(0);
+                    info!("Opponent is gone, victory claimable in {}s", secs);
+                    claim_at = Some(tokio::time::Instant::now() + Duration::from_secs(secs));
+                    continue;
+                }
+                Some(GameUpdate::ChatLine {
+                    room,
+                    username,
+                    text,
+                }) => {
+                    info!("[{} chat] {}: {}", room, username, text);
+                    if chat.asks_eval(&text) {
+                        let nn = &mut models.head_for(&board, color_white);
+                        let reply =
+                            broadcaster.chat_text(&board, history.counters(), nn, color_white);
+                        if let Some(reply) = reply {
+                            or_abort!('game, lichess.chat(game_id, &room, &reply).await);
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let g = current.as_ref().unwrap();
+            if !g.state.in_progress() {
+                game_over = true;
+                break;
+            }
+            if g.state.takeback_offered_by(!color_white) {
+                info!("Declining takeback");
+                or_abort!('game, lichess.decline_takeback(game_id).await);
+            }
+            if g.white_to_move() == color_white {
+                break;
+            }
+            info!("Waiting for my turn!");
+        }
+        repost = false;
+        let _thinking = turns.think();
+
+        // Opponent left the game, so record the win and end game loop
+        if claimed_victory {
+            info!("Claimed victory!");
+            let moves = skipped_moves + experience_memory.len() + 1;
+            curr_experience.reward = shaping.shape(WIN_REWARD, moves)
+                + shaping.material(&move_board, &board, color_white, &eval_weights);
+            curr_experience.next_state = get_state_with_history(&history, color_white);
+            curr_experience.next_board = board.clone();
+            curr_experience.done = true;
+            experience_memory.push(curr_experience.clone());
+            debug!(
+                event = "reward",
+                reward = curr_experience.reward,
+                done = true,
+                "Reward recorded"
+            );
+            break 'game None;
+        }
+
+        // The latest state of the game, holding the move list
+        let game = match &current {
+            Some(g) => g.clone(),
+            None => break 'game None,
+        };
+
+        // Read the starting position, given as a FEN unless it is "startpos"
+        if let Some(fen) = game.start_fen() {
+            let parsed = Board::from_str(fen).map_err(|_| BotError::InvalidFen(fen.to_string()));
+            initial_board = or_abort!('game, parsed);
+        }
+
+        // Update board and ply count from moves string, applying only the
+        // moves played since the last update
+        moves_str = game.state.moves.clone();
+        if let Err(e) = history.update(&initial_board, &moves_str) {
+            warn!("Ignoring game state with {}", e);
+            continue;
+        }
+
+        // Read the time control and the bot's clock, given in milliseconds
+        if let Some(c) = game.clock {
+            time_control = format!("{}+{}", c.initial / 1000, c.increment / 1000);
+        }
+        let clock = game.state.time_ms(color_white).map(|ms| ms as f64 / 1000.);
+        if let Some(status) = &game.state.status {
+            termination = status.to_string();
+        }
+        board = history.board();
+        if history.perpetual_check(!board.side_to_move()) {
+            info!("Opponent is giving perpetual check");
+        }
+        let ply = moves_str.split_whitespace().count() + 1;
+
+        // Greet the opponent when the game starts, but not when joining a game
+        // that is already under way
+        if !greeted {
+            greeted = true;
+            if let Some(text) = chat.greeting.as_ref().filter(|_| ply <= 2) {
+                or_abort!('game, lichess.chat(game_id, "player", text).await);
+            }
+        }
+
+        // Look up the opponent's history once their identity is known, and
+        // let the zoo pick the model that plays them
+        if profile.is_none() {
+            let opponent = game.player(!color_white);
+            let name = opponent.id.as_deref().unwrap_or("unknown");
+            let p = OpponentProfile::load(&database, config, name, opponent.rating);
+            info!("Opponent: {}", p.summary());
+            profile = Some(p);
+            if let Some(path) = zoo.as_ref().and_then(|z| z.select_path(opponent)) {
+                match ModelRegistry::serving(config, Some(&path)) {
+                    Ok(selected) => {
+                        info!("Playing with model {}", path);
+                        *models = selected;
+                    }
+                    Err(e) => warn!("Unable to play with model {}: {}", path, e),
+                };
+            }
+        }
+
+        // Retry the move already selected for this ply if posting it failed
+        if let Some((posted_ply, uci_str)) = &posted_move {
+            if *posted_ply == ply && !game_over {
+                tokio::time::sleep(MOVE_RETRY_DELAY).await;
+                info!("Retrying move {}", uci_str);
+                repost = !or_abort!('game, post_move(lichess, game_id, ply, uci_str).await);
+                continue;
+            }
+        }
+
+        // Grab board state and reward, where a finished game is rewarded by
+        // the result Lichess reports even if the board is still ongoing
+        let pondered = ponderer.as_mut().and_then(|p| p.take(&history));
+        let board_state = match &pondered {
+            Some(p) => p.state.clone(),
+            None => get_state_with_history(&history, color_white),
+        };
+        let board_reward = if game_over {
+            final_reward(&game.state, &board, color_white, &shaping)
+        } else {
+            get_reward(&board, color_white)
+        };
+
+        // Update previous experience and push to replay memory if not first move
+        if first_move {
+            first_move = false;
+        } else {
+            let moves = skipped_moves + experience_memory.len() + 1;
+            curr_experience.reward = shaping.shape(board_reward, moves)
+                + shaping.material(&move_board, &board, color_white, &eval_weights);
+            curr_experience.next_state = board_state.clone();
+            curr_experience.next_board = board.clone();
+            curr_experience.done = game_over || board.status() != BoardStatus::Ongoing;
+            if let (Some(value), false) = (tablebase_label, curr_experience.done) {
+                curr_experience.reward = value;
+                curr_experience.done = true;
+            }
+            experience_memory.push(curr_experience.clone());
+            if !no_learn {
+                if let Err(e) = recovery.record_experience(game_id, &curr_experience, color_white) {
+                    warn!("Unable to save the experience for recovery: {}", e);
+                }
+            }
+            debug!(
+                event = "reward",
+                reward = curr_experience.reward,
+                done = curr_experience.done,
+                "Reward recorded"
+            );
+        }
+
+        // Last experience has been recorded, we can now end game loop, which
+        // includes positions without legal moves that Lichess has not yet
+        // reported as over
+        if game_over || board.status() != BoardStatus::Ongoing {
+            break 'game None;
+        }
+
+        // Update current experience state
+        curr_experience.state = board_state.clone();
+        curr_experience.clock = clock;
+        move_board = board.clone();
+
+        // Score the bot's moves once, both for its evaluation of the position
+        // and for selecting its move, unless the opponent's reply was pondered.
+        // The watchdog's deadline starts before scoring, so that scoring
+        // counts towards it and stops once it passes
+        let mut deadline = lichess.watchdog.move_deadline();
+        let network_path = models.path_for(&board, color_white).to_string();
+        let stats = models.stats_for(&board, color_white);
+        let nn = models.network_for(&board, color_white);
+        let policy_head = is_policy_head(nn);
+        let scores = match pondered {
+            Some(p) => p.scores,
+            None => evaluate_game_position_until(
+                &history,
+                &mut Normalized::new(NetworkHead::new(nn, policy_head), stats),
+                color_white,
+                deadline,
+            ),
+        };
+
+        // Count the opponent's last move as a blunder if it raised the bot's
+        // evaluation by enough over the Q-value of the bot's move before it
+        let mut ahead = false;
+        if let Some((_, eval)) = best_scored_move(&scores) {
+            ahead = eval > 0.;
+            if let Some(expected) = expected_eval {
+                opponent_moves += 1;
+                if eval - expected > BLUNDER_THRESHOLD {
+                    opponent_blunders += 1;
+                }
+            }
+            prev_eval = Some(eval);
+            eval_history.record(eval);
+        }
+
+        // Resign once the position has been hopeless for long enough
+        if let Some(eval) = resignation.evaluate(&board, color_white, prev_eval, &eval_weights) {
+            resign_evals.push(eval);
+        }
+        if resignation.should_resign(&resign_evals, game.rated) {
+            info!("Resigning");
+            if or_abort!('game, lichess.resign(game_id).await) {
+                continue;
+            }
+            warn!("Resignation was rejected by Lichess");
+        }
+
+        // Claim an available draw, once per position, unless ahead, which
+        // Lichess does when a draw is offered in a claimable position
+        if claim_ply != Some(ply) && draw_claims.should_claim(&history, color_white, &eval_weights)
+        {
+            claim_ply = Some(ply);
+            info!("Claiming a draw");
+            if or_abort!('game, lichess.offer_draw(game_id).await) {
+                continue;
+            }
+            warn!("Draw claim was rejected by Lichess");
+        }
+
+        // Answer the opponent's draw offer by the evaluation of the position
+        if game.state.draw_offered_by(!color_white) {
+            if draw_acceptance.should_accept(prev_eval) {
+                info!("Accepting draw offer");
+                if or_abort!('game, lichess.offer_draw(game_id).await) {
+                    continue;
+                }
+                warn!("Draw acceptance was rejected by Lichess");
+            } else {
+                info!("Declining draw offer");
+                or_abort!('game, lichess.decline_draw(game_id).await);
+            }
+        }
+
+        // Select a move
+        info!("Making Move!");
+        let position = board.clone();
+        let mut context = GameContext {
+            history: history.clone(),
+            clocks: (lichess_clock(&game, true), lichess_clock(&game, false)),
+            opponent: profile.clone(),
+            draw_offered: game.state.draw_offered_by(!color_white),
+            rng: StdRng::from_entropy(),
+        };
+        if let Some(remaining_ms) = game.state.time_ms(color_white) {
+            let increment_ms = game
+                .state
+                .increment_ms(color_white)
+                .or(game.clock.map(|c| c.increment))
+                .unwrap_or(0);
+            let plan = time_manager.plan(remaining_ms, increment_ms);
+            info!(
+                "Thinking for up to {}ms ({} nodes)",
+                plan.think.as_millis(),
+                plan.nodes
+            );
+            deadline = deadline.min(Instant::now() + plan.think);
+            let clock = context.clock_to_move();
+            clock.limit = SearchLimit::Nodes(plan.nodes);
+            clock.deadline = Some(deadline);
+        }
+        let started = Instant::now();
+        let opponent = context.opponent.clone().unwrap();
+        let bonus = |b: &Board, m: ChessMove| {
+            opponent.sharpness_bonus(b, m) + history.repetition_bonus(b, m, ahead)
+        };
+        let probe = match &tablebase {
+            Some(tb) => tb.probe(&board).await,
+            None => None,
+        };
+        tablebase_label = probe
+            .filter(|_| tablebase.as_ref().map_or(false, |tb| tb.label))
+            .map(|p| p.value);
+        let decision = match (
+            probe,
+            agent.as_mut(),
+            repertoire.lookup(&board, color_white, ply),
+        ) {
+            (Some(p), _, _) => Some(MoveDecision::new(p.best, MoveSource::Tablebase)),
+            (None, Some(agent), _) => agent.select_move(&mut context),
+            (None, None, Some(m)) => Some(MoveDecision::new(m, MoveSource::Book)),
+            (None, None, None) => match quantized_inference
+                .prepare(nn, stats, &network_path)
+                .filter(|_| !policy_head)
+            {
+                Some(q) => move_by_quantized(q, &board, &board_state, color_white, bonus, deadline),
+                None => move_by_scores_with_bonus(&scores, &board, bonus),
+            },
+        };
+        time_manager.record(context.clock_to_move().nodes, started.elapsed());
+        let decision = match decision.or_else(|| {
+            warn!("Move selection failed, playing a fallback move");
+            fallback_move(&board).map(|m| MoveDecision::new(m, MoveSource::Fallback))
+        }) {
+            Some(d) => d,
+            None => {
+                info!("No legal moves in {}, ending the game", board);
+                break 'game None;
+            }
+        };
+
+        // Track the position after the move in case the game ends before the
+        // opponent replies
+        let uci_str = decision.chosen.to_string();
+        board = board.make_move_new(decision.chosen);
+        curr_experience.action = get_action(decision.chosen, color_white);
+        curr_experience.search_target = decision.target;
+        let behavior_policy = match &agent {
+            Some(a) => a.name(),
+            None => models.path(color_white).to_string(),
+        };
+        curr_experience.meta = ExperienceMeta::new(
+            ExperienceSource::Lichess,
+            Some(game_id),
+            &position,
+            ply,
+            &behavior_policy,
+        );
+        info!("Selected move {}", decision.summary(&position));
+        let q = match scores.iter().find(|(m, _)| *m == decision.chosen) {
+            Some((_, score)) => *score,
+            None => q_value(
+                &mut models.head_for(&position, color_white),
+                &position,
+                history.counters(),
+                color_white,
+                decision.chosen,
+            ),
+        };
+        expected_eval = Some(q);
+        game_log.record(ply, &history.fen(), &position, &decision, q);
+        bot_q.insert(ply, q);
+        if display.boards {
+            let after = board.make_move_new(decision.chosen);
+            info!(
+                "{}",
+                render_position(&after, Some(decision.chosen), color_white, Some(q))
+            );
+        }
+
+        // Save the move before posting it, so that a restart picks it up
+        if !no_learn {
+            let pending = PendingMove {
+                ply,
+                uci: uci_str.clone(),
+                board: position,
+                experience: curr_experience.clone(),
+            };
+            if let Err(e) = recovery.record_pending(game_id, &pending, color_white) {
+                warn!("Unable to save the move for recovery: {}", e);
+            }
+        }
+
+        // Post move
+        if !or_abort!('game, post_move(lichess, game_id, ply, &uci_str).await) {
+            warn!("Unable to post move {}, retrying it", uci_str);
+            repost = true;
+        }
+        posted_move = Some((ply, uci_str.clone()));
+        if let Some(p) = ponderer.as_mut() {
+            p.start(&history, decision.chosen);
+        }
+
+        // Offer a draw if the game has been dead equal for long enough
+        if draw_offers.should_offer(&eval_history, game.rated) {
+            info!("Offering a draw");
+            if !or_abort!('game, lichess.offer_draw(game_id).await) {
+                warn!("Draw offer was rejected by Lichess");
+            }
+            eval_history.offered();
+        }
+
+        // Share the evaluation of the position the move was played in
+        let broadcast = broadcaster
+            .broadcast(
+                lichess,
+                ply,
+                &position,
+                history.counters(),
+                &mut models.head_for(&position, color_white),
+                color_white,
+            )
+            .await;
+        if let Err(e) = broadcast {
+            warn!("Unable to broadcast the evaluation: {}", e);
+        }
+
+        // Leave the game once the move is posted when shutting down, keeping
+        // the experiences so far; the game is picked up again on restart
+        if shutdown::requested() && !repost {
+            info!("Leaving game {} to shut down", game_id);
+            break 'game None;
+        }
+    };
+    if let Some(e) = &aborted {
+        info!(
+            "Game {} aborted: {}, keeping {} experiences",
+            game_id,
+            e,
+            experience_memory.len()
+        );
+    }
+    if let Some(p) = ponderer.as_ref().filter(|p| p.hits + p.misses > 0) {
+        info!(
+            "Pondered the opponent's reply {} of {} times",
+            p.hits,
+            p.hits + p.misses
+        );
+    }
+
+    // Say goodbye once the game is over
+    if let Some(text) = chat.goodbye.as_ref().filter(|_| aborted.is_none()) {
+        if let Err(e) = lichess.chat(game_id, "player", text).await {
+            warn!("Unable to say goodbye: {}", e);
+        }
+    }
+
+    // Record the game against the opponent
+    let result = game_result(
+        current.as_ref().map(|g| &g.state),
+        &board,
+        color_white,
+        claimed_victory,
+    );
+    let player_name = |white: bool| {
+        let player = current.as_ref().map(|g| g.player(white));
+        let name = player.and_then(|p| p.name.as_ref().or(p.id.as_ref()));
+        return name.map_or("?".to_string(), |n| n.to_string());
+    };
+    let mut pgn = PgnGame::new(
+        "Lichess game",
+        &player_name(true),
+        &player_name(false),
+        &initial_board,
+    );
+    pgn.header("Site", &format!("https://lichess.org/{}", game_id));
+    for (i, m) in history.moves().iter().enumerate() {
+        pgn.push(*m, bot_q.get(&(i + 1)).copied());
+    }
+    let white_result = if color_white { result } else { 1. - result };
+    if let Err(e) = PgnLog::session(config).write(&pgn, result_from_reward(white_result - 0.5)) {
+        warn!("Unable to record the game as PGN: {}", e);
+    }
+    if let Some(p) = profile {
+        database.record_game(&GameRecord {
+            id: game_id.to_string(),
+            opponent: p.name,
+            opponent_rating: p.rating,
+            bot_white: color_white,
+            moves: moves_str.clone(),
+            result,
+            opening: opponent_opening(&moves_str, color_white),
+            opponent_moves,
+            opponent_blunders,
+            time_control,
+            termination,
+        });
+    }
+
+    // The experiences are handed over, and a game left before its end is
+    // picked up again from the bot's latest move
+    if no_learn {
+        experience_memory.clear();
+    } else {
+        let over = aborted.is_none()
+            && (game_over || claimed_victory || board.status() != BoardStatus::Ongoing);
+        recovery.release(game_id, over);
+    }
+    return Ok((experience_memory, color_white));
+}
@@ -0,0 +1,125 @@
+/**
+ * Utility module for material-odds games, where one side starts without some
+ * of its pieces so that a weak network can still get meaningful wins and
+ * losses early in training. Odds are given as piece names ("pawn", "knight",
+ * "bishop", "rook", "queen"), which remove that piece from its usual queenside
+ * square (the f-pawn for "pawn"), or as squares from White's point of view
+ * such as "b1", which are mirrored for Black.
+ */
+use chess::{Board, BoardBuilder, CastleRights, Color, File, Rank, Square};
+use rand::Rng;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+// Material odds applied to self-play games, read from the "odds" object in
+// the "selfplay" settings of config.json, e.g.
+// {"given_by": "opponent", "pieces": ["queen"], "probability": 0.5}
+#[derive(Clone, Debug)]
+pub struct Handicap {
+    pub given_by_learner: bool,
+    pub pieces: Vec<String>,
+    pub probability: f64,
+}
+
+/**
+ * [odds_square(name, giver)] returns the square of the piece removed for odds
+ * [name] when given by [giver].
+ */
+fn odds_square(name: &str, giver: Color) -> Square {
+    let white_square = match name {
+        "pawn" => "f2",
+        "knight" => "b1",
+        "bishop" => "c1",
+        "rook" => "a1",
+        "queen" => "d1",
+        s => s,
+    };
+    let square = Square::from_str(white_square).expect("Invalid odds");
+
+    return match giver {
+        Color::White => square,
+        Color::Black => Square::make_square(
+            Rank::from_index(7 - square.get_rank().to_index()),
+            square.get_file(),
+        ),
+    };
+}
+
+/**
+ * [remove_castle_rights(builder, square)] removes the castling rights that
+ * depend on a rook starting on [square] in [builder].
+ */
+fn remove_castle_rights(builder: &mut BoardBuilder, square: Square) {
+    for color in [Color::White, Color::Black] {
+        if square.get_rank() != color.to_my_backrank() {
+            continue;
+        }
+        let rights = builder.get_castle_rights(color);
+        let kingside = rights.has_kingside() && square.get_file() != File::H;
+        let queenside = rights.has_queenside() && square.get_file() != File::A;
+        let new_rights = match (kingside, queenside) {
+            (true, true) => CastleRights::Both,
+            (true, false) => CastleRights::KingSide,
+            (false, true) => CastleRights::QueenSide,
+            (false, false) => CastleRights::NoRights,
+        };
+        builder.castle_rights(color, new_rights);
+    }
+}
+
+/**
+ * [odds_board(start, giver, odds)] returns board [start] with the pieces for
+ * each of [odds] removed from the side of [giver].
+ */
+pub fn odds_board(start: &Board, giver: Color, odds: &[String]) -> Board {
+    let mut builder = BoardBuilder::from(start);
+    for name in odds {
+        let square = odds_square(name, giver);
+        builder.clear_square(square);
+        remove_castle_rights(&mut builder, square);
+    }
+
+    return Board::try_from(&builder).expect("Odds leave an invalid board");
+}
+
+impl Handicap {
+    /**
+     * [from_config(settings)] reads the material odds from [settings], or
+     * returns None if no odds are configured.
+     */
+    pub fn from_config(settings: &Value) -> Option<Handicap> {
+        let pieces: Vec<String> = match &settings["pieces"] {
+            Value::Array(a) => a
+                .iter()
+                .filter_map(|p| p.as_str())
+                .map(String::from)
+                .collect(),
+            _ => return None,
+        };
+
+        return Some(Handicap {
+            given_by_learner: settings["given_by"].as_str() == Some("learner"),
+            pieces,
+            probability: settings["probability"].as_f64().unwrap_or(1.),
+        });
+    }
+
+    /**
+     * [start_board(learner_white, rng)] returns the starting board of the
+     * next game depending on whether the learner is white, which has the odds
+     * applied with the configured probability, drawn with [rng].
+     */
+    pub fn start_board(&self, learner_white: bool, rng: &mut impl Rng) -> Board {
+        if !rng.gen_bool(self.probability.clamp(0., 1.)) {
+            return Board::default();
+        }
+
+        let giver = if self.given_by_learner == learner_white {
+            Color::White
+        } else {
+            Color::Black
+        };
+        return odds_board(&Board::default(), giver, &self.pieces);
+    }
+}
@@ -0,0 +1,311 @@
+/**
+ * Utility module for tracking the positions reached over a game, so that
+ * repetitions and perpetual checks can be detected when encoding states and
+ * selecting moves. Positions are identified by their
+ * Zobrist hash, which includes the side to move, castling rights and en
+ * passant square.
+ */
+use crate::error::{BotError, BotResult};
+use crate::notation::to_fen;
+
+use chess::{Board, ChessMove, Color, Piece};
+use std::str::FromStr;
+
+// Penalty applied to moves that repeat a position while the player is ahead
+pub const REPETITION_PENALTY: f64 = 5.;
+
+// A position reached in a game
+#[derive(Clone, Copy, Debug)]
+struct HistoryEntry {
+    board: Board,
+    hash: u64,
+    side_to_move: Color,
+    in_check: bool,
+}
+
+// The move counters of a position, as in its FEN
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MoveCounters {
+    pub halfmove_clock: usize, // moves since the last capture or pawn move
+    pub fullmove_number: usize,
+}
+
+// The positions reached in a game, from its initial position onwards
+#[derive(Clone, Debug)]
+pub struct PositionHistory {
+    entries: Vec<HistoryEntry>,
+    moves: Vec<ChessMove>,
+    board: Board,
+    initial_counters: MoveCounters,
+    counters: MoveCounters,
+}
+
+impl Default for MoveCounters {
+    fn default() -> MoveCounters {
+        return MoveCounters {
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        };
+    }
+}
+
+impl MoveCounters {
+    /**
+     * [from_fen(fen)] reads the move counters of [fen], taking those of a
+     * game's first move for any that are missing or malformed.
+     */
+    pub fn from_fen(fen: &str) -> MoveCounters {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        let field = |i: usize| fields.get(i).and_then(|f| f.parse::<usize>().ok());
+        let start = MoveCounters::default();
+        return MoveCounters {
+            halfmove_clock: field(4).unwrap_or(start.halfmove_clock),
+            fullmove_number: field(5).unwrap_or(start.fullmove_number).max(1),
+        };
+    }
+
+    /**
+     * [after(b, m)] returns the move counters once move [m] is played in board
+     * [b], which has these counters.
+     */
+    pub fn after(&self, b: &Board, m: ChessMove) -> MoveCounters {
+        let capture = b.piece_on(m.get_dest()).is_some();
+        let pawn_move = b.piece_on(m.get_source()) == Some(Piece::Pawn);
+        return MoveCounters {
+            halfmove_clock: if capture || pawn_move {
+                0
+            } else {
+                self.halfmove_clock + 1
+            },
+            fullmove_number: match b.side_to_move() {
+                Color::White => self.fullmove_number,
+                Color::Black => self.fullmove_number + 1,
+            },
+        };
+    }
+}
+
+impl PositionHistory {
+    /**
+     * [new(initial)] starts the history of a game from board [initial], as
+     * the first move of the game.
+     */
+    pub fn new(initial: &Board) -> PositionHistory {
+        return PositionHistory::with_counters(initial, MoveCounters::default());
+    }
+
+    /**
+     * [with_counters(initial, counters)] starts the history of a game from
+     * board [initial] with move counters [counters], e.g. those of the FEN
+     * the game started from.
+     */
+    pub fn with_counters(initial: &Board, counters: MoveCounters) -> PositionHistory {
+        let mut history = PositionHistory {
+            entries: Vec::new(),
+            moves: Vec::new(),
+            board: *initial,
+            initial_counters: counters,
+            counters,
+        };
+        history.push(initial);
+        return history;
+    }
+
+    /**
+     * [from_moves(initial, moves_str)] builds the history of a game played from
+     * board [initial] with the space separated uci moves [moves_str].
+     */
+    pub fn from_moves(initial: &Board, moves_str: &str) -> PositionHistory {
+        let mut history = PositionHistory::new(initial);
+        history
+            .update(initial, moves_str)
+            .expect("Invalid move list");
+        return history;
+    }
+
+    /**
+     * [update(initial, moves_str)] brings the history up to date with the game
+     * played from board [initial] with the space separated uci moves
+     * [moves_str], applying only the moves that follow those already applied.
+     * The history is rebuilt from scratch if the game started elsewhere or
+     * moves were taken back. Returns an error for the first malformed or
+     * illegal move, in which case the history stops before it.
+     */
+    pub fn update(&mut self, initial: &Board, moves_str: &str) -> BotResult<()> {
+        let applied = self.moves.len();
+        let last_applied = match self.moves.last() {
+            Some(m) => moves_str.split_whitespace().nth(applied - 1) == Some(&m.to_string()),
+            None => true,
+        };
+        if self.entries[0].hash != initial.get_hash() || !last_applied {
+            *self = PositionHistory::with_counters(initial, self.initial_counters);
+        }
+
+        for ms in moves_str.split_whitespace().skip(self.moves.len()) {
+            let m = match ChessMove::from_str(ms) {
+                Ok(m) if self.board.legal(m) => m,
+                Ok(_) => return Err(BotError::IllegalMove(ms.to_string())),
+                Err(_) => return Err(BotError::InvalidMove(ms.to_string())),
+            };
+            self.make_move(m);
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * [push(b)] records board [b] as the latest position of the game.
+     */
+    pub fn push(&mut self, b: &Board) {
+        self.entries.push(HistoryEntry {
+            board: *b,
+            hash: b.get_hash(),
+            side_to_move: b.side_to_move(),
+            in_check: b.checkers().popcnt() > 0,
+        });
+        self.board = *b;
+    }
+
+    /**
+     * [make_move(m)] plays move [m] in the latest position and records the
+     * resulting position.
+     */
+    pub fn make_move(&mut self, m: ChessMove) {
+        self.counters = self.counters.after(&self.board, m);
+        let next_board = self.board.make_move_new(m);
+        self.moves.push(m);
+        self.push(&next_board);
+    }
+
+    /**
+     * [board()] returns the latest position of the game.
+     */
+    pub fn board(&self) -> Board {
+        return self.board;
+    }
+
+    /**
+     * [last_move()] returns the move that reached the latest position, or None
+     * if no moves have been played.
+     */
+    pub fn last_move(&self) -> Option<ChessMove> {
+        return self.moves.last().copied();
+    }
+
+    /**
+     * [moves()] returns the moves played since the initial position.
+     */
+    pub fn moves(&self) -> &[ChessMove] {
+        return &self.moves;
+    }
+
+    /**
+     * [ply()] returns the number of moves played since the initial position.
+     */
+    pub fn ply(&self) -> usize {
+        return self.entries.len() - 1;
+    }
+
+    /**
+     * [counters()] returns the move counters of the latest position, counted
+     * on from those of the initial position.
+     */
+    pub fn counters(&self) -> MoveCounters {
+        return self.counters;
+    }
+
+    /**
+     * [fen()] returns the FEN of the latest position, with its move counters.
+     */
+    pub fn fen(&self) -> String {
+        return to_fen(
+            &self.board,
+            self.counters.halfmove_clock,
+            self.counters.fullmove_number,
+        );
+    }
+
+    /**
+     * [occurrences(b)] returns how many times board [b] has been reached.
+     */
+    pub fn occurrences(&self, b: &Board) -> usize {
+        let hash = b.get_hash();
+        return self.entries.iter().filter(|e| e.hash == hash).count();
+    }
+
+    /**
+     * [earlier_board(k)] returns the position reached [k] moves before the
+     * latest position, or None if the game started less than [k] moves ago.
+     */
+    pub fn earlier_board(&self, k: usize) -> Option<Board> {
+        let latest = self.entries.len() - 1;
+        return latest.checked_sub(k).map(|i| self.entries[i].board);
+    }
+
+    /**
+     * [repeats(b, m)] returns whether playing move [m] in board [b] reaches a
+     * position that has already been reached.
+     */
+    pub fn repeats(&self, b: &Board, m: ChessMove) -> bool {
+        return self.occurrences(&b.make_move_new(m)) > 0;
+    }
+
+    /**
+     * [is_threefold()] returns whether the latest position has been reached at
+     * least three times.
+     */
+    pub fn is_threefold(&self) -> bool {
+        return self.occurrences(&self.board) >= 3;
+    }
+
+    /**
+     * [can_declare_draw()] returns whether a draw can be claimed in the latest
+     * position, by threefold repetition or the fifty move rule.
+     */
+    pub fn can_declare_draw(&self) -> bool {
+        return self.is_threefold() || self.counters.halfmove_clock >= 100;
+    }
+
+    /**
+     * [is_automatic_draw()] returns whether the latest position is drawn
+     * without either side claiming it, by fivefold repetition or the
+     * seventy-five move rule.
+     */
+    pub fn is_automatic_draw(&self) -> bool {
+        return self.occurrences(&self.board) >= 5 || self.counters.halfmove_clock >= 150;
+    }
+
+    /**
+     * [perpetual_check(checker)] returns whether [checker] is giving
+     * perpetual check, i.e. the latest position repeats an earlier one and
+     * every position since then with the opponent of [checker] to move was
+     * check.
+     */
+    pub fn perpetual_check(&self, checker: Color) -> bool {
+        let latest = match self.entries.last() {
+            Some(e) => e.hash,
+            None => return false,
+        };
+        let first = match self.entries.iter().position(|e| e.hash == latest) {
+            Some(i) if i < self.entries.len() - 1 => i,
+            _ => return false,
+        };
+
+        return self.entries[first..]
+            .iter()
+            .filter(|e| e.side_to_move != checker)
+            .all(|e| e.in_check);
+    }
+
+    /**
+     * [repetition_bonus(b, m, ahead)] returns the bonus added to the score of
+     * move [m] in board [b], which penalizes repeating a position when the
+     * player is [ahead] so that won games are not drawn by repetition.
+     */
+    pub fn repetition_bonus(&self, b: &Board, m: ChessMove, ahead: bool) -> f64 {
+        if ahead && self.repeats(b, m) {
+            return -REPETITION_PENALTY;
+        }
+        return 0.;
+    }
+}
@@ -0,0 +1,146 @@
+/**
+ * Utility module for learning while the daemon waits on its opponents. The
+ * game tasks act and the daemon learns: while games are running and none of
+ * them is choosing a move, the daemon fits the policy networks to a small
+ * sample of the replay buffer, one experience at a time, and stops as soon as
+ * any game needs a move or its time budget runs out. Configured by the
+ * "idle_learning" object in config.json, e.g.
+ * {"enabled": true, "sample_size": 64, "budget_ms": 2000}, and off without it.
+ */
+use crate::discount;
+use crate::error::BotResult;
+use crate::mdp::{fit_experience, TargetNetwork};
+use crate::models::ModelRegistry;
+use crate::output_scaling::OutputScaling;
+use crate::replay_shards::ShardedReplay;
+
+use rand::Rng;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Default number of experiences sampled for each idle learning pass, and the
+// longest a pass may run
+const DEFAULT_SAMPLE_SIZE: u64 = 64;
+const DEFAULT_BUDGET_MS: u64 = 2000;
+
+// The number of games in which the bot is choosing a move, shared between the
+// game tasks and the idle learner
+#[derive(Clone, Debug, Default)]
+pub struct TurnSignal {
+    thinking: Arc<AtomicUsize>,
+}
+
+// Marks a game as choosing a move for as long as it is held
+pub struct Thinking {
+    thinking: Arc<AtomicUsize>,
+}
+
+// Bounded learning passes run between the bot's moves
+#[derive(Clone, Debug)]
+pub struct IdleLearner {
+    pub sample_size: usize,
+    pub budget: Duration,
+    pub turns: TurnSignal,
+}
+
+impl TurnSignal {
+    /**
+     * [think()] marks a game as choosing a move until the returned guard is
+     * dropped.
+     */
+    pub fn think(&self) -> Thinking {
+        self.thinking.fetch_add(1, Ordering::SeqCst);
+        return Thinking {
+            thinking: self.thinking.clone(),
+        };
+    }
+
+    /**
+     * [any_thinking()] returns whether any game is choosing a move.
+     */
+    pub fn any_thinking(&self) -> bool {
+        return self.thinking.load(Ordering::SeqCst) > 0;
+    }
+}
+
+impl Drop for Thinking {
+    fn drop(&mut self) {
+        self.thinking.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl IdleLearner {
+    /**
+     * [from_config(config, turns)] reads the idle learning settings from the
+     * parsed [config], pausing for the games signalled through [turns], or
+     * returns None if idle learning is off.
+     */
+    pub fn from_config(config: &Value, turns: &TurnSignal) -> Option<IdleLearner> {
+        let settings = &config["idle_learning"];
+        if !settings["enabled"].as_bool().unwrap_or(false) {
+            return None;
+        }
+
+        let sample_size = settings["sample_size"]
+            .as_u64()
+            .unwrap_or(DEFAULT_SAMPLE_SIZE);
+        let budget_ms = settings["budget_ms"].as_u64().unwrap_or(DEFAULT_BUDGET_MS);
+        return Some(IdleLearner {
+            sample_size: sample_size as usize,
+            budget: Duration::from_millis(budget_ms),
+            turns: turns.clone(),
+        });
+    }
+
+    /**
+     * [learn(config, replay, rng)] fits the policy networks given by the
+     * parsed [config] to a sample of the experiences in [replay], drawn with
+     * [rng], until a game needs
+     * a move or the budget runs out, saving them if anything was learned.
+     * Returns the number of experiences learned from, or an error if the
+     * replay buffer cannot be sampled or the networks loaded or saved.
+     */
+    pub fn learn(
+        &self,
+        config: &Value,
+        replay: &ShardedReplay,
+        rng: &mut impl Rng,
+    ) -> BotResult<usize> {
+        if self.turns.any_thinking() {
+            return Ok(0);
+        }
+
+        let deadline = Instant::now() + self.budget;
+        let sample = replay.sample(self.sample_size, rng)?;
+        let scaling = OutputScaling::from_config(config);
+        let mut models = ModelRegistry::from_config(config)?;
+        let mut targets = [false, true].map(|player_white| {
+            let (network, stats) = models.learner(player_white);
+            return TargetNetwork::from_config(config, network, *stats);
+        });
+        let mut learned = 0;
+        for (e, player_white) in &sample {
+            if self.turns.any_thinking() || Instant::now() >= deadline {
+                break;
+            }
+            let (network, stats) = models.learner(*player_white);
+            fit_experience(
+                network,
+                stats,
+                &mut targets[*player_white as usize],
+                e,
+                discount(config),
+                &scaling,
+            );
+            learned += 1;
+        }
+
+        if learned > 0 {
+            models.save(true)?;
+            models.save(false)?;
+        }
+        return Ok(learned);
+    }
+}
@@ -0,0 +1,380 @@
+/**
+ * Utility module for pretraining the policy networks on human games from the
+ * monthly Lichess database dumps, which are zstd compressed PGN files of
+ * hundreds of GB. Dumps are decompressed as they are read, never to disk, and
+ * games are filtered by the "ingest" object in config.json, e.g.
+ * {"min_rating": 2000, "min_base_secs": 180, "max_base_secs": 900,
+ *  "save_interval": 10000}. Every move of an accepted game is fit to the
+ * discounted outcome of the game for the player who made it. The networks
+ * are saved along with how far into the dump the import got every
+ * save_interval games, so an interrupted import resumes where it stopped.
+ */
+use crate::error::BotResult;
+use crate::history::PositionHistory;
+use crate::mdp::{get_action, get_state_with_history, WIN_REWARD};
+use crate::models::ModelRegistry;
+use crate::output_scaling::OutputScaling;
+use crate::repertoire::{parse_move, strip_comments};
+use crate::GAMMA;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use tracing::info;
+
+const DEFAULT_MIN_RATING: u64 = 0;
+const DEFAULT_MIN_BASE_SECS: u64 = 0;
+const DEFAULT_MAX_BASE_SECS: u64 = u64::MAX;
+const DEFAULT_SAVE_INTERVAL: usize = 10000;
+
+// Which games of a dump are used for pretraining
+#[derive(Clone, Copy, Debug)]
+pub struct IngestFilter {
+    pub min_rating: u64,
+    pub min_base_secs: u64,
+    pub max_base_secs: u64,
+}
+
+// A game read from a PGN file, as its tag pairs and move text
+#[derive(Clone, Debug, Default)]
+pub struct PgnGame {
+    pub tags: HashMap<String, String>,
+    pub movetext: String,
+}
+
+// Reads the games of a PGN stream one at a time
+pub struct PgnReader<R: BufRead> {
+    reader: R,
+    pending: Option<PgnGame>,
+}
+
+// How far an import of a dump has got
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IngestProgress {
+    pub games_read: usize,
+    pub games_used: usize,
+    pub positions: usize,
+}
+
+impl IngestFilter {
+    /**
+     * [from_config(config)] reads the game filter given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> IngestFilter {
+        let ingest = &config["ingest"];
+        return IngestFilter {
+            min_rating: ingest["min_rating"].as_u64().unwrap_or(DEFAULT_MIN_RATING),
+            min_base_secs: ingest["min_base_secs"]
+                .as_u64()
+                .unwrap_or(DEFAULT_MIN_BASE_SECS),
+            max_base_secs: ingest["max_base_secs"]
+                .as_u64()
+                .unwrap_or(DEFAULT_MAX_BASE_SECS),
+        };
+    }
+
+    /**
+     * [accepts(game)] returns whether [game] is used for pretraining: both
+     * players must be rated at least the minimum rating, and the base time of
+     * the time control must be within range. Correspondence games, which have
+     * no time control, games from a custom position and unfinished games are
+     * never used.
+     */
+    pub fn accepts(&self, game: &PgnGame) -> bool {
+        if game.tags.contains_key("FEN") {
+            return false;
+        }
+        let rating = |tag: &str| game.tags.get(tag).and_then(|r| r.parse::<u64>().ok());
+        match (rating("WhiteElo"), rating("BlackElo")) {
+            (Some(w), Some(b)) if w.min(b) >= self.min_rating => (),
+            _ => return false,
+        };
+
+        let base_secs = game
+            .tags
+            .get("TimeControl")
+            .and_then(|tc| tc.split('+').next())
+            .and_then(|base| base.parse::<u64>().ok());
+        match base_secs {
+            Some(s) if s >= self.min_base_secs && s <= self.max_base_secs => (),
+            _ => return false,
+        };
+
+        return game_result(game).is_some();
+    }
+}
+
+/**
+ * [parse_tag(line)] parses the PGN tag pair [line], e.g. [WhiteElo "1500"],
+ * returning None if it is not a tag pair.
+ */
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (key, value) = inner.split_once(' ')?;
+    return Some((key.to_string(), value.trim().trim_matches('"').to_string()));
+}
+
+impl<R: BufRead> PgnReader<R> {
+    /**
+     * [new(reader)] reads PGN games from [reader].
+     */
+    pub fn new(reader: R) -> PgnReader<R> {
+        return PgnReader {
+            reader,
+            pending: None,
+        };
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = io::Result<PgnGame>;
+
+    /**
+     * [next()] reads the next game, which ends where the tag pairs of the
+     * following game start.
+     */
+    fn next(&mut self) -> Option<io::Result<PgnGame>> {
+        let mut game = self.pending.take().unwrap_or_default();
+        let mut bytes = Vec::new();
+        loop {
+            bytes.clear();
+            match self.reader.read_until(b'\n', &mut bytes) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            };
+            let line = String::from_utf8_lossy(&bytes);
+
+            match parse_tag(&line) {
+                Some((key, value)) if game.movetext.trim().len() > 0 => {
+                    let mut next_game = PgnGame::default();
+                    next_game.tags.insert(key, value);
+                    self.pending = Some(next_game);
+                    return Some(Ok(game));
+                }
+                Some((key, value)) => {
+                    game.tags.insert(key, value);
+                }
+                None => {
+                    game.movetext += " ";
+                    game.movetext += line.trim();
+                }
+            };
+        }
+
+        if game.tags.len() == 0 && game.movetext.trim().len() == 0 {
+            return None;
+        }
+        return Some(Ok(game));
+    }
+}
+
+/**
+ * [open_dump(path)] opens the PGN dump at [path] for streaming, decompressing
+ * it on the fly if it is zstd compressed (ends in .zst).
+ */
+pub fn open_dump(path: &str) -> io::Result<PgnReader<Box<dyn BufRead>>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = if path.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    return Ok(PgnReader::new(Box::new(BufReader::new(reader))));
+}
+
+/**
+ * [game_result(game)] returns the score of White in [game]: 1 for a win, 0.5
+ * for a draw and 0 for a loss, or None if it did not finish.
+ */
+fn game_result(game: &PgnGame) -> Option<f64> {
+    match game.tags.get("Result").map(|r| r.as_str()) {
+        Some("1-0") => Some(1.),
+        Some("0-1") => Some(0.),
+        Some("1/2-1/2") => Some(0.5),
+        _ => None,
+    }
+}
+
+/**
+ * [game_moves(game)] replays the move text of [game] from its starting
+ * position, returning each position along with the move played in it. Stops
+ * at the first move that cannot be played.
+ */
+pub fn game_moves(game: &PgnGame) -> Vec<(Board, ChessMove)> {
+    let mut board = Board::default();
+    let mut moves = Vec::new();
+    for token in strip_comments(&game.movetext).split_whitespace() {
+        if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token) {
+            break;
+        }
+
+        // Drop move numbers ("1.", "1...") and annotations ("e4!?", "$1")
+        let token = token
+            .trim_start_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .trim_end_matches(|c: char| c == '!' || c == '?');
+        if token.len() == 0 || token.starts_with("$") {
+            continue;
+        }
+
+        match parse_move(&board, token) {
+            Some(m) => {
+                moves.push((board, m));
+                board = board.make_move_new(m);
+            }
+            None => break,
+        };
+        if board.status() != BoardStatus::Ongoing {
+            break;
+        }
+    }
+
+    return moves;
+}
+
+/**
+ * [pretrain_game(models, game, scaling)] fits the networks in [models] to
+ * every move of [game], targeting the outcome of the game for the player who
+ * made the move, discounted by how many moves later the game ended and
+ * converted into network outputs by [scaling]. Returns the number of
+ * positions fit.
+ */
+pub fn pretrain_game(models: &mut ModelRegistry, game: &PgnGame, scaling: &OutputScaling) -> usize {
+    let white_score = match game_result(game) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    let moves = game_moves(game);
+    let plies = moves.len();
+    let mut history = PositionHistory::new(&Board::default());
+    for (ply, (board, m)) in moves.iter().enumerate() {
+        let player_white = board.side_to_move() == Color::White;
+        let score = if player_white {
+            white_score
+        } else {
+            1. - white_score
+        };
+        let value = (2. * score - 1.) * WIN_REWARD * GAMMA.powi((plies - 1 - ply) as i32);
+        let target = scaling.squash(value);
+
+        let state = get_state_with_history(&history, player_white);
+        let mut sa = models.stats(player_white).normalized(&state);
+        sa.append(&mut get_action(*m, player_white));
+        models.network(player_white).fit(&sa[..], &[target]);
+        history.make_move(*m);
+    }
+
+    return plies;
+}
+
+/**
+ * [progress_path(path)] returns where progress importing the dump at [path] is
+ * saved.
+ */
+fn progress_path(path: &str) -> String {
+    return format!("{}.progress", path);
+}
+
+/**
+ * [read_progress(path)] reads how far the import of the dump at [path] got,
+ * starting from the beginning if it was never imported.
+ */
+pub fn read_progress(path: &str) -> IngestProgress {
+    let json: Value = match fs::read_to_string(progress_path(path)) {
+        Ok(s) => serde_json::from_str(&s).unwrap_or(Value::Null),
+        Err(_) => return IngestProgress::default(),
+    };
+    let field = |key: &str| json[key].as_u64().unwrap_or(0) as usize;
+
+    return IngestProgress {
+        games_read: field("games_read"),
+        games_used: field("games_used"),
+        positions: field("positions"),
+    };
+}
+
+/**
+ * [write_progress(path, progress)] saves how far the import of the dump at
+ * [path] got, atomically so a crash never leaves partial progress.
+ */
+fn write_progress(path: &str, progress: &IngestProgress) {
+    let json = json!({
+        "games_read": progress.games_read,
+        "games_used": progress.games_used,
+        "positions": progress.positions,
+    });
+    let tmp_path = format!("{}.tmp", progress_path(path));
+    fs::write(&tmp_path, json.to_string()).unwrap();
+    fs::rename(&tmp_path, progress_path(path)).unwrap();
+}
+
+/**
+ * [ingest_dump(config, path, max_games)] pretrains the policy networks given
+ * by the parsed [config] on the games of the dump at [path] that pass the
+ * filter, reading at most [max_games] more games (all if None). Resumes after
+ * the games read by earlier imports of the dump, and returns how far the
+ * import has got, or an error if the dump or the networks cannot be read or
+ * saved.
+ */
+pub fn ingest_dump(
+    config: &Value,
+    path: &str,
+    max_games: Option<usize>,
+) -> BotResult<IngestProgress> {
+    let filter = IngestFilter::from_config(config);
+    let save_interval = config["ingest"]["save_interval"]
+        .as_u64()
+        .map(|n| n as usize)
+        .unwrap_or(DEFAULT_SAVE_INTERVAL)
+        .max(1);
+    let mut models = ModelRegistry::from_config(config)?;
+    let scaling = OutputScaling::from_config(config);
+
+    let mut progress = read_progress(path);
+    let mut games = open_dump(path)?;
+    if progress.games_read > 0 {
+        info!("Resuming after {} games", progress.games_read);
+        for _ in 0..progress.games_read {
+            if games.next().transpose()?.is_none() {
+                return Ok(progress);
+            }
+        }
+    }
+
+    let save = |models: &mut ModelRegistry, progress: &IngestProgress| -> BotResult<()> {
+        models.save(true)?;
+        models.save(false)?;
+        write_progress(path, progress);
+        info!(
+            "Read {} games, pretrained on {} games ({} positions)",
+            progress.games_read, progress.games_used, progress.positions
+        );
+        return Ok(());
+    };
+
+    let mut read = 0;
+    while max_games.map_or(true, |n| read < n) {
+        let game = match games.next().transpose()? {
+            Some(g) => g,
+            None => break,
+        };
+        read += 1;
+        progress.games_read += 1;
+
+        if filter.accepts(&game) {
+            progress.positions += pretrain_game(&mut models, &game, &scaling);
+            progress.games_used += 1;
+        }
+        if progress.games_read % save_interval == 0 {
+            save(&mut models, &progress)?;
+        }
+    }
+    save(&mut models, &progress)?;
+
+    return Ok(progress);
+}
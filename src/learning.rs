@@ -0,0 +1,251 @@
+/**
+ * Utility module for learning from chunks of experiences with the policy
+ * networks kept in memory, which the daemon's train windows, the train
+ * command and learning passes over the whole sharded replay buffer all go
+ * through. The networks and their target networks live across chunks, so the
+ * target networks are updated as configured regardless of the chunk size, and
+ * the networks are saved every few chunks as given by the "learning" object
+ * in config.json, e.g. {"save_every": 10}, and after every chunk by default.
+ * A learning pass over the buffer, which can hold millions of experiences,
+ * saves its progress (the shard and offset it reached) along with the
+ * networks, so that an interrupted pass resumes where it stopped instead of
+ * starting over, and reports how far it has got and how fast it is going.
+ * Unlike training during the daemon's train windows, a pass leaves the buffer
+ * as it is.
+ */
+use crate::discount;
+use crate::error::BotResult;
+use crate::mdp::{continue_learning, Experience, TargetNetwork};
+use crate::models::ModelRegistry;
+use crate::output_scaling::OutputScaling;
+use crate::q_function::check_training_backend;
+use crate::replay::load_experiences;
+use crate::replay_buffer::ReplayBuffer;
+use crate::replay_shards::ShardedReplay;
+use crate::returns::ReturnTarget;
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+
+// Name of the file holding the progress of the current pass, within the shard
+// directory
+const PROGRESS_FILE: &str = "pass.json";
+
+// How far a learning pass has got
+#[derive(Clone, Debug, Default)]
+pub struct PassProgress {
+    pub shard: Option<String>, // file of the shard being learned from
+    pub offset: usize,         // experiences already learned from in the shard
+    pub learned: usize,        // experiences learned from over the whole pass
+}
+
+/**
+ * [progress_path(replay)] returns where the progress of a pass over [replay]
+ * is saved.
+ */
+fn progress_path(replay: &ShardedReplay) -> String {
+    return format!("{}/{}", replay.dir, PROGRESS_FILE);
+}
+
+/**
+ * [read_progress(path)] reads the progress of a pass saved at [path], which
+ * is a fresh pass if there is none.
+ */
+pub fn read_progress(path: &str) -> PassProgress {
+    let json: Value = match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s).unwrap_or(Value::Null),
+        Err(_) => return PassProgress::default(),
+    };
+
+    return PassProgress {
+        shard: json["shard"].as_str().map(|s| s.to_string()),
+        offset: json["offset"].as_u64().unwrap_or(0) as usize,
+        learned: json["learned"].as_u64().unwrap_or(0) as usize,
+    };
+}
+
+// Learns from chunks of experiences with the policy networks for each color
+// kept in memory, saving them every few chunks
+pub struct Learner {
+    pub models: ModelRegistry,
+    pub save_every: usize,       // chunks learned from between saves
+    targets: [TargetNetwork; 2], // by whether the networks play white
+    config: Value,
+    unsaved: usize, // chunks learned from since the networks were last saved
+}
+
+impl Learner {
+    /**
+     * [from_config(config)] loads the policy networks given by the parsed
+     * [config] to learn with, each starting its target network, or returns an
+     * error if the configured backend cannot be trained.
+     */
+    pub fn from_config(config: &Value) -> BotResult<Learner> {
+        check_training_backend(config)?;
+        let mut models = ModelRegistry::from_config(config)?;
+        let targets = [false, true].map(|player_white| {
+            let (network, stats) = models.learner(player_white);
+            return TargetNetwork::from_config(config, network, *stats);
+        });
+        return Ok(Learner {
+            models,
+            save_every: config["learning"]["save_every"]
+                .as_u64()
+                .unwrap_or(1)
+                .max(1) as usize,
+            targets,
+            config: config.clone(),
+            unsaved: 0,
+        });
+    }
+
+    /**
+     * [learn(chunk, rng)] learns from every experience in [chunk], each paired
+     * with whether its player was white, from its player's perspective, with
+     * minibatches drawn with [rng]. The networks are not saved.
+     */
+    pub fn learn(&mut self, chunk: &[(Experience, bool)], rng: &mut impl Rng) {
+        let gamma = discount(&self.config);
+        let scaling = OutputScaling::from_config(&self.config);
+        let returns = ReturnTarget::from_config(&self.config);
+        for color_white in [true, false] {
+            let mut memory = ReplayBuffer::from_config(&self.config);
+            memory.extend(
+                chunk
+                    .iter()
+                    .filter(|(_, w)| *w == color_white)
+                    .map(|(e, _)| e.clone()),
+            );
+            let updates = memory.len();
+            let (network, stats) = self.models.learner(color_white);
+            continue_learning(
+                network,
+                stats,
+                &mut self.targets[color_white as usize],
+                &memory,
+                updates,
+                gamma,
+                &scaling,
+                &returns,
+                rng,
+            );
+        }
+        self.unsaved += 1;
+    }
+
+    /**
+     * [save_due()] returns whether enough chunks have been learned from since
+     * the networks were last saved for them to be saved again.
+     */
+    pub fn save_due(&self) -> bool {
+        return self.unsaved >= self.save_every;
+    }
+
+    /**
+     * [save()] saves the policy networks for both colors, returning an error
+     * if either cannot be saved.
+     */
+    pub fn save(&mut self) -> BotResult<()> {
+        for color_white in [true, false] {
+            self.models.save(color_white)?;
+        }
+        self.unsaved = 0;
+        return Ok(());
+    }
+}
+
+/**
+ * [write_progress(path, progress)] saves [progress] to [path] atomically.
+ */
+fn write_progress(path: &str, progress: &PassProgress) -> io::Result<()> {
+    let json = json!({
+        "shard": progress.shard,
+        "offset": progress.offset,
+        "learned": progress.learned,
+    });
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, json.to_string())?;
+    return fs::rename(&tmp_path, path);
+}
+
+/**
+ * [learn_pass(config, replay, chunk_size)] learns from every experience in
+ * [replay] with the policy networks given by the parsed [config], [chunk_size]
+ * experiences at a time, resuming the pass saved in the shard directory if
+ * there is one. The progress is saved along with the networks, and removed
+ * once the pass completes. Returns the number of experiences learned from
+ * over the whole pass.
+ */
+pub fn learn_pass(
+    config: &Value,
+    replay: &ShardedReplay,
+    chunk_size: usize,
+    rng: &mut impl Rng,
+) -> BotResult<usize> {
+    let path = progress_path(replay);
+    let mut progress = read_progress(&path);
+    let total = replay.len();
+    let started = Instant::now();
+    let learned_before = progress.learned;
+
+    // Resume from the shard the pass reached, or from the start if it has
+    // since been retired
+    let first = match &progress.shard {
+        Some(file) => match replay.shards.iter().position(|s| s.file.eq(file)) {
+            Some(i) => i,
+            None => {
+                progress.offset = 0;
+                0
+            }
+        },
+        None => 0,
+    };
+    if progress.learned > 0 {
+        info!(
+            "Resuming learning pass after {} experiences",
+            progress.learned
+        );
+    }
+
+    let mut learner = Learner::from_config(config)?;
+    for shard in &replay.shards[first..] {
+        if progress.shard.as_ref() != Some(&shard.file) {
+            progress.shard = Some(shard.file.clone());
+            progress.offset = 0;
+        }
+        let experiences = load_experiences(&replay.shard_path(shard))?;
+
+        while progress.offset < experiences.len() {
+            let end = (progress.offset + chunk_size.max(1)).min(experiences.len());
+            let chunk = &experiences[progress.offset..end];
+            learner.learn(chunk, rng);
+            progress.offset = end;
+            progress.learned += chunk.len();
+            if learner.save_due() {
+                learner.save()?;
+                write_progress(&path, &progress)?;
+            }
+
+            let rate = (progress.learned - learned_before) as f64
+                / started.elapsed().as_secs_f64().max(1e-9);
+            info!(
+                "Learned from {} of {} experiences ({:.1}%), {:.0} per second",
+                progress.learned,
+                total,
+                100. * progress.learned as f64 / total.max(1) as f64,
+                rate
+            );
+        }
+    }
+
+    learner.save()?;
+    if Path::new(&path).exists() {
+        fs::remove_file(&path)?;
+    }
+    return Ok(progress.learned);
+}
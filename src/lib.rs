@@ -0,0 +1,139 @@
+/**
+ * The library shared by the bot's binaries: chessbot-play, which plays on
+ * Lichess or over UCI, chessbot-train, which trains from self-play and the
+ * replay buffer, and the combined binary offering both. The playing and
+ * training binaries only communicate through the replay buffer storage and
+ * the checkpoint directory, so they can run on separate machines sharing
+ * those directories.
+ */
+pub mod action_space;
+pub mod agent;
+pub mod arena;
+pub mod augment;
+pub mod backup;
+pub mod bench;
+pub mod broadcast;
+#[cfg(feature = "burn")]
+pub mod burn_network;
+pub mod challenge;
+pub mod checkpoint;
+pub mod cli;
+pub mod config;
+pub mod curriculum;
+pub mod daemon;
+pub mod database;
+pub mod decision;
+pub mod display;
+pub mod distill;
+pub mod distributed;
+pub mod draw_offer;
+pub mod dueling;
+pub mod engine_reward;
+pub mod error;
+pub mod eval;
+pub mod explain;
+pub mod game_context;
+pub mod game_loop;
+pub mod handicap;
+pub mod history;
+pub mod idle_learning;
+pub mod ingest;
+pub mod learning;
+pub mod lichess;
+pub mod lichess_log;
+pub mod limits;
+pub mod local_play;
+pub mod logging;
+pub mod mdp;
+pub mod metrics;
+pub mod mock_lichess;
+pub mod model;
+pub mod models;
+pub mod move_log;
+pub mod normalization;
+pub mod notation;
+pub mod novelty;
+pub mod onnx;
+pub mod opponent;
+pub mod output_scaling;
+pub mod pgn;
+pub mod policy_head;
+pub mod ponder;
+pub mod puzzles;
+pub mod q_function;
+pub mod quantize;
+pub mod recovery;
+pub mod repertoire;
+pub mod replay;
+pub mod replay_buffer;
+pub mod replay_shards;
+pub mod resign;
+pub mod retry;
+pub mod returns;
+pub mod reward;
+pub mod run_report;
+pub mod runs;
+pub mod sampling;
+pub mod schedule;
+pub mod scripted;
+pub mod search;
+pub mod selfplay;
+pub mod server;
+pub mod shared_replay;
+pub mod shutdown;
+pub mod sweep;
+pub mod tablebase;
+pub mod testing;
+pub mod time_manager;
+pub mod tournament;
+pub mod training;
+pub mod uci;
+pub mod uci_engine;
+pub mod warmstart;
+pub mod watchdog;
+pub mod weights;
+pub mod zoo;
+
+use crate::mdp::{ACTION_DIM, STATE_DIM};
+
+use chess::{Board, ChessMove, MoveGen};
+use rand::Rng;
+use serde_json::Value;
+
+const INPUT_DIM: i32 = (STATE_DIM + ACTION_DIM) as i32;
+const GAMMA: f64 = 0.99;
+
+/**
+ * [discount(config)] returns the factor future rewards are discounted by when
+ * learning, which is "gamma" in the parsed [config] or 0.99 by default.
+ */
+pub fn discount(config: &Value) -> f64 {
+    return config["gamma"].as_f64().unwrap_or(GAMMA);
+}
+
+/**
+ * [make_random_move(b)] selects a random legal move for board b. If there are
+ * no legal moves, it returns None. If there is at least one legal move, it
+ * returns Some(m) where m is the legal move selected.
+ */
+pub fn make_random_move(b: Board) -> Option<ChessMove> {
+    return make_random_move_with(b, &mut rand::thread_rng());
+}
+
+/**
+ * [make_random_move_with(b, rng)] selects a random legal move for board b with
+ * [rng], or None if there are no legal moves.
+ */
+pub fn make_random_move_with(b: Board, rng: &mut impl Rng) -> Option<ChessMove> {
+    // Generate legal moves
+    let mut legal_moves = MoveGen::new_legal(&b);
+    if legal_moves.len() == 0 {
+        // If no legal moves, do nothing
+        return None;
+    }
+
+    // Pick a random move
+    let next_move = legal_moves.nth(rng.gen_range(0..=legal_moves.len() - 1));
+
+    return next_move;
+}
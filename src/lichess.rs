@@ -0,0 +1,854 @@
+/**
+ * Utility module for talking to the Lichess Bot API through a typed client.
+ * The events and game states Lichess streams are parsed into structs rather
+ * than read out of raw json, so that a missing or unexpected field turns into
+ * an event the caller can handle instead of a panic deep in the game loop.
+ * The streams are read as newline delimited json, one line at a time, and
+ * are re-opened if they end, drop or stall for longer than the watchdog
+ * allows; a re-opened game stream starts with the full game again, so the
+ * board is resynchronized from its move list. Every request is retried after
+ * transient failures and rate limiting according to the retry policy.
+ * What the bot says in the player chat of its games is configured by the
+ * "chat" object in config.json, e.g. {"greeting": "Good luck!",
+ * "goodbye": "gg", "eval_command": true}, where a message set to false is not
+ * sent and "eval_command" answers "!eval" with the bot's evaluation.
+ */
+use crate::config::read_lichess_url;
+use crate::error::BotResult;
+use crate::lichess_log::log_body;
+use crate::retry::RetryPolicy;
+use crate::watchdog::Watchdog;
+
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+
+// How long to wait before re-opening a stream that ended
+const REOPEN_DELAY: Duration = Duration::from_millis(500);
+
+// Default messages sent at the start and the end of a game
+const DEFAULT_GREETING: &str = "Hi! I am a bot learning chess as I play. Good luck!";
+const DEFAULT_GOODBYE: &str = "gg";
+
+// Chat command answered with the bot's evaluation
+pub const EVAL_COMMAND: &str = "!eval";
+
+// A client of the Lichess API at base, authenticated by the bot's token
+#[derive(Clone)]
+pub struct LichessClient {
+    pub client: reqwest::Client,
+    pub base: String,
+    auth_token: String,
+    pub watchdog: Watchdog,
+    pub retry: RetryPolicy,
+}
+
+// A stream of newline delimited json from the API, read one line at a time
+// and re-opened whenever it stalls, drops or ends
+pub struct NdjsonStream {
+    lichess: LichessClient,
+    url: String,
+    response: Option<reqwest::Response>,
+    buffer: Vec<u8>, // bytes received after the last complete line
+    opened: bool,
+}
+
+// An event on the stream of incoming events
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    GameStart {
+        game: GameEvent,
+    },
+    GameFinish {
+        game: GameEvent,
+    },
+    Challenge {
+        challenge: ChallengeEvent,
+    },
+    ChallengeCanceled {
+        challenge: ChallengeEvent,
+    },
+    ChallengeDeclined {
+        challenge: ChallengeEvent,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// A game of the bot's that started or finished
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameEvent {
+    pub id: String,
+    pub color: String, // "white" or "black"
+    #[serde(default)]
+    pub is_my_turn: bool,
+    pub fen: Option<String>,
+    pub source: Option<String>, // e.g. "arena", "swiss" or "friend"
+    pub tournament_id: Option<String>,
+    pub swiss_id: Option<String>,
+}
+
+// A challenge sent to or by the bot
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeEvent {
+    pub id: String,
+    #[serde(default)]
+    pub rated: bool,
+    pub challenger: Option<Player>,
+    pub dest_user: Option<Player>,
+    pub variant: Option<Variant>,
+    pub time_control: Option<TimeControl>,
+    pub initial_fen: Option<String>,
+    pub direction: Option<String>, // "in" if sent to the bot, "out" if by it
+}
+
+// A chess variant, e.g. "standard" or "fromPosition"
+#[derive(Clone, Debug, Deserialize)]
+pub struct Variant {
+    pub key: String,
+}
+
+// The time control of a challenge, with times in seconds
+#[derive(Clone, Debug, Deserialize)]
+pub struct TimeControl {
+    #[serde(rename = "type")]
+    pub kind: String, // "clock", "correspondence" or "unlimited"
+    pub limit: Option<u64>,
+    pub increment: Option<u64>,
+}
+
+// A player of a game or challenge, which has no id if it is the Lichess AI
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Player {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub rating: Option<i64>,
+}
+
+// A user listed by the API, with their rating in each perf (e.g. "blitz")
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct User {
+    pub id: String,
+    #[serde(default)]
+    pub perfs: HashMap<String, Perf>,
+}
+
+// A user's rating in one perf
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Perf {
+    pub rating: Option<i64>,
+}
+
+// The clock of a game, with times in milliseconds
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Clock {
+    pub initial: u64,
+    pub increment: u64,
+}
+
+// The full game, sent first on the game stream
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameFull {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub rated: bool,
+    pub clock: Option<Clock>,
+    #[serde(default)]
+    pub white: Player,
+    #[serde(default)]
+    pub black: Player,
+    pub initial_fen: Option<String>, // a FEN, or "startpos"
+    pub state: GameState,
+}
+
+// The current state of a game, with times in milliseconds
+#[derive(Clone, Debug, Deserialize)]
+pub struct GameState {
+    #[serde(default)]
+    pub moves: String, // space separated uci moves
+    pub wtime: Option<u64>,
+    pub btime: Option<u64>,
+    pub winc: Option<u64>,
+    pub binc: Option<u64>,
+    pub status: Option<String>,
+    pub winner: Option<String>,
+    #[serde(default)]
+    pub wdraw: bool,
+    #[serde(default)]
+    pub bdraw: bool,
+    #[serde(default)]
+    pub wtakeback: bool,
+    #[serde(default)]
+    pub btakeback: bool,
+}
+
+// A line on the game stream
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameUpdate {
+    GameFull(GameFull),
+    GameState(GameState),
+    #[serde(rename_all = "camelCase")]
+    OpponentGone {
+        gone: bool,
+        claim_win_in_seconds: Option<u64>,
+    },
+    ChatLine {
+        #[serde(default)]
+        room: String, // "player" or "spectator"
+        #[serde(default)]
+        username: String,
+        #[serde(default)]
+        text: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// What the bot says in the player chat of its games
+#[derive(Clone, Debug)]
+pub struct ChatSettings {
+    pub greeting: Option<String>, // sent at the start of a game
+    pub goodbye: Option<String>,  // sent at the end of a game
+    pub eval_command: bool,       // whether "!eval" is answered
+}
+
+// How Lichess answered a posted move
+#[derive(Clone, Debug, PartialEq)]
+pub enum MoveResponse {
+    Accepted,
+    Rejected(String),   // the move was refused, with the reason given
+    Failed(StatusCode), // the request may succeed if retried
+}
+
+/**
+ * [parse_line(line)] parses [line] read from a Lichess stream, or returns
+ * None if it is blank (a keep-alive newline) or does not parse.
+ */
+fn parse_line<T: DeserializeOwned>(line: &[u8]) -> Option<T> {
+    if line.iter().all(|b| b.is_ascii_whitespace()) {
+        return None;
+    }
+    return serde_json::from_slice(line).ok();
+}
+
+impl NdjsonStream {
+    /**
+     * [new(lichess, url)] creates the stream at [url] of the API of
+     * [lichess], which is opened once it is first read.
+     */
+    fn new(lichess: &LichessClient, url: &str) -> NdjsonStream {
+        return NdjsonStream {
+            lichess: lichess.clone(),
+            url: url.to_string(),
+            response: None,
+            buffer: Vec::new(),
+            opened: false,
+        };
+    }
+
+    /**
+     * [next_line()] reads the next line of the stream that parses, skipping
+     * keep-alive newlines, or returns None if the stream ended first, in
+     * which case the next read re-opens it. Lines may arrive split across
+     * chunks of data or several to a chunk. If no data arrives within the
+     * watchdog's stream timeout, or the connection drops, the stream is
+     * re-opened. Returns an error if it can not be re-opened.
+     */
+    pub async fn next_line<T: DeserializeOwned>(&mut self) -> BotResult<Option<T>> {
+        loop {
+            // Handle each complete line received so far
+            while let Some(i) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=i).collect();
+                if let Some(value) = parse_line(&line) {
+                    return Ok(Some(value));
+                }
+            }
+
+            if self.response.is_none() {
+                if self.opened {
+                    tokio::time::sleep(REOPEN_DELAY).await;
+                }
+                self.opened = true;
+                let request = self
+                    .lichess
+                    .client
+                    .get(&self.url)
+                    .bearer_auth(&self.lichess.auth_token);
+                let retry = self.lichess.retry;
+                self.response = Some(retry.send("GET", &self.url, request).await?);
+            }
+            let response = self.response.as_mut().unwrap();
+            let timeout = self.lichess.watchdog.stream_timeout;
+            match tokio::time::timeout(timeout, response.chunk()).await {
+                Ok(Ok(Some(b))) => {
+                    log_body(&self.url, &b);
+                    self.buffer.extend_from_slice(&b);
+                }
+                Ok(Ok(None)) => {
+                    // The last line may not end in a newline
+                    self.response = None;
+                    let rest: Vec<u8> = self.buffer.drain(..).collect();
+                    return Ok(parse_line(&rest));
+                }
+                Ok(Err(e)) => {
+                    warn!("Stream {} dropped ({}), re-opening it", self.url, e);
+                    self.response = None;
+                    self.buffer.clear();
+                }
+                Err(_) => {
+                    warn!("Stream {} stalled, re-opening it", self.url);
+                    self.response = None;
+                    self.buffer.clear();
+                }
+            };
+        }
+    }
+}
+
+impl GameFull {
+    /**
+     * [player(white)] returns the player of the given color.
+     */
+    pub fn player(&self, white: bool) -> &Player {
+        return if white { &self.white } else { &self.black };
+    }
+
+    /**
+     * [start_fen()] returns the FEN the game started from, or None if it
+     * started from the standard position.
+     */
+    pub fn start_fen(&self) -> Option<&str> {
+        return self.initial_fen.as_deref().filter(|f| !f.eq(&"startpos"));
+    }
+
+    /**
+     * [white_to_move()] returns whether it is white's turn in the current
+     * state of the game.
+     */
+    pub fn white_to_move(&self) -> bool {
+        let white_started = match self.start_fen() {
+            Some(fen) => fen.split_whitespace().nth(1) != Some("b"),
+            None => true,
+        };
+        let plies = self.state.moves.split_whitespace().count();
+        return white_started == (plies % 2 == 0);
+    }
+}
+
+impl GameState {
+    /**
+     * [time_ms(white)] returns the time left on the clock of the given color,
+     * or None in games without a clock.
+     */
+    pub fn time_ms(&self, white: bool) -> Option<u64> {
+        return if white { self.wtime } else { self.btime };
+    }
+
+    /**
+     * [increment_ms(white)] returns the increment of the given color, or None
+     * if the stream did not send it.
+     */
+    pub fn increment_ms(&self, white: bool) -> Option<u64> {
+        return if white { self.winc } else { self.binc };
+    }
+
+    /**
+     * [in_progress()] returns whether the game is still being played.
+     */
+    pub fn in_progress(&self) -> bool {
+        return match self.status.as_deref() {
+            None | Some("created") | Some("started") => true,
+            Some(_) => false,
+        };
+    }
+
+    /**
+     * [draw_offered_by(white)] returns whether the player of the given color
+     * is offering a draw.
+     */
+    pub fn draw_offered_by(&self, white: bool) -> bool {
+        return if white { self.wdraw } else { self.bdraw };
+    }
+
+    /**
+     * [takeback_offered_by(white)] returns whether the player of the given
+     * color is proposing a takeback.
+     */
+    pub fn takeback_offered_by(&self, white: bool) -> bool {
+        return if white {
+            self.wtakeback
+        } else {
+            self.btakeback
+        };
+    }
+}
+
+impl ChatSettings {
+    /**
+     * [from_config(config)] reads the chat settings from the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> ChatSettings {
+        let settings = &config["chat"];
+        let message = |key: &str, default: &str| match &settings[key] {
+            Value::Null => Some(default.to_string()),
+            Value::String(s) if s.len() > 0 => Some(s.to_string()),
+            _ => None,
+        };
+        return ChatSettings {
+            greeting: message("greeting", DEFAULT_GREETING),
+            goodbye: message("goodbye", DEFAULT_GOODBYE),
+            eval_command: settings["eval_command"].as_bool().unwrap_or(true),
+        };
+    }
+
+    /**
+     * [asks_eval(text)] returns whether the chat line [text] asks for the
+     * bot's evaluation.
+     */
+    pub fn asks_eval(&self, text: &str) -> bool {
+        return self.eval_command && text.trim().eq_ignore_ascii_case(EVAL_COMMAND);
+    }
+}
+
+impl LichessClient {
+    /**
+     * [new(client, base, auth_token, watchdog, retry)] creates a client of the
+     * Lichess API at [base] sending requests with [client] on behalf of the
+     * bot with [auth_token], re-opening stalled streams with [watchdog] and
+     * retrying failed requests according to [retry].
+     */
+    pub fn new(
+        client: reqwest::Client,
+        base: &str,
+        auth_token: &str,
+        watchdog: Watchdog,
+        retry: RetryPolicy,
+    ) -> LichessClient {
+        return LichessClient {
+            client,
+            base: base.trim_end_matches('/').to_string(),
+            auth_token: auth_token.to_string(),
+            watchdog,
+            retry,
+        };
+    }
+
+    /**
+     * [from_config(client, auth_token, config)] creates a client of the
+     * Lichess API, watchdog and retry policy given by the parsed [config].
+     */
+    pub fn from_config(
+        client: &reqwest::Client,
+        auth_token: &str,
+        config: &Value,
+    ) -> LichessClient {
+        return LichessClient::new(
+            client.clone(),
+            &read_lichess_url(config),
+            auth_token,
+            Watchdog::from_config(config),
+            RetryPolicy::from_config(config),
+        );
+    }
+
+    /**
+     * [post(path, form)] sends a POST request to [path] of the API, with
+     * the fields in [form] if there are any.
+     */
+    async fn post(&self, path: &str, form: &[(&str, &str)]) -> BotResult<reqwest::Response> {
+        let url = format!("{}{}", self.base, path);
+        let mut request = self.client.post(&url).bearer_auth(&self.auth_token);
+        if form.len() > 0 {
+            request = request.form(form);
+        }
+        return Ok(self.retry.send("POST", &url, request).await?);
+    }
+
+    /**
+     * [get(path)] sends a GET request to [path] of the API and returns its
+     * body, or None if it failed.
+     */
+    async fn get(&self, path: &str) -> BotResult<Option<String>> {
+        let url = format!("{}{}", self.base, path);
+        let request = self.client.get(&url).bearer_auth(&self.auth_token);
+        let res = self.retry.send("GET", &url, request).await?;
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        let body = res.text().await?;
+        log_body(&url, body.as_bytes());
+        return Ok(Some(body));
+    }
+
+    /**
+     * [account_id()] returns the id of the bot's account, or None if Lichess
+     * did not send it.
+     */
+    pub async fn account_id(&self) -> BotResult<Option<String>> {
+        let body = self.get("/api/account").await?.unwrap_or_default();
+        let account: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        return Ok(account["id"].as_str().map(|id| id.to_string()));
+    }
+
+    /**
+     * [online_bots(count)] returns up to [count] of the bots that are online.
+     */
+    pub async fn online_bots(&self, count: usize) -> BotResult<Vec<User>> {
+        let body = self.get(&format!("/api/bot/online?nb={}", count)).await?;
+        return Ok(body
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| parse_line(line.as_bytes()))
+            .collect());
+    }
+
+    /**
+     * [create_challenge(username, rated, limit_secs, increment_secs)]
+     * challenges [username] to a standard game, [rated] or not, with a clock
+     * of [limit_secs] seconds and an increment of [increment_secs] seconds,
+     * with colors picked at random. Returns the id of the challenge, which
+     * becomes the id of the game if it is accepted, or None if it could not
+     * be sent.
+     */
+    pub async fn create_challenge(
+        &self,
+        username: &str,
+        rated: bool,
+        limit_secs: u64,
+        increment_secs: u64,
+    ) -> BotResult<Option<String>> {
+        let path = format!("/api/challenge/{}", username);
+        let (rated, limit, increment) = (
+            rated.to_string(),
+            limit_secs.to_string(),
+            increment_secs.to_string(),
+        );
+        let form = [
+            ("rated", rated.as_str()),
+            ("clock.limit", limit.as_str()),
+            ("clock.increment", increment.as_str()),
+            ("color", "random"),
+            ("variant", "standard"),
+        ];
+        let res = self.post(&path, &form).await?;
+        let success = res.status().is_success();
+        let body = res.text().await?;
+        log_body(&format!("{}{}", self.base, path), body.as_bytes());
+        if !success {
+            return Ok(None);
+        }
+
+        // The challenge is sent either on its own or wrapped in an object
+        let challenge: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        let id = challenge["id"]
+            .as_str()
+            .or(challenge["challenge"]["id"].as_str());
+        return Ok(id.map(|id| id.to_string()));
+    }
+
+    /**
+     * [cancel_challenge(challenge_id)] withdraws the challenge the bot sent
+     * with id [challenge_id], returning whether Lichess accepted the request.
+     */
+    pub async fn cancel_challenge(&self, challenge_id: &str) -> BotResult<bool> {
+        let path = format!("/api/challenge/{}/cancel", challenge_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [event_stream()] returns the stream of incoming events, which lists
+     * every ongoing game of the bot's each time it is opened.
+     */
+    pub fn event_stream(&self) -> NdjsonStream {
+        return NdjsonStream::new(self, &format!("{}/api/stream/event", self.base));
+    }
+
+    /**
+     * [game_stream(game_id)] returns the stream of game [game_id], which
+     * starts with the full game each time it is opened.
+     */
+    pub fn game_stream(&self, game_id: &str) -> NdjsonStream {
+        let url = format!("{}/api/bot/game/stream/{}", self.base, game_id);
+        return NdjsonStream::new(self, &url);
+    }
+
+    /**
+     * [stream_game(game_id)] reads the full game [game_id] from a fresh game
+     * stream, returning None if the stream ended or sent something else.
+     */
+    pub async fn stream_game(&self, game_id: &str) -> BotResult<Option<GameFull>> {
+        return match self.game_stream(game_id).next_line().await? {
+            Some(GameUpdate::GameFull(game)) => Ok(Some(game)),
+            _ => Ok(None),
+        };
+    }
+
+    /**
+     * [make_move(game_id, uci)] plays move [uci] in game [game_id].
+     */
+    pub async fn make_move(&self, game_id: &str, uci: &str) -> BotResult<MoveResponse> {
+        let path = format!("/api/bot/game/{}/move/{}", game_id, uci);
+        let res = self.post(&path, &[]).await?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(MoveResponse::Accepted);
+        }
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            let body = res.text().await?;
+            log_body(&format!("{}{}", self.base, path), body.as_bytes());
+            return Ok(MoveResponse::Rejected(body));
+        }
+        return Ok(MoveResponse::Failed(status));
+    }
+
+    /**
+     * [claim_victory(game_id)] claims victory in game [game_id] after the
+     * opponent left it, returning whether Lichess accepted the claim.
+     */
+    pub async fn claim_victory(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/claim-victory", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [resign(game_id)] resigns game [game_id], returning whether Lichess
+     * accepted the resignation.
+     */
+    pub async fn resign(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/resign", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [offer_draw(game_id)] offers the opponent a draw in game [game_id], or
+     * accepts their offer, returning whether Lichess accepted the request.
+     */
+    pub async fn offer_draw(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/draw/yes", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [decline_draw(game_id)] declines the opponent's draw offer in game
+     * [game_id], returning whether Lichess accepted the request.
+     */
+    pub async fn decline_draw(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/draw/no", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [decline_takeback(game_id)] declines the opponent's takeback proposal in
+     * game [game_id], returning whether Lichess accepted the request.
+     */
+    pub async fn decline_takeback(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/takeback/no", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [accept_challenge(challenge_id)] accepts the challenge with id
+     * [challenge_id], returning whether Lichess accepted the request.
+     */
+    pub async fn accept_challenge(&self, challenge_id: &str) -> BotResult<bool> {
+        let path = format!("/api/challenge/{}/accept", challenge_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [decline_challenge(challenge_id, reason)] declines the challenge with
+     * id [challenge_id] for [reason] (e.g. "tooFast"), returning whether
+     * Lichess accepted the request.
+     */
+    pub async fn decline_challenge(&self, challenge_id: &str, reason: &str) -> BotResult<bool> {
+        let path = format!("/api/challenge/{}/decline", challenge_id);
+        let res = self.post(&path, &[("reason", reason)]).await?;
+        return Ok(res.status().is_success());
+    }
+
+    /**
+     * [berserk(game_id)] halves the bot's clock in arena game [game_id] for an
+     * extra point if it wins, returning whether Lichess accepted the request.
+     */
+    pub async fn berserk(&self, game_id: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/berserk", game_id);
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [join_tournament(tournament_id, swiss)] joins the arena tournament, or
+     * the swiss tournament if [swiss], with id [tournament_id], asking to be
+     * paired as soon as possible, and returns whether Lichess accepted the
+     * request. Joining an arena again after a game marks the bot ready for
+     * its next pairing.
+     */
+    pub async fn join_tournament(&self, tournament_id: &str, swiss: bool) -> BotResult<bool> {
+        let res = if swiss {
+            let path = format!("/api/swiss/{}/join", tournament_id);
+            self.post(&path, &[]).await?
+        } else {
+            let path = format!("/api/tournament/{}/join", tournament_id);
+            self.post(&path, &[("pairMeAsap", "true")]).await?
+        };
+        let success = res.status().is_success();
+        if !success {
+            let body = res.text().await?;
+            warn!(
+                "Unable to join tournament {}: {}",
+                tournament_id,
+                body.trim()
+            );
+        }
+        return Ok(success);
+    }
+
+    /**
+     * [withdraw_tournament(tournament_id, swiss)] leaves the arena tournament,
+     * or the swiss tournament if [swiss], with id [tournament_id], returning
+     * whether Lichess accepted the request.
+     */
+    pub async fn withdraw_tournament(&self, tournament_id: &str, swiss: bool) -> BotResult<bool> {
+        let path = if swiss {
+            format!("/api/swiss/{}/withdraw", tournament_id)
+        } else {
+            format!("/api/tournament/{}/withdraw", tournament_id)
+        };
+        return Ok(self.post(&path, &[]).await?.status().is_success());
+    }
+
+    /**
+     * [tournament_finished(tournament_id, swiss)] returns whether the arena
+     * tournament, or the swiss tournament if [swiss], with id [tournament_id]
+     * has finished, which a tournament Lichess does not describe counts as.
+     */
+    pub async fn tournament_finished(&self, tournament_id: &str, swiss: bool) -> BotResult<bool> {
+        let kind = if swiss { "swiss" } else { "tournament" };
+        let body = match self
+            .get(&format!("/api/{}/{}", kind, tournament_id))
+            .await?
+        {
+            Some(b) => b,
+            None => return Ok(true),
+        };
+        let tournament: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        return Ok(tournament["isFinished"].as_bool().unwrap_or(false)
+            || tournament["status"].as_str() == Some("finished"));
+    }
+
+    /**
+     * [chat(game_id, room, text)] posts [text] to the chat [room] ("player"
+     * or "spectator") of game [game_id], returning whether it was posted.
+     */
+    pub async fn chat(&self, game_id: &str, room: &str, text: &str) -> BotResult<bool> {
+        let path = format!("/api/bot/game/{}/chat", game_id);
+        let res = self.post(&path, &[("room", room), ("text", text)]).await?;
+        return Ok(res.status().is_success());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A connection the test server answers, with the chunks of its body and
+    // whether it then stalls rather than closing
+    type Connection = (&'static [&'static str], bool);
+
+    /**
+     * [serve(connections)] starts a server on a free local port answering
+     * the requests it gets with [connections] in turn, returning a client of
+     * it along with the number of requests it got.
+     */
+    async fn serve(connections: &'static [Connection]) -> (LichessClient, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                let _ = stream.read(&mut buffer).await;
+                let n = counted.fetch_add(1, Ordering::SeqCst);
+                let (chunks, stalls) = connections[n.min(connections.len() - 1)];
+                tokio::spawn(async move {
+                    let head = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n";
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    for chunk in chunks {
+                        let _ = stream.write_all(chunk.as_bytes()).await;
+                        let _ = stream.flush().await;
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                    if stalls {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                });
+            }
+        });
+
+        let watchdog = Watchdog {
+            move_timeout: Duration::from_secs(1),
+            stream_timeout: Duration::from_millis(300),
+        };
+        let retry = RetryPolicy::from_config(&Value::Null);
+        let lichess = LichessClient::new(reqwest::Client::new(), &base, "token", watchdog, retry);
+        return (lichess, requests);
+    }
+
+    /**
+     * [read_n(stream)] reads the next line of [stream], returning its "n".
+     */
+    async fn read_n(stream: &mut NdjsonStream) -> Option<u64> {
+        let line: Option<Value> = stream.next_line().await.unwrap();
+        return line.and_then(|v| v["n"].as_u64());
+    }
+
+    #[tokio::test]
+    async fn lines_are_split_across_and_within_chunks() {
+        const CONNECTIONS: &[Connection] = &[(
+            &[
+                "{\"n\":1}\n{\"n\"",
+                ":2}\n\nnot json\n",
+                "{\"n\":3}\n{\"n\":4}",
+            ],
+            false,
+        )];
+        let (lichess, _) = serve(CONNECTIONS).await;
+        let mut stream = lichess.event_stream();
+        for n in 1..=4 {
+            assert_eq!(read_n(&mut stream).await, Some(n));
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_are_reopened_after_ending_or_stalling() {
+        const CONNECTIONS: &[Connection] = &[
+            (&["{\"n\":1}\n"], false),
+            (&["{\"n\":2}\n{\"n\":"], true),
+            (&["{\"n\":3}\n"], false),
+        ];
+        let (lichess, requests) = serve(CONNECTIONS).await;
+        let mut stream = lichess.event_stream();
+        assert_eq!(read_n(&mut stream).await, Some(1));
+        assert_eq!(read_n(&mut stream).await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // The partial line of the stalled stream is dropped with it
+        assert_eq!(read_n(&mut stream).await, Some(2));
+        assert_eq!(read_n(&mut stream).await, Some(3));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
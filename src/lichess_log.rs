@@ -0,0 +1,147 @@
+/**
+ * Utility module for opt-in debug logging of every request made to Lichess,
+ * along with the status and body of each response, for diagnosing unexpected
+ * responses without rebuilding. Logging is enabled by the "debug_log" object
+ * in config.json, e.g.
+ * {"enabled": true, "path": "lichess_debug.log", "max_bytes": 10000000,
+ *  "max_files": 3}, which rotates the log to lichess_debug.log.1 and so on
+ * once it grows past max_bytes, keeping at most max_files old logs. The auth
+ * token is never written to the log.
+ */
+use reqwest::{RequestBuilder, Response};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+const DEFAULT_LOG_PATH: &str = "lichess_debug.log";
+const DEFAULT_MAX_BYTES: u64 = 10_000_000;
+const DEFAULT_MAX_FILES: u64 = 3;
+
+// Where and how debug entries are logged
+struct DebugLog {
+    path: String,
+    max_bytes: u64,
+    max_files: u64,
+    auth_token: String,
+}
+
+// The debug log, which is None unless enabled in config.json
+static DEBUG_LOG: Mutex<Option<DebugLog>> = Mutex::new(None);
+
+/**
+ * [init(config, auth_token)] enables the debug log if the parsed [config]
+ * asks for it, redacting [auth_token] from everything logged.
+ */
+pub fn init(config: &Value, auth_token: &str) {
+    let settings = &config["debug_log"];
+    if !settings["enabled"].as_bool().unwrap_or(false) {
+        return;
+    }
+
+    *DEBUG_LOG.lock().unwrap() = Some(DebugLog {
+        path: settings["path"]
+            .as_str()
+            .unwrap_or(DEFAULT_LOG_PATH)
+            .to_string(),
+        max_bytes: settings["max_bytes"].as_u64().unwrap_or(DEFAULT_MAX_BYTES),
+        max_files: settings["max_files"].as_u64().unwrap_or(DEFAULT_MAX_FILES),
+        auth_token: auth_token.to_string(),
+    });
+}
+
+impl DebugLog {
+    /**
+     * [rotate()] shifts each old log up by one, dropping the oldest, and
+     * moves the current log to the first old log.
+     */
+    fn rotate(&self) {
+        for i in (1..self.max_files).rev() {
+            let _ = fs::rename(
+                format!("{}.{}", self.path, i),
+                format!("{}.{}", self.path, i + 1),
+            );
+        }
+        if self.max_files > 0 {
+            let _ = fs::rename(&self.path, format!("{}.1", self.path));
+        } else {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /**
+     * [write(entry)] appends [entry] to the log with the auth token redacted,
+     * rotating the log first if it would grow too large.
+     */
+    fn write(&self, entry: &str) {
+        let entry = if self.auth_token.len() > 0 {
+            entry.replace(&self.auth_token, "<redacted>")
+        } else {
+            entry.to_string()
+        };
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + entry.len() as u64 > self.max_bytes {
+            self.rotate();
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        if let Ok(mut file) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            let _ = writeln!(file, "[{}] {}", time, entry);
+        }
+    }
+}
+
+/**
+ * [log(entry)] writes [entry] to the debug log if it is enabled.
+ */
+fn log(entry: &str) {
+    if let Some(debug_log) = DEBUG_LOG.lock().unwrap().as_ref() {
+        debug_log.write(entry);
+    }
+}
+
+/**
+ * [send(method, url, request)] sends [request], which is a [method] request
+ * to [url], logging the request and the response status or error.
+ */
+pub async fn send(
+    method: &str,
+    url: &str,
+    request: RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    log(&format!(
+        "{} {} (Authorization: Bearer <redacted>)",
+        method, url
+    ));
+    let res = request.send().await;
+    match &res {
+        Ok(r) => debug!(event = "api", method, url, status = r.status().as_u16()),
+        Err(e) => debug!(event = "api", method, url, error = %e),
+    };
+    match &res {
+        Ok(r) => log(&format!("{} {} -> {}", method, url, r.status())),
+        Err(e) => log(&format!("{} {} -> error: {}", method, url, e)),
+    };
+
+    return res;
+}
+
+/**
+ * [log_body(url, body)] logs [body], received in a response from [url].
+ */
+pub fn log_body(url: &str, body: &[u8]) {
+    log(&format!(
+        "{} <- {}",
+        url,
+        String::from_utf8_lossy(body).trim_end()
+    ));
+}
@@ -0,0 +1,154 @@
+/**
+ * Utility module for limiting how much work a network may do per move in
+ * offline games, so that stronger-vs-weaker matchups and strength-vs-speed
+ * tradeoffs can be measured reproducibly. A node is one evaluation of a move
+ * by a network, and clocks are simulated by charging a fixed time per node
+ * rather than measuring wall time. Limits are written as "unlimited",
+ * "nodes=N" for at most N nodes per move, or "clock=T+I" for a simulated clock
+ * of T milliseconds with an increment of I milliseconds per move. In Lichess
+ * games the bot's limit is instead a node budget with a wall-clock deadline,
+ * both set by the time manager from its real clock.
+ */
+use crate::mdp::get_action;
+use crate::q_function::QFunction;
+
+use chess::{Board, ChessMove, MoveGen};
+use std::time::Instant;
+
+// Simulated time charged for each node
+pub const SIMULATED_MS_PER_NODE: u64 = 1;
+
+// Fraction of the remaining clock time budgeted for a move
+const CLOCK_MOVES_TO_GO: u64 = 30;
+
+// How much work a side may do per move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchLimit {
+    Unlimited,
+    Nodes(usize),
+    Clock { time_ms: u64, increment_ms: u64 },
+}
+
+// The state of a side's limit over a game
+#[derive(Clone, Debug)]
+pub struct SideClock {
+    pub limit: SearchLimit,
+    pub remaining_ms: u64,
+    pub nodes: usize,
+    pub deadline: Option<Instant>, // when the next move must be chosen by, if set
+    flagged: bool,
+}
+
+/**
+ * [parse_limit(s)] parses the search limit [s].
+ */
+pub fn parse_limit(s: &str) -> SearchLimit {
+    if s.eq("unlimited") {
+        return SearchLimit::Unlimited;
+    }
+    if let Some(n) = s.strip_prefix("nodes=") {
+        return SearchLimit::Nodes(n.parse().expect("Invalid node limit"));
+    }
+    if let Some(clock) = s.strip_prefix("clock=") {
+        let (time, increment) = clock.split_once('+').unwrap_or((clock, "0"));
+        return SearchLimit::Clock {
+            time_ms: time.parse().expect("Invalid clock time"),
+            increment_ms: increment.parse().expect("Invalid clock increment"),
+        };
+    }
+
+    panic!("Invalid search limit: {}", s);
+}
+
+/**
+ * [best_move_limited(nn, b, state, player_white, budget)] returns the move in
+ * board [b], encoded as [state], with the highest Q-value under policy
+ * network [nn] depending on whether the player is white, evaluating at most [budget] moves (all if None, and
+ * always at least one), along with the number of moves evaluated. Captures
+ * are evaluated first. Alternatively if there are no legal moves it returns
+ * None.
+ */
+pub fn best_move_limited<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    state: &[f64],
+    player_white: bool,
+    budget: Option<usize>,
+) -> Option<(ChessMove, usize)> {
+    let mut moves: Vec<ChessMove> = MoveGen::new_legal(b).collect();
+    moves.sort_by_key(|m| b.piece_on(m.get_dest()).is_none());
+    if let Some(n) = budget {
+        moves.truncate(n.max(1));
+    }
+
+    let mut best: Option<(ChessMove, f64)> = None;
+    for m in &moves {
+        let mut sa = state.to_vec();
+        sa.append(&mut get_action(*m, player_white));
+        let score = nn.predict(&sa[..]);
+        match best {
+            Some((_, high)) if high > score => (),
+            _ => best = Some((*m, score)),
+        };
+    }
+
+    return best.map(|(m, _)| (m, moves.len()));
+}
+
+impl SideClock {
+    /**
+     * [new(limit)] starts a side's clock for a game under [limit].
+     */
+    pub fn new(limit: SearchLimit) -> SideClock {
+        let remaining_ms = match limit {
+            SearchLimit::Clock { time_ms, .. } => time_ms,
+            _ => 0,
+        };
+        return SideClock {
+            limit,
+            remaining_ms,
+            nodes: 0,
+            deadline: None,
+            flagged: false,
+        };
+    }
+
+    /**
+     * [node_budget()] returns how many nodes the side may spend on its next
+     * move, or None if it is unlimited.
+     */
+    pub fn node_budget(&self) -> Option<usize> {
+        match self.limit {
+            SearchLimit::Unlimited => None,
+            SearchLimit::Nodes(n) => Some(n),
+            SearchLimit::Clock { increment_ms, .. } => {
+                let budget_ms = self.remaining_ms / CLOCK_MOVES_TO_GO + increment_ms;
+                Some((budget_ms / SIMULATED_MS_PER_NODE) as usize)
+            }
+        }
+    }
+
+    /**
+     * [spend(nodes)] charges the side for a move that took [nodes] nodes,
+     * flagging it if its clock runs out.
+     */
+    pub fn spend(&mut self, nodes: usize) {
+        self.nodes += nodes;
+        if let SearchLimit::Clock { increment_ms, .. } = self.limit {
+            let used_ms = nodes as u64 * SIMULATED_MS_PER_NODE;
+            if used_ms > self.remaining_ms {
+                self.flagged = true;
+                self.remaining_ms = 0;
+            } else {
+                self.remaining_ms = self.remaining_ms - used_ms + increment_ms;
+            }
+        }
+    }
+
+    /**
+     * [flagged()] returns whether the side has run out of time.
+     */
+    pub fn flagged(&self) -> bool {
+        return self.flagged;
+    }
+}
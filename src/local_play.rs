@@ -0,0 +1,140 @@
+/**
+ * Utility module for playing against the bot in the terminal. The human enters
+ * moves at a prompt in SAN or uci format (e.g. "Nf3" or "g1f3"), and the bot
+ * replies with the move its policy network rates best, or with the move an
+ * alpha-beta search finds when "depth" is set in the "alphabeta" object of
+ * config.json (e.g. through --search-depth). The board is shown after every
+ * move, and the game ends at checkmate, stalemate, a fivefold repetition or
+ * the seventy-five move rule, or when the human enters "resign" or "quit".
+ */
+use crate::display::render_board;
+use crate::error::BotResult;
+use crate::game_context::GameContext;
+use crate::history::{MoveCounters, PositionHistory};
+use crate::limits::SearchLimit;
+use crate::mdp::move_by_policy;
+use crate::models::ModelRegistry;
+use crate::notation::to_san;
+use crate::repertoire::parse_move;
+use crate::search::alphabeta::{self, AlphaBetaSettings};
+use crate::search::transposition::TranspositionTable;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+// What the human entered at the prompt
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HumanInput {
+    Move(ChessMove),
+    Resign,
+    Illegal,
+}
+
+/**
+ * [parse_input(b, line)] parses the human's [line] in board [b], which is a
+ * legal move in SAN or uci format or a resignation.
+ */
+fn parse_input(b: &Board, line: &str) -> HumanInput {
+    let token = line.trim();
+    if token == "resign" || token == "quit" {
+        return HumanInput::Resign;
+    }
+    return match parse_move(b, token) {
+        Some(m) => HumanInput::Move(m),
+        None => HumanInput::Illegal,
+    };
+}
+
+/**
+ * [game_over(context)] returns the result of the game in [context] if it has
+ * ended, described for the human.
+ */
+fn game_over(context: &GameContext) -> Option<String> {
+    let board = context.board();
+    let winner = match board.side_to_move() {
+        Color::White => "Black",
+        Color::Black => "White",
+    };
+    return match board.status() {
+        BoardStatus::Checkmate => Some(format!("Checkmate, {} wins", winner)),
+        BoardStatus::Stalemate => Some("Stalemate, the game is drawn".to_string()),
+        BoardStatus::Ongoing if context.history.is_automatic_draw() => {
+            Some("The game is drawn by repetition or the move rule".to_string())
+        }
+        BoardStatus::Ongoing => None,
+    };
+}
+
+/**
+ * [play_local(config, human_white, start, counters)] plays a game in the
+ * terminal from board [start] with move [counters] between the human, who is
+ * white or not as given by [human_white], and the bot's network for the other
+ * color given by the parsed [config], or returns an error if the network
+ * cannot be loaded.
+ */
+pub fn play_local(
+    config: &Value,
+    human_white: bool,
+    start: Board,
+    counters: MoveCounters,
+) -> BotResult<()> {
+    let mut models = ModelRegistry::from_config(config)?;
+    let mut table = TranspositionTable::from_config(config);
+    let alphabeta = match config["alphabeta"]["depth"].as_u64() {
+        Some(_) => Some(AlphaBetaSettings::from_config(config)),
+        None => None,
+    };
+    let mut context = GameContext::new(&start, (SearchLimit::Unlimited, SearchLimit::Unlimited));
+    context.history = PositionHistory::with_counters(&start, counters);
+    let mut lines = io::stdin().lock().lines();
+
+    println!("{}", render_board(&start, None, human_white));
+    let result = loop {
+        if let Some(result) = game_over(&context) {
+            break result;
+        }
+        let board = context.board();
+        let player_white = context.player_white();
+
+        let m = if player_white == human_white {
+            print!("Your move: ");
+            io::stdout().flush().unwrap();
+            let line = match lines.next() {
+                Some(Ok(l)) => l,
+                _ => break "Game abandoned".to_string(),
+            };
+            match parse_input(&board, &line) {
+                HumanInput::Move(m) => m,
+                HumanInput::Resign => break "You resigned".to_string(),
+                HumanInput::Illegal => {
+                    println!("{} is not a legal move, try again", line.trim());
+                    continue;
+                }
+            }
+        } else {
+            let nn = &mut models.head(player_white);
+            let counters = context.history.counters();
+            let chosen = match &alphabeta {
+                Some(settings) => {
+                    alphabeta::search(nn, &board, counters, settings, &mut table, None, None)
+                        .map(|result| result.best)
+                }
+                None => move_by_policy(nn, &board, counters, player_white, &mut table),
+            };
+            match chosen {
+                Some(m) => {
+                    println!("Bot plays {}", to_san(&board, m));
+                    m
+                }
+                None => break "The bot has no move".to_string(),
+            }
+        };
+
+        context.make_move(m);
+        println!("{}", render_board(&context.board(), Some(m), human_white));
+    };
+
+    println!("{}", result);
+    return Ok(());
+}
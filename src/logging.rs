@@ -0,0 +1,170 @@
+/**
+ * Utility module for the bot's logs, built on tracing. Everything the bot
+ * logs goes to stderr, filtered by the RUST_LOG environment variable or else
+ * by the configured level. Optionally, every event logged while a game is
+ * played (the candidate moves and Q-scores of each decision, whether it
+ * explored, the rewards recorded and the requests made to Lichess) is also
+ * appended as a line of JSON to a transcript of the game, <dir>/<game id>.jsonl,
+ * so that bad games can be picked apart afterwards. Configured by the
+ * "logging" object in config.json, e.g.
+ * {"level": "info", "transcripts": "transcripts"}, which logs at info level
+ * and writes transcripts to the transcripts directory. Transcripts are off
+ * unless a directory is given.
+ */
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LEVEL: &str = "info";
+
+// Name of the spans games are played in
+const GAME_SPAN: &str = "game";
+
+// Target of the bot's own events, the only ones written to transcripts
+const TRANSCRIPT_TARGET: &str = "rust_chess_bot";
+
+// Writes the events of each game to its transcript
+struct TranscriptLayer {
+    dir: String,
+}
+
+// The game a span belongs to, kept in the span's extensions
+struct GameId(String);
+
+// The fields of an event or span, as JSON
+#[derive(Default)]
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+}
+
+impl TranscriptLayer {
+    /**
+     * [append(game_id, entry)] appends [entry] to the transcript of game
+     * [game_id].
+     */
+    fn append(&self, game_id: &str, entry: &Value) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{}/{}.jsonl", self.dir, game_id))?;
+        return writeln!(file, "{}", entry);
+    }
+}
+
+impl<S> Layer<S> for TranscriptLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != GAME_SPAN {
+            return;
+        }
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        if let (Some(game_id), Some(span)) = (fields.0.get("id"), ctx.span(id)) {
+            let game_id = game_id.as_str().map_or(game_id.to_string(), String::from);
+            span.extensions_mut().insert(GameId(game_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let game_id = ctx.event_scope(event).and_then(|scope| {
+            scope.from_root().find_map(|span| {
+                let extensions = span.extensions();
+                extensions.get::<GameId>().map(|g| g.0.clone())
+            })
+        });
+        let game_id = match game_id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut entry = Map::new();
+        entry.insert("time".to_string(), json!(time));
+        entry.insert(
+            "level".to_string(),
+            json!(event.metadata().level().to_string()),
+        );
+        entry.insert("target".to_string(), json!(event.metadata().target()));
+        entry.extend(fields.0);
+        if let Err(e) = self.append(&game_id, &Value::Object(entry)) {
+            eprintln!("Unable to write the transcript of {}: {}", game_id, e);
+        }
+    }
+}
+
+/**
+ * [init(config)] installs the bot's logging as given by the parsed [config].
+ * Only the first call has any effect.
+ */
+pub fn init(config: &Value) {
+    let settings = &config["logging"];
+    let level = settings["level"].as_str().unwrap_or(DEFAULT_LEVEL);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .with_filter(filter);
+
+    // Transcripts keep the bot's debug events whatever the console shows
+    let transcripts = settings["transcripts"].as_str().map(|dir| {
+        TranscriptLayer {
+            dir: dir.to_string(),
+        }
+        .with_filter(Targets::new().with_target(TRANSCRIPT_TARGET, Level::DEBUG))
+    });
+
+    let _ = tracing_subscriber::registry()
+        .with(console)
+        .with(transcripts)
+        .try_init();
+}
+
+/**
+ * [game_span(game_id)] returns the span game [game_id] is played in, whose
+ * events are written to its transcript.
+ */
+pub fn game_span(game_id: &str) -> Span {
+    return tracing::info_span!("game", id = game_id);
+}
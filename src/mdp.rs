@@ -1,11 +1,101 @@
 /**
  * Utility module for handling conversion of Chess into an MDP (Markov Decision
- * Process)
+ * Process), and for learning Q-values over it. Bootstrapped values are read
+ * from a target network kept apart from the policy network being fit, which
+ * follows it according to the "target_network" object in config.json, e.g.
+ * {"update": "hard", "interval": 1000} copies the policy network every 1000
+ * fits, {"update": "soft", "tau": 0.01} moves the target network 1% of the way
+ * towards it after every fit, where a fit is of a single experience or of a
+ * minibatch of experiences, and by default it is synced at the start of
+ * every learning pass. With "double_dqn": true the policy network picks the
+ * best next move and the target network values it, which curbs the
+ * overestimation of taking the max over noisy values. Next states are valued
+ * as they were encoded, move counters and all, rather than rebuilt from the
+ * board. The target network's evaluations are cached in a transposition
+ * table configured by the "transposition" object, keyed by the board and the
+ * rest of the state, which is cleared whenever the target network changes,
+ * so that it pays off with hard and per-pass updates.
  */
+use crate::augment::mirror_experience;
+use crate::checkpoint::{board_phase, Phase};
+use crate::decision::{MoveDecision, MoveSource};
+use crate::error::{BotError, BotResult};
+use crate::history::{MoveCounters, PositionHistory};
+use crate::model::restore_activation;
+use crate::normalization::{FeatureStats, Normalized};
+use crate::output_scaling::OutputScaling;
+use crate::policy_head::{is_policy_head, NetworkHead};
+use crate::q_function::QFunction;
+use crate::replay_buffer::ReplayBuffer;
+use crate::returns::ReturnTarget;
+use crate::search::transposition::TranspositionTable;
+
 use chess::{BitBoard, Board, BoardStatus, ChessMove, Color, MoveGen, Piece, Square};
 use neuroflow::FeedForward;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::ops::BitAnd;
 use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, trace, warn};
+
+// Lengths of the piece planes, and of the features of a single board
+pub const PIECE_DIM: usize = 12 * 64;
+pub const BOARD_DIM: usize = PIECE_DIM + 64 + 4 + 1;
+
+// Number of earlier positions whose piece planes are stacked into the state
+// when built with the history_planes feature, followed by 2 features counting
+// how often the current position was reached before
+#[cfg(feature = "history_planes")]
+pub const HISTORY_POSITIONS: usize = 7;
+#[cfg(not(feature = "history_planes"))]
+pub const HISTORY_POSITIONS: usize = 0;
+pub const HISTORY_DIM: usize = if HISTORY_POSITIONS > 0 {
+    HISTORY_POSITIONS * PIECE_DIM + 2
+} else {
+    0
+};
+
+// Number of move counters ending the state, the halfmove clock and the move
+// number, each scaled into [0, 1] by the value it saturates at
+pub const COUNTER_DIM: usize = 2;
+const HALFMOVE_CLOCK_SCALE: f64 = 100.;
+const MOVE_NUMBER_SCALE: f64 = 200.;
+
+// Lengths of the state and action vectors
+pub const STATE_DIM: usize = BOARD_DIM + HISTORY_DIM + COUNTER_DIM;
+pub const ACTION_DIM: usize = 2 * 64 + 4;
+
+// Rewards given for winning and losing a game
+pub const WIN_REWARD: f64 = 100.;
+pub const LOSS_REWARD: f64 = -100.;
+
+// Number of moves scored in each batch when scoring against a deadline
+const DEADLINE_BATCH_SIZE: usize = 8;
+
+// How the target network follows the policy network: synced at the start of
+// every learning pass, copied every [interval] fits, or moved a fraction
+// [tau] of the way towards it after every fit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetUpdate {
+    PerPass,
+    Hard { interval: usize },
+    Soft { tau: f64 },
+}
+
+// The network bootstrapped values are read from, so that targets do not chase
+// the policy network's own updates
+pub struct TargetNetwork {
+    pub network: FeedForward,
+    pub update: TargetUpdate,
+    pub double_dqn: bool,
+    pub policy_head: bool,         // whether both networks are policy heads
+    pub table: TranspositionTable, // evaluations of the target network
+    pub stats: FeatureStats,       // feature statistics of the target network
+    fits: usize,                   // fits since the last hard update
+}
 
 // Struct to represent the experience of the bot at one time-step (i.e. move)
 #[derive(Clone, Debug)]
@@ -15,6 +105,86 @@ pub struct Experience {
     pub reward: f64,
     pub next_state: Vec<f64>,
     pub next_board: Board,
+    pub clock: Option<f64>, // seconds left on the player's clock, if timed
+    pub done: bool,         // whether the game ended with the next state
+    pub search_target: Option<f64>, // searched Q-value of the action, if any
+    pub meta: ExperienceMeta,
+}
+
+// Where an experience was gathered, which is unknown for experiences stored
+// before it was recorded
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExperienceSource {
+    #[default]
+    Unknown,
+    Lichess,
+    SelfPlay,
+    Pgn,
+}
+
+// Provenance of an experience, for analysing and weighting experiences
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExperienceMeta {
+    pub game_id: Option<String>,
+    pub ply: usize, // ply the action was played at, counting from 1
+    pub phase: Phase,
+    pub source: ExperienceSource,
+    pub behavior_policy: String, // the agent that selected the action
+    pub timestamp: u64,          // seconds since the Unix epoch
+    pub position: Option<u64>,   // Zobrist hash of the board the action was played in
+}
+
+/**
+ * [parse_experience_source(s)] converts the source name [s] into an
+ * ExperienceSource, which is unknown for unrecognized names.
+ */
+pub fn parse_experience_source(s: &str) -> ExperienceSource {
+    match s {
+        "lichess" => ExperienceSource::Lichess,
+        "selfplay" => ExperienceSource::SelfPlay,
+        "pgn" => ExperienceSource::Pgn,
+        _ => ExperienceSource::Unknown,
+    }
+}
+
+/**
+ * [experience_source_name(source)] converts [source] into its name.
+ */
+pub fn experience_source_name(source: ExperienceSource) -> &'static str {
+    match source {
+        ExperienceSource::Unknown => "unknown",
+        ExperienceSource::Lichess => "lichess",
+        ExperienceSource::SelfPlay => "selfplay",
+        ExperienceSource::Pgn => "pgn",
+    }
+}
+
+impl ExperienceMeta {
+    /**
+     * [new(source, game_id, b, ply, behavior_policy)] creates the metadata of
+     * an experience gathered from [source] in the game with id [game_id] if
+     * known, whose action was selected by [behavior_policy] in board [b] at
+     * ply [ply], stamped with the current time.
+     */
+    pub fn new(
+        source: ExperienceSource,
+        game_id: Option<&str>,
+        b: &Board,
+        ply: usize,
+        behavior_policy: &str,
+    ) -> ExperienceMeta {
+        return ExperienceMeta {
+            game_id: game_id.map(|id| id.to_string()),
+            ply,
+            phase: board_phase(b, ply),
+            source,
+            behavior_policy: behavior_policy.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            position: Some(b.get_hash()),
+        };
+    }
 }
 
 /**
@@ -36,28 +206,22 @@ fn bitboard_color_piece(b: &Board, piece: Piece, color: Color, player_white: boo
 
 /**
 * [bitboard_to_vec(bitboard)] converts [bitboard] to a 64-length hot vector
-* containing a 1 for each piece and a 0 for each empty square in the bitboard.
+* containing a 1 for each piece and a 0 for each empty square in the bitboard,
+* in square order from a1 to h8.
 */
-fn bitboard_to_vec(bitboard: &BitBoard) -> Vec<f64> {
-    let bitboard_str = bitboard.to_string().replace(" ", "").replace("\n", "");
-    let mut vec = Vec::new();
-    for i in 0..64 {
-        let iter = &bitboard_str[i..i + 1];
-        let dig = if iter == "X" { 1. } else { 0. };
-        vec.push(dig);
-    }
-
-    return vec;
+pub fn bitboard_to_vec(bitboard: &BitBoard) -> Vec<f64> {
+    let bits = bitboard.0;
+    return (0..64).map(|i| ((bits >> i) & 1) as f64).collect();
 }
 
 /**
-* [get_state(b, player_white)] converts the board [b] into a vector state based
-* on whether the player is white. The state is a concatenated vector of 12
-* bitboard representations, the first 6 of which represent the locations of the
-* 6 different pieces for the player and the last 6 of which represent the
-* locations of the 6 different pieces for the opponent.
+* [piece_planes(b, player_white)] converts the pieces of board [b] into 12
+* bitboard representations based on whether the player is white, the first 6
+* of which represent the locations of the 6 different pieces for the player and
+* the last 6 of which represent the locations of the 6 different pieces for the
+* opponent.
 */
-pub fn get_state(b: &Board, player_white: bool) -> Vec<f64> {
+fn piece_planes(b: &Board, player_white: bool) -> Vec<f64> {
     let mut state = Vec::new();
 
     // White state
@@ -150,16 +314,123 @@ pub fn get_state(b: &Board, player_white: bool) -> Vec<f64> {
 }
 
 /**
-* [vec_from_board_square(square_str, player_white)] converts the bitboard with
-* only the square represented by [square_str] into a vector based on whether
-* the player is white. This is used in action representation for representing
-* bitboards of initial and final positions of a piece.
+* [get_state(b, counters, player_white)] converts the board [b], with move
+* counters [counters], into a vector state based on whether the player is
+* white. The state is a concatenated vector of the 12 piece planes given by
+* [piece_planes], followed by a plane holding the pawn that may be captured
+* en passant, if any, the player's and then the opponent's kingside and
+* queenside castling rights, and whether it is the player's turn. With the
+* history_planes feature the board's features are followed by the history
+* features of [get_state_with_history], all 0 here since the board alone
+* does not say how it was reached. The state ends with the move counters
+* given by [move_counters]. Every feature but the counters is 0 or 1, and the
+* counters are normalized by the feature statistics of the network the state
+* is fed to (see [normalization]).
 */
-fn vec_from_board_square(square_str: &str, player_white: bool) -> Vec<f64> {
-    let square = match Square::from_str(square_str) {
-        Ok(sq) => sq,
-        Err(_) => panic!(),
+pub fn get_state(b: &Board, counters: MoveCounters, player_white: bool) -> Vec<f64> {
+    let mut state = piece_planes(b, player_white);
+
+    // En passant plane, flipped like the pieces
+    let mut en_passant = vec![0.; 64];
+    if let Some(square) = b.en_passant() {
+        en_passant = vec_from_square(square, player_white);
+    }
+    state.append(&mut en_passant);
+
+    // Castling rights of the player and then the opponent
+    let (player, opponent) = if player_white {
+        (Color::White, Color::Black)
+    } else {
+        (Color::Black, Color::White)
     };
+    for color in [player, opponent] {
+        let rights = b.castle_rights(color);
+        state.push(if rights.has_kingside() { 1. } else { 0. });
+        state.push(if rights.has_queenside() { 1. } else { 0. });
+    }
+
+    // Side to move
+    state.push(if b.side_to_move() == player { 1. } else { 0. });
+
+    // No history without the game
+    state.resize(state.len() + HISTORY_DIM, 0.);
+    state.extend_from_slice(&move_counters(counters));
+    debug_assert_eq!(state.len(), STATE_DIM);
+    return state;
+}
+
+/**
+* [move_counters(counters)] returns the halfmove clock and the move number of
+* [counters], scaled into [0, 1] by the values they saturate at: the halfmove
+* clock at which a draw can be claimed, and a move number few games reach.
+*/
+fn move_counters(counters: MoveCounters) -> [f64; COUNTER_DIM] {
+    let halfmove_clock = counters.halfmove_clock as f64 / HALFMOVE_CLOCK_SCALE;
+    let move_number = counters.fullmove_number as f64 / MOVE_NUMBER_SCALE;
+    return [halfmove_clock.min(1.), move_number.min(1.)];
+}
+
+/**
+* [get_state_with_history(history, player_white)] converts the latest position
+* of [history] into a vector state based on whether the player is white, as
+* given by [get_state] followed by the piece planes of each of the
+* [HISTORY_POSITIONS] positions before it, most recent first and all 0 for
+* positions before the start of the game, and then whether the position was
+* reached at least once and at least twice before, so the network can tell a
+* repetition from the same position reached for the first time. Without the
+* history_planes feature the board's features are followed by the move
+* counters alone. The state ends with the halfmove clock and the move number
+* given by [move_counters].
+*/
+pub fn get_state_with_history(history: &PositionHistory, player_white: bool) -> Vec<f64> {
+    let board = history.board();
+    let mut state = get_state(&board, history.counters(), player_white);
+    state.truncate(BOARD_DIM);
+    if HISTORY_DIM > 0 {
+        for k in 1..=HISTORY_POSITIONS {
+            match history.earlier_board(k) {
+                Some(b) => state.append(&mut piece_planes(&b, player_white)),
+                None => state.resize(state.len() + PIECE_DIM, 0.),
+            };
+        }
+        let repetitions = history.occurrences(&board) - 1;
+        state.push(if repetitions >= 1 { 1. } else { 0. });
+        state.push(if repetitions >= 2 { 1. } else { 0. });
+    }
+
+    state.extend_from_slice(&move_counters(history.counters()));
+    debug_assert_eq!(state.len(), STATE_DIM);
+    return state;
+}
+
+// The encodings of the positions of a game, so that each is encoded once
+#[derive(Clone, Debug, Default)]
+pub struct StateCache {
+    states: HashMap<(usize, u64, bool), Vec<f64>>, // by ply, position hash and player
+}
+
+impl StateCache {
+    /**
+     * [state(history, player_white)] returns the encoding of the latest
+     * position in [history] as given by [get_state_with_history], encoding
+     * it only if it was not already. The cache holds the positions of a
+     * single game, where the ply and the position's hash identify it.
+     */
+    pub fn state(&mut self, history: &PositionHistory, player_white: bool) -> Vec<f64> {
+        let key = (history.ply(), history.board().get_hash(), player_white);
+        return self
+            .states
+            .entry(key)
+            .or_insert_with(|| get_state_with_history(history, player_white))
+            .clone();
+    }
+}
+
+/**
+* [vec_from_square(square, player_white)] converts the bitboard with only
+* [square] into a vector based on whether the player is white.
+*/
+fn vec_from_square(square: Square, player_white: bool) -> Vec<f64> {
     let square_bitboard = if player_white {
         BitBoard::from_square(square)
     } else {
@@ -170,51 +441,45 @@ fn vec_from_board_square(square_str: &str, player_white: bool) -> Vec<f64> {
 }
 
 /**
-* [get_action(uci_str, player_white)] converts the move represented by the
-* [uci_str] into an action vector based on whether the player is white. The
-* action is a concatenated vector of two bitboard representations, the first of
-* which being the initial position of the moved piece and the second of which
-* being the final position of the moved piece, along with a final 4 dimensional
-* hot vector representing the promoted-to piece if a promotion occured.
+* [get_action(m, player_white)] converts move [m] into an action vector based
+* on whether the player is white. The action is a concatenated vector of two
+* bitboard representations, the first of which being the initial position of
+* the moved piece and the second of which being the final position of the
+* moved piece, along with a final 4 dimensional hot vector representing the
+* promoted-to piece if a promotion occured. A policy head reads the action's
+* index in its output from this vector (see [action_index]).
 */
-pub fn get_action(uci_str: &str, player_white: bool) -> Vec<f64> {
-    // Parse uci string
-    let init_str = &uci_str[0..2];
-    let final_str = &uci_str[2..4];
-    let promote_str = if uci_str.len() > 4 {
-        &uci_str[4..5]
-    } else {
-        ""
-    };
-
-    // Initialize empty action vector
-    let mut action = Vec::new();
-
+pub fn get_action(m: ChessMove, player_white: bool) -> Vec<f64> {
     // Convert initial and final position into vectors
-    let mut init_pos = vec_from_board_square(init_str, player_white);
-    action.append(&mut init_pos);
-    let mut final_pos = vec_from_board_square(final_str, player_white);
-    action.append(&mut final_pos);
-
-    // Handle promotion vector possibilities
-    let mut promotion = Vec::new();
-    if promote_str.eq("") {
-        promotion = vec![0., 0., 0., 0.];
-    } else if promote_str.eq("b") {
-        promotion = vec![1., 0., 0., 0.];
-    } else if promote_str.eq("n") {
-        promotion = vec![0., 1., 0., 0.];
-    } else if promote_str.eq("r") {
-        promotion = vec![0., 0., 1., 0.];
-    } else {
-        // queen
-        promotion = vec![0., 0., 0., 1.];
-    }
+    let mut action = vec_from_square(m.get_source(), player_white);
+    action.append(&mut vec_from_square(m.get_dest(), player_white));
+
+    // Handle promotion vector possibilities, each piece with its own dimension
+    let mut promotion = match m.get_promotion() {
+        Some(Piece::Bishop) => vec![1., 0., 0., 0.],
+        Some(Piece::Knight) => vec![0., 1., 0., 0.],
+        Some(Piece::Rook) => vec![0., 0., 1., 0.],
+        Some(Piece::Queen) => vec![0., 0., 0., 1.],
+        Some(Piece::Pawn) | Some(Piece::King) | None => vec![0., 0., 0., 0.],
+    };
     action.append(&mut promotion);
 
     return action;
 }
 
+/**
+* [parse_action(uci_str, player_white)] converts the move represented by the
+* UCI string [uci_str] into an action vector like [get_action], or returns an
+* error if [uci_str] is not a well-formed move, e.g. when it was received from
+* Lichess or read from a file.
+*/
+pub fn parse_action(uci_str: &str, player_white: bool) -> BotResult<Vec<f64>> {
+    return match ChessMove::from_str(uci_str) {
+        Ok(m) => Ok(get_action(m, player_white)),
+        Err(_) => Err(BotError::InvalidMove(uci_str.to_string())),
+    };
+}
+
 /**
 * [get_reward(b, player_white)] returns the reward of a certain board state
 * depending on whether the player is white. Ongoing games and stalemates give
@@ -229,15 +494,15 @@ pub fn get_reward(b: &Board, player_white: bool) -> f64 {
         BoardStatus::Checkmate => {
             if player_white {
                 if b.side_to_move() == Color::Black {
-                    100.
+                    WIN_REWARD
                 } else {
-                    -100.
+                    LOSS_REWARD
                 }
             } else {
                 if b.side_to_move() == Color::White {
-                    100.
+                    WIN_REWARD
                 } else {
-                    -100.
+                    LOSS_REWARD
                 }
             }
         }
@@ -245,111 +510,746 @@ pub fn get_reward(b: &Board, player_white: bool) -> f64 {
 }
 
 /**
- * [compute_q_max(b, state, q_network, player_white)] computes the predicted max
- * value obtained by the Q function for any coming out of board [b] (with
- * [state] as a vector representation of [b]) depending on whether the player is
- * white. It uses [q_network] to approximate the output.
+ * [compute_q_max(b, state, q_network, player_white, table)] computes the
+ * predicted max value obtained by the Q function for any move coming out of
+ * board [b], encoded as [state], depending on whether the player is white. It
+ * uses [q_network] to approximate the output, reading evaluations through
+ * [table], which must only hold entries of [q_network]. Checkmate and
+ * stalemate are terminal states, worth nothing more.
  */
-fn compute_q_max(
+pub fn compute_q_max<Q: QFunction + ?Sized>(
     b: &Board,
-    state: Vec<f64>,
-    q_network: &mut FeedForward,
+    state: &[f64],
+    q_network: &mut Q,
     player_white: bool,
+    table: &mut TranspositionTable,
 ) -> f64 {
-    // Generate legal moves
-    let legal_moves = MoveGen::new_legal(&b);
-
-    // No more moves, means we are at end state
-    if legal_moves.len() == 0 {
+    if b.status() != BoardStatus::Ongoing {
         return 0.;
     }
 
-    // For each legal move compute the score
-    let mut high_score = f64::NEG_INFINITY;
-    for m in legal_moves {
-        let mut a = get_action(&m.to_string(), player_white);
-        let mut sa = state.clone();
-        sa.append(&mut a);
-
-        let nn_calc = q_network.calc(&sa[..]);
-        let score = nn_calc[0];
-        if score >= high_score {
-            high_score = score;
+    return table
+        .evaluate(b, state, q_network, player_white)
+        .into_iter()
+        .map(|(_, score)| score)
+        .fold(None, |high: Option<f64>, score| match high {
+            Some(h) if h > score => Some(h),
+            _ => Some(score),
+        })
+        .unwrap_or(0.);
+}
+
+/**
+ * [copy_network(nn)] returns a copy of network [nn], activation included.
+ */
+pub fn copy_network(nn: &FeedForward) -> FeedForward {
+    let mut copy = serde_json::from_value(serde_json::to_value(nn).unwrap()).unwrap();
+    restore_activation(&mut copy);
+    return copy;
+}
+
+/**
+ * [blend_weights(target, online, tau)] moves every weight in the serialized
+ * weights [target] a fraction [tau] of the way towards its counterpart in
+ * [online].
+ */
+fn blend_weights(target: &mut Value, online: &Value, tau: f64) {
+    if let (Value::Array(t), Value::Array(o)) = (&mut *target, online) {
+        for (t, o) in t.iter_mut().zip(o) {
+            blend_weights(t, o, tau);
         }
+        return;
+    }
+    if let (Some(t), Some(o)) = (target.as_f64(), online.as_f64()) {
+        *target = json!((1. - tau) * t + tau * o);
+    }
+}
+
+/**
+ * [parse_target_update(settings)] reads how the target network is updated
+ * from the "target_network" [settings].
+ */
+fn parse_target_update(settings: &Value) -> TargetUpdate {
+    match settings["update"].as_str() {
+        Some("hard") => TargetUpdate::Hard {
+            interval: settings["interval"].as_u64().unwrap_or(1).max(1) as usize,
+        },
+        Some("soft") => TargetUpdate::Soft {
+            tau: settings["tau"].as_f64().unwrap_or(0.01).clamp(0., 1.),
+        },
+        Some("pass") | None => TargetUpdate::PerPass,
+        Some(update) => panic!("Unknown target network update {}", update),
     }
-    return high_score;
+}
+
+impl TargetNetwork {
+    /**
+     * [new(network, stats, update, double_dqn)] creates a target network
+     * starting from [network], whose inputs are normalized by [stats], updated
+     * according to [update] and used for Double-DQN targets if [double_dqn].
+     */
+    pub fn new(
+        network: FeedForward,
+        stats: FeatureStats,
+        update: TargetUpdate,
+        double_dqn: bool,
+    ) -> TargetNetwork {
+        return TargetNetwork {
+            policy_head: is_policy_head(&network),
+            network,
+            update,
+            double_dqn,
+            table: TranspositionTable::default(),
+            stats,
+            fits: 0,
+        };
+    }
+
+    /**
+     * [from_config(config, policy_network, stats)] creates a target network
+     * starting as a copy of [policy_network] and its feature [stats], with the
+     * settings given by the parsed [config].
+     */
+    pub fn from_config(
+        config: &Value,
+        policy_network: &FeedForward,
+        stats: FeatureStats,
+    ) -> TargetNetwork {
+        let settings = &config["target_network"];
+        let mut target = TargetNetwork::new(
+            copy_network(policy_network),
+            stats,
+            parse_target_update(settings),
+            settings["double_dqn"].as_bool().unwrap_or(false),
+        );
+        target.table = TranspositionTable::from_config(config);
+        return target;
+    }
+
+    /**
+     * [sync(policy_network, stats)] copies [policy_network] and its feature
+     * [stats] into the target network, forgetting the evaluations of the old
+     * one.
+     */
+    pub fn sync(&mut self, policy_network: &FeedForward, stats: &FeatureStats) {
+        self.network = copy_network(policy_network);
+        self.stats = *stats;
+        self.table.clear();
+        self.fits = 0;
+    }
+
+    /**
+     * [start_pass(policy_network, stats)] syncs the target network with
+     * [policy_network] and its feature [stats] if it is synced at the start of
+     * every learning pass.
+     */
+    pub fn start_pass(&mut self, policy_network: &FeedForward, stats: &FeatureStats) {
+        if self.update == TargetUpdate::PerPass {
+            self.sync(policy_network, stats);
+        }
+    }
+
+    /**
+     * [after_fit(policy_network, stats)] updates the target network towards
+     * [policy_network] and its feature [stats] after it was fit to an
+     * experience, as often and as far as configured.
+     */
+    pub fn after_fit(&mut self, policy_network: &FeedForward, stats: &FeatureStats) {
+        match self.update {
+            TargetUpdate::PerPass => (),
+            TargetUpdate::Hard { interval } => {
+                self.fits += 1;
+                if self.fits >= interval {
+                    self.sync(policy_network, stats);
+                }
+            }
+            TargetUpdate::Soft { tau } => {
+                let mut target = serde_json::to_value(&self.network).unwrap();
+                let online = serde_json::to_value(policy_network).unwrap();
+                if let (Some(t), Some(o)) =
+                    (target["layers"].as_array_mut(), online["layers"].as_array())
+                {
+                    for (t, o) in t.iter_mut().zip(o) {
+                        blend_weights(&mut t["w"], &o["w"], tau);
+                    }
+                }
+                self.network = serde_json::from_value(target).unwrap();
+                restore_activation(&mut self.network);
+                self.stats = *stats;
+                self.table.clear();
+            }
+        };
+    }
+
+    /**
+     * [next_value(policy_network, policy_stats, b, state, player_white)]
+     * returns the value of board [b], encoded as [state], to bootstrap from
+     * depending on whether the player is white: the target network's best
+     * Q-value, or with Double-DQN the target network's Q-value of the move
+     * [policy_network], whose inputs are normalized by [policy_stats], rates
+     * best. Terminal boards are worth nothing more. States hold move
+     * counters, and may hold history planes, that cannot be rebuilt from the
+     * board alone, so moves are scored in [state] itself, with the target
+     * network's scores read through the transposition table. The policy
+     * network changes with every fit, so its scores are not cached.
+     */
+    pub fn next_value(
+        &mut self,
+        policy_network: &mut FeedForward,
+        policy_stats: &FeatureStats,
+        b: &Board,
+        state: &[f64],
+        player_white: bool,
+    ) -> f64 {
+        if b.status() != BoardStatus::Ongoing {
+            return 0.;
+        }
+        let head = NetworkHead::new(&mut self.network, self.policy_head);
+        let mut target = Normalized::new(head, self.stats);
+        let scores = self.table.evaluate(b, state, &mut target, player_white);
+        if !self.double_dqn {
+            return best_scored_move(&scores).map_or(0., |(_, score)| score);
+        }
+        let head = NetworkHead::new(policy_network, self.policy_head);
+        let mut policy = Normalized::new(head, *policy_stats);
+        let policy_scores = score_moves_in_state(&mut policy, b, state, player_white);
+        return match best_scored_move(&policy_scores) {
+            Some((m, _)) => scores.iter().find(|(n, _)| *n == m).map_or(0., |(_, s)| *s),
+            None => 0.,
+        };
+    }
+}
+
+/**
+ * [fit_experience(policy_network, stats, target, e, gamma, scaling)] trains
+ * the policy network, whose inputs are normalized by its feature [stats], on
+ * experience [e], with [target] as the target network that
+ * approximates the Q-function, [gamma] being the discounting factor used in the Bellman
+ * equation and [scaling] relating the networks' outputs to rewards. An action
+ * chosen by a search that kept its value is fit to that value instead, unless
+ * the game ended there. A policy head is only fit on its output for the
+ * action taken. The next state is valued from the perspective of the player
+ * who took the action, who is to move in it again once the reply was played.
+ * The target network is updated afterwards as configured, and the feature
+ * statistics are updated from the experience's state before it is
+ * normalized. Returns the label the policy network was fit to.
+ */
+pub fn fit_experience(
+    policy_network: &mut FeedForward,
+    stats: &mut FeatureStats,
+    target: &mut TargetNetwork,
+    e: &Experience,
+    gamma: f64,
+    scaling: &OutputScaling,
+) -> f64 {
+    return fit_trajectory(
+        policy_network,
+        stats,
+        target,
+        &[e],
+        gamma,
+        scaling,
+        &ReturnTarget::OneStep,
+    );
 }
 
 /**
- * [learn_from_experience(policy_network, q_network, replay_memory, gamma, player_white)]
- * trains the policy network on all experiences in [replay_memory] based on
- * whether the player is white, with [q_network] as the network that
- * approximates the Q-function and [gamma] being the discounting factor used in
- * the Bellman equation.
+ * [fit_trajectory(policy_network, stats, target, trajectory, gamma, scaling,
+ * returns)] trains the policy network on the first experience of
+ * [trajectory] like [fit_experience], but towards the return of [returns]
+ * along the moves of [trajectory], which continue one another in a game of
+ * the same player. Returns the label the policy network was fit to.
+ */
+pub fn fit_trajectory(
+    policy_network: &mut FeedForward,
+    stats: &mut FeatureStats,
+    target: &mut TargetNetwork,
+    trajectory: &[&Experience],
+    gamma: f64,
+    scaling: &OutputScaling,
+    returns: &ReturnTarget,
+) -> f64 {
+    // Build state-action pair
+    let e = trajectory[0];
+    stats.observe(&e.state);
+    let mut sa = e.state.clone();
+    sa.extend_from_slice(&e.action);
+
+    // Learn from training example
+    let bellman_label = trajectory_label(
+        policy_network,
+        stats,
+        target,
+        trajectory,
+        gamma,
+        scaling,
+        returns,
+    );
+    let head = NetworkHead::new(policy_network, target.policy_head);
+    Normalized::new(head, *stats).train_batch(&[sa], &[bellman_label]);
+    target.after_fit(policy_network, stats);
+
+    return bellman_label;
+}
+
+/**
+ * [trajectory_label(policy_network, stats, target, trajectory, gamma, scaling,
+ * returns)] returns the label the first experience of [trajectory] is fit to
+ * by [fit_trajectory], without fitting it.
+ */
+pub fn trajectory_label(
+    policy_network: &mut FeedForward,
+    stats: &FeatureStats,
+    target: &mut TargetNetwork,
+    trajectory: &[&Experience],
+    gamma: f64,
+    scaling: &OutputScaling,
+    returns: &ReturnTarget,
+) -> f64 {
+    // Calculate label from the target network on the next states using the
+    // Bellman equation, anchored to the rewards alone when the game ended
+    // there, even if moves were still legal (e.g. a claimed draw or a loss on
+    // time)
+    let e = trajectory[0];
+    let terminal = |e: &Experience| e.done || e.next_board.status() != BoardStatus::Ongoing;
+    let bellman_label = match e.search_target {
+        Some(value) if !terminal(e) => value,
+        _ => {
+            let steps = match trajectory.iter().position(|e| terminal(e)) {
+                Some(end) => &trajectory[..=end],
+                None => trajectory,
+            };
+            let rewards: Vec<f64> = steps.iter().map(|e| e.reward).collect();
+            let next_value = |k: usize| {
+                let e = steps[k];
+                if terminal(e) {
+                    return None;
+                }
+                let player_white = e.next_board.side_to_move() == Color::White;
+                let output = target.next_value(
+                    policy_network,
+                    stats,
+                    &e.next_board,
+                    &e.next_state,
+                    player_white,
+                );
+                return Some(scaling.unsquash(output));
+            };
+            match returns.discounted(&rewards, next_value, gamma) {
+                (value, true) => scaling.squash(value),
+                (value, false) => scaling.anchor(value),
+            }
+        }
+    };
+    return bellman_label;
+}
+
+// How well the policy network predicted the Bellman labels of a learning
+// pass, before fitting each minibatch
+#[derive(Clone, Debug, Default)]
+pub struct LearnStats {
+    pub loss: f64,          // mean squared error
+    pub mean_td_error: f64, // mean absolute error
+    pub batch_losses: Vec<f64>,
+    pub epoch_losses: Vec<f64>, // mean squared error of each pass over the sample
+}
+
+/**
+ * [learn_from_experience(policy_network, stats, target, replay_memory,
+ * updates, gamma, scaling, returns, rng)] trains the policy network, whose
+ * inputs are normalized by its feature [stats], on a sample of
+ * [updates] distinct experiences drawn with [rng] from [replay_memory], or all
+ * of them if it holds fewer, towards the returns given by [returns] along the
+ * experiences of their games held in [replay_memory], with [target] as the
+ * target network that approximates the Q-function, [gamma] being the
+ * discounting factor used in the Bellman equation and [scaling] relating the
+ * networks' outputs to rewards. The sample is passed over as many times as
+ * the buffer's epochs, shuffled before each pass and fit one minibatch at a
+ * time, the labels of a minibatch all being computed before it is fit. If the
+ * buffer mirrors experiences, the mirror image of each experience is fit to
+ * its label alongside it. The feature statistics are updated from the state
+ * of each experience of the sample once, during the first pass over it. The
+ * target network is synced at the start if it is synced every pass. Returns
+ * the mean squared and absolute errors of the policy network's predictions
+ * against the Bellman labels, before fitting each minibatch, along with the
+ * mean squared error of each minibatch and of each pass.
  */
 pub fn learn_from_experience(
     policy_network: &mut FeedForward,
-    mut q_network: FeedForward,
-    replay_memory: Vec<Experience>,
+    stats: &mut FeatureStats,
+    target: &mut TargetNetwork,
+    replay_memory: &ReplayBuffer,
+    updates: usize,
     gamma: f64,
-    player_white: bool,
-) {
-    for e in replay_memory {
-        // Extract action
-        let mut action = e.action;
+    scaling: &OutputScaling,
+    returns: &ReturnTarget,
+    rng: &mut impl Rng,
+) -> LearnStats {
+    target.start_pass(policy_network, stats);
+    return continue_learning(
+        policy_network,
+        stats,
+        target,
+        replay_memory,
+        updates,
+        gamma,
+        scaling,
+        returns,
+        rng,
+    );
+}
 
-        // Build state-action pair
-        let mut sa = e.state.clone();
-        sa.append(&mut action);
+/**
+ * [continue_learning(policy_network, stats, target, replay_memory, updates,
+ * gamma, scaling, returns, rng)] trains the policy network on a sample of
+ * [replay_memory] as learn_from_experience does, but as part of a pass that
+ * is already under way, so the target network is never synced at the start.
+ */
+pub fn continue_learning(
+    policy_network: &mut FeedForward,
+    feature_stats: &mut FeatureStats,
+    target: &mut TargetNetwork,
+    replay_memory: &ReplayBuffer,
+    updates: usize,
+    gamma: f64,
+    scaling: &OutputScaling,
+    returns: &ReturnTarget,
+    rng: &mut impl Rng,
+) -> LearnStats {
+    let mut count = 0;
+    let mut stats = LearnStats::default();
+    let successors = if returns.horizon() > 1 {
+        replay_memory.successors()
+    } else {
+        Vec::new()
+    };
+    let mut sample = replay_memory.sample_indices(updates, rng);
+    for epoch in 0..replay_memory.epochs {
+        sample.shuffle(rng);
+        let mut epoch_error = 0.;
+        for batch in sample.chunks(replay_memory.batch_size) {
+            let mut batch_error = 0.;
+            let (mut inputs, mut labels) = (Vec::new(), Vec::new());
+            for i in batch {
+                let trajectory = replay_memory.trajectory(*i, &successors, returns.horizon());
+                let e = trajectory[0];
+                if epoch == 0 {
+                    feature_stats.observe(&e.state);
+                }
+                let mut sa = e.state.clone();
+                sa.extend_from_slice(&e.action);
+                let head = NetworkHead::new(policy_network, target.policy_head);
+                let predicted = Normalized::new(head, *feature_stats).predict(&sa[..]);
+                let bellman_label = trajectory_label(
+                    policy_network,
+                    feature_stats,
+                    target,
+                    &trajectory,
+                    gamma,
+                    scaling,
+                    returns,
+                );
+                let error = bellman_label - predicted;
+                batch_error += error.powi(2);
+                stats.mean_td_error += error.abs();
+                count += 1;
 
-        // Calculate label from q network on next state using Bellman equation
-        let bellman_label = e.reward
-            + gamma * compute_q_max(&e.next_board, e.next_state, &mut q_network, player_white);
+                trace!(
+                    "Experience: reward is {}, bellman label is {}",
+                    e.reward,
+                    bellman_label
+                );
+                inputs.push(sa);
+                labels.push(bellman_label);
 
-        // Learn from training example
-        policy_network.fit(&sa[..], &[bellman_label]);
+                // The mirror image of a move is worth the same
+                let mirrored = if replay_memory.mirror {
+                    mirror_experience(e)
+                } else {
+                    None
+                };
+                if let Some(m) = mirrored {
+                    let mut sa = m.state.clone();
+                    sa.extend_from_slice(&m.action);
+                    inputs.push(sa);
+                    labels.push(bellman_label);
+                }
+            }
 
-        println!(
-            "Experience: reward is {}, bellman label is {}",
-            e.reward, bellman_label
-        );
+            // Learn from the whole minibatch at once
+            let head = NetworkHead::new(policy_network, target.policy_head);
+            Normalized::new(head, *feature_stats).train_batch(&inputs, &labels);
+            target.after_fit(policy_network, feature_stats);
+            stats.loss += batch_error;
+            epoch_error += batch_error;
+            stats.batch_losses.push(batch_error / batch.len() as f64);
+        }
+        let epoch_loss = epoch_error / sample.len().max(1) as f64;
+        info!("Epoch {}: mean loss is {}", epoch + 1, epoch_loss);
+        stats.epoch_losses.push(epoch_loss);
+    }
+
+    stats.loss /= count.max(1) as f64;
+    stats.mean_td_error /= count.max(1) as f64;
+    return stats;
+}
+
+/**
+ * [move_by_policy(nn, b, counters, player_white, table)] utilizes the policy
+ * represented by policy network [nn] to return a chess move in board [b],
+ * reached with move [counters], depending on whether the player is white,
+ * reading evaluations through [table], which must only hold entries of [nn].
+ * Only legal moves are scored, so a policy head's outputs for illegal moves
+ * are masked out. Alternatively if there are no legal moves it returns None.
+ */
+pub fn move_by_policy<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+    table: &mut TranspositionTable,
+) -> Option<ChessMove> {
+    let state = get_state(b, counters, player_white);
+    let scores = table.evaluate(b, &state, nn, player_white);
+    for (_, score) in &scores {
+        trace!("{}", score);
     }
+
+    // Pick the best move
+    return best_scored_move(&scores).map(|(m, _)| m);
+}
+
+/**
+ * [best_move_with_score(nn, b, counters, player_white)] returns the move in
+ * board [b], reached with move [counters], with the highest Q-value under
+ * policy network [nn] depending on whether the player is white, along with
+ * that Q-value. Alternatively if there are no legal moves it returns None.
+ */
+pub fn best_move_with_score<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+) -> Option<(ChessMove, f64)> {
+    return best_scored_move(&evaluate_position(b, counters, nn, player_white));
 }
 
 /**
- * [move_by_policy(nn, b, player_white)] utilizes the policy represented by
- * policy network [nn] to return a chess move in board [b] depending on whether
- * the player is white. Alternatively if there are no legal moves it returns
- * None.
+ * [principal_variation(nn, b, counters, player_white, length)] returns the
+ * evaluation of board [b], reached with move [counters], by policy network
+ * [nn] depending on whether the player is white, along with the line of up to
+ * [length] moves expected when both sides keep playing the move [nn] scores
+ * highest from their own perspective. Alternatively if there are no legal
+ * moves it returns None.
  */
-pub fn move_by_policy(nn: &mut FeedForward, b: &Board, player_white: bool) -> Option<ChessMove> {
-    // Generate legal moves
-    let legal_moves = MoveGen::new_legal(&b);
-    if legal_moves.len() == 0 {
-        // If no legal moves, do nothing
-        return None;
+pub fn principal_variation<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+    length: usize,
+) -> Option<(f64, Vec<ChessMove>)> {
+    let (first_move, score) = best_move_with_score(nn, b, counters, player_white)?;
+
+    let mut line = vec![first_move];
+    let mut counters = counters.after(b, first_move);
+    let mut board = b.make_move_new(first_move);
+    let mut perspective_white = !player_white;
+    while line.len() < length {
+        match best_move_with_score(nn, &board, counters, perspective_white) {
+            Some((m, _)) => {
+                line.push(m);
+                counters = counters.after(&board, m);
+                board = board.make_move_new(m);
+                perspective_white = !perspective_white;
+            }
+            None => break,
+        };
     }
 
-    let state = get_state(b, player_white);
-
-    let mut high_score: f64 = f64::NEG_INFINITY;
-    let mut best_move: Option<ChessMove> = None;
-    for possible_move in legal_moves {
-        let mut action = get_action(&possible_move.to_string(), player_white);
-        // Grab sa pair
-        let mut sa = state.clone();
-        sa.append(&mut action);
-
-        // Compute Q-Value from policy
-        let nn_calc = nn.calc(&sa[..]);
-        let score = nn_calc[0];
-        println!("{}", score);
-        if score >= high_score {
-            high_score = score;
-            best_move = Some(possible_move);
+    return Some((score, line));
+}
+
+/**
+ * [move_by_scores_with_bonus(scores, b, bonus)] selects a move in board [b]
+ * from [scores], the Q-values of its legal moves already evaluated (e.g. by
+ * [evaluate_game_position]), adding [bonus(b, m)] to the Q-value of each move
+ * [m] before picking the best one, and returns the decision with the scores
+ * it was made on. Alternatively if there are no legal moves it returns None.
+ */
+pub fn move_by_scores_with_bonus(
+    scores: &[(ChessMove, f64)],
+    b: &Board,
+    bonus: impl Fn(&Board, ChessMove) -> f64,
+) -> Option<MoveDecision> {
+    let scores: Vec<(ChessMove, f64)> = scores
+        .iter()
+        .map(|(m, score)| (*m, score + bonus(b, *m)))
+        .collect();
+    return best_scored_move(&scores)
+        .map(|(m, _)| MoveDecision::from_scores(&scores, m, MoveSource::Policy));
+}
+
+/**
+ * [q_value(nn, b, counters, player_white, m)] returns the Q-value of move [m]
+ * in board [b], reached with move [counters], under policy network [nn]
+ * depending on whether the player is white.
+ */
+pub fn q_value<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+    m: ChessMove,
+) -> f64 {
+    let mut sa = get_state(b, counters, player_white);
+    sa.append(&mut get_action(m, player_white));
+    return nn.predict(&sa[..]);
+}
+
+/**
+ * [score_moves(nn, b, counters, player_white)] returns every legal move in
+ * board [b], reached with move [counters], with its Q-value under policy
+ * network [nn] from the perspective of the player given by [player_white].
+ * The state is encoded once, and every move is scored in a single batch.
+ */
+pub fn score_moves<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+) -> Vec<(ChessMove, f64)> {
+    let state = get_state(b, counters, player_white);
+    return score_moves_in_state(nn, b, &state, player_white);
+}
+
+/**
+ * [score_moves_in_state(nn, b, state, player_white)] returns every legal move
+ * in board [b] with its Q-value under policy network [nn] in [state], the
+ * encoding of [b] from the perspective of the player given by [player_white],
+ * with every move scored in a single batch.
+ */
+pub fn score_moves_in_state<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    state: &[f64],
+    player_white: bool,
+) -> Vec<(ChessMove, f64)> {
+    let moves: Vec<ChessMove> = MoveGen::new_legal(b).collect();
+    let inputs: Vec<Vec<f64>> = moves
+        .iter()
+        .map(|m| state_action(state, *m, player_white))
+        .collect();
+
+    return moves.into_iter().zip(nn.predict_batch(&inputs)).collect();
+}
+
+/**
+ * [score_moves_until(nn, b, state, player_white, deadline)] returns the legal
+ * moves in board [b] with their Q-values like [score_moves_in_state], but
+ * scored a few moves per batch, stopping once [deadline] passes with at least
+ * one batch scored, so that a slow network cannot hold up the move past it.
+ */
+pub fn score_moves_until<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    state: &[f64],
+    player_white: bool,
+    deadline: Instant,
+) -> Vec<(ChessMove, f64)> {
+    let moves: Vec<ChessMove> = MoveGen::new_legal(b).collect();
+    let mut scores = Vec::with_capacity(moves.len());
+    for batch in moves.chunks(DEADLINE_BATCH_SIZE) {
+        if !scores.is_empty() && Instant::now() >= deadline {
+            warn!(
+                "Move scoring timed out after {} of {} moves",
+                scores.len(),
+                moves.len()
+            );
+            break;
         }
+        let inputs: Vec<Vec<f64>> = batch
+            .iter()
+            .map(|m| state_action(state, *m, player_white))
+            .collect();
+        scores.extend(batch.iter().copied().zip(nn.predict_batch(&inputs)));
     }
 
-    // Pick the best move
-    return best_move;
+    return scores;
+}
+
+/**
+ * [state_action(state, m, player_white)] returns the state-action pair of
+ * move [m] in [state] depending on whether the player is white.
+ */
+fn state_action(state: &[f64], m: ChessMove, player_white: bool) -> Vec<f64> {
+    let mut sa = Vec::with_capacity(STATE_DIM + ACTION_DIM);
+    sa.extend_from_slice(state);
+    sa.append(&mut get_action(m, player_white));
+    return sa;
+}
+
+/**
+ * [evaluate_position(b, counters, nn, player_white)] returns every legal move
+ * in board [b], reached with move [counters], with its Q-value under policy
+ * network [nn] from the perspective of the player given by [player_white], as
+ * given by [score_moves]. This is the one place moves are scored by a
+ * network, so every caller evaluates positions the same way.
+ */
+pub fn evaluate_position<Q: QFunction + ?Sized>(
+    b: &Board,
+    counters: MoveCounters,
+    nn: &mut Q,
+    player_white: bool,
+) -> Vec<(ChessMove, f64)> {
+    return score_moves(nn, b, counters, player_white);
+}
+
+/**
+ * [evaluate_game_position(history, nn, player_white)] returns every legal
+ * move in the latest position of [history] with its Q-value under policy
+ * network [nn] from the perspective of the player given by [player_white],
+ * like [evaluate_position] but with the state encoded along with the
+ * positions before it by [get_state_with_history].
+ */
+pub fn evaluate_game_position<Q: QFunction + ?Sized>(
+    history: &PositionHistory,
+    nn: &mut Q,
+    player_white: bool,
+) -> Vec<(ChessMove, f64)> {
+    let state = get_state_with_history(history, player_white);
+    return score_moves_in_state(nn, &history.board(), &state, player_white);
+}
+
+/**
+ * [evaluate_game_position_until(history, nn, player_white, deadline)] returns
+ * the legal moves in the latest position of [history] with their Q-values
+ * like [evaluate_game_position], but only those scored before [deadline]
+ * passes (see [score_moves_until]).
+ */
+pub fn evaluate_game_position_until<Q: QFunction + ?Sized>(
+    history: &PositionHistory,
+    nn: &mut Q,
+    player_white: bool,
+    deadline: Instant,
+) -> Vec<(ChessMove, f64)> {
+    let state = get_state_with_history(history, player_white);
+    return score_moves_until(nn, &history.board(), &state, player_white, deadline);
+}
+
+/**
+ * [best_scored_move(scores)] returns the move with the highest score in
+ * [scores] along with its score, preferring the last of any tied moves, or
+ * None if there are no moves.
+ */
+pub fn best_scored_move(scores: &[(ChessMove, f64)]) -> Option<(ChessMove, f64)> {
+    let mut best: Option<(ChessMove, f64)> = None;
+    for (m, score) in scores {
+        match best {
+            Some((_, high_score)) if high_score > *score => (),
+            _ => best = Some((*m, *score)),
+        };
+    }
+
+    return best;
 }
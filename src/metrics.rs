@@ -0,0 +1,262 @@
+/**
+ * Utility module for logging training metrics so that learning curves can be
+ * plotted. Each self-play game is recorded with its total reward, its length
+ * in plies, the mean TD error of learning from it, the fraction of the
+ * learner's moves chosen by its policy rather than at random and the size of
+ * the replay buffer, and each minibatch learned from with its loss. They are
+ * written as CSV files, games.csv and batches.csv, to the directory given by
+ * the "metrics" object in config.json, e.g. {"dir": "metrics",
+ * "tensorboard": true}, where "tensorboard" also writes them as scalars to a
+ * TensorBoard event file in the same directory. Nothing is written without
+ * the object.
+ */
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// The columns of the CSV files of games and of minibatches
+const GAME_COLUMNS: &str =
+    "game,opponent,result,total_reward,plies,mean_td_error,policy_fraction,buffer_size,loss";
+const BATCH_COLUMNS: &str = "step,game,loss";
+
+// The metrics of a self-play game and of learning from it
+#[derive(Clone, Debug, Default)]
+pub struct GameMetrics {
+    pub game: usize, // counting from 1
+    pub opponent: String,
+    pub result: f64, // 1 for a win, 0.5 for a draw and 0 for a loss
+    pub total_reward: f64,
+    pub plies: usize,
+    pub mean_td_error: f64,
+    pub policy_fraction: f64, // of the learner's moves, the rest being random
+    pub buffer_size: usize,
+    pub loss: f64,
+}
+
+// Where the metrics of a run are written, if anywhere
+pub struct MetricsLog {
+    pub dir: Option<String>,
+    events: Option<File>,
+    batch_step: usize,
+}
+
+/**
+ * [crc32c(data)] returns the CRC-32C checksum of [data].
+ */
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    return !crc;
+}
+
+/**
+ * [masked_crc(data)] returns the masked CRC-32C checksum of [data] that
+ * TensorBoard records are framed with.
+ */
+fn masked_crc(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    return ((crc >> 15) | (crc << 17)).wrapping_add(0xa282ead8);
+}
+
+/**
+ * [push_varint(buf, n)] appends [n] to [buf] as a protobuf varint.
+ */
+fn push_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/**
+ * [push_bytes(buf, field, bytes)] appends [bytes] to [buf] as the
+ * length-delimited protobuf field number [field].
+ */
+fn push_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    push_varint(buf, field << 3 | 2);
+    push_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/**
+ * [event(step, summary)] encodes a TensorBoard Event at [step], holding the
+ * encoded Summary [summary] or, if None, the file version.
+ */
+fn event(step: usize, summary: Option<&[u8]>) -> Vec<u8> {
+    let wall_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0., |d| d.as_secs_f64());
+    let mut buf = vec![1 << 3 | 1];
+    buf.extend_from_slice(&wall_time.to_le_bytes());
+    push_varint(&mut buf, 2 << 3);
+    push_varint(&mut buf, step as u64);
+    match summary {
+        Some(summary) => push_bytes(&mut buf, 5, summary),
+        None => push_bytes(&mut buf, 3, b"brain.Event:2"),
+    };
+    return buf;
+}
+
+/**
+ * [scalar_summary(scalars)] encodes a TensorBoard Summary of the named
+ * [scalars].
+ */
+fn scalar_summary(scalars: &[(&str, f64)]) -> Vec<u8> {
+    let mut summary = Vec::new();
+    for (tag, value) in scalars {
+        let mut v = Vec::new();
+        push_bytes(&mut v, 1, tag.as_bytes());
+        v.push(2 << 3 | 5);
+        v.extend_from_slice(&(*value as f32).to_le_bytes());
+        push_bytes(&mut summary, 1, &v);
+    }
+    return summary;
+}
+
+/**
+ * [append_csv(path, columns, row)] appends [row] to the CSV file at [path],
+ * starting it with the header [columns] if it is new.
+ */
+fn append_csv(path: &str, columns: &str, row: &str) -> io::Result<()> {
+    let new = !Path::new(path).exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if new {
+        writeln!(file, "{}", columns)?;
+    }
+    writeln!(file, "{}", row)?;
+    return Ok(());
+}
+
+impl MetricsLog {
+    /**
+     * [from_config(config)] opens the metrics log given by the parsed
+     * [config], starting a new TensorBoard event file if configured.
+     * Minibatches are numbered after those already in the log.
+     */
+    pub fn from_config(config: &Value) -> MetricsLog {
+        let settings = &config["metrics"];
+        let dir = match settings["dir"].as_str() {
+            Some(dir) => dir.to_string(),
+            None => {
+                return MetricsLog {
+                    dir: None,
+                    events: None,
+                    batch_step: 0,
+                }
+            }
+        };
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("Unable to create metrics directory {}: {}", dir, e);
+        }
+
+        let batch_step = fs::read_to_string(format!("{}/batches.csv", dir))
+            .map_or(0, |s| s.lines().count().saturating_sub(1));
+        let mut log = MetricsLog {
+            dir: Some(dir.clone()),
+            events: None,
+            batch_step,
+        };
+        if settings["tensorboard"].as_bool().unwrap_or(false) {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let path = format!("{}/events.out.tfevents.{}.chessbot", dir, secs);
+            match File::create(&path) {
+                Ok(file) => log.events = Some(file),
+                Err(e) => warn!("Unable to create event file {}: {}", path, e),
+            };
+            log.write_event(&event(0, None));
+        }
+        return log;
+    }
+
+    /**
+     * [write_event(data)] appends the encoded event [data] to the event file
+     * as a TFRecord, closing the file if it cannot be written.
+     */
+    fn write_event(&mut self, data: &[u8]) {
+        let file = match self.events.as_mut() {
+            Some(f) => f,
+            None => return,
+        };
+        let len = (data.len() as u64).to_le_bytes();
+        let mut record = Vec::with_capacity(data.len() + 16);
+        record.extend_from_slice(&len);
+        record.extend_from_slice(&masked_crc(&len).to_le_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&masked_crc(data).to_le_bytes());
+        if let Err(e) = file.write_all(&record) {
+            warn!("Unable to write TensorBoard event: {}", e);
+            self.events = None;
+        }
+    }
+
+    /**
+     * [record_game(metrics)] logs the [metrics] of a game.
+     */
+    pub fn record_game(&mut self, metrics: &GameMetrics) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let row = format!(
+            "{},{},{},{},{},{},{},{},{}",
+            metrics.game,
+            metrics.opponent.replace(',', " "),
+            metrics.result,
+            metrics.total_reward,
+            metrics.plies,
+            metrics.mean_td_error,
+            metrics.policy_fraction,
+            metrics.buffer_size,
+            metrics.loss
+        );
+        if let Err(e) = append_csv(&format!("{}/games.csv", dir), GAME_COLUMNS, &row) {
+            warn!("Unable to record game metrics: {}", e);
+        }
+
+        let summary = scalar_summary(&[
+            ("game/result", metrics.result),
+            ("game/total_reward", metrics.total_reward),
+            ("game/plies", metrics.plies as f64),
+            ("game/mean_td_error", metrics.mean_td_error),
+            ("game/policy_fraction", metrics.policy_fraction),
+            ("game/buffer_size", metrics.buffer_size as f64),
+            ("game/loss", metrics.loss),
+        ]);
+        self.write_event(&event(metrics.game, Some(&summary)));
+    }
+
+    /**
+     * [record_batches(game, losses)] logs the [losses] of the minibatches
+     * learned from after game number [game], in order.
+     */
+    pub fn record_batches(&mut self, game: usize, losses: &[f64]) {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        for loss in losses {
+            self.batch_step += 1;
+            let row = format!("{},{},{}", self.batch_step, game, loss);
+            if let Err(e) = append_csv(&format!("{}/batches.csv", dir), BATCH_COLUMNS, &row) {
+                warn!("Unable to record batch metrics: {}", e);
+            }
+            let summary = scalar_summary(&[("batch/loss", *loss)]);
+            self.write_event(&event(self.batch_step, Some(&summary)));
+        }
+    }
+}
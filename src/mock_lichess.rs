@@ -0,0 +1,453 @@
+/**
+ * Utility module for checking the Lichess game loop end to end against a mock
+ * Lichess server, so that changes to the networking path are caught before
+ * they meet Lichess. The server speaks just enough HTTP to serve the event
+ * and game streams as NDJSON and to accept moves, and plays a scripted
+ * opponent that mates whenever it can and otherwise plays its first legal
+ * move. Each scenario ends the game on time after a set number of moves by
+ * the bot unless it ends on the board first, and checks the game start, the
+ * moves the bot posted and the experiences and rewards it collected. The
+ * scenarios are run by the tests in tests/mock_lichess.rs.
+ */
+use crate::daemon::poll_game_start;
+use crate::error::BotResult;
+use crate::eval::EvalWeights;
+use crate::game_loop::play_game;
+use crate::history::MoveCounters;
+use crate::history::PositionHistory;
+use crate::idle_learning::TurnSignal;
+use crate::lichess::LichessClient;
+use crate::mdp::{get_reward, get_state, parse_action, BOARD_DIM, WIN_REWARD};
+use crate::models::ModelRegistry;
+use crate::reward::RewardShaping;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen};
+use serde_json::{json, Value};
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+const MOCK_GAME_ID: &str = "mockgame1";
+const MOCK_AUTH_TOKEN: &str = "mock-token";
+
+// Clock each side starts the mock game with, in milliseconds
+const MOCK_CLOCK_MS: u64 = 60000;
+
+// A scripted game against the mock server
+#[derive(Clone, Copy, Debug)]
+pub struct MockScenario {
+    pub name: &'static str,
+    pub bot_white: bool,
+    pub initial_fen: Option<&'static str>,
+    pub bot_moves: usize,  // moves by the bot before the game ends on time
+    pub bot_flagged: bool, // whether the bot rather than the opponent flags
+}
+
+// The state of the game served by the mock server
+pub struct MockGame {
+    pub scenario: MockScenario,
+    pub history: PositionHistory,
+    pub posted: Vec<String>,    // moves of the bot the server accepted
+    pub rejected: Vec<String>,  // moves of the bot the server rejected
+    pub positions: Vec<Board>,  // positions the bot moved in
+    pub status: String,         // Lichess game status, e.g. "started" or "mate"
+    pub winner: Option<String>, // color of the winner, if any
+}
+
+/**
+ * [color_name(white)] returns the Lichess name of the color that is white or
+ * not.
+ */
+fn color_name(white: bool) -> &'static str {
+    return if white { "white" } else { "black" };
+}
+
+/**
+ * [opponent_move(b)] returns the mock opponent's move in board [b]: a mating
+ * move if there is one and otherwise the first legal move.
+ */
+fn opponent_move(b: &Board) -> Option<ChessMove> {
+    let mut moves = MoveGen::new_legal(b);
+    let first = moves.next()?;
+    if b.make_move_new(first).status() == BoardStatus::Checkmate {
+        return Some(first);
+    }
+    return Some(
+        moves
+            .find(|m| b.make_move_new(*m).status() == BoardStatus::Checkmate)
+            .unwrap_or(first),
+    );
+}
+
+impl MockGame {
+    /**
+     * [new(scenario)] starts the mock game of [scenario], in which the
+     * opponent has already moved if it is to move first.
+     */
+    pub fn new(scenario: MockScenario) -> MockGame {
+        let initial = match scenario.initial_fen {
+            Some(fen) => Board::from_str(fen).expect("Invalid scenario FEN"),
+            None => Board::default(),
+        };
+        let mut game = MockGame {
+            scenario,
+            history: PositionHistory::new(&initial),
+            posted: Vec::new(),
+            rejected: Vec::new(),
+            positions: Vec::new(),
+            status: "started".to_string(),
+            winner: None,
+        };
+        if !game.bot_to_move() {
+            game.play_opponent();
+        }
+
+        return game;
+    }
+
+    /**
+     * [bot_to_move()] returns whether it is the bot's turn.
+     */
+    fn bot_to_move(&self) -> bool {
+        let white_to_move = self.history.board().side_to_move() == Color::White;
+        return white_to_move == self.scenario.bot_white;
+    }
+
+    /**
+     * [over()] returns whether the game has ended.
+     */
+    pub fn over(&self) -> bool {
+        return !self.status.eq("started");
+    }
+
+    /**
+     * [update_status()] ends the game if the latest position ended it.
+     */
+    fn update_status(&mut self) {
+        let board = self.history.board();
+        match board.status() {
+            BoardStatus::Checkmate => {
+                self.status = "mate".to_string();
+                let winner = board.side_to_move() == Color::Black;
+                self.winner = Some(color_name(winner).to_string());
+            }
+            BoardStatus::Stalemate => self.status = "stalemate".to_string(),
+            BoardStatus::Ongoing if self.history.can_declare_draw() => {
+                self.status = "draw".to_string();
+            }
+            BoardStatus::Ongoing => (),
+        };
+    }
+
+    /**
+     * [play_opponent()] plays the opponent's move unless the game is over.
+     */
+    fn play_opponent(&mut self) {
+        if self.over() {
+            return;
+        }
+        if let Some(m) = opponent_move(&self.history.board()) {
+            self.history.make_move(m);
+        }
+        self.update_status();
+    }
+
+    /**
+     * [flag(bot)] ends the game on time, lost by the bot if [bot] is true and
+     * by the opponent otherwise.
+     */
+    fn flag(&mut self, bot: bool) {
+        self.status = "outoftime".to_string();
+        let winner_white = self.scenario.bot_white != bot;
+        self.winner = Some(color_name(winner_white).to_string());
+    }
+
+    /**
+     * [play_bot_move(uci)] plays the bot's move [uci] followed by the
+     * opponent's reply, returning whether the move was accepted.
+     */
+    fn play_bot_move(&mut self, uci: &str) -> bool {
+        let board = self.history.board();
+        let m = match ChessMove::from_str(uci) {
+            Ok(m) if !self.over() && self.bot_to_move() && board.legal(m) => m,
+            _ => {
+                self.rejected.push(uci.to_string());
+                return false;
+            }
+        };
+        self.positions.push(board);
+        self.posted.push(uci.to_string());
+        self.history.make_move(m);
+        self.update_status();
+
+        // The game ends on time once the bot has played all its moves, either
+        // on the opponent's clock or on the bot's after the reply
+        if self.posted.len() >= self.scenario.bot_moves && !self.scenario.bot_flagged {
+            if !self.over() {
+                self.flag(false);
+            }
+            return true;
+        }
+        self.play_opponent();
+        if self.posted.len() >= self.scenario.bot_moves && !self.over() {
+            self.flag(true);
+        }
+
+        return true;
+    }
+
+    /**
+     * [game_full()] returns the full state of the game as sent first on the
+     * game stream.
+     */
+    fn game_full(&self) -> Value {
+        let (bot, opponent) = (
+            json!({"id": "bot", "rating": 1500}),
+            json!({"id": "mock-opponent", "rating": 1500}),
+        );
+        let (white, black) = if self.scenario.bot_white {
+            (bot, opponent)
+        } else {
+            (opponent, bot)
+        };
+        let moves: Vec<String> = self.history.moves().iter().map(|m| m.to_string()).collect();
+
+        return json!({
+            "type": "gameFull",
+            "id": MOCK_GAME_ID,
+            "rated": false,
+            "initialFen": self.scenario.initial_fen.unwrap_or("startpos"),
+            "white": white,
+            "black": black,
+            "clock": {"initial": MOCK_CLOCK_MS, "increment": 0},
+            "state": {
+                "type": "gameState",
+                "moves": moves.join(" "),
+                "wtime": MOCK_CLOCK_MS,
+                "btime": MOCK_CLOCK_MS,
+                "winc": 0,
+                "binc": 0,
+                "status": self.status,
+                "winner": self.winner,
+            },
+        });
+    }
+
+    /**
+     * [respond(method, path)] handles a request with [method] for [path],
+     * returning the status code and body of the response.
+     */
+    pub fn respond(&mut self, method: &str, path: &str) -> (u16, String) {
+        let ok = json!({"ok": true}).to_string();
+        if method.eq("GET") && path.eq("/api/stream/event") {
+            // A finished game only sends keep-alive newlines
+            if self.over() {
+                return (200, "\n".to_string());
+            }
+            let event = json!({
+                "type": "gameStart",
+                "game": {
+                    "id": MOCK_GAME_ID,
+                    "color": color_name(self.scenario.bot_white),
+                    "isMyTurn": self.bot_to_move(),
+                },
+            });
+            return (200, event.to_string() + "\n");
+        }
+
+        let parts: Vec<&str> = path
+            .trim_start_matches("/api/bot/game/")
+            .split('/')
+            .collect();
+        match (method, parts.as_slice()) {
+            ("GET", ["stream", id]) if id.eq(&MOCK_GAME_ID) => {
+                (200, self.game_full().to_string() + "\n")
+            }
+            ("POST", [id, "move", uci]) if id.eq(&MOCK_GAME_ID) => {
+                if self.play_bot_move(uci) {
+                    (200, ok)
+                } else {
+                    let error = json!({"error": "Not your turn, or game already over"});
+                    (400, error.to_string())
+                }
+            }
+            ("POST", [id, "draw", _])
+            | ("POST", [id, "takeback", "no"])
+            | ("POST", [id, "claim-victory"])
+            | ("POST", [id, "resign"])
+                if id.eq(&MOCK_GAME_ID) =>
+            {
+                (200, ok)
+            }
+            ("POST", [id, "chat"]) if id.eq(&MOCK_GAME_ID) => (200, ok),
+            _ => (404, json!({"error": "Not found"}).to_string()),
+        }
+    }
+}
+
+/**
+ * [handle_connection(stream, game)] answers the request read from [stream]
+ * with the state of [game].
+ */
+async fn handle_connection(mut stream: TcpStream, game: Arc<Mutex<MockGame>>) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let text = String::from_utf8_lossy(&request).to_string();
+    let mut request_line = text.split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+    let (status, body) = game.lock().unwrap().respond(method, path);
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        _ => "Not Found",
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    return stream.shutdown().await;
+}
+
+/**
+ * [serve(scenario)] starts a mock server playing [scenario] on a free local
+ * port, returning its url along with the game it serves.
+ */
+pub async fn serve(scenario: MockScenario) -> io::Result<(String, Arc<Mutex<MockGame>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let game = Arc::new(Mutex::new(MockGame::new(scenario)));
+
+    let served = game.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let game = served.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, game).await {
+                    warn!("Mock server connection failed: {}", e);
+                }
+            });
+        }
+    });
+
+    return Ok((url, game));
+}
+
+/**
+ * [run_scenario(client, config, scenario)] plays [scenario] against a mock
+ * server with the bot given by the parsed [config], returning a description
+ * of every check that failed. The game database is kept in memory and moves
+ * are not logged, so the run leaves no trace.
+ */
+pub async fn run_scenario(
+    client: &reqwest::Client,
+    config: &Value,
+    scenario: MockScenario,
+) -> BotResult<Vec<String>> {
+    let (url, game) = serve(scenario).await.expect("Unable to start mock server");
+    let mut config = config.clone();
+    config["lichess"]["url"] = json!(url);
+    config["database"] = json!(":memory:");
+    config["move_log"] = Value::Null;
+    config["pgn"] = Value::Null;
+    config["tablebase"] = Value::Null;
+    config["broadcast"] = Value::Null;
+    let shaping = RewardShaping::from_config(&config);
+    let eval_weights = EvalWeights::from_config(&config);
+    let mut failures = Vec::new();
+
+    // The bot finds the game on the event stream and plays it out
+    let lichess = LichessClient::from_config(client, MOCK_AUTH_TOKEN, &config);
+    let started = poll_game_start(&lichess, &mut lichess.event_stream(), &None, false).await?;
+    if started.as_deref() != Some(MOCK_GAME_ID) {
+        failures.push(format!("game start was not detected ({:?})", started));
+    }
+    let mut models = ModelRegistry::from_config(&config)?;
+    let (experiences, player_white) = play_game(
+        &lichess,
+        &config,
+        MOCK_GAME_ID,
+        &mut models,
+        &TurnSignal::default(),
+    )
+    .await?;
+
+    let game = game.lock().unwrap();
+    if !game.over() {
+        failures.push("game did not end".to_string());
+    }
+    if player_white != scenario.bot_white {
+        failures.push(format!("bot played the wrong color ({})", player_white));
+    }
+    if game.rejected.len() > 0 {
+        failures.push(format!("moves were rejected: {:?}", game.rejected));
+    }
+    if experiences.len() != game.posted.len() {
+        failures.push(format!(
+            "collected {} experiences for {} moves",
+            experiences.len(),
+            game.posted.len()
+        ));
+    }
+
+    // Each experience is the bot's move from the position it moved in, with
+    // no reward until the last one but for material changes. Only the board's
+    // own features are compared, since history planes depend on the game
+    for (i, e) in experiences.iter().enumerate() {
+        let (position, uci) = match (game.positions.get(i), game.posted.get(i)) {
+            (Some(b), Some(uci)) => (b, uci),
+            _ => break,
+        };
+        if e.state[..BOARD_DIM]
+            != get_state(position, MoveCounters::default(), player_white)[..BOARD_DIM]
+        {
+            failures.push(format!("experience {} has the wrong state", i + 1));
+        }
+        if parse_action(uci, player_white).map_or(true, |a| a != e.action) {
+            failures.push(format!("experience {} has the wrong action", i + 1));
+        }
+        let raw_reward = if i + 1 < experiences.len() {
+            0.
+        } else if game.status.eq("outoftime") {
+            if game.winner.as_deref() == Some(color_name(scenario.bot_white)) {
+                WIN_REWARD
+            } else {
+                shaping.time_loss
+            }
+        } else {
+            get_reward(&game.history.board(), player_white)
+        };
+        let next = match game.positions.get(i + 1) {
+            Some(b) => *b,
+            None => game.history.board(),
+        };
+        let expected = shaping.shape(raw_reward, i + 1)
+            + shaping.material(position, &next, player_white, &eval_weights);
+        if (e.reward - expected).abs() > 1e-9 {
+            failures.push(format!(
+                "experience {} has reward {} instead of {}",
+                i + 1,
+                e.reward,
+                expected
+            ));
+        }
+    }
+
+    return Ok(failures);
+}
@@ -0,0 +1,465 @@
+/**
+ * Utility module for building fresh policy networks with the architecture
+ * given by the "model" object in config.json, e.g. {"hidden": [128, 64],
+ * "activation": "sigmoid", "learning_rate": 0.01, "momentum": 0.1}, which
+ * defaults to a single hidden layer of 64 tanh units with neuroflow's default
+ * learning rate and momentum. The hidden layers use tanh or sigmoid, the only
+ * activations neuroflow offers. The input dimension always follows the current
+ * state and action encoding. With "head": "policy" the network instead takes
+ * only the state and outputs a Q-value for every index of the action space,
+ * scoring all moves in one pass (see policy_head), and with "head": "dueling"
+ * its outputs are split into a state value and an advantage for every action
+ * index, combined into the Q-values (see dueling). The architecture of a
+ * network, including its head, is recorded in its metadata when it is
+ * created, and networks are checked against it and against the encoding when
+ * loaded, so that a network of the wrong shape fails loudly instead of
+ * playing garbage.
+ */
+use crate::action_space::ACTION_SPACE;
+use crate::checkpoint::{read_metadata, write_metadata, CheckpointMetadata};
+use crate::dueling::DUELING_OUTPUTS;
+use crate::error::{BotError, BotResult};
+use crate::mdp::STATE_DIM;
+use crate::models::write_network;
+use crate::normalization::{write_stats, FeatureStats};
+use crate::weights::{Activation, NetworkWeights};
+use crate::INPUT_DIM;
+
+use neuroflow::activators::Type;
+use neuroflow::FeedForward;
+use serde_json::{json, Value};
+
+// Default hidden layer sizes and activation of a fresh network
+const DEFAULT_HIDDEN: usize = 64;
+const DEFAULT_ACTIVATION: &str = "tanh";
+
+// The shape of a network: its number of inputs, the size of each hidden layer,
+// the activation of the hidden layers and its number of linear outputs, 1 for
+// a network scoring state-action pairs. The kind of head follows from the
+// number of outputs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Architecture {
+    pub input_dim: usize,
+    pub hidden: Vec<usize>,
+    pub activation: String,
+    pub output_dim: usize,
+}
+
+// Builds fresh networks of a configurable architecture
+#[derive(Clone, Debug)]
+pub struct ModelBuilder {
+    pub hidden: Vec<usize>,
+    pub activation: String,
+    pub learning_rate: Option<f64>,
+    pub momentum: Option<f64>,
+    pub policy_head: bool,
+    pub dueling: bool, // a policy head split into value and advantage streams
+}
+
+/**
+ * [parse_activation(name)] converts the activation name [name] into the
+ * neuroflow activation it names, or returns an error if neuroflow has no such
+ * activation.
+ */
+fn parse_activation(name: &str) -> BotResult<Type> {
+    return match name {
+        "tanh" => Ok(Type::Tanh),
+        "sigmoid" => Ok(Type::Sigmoid),
+        _ => Err(BotError::Config(format!(
+            "unsupported activation {}, neuroflow only offers tanh and sigmoid",
+            name
+        ))),
+    };
+}
+
+/**
+ * [set_activation(nn, name)] makes the activation named [name] that of the
+ * hidden layers of network [nn], or returns an error if neuroflow has no such
+ * activation. neuroflow's own setter only swaps the function, which is not
+ * saved, so the activation is also recorded in the network's weights, where
+ * [restore_activation] reads it back.
+ */
+pub fn set_activation(nn: &mut FeedForward, name: &str) -> BotResult<()> {
+    let activation = parse_activation(name)?;
+    let mut json = serde_json::to_value(&*nn)?;
+    json["act_type"] = json!(match activation {
+        Type::Sigmoid => "Sigmoid",
+        Type::Tanh => "Tanh",
+    });
+    *nn = serde_json::from_value(json)?;
+    nn.activation(activation);
+    return Ok(());
+}
+
+/**
+ * [restore_activation(nn)] makes the activation recorded in the weights of
+ * network [nn] that of its hidden layers again, since a network that was
+ * loaded or copied always starts out with tanh.
+ */
+pub fn restore_activation(nn: &mut FeedForward) {
+    let json = serde_json::to_value(&*nn).unwrap();
+    if json["act_type"].as_str() == Some("Sigmoid") {
+        nn.activation(Type::Sigmoid);
+    }
+}
+
+/**
+ * [activation_name(activation)] converts [activation] into its name.
+ */
+pub fn activation_name(activation: Activation) -> &'static str {
+    match activation {
+        Activation::Sigmoid => "sigmoid",
+        Activation::Tanh => "tanh",
+        Activation::Linear => "linear",
+    }
+}
+
+/**
+ * [check_network(nn, path)] checks that network [nn], loaded from [path],
+ * takes the inputs of the current state and action encoding, or of the state
+ * alone for a policy head with an output for every action index, and has the
+ * architecture recorded in its metadata, if any.
+ */
+pub fn check_network(nn: &FeedForward, path: &str) -> BotResult<()> {
+    let actual = Architecture::of(nn);
+    let expected = match actual.output_dim {
+        1 => INPUT_DIM as usize,
+        ACTION_SPACE | DUELING_OUTPUTS => STATE_DIM,
+        n => {
+            return Err(BotError::Config(format!(
+                "network at {} has {} outputs, neither 1 nor one per action (and a value)",
+                path, n
+            )))
+        }
+    };
+    if actual.input_dim != expected {
+        return Err(BotError::Config(format!(
+            "network at {} takes {} inputs but the encoding has {}",
+            path, actual.input_dim, expected
+        )));
+    }
+    if let Some(recorded) = read_metadata(path).architecture {
+        if recorded != actual {
+            return Err(BotError::Config(format!(
+                "network at {} is {} but its metadata records {}",
+                path,
+                actual.summary(),
+                recorded.summary()
+            )));
+        }
+    }
+    return Ok(());
+}
+
+impl Architecture {
+    /**
+     * [of(nn)] reads the architecture of network [nn] from its weights.
+     */
+    pub fn of(nn: &FeedForward) -> Architecture {
+        let weights = NetworkWeights::from_network(nn);
+        let layers = &weights.layers;
+        let hidden = &layers[..layers.len().saturating_sub(1)];
+        return Architecture {
+            input_dim: layers
+                .first()
+                .and_then(|l| l.weights.first())
+                .map_or(0, |w| w.len()),
+            hidden: hidden.iter().map(|l| l.weights.len()).collect(),
+            activation: hidden
+                .first()
+                .map_or(DEFAULT_ACTIVATION, |l| activation_name(l.activation))
+                .to_string(),
+            output_dim: layers.last().map_or(1, |l| l.weights.len()),
+        };
+    }
+
+    /**
+     * [is_policy_head()] returns whether the architecture takes the state
+     * alone and outputs a Q-value for every action index, directly or split
+     * into value and advantage streams.
+     */
+    pub fn is_policy_head(&self) -> bool {
+        return self.output_dim == ACTION_SPACE || self.is_dueling();
+    }
+
+    /**
+     * [is_dueling()] returns whether the architecture outputs a state value
+     * and an advantage for every action index.
+     */
+    pub fn is_dueling(&self) -> bool {
+        return self.output_dim == DUELING_OUTPUTS;
+    }
+
+    /**
+     * [head()] names the kind of head of the architecture: "value",
+     * "policy" or "dueling".
+     */
+    pub fn head(&self) -> &'static str {
+        if self.is_dueling() {
+            return "dueling";
+        }
+        if self.is_policy_head() {
+            return "policy";
+        }
+        return "value";
+    }
+
+    /**
+     * [from_json(json)] reads an architecture from its parsed [json], or
+     * returns None if there is none.
+     */
+    pub fn from_json(json: &Value) -> Option<Architecture> {
+        let hidden = json["hidden"].as_array()?;
+        return Some(Architecture {
+            input_dim: json["input_dim"].as_u64()? as usize,
+            hidden: hidden
+                .iter()
+                .filter_map(|n| n.as_u64())
+                .map(|n| n as usize)
+                .collect(),
+            activation: json["activation"]
+                .as_str()
+                .unwrap_or(DEFAULT_ACTIVATION)
+                .to_string(),
+            output_dim: json["output_dim"].as_u64().unwrap_or(1) as usize,
+        });
+    }
+
+    /**
+     * [to_json()] converts the architecture into json.
+     */
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "input_dim": self.input_dim,
+            "hidden": self.hidden,
+            "activation": self.activation,
+            "output_dim": self.output_dim,
+            "head": self.head(),
+        });
+    }
+
+    /**
+     * [summary()] describes the architecture, e.g. 969-128-64-1 tanh value.
+     */
+    pub fn summary(&self) -> String {
+        let mut sizes = vec![self.input_dim.to_string()];
+        sizes.extend(self.hidden.iter().map(|n| n.to_string()));
+        sizes.push(self.output_dim.to_string());
+        return format!("{} {} {}", sizes.join("-"), self.activation, self.head());
+    }
+}
+
+impl ModelBuilder {
+    /**
+     * [new()] creates a builder of networks with the default architecture.
+     */
+    pub fn new() -> ModelBuilder {
+        return ModelBuilder {
+            hidden: vec![DEFAULT_HIDDEN],
+            activation: DEFAULT_ACTIVATION.to_string(),
+            learning_rate: None,
+            momentum: None,
+            policy_head: false,
+            dueling: false,
+        };
+    }
+
+    /**
+     * [from_config(config)] creates a builder of networks with the
+     * architecture given by the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> ModelBuilder {
+        let settings = &config["model"];
+        let mut builder = ModelBuilder::new();
+        if let Some(hidden) = settings["hidden"].as_array() {
+            builder.hidden = hidden
+                .iter()
+                .filter_map(|n| n.as_u64())
+                .map(|n| n as usize)
+                .collect();
+        }
+        if let Some(activation) = settings["activation"].as_str() {
+            builder.activation = activation.to_string();
+        }
+        builder.learning_rate = settings["learning_rate"].as_f64();
+        builder.momentum = settings["momentum"].as_f64();
+        match settings["head"].as_str() {
+            Some("policy") => builder.policy_head = true,
+            Some("dueling") => builder.dueling = true,
+            Some("value") | None => (),
+            Some(head) => panic!("Invalid model head: {}", head),
+        };
+        return builder;
+    }
+
+    /**
+     * [hidden(sizes)] sets the sizes of the hidden layers to [sizes].
+     */
+    pub fn hidden(mut self, sizes: &[usize]) -> ModelBuilder {
+        self.hidden = sizes.to_vec();
+        return self;
+    }
+
+    /**
+     * [activation(name)] sets the activation of the hidden layers to the one
+     * named [name].
+     */
+    pub fn activation(mut self, name: &str) -> ModelBuilder {
+        self.activation = name.to_string();
+        return self;
+    }
+
+    /**
+     * [policy_head(enabled)] sets whether the networks built are policy
+     * heads, scoring every action index from the state alone.
+     */
+    pub fn policy_head(mut self, enabled: bool) -> ModelBuilder {
+        self.policy_head = enabled;
+        return self;
+    }
+
+    /**
+     * [dueling(enabled)] sets whether the networks built are dueling
+     * networks, outputting a state value and an advantage for every action
+     * index.
+     */
+    pub fn dueling(mut self, enabled: bool) -> ModelBuilder {
+        self.dueling = enabled;
+        return self;
+    }
+
+    /**
+     * [architecture()] returns the architecture of the networks built.
+     */
+    pub fn architecture(&self) -> Architecture {
+        let (input_dim, output_dim) = if self.dueling {
+            (STATE_DIM, DUELING_OUTPUTS)
+        } else if self.policy_head {
+            (STATE_DIM, ACTION_SPACE)
+        } else {
+            (INPUT_DIM as usize, 1)
+        };
+        return Architecture {
+            input_dim,
+            hidden: self.hidden.clone(),
+            activation: self.activation.clone(),
+            output_dim,
+        };
+    }
+
+    /**
+     * [build()] builds a fresh network, or returns an error if the
+     * architecture is invalid.
+     */
+    pub fn build(&self) -> BotResult<FeedForward> {
+        if self.hidden.len() == 0 || self.hidden.contains(&0) {
+            return Err(BotError::Config(format!(
+                "invalid hidden layers {:?}",
+                self.hidden
+            )));
+        }
+        let architecture = self.architecture();
+        let mut sizes = vec![architecture.input_dim as i32];
+        sizes.extend(self.hidden.iter().map(|n| *n as i32));
+        sizes.push(architecture.output_dim as i32);
+        let mut nn = FeedForward::new(&sizes);
+        set_activation(&mut nn, &self.activation)?;
+        if let Some(rate) = self.learning_rate {
+            nn.learning_rate(rate);
+        }
+        if let Some(momentum) = self.momentum {
+            nn.momentum(momentum);
+        }
+        return Ok(nn);
+    }
+
+    /**
+     * [create(path)] builds a fresh network and saves it to [path] with its
+     * architecture recorded in its metadata and no feature statistics, or
+     * returns an error if it cannot be built or saved.
+     */
+    pub fn create(&self, path: &str) -> BotResult<FeedForward> {
+        let nn = self.build()?;
+        write_network(&nn, path)?;
+        write_stats(&FeatureStats::new(), path)?;
+        let metadata = CheckpointMetadata {
+            architecture: Some(self.architecture()),
+            ..Default::default()
+        };
+        write_metadata(path, &metadata);
+        return Ok(nn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdp::PIECE_DIM;
+    use crate::models::{read_network, DEFAULT_MODEL_PATH};
+    use std::fs;
+
+    #[test]
+    fn saved_network_loads_back_with_its_architecture() {
+        let dir = std::env::temp_dir().join(format!("model-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.flow");
+        let path = path.to_str().unwrap();
+
+        let mut nn = FeedForward::new(&[INPUT_DIM, 8, 1]);
+        write_network(&nn, path).unwrap();
+        let mut loaded = read_network(path).unwrap();
+
+        let architecture = Architecture::of(&nn);
+        assert_eq!(architecture.input_dim, INPUT_DIM as usize);
+        assert_eq!(architecture.hidden, vec![8]);
+        assert_eq!(Architecture::of(&loaded), architecture);
+        assert!(check_network(&loaded, path).is_ok());
+
+        let x: Vec<f64> = (0..INPUT_DIM).map(|i| (i % 7 == 0) as i32 as f64).collect();
+        assert_eq!(loaded.calc(&x)[0], nn.calc(&x)[0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sigmoid_network_keeps_its_activation_when_loaded() {
+        let dir = std::env::temp_dir().join(format!("sigmoid-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.flow");
+        let path = path.to_str().unwrap();
+
+        let builder = ModelBuilder::new().hidden(&[8]).activation("sigmoid");
+        let mut nn = builder.create(path).unwrap();
+        let mut loaded = read_network(path).unwrap();
+        assert_eq!(Architecture::of(&loaded).activation, "sigmoid");
+
+        let x: Vec<f64> = (0..INPUT_DIM).map(|i| (i % 5 == 0) as i32 as f64).collect();
+        assert_eq!(loaded.calc(&x)[0], nn.calc(&x)[0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relu_is_rejected() {
+        let builder = ModelBuilder::new().activation("relu");
+        assert!(matches!(builder.build(), Err(BotError::Config(_))));
+    }
+
+    #[test]
+    #[cfg(not(feature = "history_planes"))]
+    fn default_network_fits_the_encoding() {
+        let nn = read_network(DEFAULT_MODEL_PATH).unwrap();
+        assert_eq!(Architecture::of(&nn).input_dim, INPUT_DIM as usize);
+    }
+
+    #[test]
+    #[cfg(not(feature = "history_planes"))]
+    fn default_network_ignores_the_inputs_added_since_the_piece_planes() {
+        let nn = read_network(DEFAULT_MODEL_PATH).unwrap();
+        let weights = NetworkWeights::from_network(&nn);
+        for row in &weights.layers[0].weights {
+            assert!(row[PIECE_DIM..STATE_DIM].iter().all(|w| *w == 0.));
+            assert!(row[..PIECE_DIM].iter().any(|w| *w != 0.));
+        }
+    }
+}
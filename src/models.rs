@@ -0,0 +1,338 @@
+/**
+ * Utility module for keeping track of which policy network plays which
+ * position. By default both colors share policy.flow, but the "models" object
+ * in config.json can give each color its own file, along with an endgame
+ * specialist network that takes over once few pieces remain, e.g.
+ * {"white": "policy_white.flow", "black": "policy_black.flow",
+ *  "endgame": "policy_endgame.flow", "endgame_piece_threshold": 10}.
+ * Every network is kept along with the feature statistics saved with it, and
+ * is handed out for scoring wrapped so that its inputs are normalized by
+ * them.
+ */
+use crate::checkpoint::{read_metadata, Phase};
+use crate::error::{BotError, BotResult};
+use crate::model::{check_network, restore_activation};
+use crate::normalization::{read_stats, write_stats, FeatureStats, Normalized};
+use crate::policy_head::NetworkHead;
+
+use chess::Board;
+use neuroflow::{io, ErrorKind, FeedForward};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+pub const DEFAULT_MODEL_PATH: &str = "policy.flow";
+pub const DEFAULT_ENDGAME_PIECE_THRESHOLD: u32 = 10;
+
+// The policy networks used for each color, along with where they are saved
+pub struct ModelRegistry {
+    pub white_path: String,
+    pub black_path: String,
+    pub endgame_path: Option<String>,
+    pub endgame_piece_threshold: u32,
+    white_network: FeedForward,
+    black_network: Option<FeedForward>, // None when shared with white
+    endgame_network: Option<FeedForward>,
+    white_stats: FeatureStats,
+    black_stats: Option<FeatureStats>, // None when shared with white
+    endgame_stats: FeatureStats,
+}
+
+/**
+ * [network_error(action, path, e)] describes neuroflow's error [e] in doing
+ * [action] to the network file at [path].
+ */
+fn network_error(action: &str, path: &str, e: ErrorKind) -> BotError {
+    let reason = match e {
+        ErrorKind::IO(e) => e.to_string(),
+        ErrorKind::Encoding(e) => e.to_string(),
+        ErrorKind::StdError(e) => e.to_string(),
+    };
+    return BotError::Network(format!("unable to {} {}: {}", action, path, reason));
+}
+
+/**
+ * [read_network(path)] loads the policy network saved at [path]. If there is
+ * no file there yet, the shared default network is loaded instead so that a
+ * color-specific network starts out from it. Returns an error if the network
+ * cannot be read or does not fit the current encoding or the architecture
+ * recorded in its metadata.
+ */
+pub fn read_network(path: &str) -> BotResult<FeedForward> {
+    let path = if Path::new(path).exists() {
+        path
+    } else {
+        info!(
+            "No network at {}, starting from {}",
+            path, DEFAULT_MODEL_PATH
+        );
+        DEFAULT_MODEL_PATH
+    };
+
+    let mut nn: FeedForward = io::load(path).map_err(|e| network_error("read", path, e))?;
+    restore_activation(&mut nn);
+    check_network(&nn, path)?;
+    return Ok(nn);
+}
+
+/**
+ * [write_network(nn, path)] saves network [nn] to [path] without ever leaving
+ * a partially written network there: it is first written to a temporary file
+ * next to [path], checked to load back, and only then renamed over [path]. If
+ * anything fails the network previously saved at [path] is left untouched and
+ * an error is returned. The network's feature statistics are saved apart, by
+ * [write_stats].
+ */
+pub fn write_network(nn: &FeedForward, path: &str) -> BotResult<()> {
+    let tmp_path = format!("{}.tmp", path);
+    io::save(nn, &tmp_path).map_err(|e| network_error("write", &tmp_path, e))?;
+
+    let loaded: Result<FeedForward, _> = io::load(&tmp_path);
+    if loaded.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(BotError::Network(format!(
+            "network written to {} does not load back",
+            tmp_path
+        )));
+    }
+
+    fs::rename(&tmp_path, path)?;
+    return Ok(());
+}
+
+/**
+ * [white_path(config)] returns where the white policy network given by the
+ * parsed [config] is saved, without loading it.
+ */
+pub fn white_path(config: &Value) -> String {
+    return config["models"]["white"]
+        .as_str()
+        .unwrap_or(DEFAULT_MODEL_PATH)
+        .to_string();
+}
+
+impl ModelRegistry {
+    /**
+     * [from_config(config)] loads the policy networks for each color given by
+     * the parsed [config], or returns an error if any cannot be loaded.
+     */
+    pub fn from_config(config: &Value) -> BotResult<ModelRegistry> {
+        return ModelRegistry::serving(config, None);
+    }
+
+    /**
+     * [serving(config, checkpoint)] loads the policy networks given by the
+     * parsed [config], except that both colors are played by the network
+     * saved at [checkpoint] if there is one. Returns an error if any network
+     * cannot be loaded.
+     */
+    pub fn serving(config: &Value, checkpoint: Option<&str>) -> BotResult<ModelRegistry> {
+        let models = &config["models"];
+        let white_path = models["white"].as_str().unwrap_or(DEFAULT_MODEL_PATH);
+        let black_path = models["black"].as_str().unwrap_or(DEFAULT_MODEL_PATH);
+
+        let mut registry = match checkpoint {
+            Some(path) => ModelRegistry::new(path, path)?,
+            None => ModelRegistry::new(white_path, black_path)?,
+        };
+        if let Some(endgame_path) = models["endgame"].as_str() {
+            let threshold = models["endgame_piece_threshold"]
+                .as_u64()
+                .map(|n| n as u32)
+                .unwrap_or(DEFAULT_ENDGAME_PIECE_THRESHOLD);
+            registry.set_endgame_network(endgame_path, threshold)?;
+        }
+
+        return Ok(registry);
+    }
+
+    /**
+     * [new(white_path, black_path)] loads the policy networks for each color
+     * from [white_path] and [black_path], sharing one network if the paths
+     * are the same, each along with the feature statistics saved with it.
+     * Returns an error if any cannot be loaded.
+     */
+    pub fn new(white_path: &str, black_path: &str) -> BotResult<ModelRegistry> {
+        let white_network = read_network(white_path)?;
+        let white_stats = read_stats(white_path)?;
+        let (black_network, black_stats) = if white_path.eq(black_path) {
+            (None, None)
+        } else {
+            (
+                Some(read_network(black_path)?),
+                Some(read_stats(black_path)?),
+            )
+        };
+
+        return Ok(ModelRegistry {
+            white_path: white_path.to_string(),
+            black_path: black_path.to_string(),
+            endgame_path: None,
+            endgame_piece_threshold: DEFAULT_ENDGAME_PIECE_THRESHOLD,
+            white_network,
+            black_network,
+            endgame_network: None,
+            white_stats,
+            black_stats,
+            endgame_stats: FeatureStats::new(),
+        });
+    }
+
+    /**
+     * [set_endgame_network(path, piece_threshold)] loads the endgame network
+     * saved at [path], to be used in positions with at most
+     * [piece_threshold] pieces on the board. Returns an error if the network
+     * cannot be loaded or is not tagged as an endgame network in its
+     * metadata.
+     */
+    pub fn set_endgame_network(&mut self, path: &str, piece_threshold: u32) -> BotResult<()> {
+        let metadata = read_metadata(path);
+        if metadata.phase != Phase::Endgame {
+            return Err(BotError::Config(format!(
+                "network at {} is not tagged as an endgame network",
+                path
+            )));
+        }
+
+        self.endgame_network = Some(read_network(path)?);
+        self.endgame_stats = read_stats(path)?;
+        self.endgame_path = Some(path.to_string());
+        self.endgame_piece_threshold = piece_threshold;
+        return Ok(());
+    }
+
+    /**
+     * [path(player_white)] returns where the network for the player's color is
+     * saved depending on whether the player is white.
+     */
+    pub fn path(&self, player_white: bool) -> &str {
+        if player_white {
+            return &self.white_path;
+        } else {
+            return &self.black_path;
+        }
+    }
+
+    /**
+     * [network(player_white)] returns the policy network that plays the
+     * player's color depending on whether the player is white.
+     */
+    pub fn network(&mut self, player_white: bool) -> &mut FeedForward {
+        match (player_white, &mut self.black_network) {
+            (false, Some(nn)) => nn,
+            _ => &mut self.white_network,
+        }
+    }
+
+    /**
+     * [stats(player_white)] returns the feature statistics of the network that
+     * plays the player's color depending on whether the player is white.
+     */
+    pub fn stats(&self, player_white: bool) -> FeatureStats {
+        match (player_white, &self.black_stats) {
+            (false, Some(stats)) => *stats,
+            _ => self.white_stats,
+        }
+    }
+
+    /**
+     * [learner(player_white)] returns the policy network that plays the
+     * player's color depending on whether the player is white, along with its
+     * feature statistics, to be trained together.
+     */
+    pub fn learner(&mut self, player_white: bool) -> (&mut FeedForward, &mut FeatureStats) {
+        match (player_white, &mut self.black_network, &mut self.black_stats) {
+            (false, Some(nn), Some(stats)) => (nn, stats),
+            _ => (&mut self.white_network, &mut self.white_stats),
+        }
+    }
+
+    /**
+     * [head(player_white)] returns the policy network that plays the player's
+     * color depending on whether the player is white, ready to score moves
+     * with its inputs normalized by its feature statistics.
+     */
+    pub fn head(&mut self, player_white: bool) -> Normalized<NetworkHead<'_>> {
+        let stats = self.stats(player_white);
+        return Normalized::new(NetworkHead::of(self.network(player_white)), stats);
+    }
+
+    /**
+     * [is_endgame(b)] returns whether the endgame network evaluates board [b].
+     */
+    fn is_endgame(&self, b: &Board) -> bool {
+        let endgame = b.combined().popcnt() <= self.endgame_piece_threshold;
+        return endgame && self.endgame_network.is_some();
+    }
+
+    /**
+     * [network_for(b, player_white)] dispatches to the network that should
+     * evaluate board [b] depending on whether the player is white: the
+     * endgame network once few enough pieces remain, and otherwise the
+     * player's color network.
+     */
+    pub fn network_for(&mut self, b: &Board, player_white: bool) -> &mut FeedForward {
+        if self.is_endgame(b) {
+            return self.endgame_network.as_mut().unwrap();
+        }
+
+        return self.network(player_white);
+    }
+
+    /**
+     * [stats_for(b, player_white)] returns the feature statistics of the
+     * network that evaluates board [b] for the player, depending on whether
+     * the player is white (see network_for).
+     */
+    pub fn stats_for(&self, b: &Board, player_white: bool) -> FeatureStats {
+        if self.is_endgame(b) {
+            return self.endgame_stats;
+        }
+        return self.stats(player_white);
+    }
+
+    /**
+     * [head_for(b, player_white)] returns the network that evaluates board
+     * [b] for the player, depending on whether the player is white (see
+     * network_for), ready to score moves with its inputs normalized by its
+     * feature statistics.
+     */
+    pub fn head_for(&mut self, b: &Board, player_white: bool) -> Normalized<NetworkHead<'_>> {
+        let stats = self.stats_for(b, player_white);
+        return Normalized::new(NetworkHead::of(self.network_for(b, player_white)), stats);
+    }
+
+    /**
+     * [path_for(b, player_white)] returns where the network that evaluates
+     * board [b] for the player, depending on whether the player is white, is
+     * saved (see network_for).
+     */
+    pub fn path_for(&self, b: &Board, player_white: bool) -> &str {
+        match &self.endgame_path {
+            Some(path) if self.is_endgame(b) => path,
+            _ => self.path(player_white),
+        }
+    }
+
+    /**
+     * [load_saved(player_white)] loads a fresh copy of the last saved network
+     * for the player's color depending on whether the player is white, for use
+     * as the Q network in training, or returns an error if it cannot be
+     * loaded.
+     */
+    pub fn load_saved(&self, player_white: bool) -> BotResult<FeedForward> {
+        return read_network(self.path(player_white));
+    }
+
+    /**
+     * [save(player_white)] saves the network for the player's color depending
+     * on whether the player is white, along with its feature statistics, or
+     * returns an error if it cannot be saved.
+     */
+    pub fn save(&mut self, player_white: bool) -> BotResult<()> {
+        let path = self.path(player_white).to_string();
+        write_network(self.network(player_white), &path)?;
+        return write_stats(&self.stats(player_white), &path);
+    }
+}
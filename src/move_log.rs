@@ -0,0 +1,98 @@
+/**
+ * Utility module for a structured log of the bot's moves, with one JSON
+ * object per line giving the game, the ply, the FEN of the position before
+ * the move, the move in uci and SAN, its Q-value and the decision behind it
+ * (where it came from, the top alternatives and the margin over the second
+ * best move), so that any position can be loaded back into the analyze
+ * command later. Logging is turned on by the
+ * "move_log" object in config.json, e.g. {"path": "moves.jsonl"}. Every
+ * decision is also logged as a debug event, which ends up in the game's
+ * transcript (see logging).
+ */
+use crate::decision::{MoveDecision, MoveSource};
+use crate::notation::to_san;
+
+use chess::Board;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::Write;
+use tracing::debug;
+
+// Where moves are logged, if anywhere
+#[derive(Clone, Debug, Default)]
+pub struct MoveLog {
+    pub path: Option<String>,
+}
+
+// The move log of a single game
+pub struct GameLog<'a> {
+    log: &'a MoveLog,
+    game: String,
+}
+
+impl MoveLog {
+    /**
+     * [from_config(config)] reads where moves are logged from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> MoveLog {
+        return MoveLog {
+            path: config["move_log"]["path"].as_str().map(|p| p.to_string()),
+        };
+    }
+
+    /**
+     * [game(id)] returns the log of the moves of the game with id [id].
+     */
+    pub fn game(&self, id: &str) -> GameLog<'_> {
+        return GameLog {
+            log: self,
+            game: id.to_string(),
+        };
+    }
+}
+
+impl<'a> GameLog<'a> {
+    /**
+     * [record(ply, fen, b, decision, q_value)] logs the move chosen by
+     * [decision] at [ply] in board [b], whose FEN is [fen], with Q-value
+     * [q_value].
+     */
+    pub fn record(&self, ply: usize, fen: &str, b: &Board, decision: &MoveDecision, q_value: f64) {
+        let m = decision.chosen;
+        let details = decision.to_json(b);
+        debug!(
+            event = "decision",
+            ply,
+            fen,
+            chosen = %m,
+            san = %to_san(b, m),
+            source = %details["source"],
+            explored = decision.source == MoveSource::Exploration,
+            q = q_value,
+            candidates = %details["alternatives"],
+            "Decided on {}",
+            m
+        );
+        let path = match &self.log.path {
+            Some(p) => p,
+            None => return,
+        };
+
+        let entry = json!({
+            "game": self.game,
+            "ply": ply,
+            "fen": fen,
+            "move": m.to_string(),
+            "san": to_san(b, m),
+            "q": q_value,
+            "decision": details,
+        });
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        writeln!(file, "{}", entry).unwrap();
+    }
+}
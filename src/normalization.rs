@@ -0,0 +1,279 @@
+/**
+ * Utility module for normalizing the features of the state that are not 0 or
+ * 1, the move counters, by running estimates of their mean and variance, so
+ * that they are centered and scaled like the rest of the input instead of
+ * skewing the network. The estimates are updated from every experience the
+ * networks are fit to by Q-learning, and every network input is built from a
+ * state normalized by them, both when training and when scoring moves. Every
+ * network has statistics of its own, which are saved next to it (e.g.
+ * policy.flow.stats.json) and loaded along with it, so a checkpoint is served
+ * with the statistics it was trained with. A network is given its statistics
+ * by wrapping it in [Normalized], which normalizes every input before the
+ * network sees it. Until any experience was seen the features are left as
+ * they are, so networks saved without statistics play as before.
+ */
+use crate::error::{BotError, BotResult};
+use crate::mdp::{COUNTER_DIM, STATE_DIM};
+use crate::q_function::QFunction;
+
+use serde_json::{json, Value};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+// Indices of the state's features that are normalized
+pub const NORMALIZED_FEATURES: Range<usize> = STATE_DIM - COUNTER_DIM..STATE_DIM;
+
+// Smallest standard deviation a feature is divided by, so that a feature that
+// barely varied yet is not blown up
+const MIN_STD: f64 = 0.01;
+
+// Running mean and variance of each normalized feature, by Welford's method
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeatureStats {
+    pub count: u64,
+    pub mean: [f64; COUNTER_DIM],
+    m2: [f64; COUNTER_DIM], // sum of squared deviations from the mean
+}
+
+// A value function whose inputs are normalized by the feature statistics of
+// the network behind it
+pub struct Normalized<Q> {
+    pub q: Q,
+    pub stats: FeatureStats,
+}
+
+impl FeatureStats {
+    /**
+     * [new()] returns statistics of no states, which leave every state as it
+     * is.
+     */
+    pub const fn new() -> FeatureStats {
+        return FeatureStats {
+            count: 0,
+            mean: [0.; COUNTER_DIM],
+            m2: [0.; COUNTER_DIM],
+        };
+    }
+
+    /**
+     * [observe(state)] updates the statistics with the features of [state].
+     */
+    pub fn observe(&mut self, state: &[f64]) {
+        self.count += 1;
+        for (i, x) in state[NORMALIZED_FEATURES].iter().enumerate() {
+            let delta = x - self.mean[i];
+            self.mean[i] += delta / self.count as f64;
+            self.m2[i] += delta * (x - self.mean[i]);
+        }
+    }
+
+    /**
+     * [std()] returns the standard deviation of each feature, at least
+     * [MIN_STD].
+     */
+    pub fn std(&self) -> [f64; COUNTER_DIM] {
+        let mut std = [MIN_STD; COUNTER_DIM];
+        if self.count > 0 {
+            for i in 0..COUNTER_DIM {
+                std[i] = (self.m2[i] / self.count as f64).sqrt().max(MIN_STD);
+            }
+        }
+        return std;
+    }
+
+    /**
+     * [normalize(state)] centers and scales the normalized features of
+     * [state], which may be followed by an action, in place. Nothing is
+     * changed before any state was observed.
+     */
+    pub fn normalize(&self, state: &mut [f64]) {
+        if self.count == 0 {
+            return;
+        }
+        let std = self.std();
+        for (i, x) in state[NORMALIZED_FEATURES].iter_mut().enumerate() {
+            *x = (*x - self.mean[i]) / std[i];
+        }
+    }
+
+    /**
+     * [normalized(state)] returns a copy of [state], which may be followed by
+     * an action, with its features normalized.
+     */
+    pub fn normalized(&self, state: &[f64]) -> Vec<f64> {
+        let mut state = state.to_vec();
+        self.normalize(&mut state);
+        return state;
+    }
+}
+
+impl Default for FeatureStats {
+    fn default() -> FeatureStats {
+        return FeatureStats::new();
+    }
+}
+
+impl<Q> Normalized<Q> {
+    /**
+     * [new(q, stats)] wraps value function [q], whose inputs are normalized
+     * by [stats].
+     */
+    pub fn new(q: Q, stats: FeatureStats) -> Normalized<Q> {
+        return Normalized { q, stats };
+    }
+}
+
+impl<Q: QFunction> QFunction for Normalized<Q> {
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        let inputs: Vec<Vec<f64>> = inputs.iter().map(|x| self.stats.normalized(x)).collect();
+        return self.q.predict_batch(&inputs);
+    }
+
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]) {
+        let inputs: Vec<Vec<f64>> = inputs.iter().map(|x| self.stats.normalized(x)).collect();
+        self.q.train_batch(&inputs, targets);
+    }
+
+    fn save(&self, path: &str) -> BotResult<()> {
+        self.q.save(path)?;
+        return write_stats(&self.stats, path);
+    }
+
+    fn load(path: &str) -> BotResult<Normalized<Q>> {
+        return Ok(Normalized::new(Q::load(path)?, read_stats(path)?));
+    }
+
+    fn predict(&mut self, input: &[f64]) -> f64 {
+        return self.q.predict(&self.stats.normalized(input));
+    }
+}
+
+/**
+ * [stats_path(path)] returns the path of the statistics saved alongside the
+ * network saved at [path].
+ */
+pub fn stats_path(path: &str) -> String {
+    return format!("{}.stats.json", path);
+}
+
+/**
+ * [read_stats(path)] loads the statistics saved alongside the network saved
+ * at [path], or statistics of no states if none were saved. Returns an error
+ * if they cannot be read.
+ */
+pub fn read_stats(path: &str) -> BotResult<FeatureStats> {
+    let stats_path = stats_path(path);
+    if !Path::new(&stats_path).exists() {
+        return Ok(FeatureStats::new());
+    }
+
+    let json: Value = serde_json::from_str(&fs::read_to_string(&stats_path)?)?;
+    let features = |key: &str| -> BotResult<[f64; COUNTER_DIM]> {
+        let values: Vec<f64> = json[key].as_array().map_or(Vec::new(), |a| {
+            a.iter().filter_map(|x| x.as_f64()).collect()
+        });
+        return values.try_into().map_err(|_| {
+            BotError::Network(format!(
+                "{} in {} does not fit the encoding",
+                key, stats_path
+            ))
+        });
+    };
+
+    return Ok(FeatureStats {
+        count: json["count"].as_u64().unwrap_or(0),
+        mean: features("mean")?,
+        m2: features("m2")?,
+    });
+}
+
+/**
+ * [write_stats(stats, path)] saves [stats] alongside the network saved at
+ * [path].
+ */
+pub fn write_stats(stats: &FeatureStats, path: &str) -> BotResult<()> {
+    let json = json!({
+        "count": stats.count,
+        "mean": stats.mean,
+        "m2": stats.m2,
+    });
+    let tmp_path = format!("{}.tmp", stats_path(path));
+    fs::write(&tmp_path, json.to_string())?;
+    fs::rename(&tmp_path, stats_path(path))?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_features_are_centered_and_scaled() {
+        let mut stats = FeatureStats::new();
+        let mut state = vec![1.; STATE_DIM];
+        stats.normalize(&mut state);
+        assert_eq!(state, vec![1.; STATE_DIM]);
+
+        for x in [0.1, 0.2, 0.3, 0.4] {
+            let mut state = vec![0.; STATE_DIM];
+            state[NORMALIZED_FEATURES].fill(x);
+            stats.observe(&state);
+        }
+        assert!((stats.mean[0] - 0.25).abs() < 1e-12);
+        assert!((stats.std()[0] - 0.0125f64.sqrt()).abs() < 1e-12);
+
+        let mut state = vec![1.; STATE_DIM];
+        state[NORMALIZED_FEATURES].fill(0.25);
+        stats.normalize(&mut state);
+        assert!(state[NORMALIZED_FEATURES].iter().all(|x| x.abs() < 1e-12));
+        assert!(state[..NORMALIZED_FEATURES.start].iter().all(|x| *x == 1.));
+    }
+
+    #[test]
+    fn constant_features_are_not_blown_up() {
+        let mut stats = FeatureStats::new();
+        let mut state = vec![0.; STATE_DIM];
+        state[NORMALIZED_FEATURES].fill(0.5);
+        stats.observe(&state);
+        stats.observe(&state);
+        assert_eq!(stats.std(), [MIN_STD; COUNTER_DIM]);
+
+        state[NORMALIZED_FEATURES].fill(0.51);
+        stats.normalize(&mut state);
+        assert!(state[NORMALIZED_FEATURES]
+            .iter()
+            .all(|x| (x - 1.).abs() < 1e-9));
+    }
+
+    #[test]
+    fn statistics_are_saved_next_to_the_network() {
+        let dir = std::env::temp_dir().join(format!("stats-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.flow").to_string_lossy().to_string();
+
+        assert_eq!(read_stats(&path).unwrap(), FeatureStats::new());
+        let mut stats = FeatureStats::new();
+        for x in [0.1, 0.3] {
+            let mut state = vec![0.; STATE_DIM];
+            state[NORMALIZED_FEATURES].fill(x);
+            stats.observe(&state);
+        }
+        write_stats(&stats, &path).unwrap();
+        let json: Value =
+            serde_json::from_str(&fs::read_to_string(stats_path(&path)).unwrap()).unwrap();
+        for key in ["mean", "m2"] {
+            assert_eq!(json[key].as_array().map(|a| a.len()), Some(COUNTER_DIM));
+        }
+        assert_eq!(read_stats(&path).unwrap(), stats);
+
+        // Statistics of another encoding are refused rather than misapplied
+        fs::write(
+            stats_path(&path),
+            r#"{"count": 1, "mean": [0.5], "m2": [0]}"#,
+        )
+        .unwrap();
+        assert!(read_stats(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+/**
+ * Utility module for writing moves in Standard Algebraic Notation (SAN), e.g.
+ * "Nf6" rather than the uci "g8f6", for logs and chat messages that people
+ * read, and positions as FEN strings that can be loaded back later.
+ */
+use chess::{Board, BoardStatus, ChessMove, MoveGen, Piece};
+
+/**
+ * [piece_letter(piece)] returns the SAN letter of [piece], which is empty for
+ * pawns.
+ */
+fn piece_letter(piece: Piece) -> &'static str {
+    match piece {
+        Piece::Pawn => "",
+        Piece::Knight => "N",
+        Piece::Bishop => "B",
+        Piece::Rook => "R",
+        Piece::Queen => "Q",
+        Piece::King => "K",
+    }
+}
+
+/**
+ * [file_char(file)] returns the letter of the file with index [file].
+ */
+fn file_char(file: usize) -> char {
+    return (b'a' + file as u8) as char;
+}
+
+/**
+ * [to_san(b, m)] returns legal move [m] in board [b] in SAN, with a check or
+ * checkmate suffix.
+ */
+pub fn to_san(b: &Board, m: ChessMove) -> String {
+    let source = m.get_source();
+    let dest = m.get_dest();
+    let piece = b.piece_on(source).unwrap_or(Piece::Pawn);
+    let from_file = source.get_file().to_index();
+    let to_file = dest.get_file().to_index();
+
+    let mut san = if piece == Piece::King && (from_file as i32 - to_file as i32).abs() == 2 {
+        if to_file > from_file {
+            "O-O".to_string()
+        } else {
+            "O-O-O".to_string()
+        }
+    } else {
+        // Pawns capture diagonally, including en passant onto an empty square
+        let capture = b.piece_on(dest).is_some() || (piece == Piece::Pawn && from_file != to_file);
+
+        let mut san = piece_letter(piece).to_string();
+        if piece == Piece::Pawn {
+            if capture {
+                san.push(file_char(from_file));
+            }
+        } else {
+            // Disambiguate from other pieces of the same kind that can reach
+            // the same square, by file if that is enough, then by rank
+            let others: Vec<ChessMove> = MoveGen::new_legal(b)
+                .filter(|o| {
+                    o.get_dest() == dest
+                        && o.get_source() != source
+                        && b.piece_on(o.get_source()) == Some(piece)
+                })
+                .collect();
+            if others.len() > 0 {
+                let same_file = others
+                    .iter()
+                    .any(|o| o.get_source().get_file() == source.get_file());
+                let same_rank = others
+                    .iter()
+                    .any(|o| o.get_source().get_rank() == source.get_rank());
+                if !same_file {
+                    san.push(file_char(from_file));
+                } else if !same_rank {
+                    san += &(source.get_rank().to_index() + 1).to_string();
+                } else {
+                    san += &source.to_string();
+                }
+            }
+        }
+        if capture {
+            san.push('x');
+        }
+        san += &dest.to_string();
+        if let Some(promotion) = m.get_promotion() {
+            san.push('=');
+            san += piece_letter(promotion);
+        }
+        san
+    };
+
+    let next_board = b.make_move_new(m);
+    if next_board.status() == BoardStatus::Checkmate {
+        san.push('#');
+    } else if next_board.checkers().popcnt() > 0 {
+        san.push('+');
+    }
+
+    return san;
+}
+
+/**
+ * [line_to_san(b, line)] returns the moves of [line], played one after the
+ * other from board [b], in SAN.
+ */
+pub fn line_to_san(b: &Board, line: &[ChessMove]) -> Vec<String> {
+    let mut board = *b;
+    let mut sans = Vec::new();
+    for m in line {
+        sans.push(to_san(&board, *m));
+        board = board.make_move_new(*m);
+    }
+
+    return sans;
+}
+
+/**
+ * [to_fen(b, halfmove_clock, fullmove_number)] returns the FEN of board [b]
+ * with the given move counters, which the board does not track itself.
+ */
+pub fn to_fen(b: &Board, halfmove_clock: usize, fullmove_number: usize) -> String {
+    let board_fen = b.to_string();
+    let fields: Vec<&str> = board_fen.split_whitespace().take(4).collect();
+    return format!(
+        "{} {} {}",
+        fields.join(" "),
+        halfmove_clock,
+        fullmove_number
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /**
+     * [san(fen, uci)] returns the SAN of the move [uci] in the board [fen].
+     */
+    fn san(fen: &str, uci: &str) -> String {
+        let b = Board::from_str(fen).unwrap();
+        return to_san(&b, ChessMove::from_str(uci).unwrap());
+    }
+
+    #[test]
+    fn special_moves_are_written_in_san() {
+        let castling = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        assert_eq!(san(castling, "e1g1"), "O-O");
+        assert_eq!(san(castling, "e1c1"), "O-O-O");
+        assert_eq!(san("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"), "exd6");
+        assert_eq!(san("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7a8q"), "a8=Q+");
+        assert_eq!(san("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7a8n"), "a8=N");
+    }
+
+    #[test]
+    fn pieces_are_disambiguated_by_file_then_rank_then_square() {
+        assert_eq!(san("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "b1d2"), "Nbd2");
+        assert_eq!(san("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1", "a1a3"), "R1a3");
+        assert_eq!(san("8/7k/8/8/8/Q7/8/Q1Q4K w - - 0 1", "a1c3"), "Qa1c3");
+        assert_eq!(san("4k3/8/8/8/8/8/8/RN2K3 w - - 0 1", "b1c3"), "Nc3");
+    }
+
+    #[test]
+    fn lines_are_written_move_by_move_with_checkmate() {
+        let line: Vec<ChessMove> = ["f2f3", "e7e5", "g2g4", "d8h4"]
+            .iter()
+            .map(|m| ChessMove::from_str(m).unwrap())
+            .collect();
+        let sans = line_to_san(&Board::default(), &line);
+        assert_eq!(sans, vec!["f3", "e5", "g4", "Qh4#"]);
+        assert_eq!(
+            to_fen(&Board::default(), 5, 12),
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 5 12"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+/**
+ * Utility module for an optional exploration bonus for reaching novel
+ * positions in self-play. Visits to each position are counted approximately
+ * by a count-min sketch keyed by the position's hash, so memory stays fixed
+ * however many positions are seen, and the n-th visit to a position earns an
+ * intrinsic reward of scale / sqrt(n). This pushes self-play out of the narrow
+ * set of positions it would otherwise keep cycling through. Configured by the
+ * "novelty" settings of the "selfplay" object in config.json, e.g.
+ * {"scale": 1, "width": 65536, "depth": 4}, and off unless a scale is given.
+ */
+use chess::Board;
+use serde_json::Value;
+
+const DEFAULT_WIDTH: u64 = 1 << 16;
+const DEFAULT_DEPTH: u64 = 4;
+
+// Approximate counts of keys, which may overcount but never undercount
+#[derive(Clone, Debug)]
+pub struct CountMinSketch {
+    width: usize,
+    counts: Vec<Vec<u32>>, // a row of counters per hash function
+}
+
+// Intrinsic reward for reaching rarely visited positions
+#[derive(Clone, Debug)]
+pub struct NoveltyBonus {
+    pub scale: f64,
+    sketch: CountMinSketch,
+}
+
+/**
+ * [mix(x)] scrambles the bits of [x] (splitmix64), so that keys differing in
+ * a few bits land in unrelated counters.
+ */
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+impl CountMinSketch {
+    /**
+     * [new(width, depth)] creates an empty sketch with [depth] rows of
+     * [width] counters each.
+     */
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        return CountMinSketch {
+            width: width.max(1),
+            counts: vec![vec![0; width.max(1)]; depth.max(1)],
+        };
+    }
+
+    /**
+     * [index(row, key)] returns the counter of [key] in row [row].
+     */
+    fn index(&self, row: usize, key: u64) -> usize {
+        return (mix(key ^ mix(row as u64)) % self.width as u64) as usize;
+    }
+
+    /**
+     * [estimate(key)] returns the approximate number of times [key] was added.
+     */
+    pub fn estimate(&self, key: u64) -> u32 {
+        return (0..self.counts.len())
+            .map(|row| self.counts[row][self.index(row, key)])
+            .min()
+            .unwrap_or(0);
+    }
+
+    /**
+     * [add(key)] counts another occurrence of [key], returning its
+     * approximate count including this one.
+     */
+    pub fn add(&mut self, key: u64) -> u32 {
+        for row in 0..self.counts.len() {
+            let i = self.index(row, key);
+            self.counts[row][i] = self.counts[row][i].saturating_add(1);
+        }
+        return self.estimate(key);
+    }
+}
+
+impl NoveltyBonus {
+    /**
+     * [from_config(settings)] reads the novelty bonus given by the "novelty"
+     * [settings], or returns None if no positive scale is given.
+     */
+    pub fn from_config(settings: &Value) -> Option<NoveltyBonus> {
+        let scale = settings["scale"].as_f64().filter(|s| *s > 0.)?;
+        let width = settings["width"].as_u64().unwrap_or(DEFAULT_WIDTH);
+        let depth = settings["depth"].as_u64().unwrap_or(DEFAULT_DEPTH);
+        return Some(NoveltyBonus {
+            scale,
+            sketch: CountMinSketch::new(width as usize, depth as usize),
+        });
+    }
+
+    /**
+     * [visit(b)] counts a visit to board [b] and returns the bonus for
+     * reaching it, which shrinks the more often it has been visited.
+     */
+    pub fn visit(&mut self, b: &Board) -> f64 {
+        let visits = self.sketch.add(b.get_hash());
+        return self.scale / (visits as f64).sqrt();
+    }
+}
@@ -0,0 +1,551 @@
+/**
+ * Utility module for exporting networks to ONNX and importing them back, so
+ * that trained weights can be inspected, trained further in Python or served
+ * by any ONNX runtime rather than only by neuroflow. A network is exported as
+ * a graph taking a batch of encoded inputs named "input" and returning a batch
+ * of outputs named "output", with a Gemm node for every layer, whose weights
+ * and biases are stored as double tensors named layer{j}.weight (one row per
+ * neuron) and layer{j}.bias, each followed by the hidden activation but the
+ * last. The only activations supported are Tanh and Sigmoid, the ones
+ * neuroflow offers, so graphs using any other (e.g. Relu) are refused on
+ * import. Importing reads back graphs of that shape, including ones whose
+ * Gemm weights are not transposed or are stored as floats, as long as
+ * neuroflow can represent them (see weights). ONNX files
+ * are protobuf messages, which are written and read here directly for the
+ * handful of fields a network of this shape needs.
+ */
+use crate::error::{BotError, BotResult};
+use crate::weights::{Activation, LayerWeights, NetworkWeights};
+
+use std::collections::HashMap;
+use std::fs;
+
+// Versions of the ONNX format and operator set written
+const IR_VERSION: u64 = 7;
+const OPSET_VERSION: u64 = 13;
+
+// ONNX tensor element types
+const FLOAT: u64 = 1;
+const DOUBLE: u64 = 11;
+
+// ONNX attribute type of an integer
+const ATTRIBUTE_INT: u64 = 2;
+
+// A field of a protobuf message, by wire type
+#[derive(Clone, Copy, Debug)]
+enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+// A tensor stored in the graph, as a matrix of doubles
+#[derive(Clone, Debug)]
+struct Tensor {
+    dims: Vec<usize>,
+    data: Vec<f64>,
+}
+
+/**
+ * [malformed(reason)] returns the error for an ONNX file that cannot be
+ * imported because of [reason].
+ */
+fn malformed<T>(reason: &str) -> BotResult<T> {
+    return Err(BotError::Config(format!(
+        "unsupported ONNX model: {}",
+        reason
+    )));
+}
+
+/**
+ * [put_varint(buf, v)] appends [v] to [buf] as a protobuf varint.
+ */
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/**
+ * [put_int(buf, field, v)] appends integer field [field] with value [v] to
+ * [buf].
+ */
+fn put_int(buf: &mut Vec<u8>, field: u64, v: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, v);
+}
+
+/**
+ * [put_bytes(buf, field, bytes)] appends length-delimited field [field]
+ * holding [bytes] to [buf], which is how strings and nested messages are
+ * written.
+ */
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/**
+ * [decode(bytes)] splits the protobuf message [bytes] into its fields, in
+ * order, or returns an error if it is malformed.
+ */
+fn decode(bytes: &[u8]) -> BotResult<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    let mut i = 0;
+    let varint = |i: &mut usize| -> BotResult<u64> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = match bytes.get(*i) {
+                Some(b) => *b,
+                None => return malformed("truncated varint"),
+            };
+            *i += 1;
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return Ok(v);
+            }
+        }
+        return malformed("varint too long");
+    };
+    let take = |i: &mut usize, n: usize| -> BotResult<&[u8]> {
+        let slice = match bytes.get(*i..*i + n) {
+            Some(s) => s,
+            None => return malformed("truncated field"),
+        };
+        *i += n;
+        return Ok(slice);
+    };
+
+    while i < bytes.len() {
+        let key = varint(&mut i)?;
+        let field = match key & 7 {
+            0 => Field::Varint(varint(&mut i)?),
+            1 => Field::Fixed64(u64::from_le_bytes(take(&mut i, 8)?.try_into().unwrap())),
+            2 => {
+                let n = varint(&mut i)? as usize;
+                Field::Bytes(take(&mut i, n)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(take(&mut i, 4)?.try_into().unwrap())),
+            _ => return malformed("unknown wire type"),
+        };
+        fields.push((key >> 3, field));
+    }
+    return Ok(fields);
+}
+
+/**
+ * [bytes_fields(fields, number)] returns every length-delimited field
+ * numbered [number] among [fields].
+ */
+fn bytes_fields<'a>(fields: &[(u64, Field<'a>)], number: u64) -> Vec<&'a [u8]> {
+    return fields
+        .iter()
+        .filter_map(|(n, f)| match f {
+            Field::Bytes(b) if *n == number => Some(*b),
+            _ => None,
+        })
+        .collect();
+}
+
+/**
+ * [int_fields(fields, number)] returns every integer numbered [number] among
+ * [fields], whether written one per field or packed.
+ */
+fn int_fields(fields: &[(u64, Field)], number: u64) -> BotResult<Vec<u64>> {
+    let mut ints = Vec::new();
+    for (n, f) in fields {
+        match f {
+            Field::Varint(v) if *n == number => ints.push(*v),
+            Field::Bytes(b) if *n == number => ints.extend(packed_varints(b)?),
+            _ => (),
+        };
+    }
+    return Ok(ints);
+}
+
+/**
+ * [packed_varints(bytes)] reads the packed varints [bytes].
+ */
+fn packed_varints(bytes: &[u8]) -> BotResult<Vec<u64>> {
+    let mut ints = Vec::new();
+    let mut v = 0;
+    let mut shift = 0;
+    for byte in bytes {
+        v |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if *byte < 0x80 {
+            ints.push(v);
+            v = 0;
+            shift = 0;
+        } else if shift >= 64 {
+            return malformed("varint too long");
+        }
+    }
+    return Ok(ints);
+}
+
+/**
+ * [string_field(fields, number)] returns the first string numbered [number]
+ * among [fields], or an empty string if there is none.
+ */
+fn string_field(fields: &[(u64, Field)], number: u64) -> String {
+    return bytes_fields(fields, number)
+        .first()
+        .map_or(String::new(), |b| String::from_utf8_lossy(b).to_string());
+}
+
+/**
+ * [value_info(name, width)] encodes the description of a graph input or
+ * output named [name] holding a batch of [width] doubles.
+ */
+fn value_info(name: &str, width: usize) -> Vec<u8> {
+    let mut batch = Vec::new();
+    put_bytes(&mut batch, 2, b"batch");
+    let mut features = Vec::new();
+    put_int(&mut features, 1, width as u64);
+    let mut shape = Vec::new();
+    put_bytes(&mut shape, 1, &batch);
+    put_bytes(&mut shape, 1, &features);
+
+    let mut tensor_type = Vec::new();
+    put_int(&mut tensor_type, 1, DOUBLE);
+    put_bytes(&mut tensor_type, 2, &shape);
+    let mut type_proto = Vec::new();
+    put_bytes(&mut type_proto, 1, &tensor_type);
+
+    let mut info = Vec::new();
+    put_bytes(&mut info, 1, name.as_bytes());
+    put_bytes(&mut info, 2, &type_proto);
+    return info;
+}
+
+/**
+ * [tensor(name, dims, data)] encodes the initializer named [name] of shape
+ * [dims] holding the doubles [data].
+ */
+fn tensor(name: &str, dims: &[usize], data: &[f64]) -> Vec<u8> {
+    let mut t = Vec::new();
+    for d in dims {
+        put_int(&mut t, 1, *d as u64);
+    }
+    put_int(&mut t, 2, DOUBLE);
+    put_bytes(&mut t, 8, name.as_bytes());
+    let raw: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    put_bytes(&mut t, 9, &raw);
+    return t;
+}
+
+/**
+ * [node(op_type, inputs, output, trans_b)] encodes a graph node applying
+ * [op_type] to [inputs], producing [output], with the transB attribute set
+ * if [trans_b].
+ */
+fn node(op_type: &str, inputs: &[&str], output: &str, trans_b: bool) -> Vec<u8> {
+    let mut n = Vec::new();
+    for input in inputs {
+        put_bytes(&mut n, 1, input.as_bytes());
+    }
+    put_bytes(&mut n, 2, output.as_bytes());
+    put_bytes(&mut n, 3, output.as_bytes());
+    put_bytes(&mut n, 4, op_type.as_bytes());
+    if trans_b {
+        let mut attribute = Vec::new();
+        put_bytes(&mut attribute, 1, b"transB");
+        put_int(&mut attribute, 3, 1);
+        put_int(&mut attribute, 20, ATTRIBUTE_INT);
+        put_bytes(&mut n, 5, &attribute);
+    }
+    return n;
+}
+
+/**
+ * [activation_op(activation)] returns the ONNX operator applying
+ * [activation], or None if it is linear.
+ */
+fn activation_op(activation: Activation) -> Option<&'static str> {
+    return match activation {
+        Activation::Sigmoid => Some("Sigmoid"),
+        Activation::Tanh => Some("Tanh"),
+        Activation::Linear => None,
+    };
+}
+
+/**
+ * [encode_onnx(weights)] encodes a network with [weights] as an ONNX model.
+ */
+pub fn encode_onnx(weights: &NetworkWeights) -> Vec<u8> {
+    let mut graph = Vec::new();
+    let mut input = "input".to_string();
+    for (j, layer) in weights.layers.iter().enumerate() {
+        let (weight_name, bias_name) = (format!("layer{}.weight", j), format!("layer{}.bias", j));
+        let inputs = layer.weights.first().map_or(0, |w| w.len());
+        let flat: Vec<f64> = layer.weights.iter().flatten().cloned().collect();
+        put_bytes(
+            &mut graph,
+            5,
+            &tensor(&weight_name, &[layer.weights.len(), inputs], &flat),
+        );
+        put_bytes(
+            &mut graph,
+            5,
+            &tensor(&bias_name, &[layer.biases.len()], &layer.biases),
+        );
+
+        let last = j + 1 == weights.layers.len();
+        let linear = if last && activation_op(layer.activation).is_none() {
+            "output".to_string()
+        } else {
+            format!("layer{}_linear", j)
+        };
+        put_bytes(
+            &mut graph,
+            1,
+            &node("Gemm", &[&input, &weight_name, &bias_name], &linear, true),
+        );
+        input = linear;
+        if let Some(op) = activation_op(layer.activation) {
+            let activated = if last {
+                "output".to_string()
+            } else {
+                format!("layer{}_out", j)
+            };
+            put_bytes(&mut graph, 1, &node(op, &[&input], &activated, false));
+            input = activated;
+        }
+    }
+    put_bytes(&mut graph, 2, b"policy");
+    let input_dim = weights
+        .layers
+        .first()
+        .and_then(|l| l.weights.first())
+        .map_or(0, |w| w.len());
+    let output_dim = weights.layers.last().map_or(0, |l| l.weights.len());
+    put_bytes(&mut graph, 11, &value_info("input", input_dim));
+    put_bytes(&mut graph, 12, &value_info("output", output_dim));
+
+    let mut opset = Vec::new();
+    put_bytes(&mut opset, 1, b"");
+    put_int(&mut opset, 2, OPSET_VERSION);
+    let mut model = Vec::new();
+    put_int(&mut model, 1, IR_VERSION);
+    put_bytes(&mut model, 2, b"rust-chess-bot");
+    put_bytes(&mut model, 8, &opset);
+    put_bytes(&mut model, 7, &graph);
+    return model;
+}
+
+/**
+ * [decode_tensor(bytes)] reads the initializer [bytes] along with its name.
+ */
+fn decode_tensor(bytes: &[u8]) -> BotResult<(String, Tensor)> {
+    let fields = decode(bytes)?;
+    let dims: Vec<usize> = int_fields(&fields, 1)?
+        .iter()
+        .map(|d| *d as usize)
+        .collect();
+    let data_type = int_fields(&fields, 2)?.first().copied().unwrap_or(0);
+    let raw = bytes_fields(&fields, 9).concat();
+    let mut data: Vec<f64> = match data_type {
+        DOUBLE => raw
+            .chunks_exact(8)
+            .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+            .collect(),
+        FLOAT => raw
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+            .collect(),
+        _ => return malformed("tensors must hold floats or doubles"),
+    };
+
+    // Without raw data the values are in float_data or double_data
+    for (n, f) in &fields {
+        match (n, f) {
+            (4, Field::Fixed32(x)) => data.push(f32::from_bits(*x) as f64),
+            (4, Field::Bytes(b)) => data.extend(
+                b.chunks_exact(4)
+                    .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64),
+            ),
+            (10, Field::Fixed64(x)) => data.push(f64::from_bits(*x)),
+            (10, Field::Bytes(b)) => data.extend(
+                b.chunks_exact(8)
+                    .map(|c| f64::from_le_bytes(c.try_into().unwrap())),
+            ),
+            _ => (),
+        };
+    }
+    if data.len() != dims.iter().product::<usize>() {
+        return malformed("tensor data does not match its shape");
+    }
+    return Ok((string_field(&fields, 8), Tensor { dims, data }));
+}
+
+/**
+ * [decode_onnx(bytes)] reads the weights of a network from the ONNX model
+ * [bytes], or returns an error if it is not a stack of Gemm layers with
+ * activations.
+ */
+pub fn decode_onnx(bytes: &[u8]) -> BotResult<NetworkWeights> {
+    let model = decode(bytes)?;
+    let graph = match bytes_fields(&model, 7).first() {
+        Some(g) => decode(g)?,
+        None => return malformed("no graph"),
+    };
+    let mut tensors = HashMap::new();
+    for bytes in bytes_fields(&graph, 5) {
+        let (name, tensor) = decode_tensor(bytes)?;
+        tensors.insert(name, tensor);
+    }
+
+    let mut layers: Vec<LayerWeights> = Vec::new();
+    for bytes in bytes_fields(&graph, 1) {
+        let fields = decode(bytes)?;
+        let inputs: Vec<String> = bytes_fields(&fields, 1)
+            .iter()
+            .map(|b| String::from_utf8_lossy(b).to_string())
+            .collect();
+        let op_type = string_field(&fields, 4);
+        let activation = match op_type.as_str() {
+            "Gemm" => None,
+            "Tanh" => Some(Activation::Tanh),
+            "Sigmoid" => Some(Activation::Sigmoid),
+            "Relu" => return malformed("Relu activation, neuroflow only offers Tanh and Sigmoid"),
+            op => return malformed(&format!("unsupported operator {}", op)),
+        };
+        if let Some(activation) = activation {
+            match layers.last_mut() {
+                Some(layer) if layer.activation == Activation::Linear => {
+                    layer.activation = activation
+                }
+                _ => return malformed("activation without a layer before it"),
+            };
+            continue;
+        }
+
+        let mut trans_b = false;
+        for attribute in bytes_fields(&fields, 5) {
+            let attribute = decode(attribute)?;
+            let value = int_fields(&attribute, 3)?.first().copied().unwrap_or(0);
+            match string_field(&attribute, 1).as_str() {
+                "transB" => trans_b = value == 1,
+                "transA" if value != 0 => return malformed("Gemm with transA"),
+                _ => (),
+            };
+        }
+        let (weight, bias) = match (
+            inputs.get(1).and_then(|n| tensors.get(n)),
+            inputs.get(2).and_then(|n| tensors.get(n)),
+        ) {
+            (Some(w), b) => (w, b),
+            _ => return malformed("Gemm weights must be initializers"),
+        };
+        if weight.dims.len() != 2 {
+            return malformed("Gemm weights must be a matrix");
+        }
+
+        // Rows of neurons, transposing weights stored one column per neuron
+        let (rows, cols) = (weight.dims[0], weight.dims[1]);
+        let weights: Vec<Vec<f64>> = if trans_b {
+            weight.data.chunks(cols).map(|r| r.to_vec()).collect()
+        } else {
+            (0..cols)
+                .map(|c| (0..rows).map(|r| weight.data[r * cols + c]).collect())
+                .collect()
+        };
+        let biases = match bias {
+            Some(b) if b.data.len() == weights.len() => b.data.clone(),
+            Some(_) => return malformed("Gemm biases must have one per neuron"),
+            None => vec![0.; weights.len()],
+        };
+        layers.push(LayerWeights {
+            weights,
+            biases,
+            activation: Activation::Linear,
+        });
+    }
+    if layers.len() == 0 {
+        return malformed("no layers");
+    }
+    return Ok(NetworkWeights { layers });
+}
+
+/**
+ * [export_onnx(weights, path)] writes a network with [weights] to the ONNX
+ * file at [path].
+ */
+pub fn export_onnx(weights: &NetworkWeights, path: &str) -> BotResult<()> {
+    fs::write(path, encode_onnx(weights))?;
+    return Ok(());
+}
+
+/**
+ * [import_onnx(path)] reads the weights of the network in the ONNX file at
+ * [path].
+ */
+pub fn import_onnx(path: &str) -> BotResult<NetworkWeights> {
+    return decode_onnx(&fs::read(path)?);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::set_activation;
+    use crate::INPUT_DIM;
+    use neuroflow::FeedForward;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn network_round_trips_through_onnx() {
+        for activation in ["tanh", "sigmoid"] {
+            let mut original = FeedForward::new(&[INPUT_DIM, 8, 4, 1]);
+            set_activation(&mut original, activation).unwrap();
+            round_trip(&mut original);
+        }
+    }
+
+    #[test]
+    fn relu_graphs_are_refused() {
+        let weights = NetworkWeights::from_network(&FeedForward::new(&[INPUT_DIM, 8, 1]));
+        let mut bytes = encode_onnx(&weights);
+        let at = bytes.windows(4).position(|w| w == b"Tanh").unwrap();
+        bytes[at..at + 4].copy_from_slice(b"Relu");
+        assert!(decode_onnx(&bytes).is_err());
+    }
+
+    /**
+     * [round_trip(original)] checks that network [original] is exported and
+     * imported back with the same shape and outputs.
+     */
+    fn round_trip(original: &mut FeedForward) {
+        let weights = NetworkWeights::from_network(original);
+        let decoded = decode_onnx(&encode_onnx(&weights)).unwrap();
+
+        let shape = |w: &NetworkWeights| -> Vec<(usize, usize)> {
+            return w
+                .layers
+                .iter()
+                .map(|l| (l.weights.len(), l.weights[0].len()))
+                .collect();
+        };
+        assert_eq!(shape(&decoded), shape(&weights));
+        assert_eq!(decoded.layers[0].weights[0].len(), INPUT_DIM as usize);
+
+        let mut imported = decoded.to_network().unwrap();
+        assert_eq!(
+            shape(&NetworkWeights::from_network(&imported)),
+            shape(&weights)
+        );
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let x: Vec<f64> = (0..INPUT_DIM)
+                .map(|_| if rng.gen_bool(0.1) { 1. } else { 0. })
+                .collect();
+            assert!((imported.calc(&x)[0] - original.calc(&x)[0]).abs() < 1e-9);
+        }
+    }
+}
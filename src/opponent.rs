@@ -0,0 +1,111 @@
+/**
+ * Utility module for modeling the opponent of a game from the history of
+ * games against them in the game database, and turning that model into simple
+ * features for move selection. Against weaker or blunder-prone opponents the
+ * bot prefers sharper moves (captures and checks), scaled by the
+ * "sharpness_bonus" setting in config.json.
+ */
+use crate::database::{GameDatabase, OpponentHistory};
+
+use chess::{Board, ChessMove};
+use serde_json::Value;
+
+// Rating below which opponents count as weak, and how quickly that ramps up
+const WEAK_RATING: f64 = 1500.;
+const RATING_SCALE: f64 = 400.;
+
+const DEFAULT_SHARPNESS_BONUS: f64 = 0.5;
+
+// Number of the opponent's own moves that make up the opening they played
+pub const OPENING_PLIES: usize = 3;
+
+// How much the bot's evaluation must rise over an opponent's move to count it
+// as a blunder
+pub const BLUNDER_THRESHOLD: f64 = 20.;
+
+// What the bot knows about its opponent in a game
+#[derive(Clone, Debug)]
+pub struct OpponentProfile {
+    pub name: String,
+    pub rating: Option<i64>,
+    pub history: OpponentHistory,
+    pub openings: Vec<(String, u32)>,
+    pub sharpness_bonus: f64,
+}
+
+impl OpponentProfile {
+    /**
+     * [load(db, config, name, rating)] builds the profile of opponent [name]
+     * with [rating] from their history in [db] and the parsed [config].
+     */
+    pub fn load(db: &GameDatabase, config: &Value, name: &str, rating: Option<i64>) -> Self {
+        return OpponentProfile {
+            name: name.to_string(),
+            rating,
+            history: db.opponent_history(name),
+            openings: db.opponent_openings(name, 3),
+            sharpness_bonus: config["sharpness_bonus"]
+                .as_f64()
+                .unwrap_or(DEFAULT_SHARPNESS_BONUS),
+        };
+    }
+
+    /**
+     * [blunder_rate()] returns the fraction of the opponent's past moves
+     * against the bot that were blunders.
+     */
+    pub fn blunder_rate(&self) -> f64 {
+        if self.history.opponent_moves == 0 {
+            return 0.;
+        }
+        return self.history.opponent_blunders as f64 / self.history.opponent_moves as f64;
+    }
+
+    /**
+     * [weakness()] returns how strongly the bot should prefer sharp play
+     * against the opponent, from 0 to 1, based on their rating and blunder
+     * rate.
+     */
+    pub fn weakness(&self) -> f64 {
+        let rating_weakness = match self.rating {
+            Some(r) => ((WEAK_RATING - r as f64) / RATING_SCALE).max(0.),
+            None => 0.,
+        };
+        return (rating_weakness + self.blunder_rate()).min(1.);
+    }
+
+    /**
+     * [sharpness_bonus(b, m)] returns the bonus added to the score of move [m]
+     * in board [b] against the opponent, which is positive for captures and
+     * checks against weak opponents.
+     */
+    pub fn sharpness_bonus(&self, b: &Board, m: ChessMove) -> f64 {
+        let capture = b.piece_on(m.get_dest()).is_some();
+        let check = b.make_move_new(m).checkers().popcnt() > 0;
+        if !capture && !check {
+            return 0.;
+        }
+
+        return self.sharpness_bonus * self.weakness();
+    }
+
+    /**
+     * [summary()] describes the profile in a single line for the logs.
+     */
+    pub fn summary(&self) -> String {
+        let openings: Vec<String> = self
+            .openings
+            .iter()
+            .map(|(o, n)| format!("{} ({})", o, n))
+            .collect();
+        return format!(
+            "{} rated {:?}: {} games, average result {:.2}, blunder rate {:.3}, openings [{}]",
+            self.name,
+            self.rating,
+            self.history.games,
+            self.history.average_result,
+            self.blunder_rate(),
+            openings.join(", ")
+        );
+    }
+}
@@ -0,0 +1,100 @@
+/**
+ * Utility module for how the policy networks' outputs relate to values in
+ * reward units, which every training target is converted through. By default
+ * outputs are values, so ±100 terminal rewards are mixed with unbounded
+ * bootstrapped targets, which the networks' bounded outputs can not fit and
+ * which tend to diverge. With the "output_scaling" object in config.json set
+ * to e.g. {"kind": "tanh", "scale": 50}, outputs are instead squashed values,
+ * tanh(value / scale) in [-1, 1]: bootstrapped targets are computed in reward
+ * units from the unsquashed value of the next state and squashed again, while
+ * the targets of moves into terminal states are anchored to exactly their
+ * reward divided by the scale (a win is worth 1 and a loss -1 at the default
+ * rewards when the scale is 100). Move selection is unaffected, since
+ * squashing keeps the order of values, but scores and thresholds read off the
+ * networks are in output units.
+ */
+use serde_json::Value;
+
+// Default number of reward units squashed to tanh(1)
+const DEFAULT_TANH_SCALE: f64 = 50.;
+
+// Largest magnitude of an output that is unsquashed, so that outputs at the
+// bounds map to large but finite values
+const MAX_SQUASHED: f64 = 0.999999;
+
+// How network outputs relate to values in reward units
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputScaling {
+    #[default]
+    Linear, // outputs are values
+    Tanh {
+        scale: f64, // outputs are tanh(value / scale)
+    },
+}
+
+impl OutputScaling {
+    /**
+     * [from_config(config)] reads the output scaling given by the parsed
+     * [config], which is linear unless configured otherwise.
+     */
+    pub fn from_config(config: &Value) -> OutputScaling {
+        let settings = &config["output_scaling"];
+        return match settings["kind"].as_str() {
+            Some("tanh") => OutputScaling::Tanh {
+                scale: settings["scale"]
+                    .as_f64()
+                    .filter(|s| *s > 0.)
+                    .unwrap_or(DEFAULT_TANH_SCALE),
+            },
+            Some("linear") | None => OutputScaling::Linear,
+            Some(kind) => panic!("Unknown output scaling {}", kind),
+        };
+    }
+
+    /**
+     * [squash(value)] converts [value] in reward units into a network output.
+     */
+    pub fn squash(&self, value: f64) -> f64 {
+        return match self {
+            OutputScaling::Linear => value,
+            OutputScaling::Tanh { scale } => (value / scale).tanh(),
+        };
+    }
+
+    /**
+     * [unsquash(output)] converts the network output [output] into a value
+     * in reward units.
+     */
+    pub fn unsquash(&self, output: f64) -> f64 {
+        return match self {
+            OutputScaling::Linear => output,
+            OutputScaling::Tanh { scale } => {
+                scale * output.clamp(-MAX_SQUASHED, MAX_SQUASHED).atanh()
+            }
+        };
+    }
+
+    /**
+     * [anchor(reward)] returns the exact network output for a move into a
+     * terminal state worth [reward].
+     */
+    pub fn anchor(&self, reward: f64) -> f64 {
+        return match self {
+            OutputScaling::Linear => reward,
+            OutputScaling::Tanh { scale } => (reward / scale).clamp(-1., 1.),
+        };
+    }
+
+    /**
+     * [target(reward, next_output, gamma)] returns the network output a move
+     * earning [reward] is trained towards, bootstrapping from the network
+     * output [next_output] of the next state discounted by [gamma], or
+     * anchored to [reward] if the next state is terminal (None).
+     */
+    pub fn target(&self, reward: f64, next_output: Option<f64>, gamma: f64) -> f64 {
+        return match next_output {
+            Some(q) => self.squash(reward + gamma * self.unsquash(q)),
+            None => self.anchor(reward),
+        };
+    }
+}
@@ -0,0 +1,219 @@
+/**
+ * Utility module for recording the games the bot plays as PGN, so that its
+ * play can be replayed and debugged in any chess GUI. Each game is written
+ * with its headers, its moves in SAN, its result and the bot's Q-value of
+ * each of its moves as a comment, e.g. 1. e4 {q=0.12} e5 2. Nf3 {q=0.31}.
+ * Every run writes its games to its own file in the directory given by the
+ * "pgn" object in config.json, e.g. {"dir": "pgn"}: self-play runs to
+ * selfplay-<run seed>.pgn and Lichess sessions to lichess-<start time>.pgn.
+ * Games are not recorded without it.
+ */
+use crate::notation::to_san;
+
+use chess::{Board, ChessMove, Color};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the Unix epoch when this process first recorded a game, which
+// names the file of a Lichess session
+static SESSION_START: OnceLock<u64> = OnceLock::new();
+
+// Where the games of a run are recorded, if anywhere
+#[derive(Clone, Debug, Default)]
+pub struct PgnLog {
+    pub path: Option<String>,
+}
+
+// A game being recorded: its headers, starting position and moves, each with
+// the bot's Q-value if the bot played it
+#[derive(Clone, Debug)]
+pub struct PgnGame {
+    pub headers: Vec<(String, String)>,
+    pub start: Board,
+    pub moves: Vec<(ChessMove, Option<f64>)>,
+}
+
+/**
+ * [unix_seconds()] returns the number of seconds since the Unix epoch.
+ */
+fn unix_seconds() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+}
+
+/**
+ * [pgn_date(secs)] returns the UTC date [secs] seconds after the Unix epoch in
+ * the PGN date format, e.g. 2024.01.31.
+ */
+fn pgn_date(secs: u64) -> String {
+    // Convert days since the epoch to a civil date, counting in 400 year eras
+    // of March-based years so that leap days fall at the end of each year
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!("{:04}.{:02}.{:02}", year, month, day);
+}
+
+/**
+ * [result_from_reward(reward)] returns the PGN result of a game whose last
+ * experience for White was worth [reward]: a win, a loss, or otherwise a draw.
+ */
+pub fn result_from_reward(reward: f64) -> &'static str {
+    if reward > 0. {
+        return "1-0";
+    } else if reward < 0. {
+        return "0-1";
+    }
+    return "1/2-1/2";
+}
+
+impl PgnLog {
+    /**
+     * [from_config(config, run)] reads where the games of the run named [run]
+     * are recorded from the parsed [config].
+     */
+    pub fn from_config(config: &Value, run: &str) -> PgnLog {
+        return PgnLog {
+            path: config["pgn"]["dir"]
+                .as_str()
+                .map(|dir| format!("{}/{}.pgn", dir, run)),
+        };
+    }
+
+    /**
+     * [session(config)] reads where the Lichess games played by this process
+     * are recorded from the parsed [config].
+     */
+    pub fn session(config: &Value) -> PgnLog {
+        let start = SESSION_START.get_or_init(unix_seconds);
+        return PgnLog::from_config(config, &format!("lichess-{}", start));
+    }
+
+    /**
+     * [write(game, result)] appends [game], which ended with the PGN result
+     * [result], to the run's file.
+     */
+    pub fn write(&self, game: &PgnGame, result: &str) -> io::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", game.to_pgn(result))?;
+        return Ok(());
+    }
+}
+
+impl PgnGame {
+    /**
+     * [new(event, white, black, start)] starts recording a game of [event]
+     * between the players named [white] and [black] from board [start].
+     */
+    pub fn new(event: &str, white: &str, black: &str, start: &Board) -> PgnGame {
+        let mut headers = vec![
+            ("Event".to_string(), event.to_string()),
+            ("Site".to_string(), "?".to_string()),
+            ("Date".to_string(), pgn_date(unix_seconds())),
+            ("White".to_string(), white.to_string()),
+            ("Black".to_string(), black.to_string()),
+        ];
+        if *start != Board::default() {
+            headers.push(("SetUp".to_string(), "1".to_string()));
+            headers.push(("FEN".to_string(), start.to_string()));
+        }
+
+        return PgnGame {
+            headers,
+            start: *start,
+            moves: Vec::new(),
+        };
+    }
+
+    /**
+     * [header(name, value)] sets the header [name] to [value].
+     */
+    pub fn header(&mut self, name: &str, value: &str) {
+        match self.headers.iter_mut().find(|(n, _)| n == name) {
+            Some(header) => header.1 = value.to_string(),
+            None => self.headers.push((name.to_string(), value.to_string())),
+        };
+    }
+
+    /**
+     * [push(m, q)] records move [m], played by the bot with Q-value [q] or by
+     * its opponent if None.
+     */
+    pub fn push(&mut self, m: ChessMove, q: Option<f64>) {
+        self.moves.push((m, q));
+    }
+
+    /**
+     * [to_pgn(result)] returns the game as PGN, ending with the PGN result
+     * [result].
+     */
+    pub fn to_pgn(&self, result: &str) -> String {
+        let mut pgn = String::new();
+        for (name, value) in &self.headers {
+            pgn += &format!("[{} \"{}\"]\n", name, value.replace('"', "'"));
+        }
+        pgn += &format!("[Result \"{}\"]\n\n", result);
+
+        let mut board = self.start;
+        let mut move_number = 1;
+        let mut tokens = Vec::new();
+        if board.side_to_move() == Color::Black {
+            tokens.push("1...".to_string());
+        }
+        for (m, q) in &self.moves {
+            if board.side_to_move() == Color::White {
+                tokens.push(format!("{}.", move_number));
+            }
+            tokens.push(to_san(&board, *m));
+            if let Some(q) = q {
+                tokens.push(format!("{{q={:.2}}}", q));
+            }
+            if board.side_to_move() == Color::Black {
+                move_number += 1;
+            }
+            board = board.make_move_new(*m);
+        }
+        tokens.push(result.to_string());
+
+        // Wrap the movetext at 80 columns
+        let mut line = String::new();
+        for token in tokens {
+            if line.len() > 0 && line.len() + 1 + token.len() > 80 {
+                pgn += &line;
+                pgn += "\n";
+                line.clear();
+            }
+            if line.len() > 0 {
+                line += " ";
+            }
+            line += &token;
+        }
+        pgn += &line;
+        pgn += "\n";
+
+        return pgn;
+    }
+}
@@ -0,0 +1,240 @@
+/**
+ * Utility module for policy-head networks, which take only the state and
+ * output a Q-value for every index of the fixed action space (see
+ * action_space), so that every move in a position is scored by one forward
+ * pass instead of one pass per state-action pair. A policy head is used
+ * wherever a QFunction is: an encoded state-action pair is answered by the
+ * output for its action index among the outputs for its state, which are
+ * computed once and reused while consecutive pairs share the state, and it is
+ * trained by fitting the output of the chosen action towards its Bellman label
+ * while every other output is fit to its current value, so that only the
+ * chosen move learns from an experience. Moves can also be picked straight
+ * from the outputs, with illegal moves masked out, by argmax or by sampling
+ * from their softmax at a temperature. Policy heads are created with
+ * "head": "policy" in the "model" object in config.json, and recognized by
+ * their number of outputs when loaded. Dueling networks (see dueling) are
+ * policy heads too, whose outputs are combined into the Q-values.
+ */
+use crate::action_space::{action_index, index_to_move, masked_argmax, masked_softmax};
+use crate::dueling::{self, DUELING_OUTPUTS};
+use crate::error::{BotError, BotResult};
+use crate::history::MoveCounters;
+use crate::mdp::{get_state, STATE_DIM};
+use crate::model::Architecture;
+use crate::models::write_network;
+use crate::normalization::FeatureStats;
+use crate::q_function::QFunction;
+
+use chess::{Board, ChessMove};
+use neuroflow::FeedForward;
+use rand::Rng;
+use std::borrow::BorrowMut;
+
+// A network taking the state and outputting a Q-value for every action index.
+// The network is either owned or borrowed.
+pub struct PolicyHead<N: BorrowMut<FeedForward>> {
+    pub network: N,
+    cached: Option<(Vec<f64>, Vec<f64>, Vec<f64>)>, // the last state, its outputs and Q-values
+}
+
+// A borrowed network of either design, scoring state-action pairs as a
+// QFunction
+pub enum NetworkHead<'a> {
+    Value(&'a mut FeedForward),
+    Policy(PolicyHead<&'a mut FeedForward>),
+}
+
+/**
+ * [is_policy_head(nn)] returns whether network [nn] takes the state alone and
+ * outputs a Q-value for every action index.
+ */
+pub fn is_policy_head(nn: &FeedForward) -> bool {
+    return Architecture::of(nn).is_policy_head();
+}
+
+/**
+ * [encoded_index(sa)] returns the action index of the encoded state-action
+ * pair [sa]. Panics if [sa] does not encode a move.
+ */
+fn encoded_index(sa: &[f64]) -> usize {
+    return action_index(&sa[STATE_DIM..]).expect("Action was not well-formed");
+}
+
+impl<N: BorrowMut<FeedForward>> PolicyHead<N> {
+    /**
+     * [new(network)] wraps the policy-head [network].
+     */
+    pub fn new(network: N) -> PolicyHead<N> {
+        return PolicyHead {
+            network,
+            cached: None,
+        };
+    }
+
+    /**
+     * [evaluate(state)] computes the network's outputs in the encoded [state]
+     * along with their Q-values, unless those of the last state are cached
+     * and it is the same.
+     */
+    fn evaluate(&mut self, state: &[f64]) {
+        let fresh = match &self.cached {
+            Some((cached_state, _, _)) => cached_state.as_slice() != state,
+            None => true,
+        };
+        if fresh {
+            let outputs = self.network.borrow_mut().calc(state).to_vec();
+            let q_values = if outputs.len() == DUELING_OUTPUTS {
+                dueling::q_values(&outputs)
+            } else {
+                outputs.clone()
+            };
+            self.cached = Some((state.to_vec(), outputs, q_values));
+        }
+    }
+
+    /**
+     * [outputs(state)] returns the Q-value of every action index in the
+     * encoded [state], reusing those of the last state if it is the same.
+     */
+    pub fn outputs(&mut self, state: &[f64]) -> &[f64] {
+        self.evaluate(state);
+        return &self.cached.as_ref().unwrap().2;
+    }
+
+    /**
+     * [select_move(b, counters, player_white, stats)] returns the legal move
+     * in board [b], reached with move [counters], with the highest output
+     * depending on whether the player is white, with the state normalized by
+     * the network's feature [stats], or None if there are no legal moves.
+     */
+    pub fn select_move(
+        &mut self,
+        b: &Board,
+        counters: MoveCounters,
+        player_white: bool,
+        stats: &FeatureStats,
+    ) -> Option<ChessMove> {
+        let outputs = self
+            .outputs(&stats.normalized(&get_state(b, counters, player_white)))
+            .to_vec();
+        return masked_argmax(&outputs, b, player_white);
+    }
+
+    /**
+     * [sample_move(b, counters, player_white, stats, temperature, rng)]
+     * samples a legal move in board [b], reached with move [counters], from
+     * the softmax of the outputs at [temperature] depending on whether the
+     * player is white, with the state normalized by the network's feature
+     * [stats], or returns None if there are no legal moves.
+     */
+    pub fn sample_move<R: Rng>(
+        &mut self,
+        b: &Board,
+        counters: MoveCounters,
+        player_white: bool,
+        stats: &FeatureStats,
+        temperature: f64,
+        rng: &mut R,
+    ) -> Option<ChessMove> {
+        let outputs = self
+            .outputs(&stats.normalized(&get_state(b, counters, player_white)))
+            .to_vec();
+        let probabilities = masked_softmax(&outputs, b, player_white, temperature);
+        let mut x: f64 = rng.gen();
+        for (index, p) in probabilities.iter().enumerate() {
+            if *p > 0. && x < *p {
+                return index_to_move(index, b, player_white);
+            }
+            x -= p;
+        }
+
+        // Rounding left some probability over, so fall back to the best move
+        return masked_argmax(&outputs, b, player_white);
+    }
+}
+
+impl<N: BorrowMut<FeedForward>> QFunction for PolicyHead<N> {
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        return inputs
+            .iter()
+            .map(|sa| self.outputs(&sa[..STATE_DIM])[encoded_index(sa)])
+            .collect();
+    }
+
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]) {
+        for (sa, y) in inputs.iter().zip(targets) {
+            let state = &sa[..STATE_DIM];
+            self.evaluate(state);
+            let outputs = &self.cached.as_ref().unwrap().1;
+            let labels = if outputs.len() == DUELING_OUTPUTS {
+                dueling::labels(outputs, encoded_index(sa), *y)
+            } else {
+                let mut labels = outputs.clone();
+                labels[encoded_index(sa)] = *y;
+                labels
+            };
+            self.network.borrow_mut().fit(state, &labels);
+            self.cached = None;
+        }
+    }
+
+    fn save(&self, path: &str) -> BotResult<()> {
+        return write_network(self.network.borrow(), path);
+    }
+
+    fn load(_path: &str) -> BotResult<PolicyHead<N>> {
+        return Err(BotError::Config(
+            "policy heads are loaded with load_q_function".to_string(),
+        ));
+    }
+}
+
+impl<'a> NetworkHead<'a> {
+    /**
+     * [new(nn, policy_head)] wraps network [nn], which is a policy head if
+     * [policy_head].
+     */
+    pub fn new(nn: &'a mut FeedForward, policy_head: bool) -> NetworkHead<'a> {
+        if policy_head {
+            return NetworkHead::Policy(PolicyHead::new(nn));
+        }
+        return NetworkHead::Value(nn);
+    }
+
+    /**
+     * [of(nn)] wraps network [nn] according to its number of outputs.
+     */
+    pub fn of(nn: &'a mut FeedForward) -> NetworkHead<'a> {
+        let policy_head = is_policy_head(nn);
+        return NetworkHead::new(nn, policy_head);
+    }
+}
+
+impl<'a> QFunction for NetworkHead<'a> {
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        match self {
+            NetworkHead::Value(nn) => nn.predict_batch(inputs),
+            NetworkHead::Policy(head) => head.predict_batch(inputs),
+        }
+    }
+
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]) {
+        match self {
+            NetworkHead::Value(nn) => nn.train_batch(inputs, targets),
+            NetworkHead::Policy(head) => head.train_batch(inputs, targets),
+        };
+    }
+
+    fn save(&self, path: &str) -> BotResult<()> {
+        match self {
+            NetworkHead::Value(nn) => nn.save(path),
+            NetworkHead::Policy(head) => head.save(path),
+        }
+    }
+
+    fn load(_path: &str) -> BotResult<NetworkHead<'a>> {
+        return Err(BotError::Config(
+            "borrowed networks cannot be loaded".to_string(),
+        ));
+    }
+}
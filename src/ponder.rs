@@ -0,0 +1,165 @@
+/**
+ * Utility module for pondering on the opponent's time. Once the bot has
+ * posted its move, the game loop would otherwise sit idle until the opponent
+ * replies, so it instead predicts the opponent's replies by scoring them with
+ * the network for the opponent's color, and for the most likely ones first
+ * encodes the position the reply leads to and scores the bot's moves in it,
+ * one reply at a time whenever no update from Lichess is waiting. If the
+ * opponent plays a pondered reply, the bot's state and evaluation of the
+ * position are read back instead of computed when its turn arrives. The
+ * evaluations are encoded with the game's history, as when the move is
+ * selected. Configured by the "ponder" object in config.json, e.g.
+ * {"enabled": true, "replies": 4}, which ponders the 4 likeliest replies,
+ * and off without it. Every reply is pondered unless "replies" is given.
+ */
+use crate::history::PositionHistory;
+use crate::mdp::{evaluate_game_position, get_state_with_history, score_moves_in_state};
+use crate::models::ModelRegistry;
+
+use chess::ChessMove;
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::debug;
+
+// The bot's state in a position reached by a pondered reply, with the
+// Q-value of each of its legal moves there
+#[derive(Clone, Debug)]
+pub struct Pondered {
+    pub state: Vec<f64>,
+    pub scores: Vec<(ChessMove, f64)>,
+}
+
+// Ponders the opponent's replies to the bot's last move
+#[derive(Clone, Debug)]
+pub struct Ponderer {
+    replies: Option<usize>,           // None to ponder every reply
+    waiting: Option<PositionHistory>, // the game after the bot's last move
+    predicted: bool,                  // whether the replies have been ordered
+    queue: Vec<ChessMove>,            // replies left, most likely last
+    pondered: HashMap<u64, Pondered>, // by hash of the position reached
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Ponderer {
+    /**
+     * [from_config(config)] reads the pondering settings from the parsed
+     * [config], or returns None if pondering is off.
+     */
+    pub fn from_config(config: &Value) -> Option<Ponderer> {
+        let settings = &config["ponder"];
+        if !settings["enabled"].as_bool().unwrap_or(false) {
+            return None;
+        }
+
+        return Some(Ponderer {
+            replies: settings["replies"].as_u64().map(|n| n as usize),
+            waiting: None,
+            predicted: false,
+            queue: Vec::new(),
+            pondered: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        });
+    }
+
+    /**
+     * [start(history, chosen)] starts pondering the replies to move [chosen]
+     * of the bot's in the latest position of [history], forgetting whatever
+     * was pondered before.
+     */
+    pub fn start(&mut self, history: &PositionHistory, chosen: ChessMove) {
+        let mut waiting = history.clone();
+        waiting.make_move(chosen);
+        self.waiting = Some(waiting);
+        self.predicted = false;
+        self.queue.clear();
+        self.pondered.clear();
+    }
+
+    /**
+     * [pending()] returns whether there is anything left to ponder.
+     */
+    pub fn pending(&self) -> bool {
+        return self.waiting.is_some() && (!self.predicted || !self.queue.is_empty());
+    }
+
+    /**
+     * [step(models, player_white)] does the next piece of pondering with the
+     * networks in [models], the bot playing white or not as given by
+     * [player_white]: first ordering the opponent's replies by how likely the
+     * opponent's network finds them, and then pondering one reply at a time.
+     */
+    pub fn step(&mut self, models: &mut ModelRegistry, player_white: bool) {
+        let history = match &self.waiting {
+            Some(h) => h,
+            None => return,
+        };
+
+        if !self.predicted {
+            let b = history.board();
+            let mut scores = evaluate_game_position(
+                history,
+                &mut models.head_for(&b, !player_white),
+                !player_white,
+            );
+            scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let keep = self.replies.unwrap_or(scores.len()).min(scores.len());
+            self.queue = scores[scores.len() - keep..]
+                .iter()
+                .map(|(m, _)| *m)
+                .collect();
+            self.predicted = true;
+            return;
+        }
+
+        let reply = match self.queue.pop() {
+            Some(m) => m,
+            None => return,
+        };
+        let mut after = history.clone();
+        after.make_move(reply);
+        let b = after.board();
+        let state = get_state_with_history(&after, player_white);
+        let scores = score_moves_in_state(
+            &mut models.head_for(&b, player_white),
+            &b,
+            &state,
+            player_white,
+        );
+        self.pondered
+            .insert(b.get_hash(), Pondered { state, scores });
+    }
+
+    /**
+     * [take(history)] stops pondering and returns what was pondered of the
+     * latest position of [history], if it was reached by one of the replies
+     * pondered.
+     */
+    pub fn take(&mut self, history: &PositionHistory) -> Option<Pondered> {
+        let waiting = self.waiting.take()?;
+        let replied = history
+            .earlier_board(1)
+            .map_or(false, |b| b.get_hash() == waiting.board().get_hash());
+        let pondered = if replied {
+            self.pondered.remove(&history.board().get_hash())
+        } else {
+            None
+        };
+        let count = self.pondered.len() + pondered.is_some() as usize;
+        self.queue.clear();
+        self.pondered.clear();
+
+        match pondered {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        };
+        debug!(
+            event = "ponder",
+            hit = pondered.is_some(),
+            pondered = count,
+            "Opponent replied"
+        );
+        return pondered;
+    }
+}
@@ -0,0 +1,442 @@
+/**
+ * Utility module for measuring and training the networks on tactical puzzle
+ * suites, whose known solutions give an objective measure of progress that
+ * does not depend on noisy self-play results. Suites are EPD files, whose
+ * records give the accepted best moves ("bm") or moves to avoid ("am") of a
+ * position, or Lichess puzzle csv files (PuzzleId,FEN,Moves,Rating,...), such
+ * as the Lichess puzzle dump (decompressed on the fly if it ends in .zst),
+ * where the opponent's move is played first and the solver must then find
+ * every one of its moves of the solution line. A move that checkmates is
+ * always accepted. Configured by the "puzzles" object in config.json, e.g.
+ * {"path": "puzzles.csv", "limit": 1000, "min_rating": 1200,
+ *  "max_rating": 2200, "depth": 0, "epochs": 1, "holdout": 0.2},
+ * which scores at most 1000 puzzles rated 1200 to 2200 by the move the policy
+ * network scores highest ("depth" searches that many plies with alpha-beta
+ * instead, and defaults to the "alphabeta" depth when set). When training,
+ * the solution moves of all but the last "holdout" fraction of the puzzles
+ * are fit to the discounted value of the win they lead to over "epochs"
+ * passes, and accuracy is measured on the held out puzzles before and after.
+ */
+use crate::discount;
+use crate::error::{BotError, BotResult};
+use crate::history::MoveCounters;
+use crate::mdp::{best_scored_move, evaluate_position, get_action, get_state, WIN_REWARD};
+use crate::models::ModelRegistry;
+use crate::output_scaling::OutputScaling;
+use crate::repertoire::parse_move;
+use crate::search::alphabeta::{self, AlphaBetaSettings};
+use crate::search::transposition::TranspositionTable;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+const DEFAULT_EPOCHS: u64 = 1;
+const DEFAULT_HOLDOUT: f64 = 0.2;
+
+// Which puzzles are used and how they are solved and trained on
+#[derive(Clone, Debug)]
+pub struct PuzzleSettings {
+    pub path: Option<String>,
+    pub limit: Option<usize>,
+    pub min_rating: Option<i64>,
+    pub max_rating: Option<i64>,
+    pub depth: usize, // alpha-beta plies, or 0 for the policy alone
+    pub epochs: usize,
+    pub holdout: f64, // fraction of the puzzles never trained on
+}
+
+// A position with the moves that solve it
+#[derive(Clone, Debug)]
+pub struct Puzzle {
+    pub id: String,
+    pub board: Board,
+    pub counters: MoveCounters, // of the board, as in the puzzle's FEN
+    pub line: Vec<ChessMove>,   // the solver's moves alternating with the replies
+    pub alternatives: Vec<ChessMove>, // other accepted first moves
+    pub avoid: Vec<ChessMove>,  // first moves that fail, if no line is known
+    pub rating: Option<i64>,
+}
+
+// How many puzzles and solver moves were found
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PuzzleReport {
+    pub puzzles: usize,
+    pub solved: usize,
+    pub moves: usize,
+    pub correct_moves: usize,
+}
+
+impl PuzzleSettings {
+    /**
+     * [from_config(config)] reads the puzzle settings given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> PuzzleSettings {
+        let settings = &config["puzzles"];
+        let depth = match settings["depth"].as_u64() {
+            Some(n) => n,
+            None => config["alphabeta"]["depth"].as_u64().unwrap_or(0),
+        };
+        return PuzzleSettings {
+            path: settings["path"].as_str().map(|s| s.to_string()),
+            limit: settings["limit"].as_u64().map(|n| n as usize),
+            min_rating: settings["min_rating"].as_i64(),
+            max_rating: settings["max_rating"].as_i64(),
+            depth: depth as usize,
+            epochs: settings["epochs"].as_u64().unwrap_or(DEFAULT_EPOCHS).max(1) as usize,
+            holdout: settings["holdout"]
+                .as_f64()
+                .unwrap_or(DEFAULT_HOLDOUT)
+                .clamp(0., 1.),
+        };
+    }
+
+    /**
+     * [accepts(puzzle)] returns whether [puzzle] is rated within the
+     * configured range. Unrated puzzles are always accepted.
+     */
+    pub fn accepts(&self, puzzle: &Puzzle) -> bool {
+        return match puzzle.rating {
+            Some(r) => {
+                self.min_rating.map_or(true, |min| r >= min)
+                    && self.max_rating.map_or(true, |max| r <= max)
+            }
+            None => true,
+        };
+    }
+}
+
+impl PuzzleReport {
+    /**
+     * [accuracy()] returns the fraction of puzzles solved.
+     */
+    pub fn accuracy(&self) -> f64 {
+        return self.solved as f64 / self.puzzles.max(1) as f64;
+    }
+
+    /**
+     * [move_accuracy()] returns the fraction of solver moves found.
+     */
+    pub fn move_accuracy(&self) -> f64 {
+        return self.correct_moves as f64 / self.moves.max(1) as f64;
+    }
+
+    /**
+     * [summary()] describes the report for the logs.
+     */
+    pub fn summary(&self) -> String {
+        return format!(
+            "solved {}/{} puzzles ({:.1}%), found {}/{} moves ({:.1}%)",
+            self.solved,
+            self.puzzles,
+            100. * self.accuracy(),
+            self.correct_moves,
+            self.moves,
+            100. * self.move_accuracy()
+        );
+    }
+}
+
+/**
+ * [parse_lichess_puzzle(line)] parses a [line] of a Lichess puzzle csv file,
+ * playing the opponent's first move so that the solver is to move, or returns
+ * None if it is a header or malformed.
+ */
+pub fn parse_lichess_puzzle(line: &str) -> Option<Puzzle> {
+    let fields: Vec<&str> = line.split(',').collect();
+    if fields.len() < 3 || fields[0].eq("PuzzleId") {
+        return None;
+    }
+
+    let start = Board::from_str(fields[1]).ok()?;
+    let mut moves = Vec::new();
+    let mut board = start;
+    for token in fields[2].split_whitespace() {
+        let m = parse_move(&board, token)?;
+        moves.push(m);
+        board = board.make_move_new(m);
+    }
+    if moves.len() < 2 {
+        return None;
+    }
+
+    return Some(Puzzle {
+        id: fields[0].to_string(),
+        board: start.make_move_new(moves[0]),
+        counters: MoveCounters::from_fen(fields[1]).after(&start, moves[0]),
+        line: moves[1..].to_vec(),
+        alternatives: Vec::new(),
+        avoid: Vec::new(),
+        rating: fields.get(3).and_then(|r| r.parse().ok()),
+    });
+}
+
+/**
+ * [parse_epd_puzzle(line, index)] parses an EPD record [line], the puzzle
+ * numbered [index] in its file, or returns None if it is malformed or gives
+ * neither best moves nor moves to avoid.
+ */
+pub fn parse_epd_puzzle(line: &str, index: usize) -> Option<Puzzle> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let board = Board::from_str(&(fields[..4].join(" ") + " 0 1")).ok()?;
+
+    let mut puzzle = Puzzle {
+        id: format!("{}", index + 1),
+        board,
+        counters: MoveCounters::default(),
+        line: Vec::new(),
+        alternatives: Vec::new(),
+        avoid: Vec::new(),
+        rating: None,
+    };
+    let operations = fields[4..].join(" ");
+    for operation in operations.split(';') {
+        let mut tokens = operation.split_whitespace();
+        let opcode = match tokens.next() {
+            Some(op) => op,
+            None => continue,
+        };
+        let operands: Vec<&str> = tokens.collect();
+        let moves = || -> Vec<ChessMove> {
+            return operands
+                .iter()
+                .filter_map(|t| parse_move(&board, t))
+                .collect();
+        };
+        match opcode {
+            "bm" => {
+                let best = moves();
+                if let Some((first, rest)) = best.split_first() {
+                    puzzle.line = vec![*first];
+                    puzzle.alternatives = rest.to_vec();
+                }
+            }
+            "am" => puzzle.avoid = moves(),
+            "id" => puzzle.id = operands.join(" ").trim_matches('"').to_string(),
+            _ => (),
+        };
+    }
+    if puzzle.line.len() == 0 && puzzle.avoid.len() == 0 {
+        return None;
+    }
+
+    return Some(puzzle);
+}
+
+/**
+ * [load_puzzles(path, settings)] reads the puzzles of the suite at [path]
+ * accepted by [settings], up to its limit, as a Lichess puzzle csv file if
+ * its name contains .csv and as an EPD file otherwise.
+ */
+pub fn load_puzzles(path: &str, settings: &PuzzleSettings) -> io::Result<Vec<Puzzle>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = if path.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+    let lichess = path.contains(".csv");
+
+    let mut puzzles = Vec::new();
+    for (index, line) in BufReader::new(reader).lines().enumerate() {
+        if settings.limit.map_or(false, |n| puzzles.len() >= n) {
+            break;
+        }
+        let line = line?;
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+
+        let puzzle = if lichess {
+            parse_lichess_puzzle(line)
+        } else {
+            parse_epd_puzzle(line, index)
+        };
+        match puzzle {
+            Some(p) if settings.accepts(&p) => puzzles.push(p),
+            Some(_) => (),
+            None if index > 0 || !lichess => warn!("Skipping invalid puzzle {}", line),
+            None => (),
+        };
+    }
+
+    return Ok(puzzles);
+}
+
+/**
+ * [accepted(puzzle, ply, b, m)] returns whether the solver's move [m] in board
+ * [b], reached after [ply] plies of the solution of [puzzle], solves it.
+ */
+fn accepted(puzzle: &Puzzle, ply: usize, b: &Board, m: ChessMove) -> bool {
+    if b.make_move_new(m).status() == BoardStatus::Checkmate {
+        return true;
+    }
+    if puzzle.line.len() == 0 {
+        return !puzzle.avoid.contains(&m);
+    }
+    return puzzle.line[ply] == m || (ply == 0 && puzzle.alternatives.contains(&m));
+}
+
+/**
+ * [choose_move(models, b, counters, settings, alphabeta, table)] returns the
+ * move the networks in [models] play in board [b], reached with move
+ * [counters], searching with [alphabeta] if the [settings] set a depth, or
+ * None if there are no legal moves.
+ */
+fn choose_move(
+    models: &mut ModelRegistry,
+    b: &Board,
+    counters: MoveCounters,
+    settings: &PuzzleSettings,
+    alphabeta: &AlphaBetaSettings,
+    table: &mut TranspositionTable,
+) -> Option<ChessMove> {
+    let player_white = b.side_to_move() == Color::White;
+    let nn = &mut models.head_for(b, player_white);
+    if settings.depth > 0 {
+        return alphabeta::search(nn, b, counters, alphabeta, table, None, None).map(|r| r.best);
+    }
+    return best_scored_move(&evaluate_position(b, counters, nn, player_white)).map(|(m, _)| m);
+}
+
+/**
+ * [score_puzzles(config, models, puzzles)] asks the networks in [models] for
+ * their moves in each of [puzzles], as set by the parsed [config], playing
+ * out the solution lines while they keep finding the solver's moves, and
+ * reports how many were solved.
+ */
+pub fn score_puzzles(
+    config: &Value,
+    models: &mut ModelRegistry,
+    puzzles: &[Puzzle],
+) -> PuzzleReport {
+    let settings = PuzzleSettings::from_config(config);
+    let mut alphabeta = AlphaBetaSettings::from_config(config);
+    alphabeta.depth = settings.depth.max(1);
+    let mut table = TranspositionTable::from_config(config);
+
+    let mut report = PuzzleReport::default();
+    for puzzle in puzzles {
+        let mut board = puzzle.board;
+        let mut counters = puzzle.counters;
+        let mut solved = true;
+        let mut ply = 0;
+        loop {
+            report.moves += 1;
+            let chosen = choose_move(models, &board, counters, &settings, &alphabeta, &mut table);
+            let correct = match chosen {
+                Some(m) => accepted(puzzle, ply, &board, m),
+                None => false,
+            };
+            if !correct {
+                solved = false;
+                break;
+            }
+            report.correct_moves += 1;
+
+            // Play on with the solution line and its reply
+            if ply + 2 >= puzzle.line.len() {
+                break;
+            }
+            for m in &puzzle.line[ply..ply + 2] {
+                counters = counters.after(&board, *m);
+                board = board.make_move_new(*m);
+            }
+            ply += 2;
+        }
+
+        report.puzzles += 1;
+        if solved {
+            report.solved += 1;
+        }
+    }
+
+    return report;
+}
+
+/**
+ * [train_on_puzzles(config, models, puzzles)] fits the networks in [models]
+ * to each solver move of the solution lines of [puzzles], targeting the
+ * value of the win it leads to, discounted by the solver moves still to
+ * come and converted into network outputs, over the configured number of
+ * epochs. Returns the number of moves fit.
+ */
+pub fn train_on_puzzles(config: &Value, models: &mut ModelRegistry, puzzles: &[Puzzle]) -> usize {
+    let settings = PuzzleSettings::from_config(config);
+    let scaling = OutputScaling::from_config(config);
+    let gamma = discount(config);
+
+    let mut fit = 0;
+    for epoch in 0..settings.epochs {
+        for puzzle in puzzles {
+            let mut board = puzzle.board;
+            let mut counters = puzzle.counters;
+            let solver_moves = (puzzle.line.len() + 1) / 2;
+            for (ply, m) in puzzle.line.iter().enumerate() {
+                if ply % 2 == 0 {
+                    let player_white = board.side_to_move() == Color::White;
+                    let remaining = solver_moves - 1 - ply / 2;
+                    let target = scaling.squash(WIN_REWARD * gamma.powi(remaining as i32));
+
+                    let state = get_state(&board, counters, player_white);
+                    let mut sa = models.stats(player_white).normalized(&state);
+                    sa.append(&mut get_action(*m, player_white));
+                    models.network(player_white).fit(&sa[..], &[target]);
+                    fit += 1;
+                }
+                counters = counters.after(&board, *m);
+                board = board.make_move_new(*m);
+            }
+        }
+        info!("Finished puzzle training epoch {}", epoch + 1);
+    }
+
+    return fit;
+}
+
+/**
+ * [run_puzzles(config, path, train)] scores the networks given by the parsed
+ * [config] on the puzzle suite at [path] (or the configured one if None),
+ * first training them on the solutions of all but the held out puzzles and
+ * saving them if [train]. Returns the report on the puzzles scored, or an
+ * error if the suite or the networks cannot be read or saved.
+ */
+pub fn run_puzzles(config: &Value, path: Option<&str>, train: bool) -> BotResult<PuzzleReport> {
+    let settings = PuzzleSettings::from_config(config);
+    let path = match path.or(settings.path.as_deref()) {
+        Some(p) => p.to_string(),
+        None => return Err(BotError::Config("no puzzle suite given".to_string())),
+    };
+    let puzzles = load_puzzles(&path, &settings)?;
+    info!("Loaded {} puzzles from {}", puzzles.len(), path);
+    let mut models = ModelRegistry::from_config(config)?;
+
+    if !train {
+        let report = score_puzzles(config, &mut models, &puzzles);
+        println!("{}", report.summary());
+        return Ok(report);
+    }
+
+    let split = puzzles.len() - (puzzles.len() as f64 * settings.holdout).round() as usize;
+    let (training, validation) = puzzles.split_at(split);
+    println!(
+        "Before training: {}",
+        score_puzzles(config, &mut models, validation).summary()
+    );
+    let fit = train_on_puzzles(config, &mut models, training);
+    models.save(true)?;
+    models.save(false)?;
+    println!("Fit {} solution moves of {} puzzles", fit, training.len());
+
+    let report = score_puzzles(config, &mut models, validation);
+    println!("After training: {}", report.summary());
+    return Ok(report);
+}
@@ -0,0 +1,127 @@
+/**
+ * Utility module for the value function interface that move scoring is
+ * written against, so that the network library can be swapped without
+ * touching the MDP. A QFunction maps encoded state-action pairs to Q-values,
+ * a whole batch at a time, and can be trained on a batch of Bellman labels.
+ * neuroflow networks implement it by handling one input at a time, and when
+ * built with the "burn" feature a burn network can be used instead, selected
+ * by the "q_function" object in config.json, e.g. {"backend": "burn",
+ * "learning_rate": 0.001}, and "neuroflow" otherwise. A neuroflow network with
+ * a policy head is wrapped so that it answers for state-action pairs too.
+ * Only the UCI engine loads its network through the configured backend for
+ * now: Lichess games, self-play and training always use neuroflow networks,
+ * and training refuses to start with another backend configured rather than
+ * silently training a network that is not the one served.
+ */
+#[cfg(feature = "burn")]
+use crate::burn_network::BurnNetwork;
+use crate::error::{BotError, BotResult};
+use crate::models::{read_network, write_network};
+use crate::normalization::{read_stats, Normalized};
+use crate::policy_head::{is_policy_head, PolicyHead};
+
+use neuroflow::FeedForward;
+use serde_json::Value;
+
+// A function approximating the Q-value of encoded state-action pairs
+pub trait QFunction {
+    /**
+     * [predict_batch(inputs)] returns the Q-value of each of the encoded
+     * state-action pairs [inputs].
+     */
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64>;
+
+    /**
+     * [train_batch(inputs, targets)] fits the Q-values of the encoded
+     * state-action pairs [inputs] towards [targets].
+     */
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]);
+
+    /**
+     * [save(path)] saves the function to [path].
+     */
+    fn save(&self, path: &str) -> BotResult<()>;
+
+    /**
+     * [load(path)] loads a function saved to [path].
+     */
+    fn load(path: &str) -> BotResult<Self>
+    where
+        Self: Sized;
+
+    /**
+     * [predict(input)] returns the Q-value of the encoded state-action pair
+     * [input].
+     */
+    fn predict(&mut self, input: &[f64]) -> f64 {
+        return self.predict_batch(&[input.to_vec()])[0];
+    }
+}
+
+impl QFunction for FeedForward {
+    fn predict_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        return inputs.iter().map(|x| self.calc(x)[0]).collect();
+    }
+
+    fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[f64]) {
+        for (x, y) in inputs.iter().zip(targets) {
+            self.fit(x, &[*y]);
+        }
+    }
+
+    fn save(&self, path: &str) -> BotResult<()> {
+        return write_network(self, path);
+    }
+
+    fn load(path: &str) -> BotResult<FeedForward> {
+        return read_network(path);
+    }
+
+    fn predict(&mut self, input: &[f64]) -> f64 {
+        return self.calc(input)[0];
+    }
+}
+
+/**
+ * [check_training_backend(config)] returns an error unless the backend given
+ * by the parsed [config] is neuroflow, the only one that can be trained.
+ */
+pub fn check_training_backend(config: &Value) -> BotResult<()> {
+    return match config["q_function"]["backend"].as_str() {
+        None | Some("neuroflow") => Ok(()),
+        Some(backend) => Err(BotError::Config(format!(
+            "only neuroflow networks can be trained, not the {} backend",
+            backend
+        ))),
+    };
+}
+
+/**
+ * [load_q_function(config, path)] loads the value function saved at [path]
+ * with the backend given by the parsed [config], normalizing its inputs by the
+ * feature statistics saved with it.
+ */
+pub fn load_q_function(config: &Value, path: &str) -> BotResult<Box<dyn QFunction + Send>> {
+    return match config["q_function"]["backend"]
+        .as_str()
+        .unwrap_or("neuroflow")
+    {
+        "neuroflow" => {
+            let (nn, stats) = (read_network(path)?, read_stats(path)?);
+            if is_policy_head(&nn) {
+                return Ok(Box::new(Normalized::new(PolicyHead::new(nn), stats)));
+            }
+            Ok(Box::new(Normalized::new(nn, stats)))
+        }
+        #[cfg(feature = "burn")]
+        "burn" => Ok(Box::new(Normalized::new(
+            BurnNetwork::load_with(config, path)?,
+            read_stats(path)?,
+        ))),
+        #[cfg(not(feature = "burn"))]
+        "burn" => Err(BotError::Config(
+            "the burn backend needs the burn feature".to_string(),
+        )),
+        backend => Err(BotError::Config(format!("unknown backend {}", backend))),
+    };
+}
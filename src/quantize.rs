@@ -0,0 +1,306 @@
+/**
+ * Utility module for an int8 quantized copy of a policy network, used to speed
+ * up scoring every legal move on CPU. Weights are quantized per neuron and
+ * activations per layer to symmetric int8, with dot products accumulated in
+ * i32. The quantized network is built from the weights of the neuroflow
+ * network, so every quantized network should be verified against the float
+ * network before use. A quantized network keeps the feature statistics of the
+ * network it came from, which its inputs are normalized by.
+ */
+use crate::decision::{MoveDecision, MoveSource};
+use crate::mdp::{get_action, get_state};
+use crate::normalization::FeatureStats;
+use crate::sampling::random_game_position;
+use crate::weights::{activate, Activation, NetworkWeights};
+
+use chess::{Board, ChessMove, MoveGen};
+use neuroflow::FeedForward;
+use rand::Rng;
+use serde_json::Value;
+use std::time::Instant;
+use tracing::{info, warn};
+
+// Longest random game played to sample a probe position
+const MAX_PROBE_PLIES: usize = 120;
+
+// A layer of neurons with int8 weights
+#[derive(Clone, Debug)]
+pub struct QuantizedLayer {
+    weights: Vec<Vec<i8>>,
+    scales: Vec<f64>,
+    biases: Vec<f64>,
+    activation: Activation,
+}
+
+// An int8 quantized copy of a policy network
+#[derive(Clone, Debug)]
+pub struct QuantizedNetwork {
+    layers: Vec<QuantizedLayer>,
+    pub stats: FeatureStats, // feature statistics of the float network
+}
+
+// How closely a quantized network matches the float network it came from
+#[derive(Clone, Debug)]
+pub struct QuantizationReport {
+    pub max_error: f64,
+    pub mean_error: f64,
+    pub best_move_agreement: f64,
+}
+
+/**
+ * [quantize(values)] quantizes [values] to symmetric int8, returning the
+ * quantized values along with the scale that converts them back.
+ */
+fn quantize(values: &[f64]) -> (Vec<i8>, f64) {
+    let max_abs = values.iter().fold(0., |m: f64, v| m.max(v.abs()));
+    if max_abs == 0. {
+        return (vec![0; values.len()], 1.);
+    }
+
+    let scale = max_abs / 127.;
+    let quantized = values
+        .iter()
+        .map(|v| (v / scale).round().clamp(-127., 127.) as i8)
+        .collect();
+
+    return (quantized, scale);
+}
+
+impl QuantizedLayer {
+    /**
+     * [calc(x)] returns the outputs of the layer for inputs [x].
+     */
+    fn calc(&self, x: &[f64]) -> Vec<f64> {
+        let (x_quantized, x_scale) = quantize(x);
+
+        let mut outputs = Vec::with_capacity(self.weights.len());
+        for i in 0..self.weights.len() {
+            let mut sum: i32 = 0;
+            for (w, xq) in self.weights[i].iter().zip(x_quantized.iter()) {
+                sum += (*w as i32) * (*xq as i32);
+            }
+            let v = sum as f64 * self.scales[i] * x_scale + self.biases[i];
+            outputs.push(activate(self.activation, v));
+        }
+
+        return outputs;
+    }
+}
+
+impl QuantizedNetwork {
+    /**
+     * [from_network(nn, stats)] builds the int8 quantized copy of network
+     * [nn], whose inputs are normalized by its feature [stats].
+     */
+    pub fn from_network(nn: &FeedForward, stats: FeatureStats) -> QuantizedNetwork {
+        let mut layers = Vec::new();
+        for layer in NetworkWeights::from_network(nn).layers {
+            let mut weights = Vec::new();
+            let mut scales = Vec::new();
+            for w in &layer.weights {
+                let (w_quantized, scale) = quantize(w);
+                weights.push(w_quantized);
+                scales.push(scale);
+            }
+            layers.push(QuantizedLayer {
+                weights,
+                scales,
+                biases: layer.biases,
+                activation: layer.activation,
+            });
+        }
+
+        return QuantizedNetwork { layers, stats };
+    }
+
+    /**
+     * [calc(x)] returns the output of the network for inputs [x], which are
+     * already normalized.
+     */
+    pub fn calc(&self, x: &[f64]) -> f64 {
+        let mut y = x.to_vec();
+        for layer in &self.layers {
+            y = layer.calc(&y);
+        }
+
+        return y[0];
+    }
+}
+
+/**
+ * [score_moves_quantized(q, b, state, player_white)] returns every legal move
+ * in board [b], encoded as [state], with its Q-value under quantized network
+ * [q] depending on whether the player is white.
+ */
+pub fn score_moves_quantized(
+    q: &QuantizedNetwork,
+    b: &Board,
+    state: &[f64],
+    player_white: bool,
+) -> Vec<(ChessMove, f64)> {
+    let state = q.stats.normalized(state);
+
+    let mut scores = Vec::new();
+    for m in MoveGen::new_legal(b) {
+        let mut sa = state.clone();
+        sa.append(&mut get_action(m, player_white));
+        scores.push((m, q.calc(&sa[..])));
+    }
+
+    return scores;
+}
+
+/**
+ * [move_by_quantized(q, b, state, player_white, bonus, deadline)] selects the
+ * move in board [b], encoded as [state], with the highest Q-value under
+ * quantized network [q] depending on whether the player is white, with
+ * [bonus(b, m)] added to the Q-value of each
+ * move [m], and returns the decision with the scores it was made on. Once
+ * [deadline] passes the best move evaluated so far is returned. Alternatively
+ * if there are no legal moves it returns None.
+ */
+pub fn move_by_quantized(
+    q: &QuantizedNetwork,
+    b: &Board,
+    state: &[f64],
+    player_white: bool,
+    bonus: impl Fn(&Board, ChessMove) -> f64,
+    deadline: Instant,
+) -> Option<MoveDecision> {
+    let state = q.stats.normalized(state);
+
+    let mut high_score = f64::NEG_INFINITY;
+    let mut best_move = None;
+    let mut scores = Vec::new();
+    for m in MoveGen::new_legal(b) {
+        if best_move.is_some() && Instant::now() >= deadline {
+            warn!("Move selection timed out, playing the best move so far");
+            break;
+        }
+
+        let mut sa = state.clone();
+        sa.append(&mut get_action(m, player_white));
+        let score = q.calc(&sa[..]) + bonus(b, m);
+        scores.push((m, score));
+        if score >= high_score {
+            high_score = score;
+            best_move = Some(m);
+        }
+    }
+
+    return best_move.map(|m| MoveDecision::from_scores(&scores, m, MoveSource::Policy));
+}
+
+/**
+ * [verify(nn, q, positions)] compares the Q-values of network [nn] and its
+ * quantized copy [q] over every legal move of [positions] randomly sampled
+ * probe positions, both normalizing their inputs by the statistics of [q],
+ * reporting the errors and how often both pick the same best move.
+ */
+pub fn verify(nn: &mut FeedForward, q: &QuantizedNetwork, positions: usize) -> QuantizationReport {
+    let mut max_error: f64 = 0.;
+    let mut total_error = 0.;
+    let mut count = 0;
+    let mut agreements = 0;
+    let mut probed = 0;
+    for _ in 0..positions {
+        let (board, counters) = random_game_position(MAX_PROBE_PLIES);
+        let player_white = rand::thread_rng().gen_bool(0.5);
+        let raw_state = get_state(&board, counters, player_white);
+        let state = q.stats.normalized(&raw_state);
+
+        let mut float_best = (None, f64::NEG_INFINITY);
+        let mut quantized_best = (None, f64::NEG_INFINITY);
+        for (m, quantized_score) in score_moves_quantized(q, &board, &raw_state, player_white) {
+            let mut sa = state.clone();
+            sa.append(&mut get_action(m, player_white));
+            let float_score = nn.calc(&sa[..])[0];
+
+            let error = (float_score - quantized_score).abs();
+            max_error = max_error.max(error);
+            total_error += error;
+            count += 1;
+            if float_score >= float_best.1 {
+                float_best = (Some(m), float_score);
+            }
+            if quantized_score >= quantized_best.1 {
+                quantized_best = (Some(m), quantized_score);
+            }
+        }
+
+        if float_best.0.is_some() {
+            probed += 1;
+            if float_best.0 == quantized_best.0 {
+                agreements += 1;
+            }
+        }
+    }
+
+    return QuantizationReport {
+        max_error,
+        mean_error: total_error / count.max(1) as f64,
+        best_move_agreement: agreements as f64 / probed.max(1) as f64,
+    };
+}
+
+// Settings for using quantized networks in play, read from the
+// "quantized_inference" object in config.json, e.g.
+// {"enabled": true, "probe_positions": 50, "max_error": 1.0}
+pub struct QuantizedInference {
+    pub enabled: bool,
+    pub probe_positions: usize,
+    pub max_error: f64,
+    cached: Option<(String, QuantizedNetwork)>, // by where its network is saved
+}
+
+impl QuantizedInference {
+    /**
+     * [from_config(config)] reads the quantized inference settings from the
+     * parsed [config], which is disabled unless configured.
+     */
+    pub fn from_config(config: &Value) -> QuantizedInference {
+        let settings = &config["quantized_inference"];
+        return QuantizedInference {
+            enabled: settings["enabled"].as_bool().unwrap_or(false),
+            probe_positions: settings["probe_positions"].as_u64().unwrap_or(50) as usize,
+            max_error: settings["max_error"].as_f64().unwrap_or(1.),
+            cached: None,
+        };
+    }
+
+    /**
+     * [prepare(nn, stats, path)] returns the quantized copy of network [nn],
+     * whose inputs are normalized by its feature [stats], saved at [path], to
+     * play with, or None if quantized inference is disabled. The
+     * copy is kept until a network saved elsewhere is prepared, e.g. once the
+     * endgame network takes over or another checkpoint is served, so [nn] is
+     * only quantized again when it changes. Each new copy is verified against
+     * [nn], and quantized inference is disabled if its mean error is above
+     * the configured maximum.
+     */
+    pub fn prepare(
+        &mut self,
+        nn: &mut FeedForward,
+        stats: FeatureStats,
+        path: &str,
+    ) -> Option<&QuantizedNetwork> {
+        if !self.enabled {
+            return None;
+        }
+
+        if self.cached.as_ref().map_or(true, |(p, _)| p != path) {
+            let q = QuantizedNetwork::from_network(nn, stats);
+            let report = verify(nn, &q, self.probe_positions);
+            info!("Quantized network verification for {}: {:?}", path, report);
+            if report.mean_error > self.max_error {
+                warn!("Quantized network is too inaccurate, using float network.");
+                self.enabled = false;
+                self.cached = None;
+                return None;
+            }
+            self.cached = Some((path.to_string(), q));
+        }
+
+        return self.cached.as_ref().map(|(_, q)| q);
+    }
+}
@@ -0,0 +1,184 @@
+/**
+ * Utility module for recovering the Lichess games the bot was playing when its
+ * process died, so that restarting it carries on with their experiences
+ * instead of starting over. While a game is played, every experience recorded
+ * is appended to <dir>/<game id>.jsonl, and the bot's latest move is saved to
+ * <dir>/<game id>.pending.json along with the experience it starts, before the
+ * move is posted. A game resumed on restart reloads both, and keeps the
+ * pending experience if its move was played, or posts the move again if it
+ * was not. The experiences file is removed whenever the game loop hands its
+ * experiences over, and the pending move once the game is over. Configured by
+ * "recovery_dir" in the "lichess" object of config.json, "recovery" by
+ * default.
+ */
+use crate::mdp::Experience;
+use crate::replay::{experience_from_json, experience_to_json};
+
+use chess::Board;
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const DEFAULT_RECOVERY_DIR: &str = "recovery";
+
+// Where the state of the games in progress is saved
+#[derive(Clone, Debug)]
+pub struct GameRecovery {
+    pub dir: String,
+}
+
+// The bot's latest move in a game, with the experience it starts
+#[derive(Clone, Debug)]
+pub struct PendingMove {
+    pub ply: usize,
+    pub uci: String,
+    pub board: Board, // where the move was played
+    pub experience: Experience,
+}
+
+// What was saved of a game before the process died
+#[derive(Clone, Debug, Default)]
+pub struct RecoveredGame {
+    pub experiences: Vec<Experience>,
+    pub pending: Option<PendingMove>,
+}
+
+impl GameRecovery {
+    /**
+     * [from_config(config)] reads where games in progress are saved from the
+     * parsed [config].
+     */
+    pub fn from_config(config: &Value) -> GameRecovery {
+        return GameRecovery {
+            dir: config["lichess"]["recovery_dir"]
+                .as_str()
+                .unwrap_or(DEFAULT_RECOVERY_DIR)
+                .to_string(),
+        };
+    }
+
+    /**
+     * [experiences_path(game_id)] returns where the experiences of game
+     * [game_id] are saved.
+     */
+    fn experiences_path(&self, game_id: &str) -> String {
+        return format!("{}/{}.jsonl", self.dir, game_id);
+    }
+
+    /**
+     * [pending_path(game_id)] returns where the bot's latest move in game
+     * [game_id] is saved.
+     */
+    fn pending_path(&self, game_id: &str) -> String {
+        return format!("{}/{}.pending.json", self.dir, game_id);
+    }
+
+    /**
+     * [record_experience(game_id, e, player_white)] appends experience [e] of
+     * the player whose color is given by [player_white] to the saved state of
+     * game [game_id].
+     */
+    pub fn record_experience(
+        &self,
+        game_id: &str,
+        e: &Experience,
+        player_white: bool,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.experiences_path(game_id))?;
+        return writeln!(file, "{}", experience_to_json(e, player_white));
+    }
+
+    /**
+     * [record_pending(game_id, pending, player_white)] saves [pending] as the
+     * latest move of the player whose color is given by [player_white] in
+     * game [game_id], atomically so a crash never leaves it partially
+     * written.
+     */
+    pub fn record_pending(
+        &self,
+        game_id: &str,
+        pending: &PendingMove,
+        player_white: bool,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let saved = json!({
+            "ply": pending.ply,
+            "move": pending.uci,
+            "board": pending.board.to_string(),
+            "experience": experience_to_json(&pending.experience, player_white),
+        });
+        let path = self.pending_path(game_id);
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, saved.to_string())?;
+        return fs::rename(&tmp_path, &path);
+    }
+
+    /**
+     * [load(game_id)] reads what was saved of game [game_id], or returns None
+     * if nothing was.
+     */
+    pub fn load(&self, game_id: &str) -> Option<RecoveredGame> {
+        let experiences: Vec<Experience> = fs::read_to_string(self.experiences_path(game_id))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .map(|json| experience_from_json(&json).0)
+            .collect();
+
+        let pending = fs::read_to_string(self.pending_path(game_id))
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .and_then(|saved| {
+                Some(PendingMove {
+                    ply: saved["ply"].as_u64()? as usize,
+                    uci: saved["move"].as_str()?.to_string(),
+                    board: Board::from_str(saved["board"].as_str()?).ok()?,
+                    experience: experience_from_json(&saved["experience"]).0,
+                })
+            });
+        if experiences.len() == 0 && pending.is_none() {
+            return None;
+        }
+
+        return Some(RecoveredGame {
+            experiences,
+            pending,
+        });
+    }
+
+    /**
+     * [release(game_id, over)] removes the saved experiences of game
+     * [game_id] once they were handed over, along with its pending move if
+     * the game is [over].
+     */
+    pub fn release(&self, game_id: &str, over: bool) {
+        let _ = fs::remove_file(self.experiences_path(game_id));
+        if over {
+            let _ = fs::remove_file(self.pending_path(game_id));
+        }
+    }
+}
+
+impl RecoveredGame {
+    /**
+     * [consistent_with(moves_str)] returns whether the saved state fits the
+     * game whose space separated uci moves are [moves_str]: the pending move
+     * was either played at its ply or is still to be played there.
+     */
+    pub fn consistent_with(&self, moves_str: &str) -> bool {
+        let pending = match &self.pending {
+            Some(p) => p,
+            None => return true,
+        };
+        let played = moves_str.split_whitespace().count();
+        if pending.ply == played + 1 {
+            return true;
+        }
+        return moves_str.split_whitespace().nth(pending.ply - 1) == Some(pending.uci.as_str());
+    }
+}
@@ -0,0 +1,192 @@
+/**
+ * Utility module for a user-curated opening repertoire that the bot must
+ * follow for its first moves whenever the position matches. The "repertoire"
+ * object in config.json gives a file per color along with how many moves it
+ * applies for, e.g.
+ * {"white": "white.pgn", "black": "black.txt", "max_moves": 8}.
+ * Files are either PGN, with one line per game, or plain move lists with one
+ * line per variation. Moves may be in SAN or uci format.
+ */
+use chess::{Board, ChessMove, Color};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use tracing::warn;
+
+const DEFAULT_MAX_MOVES: usize = 10;
+
+// The repertoire moves for each color, keyed by the hash of the position
+pub struct Repertoire {
+    white: HashMap<u64, ChessMove>,
+    black: HashMap<u64, ChessMove>,
+    pub max_moves: usize,
+}
+
+/**
+ * [parse_move(b, token)] parses the move [token] in SAN or uci format for board
+ * [b], returning None if it is not a legal move.
+ */
+pub fn parse_move(b: &Board, token: &str) -> Option<ChessMove> {
+    if let Ok(m) = ChessMove::from_san(b, token) {
+        return Some(m);
+    }
+    match ChessMove::from_str(token) {
+        Ok(m) if b.legal(m) => Some(m),
+        _ => None,
+    }
+}
+
+/**
+ * [strip_comments(text)] removes all {...} comments from PGN [text].
+ */
+pub fn strip_comments(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_comment = false;
+    for c in text.chars() {
+        match c {
+            '{' => in_comment = true,
+            '}' => in_comment = false,
+            _ if !in_comment => stripped.push(c),
+            _ => (),
+        };
+    }
+
+    return stripped;
+}
+
+/**
+ * [split_lines(text)] splits the contents [text] of a repertoire file into the
+ * move text of each of its lines. PGN files (detected by their tag pairs)
+ * have one line per game, and move list files one line per text line.
+ */
+fn split_lines(text: &str) -> Vec<String> {
+    let text = strip_comments(text);
+    if !text.lines().any(|l| l.trim_start().starts_with("[")) {
+        return text.lines().map(|l| l.to_string()).collect();
+    }
+
+    let mut lines = Vec::new();
+    let mut curr = String::new();
+    for l in text.lines() {
+        if l.trim_start().starts_with("[") {
+            if curr.trim().len() > 0 {
+                lines.push(curr);
+            }
+            curr = String::new();
+        } else {
+            curr += " ";
+            curr += l;
+        }
+    }
+    if curr.trim().len() > 0 {
+        lines.push(curr);
+    }
+
+    return lines;
+}
+
+/**
+ * [add_line(moves, line, player_white)] replays the move text [line] from the
+ * starting position and adds each move played by the player to [moves]
+ * depending on whether the player is white. Earlier lines take priority when
+ * two lines disagree on a position.
+ */
+fn add_line(moves: &mut HashMap<u64, ChessMove>, line: &str, player_white: bool) {
+    let mut board = Board::default();
+    for token in line.split_whitespace() {
+        if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token) {
+            break;
+        }
+
+        // Drop move numbers, whether standalone ("1.") or attached ("1.e4"),
+        // along with annotations ("e4!?", "$1")
+        let token = token
+            .trim_start_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .trim_end_matches(|c: char| c == '!' || c == '?');
+        if token.len() == 0 || token.starts_with("$") {
+            continue;
+        }
+
+        let m = match parse_move(&board, token) {
+            Some(m) => m,
+            None => {
+                warn!(
+                    "Repertoire: could not parse move {} in {}",
+                    token,
+                    line.trim()
+                );
+                break;
+            }
+        };
+        let player_to_move = (board.side_to_move() == Color::White) == player_white;
+        if player_to_move {
+            moves.entry(board.get_hash()).or_insert(m);
+        }
+        board = board.make_move_new(m);
+    }
+}
+
+/**
+ * [load_moves(path, player_white)] loads the repertoire file at [path] for the
+ * player depending on whether the player is white.
+ */
+fn load_moves(path: &str, player_white: bool) -> HashMap<u64, ChessMove> {
+    let text = fs::read_to_string(path).expect("Unable to read repertoire file");
+    let mut moves = HashMap::new();
+    for line in split_lines(&text) {
+        add_line(&mut moves, &line, player_white);
+    }
+
+    return moves;
+}
+
+impl Repertoire {
+    /**
+     * [from_config(config)] loads the repertoire given by the parsed
+     * [config], which is empty for any color without a file.
+     */
+    pub fn from_config(config: &Value) -> Repertoire {
+        let settings = &config["repertoire"];
+        let white = match settings["white"].as_str() {
+            Some(path) => load_moves(path, true),
+            None => HashMap::new(),
+        };
+        let black = match settings["black"].as_str() {
+            Some(path) => load_moves(path, false),
+            None => HashMap::new(),
+        };
+        let max_moves = settings["max_moves"]
+            .as_u64()
+            .map(|n| n as usize)
+            .unwrap_or(DEFAULT_MAX_MOVES);
+
+        return Repertoire {
+            white,
+            black,
+            max_moves,
+        };
+    }
+
+    /**
+     * [lookup(b, player_white, ply)] returns the repertoire move for board
+     * [b], reached before the player's move at [ply] (starting from 1),
+     * depending on whether the player is white. Returns None once past the
+     * repertoire's move limit or when the position is not in the repertoire.
+     */
+    pub fn lookup(&self, b: &Board, player_white: bool, ply: usize) -> Option<ChessMove> {
+        if (ply + 1) / 2 > self.max_moves {
+            return None;
+        }
+
+        let moves = if player_white {
+            &self.white
+        } else {
+            &self.black
+        };
+        match moves.get(&b.get_hash()) {
+            Some(m) if b.legal(*m) => Some(*m),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,436 @@
+/**
+ * Utility module for persisting experiences to disk, so that experiences
+ * gathered while playing can be learned from at a later time. Experiences are
+ * stored one per line as json, with the next board stored as a FEN string.
+ *
+ * The first line of a replay file is a header giving its format version and
+ * the lengths of the state and action vectors it was written with, e.g.
+ * {"replay_format": 2, "state_dim": 839, "action_dim": 132}. Files from before
+ * the header was introduced are format version 1, and files that do not match
+ * the current encoding are refused rather than trained against. Experiences
+ * record under "done" whether the game ended with them, which for
+ * experiences written before it was recorded is read off the next board. Each
+ * experience carries its metadata under "meta", e.g.
+ * {"game": "abcd1234", "ply": 12, "phase": "opening", "source": "lichess",
+ *  "policy": "policy.flow", "timestamp": 1700000000,
+ *  "position": "463b96181691fc9c"}, where "position" is the Zobrist hash of
+ * the board the action was played in, in hex. Experiences written before it
+ * was recorded lack it, apart from the id of the game they came from that
+ * they may be tagged with.
+ */
+use crate::checkpoint::{parse_phase, phase_name};
+use crate::mdp::{
+    experience_source_name, parse_experience_source, Experience, ExperienceMeta, ACTION_DIM,
+    STATE_DIM,
+};
+
+use chess::{Board, BoardStatus};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const REPLAY_PATH: &str = "replay.jsonl";
+
+// Version of the replay file format written by this build
+pub const REPLAY_FORMAT_VERSION: u64 = 2;
+
+// The format of a replay file, as given by its header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayFormat {
+    pub version: u64,
+    pub state_dim: usize,
+    pub action_dim: usize,
+}
+
+/**
+ * [current_format()] returns the replay file format written by this build.
+ */
+pub fn current_format() -> ReplayFormat {
+    return ReplayFormat {
+        version: REPLAY_FORMAT_VERSION,
+        state_dim: STATE_DIM,
+        action_dim: ACTION_DIM,
+    };
+}
+
+/**
+ * [format_header(format)] converts [format] into the header line of a replay
+ * file.
+ */
+pub fn format_header(format: &ReplayFormat) -> String {
+    return json!({
+        "replay_format": format.version,
+        "state_dim": format.state_dim,
+        "action_dim": format.action_dim,
+    })
+    .to_string();
+}
+
+/**
+ * [parse_format(first_line)] returns the format of a replay file whose first
+ * line is [first_line]. Files without a header are format version 1, whose
+ * vector lengths are read from the first experience.
+ */
+pub fn parse_format(first_line: &str) -> io::Result<ReplayFormat> {
+    let json: Value = serde_json::from_str(first_line).map_err(|e| invalid_data(e.to_string()))?;
+    if let Some(version) = json["replay_format"].as_u64() {
+        return Ok(ReplayFormat {
+            version,
+            state_dim: json["state_dim"].as_u64().unwrap_or(0) as usize,
+            action_dim: json["action_dim"].as_u64().unwrap_or(0) as usize,
+        });
+    }
+
+    let len = |v: &Value| v.as_array().map(|a| a.len()).unwrap_or(0);
+    return Ok(ReplayFormat {
+        version: 1,
+        state_dim: len(&json["state"]),
+        action_dim: len(&json["action"]),
+    });
+}
+
+/**
+ * [invalid_data(message)] returns an io error for a malformed or incompatible
+ * replay file described by [message].
+ */
+fn invalid_data(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+/**
+ * [read_format(path)] returns the format of the replay file at [path], or None
+ * if it is missing or empty.
+ */
+pub fn read_format(path: &str) -> io::Result<Option<ReplayFormat>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+
+    let mut first_line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut first_line)?;
+    if first_line.trim().len() == 0 {
+        return Ok(None);
+    }
+
+    return parse_format(first_line.trim()).map(Some);
+}
+
+/**
+ * [check_format(path, format)] returns an error explaining why the replay file
+ * at [path] with [format] can not be used by this build, if it can not.
+ */
+fn check_format(path: &str, format: &ReplayFormat) -> io::Result<()> {
+    let current = current_format();
+    if *format == current {
+        return Ok(());
+    }
+    if format.version == 1
+        && format.state_dim == current.state_dim
+        && format.action_dim == current.action_dim
+    {
+        return Err(invalid_data(format!(
+            "Replay file {} is in format version 1, run `replay-migrate {}` to upgrade it",
+            path, path
+        )));
+    }
+
+    return Err(invalid_data(format!(
+        "Replay file {} was written with format {:?}, which is incompatible with the \
+         current format {:?}; move it aside to start a new buffer",
+        path, format, current
+    )));
+}
+
+/**
+ * [meta_to_json(meta)] converts the metadata [meta] of an experience into a
+ * json value.
+ */
+pub fn meta_to_json(meta: &ExperienceMeta) -> Value {
+    return json!({
+        "game": meta.game_id,
+        "ply": meta.ply,
+        "phase": phase_name(meta.phase),
+        "source": experience_source_name(meta.source),
+        "policy": meta.behavior_policy,
+        "timestamp": meta.timestamp,
+        "position": meta.position.map(|h| format!("{:016x}", h)),
+    });
+}
+
+/**
+ * [meta_from_json(json)] converts the json value of an experience into its
+ * metadata, falling back to the game id it was tagged with before metadata
+ * was recorded.
+ */
+fn meta_from_json(json: &Value) -> ExperienceMeta {
+    let meta = &json["meta"];
+    return ExperienceMeta {
+        game_id: meta["game"]
+            .as_str()
+            .or(json["game"].as_str())
+            .map(|id| id.to_string()),
+        ply: meta["ply"].as_u64().unwrap_or(0) as usize,
+        phase: meta["phase"]
+            .as_str()
+            .map_or(Default::default(), parse_phase),
+        source: parse_experience_source(meta["source"].as_str().unwrap_or("")),
+        behavior_policy: meta["policy"].as_str().unwrap_or_default().to_string(),
+        timestamp: meta["timestamp"].as_u64().unwrap_or(0),
+        position: meta["position"]
+            .as_str()
+            .and_then(|h| u64::from_str_radix(h, 16).ok()),
+    };
+}
+
+/**
+ * [experience_to_json(e, player_white)] converts experience [e], gathered by
+ * the player whose color is given by [player_white], into a json value.
+ */
+pub fn experience_to_json(e: &Experience, player_white: bool) -> Value {
+    return json!({
+        "state": e.state,
+        "action": e.action,
+        "reward": e.reward,
+        "next_state": e.next_state,
+        "next_board": e.next_board.to_string(),
+        "player_white": player_white,
+        "clock": e.clock,
+        "done": e.done,
+        "search_target": e.search_target,
+        "meta": meta_to_json(&e.meta),
+    });
+}
+
+/**
+ * [json_to_vec(json)] converts a json array of numbers into a vector.
+ */
+fn json_to_vec(json: &Value) -> Vec<f64> {
+    match json {
+        Value::Array(a) => a.iter().map(|x| x.as_f64().unwrap()).collect(),
+        _ => panic!(),
+    }
+}
+
+/**
+ * [experience_from_json(json)] converts a json value written by
+ * [experience_to_json] back into the experience and whether the player was
+ * white.
+ */
+pub fn experience_from_json(json: &Value) -> (Experience, bool) {
+    let next_board = match &json["next_board"] {
+        Value::String(s) => Board::from_str(s).unwrap(),
+        _ => panic!(),
+    };
+    let player_white = match &json["player_white"] {
+        Value::Bool(b) => *b,
+        _ => panic!(),
+    };
+    let experience = Experience {
+        state: json_to_vec(&json["state"]),
+        action: json_to_vec(&json["action"]),
+        reward: json["reward"].as_f64().unwrap(),
+        next_state: json_to_vec(&json["next_state"]),
+        next_board,
+        clock: json["clock"].as_f64(),
+        done: json["done"]
+            .as_bool()
+            .unwrap_or(next_board.status() != BoardStatus::Ongoing),
+        search_target: json["search_target"].as_f64(),
+        meta: meta_from_json(json),
+    };
+
+    return (experience, player_white);
+}
+
+/**
+ * [append_experiences(path, experiences, player_white)] appends
+ * [experiences], gathered by the player whose color is given by
+ * [player_white], to the replay file at [path].
+ */
+pub fn append_experiences(
+    path: &str,
+    experiences: &[Experience],
+    player_white: bool,
+) -> io::Result<()> {
+    let format = read_format(path)?;
+    if let Some(f) = &format {
+        check_format(path, f)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if format.is_none() {
+        writeln!(file, "{}", format_header(&current_format()))?;
+    }
+    for e in experiences {
+        writeln!(file, "{}", experience_to_json(e, player_white))?;
+    }
+
+    return Ok(());
+}
+
+/**
+ * [write_experiences(path, experiences)] overwrites the replay file at [path]
+ * with [experiences], each paired with whether its player was white.
+ */
+pub fn write_experiences(path: &str, experiences: &[(Experience, bool)]) -> io::Result<()> {
+    let mut contents = format_header(&current_format()) + "\n";
+    for (e, player_white) in experiences {
+        contents += &experience_to_json(e, *player_white).to_string();
+        contents += "\n";
+    }
+
+    // Write to a temporary file first so a crash never truncates the buffer
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, contents)?;
+    return fs::rename(&tmp_path, path);
+}
+
+/**
+ * [read_lines(path)] returns the experience lines of the replay file at
+ * [path] along with its format, or None if it is missing or empty.
+ */
+fn read_lines(path: &str) -> io::Result<Option<(ReplayFormat, Vec<Value>)>> {
+    let format = match read_format(path)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let contents = fs::read_to_string(path)?;
+
+    let mut lines = Vec::new();
+    let skip = if format.version == 1 { 0 } else { 1 };
+    for line in contents.lines().skip(skip) {
+        if line.len() == 0 {
+            continue;
+        }
+        let json: Value = serde_json::from_str(line).map_err(|e| {
+            invalid_data(format!(
+                "Replay file {} was not well-formatted: {}",
+                path, e
+            ))
+        })?;
+        lines.push(json);
+    }
+
+    return Ok(Some((format, lines)));
+}
+
+/**
+ * [load_experiences(path)] loads every experience stored in the replay file at
+ * [path], each paired with whether its player was white. A missing file is
+ * treated as an empty replay buffer, and a file in a format incompatible with
+ * this build is an error.
+ */
+pub fn load_experiences(path: &str) -> io::Result<Vec<(Experience, bool)>> {
+    let (format, lines) = match read_lines(path)? {
+        Some(l) => l,
+        None => return Ok(Vec::new()),
+    };
+    check_format(path, &format)?;
+
+    return Ok(lines.iter().map(experience_from_json).collect());
+}
+
+/**
+ * [migrate_replay(path)] upgrades the replay file at [path] to the current
+ * format, returning the number of experiences migrated. Only files whose
+ * state and action vectors match the current encoding can be migrated.
+ */
+pub fn migrate_replay(path: &str) -> io::Result<usize> {
+    let (format, lines) = match read_lines(path)? {
+        Some(l) => l,
+        None => return Ok(0),
+    };
+    let current = current_format();
+    if format.state_dim != current.state_dim || format.action_dim != current.action_dim {
+        return Err(invalid_data(format!(
+            "Replay file {} was written with format {:?}, which can not be migrated to {:?}",
+            path, format, current
+        )));
+    }
+
+    let experiences: Vec<(Experience, bool)> = lines.iter().map(experience_from_json).collect();
+    write_experiences(path, &experiences)?;
+
+    return Ok(experiences.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Board after fool's mate, which ended the game
+    const MATED: &str = "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3";
+
+    /**
+     * [v1_line(state_dim, action_dim)] returns an experience line written
+     * before the header, "done" and "meta" were introduced.
+     */
+    fn v1_line(state_dim: usize, action_dim: usize) -> String {
+        return json!({
+            "state": vec![0.; state_dim],
+            "action": vec![1.; action_dim],
+            "reward": 1.,
+            "next_state": vec![0.; state_dim],
+            "next_board": MATED,
+            "player_white": false,
+        })
+        .to_string();
+    }
+
+    #[test]
+    fn version_1_files_are_refused_until_migrated() {
+        let dir = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replay.jsonl").to_string_lossy().to_string();
+
+        let line = v1_line(STATE_DIM, ACTION_DIM);
+        fs::write(&path, format!("{}\n{}\n", line, line)).unwrap();
+        let format = read_format(&path).unwrap().unwrap();
+        assert_eq!(format.version, 1);
+        let error = load_experiences(&path).unwrap_err();
+        assert!(error.to_string().contains("replay-migrate"));
+        assert!(append_experiences(&path, &[], true).is_err());
+
+        assert_eq!(migrate_replay(&path).unwrap(), 2);
+        assert_eq!(read_format(&path).unwrap(), Some(current_format()));
+        let experiences = load_experiences(&path).unwrap();
+        assert_eq!(experiences.len(), 2);
+        let (e, player_white) = &experiences[0];
+        assert!(!player_white);
+        assert_eq!(e.reward, 1.);
+        assert_eq!(e.action.len(), ACTION_DIM);
+
+        // Whether the game ended is read off the next board
+        assert!(e.done);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_of_another_encoding_are_refused() {
+        let dir = std::env::temp_dir().join(format!("replay-enc-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replay.jsonl").to_string_lossy().to_string();
+
+        fs::write(&path, v1_line(STATE_DIM - 1, ACTION_DIM) + "\n").unwrap();
+        assert!(load_experiences(&path).is_err());
+        assert!(migrate_replay(&path).is_err());
+
+        let header = format_header(&ReplayFormat {
+            version: REPLAY_FORMAT_VERSION,
+            state_dim: STATE_DIM,
+            action_dim: ACTION_DIM + 1,
+        });
+        fs::write(&path, header + "\n").unwrap();
+        assert!(load_experiences(&path).is_err());
+        assert!(append_experiences(&path, &[], true).is_err());
+
+        // A new buffer starts with the current header
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load_experiences(&path).unwrap().len(), 0);
+        append_experiences(&path, &[], true).unwrap();
+        assert_eq!(read_format(&path).unwrap(), Some(current_format()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
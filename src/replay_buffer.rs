@@ -0,0 +1,268 @@
+/**
+ * Utility module for the in-memory replay buffer that learning samples from.
+ * The buffer holds at most a fixed number of experiences, evicting the oldest
+ * once it is full, and learning draws random minibatches from it rather than
+ * passing over experiences in the order they were played, which breaks up the
+ * correlation between consecutive moves of a game. The capacity and minibatch
+ * size are read from the "replay" object in config.json, e.g.
+ * {"capacity": 50000, "batch_size": 32, "epochs": 4}, where "epochs" is how
+ * many times each sample drawn for learning is passed over, reshuffled each
+ * time, and with "mirror": true learning also fits the mirror image of every
+ * experience drawn (see the augment module). A buffer can be saved to and loaded
+ * from a replay file, oldest experience first, so that with "buffer_path" given
+ * self-play keeps its experiences across runs, and the saved file can be
+ * learned from offline with the train --replay command. Experiences of the
+ * same game held in the buffer are linked into trajectories, which multi-step
+ * returns look ahead along. Self-play reaches the same early positions over
+ * and over, so with "max_duplicates" given the buffer holds at most that many
+ * experiences of the same move in the same position, identified by the
+ * position's Zobrist hash, and drops any more of them.
+ */
+use crate::mdp::Experience;
+use crate::replay::{load_experiences, write_experiences};
+
+use rand::seq::index;
+use rand::Rng;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+// Default number of experiences held, and drawn in each minibatch
+const DEFAULT_CAPACITY: u64 = 50000;
+const DEFAULT_BATCH_SIZE: u64 = 32;
+
+// Default number of passes over each sample learned from
+const DEFAULT_EPOCHS: u64 = 1;
+
+// A fixed-capacity ring buffer of experiences
+#[derive(Clone, Debug)]
+pub struct ReplayBuffer {
+    pub capacity: usize,
+    pub batch_size: usize,
+    pub epochs: usize,
+    pub mirror: bool, // whether experiences are learned along with their mirror images
+    pub max_duplicates: Option<usize>, // of the same move in the same position
+    pub dropped_duplicates: usize,
+    experiences: Vec<Experience>,
+    next: usize,                     // where the next experience is written once full
+    duplicates: HashMap<u64, usize>, // experiences held of each state-action pair
+}
+
+/**
+ * [duplicate_key(e)] returns the key identifying the move of experience [e]
+ * in its position by the position's Zobrist hash, or None if the position was
+ * not recorded.
+ */
+fn duplicate_key(e: &Experience) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    e.meta.position?.hash(&mut hasher);
+    for x in &e.action {
+        x.to_bits().hash(&mut hasher);
+    }
+    return Some(hasher.finish());
+}
+
+impl ReplayBuffer {
+    /**
+     * [new(capacity, batch_size)] creates an empty buffer holding at most
+     * [capacity] experiences, sampled in minibatches of [batch_size].
+     */
+    pub fn new(capacity: usize, batch_size: usize) -> ReplayBuffer {
+        return ReplayBuffer {
+            capacity: capacity.max(1),
+            batch_size: batch_size.max(1),
+            epochs: DEFAULT_EPOCHS as usize,
+            mirror: false,
+            max_duplicates: None,
+            dropped_duplicates: 0,
+            experiences: Vec::new(),
+            next: 0,
+            duplicates: HashMap::new(),
+        };
+    }
+
+    /**
+     * [from_config(config)] creates an empty buffer with the capacity,
+     * minibatch size, number of epochs, augmentation and limit on duplicates
+     * given by the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> ReplayBuffer {
+        let settings = &config["replay"];
+        let capacity = settings["capacity"].as_u64().unwrap_or(DEFAULT_CAPACITY);
+        let batch_size = settings["batch_size"]
+            .as_u64()
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+        let mut buffer = ReplayBuffer::new(capacity as usize, batch_size as usize);
+        buffer.epochs = settings["epochs"].as_u64().unwrap_or(DEFAULT_EPOCHS).max(1) as usize;
+        buffer.mirror = settings["mirror"].as_bool().unwrap_or(false);
+        buffer.max_duplicates = settings["max_duplicates"]
+            .as_u64()
+            .map(|n| n.max(1) as usize);
+        return buffer;
+    }
+
+    /**
+     * [len()] returns the number of experiences held.
+     */
+    pub fn len(&self) -> usize {
+        return self.experiences.len();
+    }
+
+    /**
+     * [push(e)] adds experience [e], evicting the oldest experience if the
+     * buffer is full, unless it already holds as many experiences of the same
+     * move in the same position as allowed. Returns whether [e] was added.
+     */
+    pub fn push(&mut self, e: Experience) -> bool {
+        let key = self.max_duplicates.and(duplicate_key(&e));
+        if let (Some(key), Some(max)) = (key, self.max_duplicates) {
+            if self.duplicates.get(&key).map_or(false, |n| *n >= max) {
+                self.dropped_duplicates += 1;
+                return false;
+            }
+            *self.duplicates.entry(key).or_insert(0) += 1;
+        }
+
+        if self.experiences.len() < self.capacity {
+            self.experiences.push(e);
+        } else {
+            let evicted = std::mem::replace(&mut self.experiences[self.next], e);
+            self.forget(&evicted);
+        }
+        self.next = (self.next + 1) % self.capacity;
+        return true;
+    }
+
+    /**
+     * [forget(e)] stops counting experience [e], which was evicted, among
+     * the duplicates held.
+     */
+    fn forget(&mut self, e: &Experience) {
+        let key = match self.max_duplicates.and(duplicate_key(e)) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(n) = self.duplicates.get_mut(&key) {
+            *n -= 1;
+            if *n == 0 {
+                self.duplicates.remove(&key);
+            }
+        }
+    }
+
+    /**
+     * [extend(experiences)] adds every one of [experiences] in order.
+     */
+    pub fn extend(&mut self, experiences: impl IntoIterator<Item = Experience>) {
+        for e in experiences {
+            self.push(e);
+        }
+    }
+
+    /**
+     * [save(path, player_white)] overwrites the replay file at [path] with the
+     * experiences held, oldest first, gathered by the player whose color is
+     * given by [player_white].
+     */
+    pub fn save(&self, path: &str, player_white: bool) -> io::Result<()> {
+        // Until the buffer is full the next slot is its end, so the oldest
+        // experience is always the one at the next slot
+        let (newer, older) = self.experiences.split_at(self.next);
+        let experiences: Vec<(Experience, bool)> = older
+            .iter()
+            .chain(newer)
+            .map(|e| (e.clone(), player_white))
+            .collect();
+        return write_experiences(path, &experiences);
+    }
+
+    /**
+     * [load(path, player_white)] adds the experiences of the player whose
+     * color is given by [player_white] stored in the replay file at [path],
+     * in order, returning the number added, which leaves out duplicates
+     * dropped. A missing file adds nothing.
+     */
+    pub fn load(&mut self, path: &str, player_white: bool) -> io::Result<usize> {
+        let mut added = 0;
+        for (e, w) in load_experiences(path)? {
+            if w == player_white && self.push(e) {
+                added += 1;
+            }
+        }
+        return Ok(added);
+    }
+
+    /**
+     * [sample_indices(batch_size, rng)] draws the indices of a minibatch of
+     * [batch_size] distinct experiences uniformly at random with [rng], or of
+     * every experience held in random order if there are fewer.
+     */
+    pub fn sample_indices(&self, batch_size: usize, rng: &mut impl Rng) -> Vec<usize> {
+        let amount = batch_size.min(self.experiences.len());
+        return index::sample(rng, self.experiences.len(), amount).into_vec();
+    }
+
+    /**
+     * [sample(batch_size, rng)] draws a minibatch of [batch_size] distinct
+     * experiences uniformly at random with [rng], or every experience held in
+     * random order if there are fewer.
+     */
+    pub fn sample(&self, batch_size: usize, rng: &mut impl Rng) -> Vec<&Experience> {
+        return self
+            .sample_indices(batch_size, rng)
+            .into_iter()
+            .map(|i| &self.experiences[i])
+            .collect();
+    }
+
+    /**
+     * [successors()] returns for every experience held the index of the
+     * experience continuing its game, if it is held: the next move of the
+     * same player in the same game, made from its next state. Experiences
+     * that ended their game or do not record their game have none.
+     */
+    pub fn successors(&self) -> Vec<Option<usize>> {
+        let mut by_ply: HashMap<(&str, usize), usize> = HashMap::new();
+        for (i, e) in self.experiences.iter().enumerate() {
+            if let Some(game) = &e.meta.game_id {
+                by_ply.insert((game.as_str(), e.meta.ply), i);
+            }
+        }
+        return self
+            .experiences
+            .iter()
+            .map(|e| {
+                let game = e.meta.game_id.as_deref().filter(|_| !e.done)?;
+                let next = *by_ply.get(&(game, e.meta.ply + 2))?;
+                return Some(next).filter(|j| self.experiences[*j].state == e.next_state);
+            })
+            .collect();
+    }
+
+    /**
+     * [trajectory(i, successors, steps)] returns up to [steps] experiences
+     * of a game starting from the experience at index [i], following the
+     * [successors] of the experiences held.
+     */
+    pub fn trajectory(
+        &self,
+        i: usize,
+        successors: &[Option<usize>],
+        steps: usize,
+    ) -> Vec<&Experience> {
+        let mut trajectory = vec![&self.experiences[i]];
+        let mut current = i;
+        while trajectory.len() < steps {
+            match successors.get(current).copied().flatten() {
+                Some(next) => {
+                    trajectory.push(&self.experiences[next]);
+                    current = next;
+                }
+                None => break,
+            }
+        }
+        return trajectory;
+    }
+}
@@ -0,0 +1,264 @@
+/**
+ * Utility module for storing the replay buffer on disk as a directory of
+ * size-bounded shard files, each a replay file in the format of the replay
+ * module, along with an index of the shards and how many experiences each
+ * holds. New experiences are appended to the newest shard until it is full,
+ * and once there are more shards than allowed the oldest are moved to the
+ * archive directory, or deleted if there is none. Sampling only reads the
+ * shards the sampled experiences fall in.
+ *
+ * Settings are read from the "replay" object in config.json, e.g.
+ * {"shard_dir": "replay", "shard_experiences": 5000, "max_shards": 100,
+ * "archive_dir": "replay-archive"}, where a "max_shards" of 0 keeps every
+ * shard. A single replay file from before sharding is imported as the first
+ * shards.
+ */
+use crate::mdp::Experience;
+use crate::replay::{append_experiences, load_experiences, write_experiences, REPLAY_PATH};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::info;
+
+// Default settings for shards
+pub const DEFAULT_SHARD_DIR: &str = "replay";
+const DEFAULT_SHARD_EXPERIENCES: usize = 5000;
+
+// Name of the index file within the shard directory
+const INDEX_FILE: &str = "index.json";
+
+// A shard of the replay buffer, as recorded in the index
+#[derive(Clone, Debug)]
+pub struct Shard {
+    pub file: String,
+    pub experiences: usize,
+}
+
+// The replay buffer, stored as shards from oldest to newest
+#[derive(Clone, Debug)]
+pub struct ShardedReplay {
+    pub dir: String,
+    pub shard_experiences: usize,
+    pub max_shards: usize,
+    pub archive_dir: Option<String>,
+    pub shards: Vec<Shard>,
+    next_id: u64,
+}
+
+impl ShardedReplay {
+    /**
+     * [from_config(config)] opens the sharded replay buffer described by the
+     * parsed [config], creating it if it does not exist yet and importing the
+     * unsharded replay file if there is one.
+     */
+    pub fn from_config(config: &Value) -> io::Result<ShardedReplay> {
+        let settings = &config["replay"];
+        let mut replay = ShardedReplay {
+            dir: settings["shard_dir"]
+                .as_str()
+                .unwrap_or(DEFAULT_SHARD_DIR)
+                .to_string(),
+            shard_experiences: settings["shard_experiences"]
+                .as_u64()
+                .map_or(DEFAULT_SHARD_EXPERIENCES, |n| n.max(1) as usize),
+            max_shards: settings["max_shards"].as_u64().unwrap_or(0) as usize,
+            archive_dir: settings["archive_dir"].as_str().map(|d| d.to_string()),
+            shards: Vec::new(),
+            next_id: 1,
+        };
+        fs::create_dir_all(&replay.dir)?;
+
+        let index_path = replay.index_path();
+        if Path::new(&index_path).exists() {
+            let index: Value = serde_json::from_str(&fs::read_to_string(&index_path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            replay.next_id = index["next_id"].as_u64().unwrap_or(1);
+            if let Value::Array(shards) = &index["shards"] {
+                for s in shards {
+                    replay.shards.push(Shard {
+                        file: s["file"].as_str().unwrap_or_default().to_string(),
+                        experiences: s["experiences"].as_u64().unwrap_or(0) as usize,
+                    });
+                }
+            }
+        } else if Path::new(REPLAY_PATH).exists() {
+            // Append runs of experiences of the same player together
+            let experiences = load_experiences(REPLAY_PATH)?;
+            let mut start = 0;
+            while start < experiences.len() {
+                let player_white = experiences[start].1;
+                let run: Vec<Experience> = experiences[start..]
+                    .iter()
+                    .take_while(|(_, w)| *w == player_white)
+                    .map(|(e, _)| e.clone())
+                    .collect();
+                replay.append(&run, player_white)?;
+                start += run.len();
+            }
+            fs::rename(REPLAY_PATH, format!("{}.imported", REPLAY_PATH))?;
+            info!(
+                "Imported {} experiences from {} into {}",
+                experiences.len(),
+                REPLAY_PATH,
+                replay.dir
+            );
+        }
+        replay.save_index()?;
+
+        return Ok(replay);
+    }
+
+    /**
+     * [index_path()] returns the path of the index file.
+     */
+    fn index_path(&self) -> String {
+        return format!("{}/{}", self.dir, INDEX_FILE);
+    }
+
+    /**
+     * [shard_path(shard)] returns the path of the file of [shard].
+     */
+    pub fn shard_path(&self, shard: &Shard) -> String {
+        return format!("{}/{}", self.dir, shard.file);
+    }
+
+    /**
+     * [save_index()] writes the index of the shards.
+     */
+    fn save_index(&self) -> io::Result<()> {
+        let shards: Vec<Value> = self
+            .shards
+            .iter()
+            .map(|s| json!({"file": s.file, "experiences": s.experiences}))
+            .collect();
+        let index = json!({"next_id": self.next_id, "shards": shards});
+
+        // Write to a temporary file first so a crash never loses the index
+        let index_path = self.index_path();
+        let tmp_path = format!("{}.tmp", index_path);
+        fs::write(&tmp_path, index.to_string())?;
+        return fs::rename(&tmp_path, &index_path);
+    }
+
+    /**
+     * [len()] returns the number of experiences in the replay buffer.
+     */
+    pub fn len(&self) -> usize {
+        return self.shards.iter().map(|s| s.experiences).sum();
+    }
+
+    /**
+     * [append(experiences, player_white)] appends [experiences], gathered
+     * by the player whose color is given by [player_white], starting new
+     * shards as the newest fills
+     * up and retiring the oldest shards if there are too many.
+     */
+    pub fn append(&mut self, experiences: &[Experience], player_white: bool) -> io::Result<()> {
+        let mut rest = experiences;
+        while rest.len() > 0 {
+            let full = match self.shards.last() {
+                Some(s) => s.experiences >= self.shard_experiences,
+                None => true,
+            };
+            if full {
+                self.shards.push(Shard {
+                    file: format!("shard-{:06}.jsonl", self.next_id),
+                    experiences: 0,
+                });
+                self.next_id += 1;
+            }
+
+            let shard = self.shards.last().unwrap();
+            let n = rest.len().min(self.shard_experiences - shard.experiences);
+            append_experiences(&self.shard_path(shard), &rest[..n], player_white)?;
+            self.shards.last_mut().unwrap().experiences += n;
+            rest = &rest[n..];
+        }
+
+        // Retire the oldest shards beyond the limit
+        while self.max_shards > 0 && self.shards.len() > self.max_shards {
+            let shard = self.shards.remove(0);
+            match &self.archive_dir {
+                Some(archive) => {
+                    fs::create_dir_all(archive)?;
+                    fs::rename(
+                        self.shard_path(&shard),
+                        format!("{}/{}", archive, shard.file),
+                    )?;
+                }
+                None => fs::remove_file(self.shard_path(&shard))?,
+            };
+        }
+
+        return self.save_index();
+    }
+
+    /**
+     * [oldest()] loads every experience in the oldest shard, each paired with
+     * whether its player was white.
+     */
+    pub fn oldest(&self) -> io::Result<Vec<(Experience, bool)>> {
+        return match self.shards.first() {
+            Some(s) => load_experiences(&self.shard_path(s)),
+            None => Ok(Vec::new()),
+        };
+    }
+
+    /**
+     * [replace_oldest(experiences)] overwrites the oldest shard with
+     * [experiences], removing the shard if there are none left.
+     */
+    pub fn replace_oldest(&mut self, experiences: &[(Experience, bool)]) -> io::Result<()> {
+        let path = match self.shards.first() {
+            Some(s) => self.shard_path(s),
+            None => return Ok(()),
+        };
+        if experiences.len() == 0 {
+            fs::remove_file(&path)?;
+            self.shards.remove(0);
+        } else {
+            write_experiences(&path, experiences)?;
+            self.shards[0].experiences = experiences.len();
+        }
+
+        return self.save_index();
+    }
+
+    /**
+     * [sample(count, rng)] returns [count] experiences sampled uniformly at
+     * random with replacement from the whole replay buffer by [rng], each
+     * paired with whether its player was white, reading only the shards
+     * sampled from.
+     */
+    pub fn sample(&self, count: usize, rng: &mut impl Rng) -> io::Result<Vec<(Experience, bool)>> {
+        let total = self.len();
+        if total == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut picks: Vec<usize> = (0..count).map(|_| rng.gen_range(0..total)).collect();
+        picks.sort();
+
+        let mut sample = Vec::new();
+        let mut start = 0;
+        let mut next_pick = 0;
+        for shard in &self.shards {
+            let end = start + shard.experiences;
+            if next_pick < picks.len() && picks[next_pick] < end {
+                let experiences = load_experiences(&self.shard_path(shard))?;
+                while next_pick < picks.len() && picks[next_pick] < end {
+                    if let Some(e) = experiences.get(picks[next_pick] - start) {
+                        sample.push(e.clone());
+                    }
+                    next_pick += 1;
+                }
+            }
+            start = end;
+        }
+
+        return Ok(sample);
+    }
+}
@@ -0,0 +1,97 @@
+/**
+ * Utility module for deciding when the bot resigns a Lichess game rather than
+ * playing out a hopeless position. The bot resigns once its evaluation has
+ * stayed below a threshold for a number of its moves in a row, judged either
+ * by the policy network's best Q-value or by the material difference under
+ * the evaluation weights. Resignation is turned on by the "resignation"
+ * object in config.json, e.g. {"enabled": true, "eval": "material",
+ * "threshold": -9, "moves": 5, "rated_only": false}, which resigns once the
+ * bot has been down more than a queen's worth of material for 5 moves. With
+ * "eval": "network" the threshold is in the network's output units.
+ */
+use crate::eval::{point_difference, EvalWeights};
+
+use chess::Board;
+use serde_json::Value;
+
+const DEFAULT_THRESHOLD: f64 = -9.;
+const DEFAULT_MOVES: usize = 5;
+
+// What the bot's position is judged by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResignEval {
+    Network,
+    Material,
+}
+
+// When the bot resigns
+#[derive(Clone, Copy, Debug)]
+pub struct ResignStrategy {
+    pub enabled: bool,
+    pub eval: ResignEval,
+    pub threshold: f64,
+    pub moves: usize,
+    pub rated_only: bool,
+}
+
+/**
+ * [parse_resign_eval(s)] parses the name of what resignation is judged by.
+ */
+fn parse_resign_eval(s: &str) -> ResignEval {
+    match s {
+        "network" => ResignEval::Network,
+        "material" => ResignEval::Material,
+        _ => panic!("Unknown resignation evaluation {}", s),
+    }
+}
+
+impl ResignStrategy {
+    /**
+     * [from_config(config)] reads the resignation strategy from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> ResignStrategy {
+        let settings = &config["resignation"];
+        return ResignStrategy {
+            enabled: settings["enabled"].as_bool().unwrap_or(false),
+            eval: parse_resign_eval(settings["eval"].as_str().unwrap_or("material")),
+            threshold: settings["threshold"].as_f64().unwrap_or(DEFAULT_THRESHOLD),
+            moves: settings["moves"]
+                .as_u64()
+                .map_or(DEFAULT_MOVES, |n| n.max(1) as usize),
+            rated_only: settings["rated_only"].as_bool().unwrap_or(false),
+        };
+    }
+
+    /**
+     * [evaluate(b, player_white, network_eval, weights)] returns the bot's
+     * evaluation of board [b] depending on whether it plays white, given the
+     * policy network's evaluation [network_eval], if any, and the evaluation
+     * [weights].
+     */
+    pub fn evaluate(
+        &self,
+        b: &Board,
+        player_white: bool,
+        network_eval: Option<f64>,
+        weights: &EvalWeights,
+    ) -> Option<f64> {
+        return match self.eval {
+            ResignEval::Network => network_eval,
+            ResignEval::Material => Some(point_difference(b, player_white, weights)),
+        };
+    }
+
+    /**
+     * [should_resign(evals, rated)] returns whether the bot resigns now given
+     * its evaluations [evals] of its positions so far in a game that is
+     * [rated] or not.
+     */
+    pub fn should_resign(&self, evals: &[f64], rated: bool) -> bool {
+        if !self.enabled || (self.rated_only && !rated) || evals.len() < self.moves {
+            return false;
+        }
+        let recent = &evals[evals.len() - self.moves..];
+        return recent.iter().all(|e| *e < self.threshold);
+    }
+}
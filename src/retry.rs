@@ -0,0 +1,209 @@
+/**
+ * Utility module for retrying requests to Lichess that fail for a transient
+ * reason: the connection failing or timing out, Lichess rate limiting the bot
+ * (429 Too Many Requests) or a server error. Each retry waits twice as long as
+ * the one before, up to a maximum, except that a rate limited request waits
+ * as long as its Retry-After header asks, or a full minute if it does not say,
+ * as Lichess asks of clients it rate limits. A POST is only tried again when
+ * it cannot have reached Lichess, i.e. the connection failed or Lichess rate
+ * limited it, since a POST that timed out or failed on the server may still
+ * have been acted on. Configured by the "retry" object
+ * in config.json, e.g. {"attempts": 5, "base_ms": 500, "max_ms": 30000},
+ * which tries each request up to 5 times, waiting 0.5s, 1s, 2s and 4s between
+ * attempts.
+ */
+use crate::lichess_log::send;
+
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const DEFAULT_ATTEMPTS: u64 = 5;
+const DEFAULT_BASE_MS: u64 = 500;
+const DEFAULT_MAX_MS: u64 = 30000;
+
+// How long to wait after a 429 that does not say how long to wait
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+// How often and how long apart failed requests are tried again
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /**
+     * [from_config(config)] reads the retry policy given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> RetryPolicy {
+        let settings = &config["retry"];
+        let ms = |key: &str, default: u64| {
+            Duration::from_millis(settings[key].as_u64().unwrap_or(default))
+        };
+        return RetryPolicy {
+            attempts: settings["attempts"]
+                .as_u64()
+                .unwrap_or(DEFAULT_ATTEMPTS)
+                .max(1) as u32,
+            base_delay: ms("base_ms", DEFAULT_BASE_MS),
+            max_delay: ms("max_ms", DEFAULT_MAX_MS),
+        };
+    }
+
+    /**
+     * [backoff(attempt)] returns how long to wait after failed attempt number
+     * [attempt], counting from 1.
+     */
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        return self.base_delay.saturating_mul(factor).min(self.max_delay);
+    }
+
+    /**
+     * [delay(attempt, response, idempotent)] returns how long to wait before
+     * trying again after attempt number [attempt] got [response], or None if
+     * the response is final. Server errors are only tried again if the
+     * request is [idempotent].
+     */
+    fn delay(&self, attempt: u32, response: &Response, idempotent: bool) -> Option<Duration> {
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok());
+            return Some(retry_after.map_or(DEFAULT_RATE_LIMIT_WAIT, Duration::from_secs));
+        }
+        if status.is_server_error() && idempotent {
+            return Some(self.backoff(attempt));
+        }
+        return None;
+    }
+
+    /**
+     * [send(method, url, request)] sends [request], which is a [method]
+     * request to [url], trying it again after transient failures until it
+     * succeeds, fails for good or runs out of attempts, where a POST is only
+     * tried again if it cannot have reached the server. Returns the last
+     * response, or the last error if no response arrived.
+     */
+    pub async fn send(
+        &self,
+        method: &str,
+        url: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let idempotent = !method.eq_ignore_ascii_case("POST");
+        let mut attempt = 1;
+        loop {
+            // A request whose body can not be copied is only sent once
+            let retry = request.try_clone().filter(|_| attempt < self.attempts);
+            let current = match retry {
+                Some(r) => r,
+                None => return send(method, url, request).await,
+            };
+
+            let wait = match send(method, url, current).await {
+                Ok(res) => match self.delay(attempt, &res, idempotent) {
+                    Some(wait) => {
+                        info!("{} {} got {}, retrying", method, url, res.status());
+                        wait
+                    }
+                    None => return Ok(res),
+                },
+                Err(e) if e.is_connect() || (idempotent && (e.is_timeout() || e.is_request())) => {
+                    warn!("{} {} failed: {}, retrying", method, url, e);
+                    self.backoff(attempt)
+                }
+                Err(e) => return Err(e),
+            };
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(2),
+    };
+
+    /**
+     * [serve(statuses)] starts a server on a free local port answering the
+     * requests it gets with [statuses] in turn, returning its url along with
+     * the number of requests it got.
+     */
+    async fn serve(statuses: &'static [u16]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                let _ = stream.read(&mut buffer).await;
+                let n = counted.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[n.min(statuses.len() - 1)];
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        return (url, requests);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy::from_config(&Value::Null);
+        assert_eq!(policy.attempts, DEFAULT_ATTEMPTS as u32);
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(20), Duration::from_millis(DEFAULT_MAX_MS));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_only_retried_for_idempotent_requests() {
+        let client = reqwest::Client::new();
+        let (url, requests) = serve(&[500, 200]).await;
+        let res = POLICY.send("GET", &url, client.get(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // A POST that failed on the server may have been acted on
+        let (url, requests) = serve(&[500, 200]).await;
+        let res = POLICY.send("POST", &url, client.post(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_posts_are_retried_until_attempts_run_out() {
+        let client = reqwest::Client::new();
+        let (url, requests) = serve(&[429, 200]).await;
+        let res = POLICY.send("POST", &url, client.post(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let (url, requests) = serve(&[429]).await;
+        let res = POLICY.send("POST", &url, client.post(&url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(requests.load(Ordering::SeqCst), POLICY.attempts as usize);
+    }
+}
@@ -0,0 +1,115 @@
+/**
+ * Utility module for the returns moves are learned towards, which can look
+ * further ahead than the next state so that the reward of a game's end
+ * reaches its early moves in fewer updates. Configured by the "returns"
+ * object in config.json: by default every move is fit to its reward plus the
+ * discounted value of the next state, {"kind": "nstep", "n": 5} sums the
+ * discounted rewards of the next 5 moves of the player before bootstrapping
+ * from the value of the state they reach, and {"kind": "lambda", "lambda":
+ * 0.9, "horizon": 40} mixes every such return up to 40 moves ahead into a
+ * TD(λ) return, weighting the return of k moves by λ^(k-1). The moves
+ * following a move are the experiences of the same game held in the replay
+ * buffer, so a trajectory ends early where the game did or where one of its
+ * experiences is missing.
+ */
+use serde_json::Value;
+
+// Default number of moves summed by n-step returns
+const DEFAULT_N: usize = 3;
+
+// Default weight of longer returns and number of moves of TD(λ) returns
+const DEFAULT_LAMBDA: f64 = 0.9;
+const DEFAULT_HORIZON: usize = 32;
+
+// The return a move is learned towards
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReturnTarget {
+    #[default]
+    OneStep,
+    NStep {
+        n: usize,
+    },
+    Lambda {
+        lambda: f64,
+        horizon: usize,
+    },
+}
+
+impl ReturnTarget {
+    /**
+     * [from_config(config)] reads the returns learned towards from the parsed
+     * [config], which are one-step unless configured otherwise.
+     */
+    pub fn from_config(config: &Value) -> ReturnTarget {
+        let settings = &config["returns"];
+        return match settings["kind"].as_str() {
+            Some("one_step") | None => ReturnTarget::OneStep,
+            Some("nstep") => ReturnTarget::NStep {
+                n: settings["n"]
+                    .as_u64()
+                    .map_or(DEFAULT_N, |n| n as usize)
+                    .max(1),
+            },
+            Some("lambda") => ReturnTarget::Lambda {
+                lambda: settings["lambda"]
+                    .as_f64()
+                    .unwrap_or(DEFAULT_LAMBDA)
+                    .clamp(0., 1.),
+                horizon: settings["horizon"]
+                    .as_u64()
+                    .map_or(DEFAULT_HORIZON, |n| n as usize)
+                    .max(1),
+            },
+            Some(kind) => panic!("Unknown return kind {}", kind),
+        };
+    }
+
+    /**
+     * [horizon()] returns the most moves of a player a return looks ahead.
+     */
+    pub fn horizon(&self) -> usize {
+        return match self {
+            ReturnTarget::OneStep => 1,
+            ReturnTarget::NStep { n } => *n,
+            ReturnTarget::Lambda { horizon, .. } => *horizon,
+        };
+    }
+
+    /**
+     * [discounted(rewards, next_value, gamma)] returns the return in reward
+     * units of a trajectory of moves earning [rewards], discounted by
+     * [gamma], where [next_value(k)] gives the value in reward units of the
+     * state move k leads to, or None if the game ended there, which can only
+     * be the case for the last move. Also returns whether the return
+     * bootstraps from any value, since a return made of rewards alone is
+     * exact.
+     */
+    pub fn discounted(
+        &self,
+        rewards: &[f64],
+        mut next_value: impl FnMut(usize) -> Option<f64>,
+        gamma: f64,
+    ) -> (f64, bool) {
+        let last = rewards.len() - 1;
+        let final_value = next_value(last);
+        let mut value = rewards[last] + gamma * final_value.unwrap_or(0.);
+        let mut bootstrapped = final_value.is_some();
+        match self {
+            ReturnTarget::Lambda { lambda, .. } if *lambda < 1. => {
+                // Each earlier move mixes the value of the state it leads to
+                // with the return of the moves after it
+                for k in (0..last).rev() {
+                    let v = next_value(k).unwrap_or(0.);
+                    value = rewards[k] + gamma * ((1. - lambda) * v + lambda * value);
+                    bootstrapped = true;
+                }
+            }
+            _ => {
+                for k in (0..last).rev() {
+                    value = rewards[k] + gamma * value;
+                }
+            }
+        };
+        return (value, bootstrapped);
+    }
+}
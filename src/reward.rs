@@ -0,0 +1,102 @@
+/**
+ * Utility module for optionally shaping the rewards of experiences so that the
+ * bot prefers faster wins. Without it a mate in 2 and a mate in 60 are worth
+ * the same, so nothing pushes the bot to finish won games. Configured through
+ * the "reward" object in config.json, e.g.
+ * {"win_decay_moves": 100, "min_win_scale": 0.5, "living_penalty": 0.1},
+ * which scales the win reward down linearly with the number of moves the win
+ * took, to half of it for wins taking 50 moves or more, and charges 0.1 for
+ * every move. By default rewards are left as they are. Losing on time is
+ * worth the "time_loss" reward, a loss by default, so the bot learns that
+ * running out of time is as bad as being mated. With "material_scale" set,
+ * e.g. to 1, every move is also rewarded with the change in the player's
+ * material lead it led to (the point difference after the reply minus the
+ * point difference before the move) times the scale, so that winning a knight
+ * is worth 3 on the way to the terminal reward rather than nothing.
+ * Self-play rewards can also be blended with an external engine's
+ * evaluations (see engine_reward).
+ */
+use crate::eval::{point_difference, EvalWeights};
+use crate::mdp::LOSS_REWARD;
+
+use chess::Board;
+
+use serde_json::Value;
+
+const DEFAULT_MIN_WIN_SCALE: f64 = 0.5;
+
+// How rewards are shaped by the number of moves played
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RewardShaping {
+    pub win_decay_moves: usize, // 0 leaves win rewards unscaled
+    pub min_win_scale: f64,
+    pub living_penalty: f64,
+    pub time_loss: f64,      // reward for losing on time
+    pub material_scale: f64, // 0 gives no reward for material changes
+}
+
+impl RewardShaping {
+    /**
+     * [from_config(config)] reads the reward shaping given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> RewardShaping {
+        let reward = &config["reward"];
+        return RewardShaping {
+            win_decay_moves: reward["win_decay_moves"].as_u64().unwrap_or(0) as usize,
+            min_win_scale: reward["min_win_scale"]
+                .as_f64()
+                .unwrap_or(DEFAULT_MIN_WIN_SCALE),
+            living_penalty: reward["living_penalty"].as_f64().unwrap_or(0.),
+            time_loss: reward["time_loss"].as_f64().unwrap_or(LOSS_REWARD),
+            material_scale: reward["material_scale"].as_f64().unwrap_or(0.),
+        };
+    }
+
+    /**
+     * [win_scale(moves)] returns how much the reward for a win that took the
+     * player [moves] moves is scaled by.
+     */
+    pub fn win_scale(&self, moves: usize) -> f64 {
+        if self.win_decay_moves == 0 {
+            return 1.;
+        }
+        let scale = 1. - moves as f64 / self.win_decay_moves as f64;
+        return scale.max(self.min_win_scale).min(1.);
+    }
+
+    /**
+     * [shape(reward, moves)] returns the shaped reward for the player's move
+     * number [moves] of the game, which was given [reward]: a win is scaled by
+     * how quickly it came, and every move is charged the living penalty.
+     */
+    pub fn shape(&self, reward: f64, moves: usize) -> f64 {
+        let reward = if reward > 0. {
+            reward * self.win_scale(moves)
+        } else {
+            reward
+        };
+        return reward - self.living_penalty;
+    }
+
+    /**
+     * [material(before, after, player_white, weights)] returns the
+     * intermediate reward for the change in material lead under [weights]
+     * from board [before] to board [after], depending on whether the player
+     * is white.
+     */
+    pub fn material(
+        &self,
+        before: &Board,
+        after: &Board,
+        player_white: bool,
+        weights: &EvalWeights,
+    ) -> f64 {
+        if self.material_scale == 0. {
+            return 0.;
+        }
+        let change = point_difference(after, player_white, weights)
+            - point_difference(before, player_white, weights);
+        return self.material_scale * change;
+    }
+}
@@ -0,0 +1,300 @@
+/**
+ * Utility module for comparing the metrics of two or more training runs side
+ * by side, as a Markdown or HTML report. The report gives the summary of each
+ * run followed by its curves against the number of games played: the win
+ * rate and the loss of learning from each game, both averaged over a moving
+ * window of games, and the evaluation (e.g. EPD suite) scores of its
+ * checkpoints. Each curve is a table with a column per run, and the HTML
+ * report also draws it as a line chart.
+ */
+use crate::runs::Run;
+
+use std::fmt::Write;
+
+// Number of games the win rate and loss curves are averaged over by default
+pub const DEFAULT_WINDOW: usize = 50;
+
+// Maximum number of rows in the table of a curve
+const TABLE_ROWS: usize = 20;
+
+// Size of the charts of the HTML report, in pixels
+const CHART_WIDTH: f64 = 640.;
+const CHART_HEIGHT: f64 = 240.;
+
+// Colors of each run's line in the charts, reused beyond the sixth run
+const COLORS: [&str; 6] = [
+    "#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b",
+];
+
+// The formats a report can be written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+// A metric of a run against the number of games played
+pub type Curve = Vec<(usize, f64)>;
+
+// The curves of a run compared by the report
+#[derive(Clone, Debug)]
+pub struct RunCurves {
+    pub name: String,
+    pub win_rate: Curve,
+    pub loss: Curve,
+    pub scores: Curve, // evaluation scores of the checkpoints
+}
+
+/**
+ * [parse_format(s)] converts the format name [s] into a ReportFormat.
+ */
+pub fn parse_format(s: &str) -> ReportFormat {
+    match s {
+        "markdown" | "md" => ReportFormat::Markdown,
+        "html" => ReportFormat::Html,
+        _ => panic!("Invalid report format: {}", s),
+    }
+}
+
+/**
+ * [moving_average(points, window)] averages the values of [points] over the
+ * last [window] points up to each of them.
+ */
+fn moving_average(points: &[(usize, f64)], window: usize) -> Curve {
+    let window = window.max(1);
+    let mut sum = 0.;
+    let mut curve = Vec::new();
+    for (i, (games, value)) in points.iter().enumerate() {
+        sum += value;
+        if i >= window {
+            sum -= points[i - window].1;
+        }
+        curve.push((*games, sum / (i + 1).min(window) as f64));
+    }
+    return curve;
+}
+
+/**
+ * [run_curves(run, window)] reads the curves of [run], averaging its win rate
+ * and loss over [window] games.
+ */
+pub fn run_curves(run: &Run, window: usize) -> RunCurves {
+    let metrics = run.metrics();
+    let points = |key: &str| -> Curve {
+        metrics
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| {
+                let games = m["game"].as_u64().map_or(i + 1, |g| g as usize);
+                Some((games, m[key].as_f64()?))
+            })
+            .collect()
+    };
+
+    return RunCurves {
+        name: run.name.clone(),
+        win_rate: moving_average(&points("result"), window),
+        loss: moving_average(&points("loss"), window),
+        scores: run.checkpoint_scores(),
+    };
+}
+
+/**
+ * [value_at(curve, games)] returns the value of [curve] at the last point
+ * reached within [games] games, or None if there is none.
+ */
+fn value_at(curve: &Curve, games: usize) -> Option<f64> {
+    return curve
+        .iter()
+        .take_while(|(g, _)| *g <= games)
+        .last()
+        .map(|(_, v)| *v);
+}
+
+/**
+ * [table(runs, curve, format)] renders the curve picked by [curve] for each
+ * of [runs] as a table in [format], with a row every so many games.
+ */
+fn table(runs: &[RunCurves], curve: impl Fn(&RunCurves) -> &Curve, format: ReportFormat) -> String {
+    let last = runs
+        .iter()
+        .filter_map(|r| curve(r).last().map(|(g, _)| *g))
+        .max()
+        .unwrap_or(0);
+    if last == 0 {
+        return match format {
+            ReportFormat::Markdown => "No data.\n".to_string(),
+            ReportFormat::Html => "<p>No data.</p>\n".to_string(),
+        };
+    }
+    let step = ((last + TABLE_ROWS - 1) / TABLE_ROWS).max(1);
+    let mut rows: Vec<usize> = (1..=TABLE_ROWS).map(|i| (i * step).min(last)).collect();
+    rows.dedup();
+    let cell = |games: usize, r: &RunCurves| match value_at(curve(r), games) {
+        Some(v) => format!("{:.3}", v),
+        None => "-".to_string(),
+    };
+
+    let mut s = String::new();
+    match format {
+        ReportFormat::Markdown => {
+            let names: Vec<&str> = runs.iter().map(|r| r.name.as_str()).collect();
+            writeln!(s, "| Games | {} |", names.join(" | ")).unwrap();
+            writeln!(s, "|---:|{}", "---:|".repeat(runs.len())).unwrap();
+            for games in rows {
+                let cells: Vec<String> = runs.iter().map(|r| cell(games, r)).collect();
+                writeln!(s, "| {} | {} |", games, cells.join(" | ")).unwrap();
+            }
+        }
+        ReportFormat::Html => {
+            s += "<table>\n<tr><th>Games</th>";
+            for r in runs {
+                write!(s, "<th>{}</th>", escape(&r.name)).unwrap();
+            }
+            s += "</tr>\n";
+            for games in rows {
+                write!(s, "<tr><td>{}</td>", games).unwrap();
+                for r in runs {
+                    write!(s, "<td>{}</td>", cell(games, r)).unwrap();
+                }
+                s += "</tr>\n";
+            }
+            s += "</table>\n";
+        }
+    }
+    return s;
+}
+
+/**
+ * [chart(runs, curve)] draws the curve picked by [curve] for each of [runs]
+ * as an SVG line chart, scaled to fit every run.
+ */
+fn chart(runs: &[RunCurves], curve: impl Fn(&RunCurves) -> &Curve) -> String {
+    let points: Vec<&(usize, f64)> = runs.iter().flat_map(|r| curve(r).iter()).collect();
+    if points.len() == 0 {
+        return String::new();
+    }
+    let max_games = points.iter().map(|(g, _)| *g).max().unwrap().max(1) as f64;
+    let low = points.iter().fold(f64::INFINITY, |l, (_, v)| l.min(*v));
+    let high = points.iter().fold(f64::NEG_INFINITY, |h, (_, v)| h.max(*v));
+    let range = if high > low { high - low } else { 1. };
+
+    let mut s = format!(
+        "<svg width=\"{}\" height=\"{}\" style=\"border:1px solid #ccc\">\n",
+        CHART_WIDTH, CHART_HEIGHT
+    );
+    for (i, r) in runs.iter().enumerate() {
+        let line: Vec<String> = curve(r)
+            .iter()
+            .map(|(g, v)| {
+                let x = *g as f64 / max_games * CHART_WIDTH;
+                let y = CHART_HEIGHT - (v - low) / range * CHART_HEIGHT;
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect();
+        writeln!(
+            s,
+            "<polyline fill=\"none\" stroke=\"{}\" points=\"{}\"><title>{}</title></polyline>",
+            COLORS[i % COLORS.len()],
+            line.join(" "),
+            escape(&r.name)
+        )
+        .unwrap();
+    }
+    writeln!(
+        s,
+        "</svg>\n<p>From {:.3} to {:.3} over {} games</p>",
+        low, high, max_games
+    )
+    .unwrap();
+    return s;
+}
+
+/**
+ * [escape(s)] escapes [s] for use as HTML text.
+ */
+fn escape(s: &str) -> String {
+    return s
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+}
+
+/**
+ * [compare_runs(runs, window, format)] writes the report comparing [runs] in
+ * [format], averaging the win rate and loss over [window] games.
+ */
+pub fn compare_runs(runs: &[Run], window: usize, format: ReportFormat) -> String {
+    let curves: Vec<RunCurves> = runs.iter().map(|r| run_curves(r, window)).collect();
+    let sections: [(String, fn(&RunCurves) -> &Curve); 3] = [
+        (format!("Win rate over {} games", window), |r| &r.win_rate),
+        (format!("Loss over {} games", window), |r| &r.loss),
+        ("Checkpoint scores".to_string(), |r| &r.scores),
+    ];
+
+    let mut s = String::new();
+    match format {
+        ReportFormat::Markdown => {
+            s += "# Run comparison\n\n";
+            s += "| Run | Games | Score | Recent score | Experiences per game | Checkpoints |\n";
+            s += "|---|---:|---:|---:|---:|---:|\n";
+        }
+        ReportFormat::Html => {
+            s += "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">";
+            s += "<title>Run comparison</title></head>\n<body>\n<h1>Run comparison</h1>\n";
+            s += "<table>\n<tr><th>Run</th><th>Games</th><th>Score</th><th>Recent score</th>";
+            s += "<th>Experiences per game</th><th>Checkpoints</th></tr>\n";
+        }
+    }
+    for (i, run) in runs.iter().enumerate() {
+        let summary = run.summary();
+        match format {
+            ReportFormat::Markdown => writeln!(
+                s,
+                "| {} | {} | {:.3} | {:.3} | {:.1} | {} |",
+                run.name,
+                summary.games,
+                summary.score,
+                summary.recent_score,
+                summary.mean_experiences,
+                summary.checkpoints
+            ),
+            ReportFormat::Html => writeln!(
+                s,
+                "<tr><td style=\"color:{}\">{}</td><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.1}</td><td>{}</td></tr>",
+                COLORS[i % COLORS.len()],
+                escape(&run.name),
+                summary.games,
+                summary.score,
+                summary.recent_score,
+                summary.mean_experiences,
+                summary.checkpoints
+            ),
+        }
+        .unwrap();
+    }
+    if format == ReportFormat::Html {
+        s += "</table>\n";
+    }
+
+    for (title, curve) in sections {
+        match format {
+            ReportFormat::Markdown => {
+                write!(s, "\n## {}\n\n{}", title, table(&curves, curve, format)).unwrap()
+            }
+            ReportFormat::Html => write!(
+                s,
+                "<h2>{}</h2>\n{}{}",
+                title,
+                chart(&curves, curve),
+                table(&curves, curve, format)
+            )
+            .unwrap(),
+        };
+    }
+    if format == ReportFormat::Html {
+        s += "</body>\n</html>\n";
+    }
+
+    return s;
+}
@@ -0,0 +1,292 @@
+/**
+ * Utility module for keeping each self-play training run in its own directory
+ * under the runs directory (by default "runs", or the "dir" of the "runs"
+ * object in config.json), so that runs never overwrite each other's networks
+ * and can be reproduced later. A run's directory holds a snapshot of the
+ * config it was started with (without the auth token), its policy network,
+ * its checkpoints, the log of its games, its progress through the curriculum
+ * and its metrics, one JSON object per game giving the opponent, the number
+ * of experiences, the result and the loss of learning from it.
+ */
+use crate::checkpoint::{read_metadata, CheckpointManager};
+use crate::models::DEFAULT_MODEL_PATH;
+
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tracing::info;
+
+const DEFAULT_RUNS_DIR: &str = "runs";
+
+// Number of most recent games the recent score of a run is taken over
+const RECENT_GAMES: usize = 100;
+
+// A named training run, kept in its own directory
+#[derive(Clone, Debug)]
+pub struct Run {
+    pub name: String,
+    pub dir: String,
+}
+
+// A summary of the metrics of a run
+#[derive(Clone, Debug)]
+pub struct RunSummary {
+    pub games: usize,
+    pub score: f64,        // average result over every game
+    pub recent_score: f64, // average result over the most recent games
+    pub mean_experiences: f64,
+    pub checkpoints: usize,
+}
+
+/**
+ * [runs_dir(config)] returns the directory runs are kept in according to the
+ * parsed [config].
+ */
+fn runs_dir(config: &Value) -> String {
+    return config["runs"]["dir"]
+        .as_str()
+        .unwrap_or(DEFAULT_RUNS_DIR)
+        .to_string();
+}
+
+/**
+ * [list_runs(config)] returns every run in the runs directory, sorted by name.
+ */
+pub fn list_runs(config: &Value) -> Vec<Run> {
+    let entries = match fs::read_dir(runs_dir(config)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut runs: Vec<Run> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| Run::open(config, &e.file_name().to_string_lossy()))
+        .collect();
+    runs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    return runs;
+}
+
+/**
+ * [flatten(json, prefix, out)] appends every leaf of [json] to [out] as a
+ * dotted path starting with [prefix] and its value.
+ */
+fn flatten(json: &Value, prefix: &str, out: &mut Vec<(String, String)>) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.len() == 0 {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(value, &path, out);
+            }
+        }
+        _ => out.push((prefix.to_string(), json.to_string())),
+    }
+}
+
+/**
+ * [config_differences(a, b)] returns every setting that differs between the
+ * configs [a] and [b], as its dotted path and its value in each ("-" if it is
+ * not set).
+ */
+pub fn config_differences(a: &Value, b: &Value) -> Vec<(String, String, String)> {
+    let mut a_settings = Vec::new();
+    let mut b_settings = Vec::new();
+    flatten(a, "", &mut a_settings);
+    flatten(b, "", &mut b_settings);
+
+    let mut paths: Vec<&String> = a_settings
+        .iter()
+        .chain(&b_settings)
+        .map(|(p, _)| p)
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let lookup = |settings: &Vec<(String, String)>, path: &str| {
+        settings
+            .iter()
+            .find(|(p, _)| p.eq(path))
+            .map_or("-".to_string(), |(_, v)| v.clone())
+    };
+    return paths
+        .into_iter()
+        .map(|p| (p.clone(), lookup(&a_settings, p), lookup(&b_settings, p)))
+        .filter(|(_, a, b)| a != b)
+        .collect();
+}
+
+impl Run {
+    /**
+     * [open(config, name)] returns the run named [name] in the runs directory
+     * given by the parsed [config], which may not have been started yet.
+     */
+    pub fn open(config: &Value, name: &str) -> Run {
+        return Run {
+            name: name.to_string(),
+            dir: format!("{}/{}", runs_dir(config), name),
+        };
+    }
+
+    /**
+     * [config_path()] returns where the config snapshot of the run is saved.
+     */
+    pub fn config_path(&self) -> String {
+        return format!("{}/config.json", self.dir);
+    }
+
+    /**
+     * [model_path()] returns where the policy network of the run is saved.
+     */
+    pub fn model_path(&self) -> String {
+        return format!("{}/policy.flow", self.dir);
+    }
+
+    /**
+     * [checkpoint_dir()] returns where the checkpoints of the run are saved.
+     */
+    pub fn checkpoint_dir(&self) -> String {
+        return format!("{}/checkpoints", self.dir);
+    }
+
+    /**
+     * [games_path()] returns where the moves of the run's games are logged.
+     */
+    pub fn games_path(&self) -> String {
+        return format!("{}/games.jsonl", self.dir);
+    }
+
+    /**
+     * [metrics_path()] returns where the metrics of the run's games are
+     * logged.
+     */
+    pub fn metrics_path(&self) -> String {
+        return format!("{}/metrics.jsonl", self.dir);
+    }
+
+    /**
+     * [start(config)] starts or resumes the run with the parsed [config],
+     * returning the config to train with, which saves every file of the run in
+     * its directory. A new run snapshots [config] and starts from the white
+     * policy network it gives; a resumed run keeps its original snapshot.
+     */
+    pub fn start(&self, config: &Value) -> Value {
+        fs::create_dir_all(&self.dir).unwrap();
+
+        if !Path::new(&self.config_path()).exists() {
+            let mut snapshot = config.clone();
+            if let Value::Object(map) = &mut snapshot {
+                map.remove("auth_token");
+            }
+            let pretty = serde_json::to_string_pretty(&snapshot).unwrap();
+            fs::write(self.config_path(), pretty).unwrap();
+        }
+        if !Path::new(&self.model_path()).exists() {
+            let initial = config["models"]["white"]
+                .as_str()
+                .unwrap_or(DEFAULT_MODEL_PATH);
+            fs::copy(initial, self.model_path()).expect("Unable to copy initial network");
+            info!("Started run {} from {}", self.name, initial);
+        }
+
+        let mut run_config = config.clone();
+        run_config["models"]["white"] = json!(self.model_path());
+        run_config["checkpoints"]["dir"] = json!(self.checkpoint_dir());
+        run_config["move_log"]["path"] = json!(self.games_path());
+        run_config["selfplay"]["metrics"] = json!(self.metrics_path());
+        if run_config["curriculum"].is_object() {
+            run_config["curriculum"]["progress"] = json!(format!("{}/curriculum.json", self.dir));
+        }
+
+        return run_config;
+    }
+
+    /**
+     * [snapshot()] returns the config the run was started with, or Null if it
+     * was never started.
+     */
+    pub fn snapshot(&self) -> Value {
+        return match fs::read_to_string(self.config_path()) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or(Value::Null),
+            Err(_) => Value::Null,
+        };
+    }
+
+    /**
+     * [metrics()] returns the metrics logged for each game of the run, in the
+     * order the games were played.
+     */
+    pub fn metrics(&self) -> Vec<Value> {
+        return fs::read_to_string(self.metrics_path())
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect();
+    }
+
+    /**
+     * [checkpoint_scores()] returns the number of games each evaluated
+     * checkpoint of the run was trained on along with its evaluation score,
+     * in the order the checkpoints were saved.
+     */
+    pub fn checkpoint_scores(&self) -> Vec<(usize, f64)> {
+        let mut checkpoints = CheckpointManager::from_config(&Value::Null);
+        checkpoints.dir = self.checkpoint_dir();
+        return checkpoints
+            .list()
+            .iter()
+            .map(|(_, path)| read_metadata(path))
+            .filter_map(|m| m.score.map(|s| (m.games, s)))
+            .collect();
+    }
+
+    /**
+     * [summary()] summarizes the metrics and checkpoints of the run.
+     */
+    pub fn summary(&self) -> RunSummary {
+        let metrics = self.metrics();
+        let results: Vec<f64> = metrics
+            .iter()
+            .map(|m| m["result"].as_f64().unwrap_or(0.5))
+            .collect();
+        let mean = |xs: &[f64]| xs.iter().sum::<f64>() / xs.len().max(1) as f64;
+
+        let experiences: Vec<f64> = metrics
+            .iter()
+            .map(|m| m["experiences"].as_f64().unwrap_or(0.))
+            .collect();
+        let checkpoints = match fs::read_dir(self.checkpoint_dir()) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().ends_with(".flow"))
+                .count(),
+            Err(_) => 0,
+        };
+
+        return RunSummary {
+            games: metrics.len(),
+            score: mean(&results),
+            recent_score: mean(&results[results.len().saturating_sub(RECENT_GAMES)..]),
+            mean_experiences: mean(&experiences),
+            checkpoints,
+        };
+    }
+}
+
+/**
+ * [record_metrics(path, entry)] appends the metrics [entry] of a game to the
+ * metrics log at [path].
+ */
+pub fn record_metrics(path: &str, entry: &Value) {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap();
+    writeln!(file, "{}", entry).unwrap();
+}
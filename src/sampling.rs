@@ -0,0 +1,127 @@
+/**
+ * Utility module for sampling chess positions, used to generate training data
+ * for the network outside of actual games, and for the random number
+ * generators of training. With a "seed" given at the top level of
+ * config.json, e.g. {"seed": 42}, training draws its randomness from
+ * generators seeded with it, so that two runs with the same seed and config
+ * make the same games and learning updates.
+ */
+use crate::arena::load_openings;
+use crate::history::MoveCounters;
+use crate::ingest::{game_moves, open_dump};
+use crate::make_random_move;
+
+use chess::{Board, BoardStatus, MoveGen};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/**
+ * [config_seed(config)] returns the seed given by the parsed [config], or
+ * None if training is not seeded.
+ */
+pub fn config_seed(config: &Value) -> Option<u64> {
+    return config["seed"].as_u64();
+}
+
+/**
+ * [rng_from_config(config)] returns a random number generator seeded with the
+ * seed given by the parsed [config], or from entropy if there is none.
+ */
+pub fn rng_from_config(config: &Value) -> StdRng {
+    return match config_seed(config) {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+}
+
+/**
+ * [random_position(max_plies)] plays a random number of random legal moves,
+ * up to [max_plies], from the starting position and returns the resulting
+ * board. The board returned always has at least one legal move.
+ */
+pub fn random_position(max_plies: usize) -> Board {
+    return random_game_position(max_plies).0;
+}
+
+/**
+ * [random_game_position(max_plies)] returns a board like [random_position],
+ * along with the move counters of the game that reached it, so that it can be
+ * encoded the way the network sees positions in games.
+ */
+pub fn random_game_position(max_plies: usize) -> (Board, MoveCounters) {
+    let plies = rand::thread_rng().gen_range(0..=max_plies);
+
+    let mut board = Board::default();
+    let mut counters = MoveCounters::default();
+    for _ in 0..plies {
+        let (next_board, next_counters) = match make_random_move(board) {
+            Some(m) => (board.make_move_new(m), counters.after(&board, m)),
+            None => break,
+        };
+        if next_board.status() != BoardStatus::Ongoing {
+            // Keep the last position that still has moves to evaluate
+            break;
+        }
+        board = next_board;
+        counters = next_counters;
+    }
+
+    return (board, counters);
+}
+
+/**
+ * [seeded_openings(count, plies, seed)] generates [count] distinct openings by
+ * playing [plies] random legal moves from the starting position, drawn from a
+ * random number generator seeded with [seed], so the same arguments always
+ * give the same openings. Lines that end the game early are skipped, so fewer
+ * openings may be returned if there are not enough distinct ones.
+ */
+pub fn seeded_openings(count: usize, plies: usize, seed: u64) -> Vec<Board> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut openings = Vec::new();
+    let mut seen = HashSet::new();
+    let mut attempts = 0;
+    while openings.len() < count && attempts < 100 * count.max(1) {
+        attempts += 1;
+
+        let mut board = Board::default();
+        for _ in 0..plies {
+            let moves: Vec<_> = MoveGen::new_legal(&board).collect();
+            if moves.len() == 0 {
+                break;
+            }
+            board = board.make_move_new(moves[rng.gen_range(0..moves.len())]);
+        }
+        if board.status() == BoardStatus::Ongoing && seen.insert(board.get_hash()) {
+            openings.push(board);
+        }
+    }
+
+    return openings;
+}
+
+/**
+ * [load_opening_suite(path, book_plies)] loads the starting positions of the
+ * opening suite at [path]. A PGN suite (.pgn or .pgn.zst) gives the position
+ * with White to move after the first [book_plies] plies of each game, rounded
+ * down to an even number, skipping shorter games; any other suite is read as
+ * one FEN or EPD record per line, with either side to move.
+ */
+pub fn load_opening_suite(path: &str, book_plies: usize) -> Vec<Board> {
+    if !path.ends_with(".pgn") && !path.ends_with(".pgn.zst") {
+        return load_openings(path);
+    }
+
+    let plies = book_plies - book_plies % 2;
+    let mut openings = Vec::new();
+    for game in open_dump(path).expect("Unable to read opening suite") {
+        let game = game.expect("Unable to read opening suite");
+        if let Some((board, _)) = game_moves(&game).get(plies) {
+            openings.push(*board);
+        }
+    }
+
+    return openings;
+}
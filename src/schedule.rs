@@ -0,0 +1,288 @@
+/**
+ * Utility module for deciding what the daemon should be doing at a given time.
+ * The schedule is read from the "schedule" list in config.json, where each
+ * entry is a cron-like window of the form "<mode> <days> <HH:MM>-<HH:MM>",
+ * e.g. "play mon-fri 18:00-23:30" or "train * 01:00-07:00". Times are local
+ * to the "utc_offset_minutes" setting, and a window whose end is before its
+ * start wraps past midnight.
+ */
+use crate::error::{BotError, BotResult};
+
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// What the daemon should be doing at a point in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Play,
+    Train,
+    Idle,
+}
+
+// A single window of time in the week during which the daemon is in a mode
+#[derive(Clone, Debug)]
+pub struct Window {
+    pub mode: Mode,
+    pub days: [bool; 7],
+    pub start: u32,
+    pub end: u32,
+}
+
+// The full weekly schedule of the daemon
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+    pub utc_offset_minutes: i64,
+}
+
+/**
+ * [parse_day(s)] converts the three letter day name [s] into its index in the
+ * week, with sunday being 0.
+ */
+fn parse_day(s: &str) -> BotResult<usize> {
+    match DAY_NAMES.iter().position(|d| d.eq(&s)) {
+        Some(i) => Ok(i),
+        None => Err(BotError::Config(format!("Invalid day in schedule: {}", s))),
+    }
+}
+
+/**
+ * [parse_days(s)] converts the day field [s] of a schedule entry into a hot
+ * vector over the days of the week. The field is either "*" for every day or
+ * a comma separated list of days and day ranges, e.g. "mon-wed,sat".
+ */
+fn parse_days(s: &str) -> BotResult<[bool; 7]> {
+    if s.eq("*") {
+        return Ok([true; 7]);
+    }
+
+    let mut days = [false; 7];
+    for part in s.split(",") {
+        match part.split_once("-") {
+            Some((first, last)) => {
+                let mut d = parse_day(first)?;
+                let last = parse_day(last)?;
+                days[d] = true;
+                while d != last {
+                    d = (d + 1) % 7;
+                    days[d] = true;
+                }
+            }
+            None => days[parse_day(part)?] = true,
+        }
+    }
+
+    return Ok(days);
+}
+
+/**
+ * [parse_time(s)] converts the time [s] in "HH:MM" format into minutes since
+ * midnight. Hours run from 0 to 23, and "24:00" stands for the end of the
+ * day.
+ */
+fn parse_time(s: &str) -> BotResult<u32> {
+    let invalid = || BotError::Config(format!("Invalid time in schedule: {}", s));
+    let (hours, minutes) = match s.split_once(":") {
+        Some((h, m)) => (h.parse::<u32>(), m.parse::<u32>()),
+        None => return Err(invalid()),
+    };
+    match (hours, minutes) {
+        (Ok(h), Ok(m)) if (h < 24 && m < 60) || (h == 24 && m == 0) => Ok(h * 60 + m),
+        _ => Err(invalid()),
+    }
+}
+
+/**
+ * [parse_window(s)] converts the schedule entry [s] into a Window. Returns an
+ * error if the entry is malformed.
+ */
+pub fn parse_window(s: &str) -> BotResult<Window> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() != 3 {
+        return Err(BotError::Config(format!("Invalid schedule entry: {}", s)));
+    }
+
+    let mode = match fields[0] {
+        "play" => Mode::Play,
+        "train" => Mode::Train,
+        "idle" => Mode::Idle,
+        _ => {
+            return Err(BotError::Config(format!(
+                "Invalid mode in schedule: {}",
+                fields[0]
+            )))
+        }
+    };
+    let (start, end) = match fields[2].split_once("-") {
+        Some((start, end)) => (parse_time(start)?, parse_time(end)?),
+        None => {
+            return Err(BotError::Config(format!(
+                "Invalid time range in schedule: {}",
+                fields[2]
+            )))
+        }
+    };
+
+    return Ok(Window {
+        mode,
+        days: parse_days(fields[1])?,
+        start,
+        end,
+    });
+}
+
+impl Window {
+    /**
+     * [contains(day, minute)] returns whether the window covers the [minute]
+     * since midnight of the [day] of the week. Windows wrapping past midnight
+     * belong to the day they start on.
+     */
+    pub fn contains(&self, day: usize, minute: u32) -> bool {
+        if self.start <= self.end {
+            return self.days[day] && self.start <= minute && minute < self.end;
+        }
+
+        let prev_day = (day + 6) % 7;
+        return (self.days[day] && minute >= self.start)
+            || (self.days[prev_day] && minute < self.end);
+    }
+}
+
+impl Schedule {
+    /**
+     * [from_config(config)] reads the schedule from the parsed [config]. With
+     * no schedule given the daemon plays at all times. Returns an error if the
+     * schedule is malformed.
+     */
+    pub fn from_config(config: &Value) -> BotResult<Schedule> {
+        let windows = match &config["schedule"] {
+            Value::Array(entries) => entries
+                .iter()
+                .map(|e| match e {
+                    Value::String(s) => parse_window(s),
+                    _ => Err(BotError::Config(format!("Invalid schedule entry: {}", e))),
+                })
+                .collect::<BotResult<Vec<Window>>>()?,
+            Value::Null => vec![parse_window("play * 00:00-24:00")?],
+            _ => {
+                return Err(BotError::Config(
+                    "The schedule must be a list of entries".to_string(),
+                ))
+            }
+        };
+        let utc_offset_minutes = config["utc_offset_minutes"].as_i64().unwrap_or(0);
+
+        return Ok(Schedule {
+            windows,
+            utc_offset_minutes,
+        });
+    }
+
+    /**
+     * [mode_at(day, minute)] returns the mode of the first window covering the
+     * [minute] since midnight of the [day] of the week, or Idle if no window
+     * covers it.
+     */
+    pub fn mode_at(&self, day: usize, minute: u32) -> Mode {
+        for w in &self.windows {
+            if w.contains(day, minute) {
+                return w.mode;
+            }
+        }
+
+        return Mode::Idle;
+    }
+
+    /**
+     * [current_mode()] returns the mode the daemon should currently be in.
+     */
+    pub fn current_mode(&self) -> Mode {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let local_minutes = secs / 60 + self.utc_offset_minutes;
+        let days_since_epoch = local_minutes.div_euclid(24 * 60);
+
+        // The epoch fell on a thursday
+        let day = (days_since_epoch + 4).rem_euclid(7) as usize;
+        let minute = local_minutes.rem_euclid(24 * 60) as u32;
+
+        return self.mode_at(day, minute);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn day_ranges_cover_every_day_between_their_ends() {
+        let weekdays = parse_days("mon-fri").unwrap();
+        assert_eq!(weekdays, [false, true, true, true, true, true, false]);
+
+        // A range whose last day comes before its first wraps over sunday
+        let weekend = parse_days("fri-mon,wed").unwrap();
+        assert_eq!(weekend, [true, true, false, true, false, true, true]);
+        assert_eq!(parse_days("*").unwrap(), [true; 7]);
+    }
+
+    #[test]
+    fn windows_may_end_at_midnight() {
+        let w = parse_window("play sat 18:00-24:00").unwrap();
+        assert_eq!((w.start, w.end), (18 * 60, 24 * 60));
+        assert!(w.contains(6, 18 * 60));
+        assert!(w.contains(6, 24 * 60 - 1));
+        assert!(!w.contains(0, 0));
+        assert!(parse_time("24:01").is_err());
+        assert!(parse_time("23:60").is_err());
+    }
+
+    #[test]
+    fn windows_wrap_past_midnight_into_the_next_day() {
+        let w = parse_window("train fri 23:00-02:00").unwrap();
+        assert_eq!(w.mode, Mode::Train);
+        assert!(w.contains(5, 23 * 60));
+        assert!(w.contains(6, 60));
+        assert!(!w.contains(6, 2 * 60));
+        assert!(!w.contains(6, 23 * 60));
+        assert!(!w.contains(5, 60));
+
+        // A window starting on saturday wraps into sunday
+        let w = parse_window("train sat 22:00-01:00").unwrap();
+        assert!(w.contains(0, 30));
+    }
+
+    #[test]
+    fn malformed_schedules_are_errors() {
+        for entry in [
+            "play",
+            "dance * 00:00-01:00",
+            "play someday 00:00-01:00",
+            "play mon-someday 00:00-01:00",
+            "play * 0000-0100",
+            "play * 00:00",
+            "play * 25:00-26:00",
+            "play * 00:00-01:00 extra",
+        ] {
+            assert!(parse_window(entry).is_err(), "{}", entry);
+        }
+
+        assert!(Schedule::from_config(&json!({ "schedule": "play" })).is_err());
+        assert!(Schedule::from_config(&json!({ "schedule": [1] })).is_err());
+        let schedule = Schedule::from_config(&json!({
+            "schedule": ["train * 01:00-07:00", "play mon-fri 18:00-23:30"]
+        }))
+        .unwrap();
+        assert_eq!(schedule.mode_at(1, 2 * 60), Mode::Train);
+        assert_eq!(schedule.mode_at(1, 19 * 60), Mode::Play);
+        assert_eq!(schedule.mode_at(0, 19 * 60), Mode::Idle);
+        assert_eq!(
+            Schedule::from_config(&json!({})).unwrap().mode_at(3, 0),
+            Mode::Play
+        );
+    }
+}
@@ -0,0 +1,161 @@
+/**
+ * Utility module for scripted weak opponents, which give a ladder of
+ * difficulty far below an engine for evaluation baselines and training. In
+ * order of strength: "random" plays uniformly random moves, "greedy_capture"
+ * takes the most valuable piece it can and otherwise moves randomly,
+ * "mate_blocker" also mates in one when it can and never allows a mate in one
+ * when it can avoid it, and "handcrafted" plays the move leaving the best
+ * material (one ply of the handcrafted evaluation). The arena accepts these
+ * names in place of network paths, and self-play and agent settings accept
+ * them as kinds of opponents.
+ */
+use crate::agent::{Agent, RandomAgent, SearchAgent};
+use crate::decision::{MoveDecision, MoveSource};
+use crate::eval::EvalWeights;
+use crate::game_context::GameContext;
+
+use chess::{Board, BoardStatus, ChessMove, MoveGen, Piece};
+use rand::Rng;
+
+// Names of the scripted opponents, weakest first
+pub const LADDER: [&str; 4] = ["random", "greedy_capture", "mate_blocker", "handcrafted"];
+
+// Takes the most valuable piece it can, with the least valuable attacker, and
+// otherwise plays a random move
+pub struct GreedyCaptureAgent {
+    pub weights: EvalWeights,
+}
+
+// Mates in one when it can, otherwise plays like the greedy capturer among
+// the moves that do not allow a mate in one
+pub struct MateBlockerAgent {
+    pub weights: EvalWeights,
+}
+
+/**
+ * [capture_value(b, m, weights)] returns the value under [weights] of the
+ * piece captured by move [m] in board [b], or None if it captures nothing.
+ */
+fn capture_value(b: &Board, m: ChessMove, weights: &EvalWeights) -> Option<f64> {
+    let (source, dest) = (m.get_source(), m.get_dest());
+    match b.piece_on(dest) {
+        Some(piece) => Some(weights.piece_value(piece)),
+        None if b.piece_on(source) == Some(Piece::Pawn) && source.get_file() != dest.get_file() => {
+            Some(weights.pawn) // en passant
+        }
+        None => None,
+    }
+}
+
+/**
+ * [greedy_move(b, moves, weights, rng)] selects the capture among [moves] in
+ * board [b] taking the most valuable piece under [weights], with the least
+ * valuable attacker, breaking ties randomly with [rng], or a random one of
+ * [moves] if none captures. Alternatively if there are no moves it returns
+ * None.
+ */
+fn greedy_move(
+    b: &Board,
+    moves: &[ChessMove],
+    weights: &EvalWeights,
+    rng: &mut impl Rng,
+) -> Option<ChessMove> {
+    let mut high_score = f64::NEG_INFINITY;
+    let mut best_moves = Vec::new();
+    for m in moves {
+        let victim = match capture_value(b, *m, weights) {
+            Some(v) => v,
+            None => continue,
+        };
+        let attacker = b
+            .piece_on(m.get_source())
+            .map_or(0., |p| weights.piece_value(p));
+        let score = victim - attacker / 100.;
+        if score > high_score {
+            high_score = score;
+            best_moves.clear();
+        }
+        if score == high_score {
+            best_moves.push(*m);
+        }
+    }
+
+    let pool = if best_moves.len() > 0 {
+        &best_moves[..]
+    } else {
+        moves
+    };
+    if pool.len() == 0 {
+        return None;
+    }
+    return Some(pool[rng.gen_range(0..pool.len())]);
+}
+
+/**
+ * [mates(b, m)] returns whether move [m] checkmates in board [b].
+ */
+fn mates(b: &Board, m: ChessMove) -> bool {
+    return b.make_move_new(m).status() == BoardStatus::Checkmate;
+}
+
+/**
+ * [allows_mate(b, m)] returns whether the opponent can mate in one after move
+ * [m] in board [b].
+ */
+fn allows_mate(b: &Board, m: ChessMove) -> bool {
+    let next = b.make_move_new(m);
+    return MoveGen::new_legal(&next).any(|reply| mates(&next, reply));
+}
+
+impl Agent for GreedyCaptureAgent {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let b = context.board();
+        let moves: Vec<ChessMove> = MoveGen::new_legal(&b).collect();
+        let m = greedy_move(&b, &moves, &self.weights, &mut context.rng)?;
+        return Some(MoveDecision::new(m, MoveSource::Search));
+    }
+
+    fn name(&self) -> String {
+        return "greedy capturer".to_string();
+    }
+}
+
+impl Agent for MateBlockerAgent {
+    fn select_move(&mut self, context: &mut GameContext) -> Option<MoveDecision> {
+        let b = context.board();
+        let moves: Vec<ChessMove> = MoveGen::new_legal(&b).collect();
+        if let Some(m) = moves.iter().find(|m| mates(&b, **m)) {
+            return Some(MoveDecision::new(*m, MoveSource::Search));
+        }
+
+        let safe: Vec<ChessMove> = moves
+            .iter()
+            .filter(|m| !allows_mate(&b, **m))
+            .copied()
+            .collect();
+        let pool = if safe.len() > 0 { &safe } else { &moves };
+        let m = greedy_move(&b, pool, &self.weights, &mut context.rng)?;
+        return Some(MoveDecision::new(m, MoveSource::Search));
+    }
+
+    fn name(&self) -> String {
+        return "mate blocker".to_string();
+    }
+}
+
+/**
+ * [scripted_agent(name, weights)] creates the scripted opponent called
+ * [name], valuing pieces by [weights], or returns None if there is no such
+ * opponent.
+ */
+pub fn scripted_agent(name: &str, weights: &EvalWeights) -> Option<Box<dyn Agent + Send>> {
+    let weights = weights.clone();
+    let agent: Box<dyn Agent + Send> = match name {
+        "random" => Box::new(RandomAgent),
+        "greedy_capture" => Box::new(GreedyCaptureAgent { weights }),
+        "mate_blocker" => Box::new(MateBlockerAgent { weights }),
+        "handcrafted" => Box::new(SearchAgent { weights }),
+        _ => return None,
+    };
+    return Some(agent);
+}
@@ -0,0 +1,8 @@
+/**
+ * Utility module for looking ahead before moving instead of playing the move
+ * the policy network scores highest right away, which plays poorly even with
+ * a decent network since it never sees the opponent's reply.
+ */
+pub mod alphabeta;
+pub mod mcts;
+pub mod transposition;
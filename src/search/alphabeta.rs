@@ -0,0 +1,323 @@
+/**
+ * Utility module for alpha-beta search to a fixed depth, a cheaper
+ * alternative to Monte Carlo Tree Search, configured by the "alphabeta"
+ * object in config.json, e.g. {"depth": 3, "leaf": "network"}. The depth
+ * counts plies including the move played. Positions at the depth limit are
+ * worth the best Q-value of their moves under the policy network for the side
+ * to move, or with "leaf": "material" their point difference under the
+ * evaluation weights, and positions that are over are worth their reward.
+ * Moves are searched captures of the most valuable pieces first, after the
+ * best move of the previous iteration: the search deepens one ply at a time,
+ * so that running out of nodes or time leaves the best move of the deepest
+ * iteration that finished. Leaf evaluations and the best move found in every
+ * position are kept in a transposition table, so positions reached again
+ * through another move order are not scored twice and try their best move
+ * first. Leaves are encoded with the move counters they are reached with,
+ * following those of the searched board.
+ */
+use crate::eval::{point_difference, EvalWeights};
+use crate::history::MoveCounters;
+use crate::mdp::{get_state, LOSS_REWARD};
+use crate::output_scaling::OutputScaling;
+use crate::q_function::QFunction;
+use crate::search::transposition::TranspositionTable;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen, Piece};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::time::Instant;
+
+// Default number of plies searched
+const DEFAULT_DEPTH: usize = 2;
+
+// How positions at the depth limit are evaluated
+#[derive(Clone, Debug)]
+pub enum Leaf {
+    Network,
+    Material(EvalWeights),
+}
+
+// Settings of the search
+#[derive(Clone, Debug)]
+pub struct AlphaBetaSettings {
+    pub depth: usize,
+    pub leaf: Leaf,
+    pub scaling: OutputScaling,
+}
+
+// The outcome of a search for the side to move
+#[derive(Clone, Copy, Debug)]
+pub struct AlphaBetaResult {
+    pub best: ChessMove,
+    pub score: f64,
+    pub depth: usize, // of the deepest iteration that finished
+    pub nodes: usize, // positions evaluated
+}
+
+// The state of a search in progress
+struct Searcher<'a, Q: QFunction + ?Sized> {
+    nn: &'a mut Q,
+    settings: &'a AlphaBetaSettings,
+    table: &'a mut TranspositionTable,
+    budget: Option<usize>,
+    deadline: Option<Instant>,
+    nodes: usize,
+    abortable: bool, // whether the iteration may be cut off
+    aborted: bool,
+}
+
+/**
+ * [parse_leaf(name, config)] converts the leaf evaluation name [name] into the
+ * Leaf it names, with weights given by the parsed [config].
+ */
+fn parse_leaf(name: &str, config: &Value) -> Leaf {
+    match name {
+        "network" => Leaf::Network,
+        "material" => Leaf::Material(EvalWeights::from_config(config)),
+        _ => panic!("Unknown leaf evaluation {}", name),
+    }
+}
+
+/**
+ * [max_score(scores)] returns the highest score in [scores].
+ */
+fn max_score(scores: &[(ChessMove, f64)]) -> f64 {
+    return scores
+        .iter()
+        .map(|(_, s)| *s)
+        .fold(f64::NEG_INFINITY, f64::max);
+}
+
+/**
+ * [victim_value(b, m)] returns the value of the piece move [m] captures in
+ * board [b] for ordering moves, counting a promotion as winning a queen.
+ */
+fn victim_value(b: &Board, m: ChessMove) -> u32 {
+    let victim = match b.piece_on(m.get_dest()) {
+        Some(Piece::Queen) => 9,
+        Some(Piece::Rook) => 5,
+        Some(Piece::Bishop) | Some(Piece::Knight) => 3,
+        Some(Piece::Pawn) => 1,
+        Some(Piece::King) | None => 0,
+    };
+    return victim + if m.get_promotion().is_some() { 9 } else { 0 };
+}
+
+/**
+ * [ordered_moves(b, first)] returns the legal moves in board [b], starting
+ * with [first] if given and then the most valuable captures.
+ */
+fn ordered_moves(b: &Board, first: Option<ChessMove>) -> Vec<ChessMove> {
+    let mut moves: Vec<ChessMove> = MoveGen::new_legal(b).collect();
+    moves.sort_by_key(|m| (Some(*m) != first, Reverse(victim_value(b, *m))));
+    return moves;
+}
+
+impl AlphaBetaSettings {
+    /**
+     * [from_config(config)] reads the search settings from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> AlphaBetaSettings {
+        let settings = &config["alphabeta"];
+        return AlphaBetaSettings {
+            depth: settings["depth"]
+                .as_u64()
+                .map_or(DEFAULT_DEPTH, |n| n as usize)
+                .max(1),
+            leaf: parse_leaf(settings["leaf"].as_str().unwrap_or("network"), config),
+            scaling: OutputScaling::from_config(config),
+        };
+    }
+}
+
+impl<'a, Q: QFunction + ?Sized> Searcher<'a, Q> {
+    /**
+     * [terminal_value(b)] returns the value of board [b] for the side to move
+     * if the game is over, or None if it is ongoing.
+     */
+    fn terminal_value(&self, b: &Board) -> Option<f64> {
+        let loss = match self.settings.leaf {
+            Leaf::Network => self.settings.scaling.anchor(LOSS_REWARD),
+            Leaf::Material(_) => LOSS_REWARD,
+        };
+        return match b.status() {
+            BoardStatus::Ongoing => None,
+            BoardStatus::Stalemate => Some(0.),
+            BoardStatus::Checkmate => Some(loss),
+        };
+    }
+
+    /**
+     * [leaf_value(b, counters)] returns the value of the ongoing board [b],
+     * reached with move [counters], at the depth limit for the side to move.
+     */
+    fn leaf_value(&mut self, b: &Board, counters: MoveCounters) -> f64 {
+        let player_white = b.side_to_move() == Color::White;
+        return match &self.settings.leaf {
+            Leaf::Network => {
+                let state = get_state(b, counters, player_white);
+                let hits = self.table.hits;
+                let scores = self.table.evaluate(b, &state, self.nn, player_white);
+                if self.table.hits == hits {
+                    self.nodes += scores.len();
+                }
+                max_score(&scores)
+            }
+            Leaf::Material(weights) => {
+                self.nodes += 1;
+                point_difference(b, player_white, weights)
+            }
+        };
+    }
+
+    /**
+     * [out_of_time()] returns whether the search has used up its nodes or
+     * time, marking the iteration aborted if it may be cut off.
+     */
+    fn out_of_time(&mut self) -> bool {
+        let spent = self.budget.map_or(false, |n| self.nodes >= n)
+            || self.deadline.map_or(false, |d| Instant::now() >= d);
+        if spent && self.abortable {
+            self.aborted = true;
+        }
+        return self.aborted;
+    }
+
+    /**
+     * [negamax(b, counters, depth, alpha, beta)] returns the value of board
+     * [b], reached with move [counters], for the side to move searched
+     * [depth] more plies, within the window from [alpha] to [beta].
+     */
+    fn negamax(
+        &mut self,
+        b: &Board,
+        counters: MoveCounters,
+        depth: usize,
+        mut alpha: f64,
+        beta: f64,
+    ) -> f64 {
+        if let Some(value) = self.terminal_value(b) {
+            return value;
+        }
+        if depth == 0 {
+            return self.leaf_value(b, counters);
+        }
+        if self.out_of_time() {
+            return 0.;
+        }
+
+        let player_white = b.side_to_move() == Color::White;
+        let first = self.table.best_move(b, player_white);
+        let mut best: Option<ChessMove> = None;
+        for m in ordered_moves(b, first) {
+            let next = counters.after(b, m);
+            let value = -self.negamax(&b.make_move_new(m), next, depth - 1, -beta, -alpha);
+            if self.aborted {
+                return 0.;
+            }
+            if value > alpha {
+                alpha = value;
+                best = Some(m);
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        if let Some(m) = best {
+            self.table.store_search(b, player_white, m, alpha, depth);
+        }
+        return alpha;
+    }
+
+    /**
+     * [root(b, counters, depth, first)] returns the best move in board [b],
+     * reached with move [counters], searched [depth] plies, trying [first]
+     * before the other moves, along with its value, or None if there are no
+     * legal moves or the iteration was cut off.
+     */
+    fn root(
+        &mut self,
+        b: &Board,
+        counters: MoveCounters,
+        depth: usize,
+        first: Option<ChessMove>,
+    ) -> Option<(ChessMove, f64)> {
+        let mut best: Option<(ChessMove, f64)> = None;
+        for m in ordered_moves(b, first) {
+            let alpha = best.map_or(f64::NEG_INFINITY, |(_, v)| v);
+            let next = counters.after(b, m);
+            let value = -self.negamax(
+                &b.make_move_new(m),
+                next,
+                depth - 1,
+                f64::NEG_INFINITY,
+                -alpha,
+            );
+            if self.aborted {
+                return None;
+            }
+            if best.map_or(true, |(_, v)| value > v) {
+                best = Some((m, value));
+            }
+        }
+        if let Some((m, value)) = best {
+            let player_white = b.side_to_move() == Color::White;
+            self.table.store_search(b, player_white, m, value, depth);
+        }
+        return best;
+    }
+}
+
+/**
+ * [search(nn, b, counters, settings, table, budget, deadline)] searches board
+ * [b], reached with move [counters], with policy network [nn] as deep as
+ * [settings] allow, deepening one ply at a time
+ * until [budget] positions have been evaluated or [deadline] passes, if given
+ * (the first ply is always searched), and returns the outcome for the side to
+ * move. Evaluations and best moves are shared through [table], which must
+ * only hold entries of [nn]. Alternatively if there are no legal moves it
+ * returns None.
+ */
+pub fn search<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    settings: &AlphaBetaSettings,
+    table: &mut TranspositionTable,
+    budget: Option<usize>,
+    deadline: Option<Instant>,
+) -> Option<AlphaBetaResult> {
+    let mut searcher = Searcher {
+        nn,
+        settings,
+        table,
+        budget,
+        deadline,
+        nodes: 0,
+        abortable: false,
+        aborted: false,
+    };
+
+    let mut result: Option<AlphaBetaResult> = None;
+    for depth in 1..=settings.depth {
+        searcher.abortable = depth > 1;
+        let first = result.map(|r| r.best);
+        match searcher.root(b, counters, depth, first) {
+            Some((best, score)) => {
+                result = Some(AlphaBetaResult {
+                    best,
+                    score,
+                    depth,
+                    nodes: searcher.nodes,
+                })
+            }
+            None => break,
+        };
+    }
+
+    return result.map(|r| AlphaBetaResult {
+        nodes: searcher.nodes,
+        ..r
+    });
+}
@@ -0,0 +1,310 @@
+/**
+ * Utility module for PUCT Monte Carlo Tree Search guided by the policy
+ * network, configured by the "mcts" object in config.json, e.g.
+ * {"simulations": 200, "c_puct": 1.5, "prior_temperature": 1,
+ *  "targets": true}. Every simulation walks down the tree from the root,
+ * picking the move maximizing Q + U, where Q is the mean value of the move so
+ * far and U = c_puct * P * sqrt(N) / (1 + n) favours moves with a high prior
+ * P that were visited few times compared to their parent. The position
+ * reached is expanded by scoring its moves with the network in one batch, and
+ * its value is backed up the path, negated at every ply. The value of a
+ * position is the best Q-value of its moves for the side to move and their
+ * priors are the softmax of those Q-values at the prior temperature, in units
+ * of a win, while positions that are over are worth exactly their reward. The
+ * move played is the one visited most, and with "targets" the mean value of
+ * its subtree is kept as an improved estimate of its Q-value, which training
+ * fits the move towards instead of bootstrapping from the target network.
+ * Positions are scored through a transposition table, so a position reached
+ * again through another move order with the same move counters is not scored
+ * twice.
+ */
+use crate::history::MoveCounters;
+use crate::mdp::{get_state, LOSS_REWARD, WIN_REWARD};
+use crate::output_scaling::OutputScaling;
+use crate::q_function::QFunction;
+use crate::search::transposition::TranspositionTable;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use serde_json::Value;
+
+// Default number of simulations per move, exploration constant and
+// temperature of the priors
+const DEFAULT_SIMULATIONS: usize = 200;
+const DEFAULT_C_PUCT: f64 = 1.5;
+const DEFAULT_PRIOR_TEMPERATURE: f64 = 1.;
+
+// Settings of the search
+#[derive(Clone, Copy, Debug)]
+pub struct MctsSettings {
+    pub simulations: usize,
+    pub c_puct: f64,
+    pub prior_temperature: f64,
+    pub targets: bool, // whether searched values are kept as training labels
+    pub scaling: OutputScaling,
+}
+
+// A position in the search tree, reached by a move
+struct Node {
+    board: Board,
+    counters: MoveCounters,
+    m: Option<ChessMove>, // the move leading here, None at the root
+    prior: f64,
+    q: f64, // the network's Q-value of the move
+    visits: u32,
+    value_sum: f64, // for the side that made the move, in output units
+    children: Vec<usize>,
+    expanded: bool,
+}
+
+// The outcome of a search: the move to play, and every move at the root with
+// its number of visits and mean value for the side to move
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub best: ChessMove,
+    pub value: f64, // mean value of the best move, in output units
+    pub moves: Vec<(ChessMove, u32, f64)>,
+    pub simulations: usize,
+    pub nodes: usize, // moves scored by the network
+}
+
+impl MctsSettings {
+    /**
+     * [from_config(config)] reads the search settings from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> MctsSettings {
+        let settings = &config["mcts"];
+        return MctsSettings {
+            simulations: settings["simulations"]
+                .as_u64()
+                .map_or(DEFAULT_SIMULATIONS, |n| n as usize),
+            c_puct: settings["c_puct"].as_f64().unwrap_or(DEFAULT_C_PUCT),
+            prior_temperature: settings["prior_temperature"]
+                .as_f64()
+                .filter(|t| *t > 0.)
+                .unwrap_or(DEFAULT_PRIOR_TEMPERATURE),
+            targets: settings["targets"].as_bool().unwrap_or(true),
+            scaling: OutputScaling::from_config(config),
+        };
+    }
+
+    /**
+     * [win_value()] returns the network output of a win, the unit values are
+     * compared in.
+     */
+    fn win_value(&self) -> f64 {
+        return self.scaling.anchor(WIN_REWARD).abs().max(f64::EPSILON);
+    }
+}
+
+impl Node {
+    /**
+     * [new(board, counters, m, prior, q)] creates an unvisited node for board
+     * [board] with move [counters], reached by move [m] with prior [prior]
+     * and Q-value [q].
+     */
+    fn new(board: Board, counters: MoveCounters, m: Option<ChessMove>, prior: f64, q: f64) -> Node {
+        return Node {
+            board,
+            counters,
+            m,
+            prior,
+            q,
+            visits: 0,
+            value_sum: 0.,
+            children: Vec::new(),
+            expanded: false,
+        };
+    }
+
+    /**
+     * [mean_value()] returns the mean value of the node for the side that
+     * made the move leading to it, or the network's Q-value of the move if
+     * it was never visited.
+     */
+    fn mean_value(&self) -> f64 {
+        if self.visits == 0 {
+            return self.q;
+        }
+        return self.value_sum / self.visits as f64;
+    }
+}
+
+/**
+ * [terminal_value(b, settings)] returns the value of board [b] for the side to
+ * move if the game is over, or None if it is ongoing.
+ */
+fn terminal_value(b: &Board, settings: &MctsSettings) -> Option<f64> {
+    return match b.status() {
+        BoardStatus::Ongoing => None,
+        BoardStatus::Stalemate => Some(settings.scaling.anchor(0.)),
+        BoardStatus::Checkmate => Some(settings.scaling.anchor(LOSS_REWARD)),
+    };
+}
+
+/**
+ * [expand(tree, index, nn, settings, table)] adds a child to node [index] of
+ * [tree] for every legal move, with priors from the Q-values of policy network
+ * [nn] read through [table], and returns the value of the node for the side to
+ * move along with the number of moves scored by the network.
+ */
+fn expand<Q: QFunction + ?Sized>(
+    tree: &mut Vec<Node>,
+    index: usize,
+    nn: &mut Q,
+    settings: &MctsSettings,
+    table: &mut TranspositionTable,
+) -> (f64, usize) {
+    let board = tree[index].board;
+    let counters = tree[index].counters;
+    tree[index].expanded = true;
+    if let Some(value) = terminal_value(&board, settings) {
+        return (value, 0);
+    }
+
+    let player_white = board.side_to_move() == Color::White;
+    let hits = table.hits;
+    let state = get_state(&board, counters, player_white);
+    let scores = table.evaluate(&board, &state, nn, player_white);
+    let scored = if table.hits > hits { 0 } else { scores.len() };
+    let high = scores
+        .iter()
+        .map(|(_, s)| *s)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let temperature = settings.prior_temperature * settings.win_value();
+    let exps: Vec<f64> = scores
+        .iter()
+        .map(|(_, s)| ((s - high) / temperature).exp())
+        .collect();
+    let total: f64 = exps.iter().sum();
+
+    for ((m, q), e) in scores.iter().zip(exps) {
+        let child = Node::new(
+            board.make_move_new(*m),
+            counters.after(&board, *m),
+            Some(*m),
+            e / total,
+            *q,
+        );
+        tree.push(child);
+        let child = tree.len() - 1;
+        tree[index].children.push(child);
+    }
+    return (high, scored);
+}
+
+/**
+ * [select_child(tree, index, settings)] returns the child of node [index] of
+ * [tree] with the highest PUCT score.
+ */
+fn select_child(tree: &[Node], index: usize, settings: &MctsSettings) -> usize {
+    let parent = &tree[index];
+    let exploration = settings.c_puct * (parent.visits.max(1) as f64).sqrt();
+    let win_value = settings.win_value();
+
+    let mut best = (parent.children[0], f64::NEG_INFINITY);
+    for child in &parent.children {
+        let node = &tree[*child];
+        let score =
+            node.mean_value() / win_value + exploration * node.prior / (1. + node.visits as f64);
+        if score > best.1 {
+            best = (*child, score);
+        }
+    }
+    return best.0;
+}
+
+/**
+ * [search(nn, b, counters, settings, table, budget)] searches board [b],
+ * reached with move [counters], with policy network [nn] for as many
+ * simulations as [settings] allow, stopping early
+ * once [budget] moves have been scored if given (always expanding the root),
+ * and returns the outcome for the side to move. Positions are scored through
+ * [table], which must only hold entries of [nn]. Alternatively if there are
+ * no legal moves it returns None.
+ */
+pub fn search<Q: QFunction + ?Sized>(
+    nn: &mut Q,
+    b: &Board,
+    counters: MoveCounters,
+    settings: &MctsSettings,
+    table: &mut TranspositionTable,
+    budget: Option<usize>,
+) -> Option<SearchResult> {
+    let mut tree = vec![Node::new(*b, counters, None, 1., 0.)];
+    let (_, mut nodes) = expand(&mut tree, 0, nn, settings, table);
+    if tree[0].children.len() == 0 {
+        return None;
+    }
+
+    let mut simulations = 0;
+    while simulations < settings.simulations && budget.map_or(true, |n| nodes < n) {
+        // Walk down to a position that has not been expanded yet
+        let mut path = vec![0];
+        let mut index = 0;
+        while tree[index].expanded && tree[index].children.len() > 0 {
+            index = select_child(&tree, index, settings);
+            path.push(index);
+        }
+
+        // Evaluate it, or read the result of a game that is over
+        let value = if tree[index].expanded {
+            terminal_value(&tree[index].board, settings).unwrap_or(0.)
+        } else {
+            let (value, scored) = expand(&mut tree, index, nn, settings, table);
+            nodes += scored;
+            value
+        };
+
+        // Back its value up, for the side that made each move
+        let mut value = -value;
+        for i in path.into_iter().rev() {
+            tree[i].visits += 1;
+            tree[i].value_sum += value;
+            value = -value;
+        }
+        simulations += 1;
+    }
+
+    let moves: Vec<(ChessMove, u32, f64)> = tree[0]
+        .children
+        .iter()
+        .map(|c| (tree[*c].m.unwrap(), tree[*c].visits, tree[*c].mean_value()))
+        .collect();
+    let (best, _, value) = moves.iter().fold(moves[0], |best, next| {
+        if (next.1, next.2) > (best.1, best.2) {
+            *next
+        } else {
+            best
+        }
+    });
+    return Some(SearchResult {
+        best,
+        value,
+        moves,
+        simulations,
+        nodes,
+    });
+}
+
+impl SearchResult {
+    /**
+     * [scores()] returns every move at the root with its mean value.
+     */
+    pub fn scores(&self) -> Vec<(ChessMove, f64)> {
+        return self.moves.iter().map(|(m, _, v)| (*m, *v)).collect();
+    }
+
+    /**
+     * [visit_distribution()] returns every move at the root with the
+     * fraction of simulations that went through it.
+     */
+    pub fn visit_distribution(&self) -> Vec<(ChessMove, f64)> {
+        let total = self.simulations.max(1) as f64;
+        return self
+            .moves
+            .iter()
+            .map(|(m, n, _)| (*m, *n as f64 / total))
+            .collect();
+    }
+}
@@ -0,0 +1,279 @@
+/**
+ * Utility module for a transposition table caching what is known about
+ * positions, keyed by their Zobrist hash and the perspective they were
+ * evaluated from: the network's Q-value of every legal move, and the best move
+ * found by a search along with its score and depth. It is configured by the
+ * "transposition" object in config.json, e.g. {"size": 65536,
+ * "replacement": "depth"}, where each position maps to one slot of the table
+ * and "replacement" decides whether a new position always takes over its slot
+ * or only when the slot holds a shallower search. A size of 0 disables it.
+ * Everything stored comes from one network, so the table is cleared whenever
+ * the network it caches changes. Search scores are stored for move ordering
+ * only, since they are not stored with the window they were searched in.
+ * The network's Q-values also depend on the features of the state the board
+ * alone does not give, such as the move counters, so they are stored along
+ * with a hash of those features and only read back for the same ones.
+ */
+use crate::mdp::{score_moves_in_state, BOARD_DIM};
+use crate::q_function::QFunction;
+
+use chess::{Board, ChessMove};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+// Default number of slots in the table
+const DEFAULT_SIZE: usize = 16384;
+
+// When a position takes over the slot of another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    Always,
+    Depth, // unless the slot holds a deeper search of the current network
+}
+
+// What is known about a position from one perspective
+#[derive(Clone, Debug)]
+pub struct TableEntry {
+    pub hash: u64,
+    pub player_white: bool,
+    pub scores: Option<Vec<(ChessMove, f64)>>, // Q-value of every legal move
+    pub context: u64, // hash of the state's features beyond the board, for the scores
+    pub best: Option<(ChessMove, f64)>, // best move found by a search
+    pub depth: usize, // of the search the best move came from, if any
+    generation: u64,
+}
+
+// A fixed-size table of positions
+#[derive(Clone, Debug)]
+pub struct TranspositionTable {
+    slots: Vec<Option<TableEntry>>,
+    pub replacement: Replacement,
+    generation: u64, // entries of older generations are stale
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/**
+ * [context_hash(state)] hashes the features of [state] beyond those of its
+ * board, which the board's hash does not cover.
+ */
+fn context_hash(state: &[f64]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for x in &state[BOARD_DIM..] {
+        hasher.write_u64(x.to_bits());
+    }
+    return hasher.finish();
+}
+
+/**
+ * [parse_replacement(s)] parses the name of a replacement policy.
+ */
+fn parse_replacement(s: &str) -> Replacement {
+    match s {
+        "always" => Replacement::Always,
+        "depth" => Replacement::Depth,
+        _ => panic!("Unknown replacement policy {}", s),
+    }
+}
+
+impl Default for TranspositionTable {
+    fn default() -> TranspositionTable {
+        return TranspositionTable::new(DEFAULT_SIZE, Replacement::Always);
+    }
+}
+
+impl TranspositionTable {
+    /**
+     * [new(size, replacement)] creates an empty table of [size] slots
+     * replacing entries according to [replacement].
+     */
+    pub fn new(size: usize, replacement: Replacement) -> TranspositionTable {
+        return TranspositionTable {
+            slots: vec![None; size],
+            replacement,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+        };
+    }
+
+    /**
+     * [from_config(config)] creates an empty table with the settings given by
+     * the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> TranspositionTable {
+        let settings = &config["transposition"];
+        return TranspositionTable::new(
+            settings["size"]
+                .as_u64()
+                .map_or(DEFAULT_SIZE, |n| n as usize),
+            parse_replacement(settings["replacement"].as_str().unwrap_or("always")),
+        );
+    }
+
+    /**
+     * [clear()] forgets every entry, e.g. once the network changed.
+     */
+    pub fn clear(&mut self) {
+        self.generation += 1;
+    }
+
+    /**
+     * [slot(b, player_white)] returns the slot of board [b] from the
+     * perspective given by [player_white], or None if the table is disabled.
+     */
+    fn slot(&self, b: &Board, player_white: bool) -> Option<usize> {
+        if self.slots.len() == 0 {
+            return None;
+        }
+        let key = b.get_hash() ^ player_white as u64;
+        return Some((key % self.slots.len() as u64) as usize);
+    }
+
+    /**
+     * [probe(b, player_white)] returns what is known about board [b] from the
+     * perspective given by [player_white], if anything.
+     */
+    pub fn probe(&mut self, b: &Board, player_white: bool) -> Option<&TableEntry> {
+        let slot = self.slot(b, player_white)?;
+        let generation = self.generation;
+        let found = match &self.slots[slot] {
+            Some(e) => {
+                e.generation == generation
+                    && e.hash == b.get_hash()
+                    && e.player_white == player_white
+            }
+            None => false,
+        };
+        if !found {
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        return self.slots[slot].as_ref();
+    }
+
+    /**
+     * [entry(b, player_white, depth)] returns the entry of board [b] from the
+     * perspective given by [player_white] to store into, creating it in its
+     * slot if the replacement policy lets a search of [depth] take over, or
+     * None otherwise.
+     */
+    fn entry(&mut self, b: &Board, player_white: bool, depth: usize) -> Option<&mut TableEntry> {
+        let slot = self.slot(b, player_white)?;
+        let (hash, generation) = (b.get_hash(), self.generation);
+        let replace = match &self.slots[slot] {
+            Some(e) if e.generation == generation => {
+                if e.hash == hash && e.player_white == player_white {
+                    false
+                } else if self.replacement == Replacement::Depth && e.depth > depth {
+                    return None;
+                } else {
+                    true
+                }
+            }
+            _ => true,
+        };
+        if replace {
+            self.slots[slot] = Some(TableEntry {
+                hash,
+                player_white,
+                scores: None,
+                context: 0,
+                best: None,
+                depth: 0,
+                generation,
+            });
+        }
+        return self.slots[slot].as_mut();
+    }
+
+    /**
+     * [store_scores(b, state, player_white, scores)] stores the Q-value of
+     * every legal move [scores] in board [b], encoded as [state], from the
+     * perspective given by [player_white].
+     */
+    pub fn store_scores(
+        &mut self,
+        b: &Board,
+        state: &[f64],
+        player_white: bool,
+        scores: &[(ChessMove, f64)],
+    ) {
+        let context = context_hash(state);
+        if let Some(e) = self.entry(b, player_white, 0) {
+            e.scores = Some(scores.to_vec());
+            e.context = context;
+        }
+    }
+
+    /**
+     * [store_search(b, player_white, best, score, depth)] stores [best] as the
+     * best move in board [b] from the perspective given by [player_white],
+     * worth [score] in a search [depth] plies deep, unless a deeper search is
+     * already stored.
+     */
+    pub fn store_search(
+        &mut self,
+        b: &Board,
+        player_white: bool,
+        best: ChessMove,
+        score: f64,
+        depth: usize,
+    ) {
+        if let Some(e) = self.entry(b, player_white, depth) {
+            if e.best.is_none() || e.depth <= depth {
+                e.best = Some((best, score));
+                e.depth = depth;
+            }
+        }
+    }
+
+    /**
+     * [best_move(b, player_white)] returns the best move stored for board [b]
+     * from the perspective given by [player_white], if any.
+     */
+    pub fn best_move(&mut self, b: &Board, player_white: bool) -> Option<ChessMove> {
+        return self.probe(b, player_white)?.best.map(|(m, _)| m);
+    }
+
+    /**
+     * [evaluate(b, state, nn, player_white)] returns every legal move in
+     * board [b], encoded as [state], with its Q-value under policy network
+     * [nn] from the perspective given by [player_white] like
+     * [score_moves_in_state], reading them from the table if they are stored
+     * for the same state and storing them otherwise.
+     */
+    pub fn evaluate<Q: QFunction + ?Sized>(
+        &mut self,
+        b: &Board,
+        state: &[f64],
+        nn: &mut Q,
+        player_white: bool,
+    ) -> Vec<(ChessMove, f64)> {
+        let context = context_hash(state);
+        let stored = self
+            .probe(b, player_white)
+            .map(|e| (e.context == context, e.scores.clone()));
+        match stored {
+            Some((true, Some(scores))) => return scores,
+            Some(_) => {
+                // The board is stored but not its scores in this state
+                self.hits -= 1;
+                self.misses += 1;
+            }
+            None => (),
+        }
+        let scores = score_moves_in_state(nn, b, state, player_white);
+        self.store_scores(b, state, player_white, &scores);
+        return scores;
+    }
+
+    /**
+     * [hit_rate()] returns the fraction of probes that found their position.
+     */
+    pub fn hit_rate(&self) -> f64 {
+        return self.hits as f64 / (self.hits + self.misses).max(1) as f64;
+    }
+}
@@ -0,0 +1,1133 @@
+/**
+ * Utility module for training the bot by playing games offline. The learner
+ * always plays White with the white policy network, while Black is played by
+ * an opponent picked for each game from the mix configured in the "selfplay"
+ * object of config.json, e.g.
+ * {"opponents": {"policy": 0.5, "random": 0.1, "checkpoint": 0.2,
+ *  "handcrafted": 0.1, "greedy_capture": 0.05, "mate_blocker": 0.05,
+ *  "engine": 0.1},
+ *  "engine": {"command": "stockfish", "skill": 0, "nodes": 1000}}.
+ * Without any configured opponents the learner only plays against itself.
+ * Past checkpoints are the ones saved by the checkpoint manager during
+ * self-play.
+ * Games can also start with material odds given by the "odds" settings, and
+ * each color explores according to its schedule in the "exploration"
+ * settings, e.g. {"white": {"epsilon": 0.5, "final_epsilon": 0.05,
+ * "decay_games": 200, "temperature": 0, "underpromotion": 0.2},
+ * "black": {"epsilon": 0}}, where "underpromotion" is the probability of
+ * playing a random underpromotion instead of a chosen queen promotion, so that
+ * the other promotion dimensions of the action get trained too. By default
+ * White plays a random move half the time and Black never explores. With
+ * "strategy": "boltzmann" instead of the default "epsilon_greedy" a color
+ * explores by sampling its moves from the softmax of their Q-values rather
+ * than playing random ones, e.g. {"strategy": "boltzmann", "temperature": 2,
+ * "temperature_plies": 20}, which samples at temperature 2 (1 by default) for
+ * the first 20 plies and plays the best move after. The
+ * networks of each color can also be given search limits by the "limits"
+ * settings, e.g. {"white": "nodes=10", "black": "clock=60000+100"}, and a
+ * side that runs out of time loses. Dead equal games can be adjudicated
+ * drawn by the "adjudication" settings, e.g. {"moves": 20,
+ * "eval_threshold": 0.5, "network_threshold": 1}, once both the handcrafted
+ * evaluation and the network's Q-value stay within their thresholds of 0 for
+ * that many consecutive moves. Available draws are only claimed according to
+ * the "draw_claims" settings, and are otherwise played on until drawn by
+ * fivefold repetition or the seventy-five move rule. A fraction of games can
+ * start from positions of an opening suite instead of the initial position, given by the
+ * "openings" settings, e.g. {"suite": "openings.pgn", "fraction": 0.5,
+ * "book_plies": 8}, or from positions in a FEN or EPD file such as a set of
+ * endgames, e.g. {"suite": "endgames.epd", "fraction": 1}, which the
+ * selfplay --positions option sets for a run. The result of each game can be logged to the "metrics"
+ * file, e.g. "metrics.jsonl", along with the loss of learning from it and the
+ * seed it was played from, which is derived from the run's "seed" setting,
+ * or else the top-level "seed" (random if neither is set). Learning from each
+ * game draws its minibatches from a seed derived the same way. A game can be replayed exactly from its seed and the
+ * network it was played with, except against an external engine, which has
+ * randomness of its own. The learner can be rewarded for reaching rarely
+ * visited positions by the "novelty" settings, e.g. {"scale": 1}, and its
+ * rewards blended with an external engine's evaluations as set by the
+ * "engine" settings of the "reward" object (see engine_reward). With
+ * "mcts": true the learner picks its moves by a Monte Carlo Tree Search with
+ * the "mcts" settings instead of greedily, and learns from the searched values
+ * of its moves. Every move of both colors is learned from, from the
+ * perspective of the player who made it, so the white policy network also
+ * learns from the moves played against it. A curriculum, if configured, picks
+ * the positions games start from instead of the opening suite.
+ */
+use crate::agent::{
+    Agent, EpsilonGreedyAgent, ExternalUciAgent, MctsAgent, PolicyAgent, RandomAgent, SearchAgent,
+    TemperatureSchedule,
+};
+use crate::arena::gate_after_training;
+use crate::checkpoint::{read_metadata, target_path, write_metadata, CheckpointManager};
+use crate::curriculum::Curriculum;
+use crate::decision::MoveSource;
+use crate::discount;
+use crate::display::{render_position, DisplaySettings};
+use crate::draw_offer::DrawClaimStrategy;
+use crate::engine_reward::EngineShaping;
+use crate::error::BotResult;
+use crate::eval::{evaluate, point_difference, EvalWeights};
+use crate::game_context::GameContext;
+use crate::handicap::Handicap;
+use crate::limits::{parse_limit, SearchLimit};
+use crate::logging::game_span;
+use crate::mdp::{
+    get_action, get_reward, learn_from_experience, Experience, ExperienceMeta, ExperienceSource,
+    StateCache, TargetNetwork, WIN_REWARD,
+};
+use crate::metrics::{GameMetrics, MetricsLog};
+use crate::models::{read_network, white_path, ModelRegistry};
+use crate::move_log::{GameLog, MoveLog};
+use crate::normalization::{read_stats, FeatureStats};
+use crate::novelty::NoveltyBonus;
+use crate::output_scaling::OutputScaling;
+use crate::pgn::{result_from_reward, PgnGame, PgnLog};
+use crate::replay_buffer::ReplayBuffer;
+use crate::returns::ReturnTarget;
+use crate::reward::RewardShaping;
+use crate::runs::record_metrics;
+use crate::sampling::{config_seed, load_opening_suite};
+use crate::scripted::{GreedyCaptureAgent, MateBlockerAgent};
+use crate::search::mcts::MctsSettings;
+use crate::shutdown;
+use crate::training::{StopReason, TrainingPlan};
+use crate::uci_engine::UciEngine;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen};
+use neuroflow::FeedForward;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::borrow::BorrowMut;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+// Default probabilities that each color plays a random move
+const DEFAULT_WHITE_EPSILON: f64 = 0.5;
+const DEFAULT_BLACK_EPSILON: f64 = 0.;
+
+// Default temperature of a color exploring by Boltzmann sampling, in units of
+// Q-value
+const DEFAULT_BOLTZMANN_TEMPERATURE: f64 = 1.;
+
+// Probability that a non-terminal experience is kept for learning, to spread
+// the experiences learned from over many games
+const KEEP_PROBABILITY: f64 = 0.2;
+
+// Default number of plies played from each game of a PGN opening suite
+const DEFAULT_BOOK_PLIES: usize = 8;
+
+// Multiplier spreading the seeds of consecutive games of a run apart
+const GAME_SEED_STRIDE: u64 = 0x9E37_79B9_7F4A_7C15;
+
+// Stream derived from a game's seed that the learning updates after the game
+// are drawn from
+const LEARNING_SEED_STREAM: usize = 1;
+
+// Number of moves by each side after which a game is stopped
+const MAX_MOVES: usize = 150;
+
+// A move whose experience is completed by the reply to it
+struct PendingMove {
+    board: Board,
+    state: Vec<f64>, // encoded along with the positions before it
+    chosen: ChessMove,
+    clock: Option<f64>,
+    ply: usize,
+    moves: usize, // by the player who made it, counting this one
+    q: f64,
+    search_target: Option<f64>,
+    agent: String,
+}
+
+// Thresholds within which a position counts as dead equal for adjudication
+const DEFAULT_ADJUDICATION_EVAL_THRESHOLD: f64 = 0.5;
+const DEFAULT_ADJUDICATION_NETWORK_THRESHOLD: f64 = 1.;
+
+// The kinds of opponent the learner can face
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpponentKind {
+    Policy,
+    Random,
+    Checkpoint,
+    Handcrafted,
+    GreedyCapture,
+    MateBlocker,
+    Engine,
+}
+
+// How a color explores in a single game: the probability of playing a random
+// move, and otherwise the temperature moves are sampled at from their
+// Q-values over the game, along with the probability of turning a queen
+// promotion into an underpromotion
+#[derive(Clone, Copy, Debug)]
+pub struct Exploration {
+    pub epsilon: f64,
+    pub temperature: TemperatureSchedule,
+    pub underpromotion: f64,
+}
+
+// How a color explores over a run, with epsilon decaying linearly from
+// [epsilon] to [final_epsilon] over [decay_games] games
+#[derive(Clone, Debug)]
+pub struct ExplorationSchedule {
+    pub epsilon: f64,
+    pub final_epsilon: f64,
+    pub decay_games: usize,
+    pub temperature: TemperatureSchedule,
+    pub underpromotion: f64,
+}
+
+// Everything about how self-play games are played, read from the config
+pub struct SelfPlaySettings {
+    pub mix: OpponentMix,
+    pub handicap: Option<Handicap>,
+    pub white_schedule: ExplorationSchedule,
+    pub black_schedule: ExplorationSchedule,
+    pub limits: (SearchLimit, SearchLimit),
+    pub shaping: RewardShaping,
+    pub adjudication: DrawAdjudication,
+    pub claims: DrawClaimStrategy,
+    pub move_log: MoveLog,
+    pub display: DisplaySettings,
+    pub pgn: PgnLog,
+    pub suite: Vec<Board>,
+    pub suite_fraction: f64,
+    pub novelty: Option<NoveltyBonus>,
+    pub engine_shaping: Option<EngineShaping>,
+    pub mcts: Option<MctsSettings>, // the learner's search, if it searches
+    pub keep_probability: f64,      // of each experience not ending its game
+}
+
+// Probabilities of facing each kind of opponent
+pub struct OpponentMix {
+    weights: Vec<(OpponentKind, f64)>,
+    checkpoints: CheckpointManager,
+    engine: Value,
+    eval_weights: EvalWeights,
+}
+
+/**
+ * [parse_opponent_kind(s)] parses the name of a kind of opponent.
+ */
+fn parse_opponent_kind(s: &str) -> OpponentKind {
+    match s {
+        "policy" => OpponentKind::Policy,
+        "random" => OpponentKind::Random,
+        "checkpoint" => OpponentKind::Checkpoint,
+        "handcrafted" => OpponentKind::Handcrafted,
+        "greedy_capture" => OpponentKind::GreedyCapture,
+        "mate_blocker" => OpponentKind::MateBlocker,
+        "engine" => OpponentKind::Engine,
+        _ => panic!("Unknown opponent kind {}", s),
+    }
+}
+
+// When a game is adjudicated a draw because it is dead equal
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawAdjudication {
+    pub moves: usize, // 0 never adjudicates
+    pub eval_threshold: f64,
+    pub network_threshold: f64,
+}
+
+impl DrawAdjudication {
+    /**
+     * [from_config(settings)] reads the draw adjudication from the parsed
+     * [settings].
+     */
+    pub fn from_config(settings: &Value) -> DrawAdjudication {
+        return DrawAdjudication {
+            moves: settings["moves"].as_u64().unwrap_or(0) as usize,
+            eval_threshold: settings["eval_threshold"]
+                .as_f64()
+                .unwrap_or(DEFAULT_ADJUDICATION_EVAL_THRESHOLD),
+            network_threshold: settings["network_threshold"]
+                .as_f64()
+                .unwrap_or(DEFAULT_ADJUDICATION_NETWORK_THRESHOLD),
+        };
+    }
+
+    /**
+     * [is_equal(eval, q)] returns whether a position with handcrafted
+     * evaluation [eval] and network Q-value [q] counts as dead equal.
+     */
+    pub fn is_equal(&self, eval: f64, q: f64) -> bool {
+        return eval.abs() <= self.eval_threshold && q.abs() <= self.network_threshold;
+    }
+}
+
+/**
+ * [handcrafted_move(b, player_white, weights, rng)] selects the legal move in
+ * board [b] that leaves the player with the best material difference under
+ * [weights] depending on whether the player is white, preferring checkmates
+ * and breaking ties randomly with [rng]. Alternatively if there are no legal moves it
+ * returns None.
+ */
+pub fn handcrafted_move(
+    b: &Board,
+    player_white: bool,
+    weights: &EvalWeights,
+    rng: &mut impl Rng,
+) -> Option<ChessMove> {
+    let mut high_score = f64::NEG_INFINITY;
+    let mut best_moves = Vec::new();
+    for m in MoveGen::new_legal(b) {
+        let next_board = b.make_move_new(m);
+        let score = point_difference(&next_board, player_white, weights)
+            + get_reward(&next_board, player_white);
+        if score > high_score {
+            high_score = score;
+            best_moves.clear();
+        }
+        if score == high_score {
+            best_moves.push(m);
+        }
+    }
+
+    if best_moves.len() == 0 {
+        return None;
+    }
+    return Some(best_moves[rng.gen_range(0..best_moves.len())]);
+}
+
+/**
+ * [boltzmann_move(scores, temperature, rng)] samples one of the moves in
+ * [scores] with [rng], with probability proportional to the exponential of
+ * its Q-value divided by [temperature]. Alternatively if there are no moves it returns None.
+ */
+pub fn boltzmann_move(
+    scores: &[(ChessMove, f64)],
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> Option<ChessMove> {
+    let high = scores.iter().fold(f64::NEG_INFINITY, |h, (_, s)| h.max(*s));
+    let weights: Vec<f64> = scores
+        .iter()
+        .map(|(_, s)| ((s - high) / temperature).exp())
+        .collect();
+    let dist = WeightedIndex::new(&weights).ok()?;
+
+    return Some(scores[dist.sample(rng)].0);
+}
+
+impl ExplorationSchedule {
+    /**
+     * [from_config(settings, default_epsilon)] reads the exploration schedule
+     * of a color from [settings], which explores with [default_epsilon]
+     * throughout unless configured, or samples its moves at the default
+     * temperature without random moves if its strategy is "boltzmann".
+     */
+    pub fn from_config(settings: &Value, default_epsilon: f64) -> ExplorationSchedule {
+        let (default_epsilon, default_temperature) =
+            match settings["strategy"].as_str().unwrap_or("epsilon_greedy") {
+                "epsilon_greedy" => (default_epsilon, 0.),
+                "boltzmann" => (0., DEFAULT_BOLTZMANN_TEMPERATURE),
+                s => panic!("Invalid exploration strategy: {}", s),
+            };
+        let epsilon = settings["epsilon"].as_f64().unwrap_or(default_epsilon);
+        return ExplorationSchedule {
+            epsilon,
+            final_epsilon: settings["final_epsilon"].as_f64().unwrap_or(epsilon),
+            decay_games: settings["decay_games"].as_u64().unwrap_or(0) as usize,
+            temperature: TemperatureSchedule::from_config(settings, default_temperature),
+            underpromotion: settings["underpromotion"].as_f64().unwrap_or(0.),
+        };
+    }
+
+    /**
+     * [at(game)] returns how the color explores in game number [game] of the
+     * run, counting from 0.
+     */
+    pub fn at(&self, game: usize) -> Exploration {
+        let progress = if self.decay_games == 0 {
+            1.
+        } else {
+            (game as f64 / self.decay_games as f64).min(1.)
+        };
+        let epsilon = self.epsilon + (self.final_epsilon - self.epsilon) * progress;
+        return Exploration {
+            epsilon: epsilon.clamp(0., 1.),
+            temperature: self.temperature,
+            underpromotion: self.underpromotion.clamp(0., 1.),
+        };
+    }
+}
+
+impl OpponentMix {
+    /**
+     * [from_config(config)] reads the opponent probabilities from the parsed
+     * [config], which default to only the current policy.
+     */
+    pub fn from_config(config: &Value) -> OpponentMix {
+        let settings = &config["selfplay"];
+
+        let mut weights = Vec::new();
+        if let Value::Object(opponents) = &settings["opponents"] {
+            for (kind, weight) in opponents {
+                weights.push((parse_opponent_kind(kind), weight.as_f64().unwrap_or(0.)));
+            }
+        }
+        if weights.iter().all(|(_, w)| *w <= 0.) {
+            weights = vec![(OpponentKind::Policy, 1.)];
+        }
+
+        return OpponentMix {
+            weights,
+            checkpoints: CheckpointManager::from_config(config),
+            engine: settings["engine"].clone(),
+            eval_weights: EvalWeights::from_config(config),
+        };
+    }
+
+    /**
+     * [random_checkpoint(rng)] returns the path of a saved checkpoint picked
+     * with [rng], or None if there are none.
+     */
+    fn random_checkpoint(&self, rng: &mut StdRng) -> Option<String> {
+        let checkpoints = self.checkpoints.list();
+        if checkpoints.len() == 0 {
+            return None;
+        }
+
+        let i = rng.gen_range(0..checkpoints.len());
+        return Some(checkpoints[i].1.clone());
+    }
+
+    /**
+     * [sample(policy_path, exploration, rng)] picks the opponent for the next
+     * game with [rng] according to the configured probabilities, exploring
+     * according to [exploration], where [policy_path] is where the learner's
+     * current network is saved. Opponents that are unavailable, such as past
+     * checkpoints before any exist or an engine that fails to start, are
+     * replaced by the current policy. Returns an error if the current network
+     * is needed and cannot be loaded.
+     */
+    pub fn sample(
+        &self,
+        policy_path: &str,
+        exploration: &Exploration,
+        rng: &mut StdRng,
+    ) -> BotResult<Box<dyn Agent>> {
+        let dist = WeightedIndex::new(self.weights.iter().map(|(_, w)| w.max(0.))).unwrap();
+        let kind = self.weights[dist.sample(rng)].0;
+
+        let current_policy = || -> BotResult<Box<dyn Agent>> {
+            let mut agent = PolicyAgent::new(read_network(policy_path)?, "current policy");
+            agent.stats = read_stats(policy_path)?;
+            agent.temperature = exploration.temperature;
+            return Ok(Box::new(agent));
+        };
+        let agent: Box<dyn Agent> = match kind {
+            OpponentKind::Policy => current_policy()?,
+            OpponentKind::Random => Box::new(RandomAgent),
+            OpponentKind::Handcrafted => Box::new(SearchAgent {
+                weights: self.eval_weights.clone(),
+            }),
+            OpponentKind::GreedyCapture => Box::new(GreedyCaptureAgent {
+                weights: self.eval_weights.clone(),
+            }),
+            OpponentKind::MateBlocker => Box::new(MateBlockerAgent {
+                weights: self.eval_weights.clone(),
+            }),
+            OpponentKind::Checkpoint => match self.random_checkpoint(rng).map(|p| {
+                let network = read_network(&p).and_then(|nn| Ok((nn, read_stats(&p)?)));
+                (p, network)
+            }) {
+                Some((path, Ok((network, stats)))) => {
+                    let label = format!("checkpoint {}", path);
+                    let mut agent = PolicyAgent::new(network, &label);
+                    agent.stats = stats;
+                    agent.temperature = exploration.temperature;
+                    Box::new(agent)
+                }
+                Some((_, Err(e))) => {
+                    warn!("Unable to load checkpoint ({}), using current policy.", e);
+                    current_policy()?
+                }
+                None => current_policy()?,
+            },
+            OpponentKind::Engine => match UciEngine::from_config(&self.engine) {
+                Ok(engine) => Box::new(ExternalUciAgent { engine }),
+                Err(e) => {
+                    warn!("Unable to start engine ({}), using current policy.", e);
+                    current_policy()?
+                }
+            },
+        };
+
+        return Ok(Box::new(EpsilonGreedyAgent {
+            inner: agent,
+            epsilon: exploration.epsilon,
+            underpromotion: exploration.underpromotion,
+        }));
+    }
+}
+
+/**
+ * [exploring_policy(network, stats, label, exploration)] creates an agent
+ * playing with policy [network], whose inputs are normalized by its feature
+ * [stats], described by [label], that explores according to [exploration].
+ */
+pub fn exploring_policy<N: BorrowMut<FeedForward>>(
+    network: N,
+    stats: FeatureStats,
+    label: &str,
+    exploration: &Exploration,
+) -> EpsilonGreedyAgent<PolicyAgent<N>> {
+    let mut agent = PolicyAgent::new(network, label);
+    agent.stats = stats;
+    agent.temperature = exploration.temperature;
+    return EpsilonGreedyAgent {
+        inner: agent,
+        epsilon: exploration.epsilon,
+        underpromotion: exploration.underpromotion,
+    };
+}
+
+/**
+ * [complete_experience(pending, context, states, next_board, game_over,
+ * shaping, weights)] returns the experience of move [pending] from the
+ * perspective of the player who made it, reaching [next_board], the latest
+ * position of [context] whose encoding is looked up in [states], once the
+ * reply was played or the game ended as given by [game_over], with its reward
+ * shaped by [shaping] and the change in material under [weights].
+ */
+fn complete_experience(
+    pending: &PendingMove,
+    context: &GameContext,
+    states: &mut StateCache,
+    next_board: Board,
+    game_over: bool,
+    shaping: &RewardShaping,
+    weights: &EvalWeights,
+) -> Experience {
+    let player_white = pending.board.side_to_move() == Color::White;
+
+    // A side that ran out of time loses
+    let reward = if context.clock(player_white).flagged() {
+        shaping.time_loss
+    } else if context.clock(!player_white).flagged() {
+        WIN_REWARD
+    } else {
+        get_reward(&next_board, player_white)
+    };
+    let reward = shaping.shape(reward, pending.moves)
+        + shaping.material(&pending.board, &next_board, player_white, weights);
+    return Experience {
+        state: pending.state.clone(),
+        action: get_action(pending.chosen, player_white),
+        reward,
+        next_state: states.state(&context.history, player_white),
+        next_board,
+        clock: pending.clock,
+        done: game_over,
+        search_target: pending.search_target,
+        meta: ExperienceMeta::new(
+            ExperienceSource::SelfPlay,
+            None,
+            &pending.board,
+            pending.ply,
+            &pending.agent,
+        ),
+    };
+}
+
+/**
+ * [play_against_self(white, black, start, limits, shaping, log, display, pgn,
+ * adjudication, claims, novelty, engine, keep_probability, seed)] plays a game
+ * from board [start] between agents [white] and [black], each searching within
+ * its own of the White and Black [limits], and returns the experiences of both
+ * colors kept for learning, with rewards shaped by [shaping] and a bonus from
+ * [novelty], if given, for reaching rarely visited positions, blended with the
+ * evaluations of [engine] if given. Each experience spans a move and the reply
+ * to it from the perspective of the player who made the move, so that the
+ * player is to move again in its next state. Every move is recorded in [log]
+ * and [pgn] with its player's Q-value, and shown with the board it reaches as
+ * configured by [display], and the game is drawn early according to
+ * [adjudication], or when the side to move claims an available draw according
+ * to [claims]. Experiences that do not end the game are kept with
+ * [keep_probability]. The agents' random decisions and which experiences are
+ * kept are drawn from [seed]. Also returns the length of the game, White's
+ * result and total reward and the fraction of White's moves chosen by its
+ * policy.
+ */
+pub fn play_against_self(
+    white: &mut dyn Agent,
+    black: &mut dyn Agent,
+    start: Board,
+    limits: (SearchLimit, SearchLimit),
+    shaping: &RewardShaping,
+    log: &GameLog,
+    display: &DisplaySettings,
+    pgn: &mut PgnGame,
+    adjudication: &DrawAdjudication,
+    claims: &DrawClaimStrategy,
+    mut novelty: Option<&mut NoveltyBonus>,
+    mut engine: Option<&mut EngineShaping>,
+    keep_probability: f64,
+    seed: u64,
+) -> (Vec<Experience>, GameMetrics) {
+    let mut context = GameContext::new(&start, limits);
+    context.rng = StdRng::seed_from_u64(seed);
+    let mut experiences = Vec::new();
+    let mut metrics = GameMetrics::default();
+    metrics.result = 0.5;
+    let (mut white_moves, mut explored_moves) = (0, 0);
+    let eval_weights = EvalWeights::default();
+    let mut equal_moves = 0;
+    let mut pending: [Option<PendingMove>; 2] = [None, None]; // Black's and White's
+    let mut states = StateCache::default();
+
+    for plies in 1..=2 * MAX_MOVES {
+        let board = context.board();
+        let player_white = context.player_white();
+        let clock = match context.clock(player_white).limit {
+            SearchLimit::Clock { .. } => {
+                Some(context.clock(player_white).remaining_ms as f64 / 1000.)
+            }
+            _ => None,
+        };
+
+        // The side to move moves
+        let agent: &mut dyn Agent = if player_white {
+            &mut *white
+        } else {
+            &mut *black
+        };
+        let decision = match agent.select_move(&mut context) {
+            Some(d) => d,
+            None => break,
+        };
+        if player_white {
+            white_moves += 1;
+            if decision.source == MoveSource::Exploration {
+                explored_moves += 1;
+            }
+        }
+        let q = agent.evaluate(&context, decision.chosen).unwrap_or(0.);
+        log.record(context.ply(), &context.history.fen(), &board, &decision, q);
+        pgn.push(decision.chosen, Some(q));
+        let mover = PendingMove {
+            board,
+            state: states.state(&context.history, player_white),
+            chosen: decision.chosen,
+            clock,
+            ply: context.ply(),
+            moves: (plies + 1) / 2,
+            q,
+            search_target: decision.target,
+            agent: agent.name(),
+        };
+        context.make_move(decision.chosen);
+        if display.boards {
+            let rendered = render_position(&context.board(), Some(decision.chosen), true, Some(q));
+            println!("{}", rendered);
+        }
+
+        // The opponent may claim a draw before replying
+        let next_board = context.board();
+        let claimed = next_board.status() == BoardStatus::Ongoing
+            && claims.should_claim(&context.history, !player_white, &eval_weights);
+        if claimed {
+            info!("Claimed a draw in {}", next_board);
+        }
+
+        // Count how long the game has been dead equal after White's move and
+        // the reply to it
+        let mut adjudicated = false;
+        if let Some(p) = pending[1].as_ref().filter(|_| !player_white) {
+            if adjudication.is_equal(evaluate(&next_board, true, &eval_weights), p.q) {
+                equal_moves += 1;
+            } else {
+                equal_moves = 0;
+            }
+            adjudicated = adjudication.moves > 0 && equal_moves >= adjudication.moves;
+            if adjudicated {
+                info!("Adjudicated a draw after {} equal moves", equal_moves);
+            }
+        }
+
+        // The game ended, which a game cut off at the move limit or by a
+        // shutdown did not, so its last experiences still bootstrap
+        let game_over = next_board.status() != BoardStatus::Ongoing
+            || adjudicated
+            || claimed
+            || context.history.is_automatic_draw()
+            || context.clocks.0.flagged()
+            || context.clocks.1.flagged();
+        let done = game_over || plies == 2 * MAX_MOVES || shutdown::requested();
+
+        // The move completes the opponent's experience, and the mover's own
+        // experience too when it ended the game
+        pending[player_white as usize] = Some(mover);
+        let mut completed = vec![!player_white];
+        if game_over {
+            completed.push(player_white);
+        }
+        let bonus = match novelty.as_mut() {
+            Some(n) if next_board.status() == BoardStatus::Ongoing => n.visit(&next_board),
+            _ => 0.,
+        };
+        for color in completed {
+            let p = match pending[color as usize].take() {
+                Some(p) => p,
+                None => continue,
+            };
+            let mut experience = complete_experience(
+                &p,
+                &context,
+                &mut states,
+                next_board,
+                game_over,
+                shaping,
+                &eval_weights,
+            );
+            let outcome = experience.reward;
+            if let Some(e) = engine.as_mut() {
+                experience.reward = e.blend(outcome, &p.board, &next_board, color);
+            }
+            experience.reward += bonus;
+            debug!(
+                event = "reward",
+                white = color,
+                outcome,
+                bonus,
+                reward = experience.reward,
+                done = experience.done,
+                "Reward recorded"
+            );
+            if color {
+                metrics.total_reward += experience.reward;
+                if game_over {
+                    metrics.result = 0.5 + outcome.signum() / 2.;
+                }
+            }
+            if done || context.rng.gen_bool(keep_probability) {
+                experiences.push(experience);
+            }
+        }
+        if done {
+            break;
+        }
+    }
+
+    metrics.plies = context.history.ply();
+    metrics.policy_fraction = 1. - explored_moves as f64 / white_moves.max(1) as f64;
+    return (experiences, metrics);
+}
+
+/**
+ * [game_seed(run_seed, game)] returns the seed of game number [game] of the
+ * run with seed [run_seed], counting from 0.
+ */
+pub fn game_seed(run_seed: u64, game: usize) -> u64 {
+    let mut rng = StdRng::seed_from_u64(run_seed ^ (game as u64).wrapping_mul(GAME_SEED_STRIDE));
+    return rng.gen();
+}
+
+impl SelfPlaySettings {
+    /**
+     * [from_config(config)] reads the self-play settings from the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> SelfPlaySettings {
+        let exploration = &config["selfplay"]["exploration"];
+        let limit = |color: &str| match config["selfplay"]["limits"][color].as_str() {
+            Some(s) => parse_limit(s),
+            None => SearchLimit::Unlimited,
+        };
+        let openings = &config["selfplay"]["openings"];
+        let suite = match openings["suite"].as_str() {
+            Some(path) => {
+                let book_plies = openings["book_plies"]
+                    .as_u64()
+                    .map_or(DEFAULT_BOOK_PLIES, |n| n as usize);
+                let suite = load_opening_suite(path, book_plies);
+                info!("Loaded {} opening positions from {}", suite.len(), path);
+                suite
+            }
+            None => Vec::new(),
+        };
+
+        return SelfPlaySettings {
+            mix: OpponentMix::from_config(config),
+            handicap: Handicap::from_config(&config["selfplay"]["odds"]),
+            white_schedule: ExplorationSchedule::from_config(
+                &exploration["white"],
+                DEFAULT_WHITE_EPSILON,
+            ),
+            black_schedule: ExplorationSchedule::from_config(
+                &exploration["black"],
+                DEFAULT_BLACK_EPSILON,
+            ),
+            limits: (limit("white"), limit("black")),
+            shaping: RewardShaping::from_config(config),
+            adjudication: DrawAdjudication::from_config(&config["selfplay"]["adjudication"]),
+            claims: DrawClaimStrategy::from_config(config),
+            move_log: MoveLog::from_config(config),
+            display: DisplaySettings::from_config(config),
+            pgn: PgnLog::default(),
+            suite,
+            suite_fraction: openings["fraction"].as_f64().unwrap_or(1.).clamp(0., 1.),
+            novelty: NoveltyBonus::from_config(&config["selfplay"]["novelty"]),
+            engine_shaping: EngineShaping::from_config(config)
+                .expect("Unable to start the engine shaping rewards"),
+            mcts: match config["selfplay"]["mcts"].as_bool() {
+                Some(true) => Some(MctsSettings::from_config(config)),
+                _ => None,
+            },
+            // Multi-step returns follow every move of a game
+            keep_probability: if ReturnTarget::from_config(config).horizon() > 1 {
+                1.
+            } else {
+                KEEP_PROBABILITY
+            },
+        };
+    }
+
+    /**
+     * [play(network, stats, policy_path, game, seed, log_id)] plays game
+     * number [game] of a run, counting from 0, with policy [network], whose
+     * inputs are normalized by its feature [stats], as the learner against an opponent picked with [seed], where [policy_path] is where the
+     * learner's current network is saved. Every random decision of the game
+     * is drawn from [seed], so replaying it from the same network and seed
+     * gives the same game, except against an external engine. The moves are
+     * logged under [log_id], and the game is recorded as PGN if configured.
+     * Returns the experiences of both colors kept for learning, each from
+     * its mover's perspective, along with the metrics of the game, or an
+     * error if the opponent cannot be created.
+     */
+    pub fn play(
+        &mut self,
+        network: &mut FeedForward,
+        stats: FeatureStats,
+        policy_path: &str,
+        game: usize,
+        seed: u64,
+        log_id: &str,
+    ) -> BotResult<(Vec<Experience>, GameMetrics)> {
+        let _span = game_span(log_id).entered();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let white = self.white_schedule.at(game);
+        let black = self.black_schedule.at(game);
+        let mut opponent = self.mix.sample(policy_path, &black, &mut rng)?;
+        let start = match &self.handicap {
+            Some(h) => h.start_board(true, &mut rng),
+            None if self.suite.len() > 0 && rng.gen_bool(self.suite_fraction) => {
+                self.suite[rng.gen_range(0..self.suite.len())]
+            }
+            None => Board::default(),
+        };
+        info!(
+            "Game {}: playing against {} from {} (seed {})",
+            game + 1,
+            opponent.name(),
+            start,
+            seed
+        );
+        info!("Exploration: white {:?}, black {:?}", white, black);
+
+        let mut learner: Box<dyn Agent> = match self.mcts {
+            Some(settings) => {
+                let mut mcts = MctsAgent::new(network, "learner", settings);
+                mcts.stats = stats;
+                Box::new(EpsilonGreedyAgent {
+                    inner: mcts,
+                    epsilon: white.epsilon,
+                    underpromotion: white.underpromotion,
+                })
+            }
+            None => Box::new(exploring_policy(network, stats, "learner", &white)),
+        };
+        let mut pgn = PgnGame::new(log_id, &learner.name(), &opponent.name(), &start);
+        let (mut experiences, mut metrics) = play_against_self(
+            &mut *learner,
+            &mut *opponent,
+            start,
+            self.limits,
+            &self.shaping,
+            &self.move_log.game(log_id),
+            &self.display,
+            &mut pgn,
+            &self.adjudication,
+            &self.claims,
+            self.novelty.as_mut(),
+            self.engine_shaping.as_mut(),
+            self.keep_probability,
+            rng.gen(),
+        );
+        for e in experiences.iter_mut() {
+            e.meta.game_id = Some(log_id.to_string());
+        }
+        if let Err(e) = self
+            .pgn
+            .write(&pgn, result_from_reward(metrics.result - 0.5))
+        {
+            warn!("Unable to record the game as PGN: {}", e);
+        }
+
+        metrics.game = game + 1;
+        metrics.opponent = opponent.name();
+        return Ok((experiences, metrics));
+    }
+}
+
+/**
+ * [hyperparameters(config)] returns the hyperparameters of training given by
+ * the parsed [config], to be recorded in the metadata of the networks trained.
+ */
+fn hyperparameters(config: &Value) -> Value {
+    return json!({
+        "gamma": discount(config),
+        "selfplay": config["selfplay"],
+        "replay": config["replay"],
+        "target_network": config["target_network"],
+        "reward": config["reward"],
+        "output_scaling": config["output_scaling"],
+    });
+}
+
+/**
+ * [run_selfplay(config, games, resume)] plays self-play games against
+ * opponents picked according to the parsed [config] until its training plan
+ * stops the run, after [games] games if given, learning after each
+ * game with the white policy network from minibatches of a replay buffer of
+ * the recent games, against a target network kept across games, and saving
+ * it after every game along with the replay buffer, if it has a path. A checkpoint
+ * of the network is saved every checkpoint interval. Runs resume after the
+ * games the network was already trained on, as recorded in its metadata.
+ * Each game is played from its own seed, derived from the run's seed and
+ * recorded in the metrics so that the game can be replayed. The metrics of
+ * each game and of each minibatch learned from are also logged as configured.
+ * The network is evaluated against the gauntlet as often as the plan sets,
+ * and a summary of the run is printed once it stops, after which the network
+ * is gated for promotion if configured (see arena).
+ * With [resume] the run first restores the latest checkpoint as the white
+ * policy network, along with its target network, metadata and run seed.
+ * Returns an error, ending the run, if a network cannot be loaded or saved.
+ */
+pub fn run_selfplay(config: &Value, games: Option<usize>, resume: bool) -> BotResult<()> {
+    let mut models = ModelRegistry::from_config(config)?;
+    let checkpoints = CheckpointManager::from_config(config);
+    let restored = match checkpoints.latest() {
+        Some(path) if resume => {
+            info!("Restoring checkpoint {}", path);
+            let (network, stats) = models.learner(true);
+            *network = read_network(&path)?;
+            *stats = read_stats(&path)?;
+            models.save(true)?;
+            write_metadata(models.path(true), &read_metadata(&path));
+            Some(path)
+        }
+        _ if resume => {
+            info!("No checkpoint to resume from, continuing from the policy network");
+            None
+        }
+        _ => None,
+    };
+    let mut settings = SelfPlaySettings::from_config(config);
+    let scaling = OutputScaling::from_config(config);
+    let returns = ReturnTarget::from_config(config);
+    let mut replay = ReplayBuffer::from_config(config);
+    let replay_path = config["replay"]["buffer_path"].as_str();
+    if let Some(path) = replay_path {
+        match replay.load(path, true) {
+            Ok(n) => info!("Loaded {} experiences from {}", n, path),
+            Err(e) => warn!("Unable to load experiences from {}: {}", path, e),
+        };
+    }
+    let (network, stats) = models.learner(true);
+    let mut target = TargetNetwork::from_config(config, network, *stats);
+    if let Some(path) = restored.as_ref().map(|p| target_path(p)) {
+        if Path::new(&path).exists() {
+            target.network = read_network(&path)?;
+            target.stats = read_stats(&path)?;
+        }
+    }
+    let metrics_path = config["selfplay"]["metrics"].as_str();
+    let mut metrics_log = MetricsLog::from_config(config);
+    let run_seed = match config["selfplay"]["seed"].as_u64().or(config_seed(config)) {
+        Some(seed) => seed,
+        None if resume => read_metadata(models.path(true))
+            .run_seed
+            .unwrap_or_else(|| rand::thread_rng().gen()),
+        None => rand::thread_rng().gen(),
+    };
+    info!("Run seed: {}", run_seed);
+    settings.pgn = PgnLog::from_config(config, &format!("selfplay-{}", run_seed));
+    let mut curriculum = Curriculum::from_config(config);
+    if let Some(c) = &curriculum {
+        settings.suite = c.positions(game_seed(run_seed, c.stage));
+        settings.suite_fraction = 1.;
+        info!("Curriculum at {}", c.describe());
+    }
+
+    let trained = read_metadata(models.path(true)).games;
+    if trained > 0 {
+        info!("Resuming after {} games", trained);
+    }
+
+    let policy_path = models.path(true).to_string();
+    let mut plan = TrainingPlan::from_config(config, games);
+    let mut reason = StopReason::Shutdown;
+    for i in trained.. {
+        if let Some(r) = plan.stop_reason() {
+            reason = r;
+            break;
+        }
+        let seed = game_seed(run_seed, i);
+        let stats = models.stats(true);
+        let (experiences, mut metrics) = settings.play(
+            models.network(true),
+            stats,
+            &policy_path,
+            i,
+            seed,
+            &format!("selfplay-{}", i + 1),
+        )?;
+        info!("Collected {} experiences", experiences.len());
+        let count = experiences.len();
+
+        // Learn from minibatches of the buffer, as many experiences as the
+        // game added
+        let dropped = replay.dropped_duplicates;
+        replay.extend(experiences);
+        if replay.dropped_duplicates > dropped {
+            info!(
+                "Dropped {} duplicate experiences",
+                replay.dropped_duplicates - dropped
+            );
+        }
+        let (network, feature_stats) = models.learner(true);
+        let stats = learn_from_experience(
+            network,
+            feature_stats,
+            &mut target,
+            &replay,
+            count,
+            discount(config),
+            &scaling,
+            &returns,
+            &mut StdRng::seed_from_u64(game_seed(seed, LEARNING_SEED_STREAM)),
+        );
+        metrics.mean_td_error = stats.mean_td_error;
+        metrics.buffer_size = replay.len();
+        metrics.loss = stats.loss;
+        metrics_log.record_game(&metrics);
+        metrics_log.record_batches(i + 1, &stats.batch_losses);
+        plan.record_game(metrics.result);
+
+        // Move on to the next stage of the curriculum once the learner
+        // converts the positions of its current stage often enough
+        let stage = curriculum.as_ref().map(|c| c.stage + 1);
+        if let Some(c) = curriculum.as_mut() {
+            if c.record(metrics.result) {
+                settings.suite = c.positions(game_seed(run_seed, c.stage));
+                info!("Curriculum moved on to {}", c.describe());
+            }
+        }
+        if let Some(path) = metrics_path {
+            let entry = json!({
+                "game": i + 1,
+                "opponent": metrics.opponent,
+                "experiences": count,
+                "result": metrics.result,
+                "loss": stats.loss,
+                "epsilon": settings.white_schedule.at(i).epsilon,
+                "seed": seed,
+                "stage": stage,
+            });
+            record_metrics(path, &entry);
+        }
+        models.save(true)?;
+        if let Some(path) = replay_path {
+            if let Err(e) = replay.save(path, true) {
+                warn!("Unable to save experiences to {}: {}", path, e);
+            }
+        }
+        let mut metadata = read_metadata(models.path(true));
+        metadata.games = i + 1;
+        metadata.epsilon = Some(settings.white_schedule.at(i).epsilon);
+        metadata.run_seed = Some(run_seed);
+        metadata.hyperparameters = hyperparameters(config);
+        write_metadata(models.path(true), &metadata);
+
+        // Evaluate before saving a checkpoint, so that the network is never
+        // played against itself
+        if plan.evaluation_due() {
+            plan.evaluate(config, &policy_path, i + 1);
+        }
+        if checkpoints.interval > 0 && (i + 1) % checkpoints.interval == 0 {
+            let stats = models.stats(true);
+            let path = checkpoints.save_with_target(
+                models.network(true),
+                &stats,
+                Some((&target.network, &target.stats)),
+                &metadata,
+            )?;
+            info!("Saved checkpoint {}", path);
+        } else if shutdown::requested() {
+            let stats = models.stats(true);
+            let path = checkpoints.save_with_target(
+                models.network(true),
+                &stats,
+                Some((&target.network, &target.stats)),
+                &metadata,
+            )?;
+            info!("Saved checkpoint {} before shutting down", path);
+        }
+        if shutdown::requested() {
+            break;
+        }
+    }
+    print!("{}", plan.summary(reason));
+    if reason != StopReason::Shutdown {
+        gate_after_training(config);
+    }
+    return Ok(());
+}
+
+/**
+ * [replay_selfplay(config, game, seed, checkpoint)] plays game number [game]
+ * of a self-play run again, counting from 1, from its recorded [seed] with
+ * the network saved at [checkpoint] (the white policy network if None) as
+ * both the learner and the current policy, without learning from it. The
+ * replayed game makes the same decisions as the original as long as the
+ * network is the one the original was played with. Every experience is
+ * printed, and White's moves are logged under "replay-<game>". Returns an
+ * error if the network or the opponent cannot be loaded.
+ */
+pub fn replay_selfplay(
+    config: &Value,
+    game: usize,
+    seed: u64,
+    checkpoint: Option<&str>,
+) -> BotResult<()> {
+    let mut settings = SelfPlaySettings::from_config(config);
+    let path = match checkpoint {
+        Some(p) => p.to_string(),
+        None => white_path(config),
+    };
+    let mut network = read_network(&path)?;
+    let stats = read_stats(&path)?;
+
+    let (experiences, _) = settings.play(
+        &mut network,
+        stats,
+        &path,
+        game.max(1) - 1,
+        seed,
+        &format!("replay-{}", game),
+    )?;
+    for (i, e) in experiences.iter().enumerate() {
+        info!(
+            "Experience {}: reward {}, reaching {}",
+            i + 1,
+            e.reward,
+            e.next_board
+        );
+    }
+    return Ok(());
+}
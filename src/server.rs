@@ -0,0 +1,199 @@
+/**
+ * Utility module for serving the policy networks over HTTP, so that other
+ * programs (e.g. a web GUI or analysis scripts) can use them. Requests are
+ * POSTed as json holding a FEN, e.g. {"fen": "rnbqkbnr/pppppppp/8/8/4P3/8/
+ * PPPP1PPP/RNBQKBNR b KQkq - 0 1"}: /bestmove answers with the best move for
+ * the side to move and its score, e.g. {"move": "e7e5", "san": "e5", "score":
+ * 0.12}, and /evaluate with the score of every legal move, best first, e.g.
+ * {"moves": [{"move": "e7e5", "san": "e5", "score": 0.12}, ...]}. Failed
+ * requests are answered with {"error": "..."}. The address listened on is
+ * given by the "server" object in config.json, e.g.
+ * {"address": "127.0.0.1:8080"}. Requests are answered one at a time, since
+ * each takes a single batch through the network.
+ */
+use crate::error::{BotError, BotResult};
+use crate::history::MoveCounters;
+use crate::mdp::{best_scored_move, evaluate_position};
+use crate::models::ModelRegistry;
+use crate::notation::to_san;
+
+use chess::{Board, ChessMove, Color};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+// Address listened on unless configured otherwise
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+// Largest request body read, which is plenty for a FEN
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+// How long a client may take to send its request before it is dropped, so
+// that a stalled client does not hold up the others
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A response to a request, as its status line and json body
+struct Response {
+    status: &'static str,
+    body: Value,
+}
+
+impl Response {
+    /**
+     * [ok(body)] answers a request with json [body].
+     */
+    fn ok(body: Value) -> Response {
+        return Response {
+            status: "200 OK",
+            body,
+        };
+    }
+
+    /**
+     * [error(status, message)] answers a request with [status] and an error
+     * [message].
+     */
+    fn error(status: &'static str, message: &str) -> Response {
+        return Response {
+            status,
+            body: json!({ "error": message }),
+        };
+    }
+}
+
+/**
+ * [server_address(config)] returns the address the server listens on, as
+ * given by the parsed [config].
+ */
+pub fn server_address(config: &Value) -> String {
+    return config["server"]["address"]
+        .as_str()
+        .unwrap_or(DEFAULT_ADDRESS)
+        .to_string();
+}
+
+/**
+ * [scored_move(board, m, score)] returns the json describing move [m] in
+ * [board] and its [score].
+ */
+fn scored_move(board: &Board, m: ChessMove, score: f64) -> Value {
+    return json!({
+        "move": m.to_string(),
+        "san": to_san(board, m),
+        "score": score,
+    });
+}
+
+/**
+ * [respond(models, path, body)] answers a POST to [path] with request [body]
+ * using the networks in [models].
+ */
+fn respond(models: &mut ModelRegistry, path: &str, body: &str) -> Response {
+    if path != "/bestmove" && path != "/evaluate" {
+        return Response::error("404 Not Found", "unknown endpoint");
+    }
+    let fen = match serde_json::from_str::<Value>(body) {
+        Ok(request) => match request["fen"].as_str() {
+            Some(fen) => fen.to_string(),
+            None => return Response::error("400 Bad Request", "missing fen"),
+        },
+        Err(e) => return Response::error("400 Bad Request", &e.to_string()),
+    };
+    let board = match Board::from_str(&fen) {
+        Ok(b) => b,
+        Err(_) => {
+            return Response::error("400 Bad Request", &BotError::InvalidFen(fen).to_string())
+        }
+    };
+
+    let player_white = board.side_to_move() == Color::White;
+    let nn = &mut models.head_for(&board, player_white);
+    let counters = MoveCounters::from_fen(&fen);
+    let mut scores = evaluate_position(&board, counters, nn, player_white);
+    if path == "/bestmove" {
+        return match best_scored_move(&scores) {
+            Some((m, score)) => Response::ok(scored_move(&board, m, score)),
+            None => Response::error("422 Unprocessable Entity", "no legal moves"),
+        };
+    }
+
+    scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    let moves: Vec<Value> = scores
+        .iter()
+        .map(|(m, score)| scored_move(&board, *m, *score))
+        .collect();
+    return Response::ok(json!({ "moves": moves }));
+}
+
+/**
+ * [handle_connection(stream, models)] reads a request from [stream] and
+ * answers it using the networks in [models].
+ */
+async fn handle_connection(stream: TcpStream, models: &mut ModelRegistry) -> BotResult<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    // Headers end at an empty line, and only the body's length is needed
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().len() == 0 {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let response = if method != "POST" {
+        Response::error("405 Method Not Allowed", "only POST is supported")
+    } else if content_length > MAX_BODY_BYTES {
+        Response::error("413 Payload Too Large", "request body is too large")
+    } else {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+        respond(models, path, &String::from_utf8_lossy(&body))
+    };
+
+    let body = response.body.to_string();
+    let message = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        body.len(),
+        body
+    );
+    let mut stream = reader.into_inner();
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await?;
+    return Ok(());
+}
+
+/**
+ * [run_server(config)] serves the policy networks given by the parsed
+ * [config] over HTTP at the configured address, forever. A connection that
+ * fails or stalls is logged and dropped.
+ */
+pub async fn run_server(config: &Value) -> BotResult<()> {
+    let mut models = ModelRegistry::from_config(config)?;
+    let address = server_address(config);
+    let listener = TcpListener::bind(&address).await?;
+    info!("Serving on http://{}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        match timeout(REQUEST_TIMEOUT, handle_connection(stream, &mut models)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Request from {} failed: {}", peer, e),
+            Err(_) => warn!("Request from {} timed out", peer),
+        };
+    }
+}
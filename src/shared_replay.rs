@@ -0,0 +1,130 @@
+/**
+ * Utility module for funnelling the experiences of every Lichess game the bot
+ * plays into one shared replay buffer. Each game sends its experiences as an
+ * episode tagged with the game's id through a channel, so games can run as
+ * their own tasks, and the daemon collects the episodes into the sharded
+ * replay storage. Learning passes over a sample of the buffer are triggered by
+ * the "replay" settings in config.json rather than after every game, e.g.
+ * {"learn_every_games": 4, "sample_size": 1000} learns from 1000 sampled
+ * experiences once four new games have been collected, and the default of 0
+ * only learns during the train windows of the schedule. An actor of
+ * distributed training sends its episodes on to the learner instead of
+ * storing them.
+ */
+use crate::distributed::LearnerLink;
+use crate::mdp::Experience;
+use crate::replay_shards::ShardedReplay;
+
+use serde_json::Value;
+use std::io;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
+
+// The experiences collected over a single game, tagged with the game's id
+#[derive(Clone, Debug)]
+pub struct Episode {
+    pub game_id: String,
+    pub player_white: bool,
+    pub experiences: Vec<Experience>,
+}
+
+// The sending end of the shared replay buffer, cloned into every game
+#[derive(Clone, Debug)]
+pub struct EpisodeSender {
+    sender: UnboundedSender<Episode>,
+}
+
+// Default number of experiences sampled for a learning pass between games
+const DEFAULT_SAMPLE_SIZE: usize = 1000;
+
+// The replay buffer shared by every game, stored in shards
+pub struct SharedReplayBuffer {
+    receiver: UnboundedReceiver<Episode>,
+    pub storage: ShardedReplay,
+    pub learn_every_games: usize, // 0 only learns during train windows
+    pub sample_size: usize,
+    pub learner: Option<LearnerLink>, // where an actor sends its episodes
+    games_since_learning: usize,
+}
+
+impl EpisodeSender {
+    /**
+     * [send(episode)] sends [episode] to the shared replay buffer. Episodes
+     * sent after the buffer is gone are dropped.
+     */
+    pub fn send(&self, episode: Episode) {
+        if self.sender.send(episode).is_err() {
+            warn!("Replay buffer is closed, dropping episode");
+        }
+    }
+}
+
+impl SharedReplayBuffer {
+    /**
+     * [from_config(config, storage)] creates a shared replay buffer stored in
+     * [storage] with learning passes triggered according to the parsed
+     * [config], returning it along with the sender games use to add their
+     * episodes.
+     */
+    pub fn from_config(
+        config: &Value,
+        storage: ShardedReplay,
+    ) -> (SharedReplayBuffer, EpisodeSender) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let settings = &config["replay"];
+        let buffer = SharedReplayBuffer {
+            receiver,
+            storage,
+            learn_every_games: settings["learn_every_games"].as_u64().unwrap_or(0) as usize,
+            sample_size: settings["sample_size"]
+                .as_u64()
+                .map_or(DEFAULT_SAMPLE_SIZE, |n| n as usize),
+            learner: None,
+            games_since_learning: 0,
+        };
+
+        return (buffer, EpisodeSender { sender });
+    }
+
+    /**
+     * [collect()] stores every episode sent since the last collection in the
+     * replay storage, or sends it to the learner if there is one, returning
+     * the number of episodes collected.
+     */
+    pub fn collect(&mut self) -> io::Result<usize> {
+        let mut collected = 0;
+        while let Ok(episode) = self.receiver.try_recv() {
+            let (experiences, player_white) = (&episode.experiences, episode.player_white);
+            match self.learner.as_mut() {
+                Some(learner) => {
+                    learner.send_episode(&episode.game_id, player_white, experiences)?
+                }
+                None => self.storage.append(experiences, player_white)?,
+            };
+            info!(
+                "Collected {} experiences from game {}",
+                episode.experiences.len(),
+                episode.game_id
+            );
+            collected += 1;
+        }
+        self.games_since_learning += collected;
+
+        return Ok(collected);
+    }
+
+    /**
+     * [learning_due()] returns whether enough games have been collected since
+     * the last learning pass to trigger another one.
+     */
+    pub fn learning_due(&self) -> bool {
+        return self.learn_every_games > 0 && self.games_since_learning >= self.learn_every_games;
+    }
+
+    /**
+     * [mark_learned()] records that a learning pass over the buffer was made.
+     */
+    pub fn mark_learned(&mut self) {
+        self.games_since_learning = 0;
+    }
+}
@@ -0,0 +1,40 @@
+/**
+ * Utility module for shutting down gracefully on Ctrl-C. The first Ctrl-C
+ * only requests a shutdown: Lichess and self-play games stop once the move
+ * being made is played, the experiences collected so far are stored and the
+ * networks, replay buffer and a checkpoint are saved (each written to a
+ * temporary file and renamed into place, so an interrupted save never leaves
+ * a half-written file) before the process exits. A second Ctrl-C quits
+ * immediately.
+ */
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+// Exit code of a process quit by a second Ctrl-C
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// Whether Ctrl-C has been pressed
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * [install_handler()] starts listening for Ctrl-C on the current tokio
+ * runtime, requesting a shutdown the first time and quitting the second.
+ */
+pub fn install_handler() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if REQUESTED.swap(true, Ordering::SeqCst) {
+                warn!("Quitting without saving");
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+            info!("Shutting down after the current move, press Ctrl-C again to quit now");
+        }
+    });
+}
+
+/**
+ * [requested()] returns whether a shutdown has been requested.
+ */
+pub fn requested() -> bool {
+    return REQUESTED.load(Ordering::SeqCst);
+}
@@ -0,0 +1,259 @@
+/**
+ * Utility module for hyperparameter sweeps, which train and evaluate a list
+ * of configurations in turn so they can be compared without editing
+ * constants. A sweep is described by a json file, e.g.
+ * {"name": "gamma-hidden", "games": 50, "baseline": "handcrafted",
+ *  "openings": 10, "plies": 8, "seed": 0,
+ *  "grid": {"gamma": [0.9, 0.99], "model.hidden": [[64], [128, 64]],
+ *           "selfplay.exploration.white.epsilon": [0.1, 0.3]},
+ *  "configs": [{"gamma": 0.95, "selfplay.exploration.white.decay_games": 40}]}
+ * where each key is a dotted path into config.json. Every combination of the
+ * "grid" values is tried, along with every entry of "configs". Each
+ * configuration trains a fresh network with its own architecture over "games"
+ * self-play games in its own run (named after the sweep and numbered), then
+ * plays it against the "baseline" player (a network path or scripted
+ * opponent) from "openings" seeded random openings of "plies" plies with both
+ * colors. The configurations are ranked by their score in a results table
+ * written as csv to "output" (by default <name>.csv). A sweep that is
+ * interrupted picks up where it stopped, since each run resumes after the
+ * games it already trained on.
+ */
+use crate::arena::{compare, parse_player, PairedComparison};
+use crate::checkpoint::read_metadata;
+use crate::error::{BotError, BotResult};
+use crate::limits::SearchLimit;
+use crate::model::ModelBuilder;
+use crate::runs::Run;
+use crate::sampling::seeded_openings;
+use crate::selfplay::run_selfplay;
+use crate::shutdown;
+
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_GAMES: u64 = 50;
+const DEFAULT_BASELINE: &str = "handcrafted";
+const DEFAULT_OPENINGS: u64 = 10;
+const DEFAULT_PLIES: u64 = 8;
+
+// A sweep over configurations, as read from its json file
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub name: String,
+    pub games: usize, // self-play games trained per configuration
+    pub baseline: String,
+    pub openings: usize,
+    pub plies: usize,
+    pub seed: u64,
+    pub output: String,
+    pub trials: Vec<Map<String, Value>>, // the overrides of each configuration
+}
+
+// The outcome of training and evaluating one configuration
+#[derive(Clone, Debug)]
+pub struct TrialResult {
+    pub run: String,
+    pub overrides: Map<String, Value>,
+    pub comparison: PairedComparison,
+}
+
+/**
+ * [set_path(config, path, value)] sets the setting at the dotted [path] of
+ * [config] (e.g. "selfplay.exploration.white.epsilon") to [value], creating
+ * the objects along the way.
+ */
+pub fn set_path(config: &mut Value, path: &str, value: Value) {
+    let mut setting = config;
+    for key in path.split('.') {
+        setting = &mut setting[key];
+    }
+    *setting = value;
+}
+
+/**
+ * [expand_grid(grid)] returns every combination of the values listed for
+ * each setting of [grid], in order.
+ */
+pub fn expand_grid(grid: &Map<String, Value>) -> Vec<Map<String, Value>> {
+    let mut combinations = vec![Map::new()];
+    for (path, values) in grid {
+        let values = match values.as_array() {
+            Some(a) => a.clone(),
+            None => vec![values.clone()],
+        };
+        combinations = combinations
+            .iter()
+            .flat_map(|c| {
+                values.iter().map(move |v| {
+                    let mut combination = c.clone();
+                    combination.insert(path.clone(), v.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    return combinations;
+}
+
+impl Sweep {
+    /**
+     * [load(path)] reads the sweep described by the json file at [path].
+     */
+    pub fn load(path: &str) -> BotResult<Sweep> {
+        let text = fs::read_to_string(path)?;
+        let spec: Value = serde_json::from_str(&text)?;
+
+        let mut trials = match spec["grid"].as_object() {
+            Some(grid) => expand_grid(grid),
+            None => Vec::new(),
+        };
+        if let Some(configs) = spec["configs"].as_array() {
+            trials.extend(configs.iter().filter_map(|c| c.as_object().cloned()));
+        }
+        if trials.len() == 0 {
+            return Err(BotError::Config(format!(
+                "sweep {} has no configurations",
+                path
+            )));
+        }
+
+        let name = spec["name"].as_str().unwrap_or("sweep").to_string();
+        let count = |key: &str, default: u64| spec[key].as_u64().unwrap_or(default) as usize;
+        return Ok(Sweep {
+            games: count("games", DEFAULT_GAMES),
+            baseline: spec["baseline"]
+                .as_str()
+                .unwrap_or(DEFAULT_BASELINE)
+                .to_string(),
+            openings: count("openings", DEFAULT_OPENINGS),
+            plies: count("plies", DEFAULT_PLIES),
+            seed: spec["seed"].as_u64().unwrap_or(0),
+            output: spec["output"]
+                .as_str()
+                .map_or(format!("{}.csv", name), |s| s.to_string()),
+            name,
+            trials,
+        });
+    }
+
+    /**
+     * [trial_config(config, overrides)] returns the parsed [config] with the
+     * settings in [overrides] applied.
+     */
+    fn trial_config(config: &Value, overrides: &Map<String, Value>) -> Value {
+        let mut trial = config.clone();
+        for (path, value) in overrides {
+            set_path(&mut trial, path, value.clone());
+        }
+        return trial;
+    }
+
+    /**
+     * [run_trial(config, index)] trains configuration number [index] of the
+     * sweep on top of the parsed [config] in its own run, starting from a
+     * fresh network, and evaluates it against the baseline.
+     */
+    fn run_trial(&self, config: &Value, index: usize) -> BotResult<TrialResult> {
+        let overrides = &self.trials[index];
+        let trial = Sweep::trial_config(config, overrides);
+        let run = Run::open(&trial, &format!("{}-{:03}", self.name, index + 1));
+        println!(
+            "Sweep {}: configuration {}/{} ({}) {}",
+            self.name,
+            index + 1,
+            self.trials.len(),
+            run.name,
+            Value::Object(overrides.clone())
+        );
+
+        // Each configuration starts from its own fresh network, so that
+        // architectures can differ, and keeps its experiences to itself
+        if !Path::new(&run.model_path()).exists() {
+            fs::create_dir_all(&run.dir)?;
+            ModelBuilder::from_config(&trial).create(&run.model_path())?;
+        }
+        let mut run_config = run.start(&trial);
+        run_config["replay"]["buffer_path"] = Value::Null;
+
+        let trained = read_metadata(&run.model_path()).games;
+        if trained < self.games {
+            run_selfplay(&run_config, Some(self.games - trained), false)?;
+        }
+
+        let openings = seeded_openings(self.openings, self.plies, self.seed);
+        let comparison = compare(
+            &(run.model_path(), SearchLimit::Unlimited),
+            &parse_player(&self.baseline),
+            &openings,
+        )?;
+        println!(
+            "{} scored {:.3} against {}",
+            run.name,
+            comparison.score(),
+            self.baseline
+        );
+        return Ok(TrialResult {
+            run: run.name,
+            overrides: overrides.clone(),
+            comparison,
+        });
+    }
+
+    /**
+     * [run(config)] trains and evaluates every configuration of the sweep on
+     * top of the parsed [config], writes the results table ranking them and
+     * returns the results, best first. Stops early, ranking the
+     * configurations done so far, when a shutdown is requested.
+     */
+    pub fn run(&self, config: &Value) -> BotResult<Vec<TrialResult>> {
+        let mut results = Vec::new();
+        for index in 0..self.trials.len() {
+            results.push(self.run_trial(config, index)?);
+            if shutdown::requested() {
+                break;
+            }
+        }
+
+        results.sort_by(|a, b| {
+            let (a, b) = (a.comparison.score(), b.comparison.score());
+            b.partial_cmp(&a).unwrap()
+        });
+        fs::write(&self.output, results_table(&results))?;
+        println!(
+            "Wrote the results of {} configurations to {}",
+            results.len(),
+            self.output
+        );
+        return Ok(results);
+    }
+}
+
+/**
+ * [results_table(results)] formats [results], best first, as a csv table
+ * giving the rank, run, score, Elo difference against the baseline, wins,
+ * draws and losses, and the settings of each configuration.
+ */
+pub fn results_table(results: &[TrialResult]) -> String {
+    let mut table = String::from("rank,run,score,elo,wins,draws,losses,settings\n");
+    for (rank, result) in results.iter().enumerate() {
+        let (wins, draws, losses) = result.comparison.wdl();
+        let elo = match result.comparison.elo_difference() {
+            Some(elo) => format!("{:.0}", elo),
+            None => String::new(),
+        };
+        let settings = json!(result.overrides).to_string().replace('"', "\"\"");
+        table.push_str(&format!(
+            "{},{},{:.3},{},{},{},{},\"{}\"\n",
+            rank + 1,
+            result.run,
+            result.comparison.score(),
+            elo,
+            wins,
+            draws,
+            losses,
+            settings
+        ));
+    }
+    return table;
+}
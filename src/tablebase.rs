@@ -0,0 +1,135 @@
+/**
+ * Utility module for probing Syzygy endgame tablebases, so that the bot plays
+ * endgames with few enough pieces perfectly instead of relying on the
+ * network. Tables are probed through a server speaking the Lichess tablebase
+ * API, such as tablebase.lichess.ovh, which serves the Syzygy tables for up to
+ * 7 pieces, configured by the "tablebase" object in config.json, e.g.
+ * {"url": "https://tablebase.lichess.ovh/standard", "max_pieces": 6,
+ *  "label": true}. Positions with at most "max_pieces" pieces are probed
+ * before the network is asked, and the best move of the tables is played. With
+ * "label" set, the experience of a move chosen by the tables is labelled with
+ * its exact outcome (a win, draw or loss worth the terminal rewards) rather
+ * than bootstrapped. A failed probe falls back to the network, and nothing is
+ * probed without the object.
+ */
+use crate::mdp::WIN_REWARD;
+
+use chess::{Board, ChessMove};
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+use tracing::warn;
+
+// Default largest number of pieces, kings included, of a probed position
+const DEFAULT_MAX_PIECES: u64 = 6;
+
+// A tablebase server and which positions are probed
+#[derive(Clone)]
+pub struct Tablebase {
+    client: reqwest::Client,
+    pub url: String,
+    pub max_pieces: u32,
+    pub label: bool,
+}
+
+// The best move of a position according to the tables, along with its value
+// to the side to move in reward units
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    pub best: ChessMove,
+    pub value: f64,
+}
+
+// A response of the tablebase server, with moves sorted best first
+#[derive(Debug, Deserialize)]
+struct ProbeResponse {
+    moves: Vec<ProbeMove>,
+}
+
+// A move in a tablebase response, whose category is from the perspective of
+// the side to move after it
+#[derive(Debug, Deserialize)]
+struct ProbeMove {
+    uci: String,
+    category: String,
+}
+
+/**
+ * [move_value(category)] returns the value of a move to the side making it,
+ * in reward units, given the [category] of the position it leads to for the
+ * opponent, or None if the outcome is unknown. Wins and losses that the
+ * fifty-move rule turns into draws are worth a draw.
+ */
+fn move_value(category: &str) -> Option<f64> {
+    return match category {
+        "loss" | "syzygy-loss" | "maybe-loss" => Some(WIN_REWARD),
+        "win" | "syzygy-win" | "maybe-win" => Some(-WIN_REWARD),
+        "draw" | "cursed-win" | "blessed-loss" => Some(0.),
+        _ => None,
+    };
+}
+
+impl Tablebase {
+    /**
+     * [from_config(config, client)] reads the tablebase settings from the
+     * parsed [config], probing through [client], or returns None if no
+     * tablebase is configured.
+     */
+    pub fn from_config(config: &Value, client: &reqwest::Client) -> Option<Tablebase> {
+        let settings = &config["tablebase"];
+        let url = settings["url"].as_str()?;
+        return Some(Tablebase {
+            client: client.clone(),
+            url: url.to_string(),
+            max_pieces: settings["max_pieces"]
+                .as_u64()
+                .unwrap_or(DEFAULT_MAX_PIECES) as u32,
+            label: settings["label"].as_bool().unwrap_or(false),
+        });
+    }
+
+    /**
+     * [covers(b)] returns whether board [b] has few enough pieces to be
+     * probed.
+     */
+    pub fn covers(&self, b: &Board) -> bool {
+        return b.combined().popcnt() <= self.max_pieces;
+    }
+
+    /**
+     * [probe(b)] returns the best move in board [b] according to the tables,
+     * or None if [b] has too many pieces, the outcome of every move is unknown
+     * or the server could not be reached.
+     */
+    pub async fn probe(&self, b: &Board) -> Option<Probe> {
+        if !self.covers(b) {
+            return None;
+        }
+
+        let request = self.client.get(&self.url).query(&[("fen", b.to_string())]);
+        let response = match request.send().await {
+            Ok(r) if r.status().is_success() => r.json::<ProbeResponse>().await,
+            Ok(r) => {
+                warn!("Tablebase probe failed with status {}", r.status());
+                return None;
+            }
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Tablebase probe failed: {}", e);
+                return None;
+            }
+        };
+
+        for m in &response.moves {
+            let (best, value) = match (ChessMove::from_str(&m.uci), move_value(&m.category)) {
+                (Ok(best), Some(value)) if b.legal(best) => (best, value),
+                _ => continue,
+            };
+            return Some(Probe { best, value });
+        }
+        return None;
+    }
+}
@@ -0,0 +1,984 @@
+/**
+ * Utility module for checking the invariants of the state and action encodings
+ * over many random legal positions and moves, since an encoding bug otherwise
+ * only shows up as mysteriously bad training. The generators are seeded so
+ * that a failure can be reproduced, and the checks are run both by the
+ * selftest command and as unit tests. The invariants checked are that state
+ * and action vectors together fill the network's input, that every piece is
+ * encoded on exactly one plane with exactly one king per side, that each of
+ * the action's from and to planes has exactly one bit, and that flipping the
+ * perspective is an involution matching the encoding for the other color. The
+ * selftest also checks that terminal positions (checkmate, stalemate and a
+ * claimable draw) flow through move selection and learning without panicking,
+ * with no move selected and nothing bootstrapped past checkmate or stalemate
+ * (with learning targets anchored to the exact reward under every output
+ * scaling), that every promotion piece is generated and encoded in its own
+ * dimension, and that Monte Carlo Tree Search and alpha-beta search find a
+ * mate in one even with an untrained network, and that the transposition table
+ * only returns what was stored for the same position and the time manager
+ * budgets sensible thinking times.
+ */
+use crate::agent::{Agent, PolicyAgent, RandomAgent};
+use crate::augment::{mirror_board, mirror_experience};
+use crate::game_context::GameContext;
+use crate::history::{MoveCounters, PositionHistory};
+use crate::limits::SearchLimit;
+use crate::mdp::{
+    bitboard_to_vec, compute_q_max, fit_experience, get_action, get_reward, get_state,
+    get_state_with_history, move_by_policy, Experience, ExperienceMeta, TargetNetwork,
+    TargetUpdate, ACTION_DIM, BOARD_DIM, COUNTER_DIM, HISTORY_DIM, HISTORY_POSITIONS, LOSS_REWARD,
+    PIECE_DIM, STATE_DIM, WIN_REWARD,
+};
+use crate::normalization::FeatureStats;
+use crate::output_scaling::OutputScaling;
+use crate::returns::ReturnTarget;
+use crate::search::alphabeta::{self, AlphaBetaSettings};
+use crate::search::mcts::{self, MctsSettings};
+use crate::search::transposition::{Replacement, TranspositionTable};
+use crate::time_manager::TimeManager;
+use crate::watchdog::fallback_move;
+use crate::{make_random_move, GAMMA, INPUT_DIM};
+
+use chess::{
+    BitBoard, Board, BoardStatus, ChessMove, Color, MoveGen, Piece, ALL_COLORS, ALL_PIECES,
+};
+use neuroflow::FeedForward;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::str::FromStr;
+use std::time::Instant;
+
+// Number of squares, and so of features, in each plane of the encodings
+const PLANE_SIZE: usize = 64;
+
+// Plane of each side's king among its 6 piece planes
+const KING_PLANE: usize = 5;
+
+// Terminal positions with White's reward in each: fool's mate and a stalemate
+const TERMINAL_POSITIONS: [(&str, f64); 2] = [
+    (
+        "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3",
+        LOSS_REWARD,
+    ),
+    ("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 0.),
+];
+
+// Positions where each side can promote a pawn, to every piece
+const PROMOTION_POSITIONS: [&str; 2] = [
+    "8/P6k/8/8/8/8/6K1/8 w - - 0 1",
+    "8/6k1/8/8/8/8/p6K/8 b - - 0 1",
+];
+
+// A position where Black mates in one, and the mating move
+const MATE_IN_ONE: (&str, &str) = (
+    "rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b KQkq - 0 2",
+    "d8h4",
+);
+
+// Knight moves returning to the starting position, repeated into a claimable
+// draw
+const REPETITION: [&str; 4] = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+/**
+ * [random_move(rng, b)] picks a legal move in board [b] uniformly with [rng],
+ * or None if there are none.
+ */
+pub fn random_move(rng: &mut StdRng, b: &Board) -> Option<ChessMove> {
+    let moves: Vec<ChessMove> = MoveGen::new_legal(b).collect();
+    if moves.len() == 0 {
+        return None;
+    }
+    return Some(moves[rng.gen_range(0..moves.len())]);
+}
+
+/**
+ * [random_legal_position(rng, max_plies)] plays up to [max_plies] random legal
+ * moves chosen with [rng] from the starting position and returns the
+ * resulting board, which may be a finished game.
+ */
+pub fn random_legal_position(rng: &mut StdRng, max_plies: usize) -> Board {
+    let plies = rng.gen_range(0..=max_plies);
+    let mut board = Board::default();
+    for _ in 0..plies {
+        if board.status() != BoardStatus::Ongoing {
+            break;
+        }
+        match random_move(rng, &board) {
+            Some(m) => board = board.make_move_new(m),
+            None => break,
+        };
+    }
+
+    return board;
+}
+
+/**
+ * [bitboard_to_vec_by_string(bitboard)] converts [bitboard] to a hot vector
+ * by parsing its Display string, the way states used to be encoded, as a
+ * reference for [bitboard_to_vec].
+ */
+fn bitboard_to_vec_by_string(bitboard: &BitBoard) -> Vec<f64> {
+    let bitboard_str = bitboard.to_string().replace(" ", "").replace("\n", "");
+    return bitboard_str
+        .chars()
+        .map(|c| if c == 'X' { 1. } else { 0. })
+        .collect();
+}
+
+/**
+ * [bench_encoding(positions, seed)] times encoding the piece bitboards of
+ * [positions] random legal positions generated from [seed] by extracting
+ * their bits and by parsing their Display strings, and full state encodings
+ * of the same positions, printing the throughput of each. Returns the number
+ * of bitboards the two encodings disagree on.
+ */
+pub fn bench_encoding(positions: usize, seed: u64) -> usize {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let boards: Vec<Board> = (0..positions)
+        .map(|_| random_legal_position(&mut rng, 200))
+        .collect();
+    let bitboards: Vec<BitBoard> = boards
+        .iter()
+        .flat_map(|b| {
+            ALL_PIECES.iter().flat_map(move |p| {
+                ALL_COLORS
+                    .iter()
+                    .map(move |c| *b.pieces(*p) & *b.color_combined(*c))
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    let by_bits: Vec<Vec<f64>> = bitboards.iter().map(bitboard_to_vec).collect();
+    let bits_secs = started.elapsed().as_secs_f64().max(1e-9);
+    let started = Instant::now();
+    let by_string: Vec<Vec<f64>> = bitboards.iter().map(bitboard_to_vec_by_string).collect();
+    let string_secs = started.elapsed().as_secs_f64().max(1e-9);
+    let started = Instant::now();
+    for b in &boards {
+        get_state(b, MoveCounters::default(), true);
+    }
+    let state_secs = started.elapsed().as_secs_f64().max(1e-9);
+
+    let n = bitboards.len() as f64;
+    println!(
+        "Bit extraction:   {:.0} bitboards per second",
+        n / bits_secs
+    );
+    println!(
+        "String parsing:   {:.0} bitboards per second",
+        n / string_secs
+    );
+    println!("Speedup:          {:.1}x", string_secs / bits_secs);
+    println!(
+        "State encoding:   {:.0} positions per second",
+        positions as f64 / state_secs
+    );
+
+    return by_bits
+        .iter()
+        .zip(&by_string)
+        .filter(|(a, b)| a != b)
+        .count();
+}
+
+/**
+ * [bits(features)] returns the number of features set in [features].
+ */
+fn bits(features: &[f64]) -> usize {
+    return features.iter().filter(|f| **f != 0.).count();
+}
+
+/**
+ * [flip_state(state)] returns [state] from the other player's perspective,
+ * swapping the player's and opponent's piece planes and castling rights,
+ * mirroring every plane vertically and passing the turn to the other side.
+ */
+pub fn flip_state(state: &[f64]) -> Vec<f64> {
+    let half = PIECE_DIM / 2;
+    let castling = PIECE_DIM + PLANE_SIZE;
+    let swapped = state[half..PIECE_DIM].iter().chain(state[..half].iter());
+    let planes: Vec<f64> = swapped
+        .chain(&state[PIECE_DIM..castling])
+        .copied()
+        .collect();
+
+    let mut flipped = Vec::with_capacity(state.len());
+    for plane in planes.chunks(PLANE_SIZE) {
+        for rank in plane.chunks(8).rev() {
+            flipped.extend_from_slice(rank);
+        }
+    }
+    flipped.extend_from_slice(&state[castling + 2..castling + 4]);
+    flipped.extend_from_slice(&state[castling..castling + 2]);
+    flipped.push(1. - state[castling + 4]);
+
+    // The pieces of earlier positions are flipped like the current ones, and
+    // the repetition counts kept
+    for k in 0..HISTORY_POSITIONS {
+        let pieces = &state[BOARD_DIM + k * PIECE_DIM..BOARD_DIM + (k + 1) * PIECE_DIM];
+        let swapped = pieces[half..]
+            .chunks(PLANE_SIZE)
+            .chain(pieces[..half].chunks(PLANE_SIZE));
+        for plane in swapped {
+            for rank in plane.chunks(8).rev() {
+                flipped.extend_from_slice(rank);
+            }
+        }
+    }
+    flipped.extend_from_slice(&state[BOARD_DIM + HISTORY_POSITIONS * PIECE_DIM..]);
+    return flipped;
+}
+
+/**
+ * [promotion_index(piece)] returns the dimension of the action's promotion
+ * vector that encodes promoting to [piece].
+ */
+fn promotion_index(piece: Piece) -> usize {
+    match piece {
+        Piece::Bishop => 0,
+        Piece::Knight => 1,
+        Piece::Rook => 2,
+        _ => 3,
+    }
+}
+
+/**
+ * [check_position(b, m)] checks the encodings of board [b] and move [m] in it
+ * for both perspectives, returning a description of every invariant broken.
+ */
+pub fn check_position(b: &Board, m: Option<ChessMove>) -> Vec<String> {
+    let mut failures = Vec::new();
+    for player_white in [true, false] {
+        let state = get_state(b, MoveCounters::default(), player_white);
+        if state.len() != STATE_DIM {
+            failures.push(format!("state has length {}", state.len()));
+            continue;
+        }
+
+        // Each piece is on exactly one plane, with one king per side
+        if bits(&state[..PIECE_DIM]) != b.combined().popcnt() as usize {
+            failures.push(format!(
+                "state encodes {} pieces instead of {}",
+                bits(&state[..PIECE_DIM]),
+                b.combined().popcnt()
+            ));
+        }
+        for square in 0..PLANE_SIZE {
+            let occupied = (0..12).filter(|p| state[p * PLANE_SIZE + square] != 0.);
+            if occupied.count() > 1 {
+                failures.push(format!("square {} is on several planes", square));
+            }
+        }
+        for side in 0..2 {
+            let plane = (6 * side + KING_PLANE) * PLANE_SIZE;
+            let kings = bits(&state[plane..plane + PLANE_SIZE]);
+            if kings != 1 {
+                failures.push(format!("side {} has {} kings encoded", side, kings));
+            }
+        }
+
+        // The en passant plane holds the capturable pawn, and the last feature
+        // whether it is the player's turn
+        let en_passant = bits(&state[PIECE_DIM..PIECE_DIM + PLANE_SIZE]);
+        if en_passant != b.en_passant().is_some() as usize {
+            failures.push(format!("en passant plane has {} bits", en_passant));
+        }
+        let to_move = (b.side_to_move() == Color::White) == player_white;
+        if state[BOARD_DIM - 1] != to_move as usize as f64 {
+            failures.push("side to move is not encoded".to_string());
+        }
+
+        // Flipping the perspective twice changes nothing, and once gives the
+        // other color's encoding
+        let flipped = flip_state(&state);
+        if flip_state(&flipped) != state {
+            failures.push("perspective flip is not an involution".to_string());
+        }
+        if flipped != get_state(b, MoveCounters::default(), !player_white) {
+            failures.push("perspective flip does not match the other color".to_string());
+        }
+
+        if let Some(m) = m {
+            let action = get_action(m, player_white);
+            if state.len() + action.len() != INPUT_DIM as usize || action.len() != ACTION_DIM {
+                failures.push(format!(
+                    "state and action have lengths {} and {} for input {}",
+                    state.len(),
+                    action.len(),
+                    INPUT_DIM
+                ));
+                continue;
+            }
+            for (name, start) in [("from", 0), ("to", PLANE_SIZE)] {
+                let set = bits(&action[start..start + PLANE_SIZE]);
+                if set != 1 {
+                    failures.push(format!("action {} plane has {} bits", name, set));
+                }
+            }
+            let promotion = &action[2 * PLANE_SIZE..];
+            let expected = m.get_promotion().map(|p| promotion_index(p));
+            let set: Vec<usize> = (0..promotion.len())
+                .filter(|i| promotion[*i] != 0.)
+                .collect();
+            if set != expected.into_iter().collect::<Vec<usize>>() {
+                failures.push(format!("action {} has promotion bits {:?}", m, set));
+            }
+        }
+    }
+
+    return failures;
+}
+
+/**
+ * [check_terminal_label(nn, b, player_white, reward)] checks that learning
+ * with network [nn] from the move into terminal board [b], worth [reward] to
+ * the player playing white or not as given by [player_white], gives its reward
+ * alone, anchored exactly when outputs are squashed, returning a description
+ * of every check failed.
+ */
+fn check_terminal_label(
+    nn: &mut FeedForward,
+    b: &Board,
+    player_white: bool,
+    reward: f64,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    let experience = Experience {
+        state: get_state(&Board::default(), MoveCounters::default(), player_white),
+        action: get_action(ChessMove::from_str("e2e4").unwrap(), player_white),
+        reward,
+        next_state: get_state(b, MoveCounters::default(), player_white),
+        next_board: *b,
+        clock: None,
+        done: true,
+        search_target: None,
+        meta: ExperienceMeta::default(),
+    };
+    for scaling in [
+        OutputScaling::Linear,
+        OutputScaling::Tanh { scale: WIN_REWARD },
+    ] {
+        let mut target = TargetNetwork::new(
+            FeedForward::new(&[INPUT_DIM, 4, 1]),
+            FeatureStats::new(),
+            TargetUpdate::PerPass,
+            false,
+        );
+        let mut stats = FeatureStats::new();
+        let label = fit_experience(nn, &mut stats, &mut target, &experience, GAMMA, &scaling);
+        if label != scaling.anchor(reward) {
+            failures.push(format!(
+                "label is {} instead of {} ({:?})",
+                label,
+                scaling.anchor(reward),
+                scaling
+            ));
+        }
+    }
+
+    return failures;
+}
+
+/**
+ * [check_terminal(b, white_reward)] checks that nothing selects a move in
+ * terminal board [b], that its rewards match [white_reward] for White and its
+ * negation for Black, and that learning from reaching it bootstraps nothing,
+ * returning a description of every check failed.
+ */
+fn check_terminal(b: &Board, white_reward: f64) -> Vec<String> {
+    let mut failures = Vec::new();
+    let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    let mut q_network = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    let mut context = GameContext::new(b, (SearchLimit::Unlimited, SearchLimit::Unlimited));
+    if make_random_move(*b).is_some() || fallback_move(b).is_some() {
+        failures.push("a fallback move was selected".to_string());
+    }
+    if RandomAgent.select_move(&mut context).is_some() {
+        failures.push("the random agent selected a move".to_string());
+    }
+    if PolicyAgent::new(&mut nn, "policy")
+        .select_move(&mut context)
+        .is_some()
+    {
+        failures.push("the policy agent selected a move".to_string());
+    }
+
+    for (player_white, reward) in [(true, white_reward), (false, -white_reward)] {
+        let counters = MoveCounters::default();
+        let table = &mut TranspositionTable::default();
+        if move_by_policy(&mut nn, b, counters, player_white, table).is_some() {
+            failures.push("the policy selected a move".to_string());
+        }
+        if get_reward(b, player_white) != reward {
+            failures.push(format!(
+                "reward is {} instead of {}",
+                get_reward(b, player_white),
+                reward
+            ));
+        }
+        let state = get_state(b, counters, player_white);
+        table.clear();
+        if compute_q_max(b, &state, &mut q_network, player_white, table) != 0. {
+            failures.push("the next state's value is bootstrapped".to_string());
+        }
+
+        failures.append(&mut check_terminal_label(&mut nn, b, player_white, reward));
+    }
+
+    return failures;
+}
+
+/**
+ * [check_terminal_positions()] checks checkmate, stalemate and a draw by
+ * repetition, which is learned from as worth nothing, printing every failure, and returns the number of positions
+ * that failed.
+ */
+pub fn check_terminal_positions() -> usize {
+    let mut failed = 0;
+    for (fen, white_reward) in TERMINAL_POSITIONS {
+        let board = Board::from_str(fen).unwrap();
+        let failures = check_terminal(&board, white_reward);
+        if failures.len() > 0 {
+            failed += 1;
+            println!("{}:", fen);
+            for failure in failures {
+                println!("  {}", failure);
+            }
+        }
+    }
+
+    // A repetition can be claimed as a draw while moves are still legal, and
+    // is worth nothing to either side
+    let mut history = PositionHistory::new(&Board::default());
+    for m in REPETITION.iter().chain(REPETITION.iter()) {
+        history.make_move(ChessMove::from_str(m).unwrap());
+    }
+    let board = history.board();
+    let mut failures = Vec::new();
+    if !history.can_declare_draw() || get_reward(&board, true) != 0. {
+        failures.push("repetition is not a claimable draw worth nothing".to_string());
+    }
+    let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    failures.append(&mut check_terminal_label(&mut nn, &board, true, 0.));
+    if failures.len() > 0 {
+        failed += 1;
+        println!("{}:", history.fen());
+        for failure in failures {
+            println!("  {}", failure);
+        }
+    }
+
+    return failed;
+}
+
+/**
+ * [check_promotions()] checks that move generation offers a promotion to each
+ * piece in positions where a pawn can promote, and that every one of them
+ * passes the encoding checks with a distinct action, printing every failure
+ * and returning the number of positions that failed.
+ */
+pub fn check_promotions() -> usize {
+    let mut failed = 0;
+    for fen in PROMOTION_POSITIONS {
+        let board = Board::from_str(fen).unwrap();
+        let player_white = board.side_to_move() == Color::White;
+        let promotions: Vec<ChessMove> = MoveGen::new_legal(&board)
+            .filter(|m| m.get_promotion().is_some())
+            .collect();
+
+        let mut failures = Vec::new();
+        for piece in [Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight] {
+            if !promotions.iter().any(|m| m.get_promotion() == Some(piece)) {
+                failures.push(format!("no promotion to {:?}", piece));
+            }
+        }
+        let mut actions: Vec<Vec<f64>> = Vec::new();
+        for m in &promotions {
+            failures.extend(check_position(&board, Some(*m)));
+            let action = get_action(*m, player_white);
+            if actions.contains(&action) {
+                failures.push(format!("action of {} is not distinct", m));
+            }
+            actions.push(action);
+        }
+
+        if failures.len() > 0 {
+            failed += 1;
+            println!("{}:", fen);
+            for failure in failures {
+                println!("  {}", failure);
+            }
+        }
+    }
+
+    return failed;
+}
+
+/**
+ * [check_target_updates()] checks that hard updates copy the policy network
+ * only every interval, and that soft updates move the target network by the
+ * configured fraction, printing every failure and returning the number of
+ * update modes that failed.
+ */
+pub fn check_target_updates() -> usize {
+    let weights = |nn: &FeedForward| {
+        let layers = serde_json::to_value(nn).unwrap()["layers"].clone();
+        return layers
+            .as_array()
+            .map(|l| l.iter().map(|l| l["w"].clone()).collect::<Vec<_>>());
+    };
+    let same = |a: &FeedForward, b: &FeedForward| weights(a) == weights(b);
+    let policy = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    let mut failed = 0;
+    for (update, syncs) in [
+        (TargetUpdate::Hard { interval: 2 }, [false, true]),
+        (TargetUpdate::Soft { tau: 0. }, [false, false]),
+        (TargetUpdate::Soft { tau: 1. }, [true, true]),
+    ] {
+        let mut target = TargetNetwork::new(
+            FeedForward::new(&[INPUT_DIM, 4, 1]),
+            FeatureStats::new(),
+            update,
+            false,
+        );
+        for synced in syncs {
+            target.after_fit(&policy, &FeatureStats::new());
+            if same(&target.network, &policy) != synced {
+                failed += 1;
+                println!("{:?}: target network synced is not {}", update, synced);
+                break;
+            }
+        }
+    }
+
+    return failed;
+}
+
+/**
+ * [check_search()] checks that Monte Carlo Tree Search and alpha-beta search
+ * with an untrained network find a mate in one, printing every failure, and
+ * returns the number of searches that failed.
+ */
+pub fn check_search() -> usize {
+    let board = Board::from_str(MATE_IN_ONE.0).unwrap();
+    let counters = MoveCounters::from_fen(MATE_IN_ONE.0);
+    let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    let mcts = MctsSettings::from_config(&Value::Null);
+    let alphabeta = AlphaBetaSettings::from_config(&Value::Null);
+    let table = &mut TranspositionTable::default();
+    let searched = [
+        (
+            "mcts",
+            mcts::search(&mut nn, &board, counters, &mcts, table, None).map(|r| r.best),
+        ),
+        (
+            "alphabeta",
+            alphabeta::search(&mut nn, &board, counters, &alphabeta, table, None, None)
+                .map(|r| r.best),
+        ),
+    ];
+
+    let mut failed = 0;
+    for (name, best) in searched {
+        let best = best.map(|m| m.to_string());
+        if best.as_deref() != Some(MATE_IN_ONE.1) {
+            failed += 1;
+            println!("{}:", MATE_IN_ONE.0);
+            println!("  {} played {:?} instead of mating", name, best);
+        }
+    }
+
+    return failed;
+}
+
+/**
+ * [check_transposition_table()] checks that the transposition table returns
+ * stored evaluations only for the position, move counters and perspective
+ * they were stored for and until it is cleared, and that its replacement
+ * policies keep or
+ * replace deeper searches, printing every failure, and returns the number of
+ * failures.
+ */
+pub fn check_transposition_table() -> usize {
+    let mut failures = Vec::new();
+    let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+    let board = Board::default();
+    let other = board.make_move_new(MoveGen::new_legal(&board).next().unwrap());
+
+    let mut table = TranspositionTable::new(64, Replacement::Always);
+    let state = get_state(&board, MoveCounters::default(), true);
+    let scores = table.evaluate(&board, &state, &mut nn, true);
+    if table.evaluate(&board, &state, &mut nn, true) != scores || table.hits != 1 {
+        failures.push("a stored evaluation was not reused".to_string());
+    }
+    let later = MoveCounters {
+        halfmove_clock: 40,
+        fullmove_number: 60,
+    };
+    table.evaluate(&board, &get_state(&board, later, true), &mut nn, true);
+    if table.hits != 1 {
+        failures.push("an evaluation was reused with other move counters".to_string());
+    }
+    if table.probe(&board, false).is_some() || table.probe(&other, true).is_some() {
+        failures.push("an evaluation was found for another position".to_string());
+    }
+    table.clear();
+    if table.probe(&board, true).is_some() {
+        failures.push("an evaluation was found after clearing".to_string());
+    }
+
+    for (replacement, kept) in [(Replacement::Always, false), (Replacement::Depth, true)] {
+        let mut table = TranspositionTable::new(1, replacement);
+        let m = MoveGen::new_legal(&board).next().unwrap();
+        table.store_search(&board, true, m, 0., 3);
+        table.store_scores(&other, &state, true, &[]);
+        if table.best_move(&board, true).is_some() != kept {
+            failures.push(format!(
+                "{:?} replacement kept a deeper search",
+                replacement
+            ));
+        }
+    }
+
+    for failure in &failures {
+        println!("transposition table: {}", failure);
+    }
+    return failures.len();
+}
+
+/**
+ * [check_time_manager()] checks that the time manager thinks longer with more
+ * time on the clock, never spends more than its share of a short clock and
+ * always leaves at least one node, printing every failure, and returns the
+ * number of failures.
+ */
+pub fn check_time_manager() -> usize {
+    let mut failures = Vec::new();
+    let manager = TimeManager::from_config(&Value::Null);
+    let (bullet, classical) = (manager.allocate(60_000, 0), manager.allocate(1_800_000, 0));
+    if bullet >= classical {
+        failures.push(format!(
+            "{:?} for bullet and {:?} for classical",
+            bullet, classical
+        ));
+    }
+    let short = manager.allocate(1_000, 0);
+    if short.as_secs_f64() * 1000. > (1_000. * manager.max_fraction).max(manager.min_ms) {
+        failures.push(format!("{:?} with 1s left", short));
+    }
+    if manager.plan(0, 0).nodes == 0 {
+        failures.push("no nodes with the clock run out".to_string());
+    }
+
+    for failure in &failures {
+        println!("time manager: {}", failure);
+    }
+    return failures.len();
+}
+
+/**
+ * [check_returns()] checks that multi-step returns discount the rewards of
+ * later moves, bootstrap only from games that did not end, and that TD(λ)
+ * returns reduce to n-step returns at λ = 1 and to one-step returns at
+ * λ = 0, printing every failure, and returns the number of failures.
+ */
+pub fn check_returns() -> usize {
+    let mut failures = Vec::new();
+    let (rewards, values) = ([1., 2., 3.], [10., 20., 30.]);
+    let gamma = 0.5;
+    let ended = |k: usize| if k == 2 { None } else { Some(values[k]) };
+    let ongoing = |k: usize| Some(values[k]);
+
+    let nstep = ReturnTarget::NStep { n: 3 };
+    let expected = 1. + 0.5 * 2. + 0.25 * 3.;
+    if nstep.discounted(&rewards, ended, gamma) != (expected, false) {
+        failures.push("n-step return of a game that ended".to_string());
+    }
+    let expected = (expected + 0.125 * 30., true);
+    if nstep.discounted(&rewards, ongoing, gamma) != expected {
+        failures.push("n-step return of an ongoing game".to_string());
+    }
+    let full = ReturnTarget::Lambda {
+        lambda: 1.,
+        horizon: 3,
+    };
+    if full.discounted(&rewards, ongoing, gamma) != expected {
+        failures.push("TD(1) return differs from the n-step return".to_string());
+    }
+    let one_step = ReturnTarget::Lambda {
+        lambda: 0.,
+        horizon: 3,
+    };
+    if one_step.discounted(&rewards, ongoing, gamma) != (1. + 0.5 * 10., true) {
+        failures.push("TD(0) return differs from the one-step return".to_string());
+    }
+
+    for failure in &failures {
+        println!("returns: {}", failure);
+    }
+    return failures.len();
+}
+
+/**
+ * [check_mirroring()] checks that mirroring an experience of a position where
+ * neither side may castle encodes the mirrored position and move, that
+ * mirroring twice gives back the position, and that positions where castling
+ * is possible are not mirrored, printing every failure, and returns the
+ * number of failures.
+ */
+pub fn check_mirroring() -> usize {
+    let mut failures = Vec::new();
+    let before = Board::from_str("4k3/8/8/8/8/8/1P6/4K3 w - - 0 1").unwrap();
+    let mirrored = Board::from_str("3k4/8/8/8/8/8/6P1/3K4 w - - 0 1").unwrap();
+    let (b2b4, g2g4) = (
+        ChessMove::from_str("b2b4").unwrap(),
+        ChessMove::from_str("g2g4").unwrap(),
+    );
+    let after = before.make_move_new(b2b4);
+    let experience = Experience {
+        state: get_state(&before, MoveCounters::default(), true),
+        action: get_action(b2b4, true),
+        reward: 0.,
+        next_state: get_state(&after, MoveCounters::default(), true),
+        next_board: after,
+        clock: None,
+        done: false,
+        search_target: None,
+        meta: ExperienceMeta::default(),
+    };
+    match mirror_experience(&experience) {
+        Some(m) => {
+            if m.state != get_state(&mirrored, MoveCounters::default(), true)
+                || m.action != get_action(g2g4, true)
+            {
+                failures.push("mirrored experience encodes another move".to_string());
+            }
+            if mirror_board(&m.next_board) != Some(after) {
+                failures.push("mirroring twice changed the position".to_string());
+            }
+        }
+        None => failures.push("experience without castling rights not mirrored".to_string()),
+    };
+    if mirror_board(&Board::default()).is_some() {
+        failures.push("mirrored a position with castling rights".to_string());
+    }
+
+    for failure in &failures {
+        println!("mirroring: {}", failure);
+    }
+    return failures.len();
+}
+
+/**
+ * [check_history()] checks that encoding a position along with the positions
+ * before it keeps the board's own features and ends with its move counters,
+ * and with the history_planes feature stacks the earlier positions and counts
+ * repetitions, returning the number of failures.
+ */
+pub fn check_history() -> usize {
+    let mut failures = Vec::new();
+    let start = Board::default();
+    let mut history = PositionHistory::new(&start);
+    for uci in ["g1f3", "g8f6", "f3g1", "f6g8"] {
+        history.make_move(ChessMove::from_str(uci).unwrap());
+    }
+    let state = get_state_with_history(&history, true);
+    if state.len() != STATE_DIM
+        || state[..BOARD_DIM] != get_state(&start, MoveCounters::default(), true)[..BOARD_DIM]
+    {
+        failures.push("history changed the board's own features".to_string());
+    }
+    let counters = STATE_DIM - COUNTER_DIM;
+    let first = get_state_with_history(&PositionHistory::new(&start), true);
+    if first != get_state(&start, MoveCounters::default(), true) {
+        failures.push("the first position of a game has a history".to_string());
+    }
+    if state[counters..] != [4. / 100., 3. / 200.] {
+        failures.push(format!("move counters are {:?}", &state[counters..]));
+    }
+    let encoded = get_state(&history.board(), history.counters(), true);
+    if state[counters..] != encoded[counters..] {
+        failures.push("the board alone is encoded with other move counters".to_string());
+    }
+    if HISTORY_DIM > 0 {
+        let previous = get_state(
+            &history.earlier_board(1).unwrap(),
+            MoveCounters::default(),
+            true,
+        );
+        if state[BOARD_DIM..BOARD_DIM + PIECE_DIM] != previous[..PIECE_DIM] {
+            failures.push("latest history planes are not the previous position".to_string());
+        }
+        if state[counters - 2..counters] != [1., 0.] {
+            failures.push("repeated position is not counted once".to_string());
+        }
+    }
+
+    for failure in &failures {
+        println!("history: {}", failure);
+    }
+    return failures.len();
+}
+
+/**
+ * [run_selftest(positions, seed)] checks the terminal and promotion positions,
+ * the target network updates, the search, the transposition table, the time
+ * manager, the returns, mirroring and history planes, and then the encodings
+ * of [positions] random legal positions and moves generated from [seed],
+ * printing every failure along with the FEN and move that reproduce it, and
+ * returns the number of positions that failed.
+ */
+pub fn run_selftest(positions: usize, seed: u64) -> usize {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut failed = check_terminal_positions()
+        + check_promotions()
+        + check_target_updates()
+        + check_search()
+        + check_transposition_table()
+        + check_time_manager()
+        + check_returns()
+        + check_mirroring()
+        + check_history();
+    for _ in 0..positions {
+        let board = random_legal_position(&mut rng, 200);
+        let m = random_move(&mut rng, &board);
+        let failures = check_position(&board, m);
+        if failures.len() > 0 {
+            failed += 1;
+            println!("{} ({:?}):", board, m.map(|m| m.to_string()));
+            for failure in failures {
+                println!("  {}", failure);
+            }
+        }
+    }
+
+    return failed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdp::{score_moves_in_state, score_moves_until};
+
+    #[test]
+    fn terminal_positions_select_nothing_and_bootstrap_nothing() {
+        for (fen, white_reward) in TERMINAL_POSITIONS {
+            let failures = check_terminal(&Board::from_str(fen).unwrap(), white_reward);
+            assert!(failures.is_empty(), "{}: {:?}", fen, failures);
+        }
+    }
+
+    #[test]
+    fn repetition_is_learned_as_a_draw_worth_nothing() {
+        let mut history = PositionHistory::new(&Board::default());
+        for m in REPETITION.iter().chain(REPETITION.iter()) {
+            history.make_move(ChessMove::from_str(m).unwrap());
+        }
+        let board = history.board();
+        assert!(history.can_declare_draw());
+
+        let reward = get_reward(&board, true);
+        assert_eq!(reward, 0.);
+        let experience = Experience {
+            state: get_state(&Board::default(), MoveCounters::default(), true),
+            action: get_action(ChessMove::from_str("f3g1").unwrap(), true),
+            reward,
+            next_state: get_state(&board, MoveCounters::default(), true),
+            next_board: board,
+            clock: None,
+            done: true,
+            search_target: None,
+            meta: ExperienceMeta::default(),
+        };
+        let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+        let mut target = TargetNetwork::new(
+            FeedForward::new(&[INPUT_DIM, 4, 1]),
+            FeatureStats::new(),
+            TargetUpdate::PerPass,
+            false,
+        );
+        let label = fit_experience(
+            &mut nn,
+            &mut FeatureStats::new(),
+            &mut target,
+            &experience,
+            GAMMA,
+            &OutputScaling::Linear,
+        );
+        assert_eq!(label, reward);
+    }
+
+    #[test]
+    fn move_counters_follow_the_game() {
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        let board = Board::from_str(fen).unwrap();
+        let counters = MoveCounters::from_fen(fen);
+        assert_eq!(
+            counters,
+            MoveCounters {
+                halfmove_clock: 2,
+                fullmove_number: 3,
+            }
+        );
+        assert_eq!(
+            MoveCounters::from_fen("8/8/8/8/8/8/8/K6k w - -"),
+            MoveCounters::default()
+        );
+
+        // A quiet move ticks the clock, a pawn move or capture resets it, and
+        // the move number advances once Black has moved
+        let quiet = counters.after(&board, ChessMove::from_str("b1c3").unwrap());
+        assert_eq!((quiet.halfmove_clock, quiet.fullmove_number), (3, 3));
+        let pawn = counters.after(&board, ChessMove::from_str("d2d4").unwrap());
+        assert_eq!((pawn.halfmove_clock, pawn.fullmove_number), (0, 3));
+        let after_black = board.make_move_new(ChessMove::from_str("b1c3").unwrap());
+        let reply = quiet.after(&after_black, ChessMove::from_str("g8f6").unwrap());
+        assert_eq!((reply.halfmove_clock, reply.fullmove_number), (4, 4));
+
+        // A game from a FEN encodes the FEN's counters, and so does the board
+        let mut history = PositionHistory::with_counters(&board, counters);
+        history.make_move(ChessMove::from_str("f3e5").unwrap());
+        assert_eq!(history.counters().halfmove_clock, 0);
+        let player_white = true;
+        let state = get_state_with_history(&history, player_white);
+        let encoded = get_state(&history.board(), history.counters(), player_white);
+        assert_eq!(
+            state[STATE_DIM - COUNTER_DIM..],
+            encoded[STATE_DIM - COUNTER_DIM..]
+        );
+        assert_eq!(state[STATE_DIM - 1], 3. / 200.);
+    }
+
+    #[test]
+    fn scoring_stops_at_the_deadline() {
+        let mut nn = FeedForward::new(&[INPUT_DIM, 4, 1]);
+        let board = Board::default();
+        let state = get_state(&board, MoveCounters::default(), true);
+        let all = score_moves_in_state(&mut nn, &board, &state, true);
+        let later = Instant::now() + std::time::Duration::from_secs(60);
+        assert_eq!(score_moves_until(&mut nn, &board, &state, true, later), all);
+
+        // A passed deadline still scores one batch, so there is a move to play
+        let passed = score_moves_until(&mut nn, &board, &state, true, Instant::now());
+        assert!(!passed.is_empty() && passed.len() < all.len());
+        assert_eq!(passed[..], all[..passed.len()]);
+    }
+
+    #[test]
+    fn random_positions_hold_the_encoding_invariants() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let board = random_legal_position(&mut rng, 200);
+            let m = random_move(&mut rng, &board);
+            let failures = check_position(&board, m);
+            assert!(failures.is_empty(), "{} ({:?}): {:?}", board, m, failures);
+        }
+    }
+
+    #[test]
+    fn selftest_passes() {
+        assert_eq!(run_selftest(50, 1), 0);
+    }
+}
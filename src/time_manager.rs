@@ -0,0 +1,111 @@
+/**
+ * Utility module for budgeting how long the bot thinks about each move in
+ * timed Lichess games, so that it neither flags in bullet nor moves instantly
+ * in classical. The time for a move is a share of the time left plus most of
+ * the increment, capped at a fraction of the time left and reduced by a
+ * safety margin for network lag, and it is turned into a number of nodes
+ * (moves scored by the network) at the speed the network was last measured
+ * at, which caps the simulations of Monte Carlo Tree Search and the depth
+ * alpha-beta search reaches. Configured by the "time_manager" object in
+ * config.json, e.g. {"moves_to_go": 30, "increment_fraction": 0.8,
+ * "max_fraction": 0.2, "overhead_ms": 300, "min_ms": 50,
+ * "nodes_per_second": 2000}.
+ */
+use serde_json::Value;
+use std::time::Duration;
+
+// Default number of moves the time left is shared between, fraction of the
+// increment spent, and largest fraction of the time left spent on one move
+const DEFAULT_MOVES_TO_GO: f64 = 30.;
+const DEFAULT_INCREMENT_FRACTION: f64 = 0.8;
+const DEFAULT_MAX_FRACTION: f64 = 0.2;
+
+// Default time kept back for network lag, and least time spent on a move
+const DEFAULT_OVERHEAD_MS: f64 = 300.;
+const DEFAULT_MIN_MS: f64 = 50.;
+
+// Default speed of the network before it is measured
+const DEFAULT_NODES_PER_SECOND: f64 = 2000.;
+
+// Weight of the latest measurement in the estimated speed, and the shortest
+// move that is measured
+const SPEED_SMOOTHING: f64 = 0.3;
+const MIN_MEASURED: Duration = Duration::from_millis(10);
+
+// How much time and work a move may take
+#[derive(Clone, Copy, Debug)]
+pub struct MovePlan {
+    pub think: Duration,
+    pub nodes: usize,
+}
+
+// Allocates thinking time from the clock over a game
+#[derive(Clone, Debug)]
+pub struct TimeManager {
+    pub moves_to_go: f64,
+    pub increment_fraction: f64,
+    pub max_fraction: f64,
+    pub overhead_ms: f64,
+    pub min_ms: f64,
+    pub nodes_per_second: f64, // estimated, updated as moves are measured
+}
+
+impl TimeManager {
+    /**
+     * [from_config(config)] reads the time management settings from the
+     * parsed [config].
+     */
+    pub fn from_config(config: &Value) -> TimeManager {
+        let settings = &config["time_manager"];
+        let read = |key: &str, default: f64| settings[key].as_f64().unwrap_or(default);
+        return TimeManager {
+            moves_to_go: read("moves_to_go", DEFAULT_MOVES_TO_GO).max(1.),
+            increment_fraction: read("increment_fraction", DEFAULT_INCREMENT_FRACTION)
+                .clamp(0., 1.),
+            max_fraction: read("max_fraction", DEFAULT_MAX_FRACTION).clamp(0., 1.),
+            overhead_ms: read("overhead_ms", DEFAULT_OVERHEAD_MS).max(0.),
+            min_ms: read("min_ms", DEFAULT_MIN_MS).max(0.),
+            nodes_per_second: read("nodes_per_second", DEFAULT_NODES_PER_SECOND).max(1.),
+        };
+    }
+
+    /**
+     * [allocate(remaining_ms, increment_ms)] returns how long to think about
+     * the next move with [remaining_ms] milliseconds left on the clock and an
+     * increment of [increment_ms] milliseconds.
+     */
+    pub fn allocate(&self, remaining_ms: u64, increment_ms: u64) -> Duration {
+        let (remaining, increment) = (remaining_ms as f64, increment_ms as f64);
+        let share = remaining / self.moves_to_go + increment * self.increment_fraction;
+        let ms = share.min(remaining * self.max_fraction) - self.overhead_ms;
+        return Duration::from_secs_f64(ms.max(self.min_ms) / 1000.);
+    }
+
+    /**
+     * [plan(remaining_ms, increment_ms)] returns the thinking time and node
+     * budget of the next move with [remaining_ms] milliseconds left on the
+     * clock and an increment of [increment_ms] milliseconds.
+     */
+    pub fn plan(&self, remaining_ms: u64, increment_ms: u64) -> MovePlan {
+        let think = self.allocate(remaining_ms, increment_ms);
+        let nodes = (think.as_secs_f64() * self.nodes_per_second) as usize;
+        return MovePlan {
+            think,
+            nodes: nodes.max(1),
+        };
+    }
+
+    /**
+     * [record(nodes, elapsed)] updates the estimated speed of the network with
+     * a move that scored [nodes] moves in [elapsed], unless it was too quick
+     * to measure.
+     */
+    pub fn record(&mut self, nodes: usize, elapsed: Duration) {
+        if nodes == 0 || elapsed < MIN_MEASURED {
+            return;
+        }
+        let speed = nodes as f64 / elapsed.as_secs_f64();
+        self.nodes_per_second =
+            (1. - SPEED_SMOOTHING) * self.nodes_per_second + SPEED_SMOOTHING * speed;
+    }
+}
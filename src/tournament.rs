@@ -0,0 +1,173 @@
+/**
+ * Utility module for playing Lichess arena and swiss tournaments, which bring
+ * a steady stream of games against real opponents without challenging
+ * anyone. The tournament command joins the tournament, waits on the event
+ * stream for the games it is paired into, plays each with the normal game
+ * loop and stores its experiences in the replay buffer for training (unless
+ * learning is disabled), then joins again, which marks the bot ready for its
+ * next arena pairing. Other challenges are declined for later meanwhile. It
+ * stops once the tournament finishes, or withdraws from it when shutting
+ * down. Configured by the "tournament" object in config.json, e.g.
+ * {"berserk": true, "berserk_max_rating_gap": 100}, which berserks every arena
+ * game against an opponent rated at most 100 points above the bot, halving
+ * its clock for an extra point if it wins.
+ */
+use crate::error::BotResult;
+use crate::game_loop::{learning_disabled, play_game};
+use crate::idle_learning::TurnSignal;
+use crate::lichess::{Event, GameEvent, GameFull, LichessClient};
+use crate::models::ModelRegistry;
+use crate::replay_shards::ShardedReplay;
+use crate::shutdown;
+
+use serde_json::Value;
+use std::time::Duration;
+use tracing::info;
+
+// How long to wait for a pairing before checking whether the tournament
+// finished
+const PAIRING_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// When the bot berserks its arena games
+#[derive(Clone, Copy, Debug)]
+pub struct TournamentSettings {
+    pub berserk: bool,
+    pub berserk_max_rating_gap: Option<i64>, // opponent's rating above the bot's
+}
+
+impl TournamentSettings {
+    /**
+     * [from_config(config)] reads the tournament settings given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> TournamentSettings {
+        let settings = &config["tournament"];
+        return TournamentSettings {
+            berserk: settings["berserk"].as_bool().unwrap_or(false),
+            berserk_max_rating_gap: settings["berserk_max_rating_gap"].as_i64(),
+        };
+    }
+
+    /**
+     * [should_berserk(game, player_white)] returns whether the bot, playing
+     * white or not as given by [player_white], berserks the arena [game].
+     */
+    pub fn should_berserk(&self, game: &GameFull, player_white: bool) -> bool {
+        if !self.berserk {
+            return false;
+        }
+        let rating = |white: bool| game.player(white).rating;
+        return match (
+            self.berserk_max_rating_gap,
+            rating(player_white),
+            rating(!player_white),
+        ) {
+            (None, _, _) => true,
+            (Some(gap), Some(mine), Some(theirs)) => theirs - mine <= gap,
+            (Some(_), _, _) => false,
+        };
+    }
+}
+
+/**
+ * [in_tournament(game, tournament_id)] returns whether [game] was paired by
+ * the tournament with id [tournament_id].
+ */
+fn in_tournament(game: &GameEvent, tournament_id: &str) -> bool {
+    return game.tournament_id.as_deref() == Some(tournament_id)
+        || game.swiss_id.as_deref() == Some(tournament_id);
+}
+
+/**
+ * [run_tournament(lichess, config, tournament_id, swiss)] plays the arena
+ * tournament, or the swiss tournament if [swiss], with id [tournament_id]
+ * with the networks given by the parsed [config] until it finishes, storing
+ * the experiences of its games in the replay buffer unless learning is
+ * disabled.
+ */
+pub async fn run_tournament(
+    lichess: &LichessClient,
+    config: &Value,
+    tournament_id: &str,
+    swiss: bool,
+) -> BotResult<()> {
+    let settings = TournamentSettings::from_config(config);
+    let mut replay = ShardedReplay::from_config(config)?;
+    if !lichess.join_tournament(tournament_id, swiss).await? {
+        return Ok(());
+    }
+    info!("Joined tournament {}", tournament_id);
+
+    let mut events = lichess.event_stream();
+    let mut played = 0;
+    loop {
+        if shutdown::requested() {
+            lichess.withdraw_tournament(tournament_id, swiss).await?;
+            info!("Withdrew from tournament {}", tournament_id);
+            break;
+        }
+
+        // Wait for the next pairing, checking whether the tournament is over
+        // whenever none comes
+        let game = match tokio::time::timeout(PAIRING_POLL_INTERVAL, events.next_line()).await {
+            Ok(event) => match event? {
+                Some(Event::GameStart { game }) if in_tournament(&game, tournament_id) => game,
+                Some(Event::Challenge { challenge })
+                    if challenge.direction.as_deref() != Some("out") =>
+                {
+                    lichess.decline_challenge(&challenge.id, "later").await?;
+                    continue;
+                }
+                _ => continue,
+            },
+            Err(_) => {
+                if lichess.tournament_finished(tournament_id, swiss).await? {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        // Berserk before the first move, which is only possible in an arena
+        let player_white = game.color.eq("white");
+        if !swiss {
+            if let Some(full) = lichess.stream_game(&game.id).await? {
+                if settings.should_berserk(&full, player_white) {
+                    info!("Berserking game {}", game.id);
+                    lichess.berserk(&game.id).await?;
+                }
+            }
+        }
+
+        let mut models = ModelRegistry::from_config(config)?;
+        let (experiences, color_white) = play_game(
+            lichess,
+            config,
+            &game.id,
+            &mut models,
+            &TurnSignal::default(),
+        )
+        .await?;
+        if !learning_disabled(config) {
+            replay.append(&experiences, color_white)?;
+        }
+        played += 1;
+        info!(
+            "Stored {} experiences from tournament game {} ({} played)",
+            experiences.len(),
+            game.id,
+            played
+        );
+
+        // Arena games are paired from the players who are ready
+        if !swiss && !shutdown::requested() {
+            lichess.join_tournament(tournament_id, swiss).await?;
+        }
+    }
+
+    info!(
+        "Tournament {} is over after {} games",
+        tournament_id, played
+    );
+    return Ok(());
+}
@@ -0,0 +1,256 @@
+/**
+ * Utility module for deciding how long a self-play run trains for and
+ * tracking its progress. A run stops after a target number of games, once a
+ * wall-clock budget is spent, when a shutdown is requested, or early once the
+ * network stops improving: every few games it plays the gauntlet against the
+ * promoted checkpoint (see arena), and after enough evaluations in a row
+ * without being promoted its progress has plateaued. Checkpoints are saved at
+ * their own interval (see checkpoint). A summary table of the run's
+ * evaluations is printed when it stops. Configured by the "training" object
+ * in config.json, e.g.
+ * {"games": 1000, "time_budget_secs": 7200, "eval_interval": 50,
+ *  "eval_openings": 10, "eval_plies": 8, "eval_seed": 0, "patience": 3},
+ * which trains for at most 1000 games or 2 hours, evaluates the network every
+ * 50 games from 10 random openings of 8 plies seeded with 0 (the same
+ * openings each time, so evaluations are comparable) and stops after 3
+ * evaluations without a promotion. The selfplay --games and --time-budget
+ * options override the game count and budget.
+ */
+use crate::arena::{gauntlet, promote_first_baseline, PairedComparison};
+use crate::sampling::seeded_openings;
+
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Games trained when neither a game count nor a time budget is given
+const DEFAULT_GAMES: usize = 1;
+const DEFAULT_EVAL_OPENINGS: u64 = 10;
+const DEFAULT_EVAL_PLIES: u64 = 8;
+
+// Why a run stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Games,
+    TimeBudget,
+    Plateau,
+    Shutdown,
+}
+
+// An evaluation of the network against the gauntlet during a run
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub game: usize, // games trained when it was evaluated
+    pub comparison: PairedComparison,
+    pub promoted: Option<String>,
+}
+
+// How long a run trains for, and how it has gone so far
+#[derive(Clone, Debug)]
+pub struct TrainingPlan {
+    pub games: Option<usize>,
+    pub time_budget: Option<Duration>,
+    pub eval_interval: usize, // games between evaluations, 0 for none
+    pub eval_openings: usize,
+    pub eval_plies: usize,
+    pub eval_seed: u64,
+    pub patience: Option<usize>, // evaluations without a promotion
+    pub evaluations: Vec<Evaluation>,
+    started: Instant,
+    played: usize,
+    total_result: f64,
+    stale_evaluations: usize,
+}
+
+/**
+ * [parse_duration(s)] parses a duration written in seconds, optionally
+ * followed by a unit, e.g. "3600", "90m" or "2h", or returns None if it is
+ * malformed.
+ */
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last()? {
+        (i, c) if c.is_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let scale = match unit {
+        's' => 1.,
+        'm' => 60.,
+        'h' => 3600.,
+        _ => return None,
+    };
+    let value: f64 = number.parse().ok()?;
+    if value < 0. {
+        return None;
+    }
+    return Some(Duration::from_secs_f64(value * scale));
+}
+
+impl StopReason {
+    /**
+     * [describe()] explains the reason for the logs.
+     */
+    pub fn describe(&self) -> &'static str {
+        return match self {
+            StopReason::Games => "trained the target number of games",
+            StopReason::TimeBudget => "spent the time budget",
+            StopReason::Plateau => "stopped improving",
+            StopReason::Shutdown => "shutdown requested",
+        };
+    }
+}
+
+impl TrainingPlan {
+    /**
+     * [from_config(config, games)] reads the training plan given by the
+     * parsed [config], training for [games] games if given instead of the
+     * configured count.
+     */
+    pub fn from_config(config: &Value, games: Option<usize>) -> TrainingPlan {
+        let settings = &config["training"];
+        let time_budget = settings["time_budget_secs"]
+            .as_f64()
+            .map(Duration::from_secs_f64);
+        let games = games.or(settings["games"].as_u64().map(|n| n as usize));
+        return TrainingPlan {
+            games: match (games, time_budget) {
+                (None, None) => Some(DEFAULT_GAMES),
+                (games, _) => games,
+            },
+            time_budget,
+            eval_interval: settings["eval_interval"].as_u64().unwrap_or(0) as usize,
+            eval_openings: settings["eval_openings"]
+                .as_u64()
+                .unwrap_or(DEFAULT_EVAL_OPENINGS) as usize,
+            eval_plies: settings["eval_plies"]
+                .as_u64()
+                .unwrap_or(DEFAULT_EVAL_PLIES) as usize,
+            eval_seed: settings["eval_seed"].as_u64().unwrap_or(0),
+            patience: settings["patience"].as_u64().map(|n| n.max(1) as usize),
+            evaluations: Vec::new(),
+            started: Instant::now(),
+            played: 0,
+            total_result: 0.,
+            stale_evaluations: 0,
+        };
+    }
+
+    /**
+     * [stop_reason()] returns why the run should stop before its next game,
+     * or None if it should play on.
+     */
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        if self.games.map_or(false, |n| self.played >= n) {
+            return Some(StopReason::Games);
+        }
+        if self
+            .time_budget
+            .map_or(false, |b| self.started.elapsed() >= b)
+        {
+            return Some(StopReason::TimeBudget);
+        }
+        if self.patience.map_or(false, |n| self.stale_evaluations >= n) {
+            return Some(StopReason::Plateau);
+        }
+        return None;
+    }
+
+    /**
+     * [record_game(result)] counts a game of the run, which the learner
+     * finished with [result].
+     */
+    pub fn record_game(&mut self, result: f64) {
+        self.played += 1;
+        self.total_result += result;
+    }
+
+    /**
+     * [evaluation_due()] returns whether the network is evaluated after the
+     * game just played.
+     */
+    pub fn evaluation_due(&self) -> bool {
+        return self.eval_interval > 0 && self.played % self.eval_interval == 0;
+    }
+
+    /**
+     * [evaluate(config, current, game)] plays the network saved at [current]
+     * against the gauntlet given by the parsed [config] after [game] games of
+     * training, promoting it if it scores well enough. Without a checkpoint
+     * to play against, the network is promoted as the first baseline.
+     */
+    pub fn evaluate(&mut self, config: &Value, current: &str, game: usize) {
+        let openings = seeded_openings(self.eval_openings, self.eval_plies, self.eval_seed);
+        let (comparison, promoted) = match gauntlet(config, None, &openings) {
+            Ok(Some(result)) => result,
+            Ok(None) => {
+                promote_first_baseline(config, current);
+                return;
+            }
+            Err(e) => {
+                warn!("Unable to evaluate after {} games: {}", game, e);
+                return;
+            }
+        };
+
+        if promoted.is_some() {
+            self.stale_evaluations = 0;
+        } else {
+            self.stale_evaluations += 1;
+        }
+        info!(
+            "Evaluation after {} games: scored {:.3}{}",
+            game,
+            comparison.score(),
+            match &promoted {
+                Some(path) => format!(", promoted {}", path),
+                None => format!(" ({} without a promotion)", self.stale_evaluations),
+            }
+        );
+        self.evaluations.push(Evaluation {
+            game,
+            comparison,
+            promoted,
+        });
+    }
+
+    /**
+     * [summary(reason)] returns a table summarizing the run, which stopped
+     * for [reason], and its evaluations.
+     */
+    pub fn summary(&self, reason: StopReason) -> String {
+        let elapsed = self.started.elapsed().as_secs();
+        let mut table = format!(
+            "Trained {} games in {}h{:02}m{:02}s ({}), mean result {:.3}\n",
+            self.played,
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60,
+            reason.describe(),
+            self.total_result / self.played.max(1) as f64
+        );
+        if self.evaluations.len() == 0 {
+            return table;
+        }
+
+        table.push_str(&format!(
+            "{:>6} {:>6} {:>6} {:>9}  {}\n",
+            "game", "score", "elo", "w/d/l", "promoted"
+        ));
+        for evaluation in &self.evaluations {
+            let (wins, draws, losses) = evaluation.comparison.wdl();
+            let elo = match evaluation.comparison.elo_difference() {
+                Some(elo) => format!("{:.0}", elo),
+                None => "-".to_string(),
+            };
+            table.push_str(&format!(
+                "{:>6} {:>6.3} {:>6} {:>9}  {}\n",
+                evaluation.game,
+                evaluation.comparison.score(),
+                elo,
+                format!("{}/{}/{}", wins, draws, losses),
+                evaluation.promoted.as_deref().unwrap_or("")
+            ));
+        }
+        return table;
+    }
+}
@@ -0,0 +1,324 @@
+/**
+ * Utility module for running the bot as a UCI engine on stdin/stdout, so GUIs
+ * and testing frameworks can play against the policy network directly. The
+ * key parameters are exposed as UCI options that can be changed with
+ * setoption without editing config.json:
+ * - Model: path of the policy network to play with
+ * - MultiPV: number of best moves reported with their scores on each search
+ * - Temperature: moves are sampled with probability proportional to the
+ *   exponential of their Q-value over the temperature, or the best move is
+ *   always played if it is 0
+ * - OwnBook: whether to play repertoire moves from config.json when possible
+ * Scores are reported in centipawns of the network's value, unsquashed by the
+ * configured output scaling, and moves that mate as mate scores. Searches
+ * finish before their bestmove is sent, so stop has nothing to interrupt. The
+ * non-standard d command prints the current board. The network is loaded
+ * with the value function backend given by config.json. When the "alphabeta"
+ * settings give a depth, e.g. with --search-depth, moves are picked by an
+ * alpha-beta search instead, reporting only the best line.
+ */
+use crate::display::render_board;
+use crate::history::{MoveCounters, PositionHistory};
+use crate::mdp::get_state_with_history;
+use crate::models::DEFAULT_MODEL_PATH;
+use crate::output_scaling::OutputScaling;
+use crate::q_function::{load_q_function, QFunction};
+use crate::repertoire::Repertoire;
+use crate::search::alphabeta::{self, AlphaBetaSettings};
+use crate::search::transposition::TranspositionTable;
+use crate::selfplay::boltzmann_move;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use serde_json::Value;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::str::FromStr;
+
+const ENGINE_NAME: &str = "rust-chess-bot";
+const ENGINE_AUTHOR: &str = "owsorber";
+const MAX_MULTIPV: usize = 256;
+
+// The engine's UCI options
+#[derive(Clone, Debug)]
+pub struct UciOptions {
+    pub model_path: String,
+    pub multipv: usize,
+    pub temperature: f64,
+    pub own_book: bool,
+}
+
+// The state of a running UCI engine
+struct UciSession {
+    options: UciOptions,
+    network: Box<dyn QFunction + Send>,
+    config: Value,
+    repertoire: Repertoire,
+    history: PositionHistory,
+    scaling: OutputScaling,
+    alphabeta: Option<AlphaBetaSettings>,
+    table: TranspositionTable, // evaluations of the network
+}
+
+impl UciOptions {
+    /**
+     * [from_config(config)] returns the default options, playing the white
+     * network given by the parsed [config].
+     */
+    pub fn from_config(config: &Value) -> UciOptions {
+        let model_path = config["models"]["white"]
+            .as_str()
+            .unwrap_or(DEFAULT_MODEL_PATH);
+        return UciOptions {
+            model_path: model_path.to_string(),
+            multipv: 1,
+            temperature: 0.,
+            own_book: true,
+        };
+    }
+
+    /**
+     * [declarations()] returns the UCI option declarations sent in response to
+     * the uci command, with the current values as defaults.
+     */
+    pub fn declarations(&self) -> Vec<String> {
+        return vec![
+            format!("option name Model type string default {}", self.model_path),
+            format!(
+                "option name MultiPV type spin default {} min 1 max {}",
+                self.multipv, MAX_MULTIPV
+            ),
+            format!(
+                "option name Temperature type string default {}",
+                self.temperature
+            ),
+            format!("option name OwnBook type check default {}", self.own_book),
+        ];
+    }
+
+    /**
+     * [set(name, value)] sets option [name] (case-insensitively, as in UCI) to
+     * [value], returning an error message if the option is unknown or the
+     * value is invalid.
+     */
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid value {} for option {}", value, name);
+        match name.to_lowercase().as_str() {
+            "model" => self.model_path = value.to_string(),
+            "multipv" => {
+                let n = value.parse::<usize>().map_err(|_| invalid())?;
+                self.multipv = n.max(1).min(MAX_MULTIPV);
+            }
+            "temperature" => match value.parse::<f64>() {
+                Ok(t) if t >= 0. => self.temperature = t,
+                _ => return Err(invalid()),
+            },
+            "ownbook" => self.own_book = value.eq_ignore_ascii_case("true"),
+            _ => return Err(format!("Unknown option {}", name)),
+        };
+
+        return Ok(());
+    }
+}
+
+/**
+ * [parse_setoption(tokens)] splits the tokens after "setoption" into the
+ * option name and value, both of which may contain spaces.
+ */
+fn parse_setoption(tokens: &[&str]) -> Option<(String, String)> {
+    let name_start = tokens.iter().position(|t| *t == "name")? + 1;
+    let value_start = tokens.iter().position(|t| *t == "value");
+    let name_end = value_start.unwrap_or(tokens.len());
+    if name_start >= name_end {
+        return None;
+    }
+
+    let name = tokens[name_start..name_end].join(" ");
+    let value = match value_start {
+        Some(i) => tokens[i + 1..].join(" "),
+        None => String::new(),
+    };
+    return Some((name, value));
+}
+
+/**
+ * [parse_position(tokens)] builds the history of the position given by the
+ * tokens after "position", either "startpos" or "fen <fen>", optionally
+ * followed by "moves" and uci moves. Returns None if the position is invalid.
+ */
+fn parse_position(tokens: &[&str]) -> Option<PositionHistory> {
+    let moves_start = tokens.iter().position(|t| *t == "moves");
+    let setup = &tokens[..moves_start.unwrap_or(tokens.len())];
+    let (initial, counters) = match setup.first() {
+        Some(&"startpos") => (Board::default(), MoveCounters::default()),
+        Some(&"fen") => {
+            let fen = setup[1..].join(" ");
+            (Board::from_str(&fen).ok()?, MoveCounters::from_fen(&fen))
+        }
+        _ => return None,
+    };
+
+    let mut history = PositionHistory::with_counters(&initial, counters);
+    if let Some(i) = moves_start {
+        for ms in &tokens[i + 1..] {
+            let m = ChessMove::from_str(ms).ok()?;
+            if !history.board().legal(m) {
+                return None;
+            }
+            history.make_move(m);
+        }
+    }
+
+    return Some(history);
+}
+
+impl UciSession {
+    /**
+     * [search()] prints the best moves in the current position with their
+     * scores and returns the move to play, or None if there are no legal
+     * moves.
+     */
+    fn search(&mut self) -> Option<ChessMove> {
+        let board = self.history.board();
+        let player_white = board.side_to_move() == Color::White;
+
+        if self.options.own_book {
+            let ply = self.history.ply() + 1;
+            if let Some(m) = self.repertoire.lookup(&board, player_white, ply) {
+                println!("info string book move");
+                return Some(m);
+            }
+        }
+
+        if let Some(settings) = &self.alphabeta {
+            let nn = self.network.as_mut();
+            let counters = self.history.counters();
+            let result =
+                alphabeta::search(nn, &board, counters, settings, &mut self.table, None, None)?;
+            let value = self.scaling.unsquash(result.score);
+            println!(
+                "info depth {} score cp {} nodes {} pv {}",
+                result.depth,
+                (value * 100.).round() as i64,
+                result.nodes,
+                result.best
+            );
+            return Some(result.best);
+        }
+
+        let state = get_state_with_history(&self.history, player_white);
+        let mut scores = self
+            .table
+            .evaluate(&board, &state, self.network.as_mut(), player_white);
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        for (i, (m, score)) in scores.iter().take(self.options.multipv).enumerate() {
+            let score = if board.make_move_new(*m).status() == BoardStatus::Checkmate {
+                "mate 1".to_string()
+            } else {
+                let value = self.scaling.unsquash(*score);
+                format!("cp {}", (value * 100.).round() as i64)
+            };
+            println!(
+                "info depth 1 multipv {} score {} nodes {} pv {}",
+                i + 1,
+                score,
+                scores.len(),
+                m
+            );
+        }
+
+        if self.options.temperature > 0. {
+            return boltzmann_move(&scores, self.options.temperature, &mut rand::thread_rng());
+        }
+        return scores.first().map(|(m, _)| *m);
+    }
+
+    /**
+     * [set_option(name, value)] sets option [name] to [value], loading the new
+     * network if the model changed.
+     */
+    fn set_option(&mut self, name: &str, value: &str) {
+        let old_model = self.options.model_path.clone();
+        if let Err(e) = self.options.set(name, value) {
+            println!("info string {}", e);
+            return;
+        }
+        if self.options.model_path != old_model {
+            if !Path::new(&self.options.model_path).exists() {
+                println!("info string No network at {}", self.options.model_path);
+                self.options.model_path = old_model;
+                return;
+            }
+            match load_q_function(&self.config, &self.options.model_path) {
+                Ok(network) => {
+                    self.network = network;
+                    self.table.clear();
+                    println!("info string loaded {}", self.options.model_path);
+                }
+                Err(e) => {
+                    println!("info string {}", e);
+                    self.options.model_path = old_model;
+                }
+            };
+        }
+    }
+}
+
+/**
+ * [run_uci(config)] runs the bot as a UCI engine until stdin closes or it is
+ * told to quit, starting from the options given by the parsed [config].
+ */
+pub fn run_uci(config: &Value) {
+    let options = UciOptions::from_config(config);
+    let mut session = UciSession {
+        network: load_q_function(config, &options.model_path).expect("Unable to load network"),
+        config: config.clone(),
+        options,
+        repertoire: Repertoire::from_config(config),
+        history: PositionHistory::new(&Board::default()),
+        scaling: OutputScaling::from_config(config),
+        alphabeta: match config["alphabeta"]["depth"].as_u64() {
+            Some(_) => Some(AlphaBetaSettings::from_config(config)),
+            None => None,
+        },
+        table: TranspositionTable::from_config(config),
+    };
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.first() {
+            Some(&"uci") => {
+                println!("id name {}", ENGINE_NAME);
+                println!("id author {}", ENGINE_AUTHOR);
+                for declaration in session.options.declarations() {
+                    println!("{}", declaration);
+                }
+                println!("uciok");
+            }
+            Some(&"isready") => println!("readyok"),
+            Some(&"setoption") => match parse_setoption(&tokens[1..]) {
+                Some((name, value)) => session.set_option(&name, &value),
+                None => println!("info string Invalid setoption command"),
+            },
+            Some(&"ucinewgame") => session.history = PositionHistory::new(&Board::default()),
+            Some(&"position") => match parse_position(&tokens[1..]) {
+                Some(history) => session.history = history,
+                None => println!("info string Invalid position"),
+            },
+            Some(&"go") => match session.search() {
+                Some(m) => println!("bestmove {}", m),
+                None => println!("bestmove 0000"),
+            },
+            Some(&"d") => println!(
+                "{}",
+                render_board(&session.history.board(), session.history.last_move(), true)
+            ),
+            Some(&"stop") => (),
+            Some(&"quit") => break,
+            _ => (),
+        };
+    }
+}
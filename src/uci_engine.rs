@@ -0,0 +1,193 @@
+/**
+ * Utility module for driving an external chess engine (e.g. Stockfish) over
+ * the UCI protocol, used as an opponent in self-play, by agents and in the
+ * arena. The engine is started as a child process and given positions as FEN
+ * strings. In the arena and in evaluations an engine is named like a player,
+ * "engine" for the defaults or e.g. "engine:skill=3,nodes=5000" or
+ * "engine:elo=1500,command=/usr/bin/stockfish" to override its settings.
+ * Engines can also evaluate positions, e.g. to shape self-play rewards.
+ */
+use chess::{Board, ChessMove};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+
+const DEFAULT_ENGINE_COMMAND: &str = "stockfish";
+const DEFAULT_SKILL_LEVEL: u64 = 0;
+const DEFAULT_NODES: u64 = 1000;
+
+// Centipawn score reported for a forced mate
+pub const MATE_SCORE_CP: i64 = 100000;
+
+// A running UCI engine
+pub struct UciEngine {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    go_command: String,
+}
+
+impl UciEngine {
+    /**
+     * [spawn(command, options, go_command)] starts the engine [command], sets
+     * each of its UCI [options] and waits until it is ready. Every search is
+     * started with [go_command], e.g. "go nodes 1000".
+     */
+    pub fn spawn(
+        command: &str,
+        options: &[(String, String)],
+        go_command: &str,
+    ) -> io::Result<UciEngine> {
+        let mut child = Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut engine = UciEngine {
+            child,
+            stdin,
+            stdout,
+            go_command: go_command.to_string(),
+        };
+        engine.send("uci")?;
+        engine.read_until("uciok")?;
+        for (name, value) in options {
+            engine.send(&format!("setoption name {} value {}", name, value))?;
+        }
+        engine.send("isready")?;
+        engine.read_until("readyok")?;
+
+        return Ok(engine);
+    }
+
+    /**
+     * [from_config(settings)] starts the engine described by [settings], e.g.
+     * {"command": "stockfish", "skill": 0, "nodes": 1000}, playing at the
+     * given skill level and searching the given number of nodes per move.
+     * With an "elo" setting the engine instead limits its strength to that
+     * rating.
+     */
+    pub fn from_config(settings: &Value) -> io::Result<UciEngine> {
+        let command = settings["command"]
+            .as_str()
+            .unwrap_or(DEFAULT_ENGINE_COMMAND);
+        let skill = settings["skill"].as_u64().unwrap_or(DEFAULT_SKILL_LEVEL);
+        let nodes = settings["nodes"].as_u64().unwrap_or(DEFAULT_NODES);
+
+        let mut options = vec![("Skill Level".to_string(), skill.to_string())];
+        if let Some(elo) = settings["elo"].as_u64() {
+            options.push(("UCI_LimitStrength".to_string(), "true".to_string()));
+            options.push(("UCI_Elo".to_string(), elo.to_string()));
+        }
+        return UciEngine::spawn(command, &options, &format!("go nodes {}", nodes));
+    }
+
+    /**
+     * [settings_from_name(name)] converts the player name [name] of an engine,
+     * e.g. "engine:skill=3,nodes=5000", into its settings, or returns None if
+     * [name] does not name an engine.
+     */
+    pub fn settings_from_name(name: &str) -> Option<Value> {
+        let overrides = match name.split_once(':') {
+            Some(("engine", overrides)) => overrides,
+            None if name == "engine" => "",
+            _ => return None,
+        };
+
+        let mut settings = json!({});
+        for setting in overrides.split(',').filter(|s| s.len() > 0) {
+            let (key, value) = setting.split_once('=')?;
+            settings[key] = match value.parse::<u64>() {
+                Ok(n) => json!(n),
+                Err(_) => json!(value),
+            };
+        }
+        return Some(settings);
+    }
+
+    /**
+     * [send(line)] sends the command [line] to the engine.
+     */
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.stdin, "{}", line)?;
+        return self.stdin.flush();
+    }
+
+    /**
+     * [read_until(prefix)] reads lines from the engine until one starts with
+     * [prefix], and returns that line.
+     */
+    fn read_until(&mut self, prefix: &str) -> io::Result<String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Engine closed its output",
+                ));
+            }
+            if line.starts_with(prefix) {
+                return Ok(line.trim().to_string());
+            }
+        }
+    }
+
+    /**
+     * [best_move(b)] asks the engine for its move in board [b], or None if the
+     * engine has no move to play.
+     */
+    pub fn best_move(&mut self, b: &Board) -> io::Result<Option<ChessMove>> {
+        self.send(&format!("position fen {}", b))?;
+        let go_command = self.go_command.clone();
+        self.send(&go_command)?;
+
+        let line = self.read_until("bestmove")?;
+        return match line.split_whitespace().nth(1) {
+            Some(uci) => Ok(ChessMove::from_str(uci).ok()),
+            None => Ok(None),
+        };
+    }
+
+    /**
+     * [evaluate(b)] asks the engine for its evaluation of board [b] in
+     * centipawns from the perspective of the side to move, with a forced mate
+     * worth [MATE_SCORE_CP] (or its negation when being mated), or None if
+     * the engine reported no score.
+     */
+    pub fn evaluate(&mut self, b: &Board) -> io::Result<Option<i64>> {
+        self.send(&format!("position fen {}", b))?;
+        let go_command = self.go_command.clone();
+        self.send(&go_command)?;
+
+        // The last score reported before the best move is the deepest
+        let mut score = None;
+        loop {
+            let line = self.read_until("")?;
+            if line.starts_with("bestmove") {
+                return Ok(score);
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if let Some(i) = tokens.iter().position(|t| *t == "score") {
+                let value = tokens.get(i + 2).and_then(|v| v.parse::<i64>().ok());
+                score = match (tokens.get(i + 1), value) {
+                    (Some(&"cp"), Some(cp)) => Some(cp),
+                    (Some(&"mate"), Some(moves)) if moves > 0 => Some(MATE_SCORE_CP),
+                    (Some(&"mate"), Some(_)) => Some(-MATE_SCORE_CP),
+                    _ => score,
+                };
+            }
+        }
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,109 @@
+/**
+ * Utility module for warm-starting a fresh network before any reinforcement
+ * learning, by training it to approximate the handcrafted evaluation
+ * (material plus piece-square tables) over randomly sampled positions. The
+ * target for each move is the handcrafted evaluation of the position it leads
+ * to, plus the reward for ending the game there, so Q-learning starts from
+ * sane values instead of random outputs. Positions are sampled as training
+ * goes, so any number of them can be used without holding them in memory.
+ * Targets are converted into network outputs by the configured output scaling,
+ * with moves that end the game anchored to their reward.
+ */
+use crate::eval::{evaluate, EvalWeights};
+use crate::history::MoveCounters;
+use crate::mdp::{get_action, get_reward, get_state};
+use crate::output_scaling::OutputScaling;
+use crate::sampling::random_game_position;
+
+use chess::{Board, BoardStatus, MoveGen};
+use neuroflow::FeedForward;
+use rand::Rng;
+use tracing::info;
+
+// Longest random game played to sample a position
+const MAX_SAMPLE_PLIES: usize = 120;
+
+// Number of positions held out to measure how well the network matches
+const PROBE_POSITIONS: usize = 200;
+
+// How often progress is reported, in positions
+const REPORT_INTERVAL: usize = 10000;
+
+/**
+ * [handcrafted_targets(b, counters, player_white, weights, scaling)] returns
+ * the state-action vector of every legal move in board [b], reached with move
+ * [counters], depending on whether the player is white, along with the
+ * handcrafted value of the move under [weights] as a network output under
+ * [scaling].
+ */
+fn handcrafted_targets(
+    b: &Board,
+    counters: MoveCounters,
+    player_white: bool,
+    weights: &EvalWeights,
+    scaling: &OutputScaling,
+) -> Vec<(Vec<f64>, f64)> {
+    let state = get_state(b, counters, player_white);
+
+    let mut targets = Vec::new();
+    for m in MoveGen::new_legal(b) {
+        let next_board = b.make_move_new(m);
+        let value =
+            evaluate(&next_board, player_white, weights) + get_reward(&next_board, player_white);
+        let target = match next_board.status() {
+            BoardStatus::Ongoing => scaling.squash(value),
+            _ => scaling.anchor(value),
+        };
+
+        let mut sa = state.clone();
+        sa.append(&mut get_action(m, player_white));
+        targets.push((sa, target));
+    }
+
+    return targets;
+}
+
+/**
+ * [sample_targets(weights, scaling)] samples a random position from the
+ * perspective of a random player and returns the handcrafted targets of its
+ * legal moves.
+ */
+fn sample_targets(weights: &EvalWeights, scaling: &OutputScaling) -> Vec<(Vec<f64>, f64)> {
+    let (board, counters) = random_game_position(MAX_SAMPLE_PLIES);
+    let player_white = rand::thread_rng().gen_bool(0.5);
+    return handcrafted_targets(&board, counters, player_white, weights, scaling);
+}
+
+/**
+ * [warm_start(nn, weights, scaling, positions)] trains network [nn] to match
+ * the handcrafted evaluation under [weights], scaled by [scaling], over the legal moves of [positions]
+ * randomly sampled positions. Returns the mean squared error of the network
+ * on a separate set of probe positions.
+ */
+pub fn warm_start(
+    nn: &mut FeedForward,
+    weights: &EvalWeights,
+    scaling: &OutputScaling,
+    positions: usize,
+) -> f64 {
+    for i in 0..positions {
+        for (sa, target) in sample_targets(weights, scaling) {
+            nn.fit(&sa[..], &[target]);
+        }
+        if (i + 1) % REPORT_INTERVAL == 0 {
+            info!("Warm-started on {} positions", i + 1);
+        }
+    }
+
+    let mut total = 0.;
+    let mut count = 0;
+    for _ in 0..PROBE_POSITIONS {
+        for (sa, target) in sample_targets(weights, scaling) {
+            let diff = nn.calc(&sa[..])[0] - target;
+            total += diff * diff;
+            count += 1;
+        }
+    }
+
+    return total / count.max(1) as f64;
+}
@@ -0,0 +1,64 @@
+/**
+ * Utility module for keeping Lichess games alive when something stalls. Move
+ * selection is given a deadline, after which the best move evaluated so far is
+ * played (or a cheap fallback move if there is none), and reads from the
+ * Lichess streams are given a timeout, after which the stream is re-opened.
+ * Configured through the "watchdog" object in config.json, e.g.
+ * {"move_secs": 10, "stream_secs": 30}.
+ */
+use crate::eval::EvalWeights;
+use crate::make_random_move;
+use crate::selfplay::handcrafted_move;
+
+use chess::{Board, ChessMove, Color};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MOVE_SECS: u64 = 10;
+const DEFAULT_STREAM_SECS: u64 = 30;
+
+// How long move selection and stream reads may take before the watchdog acts
+#[derive(Clone, Copy, Debug)]
+pub struct Watchdog {
+    pub move_timeout: Duration,
+    pub stream_timeout: Duration,
+}
+
+impl Watchdog {
+    /**
+     * [from_config(config)] reads the watchdog timeouts given by the parsed
+     * [config].
+     */
+    pub fn from_config(config: &Value) -> Watchdog {
+        let settings = &config["watchdog"];
+        let secs = |key: &str, default: u64| settings[key].as_u64().unwrap_or(default);
+        return Watchdog {
+            move_timeout: Duration::from_secs(secs("move_secs", DEFAULT_MOVE_SECS)),
+            stream_timeout: Duration::from_secs(secs("stream_secs", DEFAULT_STREAM_SECS)),
+        };
+    }
+
+    /**
+     * [move_deadline()] returns when move selection starting now must stop.
+     */
+    pub fn move_deadline(&self) -> Instant {
+        return Instant::now() + self.move_timeout;
+    }
+}
+
+/**
+ * [fallback_move(b)] selects a move in board [b] without the policy network,
+ * for when move selection fails: the move that leaves the side to move with
+ * the best material, or a random move. Alternatively if there are no legal
+ * moves it returns None.
+ */
+pub fn fallback_move(b: &Board) -> Option<ChessMove> {
+    let player_white = b.side_to_move() == Color::White;
+    return handcrafted_move(
+        b,
+        player_white,
+        &EvalWeights::default(),
+        &mut rand::thread_rng(),
+    )
+    .or_else(|| make_random_move(*b));
+}
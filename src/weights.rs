@@ -0,0 +1,415 @@
+/**
+ * Utility module for reading the weights of a neuroflow network, for
+ * quantization, export and inspection. Weights are read from the serialized
+ * network, which gives each neuron one weight more than it has inputs: its
+ * bias, as the first weight, since neuroflow prepends a constant 1 to the
+ * inputs. The output layer is linear. neuroflow's sigmoid is the logistic of
+ * minus its input, so the weights and biases of sigmoid layers are read
+ * negated, making them those of a layer using the usual logistic function.
+ * Weights can be exported per layer to CSV or NumPy (.npy) files, and
+ * summarized with per-layer norms and the number of dead units, i.e. hidden
+ * units whose activation never changes over sampled positions, which means
+ * they no longer learn anything. Weights read from elsewhere (e.g. an ONNX
+ * file, see onnx) are turned back into a network as long as neuroflow can
+ * represent them: one activation shared by the hidden layers, tanh or
+ * sigmoid, and a linear output layer.
+ */
+use crate::error::{BotError, BotResult};
+use crate::mdp::{get_action, get_state};
+use crate::model::{activation_name, restore_activation, set_activation};
+use crate::normalization::FeatureStats;
+use crate::sampling::random_game_position;
+
+use chess::MoveGen;
+use neuroflow::FeedForward;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+
+// Longest random game played to sample a position for finding dead units
+const MAX_PROBE_PLIES: usize = 120;
+
+// Smallest change in a unit's activation over the probes for it to count as
+// alive
+const DEAD_UNIT_TOLERANCE: f64 = 1e-6;
+
+// Activation functions of neuroflow's layers: tanh or sigmoid for the hidden
+// layers, and linear for the output layer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Sigmoid,
+    Tanh,
+    Linear,
+}
+
+// The weights of a layer of neurons, one row per neuron
+#[derive(Clone, Debug)]
+pub struct LayerWeights {
+    pub weights: Vec<Vec<f64>>,
+    pub biases: Vec<f64>,
+    pub activation: Activation,
+}
+
+// The weights of a network, from its first layer to its output layer
+#[derive(Clone, Debug)]
+pub struct NetworkWeights {
+    pub layers: Vec<LayerWeights>,
+}
+
+// Summary statistics of a layer's weights
+#[derive(Clone, Debug)]
+pub struct LayerStats {
+    pub inputs: usize,
+    pub neurons: usize,
+    pub weight_norm: f64,
+    pub mean_abs_weight: f64,
+    pub max_abs_weight: f64,
+    pub bias_norm: f64,
+    pub dead_units: usize,
+}
+
+/**
+ * [activate(activation, x)] applies [activation] to [x].
+ */
+pub fn activate(activation: Activation, x: f64) -> f64 {
+    match activation {
+        Activation::Sigmoid => 1. / (1. + (-x).exp()),
+        Activation::Tanh => x.tanh(),
+        Activation::Linear => x,
+    }
+}
+
+/**
+ * [json_to_vec(json)] converts a json array of numbers into a vector.
+ */
+fn json_to_vec(json: &Value) -> Vec<f64> {
+    match json {
+        Value::Array(a) => a.iter().map(|x| x.as_f64().unwrap()).collect(),
+        _ => panic!("Unexpected network weight format"),
+    }
+}
+
+impl LayerWeights {
+    /**
+     * [calc(x)] returns the outputs of the layer for inputs [x].
+     */
+    pub fn calc(&self, x: &[f64]) -> Vec<f64> {
+        let mut outputs = Vec::with_capacity(self.weights.len());
+        for (w, bias) in self.weights.iter().zip(self.biases.iter()) {
+            let v: f64 = w.iter().zip(x.iter()).map(|(w, x)| w * x).sum();
+            outputs.push(activate(self.activation, v + bias));
+        }
+
+        return outputs;
+    }
+}
+
+impl NetworkWeights {
+    /**
+     * [from_network(nn)] reads the weights of network [nn].
+     */
+    pub fn from_network(nn: &FeedForward) -> NetworkWeights {
+        let json = serde_json::to_value(nn).unwrap();
+        let hidden_activation = match json["act_type"].as_str() {
+            Some("Sigmoid") => Activation::Sigmoid,
+            _ => Activation::Tanh,
+        };
+        let layers_json = match &json["layers"] {
+            Value::Array(a) => a,
+            _ => panic!("Unexpected network weight format"),
+        };
+
+        let mut layers = Vec::new();
+        for (j, layer_json) in layers_json.iter().enumerate() {
+            let rows = match &layer_json["w"] {
+                Value::Array(a) => a,
+                _ => panic!("Unexpected network weight format"),
+            };
+            let activation = if j == layers_json.len() - 1 {
+                Activation::Linear
+            } else {
+                hidden_activation
+            };
+            let sign = if activation == Activation::Sigmoid {
+                -1.
+            } else {
+                1.
+            };
+
+            let mut weights = Vec::new();
+            let mut biases = Vec::new();
+            for row in rows {
+                let mut w: Vec<f64> = json_to_vec(row).iter().map(|x| sign * x).collect();
+                biases.push(w.remove(0));
+                weights.push(w);
+            }
+
+            layers.push(LayerWeights {
+                weights,
+                biases,
+                activation,
+            });
+        }
+
+        return NetworkWeights { layers };
+    }
+
+    /**
+     * [to_network()] builds the neuroflow network with these weights, or
+     * returns an error if neuroflow cannot represent them. The network is
+     * trained with neuroflow's default learning rate and momentum.
+     */
+    pub fn to_network(&self) -> BotResult<FeedForward> {
+        let unsupported =
+            |reason: &str| Err(BotError::Config(format!("unsupported weights: {}", reason)));
+        let (output, hidden) = match self.layers.split_last() {
+            Some(split) => split,
+            None => return unsupported("no layers"),
+        };
+        if output.activation != Activation::Linear {
+            return unsupported("the output layer is not linear");
+        }
+        let activation = hidden.first().map_or(Activation::Tanh, |l| l.activation);
+        if activation == Activation::Linear {
+            return unsupported("a hidden layer is linear");
+        }
+        if hidden.iter().any(|l| l.activation != hidden[0].activation) {
+            return unsupported("the hidden layers have different activations");
+        }
+
+        let mut sizes = vec![self.layers[0].weights.first().map_or(0, |w| w.len()) as i32];
+        sizes.extend(self.layers.iter().map(|l| l.weights.len() as i32));
+        let mut nn = FeedForward::new(&sizes);
+        set_activation(&mut nn, activation_name(activation))?;
+
+        // Every layer has its bias as the first weight, negated along with the
+        // weights in sigmoid layers
+        let mut json = serde_json::to_value(&nn)?;
+        for (j, layer) in self.layers.iter().enumerate() {
+            let sign = if layer.activation == Activation::Sigmoid {
+                -1.
+            } else {
+                1.
+            };
+            let rows: Vec<Vec<f64>> = layer
+                .weights
+                .iter()
+                .zip(&layer.biases)
+                .map(|(w, bias)| {
+                    let mut row = vec![*bias];
+                    row.extend(w);
+                    row.iter().map(|x| sign * x).collect()
+                })
+                .collect();
+            json["layers"][j]["w"] = json!(rows);
+        }
+        let mut nn = serde_json::from_value(json)?;
+        restore_activation(&mut nn);
+        return Ok(nn);
+    }
+
+    /**
+     * [layer_outputs(x)] returns the outputs of every layer of the network for
+     * inputs [x].
+     */
+    pub fn layer_outputs(&self, x: &[f64]) -> Vec<Vec<f64>> {
+        let mut outputs: Vec<Vec<f64>> = Vec::new();
+        for layer in &self.layers {
+            let input = outputs.last().map(|o| &o[..]).unwrap_or(x);
+            let y = layer.calc(input);
+            outputs.push(y);
+        }
+
+        return outputs;
+    }
+
+    /**
+     * [dead_units(positions, feature_stats)] returns for each layer how many
+     * of its units have the same activation over every legal move of
+     * [positions] randomly sampled positions, normalized by the network's
+     * [feature_stats].
+     */
+    pub fn dead_units(&self, positions: usize, feature_stats: &FeatureStats) -> Vec<usize> {
+        let mut low: Vec<Vec<f64>> = self
+            .layers
+            .iter()
+            .map(|l| vec![f64::INFINITY; l.weights.len()])
+            .collect();
+        let mut high: Vec<Vec<f64>> = self
+            .layers
+            .iter()
+            .map(|l| vec![f64::NEG_INFINITY; l.weights.len()])
+            .collect();
+
+        for _ in 0..positions {
+            let (board, counters) = random_game_position(MAX_PROBE_PLIES);
+            let player_white = rand::thread_rng().gen_bool(0.5);
+            let state = feature_stats.normalized(&get_state(&board, counters, player_white));
+            for m in MoveGen::new_legal(&board) {
+                let mut sa = state.clone();
+                sa.append(&mut get_action(m, player_white));
+                for (j, outputs) in self.layer_outputs(&sa).iter().enumerate() {
+                    for (i, y) in outputs.iter().enumerate() {
+                        low[j][i] = low[j][i].min(*y);
+                        high[j][i] = high[j][i].max(*y);
+                    }
+                }
+            }
+        }
+
+        return low
+            .iter()
+            .zip(high.iter())
+            .map(|(l, h)| {
+                l.iter()
+                    .zip(h.iter())
+                    .filter(|(l, h)| *h - *l <= DEAD_UNIT_TOLERANCE)
+                    .count()
+            })
+            .collect();
+    }
+
+    /**
+     * [stats(positions, feature_stats)] returns summary statistics of each
+     * layer, finding dead units over [positions] randomly sampled positions,
+     * normalized by the network's [feature_stats].
+     */
+    pub fn stats(&self, positions: usize, feature_stats: &FeatureStats) -> Vec<LayerStats> {
+        let dead_units = self.dead_units(positions, feature_stats);
+
+        let mut stats = Vec::new();
+        for (layer, dead) in self.layers.iter().zip(dead_units) {
+            let all: Vec<f64> = layer.weights.iter().flatten().cloned().collect();
+            stats.push(LayerStats {
+                inputs: layer.weights.first().map_or(0, |w| w.len()),
+                neurons: layer.weights.len(),
+                weight_norm: all.iter().map(|w| w * w).sum::<f64>().sqrt(),
+                mean_abs_weight: all.iter().map(|w| w.abs()).sum::<f64>() / all.len().max(1) as f64,
+                max_abs_weight: all.iter().fold(0., |m: f64, w| m.max(w.abs())),
+                bias_norm: layer.biases.iter().map(|b| b * b).sum::<f64>().sqrt(),
+                dead_units: dead,
+            });
+        }
+
+        return stats;
+    }
+}
+
+/**
+ * [write_csv(path, rows)] writes [rows] to a CSV file at [path].
+ */
+fn write_csv(path: &str, rows: &[Vec<f64>]) -> io::Result<()> {
+    let lines: Vec<String> = rows
+        .iter()
+        .map(|r| {
+            r.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect();
+    return fs::write(path, lines.join("\n") + "\n");
+}
+
+/**
+ * [write_npy(path, rows)] writes [rows] to a NumPy .npy file at [path] as a
+ * 2D array of little-endian 64-bit floats.
+ */
+fn write_npy(path: &str, rows: &[Vec<f64>]) -> io::Result<()> {
+    let cols = rows.first().map_or(0, |r| r.len());
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows.len(),
+        cols
+    );
+
+    // The magic string, version, header length and header are padded with
+    // spaces and a newline to a multiple of 64 bytes
+    let unpadded = 10 + header.len() + 1;
+    header += &" ".repeat((64 - unpadded % 64) % 64);
+    header += "\n";
+
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for x in rows.iter().flatten() {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    return fs::write(path, bytes);
+}
+
+/**
+ * [export_weights(weights, dir, format)] writes the weights and biases of
+ * every layer to files named layer_{j}_weights and layer_{j}_biases in [dir],
+ * either as "csv" or "npy". Returns the paths written.
+ */
+pub fn export_weights(
+    weights: &NetworkWeights,
+    dir: &str,
+    format: &str,
+) -> io::Result<Vec<String>> {
+    fs::create_dir_all(dir)?;
+
+    let mut paths = Vec::new();
+    for (j, layer) in weights.layers.iter().enumerate() {
+        let biases: Vec<Vec<f64>> = vec![layer.biases.clone()];
+        for (name, rows) in [("weights", &layer.weights), ("biases", &biases)] {
+            let path = format!("{}/layer_{}_{}.{}", dir, j, name, format);
+            match format {
+                "csv" => write_csv(&path, rows)?,
+                "npy" => write_npy(&path, rows)?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown export format {}", format),
+                    ))
+                }
+            };
+            paths.push(path);
+        }
+    }
+
+    return Ok(paths);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INPUT_DIM;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn weights_round_trip_through_a_network() {
+        for activation in ["tanh", "sigmoid"] {
+            let mut nn = FeedForward::new(&[INPUT_DIM, 8, 4, 1]);
+            set_activation(&mut nn, activation).unwrap();
+            let weights = NetworkWeights::from_network(&nn);
+            let mut rebuilt = weights.to_network().unwrap();
+            let rebuilt_weights = NetworkWeights::from_network(&rebuilt);
+
+            let sizes = |w: &NetworkWeights| -> Vec<(usize, usize)> {
+                return w
+                    .layers
+                    .iter()
+                    .map(|l| (l.weights.len(), l.weights[0].len()))
+                    .collect();
+            };
+            assert_eq!(weights.layers[0].weights[0].len(), INPUT_DIM as usize);
+            assert_eq!(sizes(&rebuilt_weights), sizes(&weights));
+            assert_eq!(rebuilt_weights.layers[0].biases, weights.layers[0].biases);
+
+            let mut rng = StdRng::seed_from_u64(0);
+            for _ in 0..20 {
+                let x: Vec<f64> = (0..INPUT_DIM)
+                    .map(|_| if rng.gen_bool(0.1) { 1. } else { 0. })
+                    .collect();
+                let expected = nn.calc(&x)[0];
+                assert!((rebuilt.calc(&x)[0] - expected).abs() < 1e-9);
+                assert!((weights.layer_outputs(&x).last().unwrap()[0] - expected).abs() < 1e-9);
+            }
+        }
+    }
+}
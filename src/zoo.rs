@@ -0,0 +1,220 @@
+/**
+ * Utility module for the model zoo, a directory of policy networks trained
+ * with different hyperparameters that are deployed side by side. Each network
+ * is saved as <name>.flow with its metadata next to it (see checkpoint), and
+ * the zoo picks the one that plays each Lichess game once the opponent is
+ * known, instead of the configured networks. Configured by the "zoo" object in
+ * config.json, e.g.
+ * {"dir": "zoo", "selection": "rating", "model": "baseline",
+ *  "bands": [{"max_rating": 1500, "model": "aggressive"},
+ *            {"max_rating": 2000, "model": "baseline"}],
+ *  "opponents": {"maia1": "aggressive"}},
+ * where "selection" is "config" to always play "model", "rating" to play the
+ * model of the first band the opponent's rating falls under (or "model" if
+ * none) or "round_robin" to take turns between every network in the zoo. An
+ * opponent listed in "opponents" always meets its own model. Without the
+ * object the configured networks play every game.
+ */
+use crate::checkpoint::{metadata_path, read_metadata, write_metadata, CheckpointMetadata};
+use crate::error::BotResult;
+use crate::lichess::Player;
+use crate::models::read_network;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
+
+const DEFAULT_ZOO_DIR: &str = "zoo";
+
+// Number of games handed out by round-robin selection so far in this process
+static ROUND_ROBIN_GAMES: AtomicUsize = AtomicUsize::new(0);
+
+// How the zoo picks the model that plays a game
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+    Config,
+    Rating(Vec<(i64, String)>), // (max rating, model) bands, in order
+    RoundRobin,
+}
+
+// A directory of networks, and how one is picked for each game
+#[derive(Clone, Debug)]
+pub struct ModelZoo {
+    pub dir: String,
+    pub selection: Selection,
+    pub default_model: Option<String>,
+    pub opponents: HashMap<String, String>, // lowercase opponent id to model
+}
+
+/**
+ * [parse_selection(settings)] reads the selection named by the "selection"
+ * setting of the zoo [settings], along with its rating bands.
+ */
+fn parse_selection(settings: &Value) -> Selection {
+    return match settings["selection"].as_str().unwrap_or("config") {
+        "config" => Selection::Config,
+        "rating" => {
+            let bands = settings["bands"].as_array().cloned().unwrap_or_default();
+            Selection::Rating(
+                bands
+                    .iter()
+                    .map(|band| {
+                        let max_rating = band["max_rating"].as_i64().unwrap_or(i64::MAX);
+                        let model = band["model"].as_str().expect("Zoo band without a model");
+                        (max_rating, model.to_string())
+                    })
+                    .collect(),
+            )
+        }
+        "round_robin" => Selection::RoundRobin,
+        s => panic!("Invalid zoo selection: {}", s),
+    };
+}
+
+impl ModelZoo {
+    /**
+     * [from_config(config)] reads the model zoo given by the parsed [config],
+     * or returns None if there is none.
+     */
+    pub fn from_config(config: &Value) -> Option<ModelZoo> {
+        let settings = &config["zoo"];
+        if !settings.is_object() {
+            return None;
+        }
+
+        let opponents = match settings["opponents"].as_object() {
+            Some(map) => map
+                .iter()
+                .filter_map(|(id, model)| Some((id.to_lowercase(), model.as_str()?.to_string())))
+                .collect(),
+            None => HashMap::new(),
+        };
+        return Some(ModelZoo {
+            dir: settings["dir"]
+                .as_str()
+                .unwrap_or(DEFAULT_ZOO_DIR)
+                .to_string(),
+            selection: parse_selection(settings),
+            default_model: settings["model"].as_str().map(|s| s.to_string()),
+            opponents,
+        });
+    }
+
+    /**
+     * [path(name)] returns where the model named [name] is saved.
+     */
+    pub fn path(&self, name: &str) -> String {
+        return format!("{}/{}.flow", self.dir, name);
+    }
+
+    /**
+     * [list()] returns the name and path of every model in the zoo, sorted by
+     * name.
+     */
+    pub fn list(&self) -> Vec<(String, String)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut models: Vec<(String, String)> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                let model = name.strip_suffix(".flow")?.to_string();
+                Some((model, e.path().to_string_lossy().to_string()))
+            })
+            .collect();
+        models.sort();
+
+        return models;
+    }
+
+    /**
+     * [add(name, path)] copies the network saved at [path], along with its
+     * metadata, into the zoo as the model named [name], checking that it
+     * loads. Returns where it was saved, or an error if it does not load or
+     * cannot be copied.
+     */
+    pub fn add(&self, name: &str, path: &str) -> BotResult<String> {
+        read_network(path)?;
+        fs::create_dir_all(&self.dir)?;
+        let zoo_path = self.path(name);
+        fs::copy(path, &zoo_path)?;
+        if Path::new(&metadata_path(path)).exists() {
+            fs::copy(metadata_path(path), metadata_path(&zoo_path))?;
+        } else {
+            write_metadata(&zoo_path, &CheckpointMetadata::default());
+        }
+        return Ok(zoo_path);
+    }
+
+    /**
+     * [describe(name, path)] summarizes the model named [name] saved at
+     * [path] from its metadata.
+     */
+    pub fn describe(&self, name: &str, path: &str) -> String {
+        let metadata = read_metadata(path);
+        let score = match metadata.score {
+            Some(s) => format!("{:.3}", s),
+            None => "unevaluated".to_string(),
+        };
+        return format!(
+            "{}: {} games, score {}, hyperparameters {}",
+            name, metadata.games, score, metadata.hyperparameters
+        );
+    }
+
+    /**
+     * [select(opponent)] returns the name of the model that plays a game
+     * against [opponent], or None if the configured networks should play it.
+     */
+    pub fn select(&self, opponent: &Player) -> Option<String> {
+        let id = opponent.id.as_deref().unwrap_or("").to_lowercase();
+        if let Some(model) = self.opponents.get(&id) {
+            return Some(model.clone());
+        }
+
+        return match &self.selection {
+            Selection::Config => self.default_model.clone(),
+            Selection::Rating(bands) => {
+                let band = opponent
+                    .rating
+                    .and_then(|r| bands.iter().find(|(max_rating, _)| r <= *max_rating));
+                match band {
+                    Some((_, model)) => Some(model.clone()),
+                    None => self.default_model.clone(),
+                }
+            }
+            Selection::RoundRobin => {
+                let models = self.list();
+                if models.len() == 0 {
+                    return self.default_model.clone();
+                }
+                let game = ROUND_ROBIN_GAMES.fetch_add(1, Ordering::SeqCst);
+                Some(models[game % models.len()].0.clone())
+            }
+        };
+    }
+
+    /**
+     * [select_path(opponent)] returns where the model that plays a game
+     * against [opponent] is saved, or None if the configured networks should
+     * play it or the selected model is missing from the zoo.
+     */
+    pub fn select_path(&self, opponent: &Player) -> Option<String> {
+        let name = self.select(opponent)?;
+        let path = self.path(&name);
+        if !Path::new(&path).exists() {
+            warn!(
+                "Model {} is not in the zoo at {}, ignoring it",
+                name, self.dir
+            );
+            return None;
+        }
+        return Some(path);
+    }
+}
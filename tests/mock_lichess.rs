@@ -0,0 +1,53 @@
+/**
+ * End to end tests of the Lichess game loop, playing scripted games against a
+ * mock Lichess server (see mock_lichess) with the default settings.
+ */
+use rust_chess_bot::mock_lichess::{run_scenario, MockScenario};
+
+use serde_json::json;
+
+/**
+ * [check_scenario(scenario)] plays [scenario] against a mock server and fails
+ * with every check that did not pass.
+ */
+async fn check_scenario(scenario: MockScenario) {
+    let client = reqwest::Client::new();
+    let failures = run_scenario(&client, &json!({}), scenario).await.unwrap();
+    assert!(failures.is_empty(), "{}: {:?}", scenario.name, failures);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn loses_on_time_as_white() {
+    check_scenario(MockScenario {
+        name: "loses on time as white",
+        bot_white: true,
+        initial_fen: None,
+        bot_moves: 6,
+        bot_flagged: true,
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wins_on_time_as_black() {
+    check_scenario(MockScenario {
+        name: "wins on time as black",
+        bot_white: false,
+        initial_fen: None,
+        bot_moves: 5,
+        bot_flagged: false,
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn faces_a_mating_attack_from_a_custom_position() {
+    check_scenario(MockScenario {
+        name: "faces a mating attack from a custom position",
+        bot_white: true,
+        initial_fen: Some("rnbqkbnr/pppp1ppp/8/4p3/8/5P2/PPPPP1PP/RNBQKBNR w KQkq - 0 2"),
+        bot_moves: 20,
+        bot_flagged: true,
+    })
+    .await;
+}